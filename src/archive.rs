@@ -0,0 +1,302 @@
+// Copyright (c) 2024 Jan Holthuis <jan.holthuis@rub.de>
+//
+// This Source Code Form is subject to the terms of the Mozilla Public License, v. 2.0. If a copy
+// of the MPL was not distributed with this file, You can obtain one at
+// http://mozilla.org/MPL/2.0/.
+//
+// SPDX-License-Identifier: MPL-2.0
+//! ZIP archives of the output for `--output-zip`.
+//!
+//! The sync writes into a staging directory next to the archive, which is then packed into it.
+//! Entries are stored uncompressed, since MP3s hardly compress, and are streamed from the staged
+//! files so that memory use does not depend on their size. Archives over 4 GiB use ZIP64.
+use crate::atomic;
+use crate::mtime;
+use std::ffi::OsString;
+use std::fs::File;
+use std::io::{self, BufWriter, Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+
+/// Sizes and offsets from this value on need ZIP64 records.
+const ZIP64_LIMIT: u64 = 0xFFFF_FFFF;
+
+/// Entry counts from this value on need ZIP64 records.
+const ZIP64_ENTRY_LIMIT: usize = 0xFFFF;
+
+/// General purpose flag for UTF-8 file names.
+const FLAG_UTF8: u16 = 1 << 11;
+
+/// Returns the staging directory that the output of `archive` is written to before packing.
+///
+/// The same is used for disk images.
+pub fn staging_dir(archive: &Path) -> PathBuf {
+    let mut name = OsString::from(".");
+    name.push(archive.file_name().unwrap_or_default());
+    name.push(".staging");
+    archive.with_file_name(name)
+}
+
+/// Returns the files below `dir` as paths relative to it, sorted and without hidden files like
+/// the lock file.
+pub fn files(dir: &Path) -> io::Result<Vec<PathBuf>> {
+    let mut files = vec![];
+    let mut dirs = vec![PathBuf::new()];
+    while let Some(relative) = dirs.pop() {
+        for entry in std::fs::read_dir(dir.join(&relative))? {
+            let entry = entry?;
+            if entry.file_name().to_string_lossy().starts_with('.') {
+                continue;
+            }
+            let path = relative.join(entry.file_name());
+            if entry.file_type()?.is_dir() {
+                dirs.push(path);
+            } else {
+                files.push(path);
+            }
+        }
+    }
+    files.sort();
+    Ok(files)
+}
+
+/// Returns the name of an entry, with `/` as separator regardless of the platform.
+fn entry_name(relative: &Path) -> String {
+    relative
+        .iter()
+        .map(|component| component.to_string_lossy())
+        .collect::<Vec<_>>()
+        .join("/")
+}
+
+/// An entry that was written, for the central directory.
+struct Entry {
+    name: String,
+    time: (u16, u16),
+    crc: u32,
+    size: u32,
+    offset: u64,
+}
+
+/// Writes a ZIP archive with stored entries.
+struct ZipWriter {
+    file: BufWriter<File>,
+    offset: u64,
+    entries: Vec<Entry>,
+}
+
+impl ZipWriter {
+    fn new(file: File) -> Self {
+        Self {
+            file: BufWriter::new(file),
+            offset: 0,
+            entries: vec![],
+        }
+    }
+
+    fn write(&mut self, bytes: &[u8]) -> io::Result<()> {
+        self.file.write_all(bytes)?;
+        self.offset += bytes.len() as u64;
+        Ok(())
+    }
+
+    /// Add the file at `path` as `name`, streaming its contents and filling in the checksum and
+    /// size afterwards.
+    fn add_file(&mut self, name: String, path: &Path) -> io::Result<()> {
+        let mut input = File::open(path)?;
+        let time = mtime::dos_time(input.metadata()?.modified()?);
+        let offset = self.offset;
+        let mut header = vec![];
+        header.extend(0x0403_4b50u32.to_le_bytes());
+        header.extend(20u16.to_le_bytes());
+        header.extend(FLAG_UTF8.to_le_bytes());
+        header.extend(0u16.to_le_bytes());
+        header.extend(time.0.to_le_bytes());
+        header.extend(time.1.to_le_bytes());
+        // Checksum, compressed and uncompressed size, filled in below.
+        header.extend([0; 12]);
+        header.extend((name.len() as u16).to_le_bytes());
+        header.extend(0u16.to_le_bytes());
+        header.extend(name.as_bytes());
+        self.write(&header)?;
+
+        let mut hasher = crc32fast::Hasher::new();
+        let mut size = 0u64;
+        let mut buffer = vec![0; 64 * 1024];
+        loop {
+            let len = input.read(&mut buffer)?;
+            if len == 0 {
+                break;
+            }
+            hasher.update(&buffer[..len]);
+            self.write(&buffer[..len])?;
+            size += len as u64;
+        }
+        let size = u32::try_from(size)
+            .ok()
+            .filter(|size| u64::from(*size) < ZIP64_LIMIT);
+        let Some(size) = size else {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("{}: File is too large for a ZIP archive", path.display()),
+            ));
+        };
+        let crc = hasher.finalize();
+        let mut sizes = vec![];
+        sizes.extend(crc.to_le_bytes());
+        sizes.extend(size.to_le_bytes());
+        sizes.extend(size.to_le_bytes());
+        self.file.seek(SeekFrom::Start(offset + 14))?;
+        self.file.write_all(&sizes)?;
+        self.file.seek(SeekFrom::Start(self.offset))?;
+        self.entries.push(Entry {
+            name,
+            time,
+            crc,
+            size,
+            offset,
+        });
+        Ok(())
+    }
+
+    /// Write the central directory and return the size of the archive.
+    fn finish(mut self) -> io::Result<u64> {
+        let directory_offset = self.offset;
+        for entry in std::mem::take(&mut self.entries) {
+            let zip64 = entry.offset >= ZIP64_LIMIT;
+            let version: u16 = if zip64 { 45 } else { 20 };
+            let mut header = vec![];
+            header.extend(0x0201_4b50u32.to_le_bytes());
+            // Made by Unix, so that the permissions below are used.
+            header.extend(((3 << 8) | version).to_le_bytes());
+            header.extend(version.to_le_bytes());
+            header.extend(FLAG_UTF8.to_le_bytes());
+            header.extend(0u16.to_le_bytes());
+            header.extend(entry.time.0.to_le_bytes());
+            header.extend(entry.time.1.to_le_bytes());
+            header.extend(entry.crc.to_le_bytes());
+            header.extend(entry.size.to_le_bytes());
+            header.extend(entry.size.to_le_bytes());
+            header.extend((entry.name.len() as u16).to_le_bytes());
+            header.extend((if zip64 { 12u16 } else { 0 }).to_le_bytes());
+            // Comment length, disk number and internal attributes.
+            header.extend([0; 6]);
+            header.extend((0o100_644u32 << 16).to_le_bytes());
+            header.extend((entry.offset.min(ZIP64_LIMIT) as u32).to_le_bytes());
+            header.extend(entry.name.as_bytes());
+            if zip64 {
+                header.extend(1u16.to_le_bytes());
+                header.extend(8u16.to_le_bytes());
+                header.extend(entry.offset.to_le_bytes());
+            }
+            self.write(&header)?;
+            self.entries.push(entry);
+        }
+        let directory_size = self.offset - directory_offset;
+        let num_entries = self.entries.len();
+
+        if num_entries >= ZIP64_ENTRY_LIMIT
+            || directory_offset >= ZIP64_LIMIT
+            || directory_size >= ZIP64_LIMIT
+        {
+            let record_offset = self.offset;
+            let mut record = vec![];
+            record.extend(0x0606_4b50u32.to_le_bytes());
+            record.extend(44u64.to_le_bytes());
+            record.extend(((3u16 << 8) | 45).to_le_bytes());
+            record.extend(45u16.to_le_bytes());
+            // Number of this disk and the disk with the central directory.
+            record.extend([0; 8]);
+            record.extend((num_entries as u64).to_le_bytes());
+            record.extend((num_entries as u64).to_le_bytes());
+            record.extend(directory_size.to_le_bytes());
+            record.extend(directory_offset.to_le_bytes());
+            record.extend(0x0706_4b50u32.to_le_bytes());
+            record.extend(0u32.to_le_bytes());
+            record.extend(record_offset.to_le_bytes());
+            record.extend(1u32.to_le_bytes());
+            self.write(&record)?;
+        }
+        let mut end = vec![];
+        end.extend(0x0605_4b50u32.to_le_bytes());
+        // Number of this disk and the disk with the central directory.
+        end.extend([0; 4]);
+        let num_entries = num_entries.min(ZIP64_ENTRY_LIMIT) as u16;
+        end.extend(num_entries.to_le_bytes());
+        end.extend(num_entries.to_le_bytes());
+        end.extend((directory_size.min(ZIP64_LIMIT) as u32).to_le_bytes());
+        end.extend((directory_offset.min(ZIP64_LIMIT) as u32).to_le_bytes());
+        end.extend(0u16.to_le_bytes());
+        self.write(&end)?;
+        self.file.into_inner()?.sync_all()?;
+        Ok(self.offset)
+    }
+}
+
+/// Pack all files below `dir` into a ZIP archive at `archive` and return its size.
+///
+/// The archive is written to a temporary file first and only replaces an existing one once it
+/// is complete.
+pub fn write_dir(dir: &Path, archive: &Path) -> io::Result<u64> {
+    let temp_path = atomic::temp_path(archive);
+    let result = (|| {
+        let mut writer = ZipWriter::new(File::create(&temp_path)?);
+        for relative in files(dir)? {
+            writer.add_file(entry_name(&relative), &dir.join(&relative))?;
+        }
+        writer.finish()
+    })()
+    .and_then(|size| atomic::commit(&temp_path, archive, false).map(|()| size));
+    if result.is_err() {
+        atomic::discard(&temp_path);
+    }
+    result
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn u16_at(bytes: &[u8], offset: usize) -> u16 {
+        u16::from_le_bytes(bytes[offset..offset + 2].try_into().unwrap())
+    }
+
+    fn u32_at(bytes: &[u8], offset: usize) -> u32 {
+        u32::from_le_bytes(bytes[offset..offset + 4].try_into().unwrap())
+    }
+
+    #[test]
+    fn stores_files_with_relative_names() {
+        let dir = std::env::temp_dir().join(format!("archive-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        let staging = dir.join("staging");
+        std::fs::create_dir_all(staging.join("Album")).unwrap();
+        std::fs::write(staging.join("Album/01 Song.mp3"), b"hello").unwrap();
+        std::fs::write(staging.join("Road Trip.m3u"), b"Album\\01 Song.mp3\n").unwrap();
+        std::fs::write(staging.join(".ford-sync-convert.lock"), b"").unwrap();
+        let archive = dir.join("out.zip");
+
+        let size = write_dir(&staging, &archive).unwrap();
+        let bytes = std::fs::read(&archive).unwrap();
+        assert_eq!(size, bytes.len() as u64);
+
+        // The first local header and its data.
+        assert_eq!(u32_at(&bytes, 0), 0x0403_4b50);
+        assert_eq!(u32_at(&bytes, 14), 0x3610_a686);
+        assert_eq!(u32_at(&bytes, 18), 5);
+        assert_eq!(&bytes[30..47], b"Album/01 Song.mp3");
+        assert_eq!(&bytes[47..52], b"hello");
+
+        let end = bytes.len() - 22;
+        assert_eq!(u32_at(&bytes, end), 0x0605_4b50);
+        assert_eq!(u16_at(&bytes, end + 10), 2);
+        let mut offset = u32_at(&bytes, end + 16) as usize;
+        let mut names = vec![];
+        for _ in 0..2 {
+            assert_eq!(u32_at(&bytes, offset), 0x0201_4b50);
+            let len = u16_at(&bytes, offset + 28) as usize;
+            names.push(String::from_utf8(bytes[offset + 46..offset + 46 + len].to_vec()).unwrap());
+            offset += 46 + len;
+        }
+        assert_eq!(names, ["Album/01 Song.mp3", "Road Trip.m3u"]);
+    }
+}
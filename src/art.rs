@@ -0,0 +1,355 @@
+// Copyright (c) 2024 Jan Holthuis <jan.holthuis@rub.de>
+//
+// This Source Code Form is subject to the terms of the Mozilla Public License, v. 2.0. If a copy
+// of the MPL was not distributed with this file, You can obtain one at
+// http://mozilla.org/MPL/2.0/.
+//
+// SPDX-License-Identifier: MPL-2.0
+//! Album art in the output tree.
+use crate::atomic;
+use crate::interrupt;
+use crate::longpath;
+use crate::plan::Plan;
+use crate::warnings::{report_warning, WarningCategory};
+use log::{debug, info};
+use std::collections::{BTreeMap, HashMap};
+use std::path::{Path, PathBuf};
+use std::process::{Command, Stdio};
+
+/// Name of the album art file that Sync 2 displays for a folder.
+pub const FOLDER_ART_NAME: &str = "folder.jpg";
+
+/// Default names of image files next to the sources that are used as album art.
+pub const SIDECAR_NAMES: [&str; 6] = [
+    "cover.jpg",
+    "folder.jpg",
+    "front.jpg",
+    "cover.png",
+    "folder.png",
+    "front.png",
+];
+
+/// Returns the first file in `dir` whose name matches one of `names` (case-insensitively).
+pub fn find_sidecar<S: AsRef<str>>(dir: &Path, names: &[S]) -> Option<PathBuf> {
+    let entries: Vec<_> = std::fs::read_dir(dir)
+        .ok()?
+        .filter_map(Result::ok)
+        .filter(|entry| entry.file_type().is_ok_and(|file_type| file_type.is_file()))
+        .collect();
+    names.iter().find_map(|name| {
+        entries
+            .iter()
+            .find(|entry| {
+                entry
+                    .file_name()
+                    .to_str()
+                    .is_some_and(|file_name| file_name.eq_ignore_ascii_case(name.as_ref()))
+            })
+            .map(|entry| entry.path())
+    })
+}
+
+/// Returns the FFmpeg filter that scales an image down to at most `max_size` pixels in both
+/// dimensions, keeping its aspect ratio.
+pub fn scale_filter(max_size: u32) -> String {
+    format!(
+        "scale='min({0},iw)':'min({0},ih)':force_original_aspect_ratio=decrease",
+        max_size
+    )
+}
+
+/// Write the first image of `input` (an image file or an audio file with an attached picture) as
+/// a JPEG to `output`, scaled to at most `max_size` pixels.
+pub fn write_image(input: &Path, output: &Path, max_size: u32) -> Result<(), String> {
+    let temp_path = atomic::temp_path(output);
+    let result = Command::new("ffmpeg")
+        .arg("-i")
+        .arg(input)
+        .args(["-y", "-an", "-map", "0:v:0", "-frames:v", "1", "-vf"])
+        .arg(scale_filter(max_size))
+        .args(["-c:v", "mjpeg", "-f", "image2"])
+        .arg(&temp_path)
+        .stdin(Stdio::null())
+        .stdout(Stdio::null())
+        .stderr(Stdio::piped())
+        .output()
+        .map_err(|e| format!("Failed to execute FFmpeg ({})", e))
+        .and_then(|output| {
+            if output.status.success() {
+                Ok(())
+            } else {
+                let stderr = String::from_utf8_lossy(&output.stderr);
+                Err(stderr.lines().last().unwrap_or_default().to_string())
+            }
+        })
+        .and_then(|()| atomic::commit(&temp_path, output, false).map_err(|e| e.to_string()));
+    if result.is_err() {
+        atomic::discard(&temp_path);
+    }
+    result
+}
+
+/// Read the first image of `input` as a JPEG, scaled to at most `max_size` pixels.
+pub fn load_image(input: &Path, max_size: u32) -> Result<Vec<u8>, String> {
+    let output = Command::new("ffmpeg")
+        .arg("-i")
+        .arg(input)
+        .args(["-an", "-map", "0:v:0", "-frames:v", "1", "-vf"])
+        .arg(scale_filter(max_size))
+        .args(["-c:v", "mjpeg", "-f", "image2pipe", "pipe:1"])
+        .stdin(Stdio::null())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .output()
+        .map_err(|e| format!("Failed to execute FFmpeg ({})", e))?;
+    if output.status.success() && !output.stdout.is_empty() {
+        Ok(output.stdout)
+    } else {
+        let stderr = String::from_utf8_lossy(&output.stderr);
+        Err(stderr.lines().last().unwrap_or_default().to_string())
+    }
+}
+
+/// Settings for [`write_folder_art`].
+#[derive(Clone, Debug)]
+pub struct FolderArtOptions {
+    /// Maximum width and height of the images in pixels.
+    pub max_size: u32,
+    /// Replace existing album art files.
+    pub force: bool,
+    /// Names of cover images next to the sources.
+    pub sidecar_names: Vec<String>,
+}
+
+/// Write a `folder.jpg` into every output directory of the plan.
+///
+/// The image is taken from the first source with embedded art, or from a cover image next to the
+/// sources. Directories without any art are left alone. Returns the paths of the written images.
+pub fn write_folder_art(plan: &Plan, options: &FolderArtOptions) -> Vec<PathBuf> {
+    let mut dirs: BTreeMap<&Path, Vec<&Path>> = BTreeMap::new();
+    for task in plan.tasks.iter() {
+        if let Some(dir) = task.destination.parent() {
+            dirs.entry(dir).or_default().push(&task.source);
+        }
+    }
+
+    info!("Writing album art for {} folders...", dirs.len());
+    let mut written = vec![];
+    for (dir, sources) in dirs {
+        if interrupt::is_interrupted() {
+            break;
+        }
+        let output_path = longpath::extended(&dir.join(FOLDER_ART_NAME));
+        if !options.force && output_path.exists() {
+            debug!("{}: Album art exists, skipping", output_path.display());
+            continue;
+        }
+
+        // Without probe results, every source may have embedded art. Failures are only reported
+        // for inputs that are known to contain an image.
+        let embedded = sources
+            .iter()
+            .map(|source| (source.to_path_buf(), plan.metadata.get(*source)))
+            .filter(|(_, info)| info.is_none_or(|info| info.pictures > 0))
+            .map(|(source, info)| (source, info.is_some()));
+        let mut source_dirs: Vec<_> = sources
+            .iter()
+            .filter_map(|source| source.parent())
+            .collect();
+        source_dirs.dedup();
+        let sidecars = source_dirs
+            .into_iter()
+            .filter_map(|source_dir| find_sidecar(source_dir, &options.sidecar_names))
+            .map(|sidecar| (sidecar, true));
+
+        let mut error = None;
+        for (input, has_image) in embedded.chain(sidecars) {
+            match write_image(&longpath::extended(&input), &output_path, options.max_size) {
+                Ok(()) => {
+                    debug!(
+                        "{}: Wrote album art from {}",
+                        output_path.display(),
+                        input.display()
+                    );
+                    written.push(dir.join(FOLDER_ART_NAME));
+                    error = None;
+                    break;
+                }
+                Err(e) if has_image => error = Some((input, e)),
+                Err(_) => (),
+            }
+        }
+        if let Some((input, e)) = error {
+            report_warning!(
+                WarningCategory::Art,
+                "{}: Failed to write album art from {} ({})",
+                output_path.display(),
+                input.display(),
+                e
+            );
+        }
+    }
+    info!("Wrote album art for {} folders.", written.len());
+    written
+}
+
+/// Settings for [`copy_art`].
+#[derive(Clone, Debug, Default)]
+pub struct CopyArtOptions {
+    /// Scale the images to at most this many pixels in both dimensions instead of copying them
+    /// as they are.
+    pub max_size: Option<u32>,
+    /// Replace existing images.
+    pub force: bool,
+    /// Names of cover images next to the sources, in order of preference.
+    pub sidecar_names: Vec<String>,
+}
+
+/// Returns the cover image for every output directory of the plan, taken from the first source
+/// directory of its tracks that has one.
+///
+/// Each source directory is only searched once, no matter how many tracks or output directories
+/// it has.
+pub fn cover_images<S: AsRef<str>>(plan: &Plan, names: &[S]) -> BTreeMap<PathBuf, PathBuf> {
+    let mut dirs: BTreeMap<&Path, Vec<&Path>> = BTreeMap::new();
+    for task in plan.tasks.iter() {
+        if let (Some(dir), Some(source_dir)) = (task.destination.parent(), task.source.parent()) {
+            let source_dirs = dirs.entry(dir).or_default();
+            if !source_dirs.contains(&source_dir) {
+                source_dirs.push(source_dir);
+            }
+        }
+    }
+    let mut sidecars: HashMap<&Path, Option<PathBuf>> = HashMap::new();
+    dirs.into_iter()
+        .filter_map(|(dir, source_dirs)| {
+            source_dirs
+                .into_iter()
+                .find_map(|source_dir| {
+                    sidecars
+                        .entry(source_dir)
+                        .or_insert_with(|| find_sidecar(source_dir, names))
+                        .clone()
+                })
+                .map(|sidecar| (dir.to_path_buf(), sidecar))
+        })
+        .collect()
+}
+
+/// Copy the cover image next to the sources of every output directory of the plan into it,
+/// keeping its name.
+///
+/// Scaled images are written as JPEGs, so their extension becomes `.jpg`. Existing images are
+/// left alone unless they are older than the cover. Returns the paths of the copied images.
+pub fn copy_art(plan: &Plan, options: &CopyArtOptions) -> Vec<PathBuf> {
+    let covers = cover_images(plan, &options.sidecar_names);
+    info!("Copying album art into {} folders...", covers.len());
+    let mut copied = vec![];
+    for (dir, cover) in covers {
+        if interrupt::is_interrupted() {
+            break;
+        }
+        let Some(file_name) = cover.file_name() else {
+            continue;
+        };
+        let mut output_path = dir.join(file_name);
+        if options.max_size.is_some() {
+            output_path.set_extension("jpg");
+        }
+        let plain_output_path = output_path.clone();
+        let output_path = longpath::extended(&output_path);
+        let cover = longpath::extended(&cover);
+        let modified = |path: &Path| std::fs::metadata(path).and_then(|meta| meta.modified());
+        let is_up_to_date = match (modified(&cover), modified(&output_path)) {
+            (Ok(cover), Ok(output)) => output >= cover,
+            _ => false,
+        };
+        if !options.force && is_up_to_date {
+            debug!(
+                "{}: Album art is up to date, skipping",
+                output_path.display()
+            );
+            continue;
+        }
+        let result = match options.max_size {
+            Some(max_size) => write_image(&cover, &output_path, max_size),
+            None => {
+                let temp_path = atomic::temp_path(&output_path);
+                std::fs::copy(&cover, &temp_path)
+                    .and_then(|_| atomic::commit(&temp_path, &output_path, false))
+                    .map_err(|e| {
+                        atomic::discard(&temp_path);
+                        e.to_string()
+                    })
+            }
+        };
+        match result {
+            Ok(()) => {
+                debug!(
+                    "{}: Copied album art from {}",
+                    output_path.display(),
+                    cover.display()
+                );
+                copied.push(plain_output_path);
+            }
+            Err(e) => report_warning!(
+                WarningCategory::Art,
+                "{}: Failed to copy album art from {} ({})",
+                output_path.display(),
+                cover.display(),
+                e
+            ),
+        }
+    }
+    info!("Copied album art into {} folders.", copied.len());
+    copied
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::plan::{Action, Task};
+    use crate::tags::TagOverrides;
+
+    #[test]
+    fn finds_covers_for_output_folders() {
+        let dir = std::env::temp_dir().join(format!("art-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        for file in [
+            "A/01.mp3",
+            "A/02.mp3",
+            "A/Front.JPG",
+            "B/01.mp3",
+            "B/cover.jpg",
+        ] {
+            let path = dir.join(file);
+            std::fs::create_dir_all(path.parent().unwrap()).unwrap();
+            std::fs::write(path, b"").unwrap();
+        }
+        let task = |source: &str, destination: &str| Task {
+            action: Action::Copy,
+            source: dir.join(source),
+            destination: PathBuf::from(destination),
+            tags: TagOverrides::default(),
+            encoder: None,
+            segment: None,
+        };
+        let plan = Plan {
+            tasks: vec![
+                task("A/01.mp3", "out/A/01.mp3"),
+                task("A/02.mp3", "out/All/02.mp3"),
+                task("B/01.mp3", "out/All/01.mp3"),
+                task("C/01.mp3", "out/C/01.mp3"),
+            ],
+            ..Default::default()
+        };
+        let covers = cover_images(&plan, &["cover.jpg", "front.jpg"]);
+        assert_eq!(
+            covers.into_iter().collect::<Vec<_>>(),
+            [
+                (PathBuf::from("out/A"), dir.join("A/Front.JPG")),
+                (PathBuf::from("out/All"), dir.join("A/Front.JPG")),
+            ]
+        );
+    }
+}
@@ -0,0 +1,170 @@
+// Copyright (c) 2024 Jan Holthuis <jan.holthuis@rub.de>
+//
+// This Source Code Form is subject to the terms of the Mozilla Public License, v. 2.0. If a copy
+// of the MPL was not distributed with this file, You can obtain one at
+// http://mozilla.org/MPL/2.0/.
+//
+// SPDX-License-Identifier: MPL-2.0
+//! Atomic writes of output files.
+//!
+//! Every output is first written to a temporary file next to its destination and only renamed into
+//! place once it is complete, so that a crash never leaves a truncated file under the final name.
+use crate::warnings::{report_warning, WarningCategory};
+use log::info;
+use std::ffi::OsString;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::{Duration, Instant};
+
+const TEMP_PREFIX: &str = ".tmp.";
+
+/// Returns the temporary path that is used while `destination` is being written.
+pub fn temp_path(destination: &Path) -> PathBuf {
+    let mut file_name = OsString::from(TEMP_PREFIX);
+    file_name.push(destination.file_name().unwrap_or_default());
+    file_name.push(format!(".{}", std::process::id()));
+    destination.with_file_name(file_name)
+}
+
+/// Total time spent waiting for data to be flushed to disk, in nanoseconds.
+static SYNC_NANOS: AtomicU64 = AtomicU64::new(0);
+
+/// Returns the total time spent in [`commit`] and [`sync_filesystem`] flushing data to disk.
+pub fn sync_time() -> Duration {
+    Duration::from_nanos(SYNC_NANOS.load(Ordering::Relaxed))
+}
+
+fn timed_sync(f: impl FnOnce() -> io::Result<()>) -> io::Result<()> {
+    let started = Instant::now();
+    let result = f();
+    let elapsed = u64::try_from(started.elapsed().as_nanos()).unwrap_or(u64::MAX);
+    SYNC_NANOS.fetch_add(elapsed, Ordering::Relaxed);
+    result
+}
+
+/// Flush the directory entry of `path` to disk.
+#[cfg(unix)]
+fn sync_parent_dir(path: &Path) -> io::Result<()> {
+    let parent = path.parent().unwrap_or(Path::new("."));
+    std::fs::File::open(parent)?.sync_all()
+}
+
+#[cfg(not(unix))]
+fn sync_parent_dir(_path: &Path) -> io::Result<()> {
+    Ok(())
+}
+
+/// Move a completely written temporary file to its final destination.
+///
+/// If `sync` is set, the file contents are flushed to disk before the rename, and the directory
+/// entry after it.
+pub fn commit(temp_path: &Path, destination: &Path, sync: bool) -> io::Result<()> {
+    if sync {
+        timed_sync(|| std::fs::File::open(temp_path)?.sync_all())?;
+    }
+    std::fs::rename(temp_path, destination)?;
+    if sync {
+        timed_sync(|| sync_parent_dir(destination))?;
+    }
+    Ok(())
+}
+
+/// Flush all pending writes of the filesystem that contains `path`.
+pub fn sync_filesystem(path: &Path) -> io::Result<()> {
+    timed_sync(|| {
+        #[cfg(target_os = "linux")]
+        {
+            use std::os::fd::AsRawFd;
+            let dir = std::fs::File::open(path)?;
+            // SAFETY: The file descriptor is valid for the lifetime of `dir`.
+            if unsafe { libc::syncfs(dir.as_raw_fd()) } != 0 {
+                return Err(io::Error::last_os_error());
+            }
+        }
+        #[cfg(all(unix, not(target_os = "linux")))]
+        {
+            let _ = path;
+            // SAFETY: `sync` has no preconditions.
+            unsafe { libc::sync() };
+        }
+        #[cfg(not(unix))]
+        {
+            let _ = path;
+        }
+        Ok(())
+    })
+}
+
+/// Remove a temporary file of a failed or interrupted task.
+pub fn discard(temp_path: &Path) {
+    match std::fs::remove_file(temp_path) {
+        Ok(()) => (),
+        Err(e) if e.kind() == io::ErrorKind::NotFound => (),
+        Err(e) => report_warning!(
+            WarningCategory::Io,
+            "{}: Failed to remove temporary file ({})",
+            temp_path.display(),
+            e
+        ),
+    }
+}
+
+/// Returns `true` if the file name looks like one produced by [`temp_path`].
+fn is_temp_file_name(file_name: &str) -> bool {
+    file_name
+        .strip_prefix(TEMP_PREFIX)
+        .and_then(|rest| rest.rsplit_once('.'))
+        .is_some_and(|(name, pid)| {
+            !name.is_empty() && !pid.is_empty() && pid.bytes().all(|b| b.is_ascii_digit())
+        })
+}
+
+/// Recursively find temporary files left behind by a crashed previous run.
+pub fn find_stale(dir: &Path) -> Vec<PathBuf> {
+    let Ok(entries) = std::fs::read_dir(dir) else {
+        return vec![];
+    };
+    let mut stale = vec![];
+    for entry in entries.filter_map(Result::ok) {
+        let path = entry.path();
+        let Ok(file_type) = entry.file_type() else {
+            continue;
+        };
+        if file_type.is_dir() {
+            stale.extend(find_stale(&path));
+        } else if entry.file_name().to_str().is_some_and(is_temp_file_name) {
+            stale.push(path);
+        }
+    }
+    stale
+}
+
+/// Recursively remove temporary files left behind by a crashed previous run.
+pub fn remove_stale(dir: &Path) {
+    let entries = match std::fs::read_dir(dir) {
+        Ok(entries) => entries,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return,
+        Err(e) => {
+            report_warning!(
+                WarningCategory::Io,
+                "{}: Failed to read directory ({})",
+                dir.display(),
+                e
+            );
+            return;
+        }
+    };
+    for entry in entries.filter_map(Result::ok) {
+        let path = entry.path();
+        let Ok(file_type) = entry.file_type() else {
+            continue;
+        };
+        if file_type.is_dir() {
+            remove_stale(&path);
+        } else if entry.file_name().to_str().is_some_and(is_temp_file_name) {
+            info!("{}: Removing leftover temporary file", path.display());
+            discard(&path);
+        }
+    }
+}
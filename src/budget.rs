@@ -0,0 +1,196 @@
+// Copyright (c) 2024 Jan Holthuis <jan.holthuis@rub.de>
+//
+// This Source Code Form is subject to the terms of the Mozilla Public License, v. 2.0. If a copy
+// of the MPL was not distributed with this file, You can obtain one at
+// http://mozilla.org/MPL/2.0/.
+//
+// SPDX-License-Identifier: MPL-2.0
+//! Trimming of plans that exceed a budget.
+use crate::plan::{Plan, SkipReason, SkippedEntry, Task};
+use log::{info, warn};
+use std::collections::{HashMap, HashSet};
+use std::path::PathBuf;
+
+/// Trim the plan so that the total cost of all distinct output files fits into `budget`.
+///
+/// `priority` lists playlist indices from highest to lowest priority. Entries are cut from the end
+/// of the lowest-priority playlist first, so that higher-priority playlists stay intact for as
+/// long as possible. Entries whose output is also part of a higher-priority playlist are kept, as
+/// cutting them would free nothing, and a cut output is cut from all playlists. Returns the number
+/// of cut entries.
+pub fn trim(
+    plan: &mut Plan,
+    priority: &[usize],
+    budget: u64,
+    cost: impl Fn(&Plan, &Task) -> u64,
+    reason: SkipReason,
+) -> usize {
+    let mut costs: HashMap<PathBuf, u64> = HashMap::new();
+    // Rank in `priority` of the highest-priority playlist that references each output.
+    let mut highest: HashMap<PathBuf, usize> = HashMap::new();
+    for (rank, &index) in priority.iter().enumerate() {
+        for entry in plan.playlists[index].entries.iter() {
+            let task = &plan.tasks[entry.task];
+            costs
+                .entry(task.destination.clone())
+                .or_insert_with(|| cost(plan, task));
+            highest.entry(task.destination.clone()).or_insert(rank);
+        }
+    }
+    let mut total: u64 = costs.values().sum();
+    if total <= budget {
+        return 0;
+    }
+
+    let mut cut_destinations = HashSet::new();
+    'playlists: for (rank, &index) in priority.iter().enumerate().rev() {
+        for entry in plan.playlists[index].entries.iter().rev() {
+            if total <= budget {
+                break 'playlists;
+            }
+            let destination = &plan.tasks[entry.task].destination;
+            if highest[destination] == rank && cut_destinations.insert(destination.clone()) {
+                total -= costs[destination];
+            }
+        }
+    }
+
+    let mut num_cut = 0;
+    for playlist in plan.playlists.iter_mut() {
+        let (kept, cut): (Vec<_>, Vec<_>) = std::mem::take(&mut playlist.entries)
+            .into_iter()
+            .partition(|entry| !cut_destinations.contains(&plan.tasks[entry.task].destination));
+        playlist.entries = kept;
+        if cut.is_empty() {
+            continue;
+        }
+        for entry in cut.iter() {
+            info!("{}: Cut {}", playlist.source.display(), entry.target);
+        }
+        warn!(
+            "{}: Cut {} entries ({})",
+            playlist.source.display(),
+            cut.len(),
+            reason
+        );
+        num_cut += cut.len();
+        playlist
+            .skipped
+            .extend(cut.into_iter().map(|entry| SkippedEntry {
+                entry: entry.target,
+                reason: reason.clone(),
+            }));
+    }
+
+    plan.remove_unreferenced_tasks();
+    num_cut
+}
+
+/// Returns the playlist indices ordered from highest to lowest priority.
+///
+/// Playlists listed in `preferred` come first (in that order), followed by all others in their
+/// original order.
+pub fn priority_order(plan: &Plan, preferred: &[PathBuf]) -> Vec<usize> {
+    let mut order: Vec<usize> = preferred
+        .iter()
+        .filter_map(|path| {
+            plan.playlists
+                .iter()
+                .position(|playlist| &playlist.source == path)
+        })
+        .collect();
+    for index in 0..plan.playlists.len() {
+        if !order.contains(&index) {
+            order.push(index);
+        }
+    }
+    order
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::plan::{ConflictPolicy, InputPlaylist, Planner};
+    use std::path::Path;
+
+    fn plan(playlists: &[(&str, &[&str])]) -> Plan {
+        let mut planner = Planner::new(Path::new("output"), ConflictPolicy::Error);
+        for (path, entries) in playlists {
+            planner
+                .add_playlist(&InputPlaylist {
+                    path: PathBuf::from(path),
+                    entries: entries.iter().map(PathBuf::from).collect(),
+                    skipped: vec![],
+                    title: None,
+                    durations: Default::default(),
+                })
+                .unwrap();
+        }
+        planner.finish()
+    }
+
+    fn targets(plan: &Plan, index: usize) -> Vec<&str> {
+        plan.playlists[index]
+            .entries
+            .iter()
+            .map(|entry| entry.target.as_str())
+            .collect()
+    }
+
+    #[test]
+    fn cuts_lowest_priority_playlist_from_the_end() {
+        let mut plan = plan(&[
+            ("a.m3u", &["1.mp3", "2.mp3"]),
+            ("b.m3u", &["3.mp3", "4.mp3", "5.mp3"]),
+        ]);
+        let priority = priority_order(&plan, &[]);
+        let cut = trim(
+            &mut plan,
+            &priority,
+            3,
+            |_, _| 1,
+            SkipReason::OverSizeBudget,
+        );
+        assert_eq!(cut, 2);
+        assert_eq!(targets(&plan, 0), vec!["1.mp3", "2.mp3"]);
+        assert_eq!(targets(&plan, 1), vec!["3.mp3"]);
+        assert_eq!(plan.tasks.len(), 3);
+    }
+
+    #[test]
+    fn shared_entries_are_only_freed_when_unreferenced() {
+        let mut plan = plan(&[("a.m3u", &["1.mp3", "2.mp3"]), ("b.m3u", &["2.mp3"])]);
+        let priority = priority_order(&plan, &[PathBuf::from("b.m3u")]);
+        trim(
+            &mut plan,
+            &priority,
+            1,
+            |_, _| 1,
+            SkipReason::OverSizeBudget,
+        );
+        assert_eq!(targets(&plan, 0), vec!["2.mp3"]);
+        assert_eq!(targets(&plan, 1), vec!["2.mp3"]);
+        assert_eq!(plan.playlists[0].skipped.len(), 1);
+    }
+
+    #[test]
+    fn cut_outputs_leave_all_lower_priority_playlists() {
+        let mut plan = plan(&[
+            ("a.m3u", &["1.mp3"]),
+            ("b.m3u", &["2.mp3", "3.mp3"]),
+            ("c.m3u", &["4.mp3", "3.mp3"]),
+        ]);
+        let priority = priority_order(&plan, &[]);
+        let cut = trim(
+            &mut plan,
+            &priority,
+            2,
+            |_, _| 1,
+            SkipReason::OverFileBudget,
+        );
+        assert_eq!(cut, 3);
+        assert_eq!(targets(&plan, 1), vec!["2.mp3"]);
+        assert!(targets(&plan, 2).is_empty());
+        assert_eq!(plan.tasks.len(), 2);
+    }
+}
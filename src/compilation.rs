@@ -0,0 +1,126 @@
+// Copyright (c) 2024 Jan Holthuis <jan.holthuis@rub.de>
+//
+// This Source Code Form is subject to the terms of the Mozilla Public License, v. 2.0. If a copy
+// of the MPL was not distributed with this file, You can obtain one at
+// http://mozilla.org/MPL/2.0/.
+//
+// SPDX-License-Identifier: MPL-2.0
+//! Detection of compilations, which Sync 2 splits into one album per track artist unless they
+//! have an album artist.
+use crate::probe::ProbeInfo;
+use log::{debug, info};
+use std::collections::{BTreeMap, BTreeSet, HashMap, HashSet};
+use std::path::{Path, PathBuf};
+
+/// Album artist of compilations unless configured otherwise.
+pub const DEFAULT_ARTIST: &str = "Various Artists";
+
+/// Returns `true` if the tags mark the file as part of a compilation.
+fn is_flagged(info: &ProbeInfo) -> bool {
+    info.tag("compilation")
+        .is_some_and(|flag| flag == "1" || flag.eq_ignore_ascii_case("true"))
+}
+
+/// Returns the sources that belong to compilations, i.e. albums whose tracks in the same
+/// directory have different artists, or that carry the compilation flag.
+///
+/// The decision for every album is logged.
+pub fn find(metadata: &HashMap<PathBuf, ProbeInfo>) -> HashSet<PathBuf> {
+    let mut albums: BTreeMap<(&Path, String), Vec<(&PathBuf, &ProbeInfo)>> = BTreeMap::new();
+    for (source, info) in metadata.iter() {
+        let Some(album) = info.tag("album") else {
+            continue;
+        };
+        let dir = source.parent().unwrap_or(Path::new(""));
+        albums
+            .entry((dir, album.to_lowercase()))
+            .or_default()
+            .push((source, info));
+    }
+
+    let mut compilations = HashSet::new();
+    for ((dir, _), tracks) in albums {
+        let album = tracks[0].1.tag("album").unwrap_or_default();
+        let artists: BTreeSet<_> = tracks
+            .iter()
+            .filter_map(|(_, info)| info.tag("artist"))
+            .map(str::to_lowercase)
+            .collect();
+        let reason = if tracks.iter().any(|(_, info)| is_flagged(info)) {
+            "compilation flag".to_string()
+        } else if artists.len() > 1 {
+            format!("{} track artists", artists.len())
+        } else {
+            debug!(
+                "{}: Album `{}` is not a compilation (one track artist)",
+                dir.display(),
+                album
+            );
+            continue;
+        };
+        info!(
+            "{}: Treating album `{}` as a compilation ({})",
+            dir.display(),
+            album,
+            reason
+        );
+        compilations.extend(tracks.into_iter().map(|(source, _)| source.clone()));
+    }
+    compilations
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn info(tags: &[(&str, &str)]) -> ProbeInfo {
+        ProbeInfo {
+            tags: tags
+                .iter()
+                .map(|(name, value)| (name.to_string(), value.to_string()))
+                .collect(),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn finds_albums_with_several_artists_or_the_flag() {
+        let metadata = HashMap::from([
+            (
+                PathBuf::from("Hits/1.flac"),
+                info(&[("album", "Hits"), ("artist", "A")]),
+            ),
+            (
+                PathBuf::from("Hits/2.flac"),
+                info(&[("album", "hits"), ("artist", "B")]),
+            ),
+            (
+                PathBuf::from("Solo/1.flac"),
+                info(&[("album", "Solo"), ("artist", "A")]),
+            ),
+            (
+                PathBuf::from("Solo/2.flac"),
+                info(&[("album", "Solo"), ("artist", "a")]),
+            ),
+            (
+                PathBuf::from("Mix/1.mp3"),
+                info(&[("album", "Mix"), ("artist", "A"), ("compilation", "1")]),
+            ),
+            (
+                PathBuf::from("Other/1.mp3"),
+                info(&[("album", "Hits"), ("artist", "C")]),
+            ),
+            (PathBuf::from("Loose/1.mp3"), info(&[("artist", "C")])),
+        ]);
+        let mut compilations: Vec<_> = find(&metadata).into_iter().collect();
+        compilations.sort();
+        assert_eq!(
+            compilations,
+            [
+                PathBuf::from("Hits/1.flac"),
+                PathBuf::from("Hits/2.flac"),
+                PathBuf::from("Mix/1.mp3"),
+            ]
+        );
+    }
+}
@@ -0,0 +1,654 @@
+// Copyright (c) 2024 Jan Holthuis <jan.holthuis@rub.de>
+//
+// This Source Code Form is subject to the terms of the Mozilla Public License, v. 2.0. If a copy
+// of the MPL was not distributed with this file, You can obtain one at
+// http://mozilla.org/MPL/2.0/.
+//
+// SPDX-License-Identifier: MPL-2.0
+//! TOML configuration files.
+//!
+//! Every key of the configuration file corresponds to the command line option of the same name
+//! (e.g. `output_dir = "/media/usb"` for `--output-dir /media/usb`), and so does every
+//! `FORD_SYNC_*` environment variable (e.g. `FORD_SYNC_OUTPUT_DIR`). Options given on the command
+//! line take precedence over the environment, which takes precedence over the configuration file,
+//! which takes precedence over the `--preset`. The only exception is the `[rules]` section, see
+//! [`crate::rules`].
+use crate::preset::Preset;
+use crate::rules::Rules;
+use clap::parser::ValueSource;
+use clap::{Arg, ArgAction, ArgMatches, Command};
+use std::collections::HashMap;
+use std::ffi::OsString;
+use std::fmt;
+use std::path::{Path, PathBuf};
+use toml::{Table, Value};
+
+/// Arguments that cannot be set in the configuration file.
+const EXCLUDED_ARGS: [&str; 4] = ["config", "print_config", "help", "version"];
+
+/// Key of the section with the encoder settings per source extension or codec.
+const RULES_KEY: &str = "rules";
+
+/// Returns the path of the default configuration file, i.e.
+/// `~/.config/ford-sync-convert/config.toml`.
+pub fn default_path() -> Option<PathBuf> {
+    let config_dir = std::env::var_os("XDG_CONFIG_HOME")
+        .filter(|dir| !dir.is_empty())
+        .map(PathBuf::from)
+        .or_else(|| std::env::var_os("HOME").map(|home| PathBuf::from(home).join(".config")))?;
+    Some(config_dir.join("ford-sync-convert").join("config.toml"))
+}
+
+#[derive(Debug)]
+pub enum ConfigError {
+    /// The configuration file could not be read.
+    Io {
+        path: PathBuf,
+        source: std::io::Error,
+    },
+    /// The configuration file is not valid TOML.
+    Parse { path: PathBuf, message: String },
+    /// The configuration file contains a key that is not an option.
+    UnknownKey { path: PathBuf, key: String },
+    /// The value of a key has the wrong type.
+    InvalidValue {
+        path: PathBuf,
+        key: String,
+        expected: &'static str,
+    },
+    /// A rule of the `[rules]` section is invalid.
+    InvalidRule {
+        path: PathBuf,
+        key: String,
+        message: String,
+    },
+    /// The value of an environment variable is invalid.
+    InvalidEnv { var: String, expected: &'static str },
+}
+
+impl fmt::Display for ConfigError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ConfigError::Io { path, source } => write!(
+                f,
+                "{}: Failed to read configuration file ({})",
+                path.display(),
+                source
+            ),
+            ConfigError::Parse { path, message } => write!(
+                f,
+                "{}: Failed to parse configuration file ({})",
+                path.display(),
+                message.trim()
+            ),
+            ConfigError::UnknownKey { path, key } => {
+                write!(f, "{}: Unknown configuration key `{}`", path.display(), key)
+            }
+            ConfigError::InvalidValue {
+                path,
+                key,
+                expected,
+            } => write!(
+                f,
+                "{}: Invalid value for configuration key `{}` (expected {})",
+                path.display(),
+                key,
+                expected
+            ),
+            ConfigError::InvalidRule { path, key, message } => write!(
+                f,
+                "{}: Invalid rule `{}` ({})",
+                path.display(),
+                key,
+                message
+            ),
+            ConfigError::InvalidEnv { var, expected } => write!(
+                f,
+                "Invalid value for environment variable {} (expected {})",
+                var, expected
+            ),
+        }
+    }
+}
+
+impl std::error::Error for ConfigError {}
+
+/// Read and parse a configuration file.
+pub fn load(path: &Path) -> Result<Table, ConfigError> {
+    let contents = std::fs::read_to_string(path).map_err(|source| ConfigError::Io {
+        path: path.to_path_buf(),
+        source,
+    })?;
+    contents
+        .parse()
+        .map_err(|e: toml::de::Error| ConfigError::Parse {
+            path: path.to_path_buf(),
+            message: e.to_string(),
+        })
+}
+
+/// Returns the argument that a configuration key corresponds to.
+fn find_arg<'a>(command: &'a Command, key: &str) -> Option<&'a Arg> {
+    let id = key.replace('-', "_");
+    command
+        .get_arguments()
+        .find(|arg| arg.get_id() == id.as_str())
+        .filter(|arg| !EXCLUDED_ARGS.contains(&arg.get_id().as_str()))
+}
+
+/// Render a scalar value as a command line value.
+fn scalar(value: &Value) -> Option<String> {
+    match value {
+        Value::String(value) => Some(value.clone()),
+        Value::Integer(value) => Some(value.to_string()),
+        Value::Float(value) => Some(value.to_string()),
+        _ => None,
+    }
+}
+
+/// Command line arguments derived from the configuration file or the environment.
+#[derive(Debug, Default)]
+pub struct Args {
+    /// Options as `--name=value`, to be placed before the actual command line arguments.
+    pub options: Vec<OsString>,
+    /// Positional arguments, to be placed after the actual command line arguments.
+    pub positionals: Vec<OsString>,
+    /// IDs of the arguments that were set.
+    pub ids: Vec<String>,
+}
+
+impl Args {
+    /// Add the arguments for setting `arg` to `value`, or return what kind of value was expected.
+    fn push(&mut self, arg: &Arg, value: &Value) -> Result<(), &'static str> {
+        let long = arg.get_long().map(|long| format!("--{}", long));
+        if matches!(arg.get_action(), ArgAction::Count) {
+            let count = scalar(value)
+                .and_then(|count| count.parse::<u8>().ok())
+                .ok_or("a number")?;
+            self.options.extend(
+                long.map(OsString::from)
+                    .into_iter()
+                    .cycle()
+                    .take(count.into()),
+            );
+            self.ids.push(arg.get_id().to_string());
+            return Ok(());
+        }
+        if matches!(arg.get_action(), ArgAction::SetTrue) {
+            match value {
+                Value::Boolean(true) => self.options.extend(long.map(OsString::from)),
+                Value::Boolean(false) => (),
+                _ => return Err("a boolean"),
+            }
+            self.ids.push(arg.get_id().to_string());
+            return Ok(());
+        }
+        let accepts_flag = arg
+            .get_num_args()
+            .is_some_and(|range| range.min_values() == 0);
+        let values: Vec<String> = match value {
+            Value::Boolean(true) if accepts_flag => {
+                self.options.extend(long.map(OsString::from));
+                self.ids.push(arg.get_id().to_string());
+                return Ok(());
+            }
+            Value::Array(values) if matches!(arg.get_action(), ArgAction::Append) => values
+                .iter()
+                .map(scalar)
+                .collect::<Option<_>>()
+                .ok_or("an array of strings or numbers")?,
+            value => vec![scalar(value).ok_or("a string or number")?],
+        };
+        match long {
+            Some(long) => self.options.extend(
+                values
+                    .into_iter()
+                    .map(|value| OsString::from(format!("{}={}", long, value))),
+            ),
+            None => self
+                .positionals
+                .extend(values.into_iter().map(OsString::from)),
+        }
+        self.ids.push(arg.get_id().to_string());
+        Ok(())
+    }
+}
+
+/// Convert the configuration into command line arguments for all options that are not given on
+/// the command line already.
+pub fn to_args(
+    command: &Command,
+    matches: &ArgMatches,
+    path: &Path,
+    config: &Table,
+) -> Result<Args, ConfigError> {
+    let mut args = Args::default();
+    for (key, value) in config.iter().filter(|(key, _)| *key != RULES_KEY) {
+        let Some(arg) = find_arg(command, key) else {
+            return Err(ConfigError::UnknownKey {
+                path: path.to_path_buf(),
+                key: key.clone(),
+            });
+        };
+        if matches.value_source(arg.get_id().as_str()) == Some(ValueSource::CommandLine) {
+            continue;
+        }
+        args.push(arg, value)
+            .map_err(|expected| ConfigError::InvalidValue {
+                path: path.to_path_buf(),
+                key: key.clone(),
+                expected,
+            })?;
+    }
+    Ok(args)
+}
+
+/// Read the encoder settings of the `[rules]` section of the configuration.
+pub fn rules(path: &Path, config: &Table) -> Result<Rules, ConfigError> {
+    let Some(value) = config.get(RULES_KEY) else {
+        return Ok(Rules::default());
+    };
+    let Value::Table(table) = value else {
+        return Err(ConfigError::InvalidValue {
+            path: path.to_path_buf(),
+            key: RULES_KEY.to_string(),
+            expected: "a table",
+        });
+    };
+    Rules::from_table(table).map_err(|(key, message)| ConfigError::InvalidRule {
+        path: path.to_path_buf(),
+        key,
+        message,
+    })
+}
+
+/// Convert the settings of the preset into command line arguments for all options that are not
+/// given on the command line or set in `sources` already.
+pub fn preset_args(
+    command: &Command,
+    matches: &ArgMatches,
+    sources: &HashMap<String, Source>,
+    preset: Preset,
+) -> Args {
+    let mut settings = preset.settings();
+    settings.retain(|key, _| !sources.contains_key(key));
+    to_args(command, matches, Path::new(&preset.to_string()), &settings)
+        .expect("presets only set valid options")
+}
+
+/// Prefix of the environment variables that set options.
+const ENV_PREFIX: &str = "FORD_SYNC_";
+
+/// Returns the name of the environment variable for the argument `id`, e.g.
+/// `FORD_SYNC_OUTPUT_DIR` for `output_dir`.
+pub fn env_var(id: &str) -> String {
+    format!("{}{}", ENV_PREFIX, id.to_ascii_uppercase())
+}
+
+/// Parse the value of an environment variable for `arg`.
+///
+/// Flags accept `1`/`true`/`yes`/`on` and `0`/`false`/`no`/`off`, options with an optional value
+/// are set without a value if the variable is empty, and lists are separated like `PATH` (by `:`
+/// on Unix and `;` on Windows).
+fn env_value(arg: &Arg, value: &std::ffi::OsStr) -> Result<Value, &'static str> {
+    const UTF8: &str = "a UTF-8 string";
+    if matches!(arg.get_action(), ArgAction::SetTrue) {
+        return match value.to_str().ok_or(UTF8)?.to_ascii_lowercase().as_str() {
+            "1" | "true" | "yes" | "on" => Ok(Value::Boolean(true)),
+            "" | "0" | "false" | "no" | "off" => Ok(Value::Boolean(false)),
+            _ => Err("`1`, `true`, `yes`, `on`, `0`, `false`, `no` or `off`"),
+        };
+    }
+    let accepts_flag = arg
+        .get_num_args()
+        .is_some_and(|range| range.min_values() == 0);
+    if value.is_empty() && accepts_flag {
+        return Ok(Value::Boolean(true));
+    }
+    if matches!(arg.get_action(), ArgAction::Append) {
+        return std::env::split_paths(value)
+            .map(|value| value.into_os_string().into_string().ok().map(Value::String))
+            .collect::<Option<_>>()
+            .map(Value::Array)
+            .ok_or(UTF8);
+    }
+    value
+        .to_str()
+        .map(|value| Value::String(value.to_string()))
+        .ok_or(UTF8)
+}
+
+/// Convert the `FORD_SYNC_*` environment variables into command line arguments for all options
+/// that are not given on the command line already.
+pub fn env_args(
+    command: &Command,
+    matches: &ArgMatches,
+    vars: impl Fn(&str) -> Option<OsString>,
+) -> Result<Args, ConfigError> {
+    let mut args = Args::default();
+    for arg in command.get_arguments() {
+        let id = arg.get_id().as_str();
+        if matches!(id, "help" | "version")
+            || matches.value_source(id) == Some(ValueSource::CommandLine)
+        {
+            continue;
+        }
+        let var = env_var(id);
+        let Some(value) = vars(&var) else {
+            continue;
+        };
+        let invalid = |expected| ConfigError::InvalidEnv {
+            var: var.clone(),
+            expected,
+        };
+        let value = env_value(arg, &value).map_err(invalid)?;
+        args.push(arg, &value).map_err(invalid)?;
+    }
+    Ok(args)
+}
+
+/// Where the value of an option comes from.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Source {
+    Default,
+    ConfigFile(PathBuf),
+    PlanFile(PathBuf),
+    Environment(String),
+    Preset(Preset),
+    CommandLine,
+}
+
+impl fmt::Display for Source {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Source::Default => f.write_str("default"),
+            Source::ConfigFile(path) => write!(f, "config file {}", path.display()),
+            Source::PlanFile(path) => write!(f, "plan file {}", path.display()),
+            Source::Environment(var) => write!(f, "environment variable {}", var),
+            Source::Preset(preset) => write!(f, "preset {}", preset),
+            Source::CommandLine => f.write_str("command line"),
+        }
+    }
+}
+
+/// Render a command line value as a TOML value.
+fn toml_value(value: &str) -> Value {
+    if let Ok(value) = value.parse::<i64>() {
+        Value::Integer(value)
+    } else if let Some(value) = value.parse::<f64>().ok().filter(|value| value.is_finite()) {
+        Value::Float(value)
+    } else {
+        Value::String(value.to_string())
+    }
+}
+
+/// Returns the effective configuration, i.e. the values of all options after merging the
+/// command line, the environment, the configuration file and the defaults.
+///
+/// `sources` maps the IDs of options that were set from the environment or the configuration
+/// file to their source. All other options that are set come from the command line.
+pub fn effective(
+    command: &Command,
+    matches: &ArgMatches,
+    sources: &HashMap<String, Source>,
+) -> Vec<(String, Value, Source)> {
+    let mut settings = vec![];
+    for arg in command.get_arguments() {
+        let id = arg.get_id().as_str();
+        if EXCLUDED_ARGS.contains(&id) {
+            continue;
+        }
+        let value = if matches!(arg.get_action(), ArgAction::SetTrue) {
+            Value::Boolean(matches.get_flag(id))
+        } else if matches!(arg.get_action(), ArgAction::Count) {
+            Value::Integer(matches.get_count(id).into())
+        } else {
+            let Some(values) = matches.get_raw(id) else {
+                continue;
+            };
+            let mut values = values.map(|value| toml_value(&value.to_string_lossy()));
+            if matches!(arg.get_action(), ArgAction::Append) {
+                Value::Array(values.collect())
+            } else {
+                let Some(value) = values.next() else {
+                    continue;
+                };
+                value
+            }
+        };
+        let source = match sources.get(id) {
+            Some(source) => source.clone(),
+            None if matches.value_source(id) == Some(ValueSource::CommandLine) => {
+                Source::CommandLine
+            }
+            None => Source::Default,
+        };
+        settings.push((id.to_string(), value, source));
+    }
+    settings.sort_by(|a, b| a.0.cmp(&b.0));
+    settings
+}
+
+/// Render the effective configuration as TOML, with the source of every value in a comment.
+pub fn render(settings: &[(String, Value, Source)]) -> String {
+    settings
+        .iter()
+        .map(|(key, value, source)| format!("{} = {} # {}\n", key, value, source))
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn command() -> Command {
+        Command::new("test")
+            .arg(Arg::new("config").long("config"))
+            .arg(
+                Arg::new("output_dir")
+                    .long("output-dir")
+                    .default_value("output"),
+            )
+            .arg(Arg::new("jobs").long("jobs"))
+            .arg(
+                Arg::new("dry_run")
+                    .long("dry-run")
+                    .action(ArgAction::SetTrue),
+            )
+            .arg(
+                Arg::new("flatten")
+                    .long("flatten")
+                    .num_args(0..=1)
+                    .require_equals(true)
+                    .default_missing_value("2"),
+            )
+            .arg(
+                Arg::new("verbose")
+                    .short('v')
+                    .long("verbose")
+                    .action(ArgAction::Count),
+            )
+            .arg(
+                Arg::new("priority")
+                    .long("priority")
+                    .action(ArgAction::Append),
+            )
+            .arg(
+                Arg::new("playlists")
+                    .num_args(1..)
+                    .action(ArgAction::Append),
+            )
+    }
+
+    fn config(contents: &str) -> Table {
+        contents.parse().unwrap()
+    }
+
+    fn args(cli: &[&str], contents: &str) -> Result<Args, ConfigError> {
+        let command = command();
+        let matches = command.clone().get_matches_from(cli);
+        to_args(
+            &command,
+            &matches,
+            Path::new("config.toml"),
+            &config(contents),
+        )
+    }
+
+    #[test]
+    fn converts_keys_to_options() {
+        let Args {
+            options,
+            positionals,
+            ids,
+        } = args(
+            &["test"],
+            r#"
+                output_dir = "/media/usb"
+                jobs = 4
+                dry-run = true
+                flatten = true
+                priority = ["a.m3u", "b.m3u"]
+                playlists = ["a.m3u"]
+            "#,
+        )
+        .unwrap();
+        assert_eq!(
+            options,
+            [
+                "--dry-run",
+                "--flatten",
+                "--jobs=4",
+                "--output-dir=/media/usb",
+                "--priority=a.m3u",
+                "--priority=b.m3u"
+            ]
+        );
+        assert_eq!(positionals, ["a.m3u"]);
+        assert_eq!(ids.len(), 6);
+    }
+
+    #[test]
+    fn command_line_takes_precedence() {
+        let args = args(&["test", "--jobs", "2"], "jobs = 4\ndry_run = false").unwrap();
+        assert!(args.options.is_empty());
+        assert_eq!(args.ids, ["dry_run"]);
+    }
+
+    #[test]
+    fn repeats_counted_flags() {
+        let args = args(&["test"], "verbose = 2").unwrap();
+        assert_eq!(args.options, ["--verbose", "--verbose"]);
+    }
+
+    #[test]
+    fn rejects_unknown_keys() {
+        let result = args(&["test"], "jbos = 4");
+        assert!(matches!(result, Err(ConfigError::UnknownKey { key, .. }) if key == "jbos"));
+        let result = args(&["test"], "config = \"other.toml\"");
+        assert!(matches!(result, Err(ConfigError::UnknownKey { .. })));
+    }
+
+    #[test]
+    fn reads_rules_separately() {
+        let contents = "jobs = 4\n[rules]\nflac = { quality = 0 }\n";
+        assert_eq!(args(&["test"], contents).unwrap().ids, ["jobs"]);
+        let path = Path::new("config.toml");
+        let rules = rules(path, &config(contents)).unwrap();
+        assert!(rules.find(Path::new("a.flac"), None).is_some());
+        let result = super::rules(path, &config("[rules]\nflac = { quality = 10 }"));
+        assert!(matches!(result, Err(ConfigError::InvalidRule { key, .. }) if key == "flac"));
+    }
+
+    #[test]
+    fn rejects_invalid_values() {
+        let result = args(&["test"], "dry_run = \"yes\"");
+        assert!(matches!(result, Err(ConfigError::InvalidValue { .. })));
+    }
+
+    fn env_args_from(cli: &[&str], vars: &[(&str, &str)]) -> Result<Args, ConfigError> {
+        let command = command();
+        let matches = command.clone().get_matches_from(cli);
+        env_args(&command, &matches, |var| {
+            vars.iter()
+                .find(|(name, _)| *name == var)
+                .map(|(_, value)| OsString::from(value))
+        })
+    }
+
+    #[test]
+    fn converts_environment_variables_to_options() {
+        let list = std::env::join_paths(["a.m3u", "b.m3u"]).unwrap();
+        let list = list.to_str().unwrap();
+        let args = env_args_from(
+            &["test", "--jobs=2"],
+            &[
+                ("FORD_SYNC_OUTPUT_DIR", "/media/usb"),
+                ("FORD_SYNC_JOBS", "4"),
+                ("FORD_SYNC_DRY_RUN", "yes"),
+                ("FORD_SYNC_FLATTEN", ""),
+                ("FORD_SYNC_PRIORITY", list),
+                ("FORD_SYNC_PLAYLISTS", list),
+            ],
+        )
+        .unwrap();
+        assert_eq!(
+            args.options,
+            [
+                "--output-dir=/media/usb",
+                "--dry-run",
+                "--flatten",
+                "--priority=a.m3u",
+                "--priority=b.m3u"
+            ]
+        );
+        assert_eq!(args.positionals, ["a.m3u", "b.m3u"]);
+    }
+
+    #[test]
+    fn rejects_invalid_environment_variables() {
+        let result = env_args_from(&["test"], &[("FORD_SYNC_DRY_RUN", "maybe")]);
+        assert!(
+            matches!(result, Err(ConfigError::InvalidEnv { var, .. }) if var == "FORD_SYNC_DRY_RUN")
+        );
+    }
+
+    #[test]
+    fn effective_configuration_includes_defaults() {
+        let command = command();
+        let matches =
+            command
+                .clone()
+                .get_matches_from(["test", "--jobs=4", "--priority=a.m3u", "b.m3u"]);
+        let sources = HashMap::from([(
+            "priority".to_string(),
+            Source::Environment(env_var("priority")),
+        )]);
+        let settings = effective(&command, &matches, &sources);
+        let setting = |key: &str| {
+            settings
+                .iter()
+                .find(|(name, _, _)| name == key)
+                .map(|(_, value, source)| (value.clone(), source.clone()))
+        };
+        assert_eq!(
+            setting("output_dir"),
+            Some((Value::String("output".to_string()), Source::Default))
+        );
+        assert_eq!(
+            setting("jobs"),
+            Some((Value::Integer(4), Source::CommandLine))
+        );
+        assert_eq!(
+            setting("priority").map(|(_, source)| source),
+            Some(Source::Environment("FORD_SYNC_PRIORITY".to_string()))
+        );
+        assert_eq!(
+            setting("dry_run"),
+            Some((Value::Boolean(false), Source::Default))
+        );
+        assert_eq!(setting("flatten"), None);
+        assert_eq!(setting("config"), None);
+        assert!(render(&settings).contains("jobs = 4 # command line\n"));
+    }
+}
@@ -0,0 +1,52 @@
+// Copyright (c) 2024 Jan Holthuis <jan.holthuis@rub.de>
+//
+// This Source Code Form is subject to the terms of the Mozilla Public License, v. 2.0. If a copy
+// of the MPL was not distributed with this file, You can obtain one at
+// http://mozilla.org/MPL/2.0/.
+//
+// SPDX-License-Identifier: MPL-2.0
+//! Interactive confirmation of destructive steps.
+use std::io::{BufRead, IsTerminal, Write};
+
+/// The user's answer to a question.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Answer {
+    Yes,
+    No,
+    /// Stdin is not a terminal, so nobody could be asked.
+    NotInteractive,
+}
+
+/// Ask a yes/no question on the terminal. Anything but `y` or `yes` counts as no.
+pub fn ask(question: &str) -> Answer {
+    let stdin = std::io::stdin();
+    if !stdin.is_terminal() {
+        return Answer::NotInteractive;
+    }
+    let mut stderr = std::io::stderr();
+    let _ = write!(stderr, "{} [y/N] ", question);
+    let _ = stderr.flush();
+    let mut line = String::new();
+    match stdin.lock().read_line(&mut line) {
+        Ok(_) if is_yes(&line) => Answer::Yes,
+        _ => Answer::No,
+    }
+}
+
+fn is_yes(answer: &str) -> bool {
+    matches!(answer.trim().to_lowercase().as_str(), "y" | "yes")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn only_yes_confirms() {
+        assert!(is_yes("y\n"));
+        assert!(is_yes(" YES "));
+        assert!(!is_yes("\n"));
+        assert!(!is_yes("n"));
+        assert!(!is_yes("yep"));
+    }
+}
@@ -0,0 +1,362 @@
+// Copyright (c) 2024 Jan Holthuis <jan.holthuis@rub.de>
+//
+// This Source Code Form is subject to the terms of the Mozilla Public License, v. 2.0. If a copy
+// of the MPL was not distributed with this file, You can obtain one at
+// http://mozilla.org/MPL/2.0/.
+//
+// SPDX-License-Identifier: MPL-2.0
+//! Merging of different sources of the same track, e.g. from an album and a compilation.
+use crate::fingerprint::similarity;
+use crate::plan::Plan;
+use crate::probe::ProbeInfo;
+use clap::ValueEnum;
+use log::info;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::path::PathBuf;
+
+/// Tags that identify a track across sources.
+#[derive(ValueEnum, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum DedupeKey {
+    #[value(name = "artist+title")]
+    ArtistTitle,
+}
+
+/// A source that was dropped in favour of another one with the same tags.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Duplicate {
+    pub dropped: PathBuf,
+    pub kept: PathBuf,
+    /// Why both sources are the same track, e.g. the normalized tags that they share.
+    pub key: String,
+}
+
+/// Normalize a tag value for comparison, ignoring case, punctuation and whitespace.
+fn normalize(value: &str) -> String {
+    value
+        .chars()
+        .map(|c| if c.is_alphanumeric() { c } else { ' ' })
+        .flat_map(char::to_lowercase)
+        .collect::<String>()
+        .split_whitespace()
+        .collect::<Vec<_>>()
+        .join(" ")
+}
+
+impl DedupeKey {
+    /// Returns the normalized key of a source, or `None` if it lacks one of the tags.
+    fn of(self, info: &ProbeInfo) -> Option<String> {
+        match self {
+            DedupeKey::ArtistTitle => {
+                let artist = normalize(info.tag("artist")?);
+                let title = normalize(info.tag("title")?);
+                (!artist.is_empty() && !title.is_empty()).then(|| format!("{} - {}", artist, title))
+            }
+        }
+    }
+}
+
+/// Keep only one source per key across the plan and point all playlist entries of the others at
+/// it. The source with the highest bit rate is kept, or the first one if the bit rates are equal
+/// or unknown. The parts of split sources are left alone, as they share the tags of the source.
+///
+/// The merged duplicates are recorded in [`Plan::duplicates`] and returned.
+pub fn dedupe(plan: &mut Plan, key: DedupeKey) -> Vec<Duplicate> {
+    let mut groups: HashMap<String, Vec<usize>> = HashMap::new();
+    let mut keys: Vec<String> = vec![];
+    for (index, task) in plan.tasks.iter().enumerate() {
+        let Some(key) = plan
+            .metadata
+            .get(&task.source)
+            .filter(|_| task.segment.is_none())
+            .and_then(|info| key.of(info))
+        else {
+            continue;
+        };
+        groups
+            .entry(key.clone())
+            .or_insert_with(|| {
+                keys.push(key);
+                vec![]
+            })
+            .push(index);
+    }
+    let groups = keys
+        .into_iter()
+        .map(|key| {
+            let tasks = groups.remove(&key).unwrap_or_default();
+            (key, tasks)
+        })
+        .collect();
+    merge(plan, groups, |_, key, _, _| key.to_string())
+}
+
+/// Like [`dedupe`], but compares the acoustic fingerprints of the sources instead of their tags.
+///
+/// Sources are considered the same track if the [`similarity`] of their fingerprints is at least
+/// `threshold`. Sources without a fingerprint are left alone.
+pub fn dedupe_by_fingerprint(plan: &mut Plan, threshold: f64) -> Vec<Duplicate> {
+    // The tasks of each cluster, compared by the fingerprint of the first one.
+    let mut clusters: Vec<(&[u32], Vec<usize>)> = vec![];
+    for (index, task) in plan.tasks.iter().enumerate() {
+        let Some(fingerprint) = plan
+            .metadata
+            .get(&task.source)
+            .filter(|_| task.segment.is_none())
+            .and_then(|info| info.fingerprint.as_deref())
+            .filter(|fingerprint| !fingerprint.is_empty())
+        else {
+            continue;
+        };
+        match clusters
+            .iter_mut()
+            .find(|(first, _)| similarity(first, fingerprint) >= threshold)
+        {
+            Some((_, tasks)) => tasks.push(index),
+            None => clusters.push((fingerprint, vec![index])),
+        }
+    }
+    let groups = clusters
+        .into_iter()
+        .map(|(_, tasks)| (String::new(), tasks))
+        .collect();
+    merge(plan, groups, |plan, _, dropped, kept| {
+        let fingerprint = |task: usize| {
+            plan.metadata[&plan.tasks[task].source]
+                .fingerprint
+                .as_deref()
+                .unwrap_or_default()
+        };
+        format!(
+            "fingerprints {:.0}% similar",
+            similarity(fingerprint(dropped), fingerprint(kept)) * 100.0
+        )
+    })
+}
+
+/// Point the playlist entries of each group of tasks at the one with the highest bit rate and
+/// remove the others. `describe` returns the [`Duplicate::key`] for the group's key and the tasks
+/// that are dropped and kept.
+fn merge(
+    plan: &mut Plan,
+    groups: Vec<(String, Vec<usize>)>,
+    describe: impl Fn(&Plan, &str, usize, usize) -> String,
+) -> Vec<Duplicate> {
+    let bit_rate = |plan: &Plan, task: usize| {
+        plan.metadata
+            .get(&plan.tasks[task].source)
+            .and_then(|info| info.bit_rate)
+            .unwrap_or(0)
+    };
+    let mut replacements: HashMap<usize, usize> = HashMap::new();
+    let mut keys: HashMap<usize, String> = HashMap::new();
+    for (key, tasks) in groups.iter() {
+        let Some(&first) = tasks.first() else {
+            continue;
+        };
+        let best = tasks.iter().copied().fold(first, |best, index| {
+            if bit_rate(plan, index) > bit_rate(plan, best) {
+                index
+            } else {
+                best
+            }
+        });
+        for &index in tasks {
+            if plan.tasks[best].destination != plan.tasks[index].destination {
+                replacements.insert(index, best);
+                keys.insert(index, describe(plan, key, index, best));
+            }
+        }
+    }
+    if replacements.is_empty() {
+        return vec![];
+    }
+
+    let targets: HashMap<usize, String> = plan
+        .playlists
+        .iter()
+        .flat_map(|playlist| &playlist.entries)
+        .map(|entry| (entry.task, entry.target.clone()))
+        .collect();
+    for entry in plan
+        .playlists
+        .iter_mut()
+        .flat_map(|playlist| &mut playlist.entries)
+    {
+        if let Some(&best) = replacements.get(&entry.task) {
+            entry.task = best;
+            entry.target = targets[&best].clone();
+        }
+    }
+
+    let mut duplicates: Vec<Duplicate> = vec![];
+    let mut replaced: Vec<_> = replacements.into_iter().collect();
+    replaced.sort();
+    for (index, best) in replaced {
+        let dropped = &plan.tasks[index].source;
+        let kept = &plan.tasks[best].source;
+        if duplicates
+            .iter()
+            .any(|duplicate| &duplicate.dropped == dropped)
+        {
+            continue;
+        }
+        info!(
+            "{}: Dropped as duplicate of {} ({})",
+            dropped.display(),
+            kept.display(),
+            keys[&index]
+        );
+        duplicates.push(Duplicate {
+            dropped: dropped.clone(),
+            kept: kept.clone(),
+            key: keys[&index].clone(),
+        });
+    }
+    plan.remove_unreferenced_tasks();
+    plan.duplicates.extend(duplicates.iter().cloned());
+    duplicates
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::plan::{Action, OutputEntry, PlaylistOutput, Task};
+    use crate::tags::TagOverrides;
+
+    fn source(artist: &str, title: &str, bit_rate: Option<u64>) -> ProbeInfo {
+        ProbeInfo {
+            tags: HashMap::from([
+                ("artist".to_string(), artist.to_string()),
+                ("title".to_string(), title.to_string()),
+            ]),
+            bit_rate,
+            ..Default::default()
+        }
+    }
+
+    fn plan(sources: &[(&str, ProbeInfo)], playlists: &[&[usize]]) -> Plan {
+        Plan {
+            tasks: sources
+                .iter()
+                .map(|(name, _)| Task {
+                    action: Action::Copy,
+                    source: PathBuf::from(format!("music/{}.mp3", name)),
+                    destination: PathBuf::from(format!("out/{}.mp3", name)),
+                    tags: TagOverrides::default(),
+                    encoder: None,
+                    segment: None,
+                })
+                .collect(),
+            playlists: playlists
+                .iter()
+                .enumerate()
+                .map(|(index, entries)| PlaylistOutput {
+                    source: PathBuf::from(format!("{}.m3u", index)),
+                    path: PathBuf::from(format!("out/{}.m3u", index)),
+                    entries: entries
+                        .iter()
+                        .map(|&task| OutputEntry {
+                            target: format!("{}.mp3", sources[task].0),
+                            task,
+                        })
+                        .collect(),
+                    skipped: vec![],
+                    title: None,
+                })
+                .collect(),
+            metadata: sources
+                .iter()
+                .map(|(name, info)| (PathBuf::from(format!("music/{}.mp3", name)), info.clone()))
+                .collect(),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn keeps_the_highest_bit_rate_and_rewrites_entries() {
+        let mut plan = plan(
+            &[
+                ("album", source("Queen", "Bohemian Rhapsody", Some(192_000))),
+                (
+                    "hits",
+                    source("QUEEN", "Bohemian  Rhapsody!", Some(320_000)),
+                ),
+                ("live", source("Queen", "Bohemian Rhapsody (Live)", None)),
+                ("other", source("Queen", "Other", None)),
+            ],
+            &[&[0, 3], &[1, 2]],
+        );
+        let duplicates = dedupe(&mut plan, DedupeKey::ArtistTitle);
+        assert_eq!(
+            duplicates,
+            [Duplicate {
+                dropped: PathBuf::from("music/album.mp3"),
+                kept: PathBuf::from("music/hits.mp3"),
+                key: "queen - bohemian rhapsody".to_string(),
+            }]
+        );
+        assert_eq!(plan.duplicates, duplicates);
+        let targets: Vec<Vec<&str>> = plan
+            .playlists
+            .iter()
+            .map(|playlist| {
+                playlist
+                    .entries
+                    .iter()
+                    .map(|entry| entry.target.as_str())
+                    .collect()
+            })
+            .collect();
+        assert_eq!(
+            targets,
+            [vec!["hits.mp3", "other.mp3"], vec!["hits.mp3", "live.mp3"]]
+        );
+        assert_eq!(plan.tasks.len(), 3);
+    }
+
+    #[test]
+    fn merges_sources_with_similar_fingerprints() {
+        let fingerprint: Vec<u32> = (0..32u32).map(|i| i.wrapping_mul(0x9e37_79b9)).collect();
+        let mut similar = fingerprint.clone();
+        similar[5] ^= 0xff;
+        let other: Vec<u32> = (0..32u32)
+            .map(|i| i.wrapping_mul(0x85eb_ca6b) ^ 0x5555)
+            .collect();
+        let with = |fingerprint: &[u32], bit_rate| ProbeInfo {
+            fingerprint: Some(fingerprint.to_vec()),
+            bit_rate,
+            ..Default::default()
+        };
+        let mut plan = plan(
+            &[
+                ("flac", with(&fingerprint, Some(900_000))),
+                ("mp3", with(&similar, Some(320_000))),
+                ("other", with(&other, None)),
+                ("unknown", ProbeInfo::default()),
+            ],
+            &[&[1, 2, 3], &[0]],
+        );
+        let duplicates = dedupe_by_fingerprint(&mut plan, 0.9);
+        assert_eq!(
+            duplicates,
+            [Duplicate {
+                dropped: PathBuf::from("music/mp3.mp3"),
+                kept: PathBuf::from("music/flac.mp3"),
+                key: "fingerprints 99% similar".to_string(),
+            }]
+        );
+        assert_eq!(plan.playlists[0].entries[0].target, "flac.mp3");
+        assert_eq!(plan.tasks.len(), 3);
+    }
+
+    #[test]
+    fn ignores_sources_without_tags() {
+        let mut plan = plan(
+            &[("a", ProbeInfo::default()), ("b", ProbeInfo::default())],
+            &[&[0, 1]],
+        );
+        assert!(dedupe(&mut plan, DedupeKey::ArtistTitle).is_empty());
+        assert_eq!(plan.tasks.len(), 2);
+    }
+}
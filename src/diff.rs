@@ -0,0 +1,169 @@
+// Copyright (c) 2024 Jan Holthuis <jan.holthuis@rub.de>
+//
+// This Source Code Form is subject to the terms of the Mozilla Public License, v. 2.0. If a copy
+// of the MPL was not distributed with this file, You can obtain one at
+// http://mozilla.org/MPL/2.0/.
+//
+// SPDX-License-Identifier: MPL-2.0
+//! Comparison of the source playlists against an already synced output directory.
+use crate::longpath;
+use crate::plan::{Plan, PlaylistOutput};
+use crate::verify::{self, Problem};
+use std::collections::HashSet;
+use std::fmt;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime};
+
+/// Slack for comparing modification times, since FAT only stores them with 2-second resolution.
+const MTIME_SLACK: Duration = Duration::from_secs(2);
+
+/// A difference between a source playlist and its output.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Difference {
+    /// The output playlist is missing or unreadable.
+    Playlist(String),
+    /// A planned entry has no file on the target.
+    Missing(String),
+    /// An entry of the output playlist that is not in the source playlist (anymore).
+    Extra(String),
+    /// The file on the target is older than its source.
+    Outdated(String),
+}
+
+impl fmt::Display for Difference {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Difference::Playlist(problem) => write!(f, "{}", problem),
+            Difference::Missing(entry) => write!(f, "- {}", entry),
+            Difference::Extra(entry) => write!(f, "+ {}", entry),
+            Difference::Outdated(entry) => write!(f, "~ {} (older than source)", entry),
+        }
+    }
+}
+
+/// Differences of one playlist.
+#[derive(Clone, Debug)]
+pub struct PlaylistDiff {
+    pub source: PathBuf,
+    pub entries: usize,
+    pub differences: Vec<Difference>,
+}
+
+fn modified(path: &Path) -> Option<SystemTime> {
+    std::fs::metadata(longpath::extended(path))
+        .and_then(|metadata| metadata.modified())
+        .ok()
+}
+
+fn diff_playlist(plan: &Plan, playlist: &PlaylistOutput) -> PlaylistDiff {
+    let mut differences = vec![];
+    let planned: HashSet<&str> = playlist
+        .entries
+        .iter()
+        .map(|entry| entry.target.as_str())
+        .collect();
+    match verify::read_entries(&playlist.path) {
+        Ok(entries) => {
+            let mut seen = HashSet::new();
+            differences.extend(
+                entries
+                    .into_iter()
+                    .filter(|entry| !planned.contains(entry.as_str()) && seen.insert(entry.clone()))
+                    .map(Difference::Extra),
+            );
+        }
+        Err(Problem::PlaylistMissing) => differences.push(Difference::Playlist(
+            "Output playlist is missing".to_string(),
+        )),
+        Err(problem) => differences.push(Difference::Playlist(problem.to_string())),
+    }
+    for entry in &playlist.entries {
+        let task = &plan.tasks[entry.task];
+        match (modified(&task.destination), modified(&task.source)) {
+            (None, _) => differences.push(Difference::Missing(entry.target.clone())),
+            (Some(output), Some(source)) if output + MTIME_SLACK < source => {
+                differences.push(Difference::Outdated(entry.target.clone()))
+            }
+            _ => (),
+        }
+    }
+    PlaylistDiff {
+        source: playlist.source.clone(),
+        entries: playlist.entries.len(),
+        differences,
+    }
+}
+
+/// Compare every playlist of the plan against the output directory.
+pub fn diff(plan: &Plan) -> Vec<PlaylistDiff> {
+    plan.playlists
+        .iter()
+        .map(|playlist| diff_playlist(plan, playlist))
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::plan::{Action, OutputEntry, Task};
+    use crate::tags::TagOverrides;
+
+    #[test]
+    fn reports_missing_extra_and_outdated_entries() {
+        let dir = std::env::temp_dir().join(format!("ford-sync-diff-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(dir.join("music")).unwrap();
+        std::fs::create_dir_all(dir.join("output")).unwrap();
+        for name in ["a", "b", "c"] {
+            std::fs::write(dir.join(format!("music/{}.mp3", name)), "DATA").unwrap();
+        }
+        std::fs::write(dir.join("output/a.mp3"), "DATA").unwrap();
+        std::fs::write(dir.join("output/c.mp3"), "DATA").unwrap();
+        let old = SystemTime::now() - Duration::from_secs(3600);
+        std::fs::File::options()
+            .write(true)
+            .open(dir.join("output/c.mp3"))
+            .unwrap()
+            .set_modified(old)
+            .unwrap();
+        std::fs::write(dir.join("output/playlist.m3u"), "a.mp3\nold.mp3\n").unwrap();
+
+        let plan = Plan {
+            playlists: vec![PlaylistOutput {
+                source: dir.join("music/playlist.m3u"),
+                path: dir.join("output/playlist.m3u"),
+                entries: ["a.mp3", "b.mp3", "c.mp3"]
+                    .iter()
+                    .enumerate()
+                    .map(|(task, target)| OutputEntry {
+                        target: target.to_string(),
+                        task,
+                    })
+                    .collect(),
+                skipped: vec![],
+                title: None,
+            }],
+            tasks: ["a", "b", "c"]
+                .iter()
+                .map(|name| Task {
+                    action: Action::Copy,
+                    source: dir.join(format!("music/{}.mp3", name)),
+                    destination: dir.join(format!("output/{}.mp3", name)),
+                    tags: TagOverrides::default(),
+                    encoder: None,
+                    segment: None,
+                })
+                .collect(),
+            ..Default::default()
+        };
+        assert_eq!(
+            diff(&plan)[0].differences,
+            vec![
+                Difference::Extra("old.mp3".to_string()),
+                Difference::Missing("b.mp3".to_string()),
+                Difference::Outdated("c.mp3".to_string()),
+            ]
+        );
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,377 @@
+// Copyright (c) 2024 Jan Holthuis <jan.holthuis@rub.de>
+//
+// This Source Code Form is subject to the terms of the Mozilla Public License, v. 2.0. If a copy
+// of the MPL was not distributed with this file, You can obtain one at
+// http://mozilla.org/MPL/2.0/.
+//
+// SPDX-License-Identifier: MPL-2.0
+//! Environment checks of the `doctor` subcommand.
+use crate::atomic;
+use crate::estimate::{self, ByteSize};
+use crate::exit;
+use crate::lock::{self, LockState};
+use crate::mount::{self, TargetFs};
+use std::fmt;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::process::{Command, Stdio};
+
+/// Free space below which the free space check warns.
+const LOW_SPACE: u64 = 1024 * 1024 * 1024;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Status {
+    Pass,
+    Warn,
+    Fail,
+}
+
+impl fmt::Display for Status {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Status::Pass => "pass",
+            Status::Warn => "warn",
+            Status::Fail => "fail",
+        })
+    }
+}
+
+/// Result of a single check.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Check {
+    pub name: &'static str,
+    pub status: Status,
+    pub message: String,
+    /// How to fix the problem, unless the check passed.
+    pub hint: Option<String>,
+}
+
+impl Check {
+    fn pass(name: &'static str, message: impl Into<String>) -> Self {
+        Self {
+            name,
+            status: Status::Pass,
+            message: message.into(),
+            hint: None,
+        }
+    }
+
+    fn problem(
+        name: &'static str,
+        status: Status,
+        message: impl Into<String>,
+        hint: impl Into<String>,
+    ) -> Self {
+        Self {
+            name,
+            status,
+            message: message.into(),
+            hint: Some(hint.into()),
+        }
+    }
+}
+
+impl fmt::Display for Check {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "[{}] {}: {}", self.status, self.name, self.message)?;
+        if let Some(hint) = &self.hint {
+            write!(f, "\n       {}", hint)?;
+        }
+        Ok(())
+    }
+}
+
+/// Check that `program` (i.e. `ffmpeg` or `ffprobe`) can be run, and report its version.
+fn check_program(name: &'static str, program: &str, missing: Status, purpose: &str) -> Check {
+    let output = Command::new(program)
+        .arg("-version")
+        .stdin(Stdio::null())
+        .stderr(Stdio::null())
+        .output();
+    match output {
+        Ok(output) if output.status.success() => {
+            let stdout = String::from_utf8_lossy(&output.stdout);
+            let first_line = stdout.lines().next().unwrap_or_default();
+            let version = first_line
+                .split(" Copyright")
+                .next()
+                .filter(|version| !version.is_empty())
+                .unwrap_or("unknown version");
+            Check::pass(name, version)
+        }
+        Ok(output) => Check::problem(
+            name,
+            missing,
+            format!("`{} -version` failed ({})", program, output.status),
+            format!("Reinstall {}, it is needed {}", program, purpose),
+        ),
+        Err(e) => Check::problem(
+            name,
+            missing,
+            format!("Failed to run {} ({})", program, e),
+            format!(
+                "Install FFmpeg and make sure that {} is in the PATH, it is needed {}",
+                program, purpose
+            ),
+        ),
+    }
+}
+
+fn check_output_dir(output_dir: &Path) -> Check {
+    const NAME: &str = "Output directory";
+    if !output_dir.exists() {
+        return Check::problem(
+            NAME,
+            Status::Warn,
+            format!("{} does not exist", output_dir.display()),
+            "It will be created, check that `--output-dir` points to the USB stick",
+        );
+    }
+    if !output_dir.is_dir() {
+        return Check::problem(
+            NAME,
+            Status::Fail,
+            format!("{} is not a directory", output_dir.display()),
+            "Point `--output-dir` at a directory",
+        );
+    }
+    let probe_path = atomic::temp_path(&output_dir.join("doctor"));
+    let written = std::fs::File::create(&probe_path).and_then(|mut file| file.write_all(b"ok"));
+    let _ = std::fs::remove_file(&probe_path);
+    match written {
+        Ok(()) => Check::pass(NAME, format!("{} is writable", output_dir.display())),
+        Err(e) => Check::problem(
+            NAME,
+            Status::Fail,
+            format!("{} is not writable ({})", output_dir.display(), e),
+            "Check the permissions, and whether the medium was remounted read-only because of \
+             filesystem errors (check it with e.g. `fsck.vfat`)",
+        ),
+    }
+}
+
+fn check_filesystem(output_dir: &Path, target_fs: Option<TargetFs>) -> Check {
+    const NAME: &str = "Filesystem";
+    if let Some(target_fs) = target_fs {
+        return Check::pass(NAME, format!("{} (from `--target-fs`)", target_fs));
+    }
+    match TargetFs::detect(output_dir) {
+        Some((target_fs, mount)) if target_fs.is_supported_by_sync() => Check::pass(
+            NAME,
+            format!("{} ({} on {})", target_fs, mount.fs_type, mount.device),
+        ),
+        Some((_, mount)) => Check::problem(
+            NAME,
+            Status::Warn,
+            format!(
+                "{} on {} is not readable by Sync 2",
+                mount.fs_type, mount.device
+            ),
+            "Format the stick as FAT32 or exFAT, or use `--target-fs` if this is a staging \
+             directory",
+        ),
+        None => Check::problem(
+            NAME,
+            Status::Warn,
+            "Failed to detect the filesystem",
+            "Use `--target-fs` to set it",
+        ),
+    }
+}
+
+fn check_free_space(output_dir: &Path) -> Check {
+    const NAME: &str = "Free space";
+    match estimate::available_space(output_dir) {
+        Ok(available) if available < LOW_SPACE => Check::problem(
+            NAME,
+            Status::Warn,
+            format!("Only {} free", ByteSize(available)),
+            "Free up space or use `--max-total-size` to sync a subset",
+        ),
+        Ok(available) => Check::pass(NAME, format!("{} free", ByteSize(available))),
+        Err(e) => Check::problem(
+            NAME,
+            Status::Warn,
+            format!("Failed to determine free space ({})", e),
+            "Check that the output directory is accessible",
+        ),
+    }
+}
+
+fn check_removable(output_dir: &Path) -> Check {
+    const NAME: &str = "Removable media";
+    let Some(mount) = mount::find_mount(output_dir) else {
+        return Check::problem(
+            NAME,
+            Status::Warn,
+            "Failed to find the mount of the output directory",
+            "Check that `--output-dir` points to the USB stick",
+        );
+    };
+    let is_mount_point =
+        std::fs::canonicalize(output_dir).is_ok_and(|output_dir| output_dir == mount.mount_point);
+    match (mount::is_removable(&mount), is_mount_point) {
+        (true, true) => Check::pass(
+            NAME,
+            format!(
+                "{} is the mount point of {}",
+                output_dir.display(),
+                mount.device
+            ),
+        ),
+        (true, false) => Check::problem(
+            NAME,
+            Status::Warn,
+            format!(
+                "{} is below the mount point {}",
+                output_dir.display(),
+                mount.mount_point.display()
+            ),
+            "Sync 2 indexes the whole stick, so syncing into a subdirectory is fine, but \
+             `--eject` will unmount all of it",
+        ),
+        (false, _) => Check::problem(
+            NAME,
+            Status::Warn,
+            format!("{} is not on removable media", mount.device),
+            "Point `--output-dir` at the USB stick, unless this is a staging directory",
+        ),
+    }
+}
+
+fn check_lock(output_dir: &Path) -> Check {
+    const NAME: &str = "Lock";
+    match lock::state(output_dir) {
+        LockState::Unlocked => Check::pass(NAME, "No other run is using the output directory"),
+        LockState::Locked { pid, .. } => Check::problem(
+            NAME,
+            Status::Warn,
+            format!("The output directory is in use by process {}", pid),
+            "Wait for that run to finish, or use `--force-unlock` if it crashed",
+        ),
+        LockState::Stale => Check::problem(
+            NAME,
+            Status::Warn,
+            "A stale lock file was left behind by a crashed run",
+            "It will be removed by the next run",
+        ),
+    }
+}
+
+fn check_temp_files(output_dir: &Path) -> Check {
+    const NAME: &str = "Temporary files";
+    match atomic::find_stale(output_dir).as_slice() {
+        [] => Check::pass(NAME, "No leftover temporary files"),
+        stale => Check::problem(
+            NAME,
+            Status::Warn,
+            format!(
+                "{} leftover temporary files from a crashed run (e.g. {})",
+                stale.len(),
+                stale[0].display()
+            ),
+            "They will be removed by the next run",
+        ),
+    }
+}
+
+/// Check the configuration file, which is `Err` with the error message if it is invalid.
+fn check_config(config: Result<Option<PathBuf>, String>) -> Check {
+    const NAME: &str = "Configuration";
+    match config {
+        Ok(Some(path)) => Check::pass(NAME, format!("{} is valid", path.display())),
+        Ok(None) => Check::pass(NAME, "No configuration file"),
+        Err(e) => Check::problem(
+            NAME,
+            Status::Fail,
+            e,
+            "Fix the configuration file, `--print-config` shows the expected keys",
+        ),
+    }
+}
+
+/// Run all checks.
+pub fn run(
+    output_dir: &Path,
+    target_fs: Option<TargetFs>,
+    config: Result<Option<PathBuf>, String>,
+) -> Vec<Check> {
+    vec![
+        check_program("FFmpeg", "ffmpeg", Status::Fail, "to convert files"),
+        check_program(
+            "FFprobe",
+            "ffprobe",
+            Status::Warn,
+            "for `--probe` and tag-based layouts",
+        ),
+        check_output_dir(output_dir),
+        check_filesystem(output_dir, target_fs),
+        check_free_space(output_dir),
+        check_removable(output_dir),
+        check_lock(output_dir),
+        check_temp_files(output_dir),
+        check_config(config),
+    ]
+}
+
+/// Exit status for the worst result of the checks.
+pub fn exit_status(checks: &[Check]) -> i32 {
+    match checks.iter().map(|check| check.status).max() {
+        Some(Status::Fail) => exit::ENVIRONMENT,
+        Some(Status::Warn) => exit::FAILURES,
+        _ => exit::SUCCESS,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn scratch_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("doctor-{}-{}", name, std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    #[test]
+    fn worst_result_determines_exit_status() {
+        let pass = Check::pass("A", "ok");
+        let warn = Check::problem("B", Status::Warn, "hm", "fix it");
+        let fail = Check::problem("C", Status::Fail, "no", "fix it");
+        assert_eq!(exit_status(std::slice::from_ref(&pass)), exit::SUCCESS);
+        assert_eq!(exit_status(&[pass.clone(), warn.clone()]), exit::FAILURES);
+        assert_eq!(exit_status(&[fail, warn, pass]), exit::ENVIRONMENT);
+    }
+
+    #[test]
+    fn missing_program_fails() {
+        let check = check_program("FFmpeg", "ford-sync-no-such-program", Status::Fail, "");
+        assert_eq!(check.status, Status::Fail);
+        assert!(check.hint.is_some());
+    }
+
+    #[test]
+    fn finds_leftovers() {
+        let dir = scratch_dir("leftovers");
+        assert_eq!(check_output_dir(&dir).status, Status::Pass);
+        assert_eq!(check_lock(&dir).status, Status::Pass);
+        assert_eq!(check_temp_files(&dir).status, Status::Pass);
+
+        std::fs::create_dir(dir.join("Album")).unwrap();
+        std::fs::write(atomic::temp_path(&dir.join("Album/a.mp3")), "").unwrap();
+        std::fs::write(dir.join(lock::LOCK_FILE_NAME), "garbage").unwrap();
+        assert_eq!(check_lock(&dir).status, Status::Warn);
+        let check = check_temp_files(&dir);
+        assert_eq!(check.status, Status::Warn);
+        assert!(check.message.starts_with("1 leftover"));
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn invalid_config_fails() {
+        let check = check_config(Err("config.toml: Unknown configuration key `x`".to_string()));
+        assert_eq!(check.status, Status::Fail);
+        assert_eq!(check_config(Ok(None)).status, Status::Pass);
+    }
+}
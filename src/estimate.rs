@@ -0,0 +1,336 @@
+// Copyright (c) 2024 Jan Holthuis <jan.holthuis@rub.de>
+//
+// This Source Code Form is subject to the terms of the Mozilla Public License, v. 2.0. If a copy
+// of the MPL was not distributed with this file, You can obtain one at
+// http://mozilla.org/MPL/2.0/.
+//
+// SPDX-License-Identifier: MPL-2.0
+//! Estimation of the output size of a plan, and of how long executing it takes.
+use crate::execute::{TaskStatus, WORKERS};
+use crate::plan::{Action, Plan, Task};
+use crate::report::{EntryReport, Report};
+use crate::rules::{EncoderSettings, DEFAULT_QUALITY};
+use serde::Serialize;
+use std::collections::HashSet;
+use std::fmt;
+use std::path::{Path, PathBuf};
+
+/// Approximate average bitrate (in kbit/s) of FFmpeg's LAME VBR quality 2.
+pub const TARGET_BITRATE_KBPS: f64 = 190.0;
+
+/// Approximate average bitrates (in kbit/s) of LAME VBR qualities 0 to 9.
+const VBR_BITRATES_KBPS: [f64; 10] = [
+    245.0,
+    225.0,
+    TARGET_BITRATE_KBPS,
+    175.0,
+    165.0,
+    130.0,
+    115.0,
+    100.0,
+    85.0,
+    65.0,
+];
+
+/// Returns the approximate average bitrate (in kbit/s) of outputs encoded with `encoder`.
+pub fn bitrate_kbps(encoder: Option<&EncoderSettings>) -> f64 {
+    let encoder = encoder.copied().unwrap_or_default();
+    match encoder.bitrate {
+        Some(bitrate) => bitrate.into(),
+        None => VBR_BITRATES_KBPS[usize::from(encoder.quality.unwrap_or(DEFAULT_QUALITY).min(9))],
+    }
+}
+
+/// Settings for the size estimation.
+#[derive(Clone, Copy, Debug)]
+pub struct EstimateOptions {
+    /// Assumed duration of a track in minutes when it has not been probed.
+    pub minutes_per_track: f64,
+}
+
+/// Estimated number of bytes that will be written to the output directory.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct SizeEstimate {
+    pub copy_bytes: u64,
+    pub convert_bytes: u64,
+}
+
+impl SizeEstimate {
+    pub fn total(&self) -> u64 {
+        self.copy_bytes + self.convert_bytes
+    }
+}
+
+/// Estimate the size of a single task's output.
+pub fn estimate_task(plan: &Plan, task: &Task, options: &EstimateOptions) -> u64 {
+    match task.action {
+        // A remux keeps the audio stream, so its output is about as large as the source (or its
+        // share of the source for a part).
+        Action::Copy | Action::Remux => {
+            let size = std::fs::metadata(&task.source)
+                .map(|metadata| metadata.len())
+                .unwrap_or(0);
+            let source_duration = plan
+                .metadata
+                .get(&task.source)
+                .and_then(|info| info.duration)
+                .filter(|duration| *duration > 0.0);
+            match (task.duration(&plan.metadata), source_duration) {
+                (Some(duration), Some(source_duration)) if task.segment.is_some() => {
+                    (size as f64 * duration / source_duration) as u64
+                }
+                _ => size,
+            }
+        }
+        Action::Convert => {
+            let seconds = task
+                .duration(&plan.metadata)
+                .unwrap_or(options.minutes_per_track * 60.0);
+            (seconds * bitrate_kbps(task.encoder.as_ref()) * 1000.0 / 8.0) as u64
+        }
+    }
+}
+
+/// Estimate the total output size of a plan.
+///
+/// Tasks that write to the same destination, e.g. for a track in several playlists, are only
+/// counted once.
+pub fn estimate(plan: &Plan, options: &EstimateOptions) -> SizeEstimate {
+    let mut destinations = HashSet::new();
+    plan.tasks
+        .iter()
+        .filter(|task| destinations.insert(&task.destination))
+        .fold(SizeEstimate::default(), |mut estimate, task| {
+            let bytes = estimate_task(plan, task, options);
+            match task.action {
+                Action::Copy => estimate.copy_bytes += bytes,
+                Action::Convert | Action::Remux => estimate.convert_bytes += bytes,
+            }
+            estimate
+        })
+}
+
+/// Bytes per second that a worker copies unless a previous run was measured, about the write
+/// speed of a USB 2 stick.
+const DEFAULT_COPY_SPEED: f64 = 20.0 * 1024.0 * 1024.0;
+
+/// How fast a worker converts and copies files.
+#[derive(Clone, Copy, Debug, PartialEq, Serialize)]
+pub struct Speeds {
+    /// Seconds of audio encoded per second.
+    pub encode: f64,
+    /// Bytes copied per second, also for remuxed files.
+    pub copy: f64,
+}
+
+impl Speeds {
+    /// Speeds with an encode-speed factor, i.e. the number of seconds of audio encoded per second.
+    pub fn new(encode: f64) -> Self {
+        Self {
+            encode,
+            copy: DEFAULT_COPY_SPEED,
+        }
+    }
+
+    /// Returns the speeds measured from the files that a previous run converted and copied.
+    ///
+    /// Speeds that the report has no timings for are kept.
+    pub fn measured(self, report: &Report) -> Self {
+        let entries = || {
+            report
+                .playlists
+                .iter()
+                .flat_map(|playlist| &playlist.entries)
+        };
+        Self {
+            encode: speed(
+                entries().filter(|entry| entry.action == Action::Convert),
+                |entry| entry.duration,
+            )
+            .unwrap_or(self.encode),
+            copy: speed(
+                entries().filter(|entry| entry.action != Action::Convert),
+                |entry| entry.output_size.map(|size| size as f64),
+            )
+            .unwrap_or(self.copy),
+        }
+    }
+}
+
+/// Returns the amount per second of the entries that succeeded, e.g. in bytes per second.
+fn speed<'a>(
+    entries: impl Iterator<Item = &'a EntryReport>,
+    amount: impl Fn(&EntryReport) -> Option<f64>,
+) -> Option<f64> {
+    let (total, elapsed) = entries
+        .filter(|entry| entry.status == TaskStatus::Succeeded && entry.elapsed > 0.0)
+        .filter_map(|entry| Some((amount(entry)?, entry.elapsed)))
+        .fold((0.0, 0.0), |(total, elapsed), (amount, time)| {
+            (total + amount, elapsed + time)
+        });
+    (total > 0.0).then(|| total / elapsed)
+}
+
+/// Predicted seconds that a worker needs for the task.
+fn task_seconds(plan: &Plan, task: &Task, options: &EstimateOptions, speeds: &Speeds) -> f64 {
+    match task.action {
+        Action::Copy | Action::Remux => estimate_task(plan, task, options) as f64 / speeds.copy,
+        Action::Convert => {
+            let seconds = task
+                .duration(&plan.metadata)
+                .unwrap_or(options.minutes_per_track * 60.0);
+            seconds / speeds.encode
+        }
+    }
+}
+
+/// Predicted totals of a set of tracks.
+#[derive(Clone, Debug, Default, PartialEq, Serialize)]
+pub struct Prediction {
+    pub tracks: usize,
+    /// Total size of the distinct source files in bytes.
+    pub source_size: u64,
+    /// Estimated total size of the distinct output files in bytes.
+    pub output_size: u64,
+    /// Predicted time to convert and copy the distinct files in seconds.
+    pub seconds: f64,
+}
+
+impl Prediction {
+    /// Predict the totals of the tasks with the given indices, counting each destination once.
+    fn of(
+        plan: &Plan,
+        tracks: usize,
+        tasks: impl IntoIterator<Item = usize>,
+        options: &EstimateOptions,
+        speeds: &Speeds,
+    ) -> Self {
+        let mut destinations = HashSet::new();
+        let mut prediction = Self {
+            tracks,
+            ..Default::default()
+        };
+        let mut seconds = 0.0;
+        for task in tasks.into_iter().map(|index| &plan.tasks[index]) {
+            if !destinations.insert(&task.destination) {
+                continue;
+            }
+            prediction.source_size += std::fs::metadata(&task.source)
+                .map(|metadata| metadata.len())
+                .unwrap_or(0);
+            prediction.output_size += estimate_task(plan, task, options);
+            seconds += task_seconds(plan, task, options, speeds);
+        }
+        prediction.seconds = seconds / WORKERS as f64;
+        prediction
+    }
+}
+
+/// Predictions of a playlist.
+#[derive(Clone, Debug, PartialEq, Serialize)]
+pub struct PlaylistPrediction {
+    pub source: PathBuf,
+    #[serde(flatten)]
+    pub prediction: Prediction,
+}
+
+/// Predictions per playlist and of the whole plan, for `estimate`.
+#[derive(Clone, Debug, PartialEq, Serialize)]
+pub struct PlanPrediction {
+    pub playlists: Vec<PlaylistPrediction>,
+    pub total: Prediction,
+    /// The speeds of every worker that the prediction assumes.
+    pub speeds: Speeds,
+    pub workers: usize,
+}
+
+/// Predict the sizes and duration of executing the plan, per playlist and in total.
+pub fn predict(plan: &Plan, options: &EstimateOptions, speeds: &Speeds) -> PlanPrediction {
+    let playlists = plan
+        .playlists
+        .iter()
+        .map(|playlist| PlaylistPrediction {
+            source: playlist.source.clone(),
+            prediction: Prediction::of(
+                plan,
+                playlist.entries.len(),
+                playlist.entries.iter().map(|entry| entry.task),
+                options,
+                speeds,
+            ),
+        })
+        .collect();
+    let tracks = plan
+        .tasks
+        .iter()
+        .map(|task| &task.destination)
+        .collect::<HashSet<_>>()
+        .len();
+    PlanPrediction {
+        playlists,
+        total: Prediction::of(plan, tracks, 0..plan.tasks.len(), options, speeds),
+        speeds: *speeds,
+        workers: WORKERS,
+    }
+}
+
+/// Returns the free space available on the filesystem that `path` is (or will be) located on.
+pub fn available_space(path: &Path) -> std::io::Result<u64> {
+    let existing = path
+        .ancestors()
+        .find(|ancestor| ancestor.exists())
+        .unwrap_or(Path::new("."));
+    fs4::available_space(existing)
+}
+
+/// Parse a size like `15G`, `700M` or `1.5T` (binary units, an optional `B`/`iB` suffix is
+/// accepted).
+pub fn parse_size(value: &str) -> Result<u64, String> {
+    let value = value.trim();
+    let split = value
+        .find(|c: char| !(c.is_ascii_digit() || c == '.'))
+        .unwrap_or(value.len());
+    let (number, unit) = value.split_at(split);
+    let number: f64 = number
+        .parse()
+        .map_err(|_| format!("invalid size `{}`", value))?;
+    let unit = unit.trim().to_ascii_uppercase();
+    let unit = unit
+        .strip_suffix("IB")
+        .or_else(|| unit.strip_suffix('B'))
+        .unwrap_or(&unit);
+    let multiplier: u64 = match unit {
+        "" => 1,
+        "K" => 1 << 10,
+        "M" => 1 << 20,
+        "G" => 1 << 30,
+        "T" => 1 << 40,
+        _ => {
+            return Err(format!(
+                "invalid size unit in `{}` (expected K, M, G or T)",
+                value
+            ))
+        }
+    };
+    Ok((number * multiplier as f64) as u64)
+}
+
+/// Human-readable byte count.
+pub struct ByteSize(pub u64);
+
+impl fmt::Display for ByteSize {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        const UNITS: [&str; 5] = ["B", "KiB", "MiB", "GiB", "TiB"];
+        let mut value = self.0 as f64;
+        let mut unit = 0;
+        while value >= 1024.0 && unit < UNITS.len() - 1 {
+            value /= 1024.0;
+            unit += 1;
+        }
+        if unit == 0 {
+            write!(f, "{} {}", self.0, UNITS[0])
+        } else {
+            write!(f, "{:.2} {}", value, UNITS[unit])
+        }
+    }
+}
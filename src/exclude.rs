@@ -0,0 +1,269 @@
+// Copyright (c) 2024 Jan Holthuis <jan.holthuis@rub.de>
+//
+// This Source Code Form is subject to the terms of the Mozilla Public License, v. 2.0. If a copy
+// of the MPL was not distributed with this file, You can obtain one at
+// http://mozilla.org/MPL/2.0/.
+//
+// SPDX-License-Identifier: MPL-2.0
+//! Filters for excluding playlist entries by their source path.
+//!
+//! `*` and `?` match within a path component, `**` matches any number of components and `[...]`
+//! matches a character class (negated with `[!...]`). Patterns without a `/` are matched against
+//! the file name only, all others against the whole path.
+use std::collections::BTreeSet;
+use std::io;
+use std::path::{Component, Path};
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+struct Pattern {
+    source: String,
+    glob: Vec<char>,
+    /// Match against the whole path instead of the file name.
+    full_path: bool,
+}
+
+/// A set of exclude patterns.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct Excludes {
+    patterns: Vec<Pattern>,
+}
+
+impl Excludes {
+    pub fn new(patterns: impl IntoIterator<Item = String>) -> Self {
+        let patterns = patterns
+            .into_iter()
+            .map(|source| Pattern {
+                // Paths are matched without a leading `./` or `/`.
+                glob: source
+                    .trim_start_matches("./")
+                    .trim_start_matches('/')
+                    .chars()
+                    .collect(),
+                full_path: source.contains('/'),
+                source,
+            })
+            .collect();
+        Self { patterns }
+    }
+
+    /// Read patterns from a file with one pattern per line. Empty lines and lines starting with
+    /// `#` are ignored.
+    pub fn read_file(path: &Path) -> io::Result<Vec<String>> {
+        let contents = std::fs::read_to_string(path)?;
+        Ok(contents
+            .lines()
+            .map(str::trim)
+            .filter(|line| !line.is_empty() && !line.starts_with('#'))
+            .map(str::to_string)
+            .collect())
+    }
+
+    /// Returns the first pattern that matches `path`, if any.
+    pub fn matching(&self, path: &Path) -> Option<&str> {
+        if self.patterns.is_empty() {
+            return None;
+        }
+        // Normalize the separators so that patterns work the same on all platforms.
+        let full_path: Vec<char> = path
+            .components()
+            .filter(|component| !matches!(component, Component::CurDir))
+            .map(|component| component.as_os_str().to_string_lossy().into_owned())
+            .collect::<Vec<_>>()
+            .join("/")
+            .trim_start_matches('/')
+            .chars()
+            .collect();
+        let file_name: Vec<char> = path
+            .file_name()
+            .map(|name| name.to_string_lossy().chars().collect())
+            .unwrap_or_default();
+        self.patterns
+            .iter()
+            .find(|pattern| {
+                let text = if pattern.full_path {
+                    &full_path
+                } else {
+                    &file_name
+                };
+                glob_match(&pattern.glob, text)
+            })
+            .map(|pattern| pattern.source.as_str())
+    }
+}
+
+/// Match a character class starting after the `[`, and return the rest of the pattern.
+fn match_class(pattern: &[char], c: char) -> Option<(bool, &[char])> {
+    let (negated, mut rest) = match pattern {
+        ['!' | '^', rest @ ..] => (true, rest),
+        rest => (false, rest),
+    };
+    let mut matched = false;
+    let mut first = true;
+    loop {
+        match rest {
+            [']', tail @ ..] if !first => return Some((matched != negated, tail)),
+            [start, '-', end, tail @ ..] if *end != ']' => {
+                matched |= (*start..=*end).contains(&c);
+                rest = tail;
+            }
+            [literal, tail @ ..] => {
+                matched |= *literal == c;
+                rest = tail;
+            }
+            [] => return None,
+        }
+        first = false;
+    }
+}
+
+fn glob_match(pattern: &[char], text: &[char]) -> bool {
+    match pattern {
+        [] => text.is_empty(),
+        ['*', '*', '/', rest @ ..] => {
+            // `**/` matches any number of whole directories, including none.
+            (0..=text.len())
+                .filter(|&i| i == 0 || text[i - 1] == '/')
+                .any(|i| glob_match(rest, &text[i..]))
+        }
+        ['*', '*', rest @ ..] => (0..=text.len()).any(|i| glob_match(rest, &text[i..])),
+        ['*', rest @ ..] => {
+            let component_len = text.iter().position(|&c| c == '/').unwrap_or(text.len());
+            (0..=component_len).any(|i| glob_match(rest, &text[i..]))
+        }
+        ['?', rest @ ..] => matches!(text, [c, tail @ ..] if *c != '/' && glob_match(rest, tail)),
+        ['[', class @ ..] => match text {
+            [c, tail @ ..] if *c != '/' => match match_class(class, *c) {
+                Some((matched, rest)) => matched && glob_match(rest, tail),
+                // An unterminated class is matched literally.
+                None => *c == '[' && glob_match(class, tail),
+            },
+            _ => false,
+        },
+        [literal, rest @ ..] => {
+            matches!(text, [c, tail @ ..] if c == literal && glob_match(rest, tail))
+        }
+    }
+}
+
+/// Source extensions that are considered by default.
+///
+/// Video containers such as `mp4` or `mkv` are not included; with `--include-ext all` they are
+/// converted if the probe finds an audio stream in them.
+pub const DEFAULT_EXTENSIONS: &str = "mp3,flac,m4a,ogg,opus,wav,wma";
+
+/// The source extensions that are considered, from `--include-ext`.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub enum Extensions {
+    /// Every file with an extension is considered.
+    #[default]
+    All,
+    /// Only files with one of these lowercase extensions are considered.
+    Only(BTreeSet<String>),
+}
+
+impl Extensions {
+    /// Build the filter from a list of extensions. The special value `all` disables it.
+    pub fn new<S: AsRef<str>>(extensions: impl IntoIterator<Item = S>) -> Self {
+        let mut only = BTreeSet::new();
+        for extension in extensions {
+            let extension = extension.as_ref().trim().trim_start_matches('.');
+            if extension.eq_ignore_ascii_case("all") {
+                return Extensions::All;
+            }
+            if !extension.is_empty() {
+                only.insert(extension.to_lowercase());
+            }
+        }
+        Extensions::Only(only)
+    }
+
+    /// Returns `true` if `path` has no extension or one that is considered.
+    ///
+    /// Entries without an extension are left to the planner, which reports them separately.
+    pub fn includes(&self, path: &Path) -> bool {
+        match (self, path.extension()) {
+            (Extensions::All, _) | (_, None) => true,
+            (Extensions::Only(only), Some(extension)) => {
+                only.contains(&extension.to_string_lossy().to_lowercase())
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn excludes(patterns: &[&str]) -> Excludes {
+        Excludes::new(patterns.iter().map(|pattern| pattern.to_string()))
+    }
+
+    #[test]
+    fn double_star_matches_directories() {
+        let excludes = excludes(&["**/Christmas/**"]);
+        assert!(excludes
+            .matching(Path::new("music/Christmas/a.mp3"))
+            .is_some());
+        assert!(excludes
+            .matching(Path::new("/home/me/Christmas/Best Of/a.mp3"))
+            .is_some());
+        assert!(excludes.matching(Path::new("Christmas/a.mp3")).is_some());
+        assert!(excludes
+            .matching(Path::new("music/Christmas Songs/a.mp3"))
+            .is_none());
+    }
+
+    #[test]
+    fn patterns_without_slash_match_file_names() {
+        let excludes = excludes(&["*.spoken.*"]);
+        assert_eq!(
+            excludes.matching(Path::new("music/Book/01.spoken.mp3")),
+            Some("*.spoken.*")
+        );
+        assert!(excludes
+            .matching(Path::new("music/x.spoken/01.mp3"))
+            .is_none());
+    }
+
+    #[test]
+    fn single_star_stays_within_component() {
+        let excludes = excludes(&["music/*.flac"]);
+        assert!(excludes.matching(Path::new("music/a.flac")).is_some());
+        assert!(excludes.matching(Path::new("./music/a.flac")).is_some());
+        assert!(excludes.matching(Path::new("music/A/a.flac")).is_none());
+    }
+
+    #[test]
+    fn question_marks_and_classes() {
+        let excludes = excludes(&["track0?.mp3", "[!a-c]*.ogg"]);
+        assert!(excludes.matching(Path::new("x/track01.mp3")).is_some());
+        assert!(excludes.matching(Path::new("x/track1.mp3")).is_none());
+        assert!(excludes.matching(Path::new("x/d.ogg")).is_some());
+        assert!(excludes.matching(Path::new("x/b.ogg")).is_none());
+    }
+
+    #[test]
+    fn reads_pattern_files() {
+        let path = std::env::temp_dir().join(format!("excludes-{}.txt", std::process::id()));
+        std::fs::write(&path, "# Seasonal\n**/Christmas/**\n\n  *.spoken.*  \n").unwrap();
+        let patterns = Excludes::read_file(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(patterns, vec!["**/Christmas/**", "*.spoken.*"]);
+    }
+
+    #[test]
+    fn extensions_are_case_insensitive() {
+        let extensions = Extensions::new(DEFAULT_EXTENSIONS.split(','));
+        assert!(extensions.includes(Path::new("a/Song.FLAC")));
+        assert!(extensions.includes(Path::new("a/README")));
+        assert!(!extensions.includes(Path::new("a/cover.jpg")));
+        assert!(!extensions.includes(Path::new("a/video.mp4")));
+    }
+
+    #[test]
+    fn all_includes_everything() {
+        let extensions = Extensions::new(["mp3", "all"]);
+        assert_eq!(extensions, Extensions::All);
+        assert!(extensions.includes(Path::new("a/video.mp4")));
+    }
+}
@@ -0,0 +1,1892 @@
+// Copyright (c) 2024 Jan Holthuis <jan.holthuis@rub.de>
+//
+// This Source Code Form is subject to the terms of the Mozilla Public License, v. 2.0. If a copy
+// of the MPL was not distributed with this file, You can obtain one at
+// http://mozilla.org/MPL/2.0/.
+//
+// SPDX-License-Identifier: MPL-2.0
+use crate::art;
+use crate::atomic;
+use crate::estimate::ByteSize;
+use crate::hook::{Hook, HookError};
+use crate::interrupt;
+use crate::longpath;
+use crate::mtime;
+use crate::observer::{Observers, Progress, SyncObserver};
+use crate::plan::{Action, Plan};
+use crate::probe;
+use crate::retry::{Retrier, Stall};
+use crate::segment::Segment;
+use crate::since;
+use crate::span::Span;
+use crate::tags::{self, StripFrames, TagOverrides, TagTransliteration, TextEncoding};
+use crate::transcode::{Encoding, Job, SharedTranscoder, TranscodeError, Transcoder};
+use crate::warnings::{report_warning, WarningCategory};
+use log::{debug, info, warn};
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::fmt;
+use std::io::{ErrorKind, Read};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::channel;
+use std::sync::Arc;
+use std::time::{Duration, Instant, SystemTime};
+use threadpool::ThreadPool;
+use xxhash_rust::xxh3::Xxh3;
+
+/// How long to wait before retrying a failed conversion.
+const RETRY_DELAY: Duration = Duration::from_secs(2);
+
+/// Name of the directory below the output directory that holds FFmpeg logs.
+///
+/// Anything that scans or cleans up the output directory must leave this directory alone.
+pub const LOGS_DIR_NAME: &str = ".ford-sync-logs";
+
+/// Number of files that are converted or copied at the same time.
+pub const WORKERS: usize = 4;
+
+/// Settings that influence how the plan is executed.
+#[derive(Clone, Debug, Default)]
+pub struct ExecuteOptions {
+    /// Root of the output directory.
+    pub output_dir: PathBuf,
+    /// Keep FFmpeg logs of successful conversions.
+    pub keep_logs: bool,
+    /// Number of times a failed conversion is retried.
+    pub retries: usize,
+    /// Number of times a transient error writing an output file is retried.
+    pub io_retries: usize,
+    /// Probe converted files and compare their duration against the source.
+    pub verify_output: bool,
+    /// Maximum allowed duration difference in seconds when verifying outputs.
+    pub verify_tolerance: f64,
+    /// Compare checksums of copied files against their source.
+    pub verify_copies: bool,
+    /// Flush every output file to disk before moving it into place.
+    pub sync_writes: bool,
+    /// Rewrite ID3v2.4 and ID3v1 tags of copied MP3s as ID3v2.3.
+    pub fix_tags: bool,
+    /// Frames to remove from output MP3s.
+    pub strip_frames: Option<StripFrames>,
+    /// Copy the modification times of the sources onto the outputs.
+    pub preserve_times: bool,
+    /// Maximum length of the title, artist and album of output files.
+    pub max_tag_len: Option<usize>,
+    /// Romanize non-Latin text in the title, artist and album of output files.
+    pub transliterate_tags: Option<TagTransliteration>,
+    /// Text encoding of the frames of converted files and copies with fixed or overridden tags.
+    pub id3_encoding: Option<TextEncoding>,
+    /// Attach cover images next to the sources to converted files without embedded art.
+    pub embed_art: bool,
+    /// Also attach cover images to copied MP3s without embedded art.
+    pub embed_art_copies: bool,
+    /// Maximum width and height of attached cover images in pixels.
+    pub art_size: u32,
+    /// Names of cover images next to the sources.
+    pub cover_names: Vec<String>,
+    /// Number of consecutive failures to write to the output directory after which no further
+    /// writes are started (0 to never stop).
+    pub max_write_errors: usize,
+    /// Leave tasks alone whose output already exists instead of overwriting it.
+    pub keep_existing: bool,
+    /// Leave tasks alone whose source was not modified since this time and whose output exists,
+    /// for `--since`.
+    pub since: Option<SystemTime>,
+    /// Move the sources of copies into place instead of copying them.
+    pub move_sources: bool,
+    /// Also remove the sources of conversions once their output is written and verified.
+    pub move_converted_sources: bool,
+    /// Command to run for every successfully written output file.
+    pub post_hook: Option<Hook>,
+    /// Program that converts files to MP3.
+    pub transcoder: SharedTranscoder,
+}
+
+/// Error that stopped the execution of a plan.
+#[derive(Debug)]
+pub enum ExecuteError {
+    /// Writing to the output directory failed repeatedly, e.g. because it became read-only.
+    OutputNotWritable {
+        output_dir: PathBuf,
+        failed: usize,
+        not_attempted: usize,
+        reason: String,
+    },
+    /// FFmpeg could not be run.
+    FfmpegMissing(std::io::Error),
+}
+
+impl fmt::Display for ExecuteError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ExecuteError::OutputNotWritable {
+                output_dir,
+                failed,
+                not_attempted,
+                reason,
+            } => write!(
+                f,
+                "{}: Output directory is not writable, stopped after {} failed writes and skipped \
+                 {} remaining files (last error: {}). The medium may have been remounted \
+                 read-only, check it for errors (e.g. with `fsck.vfat`) and run again.",
+                output_dir.display(),
+                failed,
+                not_attempted,
+                reason
+            ),
+            ExecuteError::FfmpegMissing(e) => write!(
+                f,
+                "Failed to run FFmpeg ({}), make sure it is installed and in the PATH",
+                e
+            ),
+        }
+    }
+}
+
+impl std::error::Error for ExecuteError {}
+
+/// Check that the transcoder (i.e. FFmpeg) can be run.
+pub fn check_ffmpeg(options: &ExecuteOptions) -> Result<(), ExecuteError> {
+    options
+        .transcoder
+        .check()
+        .map_err(ExecuteError::FfmpegMissing)
+}
+
+/// Returns `true` if the error means that the output location cannot be written to at all.
+fn is_unwritable(error: &std::io::Error) -> bool {
+    matches!(
+        error.kind(),
+        ErrorKind::PermissionDenied | ErrorKind::ReadOnlyFilesystem
+    )
+}
+
+/// Tracks failures to write to the output directory to detect when they are systemic.
+struct WriteErrors {
+    threshold: usize,
+    consecutive: usize,
+    failed: usize,
+    last_error: String,
+    /// Set once the threshold is reached, so that no further tasks are started.
+    halted: Arc<AtomicBool>,
+}
+
+impl WriteErrors {
+    fn new(threshold: usize) -> Self {
+        WriteErrors {
+            threshold,
+            consecutive: 0,
+            failed: 0,
+            last_error: String::new(),
+            halted: Arc::new(AtomicBool::new(false)),
+        }
+    }
+
+    fn succeeded(&mut self) {
+        self.consecutive = 0;
+    }
+
+    /// Record a failed write. Returns `true` if the failure should still be reported on its own.
+    fn failed(&mut self, error: String) -> bool {
+        self.failed += 1;
+        self.consecutive += 1;
+        self.last_error = error;
+        if self.is_halted() {
+            return false;
+        }
+        if self.threshold > 0 && self.consecutive >= self.threshold {
+            warn!(
+                "{} consecutive writes to the output directory failed, not starting any further \
+                 writes...",
+                self.consecutive
+            );
+            self.halted.store(true, Ordering::SeqCst);
+            return false;
+        }
+        true
+    }
+
+    fn is_halted(&self) -> bool {
+        self.halted.load(Ordering::SeqCst)
+    }
+
+    fn into_error(self, output_dir: &Path, not_attempted: usize) -> ExecuteError {
+        ExecuteError::OutputNotWritable {
+            output_dir: output_dir.to_path_buf(),
+            failed: self.failed,
+            not_attempted,
+            reason: self.last_error,
+        }
+    }
+}
+
+/// Reason why a conversion failed.
+#[derive(Debug)]
+enum ConvertFailure {
+    /// The source file does not exist.
+    MissingSource,
+    /// FFmpeg could not be started.
+    Spawn(std::io::Error),
+    /// The output directory could not be created.
+    Directory(std::io::Error),
+    /// FFmpeg exited with a non-zero status.
+    Status {
+        status: String,
+        kind: TranscodeFailureKind,
+        stderr: String,
+        /// Log file containing the command line and FFmpeg's output.
+        log_path: Option<PathBuf>,
+    },
+    /// The converted file did not pass verification.
+    Verification(String),
+    /// Moving the converted file into place failed.
+    Io(std::io::Error),
+    /// The `--post-hook` failed for the converted file.
+    Hook(HookError),
+}
+
+impl ConvertFailure {
+    /// Returns `true` if a failed remux may succeed as a normal conversion, i.e. FFmpeg failed on
+    /// the source or its output did not pass verification.
+    fn is_remux_failure(&self) -> bool {
+        matches!(
+            self,
+            ConvertFailure::Status { .. } | ConvertFailure::Verification(_)
+        )
+    }
+
+    /// Returns `true` if the output could not be written.
+    fn is_write_error(&self) -> bool {
+        match self {
+            ConvertFailure::Directory(e) | ConvertFailure::Io(e) => is_unwritable(e),
+            ConvertFailure::Status { kind, .. } => *kind == TranscodeFailureKind::Unwritable,
+            ConvertFailure::MissingSource
+            | ConvertFailure::Spawn(_)
+            | ConvertFailure::Verification(_)
+            | ConvertFailure::Hook(_) => false,
+        }
+    }
+
+    /// Returns `true` if retrying the conversion may succeed.
+    fn is_retryable(&self, input_path: &Path) -> bool {
+        if !input_path.exists() || self.is_write_error() {
+            return false;
+        }
+        match self {
+            ConvertFailure::MissingSource
+            | ConvertFailure::Spawn(_)
+            | ConvertFailure::Directory(_)
+            | ConvertFailure::Hook(_) => false,
+            ConvertFailure::Status { kind, .. } => *kind == TranscodeFailureKind::Other,
+            ConvertFailure::Verification(_) | ConvertFailure::Io(_) => true,
+        }
+    }
+}
+
+impl fmt::Display for ConvertFailure {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        TaskError::from(self).fmt(f)
+    }
+}
+
+/// Why FFmpeg failed, classified from its output when it exits.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum TranscodeFailureKind {
+    /// A file could not be opened, e.g. because the source was removed.
+    MissingFile,
+    /// The source is copy-protected.
+    Protected,
+    /// The output could not be written, e.g. because the medium became read-only.
+    Unwritable,
+    /// Any other failure, e.g. of a damaged source, which retrying may get past.
+    Other,
+}
+
+/// Messages of FFmpeg for copy-protected sources, in lowercase: WMA with DRM, iTunes AAC with
+/// FairPlay (whose codec tag is `drms`), and Audible AAX without activation bytes.
+const PROTECTED_MESSAGES: [&str; 3] = [
+    "drm protected stream detected",
+    "(drms / 0x736d7264)",
+    "activation_bytes",
+];
+
+impl TranscodeFailureKind {
+    /// Classify a failure from FFmpeg's `stderr`, for `input_path` converted to `temp_path`.
+    fn classify(stderr: &str, input_path: &Path, temp_path: &Path) -> Self {
+        let temp_name = temp_path.file_name().unwrap_or_default().to_string_lossy();
+        if stderr.lines().any(|line| {
+            line.contains("Read-only file system")
+                || line.contains(temp_name.as_ref()) && line.contains("Permission denied")
+        }) {
+            return TranscodeFailureKind::Unwritable;
+        }
+        // Lines naming the files, and tags, may contain anything, so only whole messages count.
+        let input_name = input_path.file_name().unwrap_or_default().to_string_lossy();
+        let is_protected = |line: &str| {
+            !line.contains(input_name.as_ref())
+                && !line.contains(temp_name.as_ref())
+                && !is_metadata_line(line)
+                && PROTECTED_MESSAGES
+                    .iter()
+                    .any(|message| line.to_lowercase().contains(message))
+        };
+        if stderr.to_lowercase().contains("no such file or directory") {
+            TranscodeFailureKind::MissingFile
+        } else if stderr.lines().any(is_protected) {
+            TranscodeFailureKind::Protected
+        } else {
+            TranscodeFailureKind::Other
+        }
+    }
+}
+
+/// Returns `true` if the line of FFmpeg's output lists a tag, like `    title : Name`.
+fn is_metadata_line(line: &str) -> bool {
+    line.starts_with(' ')
+        && line
+            .split_once(" : ")
+            .is_some_and(|(key, _)| !key.trim().contains(' '))
+}
+
+/// Result of a single conversion.
+enum ConvertOutcome {
+    Succeeded,
+    Failed(ConvertFailure),
+    /// FFmpeg was killed (or never started) because the run was interrupted or the conversion was
+    /// skipped.
+    Interrupted,
+}
+
+/// Run FFmpeg to convert `input_path` to `output_path`, killing it if the run gets interrupted.
+///
+/// FFmpeg writes to a temporary file that is only renamed to `output_path` on success (and after
+/// successful verification if enabled).
+#[allow(clippy::too_many_arguments)]
+fn convert(
+    input_path: &Path,
+    segment: Option<&Segment>,
+    output_path: &Path,
+    source_duration: Option<f64>,
+    cover: Option<&Path>,
+    tags: &TagOverrides,
+    encoding: Encoding,
+    options: &ExecuteOptions,
+    retrier: &Retrier,
+) -> ConvertOutcome {
+    if interrupt::is_interrupted() {
+        return ConvertOutcome::Interrupted;
+    }
+    let log_path = longpath::extended(&log_path(output_path, options));
+    let input_path = &longpath::extended(input_path);
+    let output_path = &longpath::extended(output_path);
+    if !input_path.exists() {
+        return ConvertOutcome::Failed(ConvertFailure::MissingSource);
+    }
+    if let Some(parent) = output_path.parent() {
+        if let Err(e) = std::fs::create_dir_all(parent) {
+            return ConvertOutcome::Failed(ConvertFailure::Directory(e));
+        }
+    }
+
+    let temp_path = atomic::temp_path(output_path);
+    let cover = cover.map(longpath::extended);
+    let job = Job {
+        input_path,
+        segment,
+        cover: cover.as_deref(),
+        output_path,
+        temp_path: &temp_path,
+        log_path: &log_path,
+        tags,
+        encoding,
+        options,
+    };
+    let outcome = match options.transcoder.transcode(&job) {
+        Ok(()) => {
+            override_tags(&temp_path, output_path, &tags.without_ffmpeg_metadata());
+            if let Some(style) = options.transliterate_tags {
+                transliterate_tags(&temp_path, output_path, style);
+            }
+            if let Some(max_len) = options.max_tag_len {
+                truncate_tags(&temp_path, output_path, max_len);
+            }
+            encode_tags(&temp_path, output_path, options);
+            let verification = if options.verify_output {
+                verify_output(input_path, &temp_path, source_duration, options)
+            } else {
+                Ok(())
+            };
+            match verification.and_then(|()| {
+                retrier
+                    .run(
+                        output_path,
+                        || atomic::commit(&temp_path, output_path, options.sync_writes),
+                        || (),
+                    )
+                    .map_err(ConvertFailure::Io)
+            }) {
+                Ok(()) => {
+                    if options.preserve_times {
+                        mtime::preserve(input_path, output_path);
+                    }
+                    ConvertOutcome::Succeeded
+                }
+                Err(failure) => ConvertOutcome::Failed(failure),
+            }
+        }
+        Err(TranscodeError::Spawn(e)) => ConvertOutcome::Failed(ConvertFailure::Spawn(e)),
+        Err(TranscodeError::Status {
+            status,
+            stderr,
+            log_path,
+        }) => ConvertOutcome::Failed(ConvertFailure::Status {
+            status,
+            kind: TranscodeFailureKind::classify(&stderr, input_path, &temp_path),
+            stderr,
+            log_path,
+        }),
+        Err(TranscodeError::Io(e)) => ConvertOutcome::Failed(ConvertFailure::Io(e)),
+        Err(TranscodeError::Interrupted) => ConvertOutcome::Interrupted,
+        Err(TranscodeError::Skipped) => {
+            warn!("{}: Skipped", output_path.display());
+            ConvertOutcome::Interrupted
+        }
+    };
+    atomic::discard(&temp_path);
+    outcome
+}
+
+/// Returns the path of the FFmpeg log file for `output_path`.
+fn log_path(output_path: &Path, options: &ExecuteOptions) -> PathBuf {
+    let relative = output_path
+        .strip_prefix(&options.output_dir)
+        .unwrap_or(output_path);
+    let mut log_path = options.output_dir.join(LOGS_DIR_NAME).join(relative);
+    log_path.as_mut_os_string().push(".log");
+    log_path
+}
+
+/// Check that a converted file is non-empty, can be parsed and matches the source's duration.
+fn verify_output(
+    input_path: &Path,
+    output_path: &Path,
+    source_duration: Option<f64>,
+    options: &ExecuteOptions,
+) -> Result<(), ConvertFailure> {
+    let size = std::fs::metadata(output_path)
+        .map_err(ConvertFailure::Io)?
+        .len();
+    if size == 0 {
+        return Err(ConvertFailure::Verification("output is empty".to_string()));
+    }
+    let output_info = probe::probe(output_path)
+        .map_err(|e| ConvertFailure::Verification(format!("failed to probe output: {}", e)))?;
+    let Some(output_duration) = output_info.duration else {
+        return Err(ConvertFailure::Verification(
+            "output has no duration".to_string(),
+        ));
+    };
+    let source_duration = match source_duration {
+        Some(duration) => duration,
+        None => match probe::probe(input_path).map(|info| info.duration) {
+            Ok(Some(duration)) => duration,
+            _ => {
+                debug!(
+                    "{}: Source duration unknown, skipping duration check",
+                    input_path.display()
+                );
+                return Ok(());
+            }
+        },
+    };
+    if (output_duration - source_duration).abs() > options.verify_tolerance {
+        return Err(ConvertFailure::Verification(format!(
+            "duration {:.1}s differs from source duration {:.1}s",
+            output_duration, source_duration
+        )));
+    }
+    Ok(())
+}
+
+/// Convert a file, retrying transient failures up to `options.retries` times.
+///
+/// Returns the final outcome and the number of attempts that were made.
+#[allow(clippy::too_many_arguments)]
+fn convert_with_retries(
+    input_path: &Path,
+    segment: Option<&Segment>,
+    output_path: &Path,
+    source_duration: Option<f64>,
+    cover: Option<&Path>,
+    tags: &TagOverrides,
+    encoding: Encoding,
+    options: &ExecuteOptions,
+    retrier: &Retrier,
+) -> (ConvertOutcome, usize) {
+    let max_attempts = options.retries + 1;
+    let mut attempt = 1;
+    loop {
+        let outcome = convert(
+            input_path,
+            segment,
+            output_path,
+            source_duration,
+            cover,
+            tags,
+            encoding,
+            options,
+            retrier,
+        );
+        match &outcome {
+            ConvertOutcome::Failed(failure)
+                if attempt < max_attempts && failure.is_retryable(input_path) =>
+            {
+                warn!(
+                    "{}: {} (attempt {}/{}), retrying...",
+                    output_path.display(),
+                    failure,
+                    attempt,
+                    max_attempts
+                );
+                std::thread::sleep(RETRY_DELAY);
+                attempt += 1;
+            }
+            _ => return (outcome, attempt),
+        }
+    }
+}
+
+/// Reason why a copy failed.
+#[derive(Debug)]
+enum CopyFailure {
+    /// The source file could not be read.
+    Source(std::io::Error),
+    Io(std::io::Error),
+    /// The copied file's checksum differs from the source.
+    Mismatch,
+    /// The `--post-hook` failed for the copied file.
+    Hook(HookError),
+}
+
+impl fmt::Display for CopyFailure {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        TaskError::from(self).fmt(f)
+    }
+}
+
+impl CopyFailure {
+    /// Returns `true` if the output could not be written.
+    fn is_write_error(&self) -> bool {
+        matches!(self, CopyFailure::Io(e) if is_unwritable(e))
+    }
+}
+
+/// Result of a single copy.
+enum CopyOutcome {
+    Succeeded {
+        /// Changes made to the tags of the copy.
+        tags: tags::Rewrite,
+        /// A cover image was attached to the copy.
+        art_embedded: bool,
+        /// The source was renamed into place instead of copied.
+        moved: bool,
+    },
+    Failed(CopyFailure),
+    /// The copy was not started because the run was interrupted.
+    Interrupted,
+}
+
+/// Compute the XXH3 hash of a file's contents.
+fn hash_file(path: &Path) -> std::io::Result<u64> {
+    let mut file = std::fs::File::open(path)?;
+    let mut hasher = Xxh3::new();
+    let mut buffer = vec![0; 64 * 1024];
+    loop {
+        let n = file.read(&mut buffer)?;
+        if n == 0 {
+            return Ok(hasher.digest());
+        }
+        hasher.update(&buffer[..n]);
+    }
+}
+
+/// Copy `input_path` to `output_path` via a temporary file.
+///
+/// If `options.verify_copies` or `options.move_sources` is set, the copy is re-read and compared
+/// against the source before its tags are rewritten (see [`tags::rewrite`]) and it is moved into
+/// place. With `relocate`, the source itself is renamed to the temporary file if both are on the
+/// same filesystem, and renamed back if the copy fails.
+fn copy(
+    input_path: &Path,
+    output_path: &Path,
+    cover: Option<&Path>,
+    tags: &TagOverrides,
+    relocate: bool,
+    options: &ExecuteOptions,
+    retrier: &Retrier,
+) -> CopyOutcome {
+    if interrupt::is_interrupted() {
+        return CopyOutcome::Interrupted;
+    }
+    let input_path = &longpath::extended(input_path);
+    let output_path = &longpath::extended(output_path);
+    // Open the source first, so that errors reading it are not mistaken for errors writing the
+    // output.
+    if let Err(e) = std::fs::File::open(input_path) {
+        return CopyOutcome::Failed(CopyFailure::Source(e));
+    }
+    if let Some(parent) = output_path.parent() {
+        if let Err(e) = retrier.run(output_path, || std::fs::create_dir_all(parent), || ()) {
+            return CopyOutcome::Failed(CopyFailure::Io(e));
+        }
+    }
+
+    let temp_path = atomic::temp_path(output_path);
+    let modified = std::fs::metadata(input_path)
+        .and_then(|metadata| metadata.modified())
+        .ok();
+    let moved = relocate
+        && match std::fs::rename(input_path, &temp_path) {
+            Ok(()) => true,
+            Err(e) => {
+                debug!(
+                    "{}: Failed to rename source, copying instead ({})",
+                    input_path.display(),
+                    e
+                );
+                false
+            }
+        };
+    let copied = if moved {
+        Ok(())
+    } else {
+        retrier
+            .run(
+                output_path,
+                || std::fs::copy(input_path, &temp_path),
+                || atomic::discard(&temp_path),
+            )
+            .map_err(CopyFailure::Io)
+            .and_then(|_| {
+                if (options.verify_copies || options.move_sources)
+                    && hash_file(input_path).map_err(CopyFailure::Io)?
+                        != hash_file(&temp_path).map_err(CopyFailure::Io)?
+                {
+                    return Err(CopyFailure::Mismatch);
+                }
+                Ok(())
+            })
+    };
+    let result = copied
+        .map(|()| {
+            let overrides = tags;
+            override_tags(&temp_path, output_path, overrides);
+            let transliterated = options
+                .transliterate_tags
+                .is_some_and(|style| transliterate_tags(&temp_path, output_path, style));
+            let tags = rewrite_tags(&temp_path, output_path, options);
+            let art_embedded =
+                cover.is_some_and(|cover| embed_cover(&temp_path, output_path, cover, options));
+            if options.fix_tags || !overrides.is_empty() || transliterated || art_embedded {
+                encode_tags(&temp_path, output_path, options);
+            }
+            (tags, art_embedded)
+        })
+        .and_then(|result| {
+            retrier
+                .run(
+                    output_path,
+                    || atomic::commit(&temp_path, output_path, options.sync_writes),
+                    || (),
+                )
+                .map(|()| result)
+                .map_err(CopyFailure::Io)
+        });
+    match result {
+        Ok((tags, art_embedded)) => {
+            if options.preserve_times {
+                match modified.filter(|_| moved) {
+                    Some(modified) => mtime::restore(output_path, modified),
+                    None => mtime::preserve(input_path, output_path),
+                }
+            }
+            CopyOutcome::Succeeded {
+                tags,
+                art_embedded,
+                moved,
+            }
+        }
+        Err(failure) => {
+            if moved {
+                if let Err(e) = std::fs::rename(&temp_path, input_path) {
+                    report_warning!(
+                        WarningCategory::Copy,
+                        "{}: Failed to move source back from {} ({})",
+                        input_path.display(),
+                        temp_path.display(),
+                        e
+                    );
+                }
+            } else {
+                atomic::discard(&temp_path);
+            }
+            CopyOutcome::Failed(failure)
+        }
+    }
+}
+
+/// Rewrite the tags of the copy at `temp_path` as requested, reporting failures as warnings.
+fn rewrite_tags(temp_path: &Path, output_path: &Path, options: &ExecuteOptions) -> tags::Rewrite {
+    if !options.fix_tags && options.strip_frames.is_none() {
+        return tags::Rewrite::default();
+    }
+    let max_tag_len = options.max_tag_len.filter(|_| options.fix_tags);
+    match tags::rewrite(
+        temp_path,
+        options.fix_tags,
+        options.strip_frames.as_ref(),
+        max_tag_len,
+    ) {
+        Ok(rewrite) => {
+            log_truncations(output_path, &rewrite);
+            rewrite
+        }
+        Err(e) => {
+            report_warning!(
+                WarningCategory::Tags,
+                "{}: Failed to rewrite tags ({})",
+                output_path.display(),
+                e
+            );
+            tags::Rewrite::default()
+        }
+    }
+}
+
+/// Write the overridden tags into the output at `temp_path`, reporting failures as warnings.
+fn override_tags(temp_path: &Path, output_path: &Path, tags: &TagOverrides) {
+    if let Err(e) = tags::apply_overrides(temp_path, tags) {
+        report_warning!(
+            WarningCategory::Tags,
+            "{}: Failed to override tags ({})",
+            output_path.display(),
+            e
+        );
+    }
+}
+
+/// Romanize the tags of the output at `temp_path`, reporting failures as warnings. Returns `true`
+/// if a frame was changed.
+fn transliterate_tags(temp_path: &Path, output_path: &Path, style: TagTransliteration) -> bool {
+    match tags::transliterate(temp_path, style) {
+        Ok(frames) => {
+            if !frames.is_empty() {
+                debug!(
+                    "{}: Transliterated {}",
+                    output_path.display(),
+                    frames.join(", ")
+                );
+            }
+            !frames.is_empty()
+        }
+        Err(e) => {
+            report_warning!(
+                WarningCategory::Tags,
+                "{}: Failed to transliterate tags ({})",
+                output_path.display(),
+                e
+            );
+            false
+        }
+    }
+}
+
+/// Write the text frames of the output at `temp_path` in the configured encoding, reporting
+/// failures and frames that cannot be written as Latin-1 as warnings.
+fn encode_tags(temp_path: &Path, output_path: &Path, options: &ExecuteOptions) {
+    let Some(encoding) = options.id3_encoding else {
+        return;
+    };
+    match tags::set_encoding(temp_path, encoding) {
+        Ok(fallbacks) if !fallbacks.is_empty() => report_warning!(
+            WarningCategory::Tags,
+            "{}: Writing {} as UTF-16 (not representable in Latin-1)",
+            output_path.display(),
+            fallbacks.join(", ")
+        ),
+        Ok(_) => (),
+        Err(e) => report_warning!(
+            WarningCategory::Tags,
+            "{}: Failed to set text encoding ({})",
+            output_path.display(),
+            e
+        ),
+    }
+}
+
+/// Truncate overlong text frames of the conversion at `temp_path`, reporting failures as
+/// warnings.
+fn truncate_tags(temp_path: &Path, output_path: &Path, max_len: usize) {
+    match tags::rewrite(temp_path, false, None, Some(max_len)) {
+        Ok(rewrite) => log_truncations(output_path, &rewrite),
+        Err(e) => report_warning!(
+            WarningCategory::Tags,
+            "{}: Failed to truncate tags ({})",
+            output_path.display(),
+            e
+        ),
+    }
+}
+
+fn log_truncations(output_path: &Path, rewrite: &tags::Rewrite) {
+    for truncation in rewrite.truncations.iter() {
+        debug!(
+            "{}: Truncated {} ({:?} to {:?})",
+            output_path.display(),
+            truncation.frame,
+            truncation.before,
+            truncation.after
+        );
+    }
+}
+
+/// Attach `cover` to the copy at `temp_path` unless it already has embedded art, reporting
+/// failures as warnings.
+fn embed_cover(
+    temp_path: &Path,
+    output_path: &Path,
+    cover: &Path,
+    options: &ExecuteOptions,
+) -> bool {
+    let result = tags::has_picture(temp_path)
+        .map_err(|e| e.to_string())
+        .and_then(|has_picture| {
+            if has_picture {
+                return Ok(false);
+            }
+            let jpeg = art::load_image(&longpath::extended(cover), options.art_size)?;
+            tags::add_picture(temp_path, jpeg).map_err(|e| e.to_string())?;
+            Ok(true)
+        });
+    result.unwrap_or_else(|e| {
+        report_warning!(
+            WarningCategory::Art,
+            "{}: Failed to attach {} ({})",
+            output_path.display(),
+            cover.display(),
+            e
+        );
+        false
+    })
+}
+
+/// Returns the cover image next to `source` to attach to its output, if the source has no
+/// embedded art.
+pub fn sidecar_cover(plan: &Plan, source: &Path, options: &ExecuteOptions) -> Option<PathBuf> {
+    if plan
+        .metadata
+        .get(source)
+        .is_some_and(|info| info.pictures > 0)
+    {
+        return None;
+    }
+    art::find_sidecar(source.parent()?, &options.cover_names)
+}
+
+/// Copy a file, retrying checksum mismatches up to `options.retries` times and transient write
+/// errors up to `options.io_retries` times.
+///
+/// Returns the final outcome and the number of attempts that were made.
+fn copy_with_retries(
+    input_path: &Path,
+    output_path: &Path,
+    cover: Option<&Path>,
+    tags: &TagOverrides,
+    relocate: bool,
+    options: &ExecuteOptions,
+    retrier: &Retrier,
+) -> (CopyOutcome, usize) {
+    let max_attempts = options.retries + 1;
+    let mut attempt = 1;
+    loop {
+        let outcome = copy(
+            input_path,
+            output_path,
+            cover,
+            tags,
+            relocate,
+            options,
+            retrier,
+        );
+        match &outcome {
+            CopyOutcome::Failed(failure @ CopyFailure::Mismatch) if attempt < max_attempts => {
+                warn!(
+                    "{}: {} (attempt {}/{}), retrying...",
+                    output_path.display(),
+                    failure,
+                    attempt,
+                    max_attempts
+                );
+                attempt += 1;
+            }
+            _ => return (outcome, attempt),
+        }
+    }
+}
+
+/// Final status of a task of the plan.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum TaskStatus {
+    /// The task was not started, e.g. because of a dry run or an earlier error.
+    #[default]
+    NotAttempted,
+    Succeeded,
+    Failed,
+    Interrupted,
+}
+
+/// Why a task failed.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum TaskError {
+    /// The source file does not exist (anymore).
+    MissingSource,
+    /// The source file could not be read.
+    UnreadableSource(String),
+    /// The directory of the output file could not be created.
+    OutputDirectory(String),
+    /// FFmpeg could not be started.
+    TranscoderUnavailable(String),
+    /// FFmpeg exited with a non-zero status.
+    TranscodeFailed {
+        status: String,
+        kind: TranscodeFailureKind,
+        stderr: String,
+        /// Log file containing the command line and FFmpeg's output.
+        log_path: Option<PathBuf>,
+    },
+    /// The converted file did not pass verification.
+    OutputValidation(String),
+    /// Copying the file failed.
+    CopyFailed(String),
+    /// The copied file's checksum differs from the source.
+    ChecksumMismatch,
+    /// Moving the converted file into place failed.
+    Io(String),
+    /// The `--post-hook` failed for the output file.
+    Hook(String),
+}
+
+/// The variant of a [`TaskError`], without details.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum TaskErrorKind {
+    MissingSource,
+    UnreadableSource,
+    OutputDirectory,
+    TranscoderUnavailable,
+    TranscodeFailed,
+    OutputValidation,
+    CopyFailed,
+    ChecksumMismatch,
+    Io,
+    Hook,
+}
+
+impl TaskError {
+    pub fn kind(&self) -> TaskErrorKind {
+        match self {
+            TaskError::MissingSource => TaskErrorKind::MissingSource,
+            TaskError::UnreadableSource(_) => TaskErrorKind::UnreadableSource,
+            TaskError::OutputDirectory(_) => TaskErrorKind::OutputDirectory,
+            TaskError::TranscoderUnavailable(_) => TaskErrorKind::TranscoderUnavailable,
+            TaskError::TranscodeFailed { .. } => TaskErrorKind::TranscodeFailed,
+            TaskError::OutputValidation(_) => TaskErrorKind::OutputValidation,
+            TaskError::CopyFailed(_) => TaskErrorKind::CopyFailed,
+            TaskError::ChecksumMismatch => TaskErrorKind::ChecksumMismatch,
+            TaskError::Io(_) => TaskErrorKind::Io,
+            TaskError::Hook(_) => TaskErrorKind::Hook,
+        }
+    }
+
+    /// Returns why FFmpeg failed, if it did.
+    pub fn transcode_failure(&self) -> Option<TranscodeFailureKind> {
+        match self {
+            TaskError::TranscodeFailed { kind, .. } => Some(*kind),
+            _ => None,
+        }
+    }
+}
+
+impl fmt::Display for TaskError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            TaskError::MissingSource => write!(f, "Source file does not exist"),
+            TaskError::UnreadableSource(e) => write!(f, "Failed to read source file ({})", e),
+            TaskError::OutputDirectory(e) => {
+                write!(f, "Failed to create output directory ({})", e)
+            }
+            TaskError::TranscoderUnavailable(e) => write!(f, "Failed to execute FFmpeg ({})", e),
+            TaskError::TranscodeFailed {
+                status, log_path, ..
+            } => {
+                write!(f, "FFmpeg exited with non-zero status {}", status)?;
+                if let Some(log_path) = log_path {
+                    write!(f, " (see {})", log_path.display())?;
+                }
+                Ok(())
+            }
+            TaskError::OutputValidation(reason) => {
+                write!(f, "Output verification failed ({})", reason)
+            }
+            TaskError::CopyFailed(e) => write!(f, "Failed to copy file ({})", e),
+            TaskError::ChecksumMismatch => {
+                write!(f, "Checksum of copied file does not match source")
+            }
+            TaskError::Io(e) => write!(f, "Failed to move converted file into place ({})", e),
+            TaskError::Hook(e) => write!(f, "Post-hook failed ({})", e),
+        }
+    }
+}
+
+impl std::error::Error for TaskError {}
+
+impl From<&ConvertFailure> for TaskError {
+    fn from(failure: &ConvertFailure) -> Self {
+        match failure {
+            ConvertFailure::MissingSource => TaskError::MissingSource,
+            ConvertFailure::Spawn(e) => TaskError::TranscoderUnavailable(e.to_string()),
+            ConvertFailure::Directory(e) => TaskError::OutputDirectory(e.to_string()),
+            ConvertFailure::Status {
+                status,
+                kind,
+                stderr,
+                log_path,
+            } => TaskError::TranscodeFailed {
+                status: status.clone(),
+                kind: *kind,
+                stderr: stderr.clone(),
+                log_path: log_path.clone(),
+            },
+            ConvertFailure::Verification(reason) => TaskError::OutputValidation(reason.clone()),
+            ConvertFailure::Io(e) => TaskError::Io(e.to_string()),
+            ConvertFailure::Hook(e) => TaskError::Hook(e.to_string()),
+        }
+    }
+}
+
+impl From<&CopyFailure> for TaskError {
+    fn from(failure: &CopyFailure) -> Self {
+        match failure {
+            CopyFailure::Source(e) if e.kind() == ErrorKind::NotFound => TaskError::MissingSource,
+            CopyFailure::Source(e) => TaskError::UnreadableSource(e.to_string()),
+            CopyFailure::Io(e) => TaskError::CopyFailed(e.to_string()),
+            CopyFailure::Mismatch => TaskError::ChecksumMismatch,
+            CopyFailure::Hook(e) => TaskError::Hook(e.to_string()),
+        }
+    }
+}
+
+/// Result of a task of the plan.
+#[derive(Clone, Debug, Default)]
+pub struct TaskResult {
+    pub status: TaskStatus,
+    /// Why the task failed.
+    pub error: Option<TaskError>,
+    /// Number of attempts made.
+    pub attempts: usize,
+    /// Time spent on the task.
+    pub elapsed: Duration,
+    /// The remux failed and the source was converted instead.
+    pub converted_instead: bool,
+    /// Number of transient errors writing the output that were retried.
+    pub io_retries: usize,
+}
+
+impl TaskResult {
+    /// Returns the action that was actually performed for a task planned with `planned`.
+    pub fn action(&self, planned: Action) -> Action {
+        if self.converted_instead {
+            Action::Convert
+        } else {
+            planned
+        }
+    }
+
+    fn new(outcome: &ConvertOutcome, attempts: usize, elapsed: Duration) -> Self {
+        let (status, error) = match outcome {
+            ConvertOutcome::Succeeded => (TaskStatus::Succeeded, None),
+            ConvertOutcome::Failed(failure) => (TaskStatus::Failed, Some(failure.into())),
+            ConvertOutcome::Interrupted => (TaskStatus::Interrupted, None),
+        };
+        Self {
+            status,
+            error,
+            attempts,
+            elapsed,
+            converted_instead: false,
+            io_retries: 0,
+        }
+    }
+
+    fn copy(outcome: &CopyOutcome, attempts: usize, elapsed: Duration) -> Self {
+        let (status, error) = match outcome {
+            CopyOutcome::Succeeded { .. } => (TaskStatus::Succeeded, None),
+            CopyOutcome::Failed(failure) => (TaskStatus::Failed, Some(failure.into())),
+            CopyOutcome::Interrupted => (TaskStatus::Interrupted, None),
+        };
+        Self {
+            status,
+            error,
+            attempts,
+            elapsed,
+            converted_instead: false,
+            io_retries: 0,
+        }
+    }
+
+    /// Returns `true` if the task succeeded after retrying transient write errors.
+    pub fn recovered(&self) -> bool {
+        self.status == TaskStatus::Succeeded && self.io_retries > 0
+    }
+
+    /// Returns `true` if the task failed because of the `--post-hook`.
+    pub fn hook_failed(&self) -> bool {
+        matches!(self.error, Some(TaskError::Hook(_)))
+    }
+
+    /// Returns `true` if the task wrote its output, even if the `--post-hook` failed afterwards.
+    pub fn wrote_output(&self) -> bool {
+        self.status == TaskStatus::Succeeded || self.hook_failed()
+    }
+}
+
+/// Run the `--post-hook` for an output file, passing the output and source paths as arguments
+/// and in environment variables.
+fn run_post_hook(
+    input_path: &Path,
+    output_path: &Path,
+    options: &ExecuteOptions,
+) -> Result<(), HookError> {
+    let Some(hook) = &options.post_hook else {
+        return Ok(());
+    };
+    hook.run(
+        &output_path.display().to_string(),
+        [
+            ("FORD_SYNC_OUTPUT", output_path.as_os_str()),
+            ("FORD_SYNC_SOURCE", input_path.as_os_str()),
+        ],
+        &[output_path.as_os_str(), input_path.as_os_str()],
+    )
+}
+
+/// Results of [`execute`].
+#[derive(Debug)]
+pub struct Execution {
+    /// Results of the tasks, in the order of [`Plan::tasks`].
+    pub results: Vec<TaskResult>,
+    /// Set if the run was stopped because the output directory is not writable.
+    pub error: Option<ExecuteError>,
+}
+
+/// Convert and copy all files of the plan, notifying the observers of every task.
+///
+/// Individual failures are reported as warnings. The run is only stopped early if the output
+/// directory turned out not to be writable at all.
+pub fn execute(plan: &Plan, options: &ExecuteOptions, observers: &Observers) -> Execution {
+    let mut results = vec![TaskResult::default(); plan.tasks.len()];
+    let mut moved = vec![false; plan.tasks.len()];
+    let error = execute_tasks(plan, options, observers, &mut results, &mut moved).err();
+    if options.move_sources || options.move_converted_sources {
+        remove_sources(plan, options, &results, &moved);
+    }
+    Execution { results, error }
+}
+
+/// Returns the indices of the tasks of each source.
+fn tasks_by_source(plan: &Plan) -> BTreeMap<&Path, Vec<usize>> {
+    let mut sources: BTreeMap<&Path, Vec<usize>> = BTreeMap::new();
+    for (index, task) in plan.tasks.iter().enumerate() {
+        sources.entry(&task.source).or_default().push(index);
+    }
+    sources
+}
+
+/// Remove the sources that were copied or converted for `--move`, once all of their tasks
+/// succeeded. Sources that were renamed into place are only counted.
+fn remove_sources(plan: &Plan, options: &ExecuteOptions, results: &[TaskResult], moved: &[bool]) {
+    let mut num_moved = 0;
+    for (source, tasks) in tasks_by_source(plan) {
+        if tasks.iter().any(|&task| moved[task]) {
+            num_moved += 1;
+            continue;
+        }
+        let is_moved = |task: usize| match plan.tasks[task].action {
+            Action::Copy => options.move_sources,
+            Action::Convert | Action::Remux => options.move_converted_sources,
+        };
+        if !tasks
+            .iter()
+            .all(|&task| is_moved(task) && results[task].status == TaskStatus::Succeeded)
+        {
+            continue;
+        }
+        match std::fs::remove_file(longpath::extended(source)) {
+            Ok(()) => {
+                debug!("{}: Removed source", source.display());
+                num_moved += 1;
+            }
+            Err(e) => report_warning!(
+                WarningCategory::Copy,
+                "{}: Failed to remove source ({})",
+                source.display(),
+                e
+            ),
+        }
+    }
+    info!("Moved {} sources into the output directory.", num_moved);
+}
+
+/// Returns the indices, sources and destinations of the tasks with the given action.
+///
+/// With `keep_existing`, tasks whose output already exists are left out, and with `since`, those
+/// whose output exists and whose source is older than the cutoff.
+fn files(plan: &Plan, action: Action, options: &ExecuteOptions) -> Vec<(usize, PathBuf, PathBuf)> {
+    plan.tasks
+        .iter()
+        .enumerate()
+        .filter(|(_, task)| task.action == action)
+        .filter(|(_, task)| {
+            !options.keep_existing || !longpath::extended(&task.destination).exists()
+        })
+        .filter(|(_, task)| {
+            let unchanged = options
+                .since
+                .is_some_and(|cutoff| since::is_unchanged(task, cutoff));
+            if unchanged {
+                debug!(
+                    "{}: Not modified since the cutoff, keeping existing output",
+                    task.source.display()
+                );
+            }
+            !unchanged
+        })
+        .map(|(index, task)| (index, task.source.clone(), task.destination.clone()))
+        .collect()
+}
+
+fn execute_tasks(
+    plan: &Plan,
+    options: &ExecuteOptions,
+    observers: &Observers,
+    results: &mut [TaskResult],
+    moved: &mut [bool],
+) -> Result<(), ExecuteError> {
+    let tasks_by_source = tasks_by_source(plan);
+    let files_to_copy = files(plan, Action::Copy, options);
+    let mut files_to_convert = files(plan, Action::Convert, options);
+    let files_to_remux = files(plan, Action::Remux, options);
+
+    info!("Files to copy: {}", files_to_copy.len());
+    info!("Files to convert: {}", files_to_convert.len());
+    if !files_to_remux.is_empty() {
+        info!("Files to remux: {}", files_to_remux.len());
+    }
+    // Remuxes run through FFmpeg like conversions.
+    files_to_convert.extend(files_to_remux);
+    if let Some(cutoff) = options.since {
+        let num_unchanged = plan
+            .tasks
+            .iter()
+            .filter(|task| since::is_unchanged(task, cutoff))
+            .count();
+        info!("Files not modified since the cutoff: {}", num_unchanged);
+    }
+
+    let num_copy_tasks = files_to_copy.len();
+    let num_convert_tasks = files_to_convert.len();
+    let num_tasks_total = num_copy_tasks + num_convert_tasks;
+
+    info!("Starting convert files...");
+
+    let pool = ThreadPool::new(WORKERS);
+    let mut write_errors = WriteErrors::new(options.max_write_errors);
+    let stall = Arc::new(Stall::default());
+
+    #[allow(clippy::type_complexity)]
+    let (tx, rx) = channel::<(
+        usize,
+        PathBuf,
+        ConvertOutcome,
+        usize,
+        usize,
+        bool,
+        bool,
+        Duration,
+    )>();
+    for (task, input_path, output_path) in files_to_convert.into_iter() {
+        let tx = tx.clone();
+        let options = options.clone();
+        let observers = observers.clone();
+        let planned = plan.tasks[task].clone();
+        let halted = Arc::clone(&write_errors.halted);
+        let stall = Arc::clone(&stall);
+        let source_duration = planned.duration(&plan.metadata);
+        let cover = options
+            .embed_art
+            .then(|| sidecar_cover(plan, &input_path, &options))
+            .flatten();
+        pool.execute(move || {
+            let _span = Span::task(&planned).enter();
+            let started = Instant::now();
+            let mut fell_back = false;
+            stall.wait();
+            interrupt::wait_while_paused();
+            let retrier = Retrier::new(options.io_retries, &stall);
+            let (outcome, attempts) = if halted.load(Ordering::SeqCst) {
+                (ConvertOutcome::Interrupted, 0)
+            } else {
+                observers.task_started(task, &planned);
+                let convert = |encoding| {
+                    convert_with_retries(
+                        &input_path,
+                        planned.segment.as_ref(),
+                        &output_path,
+                        source_duration,
+                        cover.as_deref(),
+                        &planned.tags,
+                        encoding,
+                        &options,
+                        &retrier,
+                    )
+                };
+                let encoding = planned.encoding();
+                let result = match convert(encoding) {
+                    (ConvertOutcome::Failed(failure), attempts)
+                        if encoding == Encoding::Remux && failure.is_remux_failure() =>
+                    {
+                        warn!(
+                            "{}: Remux failed, converting instead ({})",
+                            output_path.display(),
+                            failure
+                        );
+                        fell_back = true;
+                        let (outcome, more_attempts) = convert(Encoding::Encode(None));
+                        (outcome, attempts + more_attempts)
+                    }
+                    result => result,
+                };
+                // A skip that came too late to stop FFmpeg must not hit a later conversion.
+                interrupt::take_skip(&output_path);
+                match result {
+                    (ConvertOutcome::Succeeded, attempts) => {
+                        match run_post_hook(&input_path, &output_path, &options) {
+                            Ok(()) => (ConvertOutcome::Succeeded, attempts),
+                            Err(e) => (ConvertOutcome::Failed(ConvertFailure::Hook(e)), attempts),
+                        }
+                    }
+                    result => result,
+                }
+            };
+            let has_cover = cover.is_some();
+            tx.send((
+                task,
+                output_path,
+                outcome,
+                attempts,
+                retrier.retried(),
+                has_cover,
+                fell_back,
+                started.elapsed(),
+            ))
+            .expect("channel will be there waiting for the pool");
+        });
+    }
+
+    let mut num_interrupted = 0;
+    let mut num_verified = 0;
+    let mut num_verification_failed = 0;
+    let mut num_art_embedded = 0;
+    for (i, (task, output_path, outcome, attempts, io_retries, has_cover, fell_back, elapsed)) in
+        rx.iter().take(num_convert_tasks).enumerate()
+    {
+        let index = i + 1;
+        results[task] = TaskResult {
+            converted_instead: fell_back,
+            io_retries,
+            ..TaskResult::new(&outcome, attempts, elapsed)
+        };
+        let progress = Progress {
+            done: index,
+            total: num_tasks_total,
+        };
+        let _span = Span::task(&plan.tasks[task]).enter();
+        observers.task_finished(progress, &plan.tasks[task], &results[task]);
+        match outcome {
+            ConvertOutcome::Succeeded => {
+                write_errors.succeeded();
+                if has_cover {
+                    num_art_embedded += 1;
+                }
+                if options.verify_output {
+                    num_verified += 1;
+                }
+            }
+            ConvertOutcome::Failed(failure) => {
+                if failure.is_write_error()
+                    && !write_errors.failed(format!("{}: {}", output_path.display(), failure))
+                {
+                    continue;
+                }
+                let category = if matches!(failure, ConvertFailure::Verification(_)) {
+                    num_verification_failed += 1;
+                    WarningCategory::Verification
+                } else if matches!(failure, ConvertFailure::Hook(_)) {
+                    WarningCategory::Hook
+                } else {
+                    WarningCategory::Conversion
+                };
+                if attempts > 1 {
+                    report_warning!(
+                        category,
+                        "({}/{}) {}: {} (attempt {}/{})",
+                        index,
+                        num_tasks_total,
+                        output_path.display(),
+                        failure,
+                        attempts,
+                        options.retries + 1
+                    );
+                } else {
+                    report_warning!(
+                        category,
+                        "({}/{}) {}: {}",
+                        index,
+                        num_tasks_total,
+                        output_path.display(),
+                        failure
+                    );
+                }
+            }
+            ConvertOutcome::Interrupted => {
+                num_interrupted += 1;
+            }
+        }
+    }
+
+    if options.verify_output {
+        info!(
+            "Verified {} converted files, {} failed verification.",
+            num_verified, num_verification_failed
+        );
+    }
+
+    if options.embed_art {
+        info!(
+            "Attached cover images to {} converted files.",
+            num_art_embedded
+        );
+    }
+
+    if write_errors.is_halted() {
+        return Err(write_errors.into_error(&options.output_dir, num_interrupted + num_copy_tasks));
+    }
+
+    if interrupt::is_interrupted() {
+        warn!(
+            "Run interrupted, {} conversions were cancelled.",
+            num_interrupted
+        );
+        return Ok(());
+    }
+
+    info!("Starting to copy files...");
+
+    let (tx, rx) = channel::<(usize, PathBuf, CopyOutcome, usize, usize, Duration)>();
+    for (task, input_path, output_path) in files_to_copy.into_iter() {
+        let tx = tx.clone();
+        let options = options.clone();
+        let observers = observers.clone();
+        let planned = plan.tasks[task].clone();
+        let halted = Arc::clone(&write_errors.halted);
+        let stall = Arc::clone(&stall);
+        let cover = options
+            .embed_art_copies
+            .then(|| sidecar_cover(plan, &input_path, &options))
+            .flatten();
+        // Sources of several tasks are only removed once all of them are done.
+        let relocate = options.move_sources
+            && tasks_by_source
+                .get(input_path.as_path())
+                .is_some_and(|tasks| tasks.len() == 1);
+        pool.execute(move || {
+            let _span = Span::task(&planned).enter();
+            let started = Instant::now();
+            stall.wait();
+            interrupt::wait_while_paused();
+            let retrier = Retrier::new(options.io_retries, &stall);
+            let (outcome, attempts) = if halted.load(Ordering::SeqCst) {
+                (CopyOutcome::Interrupted, 0)
+            } else {
+                observers.task_started(task, &planned);
+                match copy_with_retries(
+                    &input_path,
+                    &output_path,
+                    cover.as_deref(),
+                    &planned.tags,
+                    relocate,
+                    &options,
+                    &retrier,
+                ) {
+                    (outcome @ CopyOutcome::Succeeded { .. }, attempts) => {
+                        match run_post_hook(&input_path, &output_path, &options) {
+                            Ok(()) => (outcome, attempts),
+                            Err(e) => (CopyOutcome::Failed(CopyFailure::Hook(e)), attempts),
+                        }
+                    }
+                    result => result,
+                }
+            };
+            let io_retries = retrier.retried();
+            tx.send((
+                task,
+                output_path,
+                outcome,
+                attempts,
+                io_retries,
+                started.elapsed(),
+            ))
+            .expect("channel will be there waiting for the pool");
+        });
+    }
+
+    let mut num_interrupted = 0;
+    let mut num_verified = 0;
+    let mut num_mismatches = 0;
+    let mut num_tags_fixed = 0;
+    let mut num_frames_stripped = 0;
+    let mut num_art_embedded = 0;
+    let mut bytes_saved = 0;
+    for (i, (task, output_path, outcome, attempts, io_retries, elapsed)) in
+        rx.iter().take(num_copy_tasks).enumerate()
+    {
+        let index = i + num_convert_tasks + 1;
+        results[task] = TaskResult {
+            io_retries,
+            ..TaskResult::copy(&outcome, attempts, elapsed)
+        };
+        let progress = Progress {
+            done: index,
+            total: num_tasks_total,
+        };
+        let _span = Span::task(&plan.tasks[task]).enter();
+        observers.task_finished(progress, &plan.tasks[task], &results[task]);
+        match outcome {
+            CopyOutcome::Succeeded {
+                tags,
+                art_embedded,
+                moved: renamed,
+            } => {
+                write_errors.succeeded();
+                moved[task] = renamed;
+                if art_embedded {
+                    num_art_embedded += 1;
+                }
+                if tags.version_fixed {
+                    num_tags_fixed += 1;
+                }
+                if tags.frames_stripped {
+                    num_frames_stripped += 1;
+                }
+                bytes_saved += tags.bytes_saved;
+                if options.verify_copies {
+                    num_verified += 1;
+                }
+            }
+            CopyOutcome::Failed(failure) => {
+                if failure.is_write_error()
+                    && !write_errors.failed(format!("{}: {}", output_path.display(), failure))
+                {
+                    continue;
+                }
+                let category = if matches!(failure, CopyFailure::Mismatch) {
+                    num_mismatches += 1;
+                    WarningCategory::Verification
+                } else if matches!(failure, CopyFailure::Hook(_)) {
+                    WarningCategory::Hook
+                } else {
+                    WarningCategory::Copy
+                };
+                if attempts > 1 {
+                    report_warning!(
+                        category,
+                        "({}/{}) {}: {} (attempt {}/{})",
+                        index,
+                        num_tasks_total,
+                        output_path.display(),
+                        failure,
+                        attempts,
+                        options.retries + 1
+                    );
+                } else {
+                    report_warning!(
+                        category,
+                        "({}/{}) {}: {}",
+                        index,
+                        num_tasks_total,
+                        output_path.display(),
+                        failure
+                    );
+                }
+            }
+            CopyOutcome::Interrupted => {
+                num_interrupted += 1;
+            }
+        }
+    }
+
+    if options.verify_copies {
+        info!(
+            "Verified {} copied files, {} checksum mismatches.",
+            num_verified, num_mismatches
+        );
+    }
+    if options.embed_art_copies {
+        info!(
+            "Attached cover images to {} copied files.",
+            num_art_embedded
+        );
+    }
+    if options.strip_frames.is_some() {
+        info!(
+            "Stripped frames from {} copied files, saving {}.",
+            num_frames_stripped,
+            ByteSize(bytes_saved)
+        );
+    }
+    if options.fix_tags {
+        info!(
+            "Rewrote tags of {} copied files as ID3v2.3.",
+            num_tags_fixed
+        );
+    }
+
+    if write_errors.is_halted() {
+        return Err(write_errors.into_error(&options.output_dir, num_interrupted));
+    }
+
+    if interrupt::is_interrupted() {
+        warn!(
+            "Run interrupted, {} copies were cancelled.",
+            num_interrupted
+        );
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    #[cfg(windows)]
+    use std::process::{Command, Stdio};
+
+    #[test]
+    fn missing_sources_have_their_own_error() {
+        let missing = CopyFailure::Source(std::io::Error::from(ErrorKind::NotFound));
+        assert_eq!(TaskError::from(&missing), TaskError::MissingSource);
+        let denied = CopyFailure::Source(std::io::Error::from(ErrorKind::PermissionDenied));
+        assert_eq!(
+            TaskError::from(&denied).kind(),
+            TaskErrorKind::UnreadableSource
+        );
+        assert_eq!(missing.to_string(), TaskError::MissingSource.to_string());
+    }
+
+    #[test]
+    fn write_errors_halt_after_consecutive_failures() {
+        let mut write_errors = WriteErrors::new(2);
+        assert!(write_errors.failed("a".to_string()));
+        write_errors.succeeded();
+        assert!(write_errors.failed("b".to_string()));
+        assert!(!write_errors.is_halted());
+        assert!(!write_errors.failed("c".to_string()));
+        assert!(write_errors.is_halted());
+    }
+
+    #[test]
+    fn classifies_ffmpeg_failures() {
+        let input_path = Path::new("music/a.flac");
+        let temp_path = Path::new("out/.tmp.a.mp3.1");
+        let classify = |stderr| TranscodeFailureKind::classify(stderr, input_path, temp_path);
+        assert_eq!(
+            classify("out/.tmp.a.mp3.1: Permission denied"),
+            TranscodeFailureKind::Unwritable
+        );
+        assert_eq!(
+            classify("a.flac: No such file or directory"),
+            TranscodeFailureKind::MissingFile
+        );
+        assert_eq!(
+            classify("[asf @ 0x1] DRM protected stream detected, decoding will likely fail!"),
+            TranscodeFailureKind::Protected
+        );
+        assert_eq!(
+            classify("  Stream #0:0(und): Audio: none (drms / 0x736D7264), 44100 Hz, 2 channels"),
+            TranscodeFailureKind::Protected
+        );
+        assert_eq!(
+            classify("Invalid data found when processing input"),
+            TranscodeFailureKind::Other
+        );
+    }
+
+    #[test]
+    fn paths_and_tags_do_not_look_protected() {
+        let input_path = Path::new("music/drm-free/Protected Memories.flac");
+        let temp_path = Path::new("out/drm-free/.tmp.Protected Memories.mp3.1");
+        let stderr = "\
+Input #0, flac, from 'music/drm-free/Protected Memories.flac':
+  Metadata:
+    title           : DRM protected stream detected
+    album           : Protected (drms / 0x736D7264)
+music/drm-free/Protected Memories.flac: Invalid data found when processing input
+[out#0/mp3 @ 0x1] Error opening output out/drm-free/.tmp.Protected Memories.mp3.1";
+        assert_eq!(
+            TranscodeFailureKind::classify(stderr, input_path, temp_path),
+            TranscodeFailureKind::Other
+        );
+    }
+
+    #[test]
+    fn read_only_destination_is_a_write_error() {
+        let failure = ConvertFailure::Io(std::io::Error::from(ErrorKind::ReadOnlyFilesystem));
+        assert!(failure.is_write_error());
+        let failure = CopyFailure::Source(std::io::Error::from(ErrorKind::PermissionDenied));
+        assert!(!failure.is_write_error());
+    }
+
+    #[test]
+    fn moves_sources_once_all_tasks_succeeded() {
+        let dir = std::env::temp_dir().join(format!("ford-sync-move-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(dir.join("music")).unwrap();
+        let task = |source: &str, destination: &str| {
+            let source = dir.join("music").join(source);
+            std::fs::write(&source, source.to_string_lossy().as_bytes()).unwrap();
+            crate::plan::Task {
+                action: Action::Copy,
+                source,
+                destination: dir.join("out").join(destination),
+                tags: TagOverrides::default(),
+                encoder: None,
+                segment: None,
+            }
+        };
+        let plan = Plan {
+            tasks: vec![
+                task("single.mp3", "single.mp3"),
+                task("shared.mp3", "a/shared.mp3"),
+                task("shared.mp3", "b/shared.mp3"),
+            ],
+            ..Default::default()
+        };
+        let options = ExecuteOptions {
+            output_dir: dir.join("out"),
+            move_sources: true,
+            ..Default::default()
+        };
+        let execution = execute(&plan, &options, &Observers::default());
+        assert!(execution
+            .results
+            .iter()
+            .all(|result| result.status == TaskStatus::Succeeded));
+        for task in plan.tasks.iter() {
+            assert!(!task.source.exists());
+            assert_eq!(
+                std::fs::read(&task.destination).unwrap(),
+                task.source.to_string_lossy().as_bytes()
+            );
+        }
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[cfg(windows)]
+    fn long_output_path(dir: &Path, file_name: &str) -> PathBuf {
+        let mut path = dir.to_path_buf();
+        for i in 0..6 {
+            path.push(format!("{}-{}", i, "a".repeat(50)));
+        }
+        path.push(file_name);
+        assert!(path.as_os_str().len() > 260);
+        path
+    }
+
+    #[cfg(windows)]
+    #[test]
+    fn copy_beyond_max_path() {
+        let dir = std::env::temp_dir().join(format!("ford-sync-execute-{}", std::process::id()));
+        let source = dir.join("source.mp3");
+        std::fs::create_dir_all(&dir).unwrap();
+        std::fs::write(&source, b"data").unwrap();
+        let destination = long_output_path(&dir, "copied.mp3");
+        let options = ExecuteOptions {
+            verify_copies: true,
+            ..Default::default()
+        };
+        assert!(matches!(
+            copy(
+                &source,
+                &destination,
+                None,
+                &TagOverrides::default(),
+                false,
+                &options,
+                &Retrier::new(0, &Stall::default())
+            ),
+            CopyOutcome::Succeeded { .. }
+        ));
+        assert!(longpath::extended(&destination).exists());
+        std::fs::remove_dir_all(longpath::extended(&dir)).unwrap();
+    }
+
+    #[cfg(windows)]
+    #[test]
+    fn convert_beyond_max_path() {
+        let dir = std::env::temp_dir().join(format!("ford-sync-convert-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let source = dir.join("source.wav");
+        let generated = Command::new("ffmpeg")
+            .args(["-f", "lavfi", "-i", "anullsrc", "-t", "1", "-y"])
+            .arg(&source)
+            .stderr(Stdio::null())
+            .status();
+        if !generated.is_ok_and(|status| status.success()) {
+            // FFmpeg is not available.
+            return;
+        }
+        let destination = long_output_path(&dir, "converted.mp3");
+        let options = ExecuteOptions {
+            output_dir: dir.clone(),
+            ..Default::default()
+        };
+        assert!(matches!(
+            convert(
+                &source,
+                None,
+                &destination,
+                None,
+                None,
+                &TagOverrides::default(),
+                Encoding::Encode(None),
+                &options,
+                &Retrier::new(0, &Stall::default())
+            ),
+            ConvertOutcome::Succeeded
+        ));
+        assert!(longpath::extended(&destination).exists());
+        std::fs::remove_dir_all(longpath::extended(&dir)).unwrap();
+    }
+}
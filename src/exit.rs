@@ -0,0 +1,20 @@
+// Copyright (c) 2024 Jan Holthuis <jan.holthuis@rub.de>
+//
+// This Source Code Form is subject to the terms of the Mozilla Public License, v. 2.0. If a copy
+// of the MPL was not distributed with this file, You can obtain one at
+// http://mozilla.org/MPL/2.0/.
+//
+// SPDX-License-Identifier: MPL-2.0
+//! Exit statuses.
+
+/// Everything was synced.
+pub const SUCCESS: i32 = 0;
+/// The run completed, but some files failed.
+pub const FAILURES: i32 = 1;
+/// Invalid command line arguments or configuration.
+pub const USAGE: i32 = 2;
+/// A problem with the environment, e.g. FFmpeg is missing or the output directory is not
+/// writable.
+pub const ENVIRONMENT: i32 = 3;
+/// The run was interrupted, or aborted when asked for confirmation.
+pub const INTERRUPTED: i32 = 4;
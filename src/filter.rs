@@ -0,0 +1,179 @@
+// Copyright (c) 2024 Jan Holthuis <jan.holthuis@rub.de>
+//
+// This Source Code Form is subject to the terms of the Mozilla Public License, v. 2.0. If a copy
+// of the MPL was not distributed with this file, You can obtain one at
+// http://mozilla.org/MPL/2.0/.
+//
+// SPDX-License-Identifier: MPL-2.0
+//! Filters for leaving out playlist entries based on the tags from the probe pass.
+use crate::probe::ProbeInfo;
+use clap::ValueEnum;
+
+/// What to do with files that lack the tag a filter looks at.
+#[derive(ValueEnum, Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum MissingTag {
+    /// Keep the file.
+    #[default]
+    Keep,
+    /// Leave the file out.
+    Skip,
+}
+
+/// Tag-based filters from `--skip-genre`, `--skip-artist` and `--min-rating`.
+#[derive(Clone, Debug, Default)]
+pub struct TagFilter {
+    /// Lowercase genres to leave out.
+    pub skip_genres: Vec<String>,
+    /// Lowercase artists to leave out.
+    pub skip_artists: Vec<String>,
+    /// Minimum rating in stars (1 to 5).
+    pub min_rating: Option<u8>,
+    pub missing_tag: MissingTag,
+}
+
+impl TagFilter {
+    pub fn new(
+        skip_genres: &[String],
+        skip_artists: &[String],
+        min_rating: Option<u8>,
+        missing_tag: MissingTag,
+    ) -> Self {
+        let normalize = |values: &[String]| {
+            values
+                .iter()
+                .map(|value| value.trim().to_lowercase())
+                .filter(|value| !value.is_empty())
+                .collect()
+        };
+        Self {
+            skip_genres: normalize(skip_genres),
+            skip_artists: normalize(skip_artists),
+            min_rating,
+            missing_tag,
+        }
+    }
+
+    /// Returns `true` if any filter is set, i.e. the sources need to be probed.
+    pub fn is_active(&self) -> bool {
+        !self.skip_genres.is_empty() || !self.skip_artists.is_empty() || self.min_rating.is_some()
+    }
+
+    /// Returns why a file with the given probe information should be left out, if it should be.
+    ///
+    /// Files that could not be probed are treated as having no tags at all.
+    pub fn rejects(&self, info: Option<&ProbeInfo>) -> Option<String> {
+        let tag = |name: &str| info.and_then(|info| info.tag(name));
+        let skip_missing = self.missing_tag == MissingTag::Skip;
+
+        if !self.skip_genres.is_empty() {
+            match tag("genre") {
+                Some(genres) => {
+                    if let Some(genre) = split_values(genres)
+                        .find(|genre| self.skip_genres.contains(&genre.to_lowercase()))
+                    {
+                        return Some(format!("Genre `{}` is skipped", genre));
+                    }
+                }
+                None if skip_missing => return Some("No genre tag".to_string()),
+                None => (),
+            }
+        }
+
+        if !self.skip_artists.is_empty() {
+            let artists: Vec<_> = ["artist", "album_artist", "albumartist"]
+                .into_iter()
+                .filter_map(tag)
+                .collect();
+            if artists.is_empty() && skip_missing {
+                return Some("No artist tag".to_string());
+            }
+            if let Some(artist) = artists
+                .iter()
+                .flat_map(|artists| split_values(artists))
+                .find(|artist| self.skip_artists.contains(&artist.to_lowercase()))
+            {
+                return Some(format!("Artist `{}` is skipped", artist));
+            }
+        }
+
+        if let Some(min_rating) = self.min_rating {
+            match info.and_then(|info| info.rating) {
+                Some(rating) if rating < min_rating => {
+                    return Some(format!("Rating {} is below {}", rating, min_rating));
+                }
+                Some(_) => (),
+                None if skip_missing => return Some("No rating".to_string()),
+                None => (),
+            }
+        }
+
+        None
+    }
+}
+
+/// Split a tag that may hold several values, e.g. `Rock; Pop`.
+fn split_values(value: &str) -> impl Iterator<Item = &str> {
+    value
+        .split([';', '\0'])
+        .map(str::trim)
+        .filter(|value| !value.is_empty())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::HashMap;
+
+    fn info(tags: &[(&str, &str)], rating: Option<u8>) -> ProbeInfo {
+        ProbeInfo {
+            tags: tags
+                .iter()
+                .map(|(name, value)| (name.to_string(), value.to_string()))
+                .collect::<HashMap<_, _>>(),
+            rating,
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn skips_genres_case_insensitively() {
+        let filter = TagFilter::new(&["podcast".to_string()], &[], None, MissingTag::Keep);
+        assert_eq!(
+            filter.rejects(Some(&info(&[("genre", "News; Podcast")], None))),
+            Some("Genre `Podcast` is skipped".to_string())
+        );
+        assert_eq!(
+            filter.rejects(Some(&info(&[("genre", "Rock")], None))),
+            None
+        );
+    }
+
+    #[test]
+    fn skips_artists() {
+        let filter = TagFilter::new(&[], &["Voice Memos".to_string()], None, MissingTag::Keep);
+        assert!(filter
+            .rejects(Some(&info(&[("album_artist", "voice memos")], None)))
+            .is_some());
+        assert!(filter
+            .rejects(Some(&info(&[("artist", "Someone")], None)))
+            .is_none());
+    }
+
+    #[test]
+    fn rating_below_minimum_is_skipped() {
+        let filter = TagFilter::new(&[], &[], Some(3), MissingTag::Keep);
+        assert!(filter.rejects(Some(&info(&[], Some(2)))).is_some());
+        assert!(filter.rejects(Some(&info(&[], Some(3)))).is_none());
+    }
+
+    #[test]
+    fn missing_tags_pass_unless_configured() {
+        let genres = ["Podcast".to_string()];
+        let keep = TagFilter::new(&genres, &[], Some(3), MissingTag::Keep);
+        assert!(keep.rejects(Some(&info(&[], None))).is_none());
+        assert!(keep.rejects(None).is_none());
+        let skip = TagFilter::new(&genres, &[], Some(3), MissingTag::Skip);
+        assert!(skip.rejects(Some(&info(&[], None))).is_some());
+        assert!(skip.rejects(None).is_some());
+    }
+}
@@ -0,0 +1,180 @@
+// Copyright (c) 2024 Jan Holthuis <jan.holthuis@rub.de>
+//
+// This Source Code Form is subject to the terms of the Mozilla Public License, v. 2.0. If a copy
+// of the MPL was not distributed with this file, You can obtain one at
+// http://mozilla.org/MPL/2.0/.
+//
+// SPDX-License-Identifier: MPL-2.0
+//! Acoustic fingerprints computed with `fpcalc` from Chromaprint.
+use crate::probe::ProbeInfo;
+use crate::warnings::{report_warning, WarningCategory};
+use log::{debug, info};
+use serde::Deserialize;
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::process::{Command, Stdio};
+use std::sync::mpsc::channel;
+use threadpool::ThreadPool;
+
+/// Minimum similarity of fingerprints of the same track unless configured otherwise.
+pub const DEFAULT_THRESHOLD: f64 = 0.9;
+
+/// Number of fingerprint items that one fingerprint may be shifted against another, to make up
+/// for different amounts of silence at the start. One item covers about 0.12 seconds.
+const MAX_OFFSET: usize = 16;
+
+/// Parse a similarity threshold from 0 to 1, e.g. for `--fingerprint-threshold`.
+pub fn parse_threshold(value: &str) -> Result<f64, String> {
+    value
+        .trim()
+        .parse()
+        .ok()
+        .filter(|threshold| (0.0..=1.0).contains(threshold))
+        .ok_or_else(|| {
+            format!(
+                "invalid threshold `{}`, expected a number from 0 to 1",
+                value
+            )
+        })
+}
+
+#[derive(Deserialize)]
+struct FpcalcOutput {
+    fingerprint: Vec<i64>,
+}
+
+/// Run `fpcalc` on a single file and return its raw fingerprint.
+pub fn fingerprint(path: &Path) -> std::io::Result<Vec<u32>> {
+    let output = Command::new("fpcalc")
+        .args(["-raw", "-json"])
+        .arg(path)
+        .stdin(Stdio::null())
+        .output()?;
+    if !output.status.success() {
+        return Err(std::io::Error::other(format!(
+            "fpcalc exited with non-zero status {}",
+            output.status
+        )));
+    }
+    let parsed: FpcalcOutput = serde_json::from_slice(&output.stdout)?;
+    // Depending on the version, fpcalc prints the items as signed or unsigned integers.
+    Ok(parsed
+        .fingerprint
+        .into_iter()
+        .map(|item| item as u32)
+        .collect())
+}
+
+/// Compute the fingerprints of the given files in parallel and store them in their metadata.
+///
+/// Files that already have a fingerprint (e.g. from a plan file) or no metadata are skipped.
+/// Files that cannot be fingerprinted are logged and left without one.
+pub fn fingerprint_all(
+    metadata: &mut HashMap<PathBuf, ProbeInfo>,
+    paths: Vec<PathBuf>,
+    n_workers: usize,
+) {
+    let paths: Vec<_> = paths
+        .into_iter()
+        .filter(|path| {
+            metadata
+                .get(path)
+                .is_some_and(|info| info.fingerprint.is_none())
+        })
+        .collect();
+    if paths.is_empty() {
+        return;
+    }
+    info!("Fingerprinting {} files...", paths.len());
+    let num_paths = paths.len();
+    let pool = ThreadPool::new(n_workers);
+    let (tx, rx) = channel();
+    for path in paths {
+        let tx = tx.clone();
+        pool.execute(move || {
+            let result = fingerprint(&path);
+            tx.send((path, result))
+                .expect("channel will be there waiting for the pool");
+        });
+    }
+
+    for (path, result) in rx.iter().take(num_paths) {
+        match result {
+            Ok(fingerprint) => {
+                debug!(
+                    "{}: Computed fingerprint ({} items)",
+                    path.display(),
+                    fingerprint.len()
+                );
+                if let Some(info) = metadata.get_mut(&path) {
+                    info.fingerprint = Some(fingerprint);
+                }
+            }
+            Err(e) => {
+                report_warning!(
+                    WarningCategory::Probe,
+                    "{}: Failed to compute fingerprint ({})",
+                    path.display(),
+                    e
+                );
+            }
+        }
+    }
+}
+
+/// Returns the similarity of two fingerprints from 0 to 1, i.e. the share of equal bits at the
+/// best offset of one against the other.
+pub fn similarity(a: &[u32], b: &[u32]) -> f64 {
+    let min_overlap = a.len().min(b.len()).div_ceil(2).max(1);
+    let compare = |a: &[u32], b: &[u32]| {
+        let overlap = a.len().min(b.len());
+        if overlap < min_overlap {
+            return 0.0;
+        }
+        let errors: u32 = a.iter().zip(b).map(|(a, b)| (a ^ b).count_ones()).sum();
+        1.0 - f64::from(errors) / (overlap as f64 * 32.0)
+    };
+    (0..=MAX_OFFSET)
+        .flat_map(|offset| {
+            [
+                compare(a.get(offset..).unwrap_or_default(), b),
+                compare(a, b.get(offset..).unwrap_or_default()),
+            ]
+        })
+        .fold(0.0, f64::max)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Pseudo-random fingerprint items from a xorshift generator.
+    fn noise(mut state: u32, len: usize) -> Vec<u32> {
+        (0..len)
+            .map(|_| {
+                state ^= state << 13;
+                state ^= state >> 17;
+                state ^= state << 5;
+                state
+            })
+            .collect()
+    }
+
+    #[test]
+    fn compares_fingerprints_at_the_best_offset() {
+        let a = noise(1, 64);
+        assert_eq!(similarity(&a, &a), 1.0);
+
+        let mut shifted = vec![0xffff_ffff; 3];
+        shifted.extend_from_slice(&a);
+        assert_eq!(similarity(&a, &shifted), 1.0);
+        assert_eq!(similarity(&shifted, &a), 1.0);
+
+        let mut noisy = a.clone();
+        noisy[0] ^= 0xffff;
+        assert!((similarity(&a, &noisy) - (1.0 - 16.0 / (64.0 * 32.0))).abs() < 1e-9);
+
+        assert!(similarity(&a, &noise(2, 64)) < 0.6);
+        assert_eq!(similarity(&a, &[]), 0.0);
+    }
+}
@@ -0,0 +1,176 @@
+// Copyright (c) 2024 Jan Holthuis <jan.holthuis@rub.de>
+//
+// This Source Code Form is subject to the terms of the Mozilla Public License, v. 2.0. If a copy
+// of the MPL was not distributed with this file, You can obtain one at
+// http://mozilla.org/MPL/2.0/.
+//
+// SPDX-License-Identifier: MPL-2.0
+//! Normalization of genres through a mapping file given with `--genre-map`.
+//!
+//! Every line of the file maps a genre to another one, e.g. `Alt Rock=Alternative Rock`. Genres
+//! are matched case-insensitively, and `*=Other` maps all genres that are not listed otherwise.
+//! Empty lines and lines starting with `#` are ignored.
+use crate::plan::Plan;
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::fmt;
+use std::path::{Path, PathBuf};
+
+/// Error reading a genre map.
+#[derive(Debug)]
+pub enum GenreMapError {
+    Io(std::io::Error),
+    /// A line is not of the form `from=to`.
+    InvalidLine {
+        line: usize,
+        text: String,
+    },
+}
+
+impl fmt::Display for GenreMapError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            GenreMapError::Io(e) => e.fmt(f),
+            GenreMapError::InvalidLine { line, text } => {
+                write!(f, "line {} is not of the form `from=to` ({})", line, text)
+            }
+        }
+    }
+}
+
+impl std::error::Error for GenreMapError {}
+
+/// Maps genres to their normalized names.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct GenreMap {
+    /// Target genres, keyed by the lowercase source genre.
+    genres: HashMap<String, String>,
+    /// Target of all genres that are not listed.
+    catch_all: Option<String>,
+}
+
+impl GenreMap {
+    pub fn read_file(path: &Path) -> Result<Self, GenreMapError> {
+        std::fs::read_to_string(path)
+            .map_err(GenreMapError::Io)?
+            .parse()
+    }
+
+    /// Returns the genre that `genre` is mapped to, or `None` if it is left unchanged.
+    ///
+    /// The catch-all does not apply to genres that other genres are mapped to.
+    pub fn map(&self, genre: &str) -> Option<&str> {
+        let key = genre.trim().to_lowercase();
+        self.genres
+            .get(&key)
+            .or_else(|| {
+                self.catch_all.as_ref().filter(|_| {
+                    !self
+                        .genres
+                        .values()
+                        .any(|target| target.to_lowercase() == key)
+                })
+            })
+            .map(String::as_str)
+            .filter(|mapped| *mapped != genre)
+    }
+}
+
+impl std::str::FromStr for GenreMap {
+    type Err = GenreMapError;
+
+    fn from_str(contents: &str) -> Result<Self, Self::Err> {
+        let mut map = GenreMap::default();
+        for (index, line) in contents.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let Some((from, to)) = line
+                .split_once('=')
+                .map(|(from, to)| (from.trim(), to.trim()))
+                .filter(|(from, to)| !from.is_empty() && !to.is_empty())
+            else {
+                return Err(GenreMapError::InvalidLine {
+                    line: index + 1,
+                    text: line.to_string(),
+                });
+            };
+            if from == "*" {
+                map.catch_all = Some(to.to_string());
+            } else {
+                map.genres.insert(from.to_lowercase(), to.to_string());
+            }
+        }
+        Ok(map)
+    }
+}
+
+/// How often a genre was seen in the sources of a plan and what it was mapped to.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct GenreUsage {
+    pub genre: String,
+    pub files: usize,
+    /// The genre written to the output, or `None` if it was left unchanged.
+    pub mapped: Option<String>,
+}
+
+/// Returns the genres of the sources of the plan and how they were mapped, by genre.
+pub fn usage(plan: &Plan) -> Vec<GenreUsage> {
+    let mut genres: BTreeMap<&str, (HashSet<&PathBuf>, Option<&str>)> = BTreeMap::new();
+    for task in plan.tasks.iter() {
+        let Some(genre) = plan
+            .metadata
+            .get(&task.source)
+            .and_then(|info| info.tag("genre"))
+        else {
+            continue;
+        };
+        let (sources, mapped) = genres.entry(genre).or_default();
+        sources.insert(&task.source);
+        if let Some(target) = task.tags.get("TCON") {
+            *mapped = Some(target);
+        }
+    }
+    genres
+        .into_iter()
+        .map(|(genre, (sources, mapped))| GenreUsage {
+            genre: genre.to_string(),
+            files: sources.len(),
+            mapped: mapped.map(str::to_string),
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn maps_genres_case_insensitively() {
+        let map: GenreMap = "# Rock\nAlt Rock = Alternative Rock\nalt-rock=Alternative Rock\n\n"
+            .parse()
+            .unwrap();
+        assert_eq!(map.map("ALT ROCK"), Some("Alternative Rock"));
+        assert_eq!(map.map("Alt-Rock"), Some("Alternative Rock"));
+        assert_eq!(map.map("Alternative Rock"), None);
+        assert_eq!(map.map("Jazz"), None);
+    }
+
+    #[test]
+    fn maps_unlisted_genres_to_the_catch_all() {
+        let map: GenreMap = "rock=Rock\nAlt Rock=Alternative\n*=Other".parse().unwrap();
+        assert_eq!(map.map("ROCK"), Some("Rock"));
+        assert_eq!(map.map("Rock"), None);
+        assert_eq!(map.map("alternative"), None);
+        assert_eq!(map.map("Polka"), Some("Other"));
+    }
+
+    #[test]
+    fn rejects_invalid_lines() {
+        let error = "Rock=Rock\nPop".parse::<GenreMap>().unwrap_err();
+        assert_eq!(
+            error.to_string(),
+            "line 2 is not of the form `from=to` (Pop)"
+        );
+    }
+}
@@ -0,0 +1,183 @@
+// Copyright (c) 2024 Jan Holthuis <jan.holthuis@rub.de>
+//
+// This Source Code Form is subject to the terms of the Mozilla Public License, v. 2.0. If a copy
+// of the MPL was not distributed with this file, You can obtain one at
+// http://mozilla.org/MPL/2.0/.
+//
+// SPDX-License-Identifier: MPL-2.0
+//! User-provided hook commands, run through the shell.
+use log::debug;
+use std::ffi::OsStr;
+use std::fmt;
+use std::io::Read;
+use std::process::{Child, Command, ExitStatus, Stdio};
+use std::time::{Duration, Instant};
+
+/// How often a running hook is checked for completion.
+const POLL_INTERVAL: Duration = Duration::from_millis(50);
+
+/// Error of a failed hook.
+#[derive(Debug)]
+pub enum HookError {
+    /// The shell could not be started.
+    Spawn(std::io::Error),
+    /// The hook exited with a non-zero status.
+    Status(ExitStatus),
+    /// The hook did not finish in time and was killed.
+    Timeout(Duration),
+}
+
+impl fmt::Display for HookError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            HookError::Spawn(e) => write!(f, "Failed to execute command ({})", e),
+            HookError::Status(status) => write!(f, "Command exited with {}", status),
+            HookError::Timeout(timeout) => write!(
+                f,
+                "Command did not finish within {}",
+                humantime::format_duration(*timeout)
+            ),
+        }
+    }
+}
+
+impl std::error::Error for HookError {}
+
+/// A shell command with a timeout.
+#[derive(Clone, Debug)]
+pub struct Hook {
+    pub command: String,
+    pub timeout: Duration,
+}
+
+impl Hook {
+    /// Run the hook with the given environment variables and positional arguments (`$1`, `$2`,
+    /// ... in the shell; only the environment variables are available on Windows).
+    ///
+    /// The hook's output is written to the debug log, prefixed with `label`.
+    pub fn run<K, V>(
+        &self,
+        label: &str,
+        env: impl IntoIterator<Item = (K, V)>,
+        args: &[&OsStr],
+    ) -> Result<(), HookError>
+    where
+        K: AsRef<OsStr>,
+        V: AsRef<OsStr>,
+    {
+        let mut command = shell(&self.command, args);
+        command
+            .envs(env)
+            .stdin(Stdio::null())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped());
+        debug!("{}: Running hook {:?}", label, self.command);
+        let mut child = command.spawn().map_err(HookError::Spawn)?;
+
+        // Read the output on separate threads so that the hook never blocks on a full pipe.
+        let mut stdout = child.stdout.take().expect("stdout is piped");
+        let mut stderr = child.stderr.take().expect("stderr is piped");
+        let stdout_reader = std::thread::spawn(move || {
+            let mut output = Vec::new();
+            let _ = stdout.read_to_end(&mut output);
+            output
+        });
+        let stderr_reader = std::thread::spawn(move || {
+            let mut output = Vec::new();
+            let _ = stderr.read_to_end(&mut output);
+            output
+        });
+
+        let started = Instant::now();
+        let result = loop {
+            match child.try_wait() {
+                Ok(Some(status)) if status.success() => break Ok(()),
+                Ok(Some(status)) => break Err(HookError::Status(status)),
+                Ok(None) => (),
+                Err(e) => break Err(HookError::Spawn(e)),
+            }
+            if started.elapsed() >= self.timeout {
+                kill(&mut child);
+                let _ = child.wait();
+                break Err(HookError::Timeout(self.timeout));
+            }
+            std::thread::sleep(POLL_INTERVAL);
+        };
+
+        for output in [stdout_reader.join(), stderr_reader.join()] {
+            for line in String::from_utf8_lossy(&output.unwrap_or_default()).lines() {
+                debug!("{}: [hook] {}", label, line);
+            }
+        }
+        result
+    }
+}
+
+#[cfg(unix)]
+fn shell(command: &str, args: &[&OsStr]) -> Command {
+    use std::os::unix::process::CommandExt;
+    let mut shell = Command::new("sh");
+    shell.arg("-c").arg(command).arg("sh").args(args);
+    // Run in a new process group, so that commands started by the hook can be killed too.
+    shell.process_group(0);
+    shell
+}
+
+#[cfg(unix)]
+fn kill(child: &mut Child) {
+    // SAFETY: Sending a signal has no memory safety implications.
+    unsafe {
+        libc::kill(-(child.id() as libc::pid_t), libc::SIGKILL);
+    }
+}
+
+#[cfg(windows)]
+fn shell(command: &str, _args: &[&OsStr]) -> Command {
+    let mut shell = Command::new("cmd");
+    shell.arg("/C").arg(command);
+    shell
+}
+
+#[cfg(windows)]
+fn kill(child: &mut Child) {
+    let _ = child.kill();
+}
+
+#[cfg(all(test, unix))]
+mod tests {
+    use super::*;
+
+    fn hook(command: &str) -> Hook {
+        Hook {
+            command: command.to_string(),
+            timeout: Duration::from_secs(5),
+        }
+    }
+
+    #[test]
+    fn passes_arguments_and_environment() {
+        let result = hook(r#"test "$1" = a.mp3 && test "$FORD_SYNC_TEST" = yes"#).run(
+            "test",
+            [("FORD_SYNC_TEST", "yes")],
+            &[OsStr::new("a.mp3")],
+        );
+        assert!(result.is_ok(), "{:?}", result);
+    }
+
+    #[test]
+    fn reports_failures_and_timeouts() {
+        let no_env: [(&str, &str); 0] = [];
+        assert!(matches!(
+            hook("exit 3").run("test", no_env, &[]),
+            Err(HookError::Status(_))
+        ));
+        let slow = Hook {
+            timeout: Duration::from_millis(100),
+            ..hook("sleep 5")
+        };
+        assert!(matches!(
+            slow.run("test", no_env, &[]),
+            Err(HookError::Timeout(_))
+        ));
+    }
+}
@@ -0,0 +1,686 @@
+// Copyright (c) 2024 Jan Holthuis <jan.holthuis@rub.de>
+//
+// This Source Code Form is subject to the terms of the Mozilla Public License, v. 2.0. If a copy
+// of the MPL was not distributed with this file, You can obtain one at
+// http://mozilla.org/MPL/2.0/.
+//
+// SPDX-License-Identifier: MPL-2.0
+//! FAT32 disk images of the output for `--output-image`.
+//!
+//! Like with `--output-zip`, the sync writes into a staging directory first. Its files are then
+//! copied into a freshly formatted FAT32 filesystem, with a long file name next to the generated
+//! 8.3 name of every entry that needs one. Files and directories are laid out in contiguous
+//! clusters in the order of their paths.
+use crate::atomic;
+use crate::estimate::ByteSize;
+use crate::mtime;
+use std::collections::HashSet;
+use std::fmt;
+use std::fs::File;
+use std::io::{self, BufWriter, Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+use std::time::SystemTime;
+
+const SECTOR_SIZE: u64 = 512;
+const RESERVED_SECTORS: u64 = 32;
+const NUM_FATS: u64 = 2;
+const ROOT_CLUSTER: u32 = 2;
+/// Minimum number of clusters, below which the filesystem would be FAT16.
+const MIN_CLUSTERS: u64 = 65_525;
+/// Maximum number of clusters that the 28 bits of a FAT32 entry can address.
+const MAX_CLUSTERS: u64 = 0x0FFF_FFF5 - 2;
+const END_OF_CHAIN: u32 = 0x0FFF_FFFF;
+const DIR_ENTRY_SIZE: u64 = 32;
+const ATTR_DIRECTORY: u8 = 0x10;
+const ATTR_ARCHIVE: u8 = 0x20;
+const ATTR_VOLUME_ID: u8 = 0x08;
+const ATTR_LONG_NAME: u8 = 0x0F;
+/// Number of UTF-16 code units in each long file name entry.
+const LFN_CHARS: usize = 13;
+/// Characters other than letters and digits that 8.3 names may contain.
+const SHORT_NAME_SPECIAL: &str = "!#$%&'()-@^_`{}~";
+
+/// Error writing a disk image.
+#[derive(Debug)]
+pub enum ImageError {
+    Io(io::Error),
+    /// FAT32 does not support filesystems of this size.
+    InvalidSize(u64),
+    /// A file name is longer than the 255 characters of a long file name.
+    NameTooLong(PathBuf),
+    /// The output is larger than the image. `missing` are the files that did not fit.
+    NoSpace {
+        needed: u64,
+        available: u64,
+        missing: Vec<PathBuf>,
+    },
+}
+
+impl fmt::Display for ImageError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ImageError::Io(e) => e.fmt(f),
+            ImageError::InvalidSize(size) => write!(
+                f,
+                "FAT32 does not support images of {} (at least about 33 MiB are needed)",
+                ByteSize(*size)
+            ),
+            ImageError::NameTooLong(path) => {
+                write!(f, "{}: File name is too long for FAT32", path.display())
+            }
+            ImageError::NoSpace {
+                needed,
+                available,
+                missing,
+            } => write!(
+                f,
+                "Output of {} does not fit into the {} of the image, {} files are left over",
+                ByteSize(*needed),
+                ByteSize(*available),
+                missing.len()
+            ),
+        }
+    }
+}
+
+impl std::error::Error for ImageError {}
+
+impl From<io::Error> for ImageError {
+    fn from(e: io::Error) -> Self {
+        ImageError::Io(e)
+    }
+}
+
+/// Layout of the filesystem.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+struct Geometry {
+    total_sectors: u64,
+    sectors_per_cluster: u64,
+    fat_sectors: u64,
+    clusters: u64,
+}
+
+impl Geometry {
+    fn new(size: u64) -> Result<Self, ImageError> {
+        let total_sectors = size / SECTOR_SIZE;
+        // The cluster sizes that Windows uses for FAT32.
+        let sectors_per_cluster = match size >> 20 {
+            0..=260 => 1,
+            261..=8192 => 8,
+            8193..=16384 => 16,
+            16385..=32768 => 32,
+            _ => 64,
+        };
+        // The size of the FATs as computed by Microsoft's reference implementation, which may
+        // leave a few entries unused.
+        let data_and_fats = total_sectors
+            .checked_sub(RESERVED_SECTORS)
+            .ok_or(ImageError::InvalidSize(size))?;
+        let fat_sectors = data_and_fats.div_ceil((256 * sectors_per_cluster + NUM_FATS) / 2);
+        let clusters = data_and_fats.saturating_sub(NUM_FATS * fat_sectors) / sectors_per_cluster;
+        if total_sectors > u64::from(u32::MAX) || !(MIN_CLUSTERS..=MAX_CLUSTERS).contains(&clusters)
+        {
+            return Err(ImageError::InvalidSize(size));
+        }
+        Ok(Self {
+            total_sectors,
+            sectors_per_cluster,
+            fat_sectors,
+            clusters,
+        })
+    }
+
+    fn cluster_size(&self) -> u64 {
+        self.sectors_per_cluster * SECTOR_SIZE
+    }
+
+    fn fat_offset(&self, fat: u64) -> u64 {
+        (RESERVED_SECTORS + fat * self.fat_sectors) * SECTOR_SIZE
+    }
+
+    fn cluster_offset(&self, cluster: u32) -> u64 {
+        self.fat_offset(NUM_FATS) + u64::from(cluster - ROOT_CLUSTER) * self.cluster_size()
+    }
+}
+
+/// A file or directory of the staging directory.
+struct Node {
+    /// Path relative to the staging directory.
+    path: PathBuf,
+    name: String,
+    short_name: [u8; 11],
+    /// Whether `name` must be stored as a long file name.
+    needs_long_name: bool,
+    modified: SystemTime,
+    /// Size of the file, or of the entries of the directory.
+    size: u64,
+    first_cluster: u32,
+    /// `None` for files.
+    children: Option<Vec<Node>>,
+}
+
+impl Node {
+    /// Number of directory entries of the node in its parent.
+    fn num_entries(&self) -> u64 {
+        let long_name = self.name.encode_utf16().count().div_ceil(LFN_CHARS);
+        1 + if self.needs_long_name { long_name } else { 0 } as u64
+    }
+}
+
+/// Returns whether `c` may appear in an 8.3 name.
+fn is_short_name_char(c: char) -> bool {
+    c.is_ascii_uppercase() || c.is_ascii_digit() || SHORT_NAME_SPECIAL.contains(c)
+}
+
+/// Returns the 8.3 name of `name` in directory entry format, with a numeric tail like `~1` if the
+/// name had to be shortened or changed or collides with one of `taken`.
+fn short_name(name: &str, taken: &HashSet<[u8; 11]>) -> [u8; 11] {
+    let (base, extension) = match name.rfind('.') {
+        Some(index) if index > 0 => (&name[..index], &name[index + 1..]),
+        _ => (name, ""),
+    };
+    let mut lossy = false;
+    let mut convert = |part: &str, max_len: usize| {
+        let mut converted: Vec<u8> = vec![];
+        for c in part.chars().map(|c| c.to_ascii_uppercase()) {
+            if c == ' ' || c == '.' {
+                lossy = true;
+            } else if is_short_name_char(c) {
+                converted.push(c as u8);
+            } else {
+                lossy = true;
+                converted.push(b'_');
+            }
+        }
+        if converted.len() > max_len {
+            lossy = true;
+            converted.truncate(max_len);
+        }
+        converted
+    };
+    let base = convert(base, 8);
+    let extension = convert(extension, 3);
+    let entry = |base: &[u8]| {
+        let mut entry = [b' '; 11];
+        entry[..base.len()].copy_from_slice(base);
+        entry[8..8 + extension.len()].copy_from_slice(&extension);
+        entry
+    };
+    let candidate = entry(&base);
+    if !lossy && !base.is_empty() && !taken.contains(&candidate) {
+        return candidate;
+    }
+    (1..)
+        .map(|n| {
+            let tail = format!("~{}", n);
+            let mut numbered = base[..base.len().min(8 - tail.len())].to_vec();
+            numbered.extend(tail.bytes());
+            entry(&numbered)
+        })
+        .find(|candidate| !taken.contains(candidate))
+        .expect("one of the numeric tails is free")
+}
+
+/// Returns the name that a short name entry is displayed as, e.g. `README.TXT`.
+fn display_short_name(entry: &[u8]) -> String {
+    let base = String::from_utf8_lossy(&entry[..8]).trim_end().to_string();
+    let extension = String::from_utf8_lossy(&entry[8..11])
+        .trim_end()
+        .to_string();
+    if extension.is_empty() {
+        base
+    } else {
+        format!("{}.{}", base, extension)
+    }
+}
+
+/// Read the files and directories below `dir`, sorted by name and without hidden files like the
+/// lock file.
+fn read_tree(root: &Path, relative: &Path) -> Result<Vec<Node>, ImageError> {
+    let mut entries: Vec<_> = std::fs::read_dir(root.join(relative))?.collect::<io::Result<_>>()?;
+    entries.sort_by_key(|entry| entry.file_name());
+    let mut nodes = vec![];
+    let mut taken = HashSet::new();
+    for entry in entries {
+        let name = entry.file_name().to_string_lossy().into_owned();
+        if name.starts_with('.') {
+            continue;
+        }
+        let path = relative.join(&name);
+        if name.encode_utf16().count() > 255 {
+            return Err(ImageError::NameTooLong(path));
+        }
+        let metadata = entry.metadata()?;
+        let short_name = short_name(&name, &taken);
+        taken.insert(short_name);
+        let children = metadata
+            .is_dir()
+            .then(|| read_tree(root, &path))
+            .transpose()?;
+        let size = match &children {
+            // Including the `.` and `..` entries.
+            Some(children) => {
+                (2 + children.iter().map(Node::num_entries).sum::<u64>()) * DIR_ENTRY_SIZE
+            }
+            None => metadata.len(),
+        };
+        nodes.push(Node {
+            needs_long_name: display_short_name(&short_name) != name,
+            path,
+            name,
+            short_name,
+            modified: metadata.modified()?,
+            size,
+            first_cluster: 0,
+            children,
+        });
+    }
+    Ok(nodes)
+}
+
+/// Assigns clusters to the children of a directory and then to those of its subdirectories.
+///
+/// Returns the contiguous runs of clusters as pairs of first cluster and length, and the files
+/// with the cluster after their last one.
+fn allocate(
+    nodes: &mut [Node],
+    next_cluster: &mut u64,
+    cluster_size: u64,
+    runs: &mut Vec<(u32, u32)>,
+    files: &mut Vec<(PathBuf, u64)>,
+) {
+    for node in nodes.iter_mut() {
+        let clusters = match node.children {
+            Some(_) => node.size.div_ceil(cluster_size).max(1),
+            None => node.size.div_ceil(cluster_size),
+        };
+        if clusters > 0 {
+            // Clusters beyond the image are only counted to report what does not fit.
+            node.first_cluster = u32::try_from(*next_cluster).unwrap_or(u32::MAX);
+            runs.push((node.first_cluster, clusters as u32));
+        }
+        *next_cluster += clusters;
+        if node.children.is_none() {
+            files.push((node.path.clone(), *next_cluster));
+        }
+    }
+    for node in nodes.iter_mut() {
+        if let Some(children) = node.children.as_mut() {
+            allocate(children, next_cluster, cluster_size, runs, files);
+        }
+    }
+}
+
+/// Returns the 32-byte directory entries for `nodes`, preceded by `.` and `..` unless the
+/// directory is the root.
+fn dir_entries(nodes: &[Node], dots: Option<(u32, u32)>) -> Vec<u8> {
+    let short_entry = |name: &[u8; 11], attributes: u8, cluster: u32, modified, size: u64| {
+        let (time, date) = mtime::dos_time(modified);
+        let mut entry = vec![0; DIR_ENTRY_SIZE as usize];
+        entry[..11].copy_from_slice(name);
+        entry[11] = attributes;
+        entry[14..16].copy_from_slice(&time.to_le_bytes());
+        entry[16..18].copy_from_slice(&date.to_le_bytes());
+        entry[18..20].copy_from_slice(&date.to_le_bytes());
+        entry[20..22].copy_from_slice(&((cluster >> 16) as u16).to_le_bytes());
+        entry[22..24].copy_from_slice(&time.to_le_bytes());
+        entry[24..26].copy_from_slice(&date.to_le_bytes());
+        entry[26..28].copy_from_slice(&(cluster as u16).to_le_bytes());
+        entry[28..32].copy_from_slice(&(size as u32).to_le_bytes());
+        entry
+    };
+    let mut entries = vec![];
+    if let Some((own, parent)) = dots {
+        let now = SystemTime::now();
+        entries.extend(short_entry(b".          ", ATTR_DIRECTORY, own, now, 0));
+        // `..` points at cluster 0 for the root directory.
+        let parent = if parent == ROOT_CLUSTER { 0 } else { parent };
+        entries.extend(short_entry(b"..         ", ATTR_DIRECTORY, parent, now, 0));
+    }
+    for node in nodes {
+        if node.needs_long_name {
+            let checksum = node.short_name.iter().fold(0u8, |sum, &byte| {
+                (sum >> 1).wrapping_add(sum << 7).wrapping_add(byte)
+            });
+            let mut units: Vec<u16> = node.name.encode_utf16().collect();
+            if !units.len().is_multiple_of(LFN_CHARS) {
+                units.push(0);
+            }
+            units.resize(units.len().div_ceil(LFN_CHARS) * LFN_CHARS, 0xFFFF);
+            let chunks: Vec<_> = units.chunks(LFN_CHARS).collect();
+            for (index, chunk) in chunks.iter().enumerate().rev() {
+                let mut entry = vec![0; DIR_ENTRY_SIZE as usize];
+                entry[0] = (index + 1) as u8 | if index + 1 == chunks.len() { 0x40 } else { 0 };
+                entry[11] = ATTR_LONG_NAME;
+                entry[13] = checksum;
+                let offsets = (1..11)
+                    .step_by(2)
+                    .chain((14..26).step_by(2))
+                    .chain([28, 30]);
+                for (offset, unit) in offsets.zip(chunk.iter()) {
+                    entry[offset..offset + 2].copy_from_slice(&unit.to_le_bytes());
+                }
+                entries.extend(entry);
+            }
+        }
+        let (attributes, size) = match node.children {
+            Some(_) => (ATTR_DIRECTORY, 0),
+            None => (ATTR_ARCHIVE, node.size),
+        };
+        entries.extend(short_entry(
+            &node.short_name,
+            attributes,
+            node.first_cluster,
+            node.modified,
+            size,
+        ));
+    }
+    entries
+}
+
+/// Writes the directories and files of the tree into the image.
+fn write_nodes(
+    image: &mut File,
+    geometry: &Geometry,
+    root: &Path,
+    nodes: &[Node],
+    cluster: u32,
+    dots: Option<(u32, u32)>,
+) -> io::Result<()> {
+    image.seek(SeekFrom::Start(geometry.cluster_offset(cluster)))?;
+    image.write_all(&dir_entries(nodes, dots))?;
+    for node in nodes {
+        match &node.children {
+            Some(children) => write_nodes(
+                image,
+                geometry,
+                root,
+                children,
+                node.first_cluster,
+                Some((node.first_cluster, cluster)),
+            )?,
+            None if node.size > 0 => {
+                image.seek(SeekFrom::Start(geometry.cluster_offset(node.first_cluster)))?;
+                let copied = io::copy(&mut File::open(root.join(&node.path))?, image)?;
+                if copied != node.size {
+                    return Err(io::Error::other(format!(
+                        "{}: File changed while writing the image",
+                        node.path.display()
+                    )));
+                }
+            }
+            None => (),
+        }
+    }
+    Ok(())
+}
+
+/// Returns the boot sector of the filesystem.
+fn boot_sector(geometry: &Geometry) -> Vec<u8> {
+    let mut sector = vec![0; SECTOR_SIZE as usize];
+    sector[..3].copy_from_slice(&[0xEB, 0x58, 0x90]);
+    sector[3..11].copy_from_slice(b"MSWIN4.1");
+    sector[11..13].copy_from_slice(&(SECTOR_SIZE as u16).to_le_bytes());
+    sector[13] = geometry.sectors_per_cluster as u8;
+    sector[14..16].copy_from_slice(&(RESERVED_SECTORS as u16).to_le_bytes());
+    sector[16] = NUM_FATS as u8;
+    // Media descriptor of fixed disks.
+    sector[21] = 0xF8;
+    sector[24..26].copy_from_slice(&63u16.to_le_bytes());
+    sector[26..28].copy_from_slice(&255u16.to_le_bytes());
+    sector[32..36].copy_from_slice(&(geometry.total_sectors as u32).to_le_bytes());
+    sector[36..40].copy_from_slice(&(geometry.fat_sectors as u32).to_le_bytes());
+    sector[44..48].copy_from_slice(&ROOT_CLUSTER.to_le_bytes());
+    // Sectors of the FSInfo structure and the backup of the boot sector.
+    sector[48..50].copy_from_slice(&1u16.to_le_bytes());
+    sector[50..52].copy_from_slice(&6u16.to_le_bytes());
+    sector[64] = 0x80;
+    sector[66] = 0x29;
+    let volume_id = SystemTime::now()
+        .duration_since(SystemTime::UNIX_EPOCH)
+        .map(|duration| duration.as_secs() as u32)
+        .unwrap_or(0);
+    sector[67..71].copy_from_slice(&volume_id.to_le_bytes());
+    sector[71..82].copy_from_slice(b"NO NAME    ");
+    sector[82..90].copy_from_slice(b"FAT32   ");
+    sector[510..].copy_from_slice(&[0x55, 0xAA]);
+    sector
+}
+
+/// Returns the FSInfo sector with the number of free clusters and the next free one.
+fn fs_info(free_clusters: u64, next_cluster: u64) -> Vec<u8> {
+    let mut sector = vec![0; SECTOR_SIZE as usize];
+    sector[..4].copy_from_slice(&0x4161_5252u32.to_le_bytes());
+    sector[484..488].copy_from_slice(&0x6141_7272u32.to_le_bytes());
+    sector[488..492].copy_from_slice(&(free_clusters as u32).to_le_bytes());
+    sector[492..496].copy_from_slice(&(next_cluster as u32).to_le_bytes());
+    sector[508..].copy_from_slice(&0xAA55_0000u32.to_le_bytes());
+    sector
+}
+
+/// Size and free space of a written image.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct ImageSummary {
+    pub size: u64,
+    pub free: u64,
+}
+
+/// Write all files below `dir` into a new FAT32 image of `size` bytes at `image`.
+///
+/// Nothing is written if the files do not fit. The image is written to a temporary file first
+/// and only replaces an existing one once it is complete.
+pub fn write_image(dir: &Path, image: &Path, size: u64) -> Result<ImageSummary, ImageError> {
+    let geometry = Geometry::new(size)?;
+    let cluster_size = geometry.cluster_size();
+    let mut nodes = read_tree(dir, Path::new(""))?;
+    let root_size = nodes.iter().map(Node::num_entries).sum::<u64>() * DIR_ENTRY_SIZE;
+    let root_clusters = root_size.div_ceil(cluster_size).max(1);
+    let mut runs = vec![(ROOT_CLUSTER, root_clusters as u32)];
+    let mut next_cluster = u64::from(ROOT_CLUSTER) + root_clusters;
+    let mut files = vec![];
+    allocate(
+        &mut nodes,
+        &mut next_cluster,
+        cluster_size,
+        &mut runs,
+        &mut files,
+    );
+    let end_cluster = u64::from(ROOT_CLUSTER) + geometry.clusters;
+    if next_cluster > end_cluster {
+        return Err(ImageError::NoSpace {
+            needed: (next_cluster - u64::from(ROOT_CLUSTER)) * cluster_size,
+            available: geometry.clusters * cluster_size,
+            missing: files
+                .into_iter()
+                .filter(|(_, end)| *end > end_cluster)
+                .map(|(path, _)| path)
+                .collect(),
+        });
+    }
+
+    let temp_path = atomic::temp_path(image);
+    let free_clusters = end_cluster - next_cluster;
+    let result = (|| -> io::Result<()> {
+        let mut file = File::create(&temp_path)?;
+        file.set_len(geometry.total_sectors * SECTOR_SIZE)?;
+        let boot_sector = boot_sector(&geometry);
+        let fs_info = fs_info(free_clusters, next_cluster);
+        for offset in [0, 6] {
+            file.seek(SeekFrom::Start(offset * SECTOR_SIZE))?;
+            file.write_all(&boot_sector)?;
+            file.write_all(&fs_info)?;
+        }
+        for fat in 0..NUM_FATS {
+            file.seek(SeekFrom::Start(geometry.fat_offset(fat)))?;
+            let mut writer = BufWriter::new(&mut file);
+            writer.write_all(&0x0FFF_FFF8u32.to_le_bytes())?;
+            writer.write_all(&END_OF_CHAIN.to_le_bytes())?;
+            for &(first, len) in runs.iter() {
+                for cluster in first..first + len {
+                    let next = if cluster + 1 == first + len {
+                        END_OF_CHAIN
+                    } else {
+                        cluster + 1
+                    };
+                    writer.write_all(&next.to_le_bytes())?;
+                }
+            }
+            writer.flush()?;
+        }
+        write_nodes(&mut file, &geometry, dir, &nodes, ROOT_CLUSTER, None)?;
+        file.sync_all()
+    })()
+    .and_then(|()| atomic::commit(&temp_path, image, false));
+    if let Err(e) = result {
+        atomic::discard(&temp_path);
+        return Err(e.into());
+    }
+    Ok(ImageSummary {
+        size: geometry.total_sectors * SECTOR_SIZE,
+        free: free_clusters * cluster_size,
+    })
+}
+
+/// Read the entries of the root directory of the FAT32 image at `image`, with a trailing `/` for
+/// directories.
+pub fn list_root(image: &Path) -> io::Result<Vec<String>> {
+    let mut file = File::open(image)?;
+    let mut sector = vec![0; SECTOR_SIZE as usize];
+    file.read_exact(&mut sector)?;
+    let u16_at =
+        |offset: usize| u64::from(u16::from_le_bytes([sector[offset], sector[offset + 1]]));
+    let u32_at =
+        |offset: usize| u32::from_le_bytes(sector[offset..offset + 4].try_into().expect("4 bytes"));
+    let bytes_per_sector = u16_at(11);
+    let cluster_size = u64::from(sector[13]) * bytes_per_sector;
+    let fat_offset = u16_at(14) * bytes_per_sector;
+    let data_offset = fat_offset + u64::from(sector[16]) * u64::from(u32_at(36)) * bytes_per_sector;
+    let mut cluster = u32_at(44);
+
+    let mut names = vec![];
+    let mut long_name: Vec<u16> = vec![];
+    let mut buffer = vec![0; cluster_size as usize];
+    let mut visited = HashSet::new();
+    while (ROOT_CLUSTER..0x0FFF_FFF8).contains(&cluster) && visited.insert(cluster) {
+        file.seek(SeekFrom::Start(
+            data_offset + u64::from(cluster - ROOT_CLUSTER) * cluster_size,
+        ))?;
+        file.read_exact(&mut buffer)?;
+        for entry in buffer.chunks(DIR_ENTRY_SIZE as usize) {
+            match (entry[0], entry[11]) {
+                (0x00, _) => return Ok(names),
+                (0xE5, _) => long_name.clear(),
+                (_, ATTR_LONG_NAME) => {
+                    let offsets = (1..11)
+                        .step_by(2)
+                        .chain((14..26).step_by(2))
+                        .chain([28, 30]);
+                    let units = offsets
+                        .map(|offset| u16::from_le_bytes([entry[offset], entry[offset + 1]]))
+                        .take_while(|unit| *unit != 0 && *unit != 0xFFFF);
+                    long_name.splice(0..0, units);
+                }
+                (_, attributes) if attributes & ATTR_VOLUME_ID != 0 => long_name.clear(),
+                (_, attributes) => {
+                    let mut name = if long_name.is_empty() {
+                        display_short_name(&entry[..11])
+                    } else {
+                        String::from_utf16_lossy(&long_name)
+                    };
+                    long_name.clear();
+                    if attributes & ATTR_DIRECTORY != 0 {
+                        name.push('/');
+                    }
+                    names.push(name);
+                }
+            }
+        }
+        file.seek(SeekFrom::Start(fat_offset + u64::from(cluster) * 4))?;
+        let mut next = [0; 4];
+        file.read_exact(&mut next)?;
+        cluster = u32::from_le_bytes(next) & 0x0FFF_FFFF;
+    }
+    Ok(names)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn computes_fat32_geometry() {
+        let geometry = Geometry::new(16 << 30).unwrap();
+        assert_eq!(geometry.sectors_per_cluster, 16);
+        assert_eq!(geometry.cluster_size(), 8192);
+        assert!(geometry.fat_sectors * SECTOR_SIZE / 4 >= geometry.clusters + 2);
+        assert!(matches!(
+            Geometry::new(16 << 20),
+            Err(ImageError::InvalidSize(_))
+        ));
+    }
+
+    #[test]
+    fn generates_unique_short_names() {
+        let mut taken = HashSet::new();
+        let mut add = |name: &str| {
+            let short_name = short_name(name, &taken);
+            taken.insert(short_name);
+            display_short_name(&short_name)
+        };
+        assert_eq!(add("README.TXT"), "README.TXT");
+        assert_eq!(add("readme.txt"), "README~1.TXT");
+        assert_eq!(add("01 Bohemian Rhapsody.mp3"), "01BOHE~1.MP3");
+        assert_eq!(add("01 Bohemian Rhapsody (Live).mp3"), "01BOHE~2.MP3");
+        assert_eq!(add("Motörhead.m3u"), "MOT_RH~1.M3U");
+        assert_eq!(add("archive.tar.gz"), "ARCHIV~1.GZ");
+    }
+
+    #[test]
+    fn writes_files_with_long_names() {
+        let dir = std::env::temp_dir().join(format!("image-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        let staging = dir.join("staging");
+        let album = staging.join("Queen - A Night at the Opera");
+        std::fs::create_dir_all(&album).unwrap();
+        std::fs::write(album.join("11 Bohemian Rhapsody.mp3"), b"hello").unwrap();
+        std::fs::write(staging.join("Road Trip.m3u"), b"entries").unwrap();
+        std::fs::write(staging.join("EMPTY.TXT"), b"").unwrap();
+        std::fs::write(staging.join(".ford-sync-convert.lock"), b"").unwrap();
+        let image = dir.join("stick.img");
+
+        let summary = write_image(&staging, &image, 64 << 20).unwrap();
+        assert_eq!(summary.size, std::fs::metadata(&image).unwrap().len());
+        // The root and album directories and the two non-empty files take a cluster each.
+        assert_eq!(
+            summary.free,
+            (Geometry::new(64 << 20).unwrap().clusters - 4) * 512
+        );
+        assert_eq!(
+            list_root(&image).unwrap(),
+            [
+                "EMPTY.TXT",
+                "Queen - A Night at the Opera/",
+                "Road Trip.m3u"
+            ]
+        );
+    }
+
+    #[test]
+    fn reports_files_that_do_not_fit() {
+        let dir = std::env::temp_dir().join(format!("image-full-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        std::fs::write(dir.join("a.mp3"), vec![0; 20 << 20]).unwrap();
+        std::fs::write(dir.join("b.mp3"), vec![0; 20 << 20]).unwrap();
+        std::fs::write(dir.join("c.mp3"), vec![0; 1024]).unwrap();
+        let image = dir.join("stick.img");
+
+        match write_image(&dir, &image, 40 << 20) {
+            Err(ImageError::NoSpace { missing, .. }) => {
+                assert_eq!(missing, [PathBuf::from("b.mp3"), PathBuf::from("c.mp3")])
+            }
+            result => panic!("unexpected result {:?}", result),
+        }
+        assert!(!image.exists());
+    }
+}
@@ -0,0 +1,90 @@
+// Copyright (c) 2024 Jan Holthuis <jan.holthuis@rub.de>
+//
+// This Source Code Form is subject to the terms of the Mozilla Public License, v. 2.0. If a copy
+// of the MPL was not distributed with this file, You can obtain one at
+// http://mozilla.org/MPL/2.0/.
+//
+// SPDX-License-Identifier: MPL-2.0
+//! Ctrl-C handling, and pausing or skipping work while a run is in progress.
+//!
+//! The first interrupt only sets a flag that is polled by the executor, so that running tasks can
+//! be terminated and cleaned up. A second interrupt exits immediately.
+use crate::exit;
+use crate::lock;
+use crate::longpath;
+use log::warn;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Mutex;
+use std::time::Duration;
+
+static INTERRUPTED: AtomicBool = AtomicBool::new(false);
+static USER_INTERRUPT: AtomicBool = AtomicBool::new(false);
+static PAUSED: AtomicBool = AtomicBool::new(false);
+/// Outputs whose conversion should be given up, as [`longpath::extended`] paths.
+static SKIPPED: Mutex<Vec<PathBuf>> = Mutex::new(Vec::new());
+
+/// Install the Ctrl-C handler.
+pub fn install() {
+    ctrlc::set_handler(request).expect("failed to install Ctrl-C handler");
+}
+
+/// Interrupt the run as if Ctrl-C was pressed.
+pub fn request() {
+    if USER_INTERRUPT.swap(true, Ordering::SeqCst) {
+        lock::force_release();
+        std::process::exit(exit::INTERRUPTED);
+    }
+    INTERRUPTED.store(true, Ordering::SeqCst);
+    warn!("Interrupted, cleaning up... (press Ctrl-C again to exit immediately)");
+}
+
+/// Stop the run the same way as on Ctrl-C, e.g. because of a fatal condition.
+pub fn stop() {
+    INTERRUPTED.store(true, Ordering::SeqCst);
+}
+
+/// Returns `true` if the run should stop.
+pub fn is_interrupted() -> bool {
+    INTERRUPTED.load(Ordering::SeqCst)
+}
+
+/// Returns `true` if the user pressed Ctrl-C.
+pub fn is_user_interrupt() -> bool {
+    USER_INTERRUPT.load(Ordering::SeqCst)
+}
+
+/// Hold back tasks that were not started yet, or let them start again. Running tasks are not
+/// affected.
+pub fn set_paused(paused: bool) {
+    PAUSED.store(paused, Ordering::SeqCst);
+}
+
+/// Returns `true` if new tasks are held back.
+pub fn is_paused() -> bool {
+    PAUSED.load(Ordering::SeqCst)
+}
+
+/// Wait until new tasks may start or the run is interrupted.
+pub fn wait_while_paused() {
+    while is_paused() && !is_interrupted() {
+        std::thread::sleep(Duration::from_millis(100));
+    }
+}
+
+/// Give up the running conversion to `output_path`. Other tasks are not affected.
+pub fn skip(output_path: &Path) {
+    SKIPPED
+        .lock()
+        .unwrap_or_else(|e| e.into_inner())
+        .push(longpath::extended(output_path));
+}
+
+/// Returns `true` (once) if the conversion to `output_path` should be given up.
+pub fn take_skip(output_path: &Path) -> bool {
+    let output_path = longpath::extended(output_path);
+    let mut skipped = SKIPPED.lock().unwrap_or_else(|e| e.into_inner());
+    let len = skipped.len();
+    skipped.retain(|path| *path != output_path);
+    skipped.len() != len
+}
@@ -0,0 +1,211 @@
+// Copyright (c) 2024 Jan Holthuis <jan.holthuis@rub.de>
+//
+// This Source Code Form is subject to the terms of the Mozilla Public License, v. 2.0. If a copy
+// of the MPL was not distributed with this file, You can obtain one at
+// http://mozilla.org/MPL/2.0/.
+//
+// SPDX-License-Identifier: MPL-2.0
+//! Layout of the output directory.
+use crate::probe::ProbeInfo;
+use clap::ValueEnum;
+use std::path::{Path, PathBuf};
+
+/// How output files are arranged in the output directory.
+#[derive(ValueEnum, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Structure {
+    /// Mirror the directory structure of the sources.
+    Mirror,
+    /// Arrange files as `Music/<Album Artist>/<Album>/<Track> <Title>.mp3` based on their tags.
+    ArtistAlbum,
+}
+
+/// Name of the directory that holds all files with the `artist-album` structure or the `sync`
+/// layout.
+const MUSIC_DIR_NAME: &str = "Music";
+
+/// Where audio files and playlists are placed in the output directory.
+#[derive(ValueEnum, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Layout {
+    /// Place audio files and playlists directly in the output directory.
+    Flat,
+    /// Place all audio files under `Music/` and playlists at the top level.
+    Sync,
+}
+
+/// Where tracks in several playlists are placed with `--group-by-playlist`.
+#[derive(ValueEnum, Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum SharedTracks {
+    /// Write a copy into the folder of every playlist.
+    #[default]
+    Duplicate,
+    /// Write the track once into the folder of its first playlist and point the entries of the
+    /// other playlists at it.
+    Single,
+}
+
+impl Layout {
+    /// Returns the directory (relative to the output directory) that holds all audio files.
+    pub fn audio_root(self) -> &'static Path {
+        match self {
+            Layout::Flat => Path::new(""),
+            Layout::Sync => Path::new(MUSIC_DIR_NAME),
+        }
+    }
+}
+
+/// Returns the album artist of a file, falling back to its artist.
+pub fn album_artist(info: &ProbeInfo) -> Option<&str> {
+    info.tag("album_artist")
+        .or_else(|| info.tag("albumartist"))
+        .or_else(|| info.tag("artist"))
+}
+
+/// Make a tag value usable as a single path component.
+pub fn component(value: &str) -> String {
+    value.replace(['/', '\\'], "_")
+}
+
+/// Returns the `artist-album` output path for the file at the relative path `path`.
+///
+/// Missing tags fall back to the corresponding components of `path`. If `multi_disc` is set, the
+/// track number is prefixed with the disc number.
+pub fn artist_album(path: &Path, info: Option<&ProbeInfo>, multi_disc: bool) -> PathBuf {
+    let tag = |name| info.and_then(|info| info.tag(name));
+    let mut dirs = path.parent().into_iter().flat_map(Path::iter).rev();
+    let fallback_album = dirs.next().map(|dir| dir.to_string_lossy());
+    let fallback_artist = dirs.next().map(|dir| dir.to_string_lossy());
+
+    let artist = info
+        .and_then(album_artist)
+        .map(component)
+        .or(fallback_artist.map(|artist| artist.into_owned()))
+        .unwrap_or_else(|| "Unknown Artist".to_string());
+    let album = tag("album")
+        .map(component)
+        .or(fallback_album.map(|album| album.into_owned()))
+        .unwrap_or_else(|| "Unknown Album".to_string());
+    let title = tag("title").map(component).unwrap_or_else(|| {
+        path.file_stem()
+            .unwrap_or_default()
+            .to_string_lossy()
+            .into_owned()
+    });
+    let track = info.and_then(|info| info.number_tag("track"));
+    let disc = info.and_then(|info| info.number_tag("disc")).unwrap_or(1);
+    let mut file_name = match track {
+        Some(track) if multi_disc => format!("{}-{:02} {}", disc, track, title),
+        Some(track) => format!("{:02} {}", track, title),
+        None => title,
+    };
+    if let Some(extension) = path.extension() {
+        file_name.push('.');
+        file_name.push_str(&extension.to_string_lossy());
+    }
+    [MUSIC_DIR_NAME, &artist, &album, &file_name]
+        .iter()
+        .collect()
+}
+
+/// Separator between folded directory names.
+const FOLD_SEPARATOR: &str = " - ";
+
+/// Reduce the directory hierarchy of a relative output path to at most `depth` levels.
+///
+/// The lowest `depth - 1` directories are kept, all directories above them are folded into the
+/// name of the topmost retained directory (e.g. `Rock/Artist/Album/CD1/Track.mp3` becomes
+/// `Rock - Artist - Album/CD1/Track.mp3` for a depth of 2). With a depth of 0, all directories
+/// are dropped.
+pub fn flatten(path: &Path, depth: usize) -> PathBuf {
+    let components: Vec<_> = path.iter().collect();
+    let Some((file_name, dirs)) = components.split_last() else {
+        return path.to_path_buf();
+    };
+    if dirs.len() <= depth {
+        return path.to_path_buf();
+    }
+    let mut result = PathBuf::new();
+    if depth > 0 {
+        let (folded, kept) = dirs.split_at(dirs.len() - (depth - 1));
+        let folded: Vec<_> = folded
+            .iter()
+            .map(|component| component.to_string_lossy())
+            .collect();
+        result.push(folded.join(FOLD_SEPARATOR));
+        result.extend(kept);
+    }
+    result.push(file_name);
+    result
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn info(tags: &[(&str, &str)]) -> ProbeInfo {
+        ProbeInfo {
+            tags: tags
+                .iter()
+                .map(|(name, value)| (name.to_string(), value.to_string()))
+                .collect(),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn artist_album_uses_tags() {
+        let info = info(&[
+            ("album_artist", "AC/DC"),
+            ("album", "Back in Black"),
+            ("title", "Hells Bells"),
+            ("track", "1/10"),
+        ]);
+        assert_eq!(
+            artist_album(Path::new("x/y/z.mp3"), Some(&info), false),
+            PathBuf::from("Music/AC_DC/Back in Black/01 Hells Bells.mp3")
+        );
+    }
+
+    #[test]
+    fn artist_album_prefixes_disc_numbers() {
+        let info = info(&[("title", "Song"), ("track", "3"), ("disc", "2/2")]);
+        assert_eq!(
+            artist_album(Path::new("Artist/Album/z.mp3"), Some(&info), true),
+            PathBuf::from("Music/Artist/Album/2-03 Song.mp3")
+        );
+    }
+
+    #[test]
+    fn artist_album_falls_back_to_path() {
+        assert_eq!(
+            artist_album(Path::new("Artist/Album/Song.mp3"), None, false),
+            PathBuf::from("Music/Artist/Album/Song.mp3")
+        );
+    }
+
+    #[test]
+    fn folds_upper_directories() {
+        let path = Path::new("Rock/Artist/2001 - Album/CD1/01 Track.mp3");
+        assert_eq!(
+            flatten(path, 2),
+            PathBuf::from("Rock - Artist - 2001 - Album/CD1/01 Track.mp3")
+        );
+        assert_eq!(
+            flatten(path, 1),
+            PathBuf::from("Rock - Artist - 2001 - Album - CD1/01 Track.mp3")
+        );
+    }
+
+    #[test]
+    fn drops_all_directories_for_depth_zero() {
+        assert_eq!(
+            flatten(Path::new("Artist/Album/Track.mp3"), 0),
+            PathBuf::from("Track.mp3")
+        );
+    }
+
+    #[test]
+    fn shallow_paths_are_unchanged() {
+        let path = Path::new("Artist/Album/Track.mp3");
+        assert_eq!(flatten(path, 2), path);
+    }
+}
@@ -0,0 +1,91 @@
+// Copyright (c) 2024 Jan Holthuis <jan.holthuis@rub.de>
+//
+// This Source Code Form is subject to the terms of the Mozilla Public License, v. 2.0. If a copy
+// of the MPL was not distributed with this file, You can obtain one at
+// http://mozilla.org/MPL/2.0/.
+//
+// SPDX-License-Identifier: MPL-2.0
+//! Converting and copying playlists for the Ford Sync 2 head unit.
+//!
+//! The [`sync`] module ties the steps together: [`sync::plan`] resolves the playlists into a
+//! [`plan::Plan`], and a [`sync::Executor`] converts and copies the files and returns a
+//! [`report::Report`].
+//!
+//! ```no_run
+//! use ford_sync_convert::sync::{self, Executor, SyncOptions};
+//!
+//! let options = SyncOptions {
+//!     output_dir: "/media/usb".into(),
+//!     ..Default::default()
+//! };
+//! let plan = sync::plan(&options, &["playlists/road-trip.m3u".into()])?;
+//! let report = Executor::new(options).run(&plan)?;
+//! println!("{}", report.counts);
+//! # Ok::<(), ford_sync_convert::sync::SyncError>(())
+//! ```
+pub mod archive;
+pub mod art;
+pub mod atomic;
+pub mod budget;
+pub mod compilation;
+pub mod config;
+pub mod dedupe;
+pub mod diff;
+pub mod doctor;
+pub mod estimate;
+pub mod exclude;
+pub mod execute;
+pub mod exit;
+pub mod filter;
+pub mod fingerprint;
+pub mod genre;
+pub mod hook;
+pub mod image;
+pub mod interrupt;
+pub mod layout;
+pub mod limits;
+pub mod lock;
+pub mod logging;
+pub mod longpath;
+pub mod manifest;
+pub mod mapping;
+pub mod media_server;
+pub mod mount;
+pub mod mpd;
+pub mod mtime;
+#[cfg(feature = "mtp")]
+pub mod mtp;
+pub mod observer;
+pub mod overlap;
+pub mod plan;
+pub mod planfile;
+pub mod playlist;
+pub mod preset;
+pub mod probe;
+pub mod report;
+pub mod resolve;
+pub mod retry;
+pub mod rules;
+pub mod sanitize;
+pub mod scan;
+pub mod script;
+pub mod segment;
+pub mod sftp;
+pub mod shard;
+pub mod short_names;
+pub mod sidecar;
+pub mod since;
+pub mod smart;
+pub mod span;
+pub mod split;
+pub mod sync;
+pub mod tags;
+pub mod target;
+pub mod template;
+pub mod transcode;
+pub mod transliterate;
+pub mod upload;
+pub mod variant;
+pub mod verify;
+pub mod warnings;
+pub mod watch;
@@ -0,0 +1,105 @@
+// Copyright (c) 2024 Jan Holthuis <jan.holthuis@rub.de>
+//
+// This Source Code Form is subject to the terms of the Mozilla Public License, v. 2.0. If a copy
+// of the MPL was not distributed with this file, You can obtain one at
+// http://mozilla.org/MPL/2.0/.
+//
+// SPDX-License-Identifier: MPL-2.0
+//! Indexing limits of the head unit.
+use crate::plan::{Plan, SkipReason, SkippedEntry};
+use log::warn;
+use std::collections::BTreeSet;
+use std::fmt;
+use std::path::Path;
+
+/// Maximum numbers of items that Sync 2 indexes on a USB device.
+#[derive(Clone, Copy, Debug)]
+pub struct IndexLimits {
+    pub max_files: usize,
+    pub max_folders: usize,
+    pub max_playlists: usize,
+}
+
+/// Numbers of items in the output of a plan.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct OutputCounts {
+    /// Number of audio files.
+    pub files: usize,
+    /// Number of folders below the output directory.
+    pub folders: usize,
+    pub playlists: usize,
+}
+
+impl OutputCounts {
+    pub fn of(plan: &Plan, output_dir: &Path) -> Self {
+        let files: BTreeSet<_> = plan.tasks.iter().map(|task| &task.destination).collect();
+        let folders: BTreeSet<_> = files
+            .iter()
+            .flat_map(|destination| destination.ancestors().skip(1))
+            .filter(|folder| folder.starts_with(output_dir) && *folder != output_dir)
+            .collect();
+        OutputCounts {
+            files: files.len(),
+            folders: folders.len(),
+            playlists: plan.playlists.len(),
+        }
+    }
+
+    /// Returns a description of every limit that is exceeded.
+    pub fn violations(&self, limits: &IndexLimits) -> Vec<String> {
+        [
+            ("audio files", self.files, limits.max_files),
+            ("folders", self.folders, limits.max_folders),
+            ("playlists", self.playlists, limits.max_playlists),
+        ]
+        .into_iter()
+        .filter(|(_, count, limit)| count > limit)
+        .map(|(name, count, limit)| {
+            format!(
+                "Output contains {} {}, which exceeds the indexing limit of {} by {}",
+                count,
+                name,
+                limit,
+                count - limit
+            )
+        })
+        .collect()
+    }
+}
+
+/// Cut the entries of every playlist after the first `max_entries`, for head units that ignore
+/// the rest of longer playlists. Returns the number of cut entries.
+pub fn truncate_playlists(plan: &mut Plan, max_entries: usize) -> usize {
+    let mut num_cut = 0;
+    for playlist in plan.playlists.iter_mut() {
+        if playlist.entries.len() <= max_entries {
+            continue;
+        }
+        let cut = playlist.entries.split_off(max_entries);
+        warn!(
+            "{}: Cut {} entries ({})",
+            playlist.source.display(),
+            cut.len(),
+            SkipReason::OverPlaylistLimit
+        );
+        num_cut += cut.len();
+        playlist
+            .skipped
+            .extend(cut.into_iter().map(|entry| SkippedEntry {
+                entry: entry.target,
+                reason: SkipReason::OverPlaylistLimit,
+            }));
+    }
+    plan.remove_unreferenced_tasks();
+    num_cut
+}
+
+impl fmt::Display for OutputCounts {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{} audio files in {} folders, {} playlists",
+            self.files, self.folders, self.playlists
+        )
+    }
+}
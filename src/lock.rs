@@ -0,0 +1,298 @@
+// Copyright (c) 2024 Jan Holthuis <jan.holthuis@rub.de>
+//
+// This Source Code Form is subject to the terms of the Mozilla Public License, v. 2.0. If a copy
+// of the MPL was not distributed with this file, You can obtain one at
+// http://mozilla.org/MPL/2.0/.
+//
+// SPDX-License-Identifier: MPL-2.0
+//! Lock file that prevents concurrent runs against the same output directory.
+use crate::atomic;
+use log::{info, warn};
+use std::fmt;
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+pub const LOCK_FILE_NAME: &str = ".ford-sync-convert.lock";
+
+/// How long an unreadable lock file is taken to belong to a process that is still writing it.
+const CREATION_GRACE: Duration = Duration::from_secs(5);
+
+/// Path of the currently held lock, so that it can be removed on forced exit.
+static HELD_LOCK: Mutex<Option<PathBuf>> = Mutex::new(None);
+
+#[derive(Debug)]
+pub enum LockError {
+    /// Another live process holds the lock.
+    Locked {
+        path: PathBuf,
+        pid: u32,
+        started: u64,
+    },
+    /// Another process is just creating the lock.
+    Starting {
+        path: PathBuf,
+    },
+    Io {
+        path: PathBuf,
+        error: io::Error,
+    },
+}
+
+impl fmt::Display for LockError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            LockError::Locked { path, pid, started } => write!(
+                f,
+                "{}: Output directory is in use by process {} (started at {} seconds since the \
+                 epoch). If that run crashed, use `--force-unlock` to remove the lock.",
+                path.display(),
+                pid,
+                started
+            ),
+            LockError::Starting { path } => write!(
+                f,
+                "{}: Output directory is being locked by another run that is just starting",
+                path.display()
+            ),
+            LockError::Io { path, error } => {
+                write!(
+                    f,
+                    "{}: Failed to create lock file ({})",
+                    path.display(),
+                    error
+                )
+            }
+        }
+    }
+}
+
+impl std::error::Error for LockError {}
+
+/// A held lock on an output directory, released on drop.
+#[derive(Debug)]
+pub struct OutputLock {
+    path: PathBuf,
+}
+
+/// Returns the `(pid, start time)` stored in an existing lock file.
+fn read_lock(path: &Path) -> Option<(u32, u64)> {
+    let contents = std::fs::read_to_string(path).ok()?;
+    let mut lines = contents.lines();
+    let pid = lines.next()?.trim().parse().ok()?;
+    let started = lines.next()?.trim().parse().ok()?;
+    Some((pid, started))
+}
+
+/// Returns `true` if the lock file was modified so recently that its creator may still be
+/// writing it.
+fn is_fresh(path: &Path) -> bool {
+    std::fs::metadata(path)
+        .and_then(|metadata| metadata.modified())
+        .is_ok_and(|modified| {
+            SystemTime::now()
+                .duration_since(modified)
+                .unwrap_or_default()
+                < CREATION_GRACE
+        })
+}
+
+/// Create the lock file at `path` with its contents in one step, so that other processes never
+/// see it empty. Fails with [`io::ErrorKind::AlreadyExists`] if it exists.
+fn create(path: &Path, contents: &str) -> io::Result<()> {
+    let temp_path = atomic::temp_path(path);
+    let linked =
+        std::fs::write(&temp_path, contents).and_then(|()| std::fs::hard_link(&temp_path, path));
+    let _ = std::fs::remove_file(&temp_path);
+    match linked {
+        Err(e) if e.kind() != io::ErrorKind::AlreadyExists => {
+            // Filesystems like FAT have no hard links, so the lock is written in place instead.
+            std::fs::OpenOptions::new()
+                .write(true)
+                .create_new(true)
+                .open(path)
+                .and_then(|mut file| file.write_all(contents.as_bytes()))
+        }
+        result => result,
+    }
+}
+
+#[cfg(unix)]
+fn is_process_alive(pid: u32) -> bool {
+    let Ok(pid) = libc::pid_t::try_from(pid) else {
+        return false;
+    };
+    // SAFETY: Signal 0 only performs the permission and existence checks.
+    let result = unsafe { libc::kill(pid, 0) };
+    result == 0 || io::Error::last_os_error().raw_os_error() == Some(libc::EPERM)
+}
+
+#[cfg(not(unix))]
+fn is_process_alive(_pid: u32) -> bool {
+    // Without a portable way to check, err on the side of caution.
+    true
+}
+
+/// State of the lock file in an output directory.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum LockState {
+    Unlocked,
+    /// A live process holds the lock.
+    Locked {
+        pid: u32,
+        started: u64,
+    },
+    /// The lock was left behind by a process that no longer exists, or is unreadable.
+    Stale,
+}
+
+/// Inspect the lock file in `output_dir` without modifying it.
+pub fn state(output_dir: &Path) -> LockState {
+    let path = output_dir.join(LOCK_FILE_NAME);
+    if !path.exists() {
+        return LockState::Unlocked;
+    }
+    match read_lock(&path) {
+        Some((pid, started)) if is_process_alive(pid) => LockState::Locked { pid, started },
+        _ => LockState::Stale,
+    }
+}
+
+impl OutputLock {
+    /// Create the lock file in `output_dir`.
+    ///
+    /// Locks of processes that no longer exist are removed automatically, live locks only if
+    /// `force` is set. Unreadable locks count as live while they are younger than a few seconds,
+    /// since their process may still be writing them.
+    pub fn acquire(output_dir: &Path, force: bool) -> Result<Self, LockError> {
+        let path = output_dir.join(LOCK_FILE_NAME);
+        if path.exists() {
+            match read_lock(&path) {
+                Some((pid, started)) if !force && is_process_alive(pid) => {
+                    return Err(LockError::Locked { path, pid, started });
+                }
+                None if !force && is_fresh(&path) => return Err(LockError::Starting { path }),
+                Some((pid, _)) if force => {
+                    warn!(
+                        "{}: Forcibly removing lock of process {}",
+                        path.display(),
+                        pid
+                    )
+                }
+                _ => info!("{}: Removing stale lock file", path.display()),
+            }
+            std::fs::remove_file(&path).map_err(|error| LockError::Io {
+                path: path.clone(),
+                error,
+            })?;
+        }
+
+        let started = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|duration| duration.as_secs())
+            .unwrap_or_default();
+        match create(&path, &format!("{}\n{}\n", std::process::id(), started)) {
+            Ok(()) => (),
+            // Another run created the lock since it was checked above.
+            Err(e) if e.kind() == io::ErrorKind::AlreadyExists => {
+                return Err(match read_lock(&path) {
+                    Some((pid, started)) => LockError::Locked { path, pid, started },
+                    None => LockError::Starting { path },
+                });
+            }
+            Err(error) => return Err(LockError::Io { path, error }),
+        }
+
+        *HELD_LOCK.lock().unwrap_or_else(|e| e.into_inner()) = Some(path.clone());
+        Ok(Self { path })
+    }
+}
+
+impl Drop for OutputLock {
+    fn drop(&mut self) {
+        HELD_LOCK.lock().unwrap_or_else(|e| e.into_inner()).take();
+        if let Err(e) = std::fs::remove_file(&self.path) {
+            warn!(
+                "{}: Failed to remove lock file ({})",
+                self.path.display(),
+                e
+            );
+        }
+    }
+}
+
+/// Remove the currently held lock file without running destructors, e.g. before a forced exit.
+pub fn force_release() {
+    if let Some(path) = HELD_LOCK.lock().unwrap_or_else(|e| e.into_inner()).take() {
+        let _ = std::fs::remove_file(path);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn scratch_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("lock-{}-{}", name, std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    #[test]
+    fn refuses_a_live_lock() {
+        let dir = scratch_dir("live");
+        let lock = OutputLock::acquire(&dir, false).unwrap();
+        let contents = std::fs::read_to_string(dir.join(LOCK_FILE_NAME)).unwrap();
+        assert!(contents.starts_with(&format!("{}\n", std::process::id())));
+        assert!(matches!(
+            OutputLock::acquire(&dir, false),
+            Err(LockError::Locked { pid, .. }) if pid == std::process::id()
+        ));
+        assert!(atomic::find_stale(&dir).is_empty());
+        drop(lock);
+        assert!(!dir.join(LOCK_FILE_NAME).exists());
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn recovers_a_stale_lock() {
+        let dir = scratch_dir("stale");
+        let mut child = std::process::Command::new("true").spawn().unwrap();
+        child.wait().unwrap();
+        std::fs::write(dir.join(LOCK_FILE_NAME), format!("{}\n0\n", child.id())).unwrap();
+        assert_eq!(state(&dir), LockState::Stale);
+        let lock = OutputLock::acquire(&dir, false).unwrap();
+        assert_eq!(
+            read_lock(&dir.join(LOCK_FILE_NAME)).map(|(pid, _)| pid),
+            Some(std::process::id())
+        );
+        drop(lock);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn treats_an_empty_lock_as_live_while_it_is_fresh() {
+        let dir = scratch_dir("empty");
+        let path = dir.join(LOCK_FILE_NAME);
+        std::fs::write(&path, "").unwrap();
+        assert!(matches!(
+            OutputLock::acquire(&dir, false),
+            Err(LockError::Starting { .. })
+        ));
+        assert!(path.exists());
+
+        let old = SystemTime::now() - 2 * CREATION_GRACE;
+        std::fs::File::options()
+            .write(true)
+            .open(&path)
+            .and_then(|file| file.set_modified(old))
+            .unwrap();
+        let lock = OutputLock::acquire(&dir, false).unwrap();
+        assert!(read_lock(&path).is_some());
+        drop(lock);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
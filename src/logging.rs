@@ -0,0 +1,347 @@
+// Copyright (c) 2024 Jan Holthuis <jan.holthuis@rub.de>
+//
+// This Source Code Form is subject to the terms of the Mozilla Public License, v. 2.0. If a copy
+// of the MPL was not distributed with this file, You can obtain one at
+// http://mozilla.org/MPL/2.0/.
+//
+// SPDX-License-Identifier: MPL-2.0
+//! Configuration of the console logger and the log file.
+use crate::span;
+use clap::ValueEnum;
+use log::kv::{self, Key, Value, VisitSource};
+use log::{Level, LevelFilter, Log, Metadata, Record};
+use pretty_env_logger::env_logger;
+use serde_json::{json, Map};
+use std::fs::File;
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Mutex, OnceLock};
+use std::time::SystemTime;
+
+/// Target of log records that are shown even with `--quiet`, e.g. the final summary.
+pub const SUMMARY_TARGET: &str = "ford_sync_convert::summary";
+
+/// Returns the log level for the `--quiet` and `--verbose` flags.
+fn level(quiet: bool, verbose: u8) -> LevelFilter {
+    match (quiet, verbose) {
+        (true, _) => LevelFilter::Warn,
+        (false, 0) => LevelFilter::Info,
+        (false, 1) => LevelFilter::Debug,
+        (false, _) => LevelFilter::Trace,
+    }
+}
+
+/// How `--log-file` treats an existing log file.
+#[derive(ValueEnum, Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum LogFileMode {
+    /// Append to the log file.
+    #[default]
+    Append,
+    /// Overwrite the log file.
+    Truncate,
+    /// Write a new log file per run, with the start time appended to the file name.
+    Timestamped,
+}
+
+/// Format of the log records on the console and in the log file.
+#[derive(ValueEnum, Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum LogFormat {
+    /// Human-readable messages.
+    #[default]
+    Text,
+    /// One JSON object per line, with the fields of the record and of the playlist or task it
+    /// belongs to.
+    Json,
+}
+
+/// Collects the key-values of a record into a JSON object.
+struct JsonFields<'a>(&'a mut Map<String, serde_json::Value>);
+
+impl<'kvs> VisitSource<'kvs> for JsonFields<'_> {
+    fn visit_pair(&mut self, key: Key<'kvs>, value: Value<'kvs>) -> Result<(), kv::Error> {
+        let value = if let Some(value) = value.to_bool() {
+            value.into()
+        } else if let Some(value) = value.to_u64() {
+            value.into()
+        } else if let Some(value) = value.to_i64() {
+            value.into()
+        } else if let Some(value) = value.to_f64() {
+            value.into()
+        } else {
+            value.to_string().into()
+        };
+        self.0.insert(key.to_string(), value);
+        Ok(())
+    }
+}
+
+/// Render a record as a JSON object, including the fields of the spans entered on the current
+/// thread.
+fn json_record(record: &Record<'_>, now: SystemTime) -> serde_json::Value {
+    let spans = span::current();
+    let mut fields = Map::new();
+    for span in spans.iter() {
+        for (key, value) in span.fields.iter() {
+            fields.insert(key.to_string(), value.clone().into());
+        }
+    }
+    // Visiting the fields of a record cannot fail.
+    let _ = record.key_values().visit(&mut JsonFields(&mut fields));
+    json!({
+        "timestamp": humantime::format_rfc3339_millis(now).to_string(),
+        "level": record.level().as_str(),
+        "target": record.target(),
+        "message": record.args().to_string(),
+        "spans": spans.iter().map(|span| span.name).collect::<Vec<_>>(),
+        "fields": fields,
+    })
+}
+
+/// Returns the path of the log file for the given mode.
+fn log_file_path(path: &Path, mode: LogFileMode, now: SystemTime) -> PathBuf {
+    if mode != LogFileMode::Timestamped {
+        return path.to_path_buf();
+    }
+    let timestamp: String = humantime::format_rfc3339_seconds(now)
+        .to_string()
+        .chars()
+        .filter(|c| !matches!(c, '-' | ':'))
+        .collect();
+    let mut file_name = path.file_stem().unwrap_or_default().to_os_string();
+    file_name.push(format!("-{}", timestamp));
+    if let Some(extension) = path.extension() {
+        file_name.push(".");
+        file_name.push(extension);
+    }
+    path.with_file_name(file_name)
+}
+
+/// Open the log file, creating its parent directories if needed.
+fn open_log_file(path: &Path, mode: LogFileMode) -> io::Result<(PathBuf, File)> {
+    let path = log_file_path(path, mode, SystemTime::now());
+    if let Some(parent) = path
+        .parent()
+        .filter(|parent| !parent.as_os_str().is_empty())
+    {
+        std::fs::create_dir_all(parent)?;
+    }
+    let mut options = File::options();
+    match mode {
+        LogFileMode::Append => options.append(true).create(true),
+        LogFileMode::Truncate | LogFileMode::Timestamped => {
+            options.write(true).truncate(true).create(true)
+        }
+    };
+    let file = options.open(&path)?;
+    Ok((path, file))
+}
+
+/// Set when a warning or an error was logged.
+static PROBLEMS_LOGGED: AtomicBool = AtomicBool::new(false);
+
+/// Returns `true` if a warning or an error was logged.
+pub fn problems_logged() -> bool {
+    PROBLEMS_LOGGED.load(Ordering::Relaxed)
+}
+
+/// Warnings and errors that are held back while the console is muted, see [`mute_console`].
+static HELD: Mutex<Option<Vec<(Level, String, String)>>> = Mutex::new(None);
+
+/// The installed logger, for replaying held back records.
+static LOGGER: OnceLock<&'static Logger> = OnceLock::new();
+
+/// Stop logging to the console, e.g. while it shows a full-screen interface.
+///
+/// Records are still written to the log file. Warnings and errors are held back until
+/// [`unmute_console`].
+pub fn mute_console() {
+    *HELD.lock().unwrap_or_else(|e| e.into_inner()) = Some(vec![]);
+}
+
+/// Log to the console again, starting with the warnings and errors that were held back.
+pub fn unmute_console() {
+    let held = HELD.lock().unwrap_or_else(|e| e.into_inner()).take();
+    let Some(logger) = LOGGER.get() else {
+        return;
+    };
+    for (level, target, message) in held.into_iter().flatten() {
+        logger.log_console(
+            &Record::builder()
+                .level(level)
+                .target(&target)
+                .args(format_args!("{}", message))
+                .build(),
+        );
+    }
+}
+
+/// Logs to the console and, at debug level, to the log file.
+struct Logger {
+    console: env_logger::Logger,
+    file: Option<Mutex<File>>,
+    format: LogFormat,
+}
+
+impl Logger {
+    fn log_console(&self, record: &Record<'_>) {
+        if !self.console.matches(record) {
+            return;
+        }
+        match self.format {
+            // There is nowhere to report failures to write the log.
+            LogFormat::Json => {
+                let json = json_record(record, SystemTime::now());
+                let _ = writeln!(io::stderr().lock(), "{}", json);
+            }
+            LogFormat::Text => self.console.log(record),
+        }
+    }
+}
+
+impl Log for Logger {
+    fn enabled(&self, metadata: &Metadata<'_>) -> bool {
+        self.console.enabled(metadata) || self.file.is_some() && metadata.level() <= Level::Debug
+    }
+
+    fn log(&self, record: &Record<'_>) {
+        if record.level() <= Level::Warn {
+            PROBLEMS_LOGGED.store(true, Ordering::Relaxed);
+        }
+        match HELD.lock().unwrap_or_else(|e| e.into_inner()).as_mut() {
+            Some(held) => {
+                if record.level() <= Level::Warn && self.console.matches(record) {
+                    held.push((
+                        record.level(),
+                        record.target().to_string(),
+                        record.args().to_string(),
+                    ));
+                }
+            }
+            None => self.log_console(record),
+        }
+        let Some(file) = self
+            .file
+            .as_ref()
+            .filter(|_| record.level() <= Level::Debug)
+        else {
+            return;
+        };
+        let mut file = file.lock().unwrap_or_else(|e| e.into_inner());
+        let _ = match self.format {
+            LogFormat::Json => writeln!(file, "{}", json_record(record, SystemTime::now())),
+            LogFormat::Text => writeln!(
+                file,
+                "{} {:<5} {} > {}",
+                humantime::format_rfc3339_millis(SystemTime::now()),
+                record.level(),
+                record.target(),
+                record.args()
+            ),
+        };
+    }
+
+    fn flush(&self) {
+        self.console.flush();
+        if let Some(file) = self.file.as_ref() {
+            let _ = file.lock().unwrap_or_else(|e| e.into_inner()).flush();
+        }
+    }
+}
+
+/// Initialize the logger for the `--quiet` and `--verbose` flags, unless `RUST_LOG` is set.
+///
+/// If `log_file` is given, all records up to debug level are also written to that file. Returns
+/// the path of the log file. If it cannot be opened, only the console logger is installed.
+pub fn init(
+    quiet: bool,
+    verbose: u8,
+    log_file: Option<(&Path, LogFileMode)>,
+    format: LogFormat,
+) -> io::Result<Option<PathBuf>> {
+    let mut builder = pretty_env_logger::formatted_builder();
+    match std::env::var("RUST_LOG") {
+        Ok(filters) if !filters.is_empty() => {
+            builder.parse_filters(&filters);
+        }
+        _ => {
+            let level = level(quiet, verbose);
+            builder
+                .filter_level(level)
+                .filter_module(SUMMARY_TARGET, level.max(LevelFilter::Info));
+        }
+    }
+    let console = builder.build();
+    let (result, file) = match log_file.map(|(path, mode)| open_log_file(path, mode)) {
+        Some(Ok((path, file))) => (Ok(Some(path)), Some(Mutex::new(file))),
+        Some(Err(e)) => (Err(e), None),
+        None => (Ok(None), None),
+    };
+    let max_level = if file.is_some() {
+        console.filter().max(LevelFilter::Debug)
+    } else {
+        console.filter()
+    };
+    let logger: &'static Logger = Box::leak(Box::new(Logger {
+        console,
+        file,
+        format,
+    }));
+    log::set_logger(logger).expect("logger is set only once");
+    let _ = LOGGER.set(logger);
+    log::set_max_level(max_level);
+    result
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn maps_flags_to_levels() {
+        assert_eq!(level(true, 0), LevelFilter::Warn);
+        assert_eq!(level(false, 0), LevelFilter::Info);
+        assert_eq!(level(false, 1), LevelFilter::Debug);
+        assert_eq!(level(false, 2), LevelFilter::Trace);
+        assert_eq!(level(false, 5), LevelFilter::Trace);
+    }
+
+    #[test]
+    fn renders_records_with_span_fields_as_json() {
+        let _span = span::Span::new("task").field("source", "a.flac").enter();
+        let now = SystemTime::UNIX_EPOCH + std::time::Duration::from_secs(1_700_000_000);
+        let json = json_record(
+            &Record::builder()
+                .args(format_args!("a.mp3: Conversion succeeded."))
+                .level(Level::Info)
+                .target("ford_sync_convert::progress")
+                .key_values(&[("elapsed_ms", 1500u64)])
+                .build(),
+            now,
+        );
+        assert_eq!(
+            json,
+            json!({
+                "timestamp": "2023-11-14T22:13:20.000Z",
+                "level": "INFO",
+                "target": "ford_sync_convert::progress",
+                "message": "a.mp3: Conversion succeeded.",
+                "spans": ["task"],
+                "fields": {"source": "a.flac", "elapsed_ms": 1500},
+            })
+        );
+    }
+
+    #[test]
+    fn timestamps_log_file_names() {
+        let now = SystemTime::UNIX_EPOCH + std::time::Duration::from_secs(1_700_000_000);
+        assert_eq!(
+            log_file_path(Path::new("logs/sync.log"), LogFileMode::Timestamped, now),
+            Path::new("logs/sync-20231114T221320Z.log")
+        );
+        assert_eq!(
+            log_file_path(Path::new("logs/sync.log"), LogFileMode::Append, now),
+            Path::new("logs/sync.log")
+        );
+    }
+}
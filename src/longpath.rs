@@ -0,0 +1,123 @@
+// Copyright (c) 2024 Jan Holthuis <jan.holthuis@rub.de>
+//
+// This Source Code Form is subject to the terms of the Mozilla Public License, v. 2.0. If a copy
+// of the MPL was not distributed with this file, You can obtain one at
+// http://mozilla.org/MPL/2.0/.
+//
+// SPDX-License-Identifier: MPL-2.0
+//! Extended-length paths on Windows.
+//!
+//! Deep output trees easily exceed `MAX_PATH` (260 characters) on Windows. All filesystem
+//! operations and the paths handed to FFmpeg therefore use [`extended`] paths. Playlist entries
+//! are never derived from these paths.
+use std::path::{Path, PathBuf};
+
+/// Returns `path` in a form that is not subject to the `MAX_PATH` limit.
+///
+/// On Windows, this is an absolute `\\?\` (or `\\?\UNC\`) path. On other platforms, the path is
+/// returned unchanged.
+#[cfg(windows)]
+pub fn extended(path: &Path) -> PathBuf {
+    use std::ffi::OsString;
+    use std::path::{Component, Prefix};
+
+    // Verbatim paths are not normalized by Windows, so they must be absolute and must not
+    // contain `.` or `..` components or forward slashes.
+    let Ok(absolute) = std::path::absolute(path) else {
+        return path.to_path_buf();
+    };
+    let Some(Component::Prefix(prefix)) = absolute.components().next() else {
+        return absolute;
+    };
+    let mut result = OsString::new();
+    match prefix.kind() {
+        Prefix::Disk(_) => {
+            result.push(r"\\?\");
+            result.push(prefix.as_os_str());
+        }
+        Prefix::UNC(server, share) => {
+            result.push(r"\\?\UNC\");
+            result.push(server);
+            result.push(r"\");
+            result.push(share);
+        }
+        // Already verbatim or a device path.
+        _ => return absolute,
+    }
+    let mut has_components = false;
+    for component in absolute.components() {
+        if let Component::Normal(name) = component {
+            result.push(r"\");
+            result.push(name);
+            has_components = true;
+        }
+    }
+    if !has_components {
+        result.push(r"\");
+    }
+    PathBuf::from(result)
+}
+
+#[cfg(not(windows))]
+pub fn extended(path: &Path) -> PathBuf {
+    path.to_path_buf()
+}
+
+#[cfg(all(test, windows))]
+mod tests {
+    use super::*;
+
+    /// Returns a fresh directory below the system's temporary directory.
+    fn temp_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("ford-sync-{}-{}", name, std::process::id()));
+        let _ = std::fs::remove_dir_all(extended(&dir));
+        dir
+    }
+
+    /// Returns a path below `dir` that is longer than `MAX_PATH`.
+    fn long_path(dir: &Path, file_name: &str) -> PathBuf {
+        let mut path = dir.to_path_buf();
+        for i in 0..6 {
+            path.push(format!("{}-{}", i, "a".repeat(50)));
+        }
+        path.push(file_name);
+        assert!(path.as_os_str().len() > 260);
+        path
+    }
+
+    #[test]
+    fn extended_disk_path() {
+        assert_eq!(
+            extended(Path::new(r"C:\Music/Album\..\Song.mp3")),
+            PathBuf::from(r"\\?\C:\Music\Song.mp3")
+        );
+        assert_eq!(extended(Path::new(r"C:\")), PathBuf::from(r"\\?\C:\"));
+    }
+
+    #[test]
+    fn extended_unc_path() {
+        assert_eq!(
+            extended(Path::new(r"\\server\share\Music\Song.mp3")),
+            PathBuf::from(r"\\?\UNC\server\share\Music\Song.mp3")
+        );
+    }
+
+    #[test]
+    fn extended_is_idempotent() {
+        let path = extended(Path::new(r"C:\Music\Song.mp3"));
+        assert_eq!(extended(&path), path);
+    }
+
+    #[test]
+    fn create_and_copy_beyond_max_path() {
+        let dir = temp_dir("copy");
+        let source = long_path(&dir, "source.mp3");
+        let destination = long_path(&dir.join("out"), "destination.mp3");
+        std::fs::create_dir_all(extended(source.parent().unwrap())).unwrap();
+        std::fs::write(extended(&source), b"data").unwrap();
+        std::fs::create_dir_all(extended(destination.parent().unwrap())).unwrap();
+        std::fs::copy(extended(&source), extended(&destination)).unwrap();
+        assert_eq!(std::fs::read(extended(&destination)).unwrap(), b"data");
+        std::fs::remove_dir_all(extended(&dir)).unwrap();
+    }
+}
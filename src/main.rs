@@ -5,209 +5,2912 @@
 // http://mozilla.org/MPL/2.0/.
 //
 // SPDX-License-Identifier: MPL-2.0
-use clap::Parser;
-use log::{info, warn};
+mod confirm;
+mod progress;
+#[cfg(feature = "tui")]
+mod tui;
+
+use clap::{
+    ArgAction, ArgMatches, Args, Command, CommandFactory, FromArgMatches, Parser, Subcommand,
+};
+use ford_sync_convert::config::Source;
+use ford_sync_convert::dedupe::DedupeKey;
+use ford_sync_convert::estimate::{ByteSize, EstimateOptions, SizeEstimate};
+use ford_sync_convert::exclude::Excludes;
+use ford_sync_convert::execute::ExecuteOptions;
+use ford_sync_convert::filter::MissingTag;
+use ford_sync_convert::fingerprint;
+use ford_sync_convert::genre::{self, GenreMap};
+use ford_sync_convert::hook::Hook;
+use ford_sync_convert::layout::{Layout, SharedTracks, Structure};
+use ford_sync_convert::limits::{IndexLimits, OutputCounts};
+use ford_sync_convert::lock::OutputLock;
+use ford_sync_convert::logging::{LogFileMode, LogFormat};
+use ford_sync_convert::manifest::Manifest;
+use ford_sync_convert::media_server::{MediaServer, PathMapping, ServerKind};
+use ford_sync_convert::mount::TargetFs;
+use ford_sync_convert::mpd::{MpdPlaylists, MpdServer, MpdSource};
+#[cfg(feature = "mtp")]
+use ford_sync_convert::mtp;
+use ford_sync_convert::observer::Observers;
+use ford_sync_convert::plan::{Action, ConflictPolicy, OutsideMusicRoot, Plan};
+use ford_sync_convert::planfile::{Change, PlanFile};
+use ford_sync_convert::playlist::{LineEnding, PlaylistEncoding, PlaylistStyle};
+use ford_sync_convert::preset::Preset;
+use ford_sync_convert::report::{Counts, Report, Timing};
+use ford_sync_convert::report_warning;
+use ford_sync_convert::rules::{self, EncoderSettings, Rules};
+use ford_sync_convert::sanitize::{LengthLimits, SanitizeMode};
+use ford_sync_convert::scan::FolderPlaylists;
+use ford_sync_convert::script::ScriptFormat;
+use ford_sync_convert::segment;
+use ford_sync_convert::sftp::{self, SftpOptions, SftpTarget};
+use ford_sync_convert::since::{self, SinceMode};
+use ford_sync_convert::smart;
+use ford_sync_convert::split::{self, SplitTarget};
+use ford_sync_convert::sync::{Executor, SyncErrorKind, SyncOptions};
+use ford_sync_convert::tags::{StripFrames, TagTransliteration, TextEncoding};
+use ford_sync_convert::template::{FilenamePattern, Template};
+use ford_sync_convert::transliterate::TransliterationStyle;
+use ford_sync_convert::variant::Variant;
+use ford_sync_convert::warnings::{StrictMode, WarningCategory};
+use ford_sync_convert::watch::Watcher;
+use ford_sync_convert::{
+    archive, art, atomic, compilation, config, diff, doctor, estimate, exclude, exit, image,
+    interrupt, logging, longpath, manifest, mount, plan, report, sanitize, script, sync, tags,
+    upload, verify, warnings,
+};
+use log::{debug, error, info, warn};
+use progress::LogObserver;
+use std::collections::{BTreeMap, BTreeSet, HashMap, HashSet};
+use std::ffi::OsString;
 use std::path::{Path, PathBuf};
-use std::process::Command;
+use std::sync::Arc;
+use std::time::{Duration, Instant, SystemTime};
 
 /// Converter for playlists into a Ford Sync 2 compatible format.
 #[derive(Parser, Debug)]
-#[command(version, about, long_about = None)]
+#[command(
+    version,
+    about,
+    long_about = None,
+    after_long_help = "Every option can also be set with a FORD_SYNC_<OPTION> environment variable, \
+        e.g. FORD_SYNC_OUTPUT_DIR or FORD_SYNC_DRY_RUN=1. Lists like FORD_SYNC_PLAYLISTS are \
+        separated like PATH (by `:` on Unix and `;` on Windows). Command line arguments take \
+        precedence over the environment, which takes precedence over the configuration file.
+
+Exit status: 0 if everything was synced, 1 if some files failed, 2 for invalid arguments or \
+        configuration, 3 for problems with the environment (e.g. FFmpeg is missing or the \
+        output directory is not writable), 4 if the run was interrupted or aborted.",
+    args_conflicts_with_subcommands = true
+)]
 struct Cli {
+    #[command(subcommand)]
+    command: Option<Commands>,
+
+    #[command(flatten)]
+    sync: SyncArgs,
+}
+
+#[derive(Subcommand, Debug)]
+enum Commands {
+    /// Convert and copy the playlists to the output directory (the default if no subcommand is
+    /// given).
+    Convert(SyncArgs),
+    /// Compute what would be done and write it to a plan file, without executing it.
+    Plan(PlanArgs),
+    /// Execute a plan file written by `plan`.
+    Apply(ApplyArgs),
+    /// Check the environment, e.g. FFmpeg and the output directory, before a sync.
+    ///
+    /// Every check passes, warns or fails with a hint on how to fix the problem. The exit status
+    /// is 0 if all checks pass, 1 if some warn and 3 if some fail.
+    Doctor(SyncArgs),
+    /// Check that an already synced output directory matches the playlists.
+    ///
+    /// Every playlist entry on the medium has to resolve to an existing, non-empty file, and every
+    /// planned file has to exist. The exit status is 1 if any problem is found.
+    Verify(VerifyArgs),
+    /// Compare the playlists against an already synced output directory.
+    ///
+    /// Lists the entries missing on the medium, entries of the synced playlists that are no longer
+    /// in the source playlists and files that are older than their source. The exit status is 1
+    /// if there are any differences.
+    Diff(SyncArgs),
+    /// Predict the output size and conversion time of the playlists, without writing anything.
+    ///
+    /// Probes every source and prints the number of tracks, the source size, the estimated output
+    /// size and the predicted time to convert and copy them, per playlist and in total.
+    Estimate(EstimateArgs),
+    /// Remove every file that earlier syncs created in the output directory.
+    ///
+    /// The files are taken from the manifest that every sync keeps in the output directory, so
+    /// other files are left alone. Directories that became empty are removed as well, and finally
+    /// the manifest. Refuses to run if there is no manifest.
+    Clean(SyncArgs),
+}
+
+impl Commands {
+    /// The options shared by all subcommands.
+    fn sync_args(&self) -> &SyncArgs {
+        match self {
+            Commands::Convert(args)
+            | Commands::Doctor(args)
+            | Commands::Diff(args)
+            | Commands::Clean(args) => args,
+            Commands::Plan(args) => &args.sync,
+            Commands::Apply(args) => &args.sync,
+            Commands::Verify(args) => &args.sync,
+            Commands::Estimate(args) => &args.sync,
+        }
+    }
+
+    fn sync_args_mut(&mut self) -> &mut SyncArgs {
+        match self {
+            Commands::Convert(args)
+            | Commands::Doctor(args)
+            | Commands::Diff(args)
+            | Commands::Clean(args) => args,
+            Commands::Plan(args) => &mut args.sync,
+            Commands::Apply(args) => &mut args.sync,
+            Commands::Verify(args) => &mut args.sync,
+            Commands::Estimate(args) => &mut args.sync,
+        }
+    }
+}
+
+#[derive(Args, Debug)]
+struct PlanArgs {
+    /// Path to write the plan to (`-` for stdout).
+    #[arg(long, value_name = "PATH")]
+    plan_file: PathBuf,
+
+    #[command(flatten)]
+    sync: SyncArgs,
+}
+
+#[derive(Args, Debug)]
+struct ApplyArgs {
+    /// Plan file written by `plan`.
+    ///
+    /// The options that were used for planning are read from the plan file, and can be overridden
+    /// on the command line. If `--output-dir` differs from the planned one, the planned output
+    /// paths are moved there.
+    plan: PathBuf,
+
+    #[command(flatten)]
+    sync: SyncArgs,
+}
+
+#[derive(Args, Debug)]
+struct VerifyArgs {
+    /// Also probe every file and compare its duration with the source (within
+    /// `--verify-tolerance`).
+    #[arg(long)]
+    deep: bool,
+
+    #[command(flatten)]
+    sync: SyncArgs,
+}
+
+#[derive(Args, Debug)]
+struct EstimateArgs {
+    /// Print the prediction as JSON.
+    #[arg(long)]
+    json: bool,
+
+    /// Seconds of audio that one worker encodes per second, for predicting the conversion time.
+    #[arg(long, value_name = "FACTOR", default_value_t = 30.0)]
+    encode_speed: f64,
+
+    /// Measure the encode and copy speeds from the report of a previous run, written with
+    /// `--report-json`, instead.
+    #[arg(long, value_name = "PATH")]
+    timings_from: Option<PathBuf>,
+
+    #[command(flatten)]
+    sync: SyncArgs,
+}
+
+#[derive(Args, Clone, Debug)]
+struct SyncArgs {
     /// Path to write output to.
     #[arg(short, long, default_value = "output")]
     output_dir: PathBuf,
 
-    /// Playlist files
+    /// Write the output into a ZIP archive at this path instead of the output directory.
+    ///
+    /// The files are staged in a hidden directory next to the archive, which is removed after
+    /// packing.
+    #[arg(
+        long,
+        value_name = "PATH",
+        conflicts_with_all = ["watch", "emit_script", "eject"]
+    )]
+    output_zip: Option<PathBuf>,
+
+    /// Write the output into a FAT32 disk image at this path, e.g. to copy it onto a stick with
+    /// `dd`.
+    ///
+    /// Like with `--output-zip`, the files are staged in a hidden directory next to the image.
+    #[arg(
+        long,
+        value_name = "PATH",
+        requires = "image_size",
+        conflicts_with_all = ["output_zip", "watch", "emit_script", "eject"]
+    )]
+    output_image: Option<PathBuf>,
+
+    /// Upload the output to an MTP device like a phone, given as `device:/folder`, e.g.
+    /// `Pixel 7:/Music`.
+    ///
+    /// The device is matched by its friendly or model name, and may be left out if only one is
+    /// attached. Files are converted into a staging directory first, and files already on the
+    /// device with the same size are not uploaded again.
+    ///
+    /// The staging directory is kept in the cache directory for the next run to the same device
+    /// and folder, which only converts the sources that changed since.
+    #[cfg(feature = "mtp")]
+    #[arg(
+        long,
+        value_name = "TARGET",
+        conflicts_with_all = ["output_zip", "output_image", "watch", "emit_script", "eject"]
+    )]
+    output_mtp: Option<mtp::MtpTarget>,
+
+    /// Upload the output to a remote directory over SFTP, given as `[user@]host:/path`.
+    ///
+    /// Files are converted into a staging directory first and uploaded with the OpenSSH client,
+    /// keeping their modification times. Files with the same size and modification time on the
+    /// remote host are not uploaded again. Failed uploads are retried `--retries` times.
+    ///
+    /// The staging directory is kept in the cache directory for the next run to the same target,
+    /// which only converts the sources that changed since.
+    #[arg(
+        long,
+        value_name = "TARGET",
+        conflicts_with_all = ["output_zip", "output_image", "watch", "emit_script", "eject"]
+    )]
+    output_sftp: Option<SftpTarget>,
+
+    /// Split the output across several directories like USB sticks, given as comma-separated
+    /// `DIR:SIZE` pairs, e.g. `/media/usb1:32G,/media/usb2:32G`.
+    ///
+    /// Each playlist is kept on one target where possible and only written there. Playlists that
+    /// fit on no target are split into parts like `Name (disc 2)`. With a single target, this is
+    /// the same as `--output-dir DIR --max-total-size SIZE`.
+    #[arg(
+        long,
+        value_name = "TARGETS",
+        value_delimiter = ',',
+        conflicts_with_all = [
+            "output_zip", "output_image", "output_sftp", "watch", "emit_script", "move_sources"
+        ]
+    )]
+    #[cfg_attr(feature = "mtp", arg(conflicts_with = "output_mtp"))]
+    split_targets: Vec<SplitTarget>,
+
+    /// Write a variant of the output with other encoder settings, given as
+    /// `NAME:CODEC[,q=QUALITY][,b=BITRATE][,ac=CHANNELS],dir=DIR`, e.g.
+    /// `car:mp3,q=2,dir=/media/usb`. Can be given several times.
+    ///
+    /// All variants are made from one plan, so each source is read and probed once. Each variant
+    /// gets its own playlists, its reports with its name appended, e.g. `report-car.json`, and a
+    /// summary line at the end. Rules from the configuration file take precedence over the
+    /// encoder settings of a variant.
+    #[arg(
+        long = "variant",
+        value_name = "VARIANT",
+        conflicts_with_all = [
+            "output_zip", "output_image", "output_sftp", "watch", "emit_script", "move_sources",
+            "split_targets"
+        ]
+    )]
+    #[cfg_attr(feature = "mtp", arg(conflicts_with = "output_mtp"))]
+    variants: Vec<Variant>,
+
+    /// Number of files uploaded at the same time with `--output-sftp`.
+    #[arg(long, default_value_t = 4, requires = "output_sftp")]
+    sftp_jobs: usize,
+
+    /// Size of the image written with `--output-image` (e.g. `16G`), at most that of the stick.
+    #[arg(long, requires = "output_image", value_parser = estimate::parse_size)]
+    image_size: Option<u64>,
+
+    /// Filesystem that the output will end up on (detected from the output directory by default).
+    ///
+    /// Useful for staging directories that are later copied to a USB stick.
+    #[arg(long, value_enum)]
+    target_fs: Option<TargetFs>,
+
+    /// What to do when different sources map to the same output path.
+    #[arg(long, value_enum, default_value_t)]
+    on_conflict: ConflictPolicy,
+
+    /// Number of times a failed conversion, a copy whose checksum does not match (with
+    /// `--verify-copies`) and a failed upload to `--output-sftp` are retried.
+    ///
+    /// Failures that retrying cannot fix, like missing sources, are not retried.
+    #[arg(long, default_value_t = 0)]
+    retries: usize,
+
+    /// Probe source files with ffprobe to determine their durations.
+    #[arg(long)]
+    probe: bool,
+
+    /// Assumed track length in minutes for estimating the output size of unprobed conversions.
+    #[arg(long, default_value_t = 4.0)]
+    minutes_per_track: f64,
+
+    /// Trim the plan so that the output fits into the given size (e.g. `15G`).
+    ///
+    /// Entries are cut from the end of the lowest-priority playlists first.
+    #[arg(long, value_parser = estimate::parse_size)]
+    max_total_size: Option<u64>,
+
+    /// Trim the plan so that at most this many audio files are written.
+    ///
+    /// Entries are cut from the end of the lowest-priority playlists first.
+    #[arg(long)]
+    max_total_files: Option<u64>,
+
+    /// Playlist to prefer when trimming the plan (can be given multiple times).
+    ///
+    /// By default, playlists are prioritized in the order they are given on the command line.
+    #[arg(long, value_name = "PLAYLIST")]
+    priority: Vec<PathBuf>,
+
+    /// Leave out entries whose source path matches this glob (can be given multiple times).
+    ///
+    /// `*` and `?` match within a directory, `**` matches any number of directories, e.g.
+    /// `**/Christmas/**`. Patterns without a `/` only match the file name, e.g. `*.spoken.*`.
+    #[arg(long, value_name = "GLOB")]
+    exclude: Vec<String>,
+
+    /// Read `--exclude` patterns from a file, one per line (`#` starts a comment).
+    #[arg(long, value_name = "FILE")]
+    exclude_from: Vec<PathBuf>,
+
+    /// Only consider entries with these source extensions, or `all` to consider every file.
+    ///
+    /// Other entries are left out of the output quietly. Video containers such as `mp4` are not
+    /// included by default; with `all`, they are converted if they have an audio stream.
+    #[arg(
+        long,
+        value_name = "EXTS",
+        value_delimiter = ',',
+        default_value = exclude::DEFAULT_EXTENSIONS
+    )]
+    include_ext: Vec<String>,
+
+    /// Leave out files with one of these genres (comma-separated, case-insensitive).
+    #[arg(long, value_name = "GENRES", value_delimiter = ',')]
+    skip_genre: Vec<String>,
+
+    /// Leave out files by this artist or album artist (can be given multiple times).
+    #[arg(long, value_name = "ARTIST")]
+    skip_artist: Vec<String>,
+
+    /// Leave out files rated below this many stars (from ID3 `POPM` frames or `rating` tags).
+    #[arg(long, value_name = "STARS", value_parser = clap::value_parser!(u8).range(1..=5))]
+    min_rating: Option<u8>,
+
+    /// Leave out files shorter than this many seconds, e.g. intros and hidden-track fragments.
+    ///
+    /// Files whose duration cannot be determined are kept.
+    #[arg(long, value_name = "SECONDS")]
+    min_duration: Option<f64>,
+
+    /// Split files longer than this duration (like `15m`) into parts like `Book - 01.mp3`, e.g.
+    /// audiobooks that head units cannot seek in.
+    ///
+    /// Files with chapter markers are split at the chapters, others into parts of this length.
+    /// The parts replace the file in the playlist.
+    #[arg(long, value_name = "DURATION", value_parser = segment::parse_max_duration)]
+    split_long_files: Option<f64>,
+
+    /// Only process entries whose source was modified after this cutoff, given as a date like
+    /// `2024-06-01` (in UTC) or a duration before now like `7d`.
+    #[arg(long, value_name = "CUTOFF", value_parser = since::parse_cutoff)]
+    since: Option<SystemTime>,
+
+    /// Whether `--since` only leaves older entries out of the conversion or also out of the
+    /// playlists.
+    #[arg(long, value_enum, default_value_t, requires = "since")]
+    since_mode: SinceMode,
+
+    /// What to do with files that lack the tag a filter looks at.
+    #[arg(long, value_enum, value_name = "ACTION", default_value_t = MissingTag::Keep)]
+    on_missing_tag: MissingTag,
+
+    /// Leave entries missing that only differ from an existing file by case or Unicode
+    /// normalization, instead of using that file.
+    #[arg(long)]
+    no_fuzzy_match: bool,
+
+    /// Also resolve missing entries to files whose names differ by up to this many characters,
+    /// ignoring case and Unicode normalization. Names with several equally close matches are left
+    /// missing.
+    #[arg(
+        long,
+        value_name = "CHARS",
+        default_value_t = 0,
+        conflicts_with = "no_fuzzy_match"
+    )]
+    fuzzy_max_distance: usize,
+
+    /// Look up entries that still do not exist by their file name below this directory, e.g.
+    /// after albums were moved. Names found several times are only used if the duration given by
+    /// the playlist tells them apart.
+    #[arg(long, value_name = "DIR")]
+    resolve_missing: Option<PathBuf>,
+
+    /// Only warn instead of aborting when the output is estimated to exceed the free space.
+    #[arg(long)]
+    ignore_space: bool,
+
+    /// Probe converted files and compare their duration against the source.
+    #[arg(long)]
+    verify_output: bool,
+
+    /// Maximum allowed duration difference in seconds for `--verify-output`.
+    #[arg(long, default_value_t = 2.0)]
+    verify_tolerance: f64,
+
+    /// Compare checksums of copied files against their source.
+    #[arg(long)]
+    verify_copies: bool,
+
+    /// Move copied sources into the output directory instead of copying them. Sources are
+    /// renamed if they are on the same filesystem, and otherwise only removed once their copies
+    /// are verified.
+    #[arg(
+        long = "move",
+        conflicts_with_all = [
+            "output_zip", "output_image", "output_sftp", "watch", "emit_script"
+        ]
+    )]
+    #[cfg_attr(feature = "mtp", arg(conflicts_with = "output_mtp"))]
+    move_sources: bool,
+
+    /// With `--move`, also remove the sources of converted files once their output is verified
+    /// (implies `--verify-output`).
+    #[arg(long, requires = "move_sources")]
+    move_converted_sources: bool,
+
+    /// Remove the lock file of another run on the output directory, e.g. after a crash.
+    #[arg(long)]
+    force_unlock: bool,
+
+    /// Allow the output directory to overlap with the playlist directories or sources.
+    #[arg(long)]
+    allow_overlap: bool,
+
+    /// Keep FFmpeg logs of successful conversions, not only of failed ones.
+    #[arg(long)]
+    keep_logs: bool,
+
+    /// Flush every output file and directory to disk, and the whole filesystem at the end.
+    ///
+    /// This makes it safe to unplug the target right after the run, but slows down writing.
+    #[arg(long)]
+    sync_writes: bool,
+
+    /// Unmount the removable medium containing the output directory after the run.
+    #[arg(long)]
+    eject: bool,
+
+    /// Print how long each phase of the run took.
+    #[arg(long)]
+    timings: bool,
+
+    /// How to arrange the output files.
+    ///
+    /// `artist-album` reads the tags of all sources (which implies `--probe`).
+    #[arg(long, value_enum, default_value_t = Structure::Mirror)]
+    structure: Structure,
+
+    /// Where to place audio files and playlists in the output directory.
+    #[arg(long, value_enum, default_value_t = Layout::Flat)]
+    layout: Layout,
+
+    /// Root of the music library. Output paths are derived from the location of the sources
+    /// below it instead of from the playlist entries (recommended), so that the output does not
+    /// depend on where the playlists are.
+    #[arg(long, value_name = "DIR")]
+    music_root: Option<PathBuf>,
+
+    /// What to do with sources outside of the `--music-root`.
+    #[arg(
+        long,
+        value_enum,
+        value_name = "POLICY",
+        default_value_t,
+        requires = "music_root"
+    )]
+    outside_music_root: OutsideMusicRoot,
+
+    /// Place the audio files of every playlist in a folder named after the playlist.
+    #[arg(long)]
+    group_by_playlist: bool,
+
+    /// Where tracks in several playlists are placed with `--group-by-playlist`.
+    #[arg(
+        long,
+        value_enum,
+        value_name = "POLICY",
+        default_value_t = SharedTracks::Duplicate,
+        requires = "group_by_playlist"
+    )]
+    shared_tracks: SharedTracks,
+
+    /// Prefix output file names with their zero-padded position in the playlist, like
+    /// `001 - Track.mp3`, to keep the playlist order when browsing by folder.
+    ///
+    /// Tracks in several playlists keep the position in the first one, unless they are placed in
+    /// the folder of every playlist with `--group-by-playlist`.
+    #[arg(long)]
+    prefix_position: bool,
+
+    /// Name output files from their tags, e.g. `{track:02} - {artist} - {title}`.
+    ///
+    /// Placeholders are {artist}, {albumartist}, {album}, {title}, {track}, {disc} and {year}.
+    /// `{track:02}` zero-pads numbers and `{album|Singles}` sets the value for a missing tag. The
+    /// extension is appended automatically. Implies `--probe`.
+    #[arg(long, value_name = "TEMPLATE")]
+    filename_template: Option<Template>,
+
+    /// Reduce the output directory hierarchy to at most DEPTH levels by folding upper directories
+    /// into the retained directory names.
+    #[arg(
+        long,
+        value_name = "DEPTH",
+        num_args = 0..=1,
+        require_equals = true,
+        default_missing_value = "2"
+    )]
+    flatten: Option<usize>,
+
+    /// Transliterate non-ASCII characters in output paths to ASCII.
+    ///
+    /// Sync 2 renders characters outside its font as boxes.
+    #[arg(
+        long,
+        value_enum,
+        num_args = 0..=1,
+        require_equals = true,
+        default_missing_value = "basic"
+    )]
+    transliterate: Option<TransliterationStyle>,
+
+    /// Rename all output files and directories to DOS-compatible 8.3 names like
+    /// `03ATMSPH.mp3`, for head units that do not index long file names.
+    ///
+    /// File names are made from the track number and an abbreviation of the title, and get a
+    /// tail like `~2` if they are taken. The mapping file lists the original names.
+    #[arg(long)]
+    short_names: bool,
+
+    /// When to replace characters in output paths that are invalid on FAT filesystems.
+    #[arg(long, value_enum, default_value_t = SanitizeMode::Fat32)]
+    sanitize: SanitizeMode,
+
+    /// Character that replaces invalid characters in sanitized output paths.
+    #[arg(long, default_value = "_", value_parser = sanitize::parse_replacement)]
+    sanitize_replacement: char,
+
+    /// Maximum length of a single file or directory name in the output.
+    #[arg(long, default_value_t = 255)]
+    max_name_len: usize,
+
+    /// Maximum length of an output path relative to the output directory.
+    ///
+    /// Sync 2 fails to index files with paths longer than about 255 characters.
+    #[arg(long, default_value_t = 255)]
+    max_path_len: usize,
+
+    /// Number of audio files that the head unit indexes at most.
+    #[arg(long, default_value_t = 15000)]
+    max_files: usize,
+
+    /// Number of folders that the head unit indexes at most.
+    #[arg(long, default_value_t = 3000)]
+    max_folders: usize,
+
+    /// Number of playlists that the head unit indexes at most.
+    #[arg(long, default_value_t = 500)]
+    max_playlists: usize,
+
+    /// Cut playlists after this many entries, for head units that ignore the rest.
+    #[arg(long, value_name = "N")]
+    max_playlist_entries: Option<usize>,
+
+    /// Move the files of output folders that would hold more than this many into numbered
+    /// subfolders like `Singles\001`, as large FAT folders are slow to index.
+    ///
+    /// Files stay in the subfolder that an earlier run put them into.
+    #[arg(
+        long,
+        value_name = "N",
+        value_parser = clap::builder::RangedU64ValueParser::<usize>::new().range(1..)
+    )]
+    max_files_per_dir: Option<usize>,
+
+    /// Abort instead of only warning when the output exceeds the indexing limits.
+    #[arg(long)]
+    enforce_limits: bool,
+
+    /// Exit with a non-zero status if any empty or corrupt source files were skipped.
+    #[arg(long)]
+    fail_on_corrupt: bool,
+
+    /// Rewrite ID3v2.4 and ID3v1-only tags of copied MP3s as ID3v2.3, which Sync 2 can read.
+    #[arg(long)]
+    fix_tags: bool,
+
+    /// Encode converted files with this constant bitrate in kbit/s instead of VBR quality 2.
+    ///
+    /// Rules of the configuration file take precedence.
+    #[arg(long, value_name = "KBPS", value_parser = rules::parse_bitrate)]
+    bitrate: Option<u32>,
+
+    /// Remove tag frames from output MP3s (default: PRIV,GEOB,APIC+).
+    ///
+    /// Takes a comma-separated list of frame IDs, where `APIC+` removes all attached pictures but
+    /// the first. Lyrics (USLT) and comments (COMM) can be added to the list.
+    #[arg(
+        long,
+        value_name = "IDS",
+        num_args = 0..=1,
+        require_equals = true,
+        default_missing_value = "PRIV,GEOB,APIC+",
+        value_parser = tags::parse_strip_frames
+    )]
+    strip_frames: Option<StripFrames>,
+
+    /// Set the album of every output file to the name of its playlist, so that the head unit's
+    /// album view lists the playlists.
+    ///
+    /// Files in several playlists get the name of the first one.
+    #[arg(long)]
+    album_from_playlist: bool,
+
+    /// Keep the original album of files in their original title frame (TOAL) with
+    /// `--album-from-playlist`. Implies `--probe`.
+    #[arg(long, requires = "album_from_playlist")]
+    keep_original_album: bool,
+
+    /// Set the track number of every output file to its position in its playlist and clear the
+    /// disc number, so that a folder plays in playlist order.
+    ///
+    /// Files in several playlists get their position in the first one.
+    #[arg(long)]
+    tracknum_from_position: bool,
+
+    /// Read missing tags of sources without artist or title from their file names, e.g. with
+    /// `{track} - {artist} - {title}`.
+    ///
+    /// Takes a file name template without extension, or `auto` to try common patterns. File names
+    /// that do not match keep their tags. Implies `--probe`.
+    #[arg(long, value_name = "PATTERN")]
+    tags_from_filename: Option<FilenamePattern>,
+
+    /// Replace existing tags with those read by `--tags-from-filename`.
+    #[arg(long, requires = "tags_from_filename")]
+    tags_from_filename_force: bool,
+
+    /// Mark compilations in the output tags, so that the head unit does not split them into one
+    /// album per track artist.
+    ///
+    /// Albums with different track artists in the same directory, or with the compilation flag,
+    /// get the compilation flag (TCMP) and, unless they have one, an album artist. Implies
+    /// `--probe`.
+    #[arg(long)]
+    fix_compilations: bool,
+
+    /// Album artist of compilations with `--fix-compilations`.
+    #[arg(long, value_name = "NAME", default_value = compilation::DEFAULT_ARTIST)]
+    compilation_artist: String,
+
+    /// Normalize genres with a mapping file of `from=to` lines, e.g. `Alt Rock=Alternative Rock`.
+    ///
+    /// Genres are matched case-insensitively, `*=Other` maps all genres that are not listed and
+    /// `#` starts a comment. Applies to copied files only with `--fix-tags`. Implies `--probe`.
+    #[arg(long, value_name = "FILE")]
+    genre_map: Option<PathBuf>,
+
+    /// List the genres of all sources and what they were mapped to by `--genre-map`.
+    #[arg(long, requires = "genre_map")]
+    genre_map_report: bool,
+
+    /// Also write a playlist per genre with all output files of that genre, named like
+    /// `Genre - Rock.m3u`. Uses the genres after `--genre-map`. Implies `--probe`.
+    #[arg(long)]
+    genre_playlists: bool,
+
+    /// Minimum number of tracks of a genre to write a playlist for it.
+    #[arg(
+        long,
+        value_name = "N",
+        default_value_t = 5,
+        requires = "genre_playlists"
+    )]
+    genre_playlist_min_tracks: usize,
+
+    /// Keep only one source of tracks that are in the library several times, e.g. on an album and
+    /// a compilation, and point all playlist entries at it.
+    ///
+    /// Tracks are compared by their tags, ignoring case and punctuation. The source with the
+    /// highest bit rate is kept. Implies `--probe`.
+    #[arg(long, value_enum, value_name = "TAGS")]
+    dedupe_by_tag: Option<DedupeKey>,
+
+    /// Keep only one source of tracks that sound the same, comparing acoustic fingerprints
+    /// computed with `fpcalc` from Chromaprint.
+    ///
+    /// This is slow since every planned source has to be decoded, so it is off by default. The
+    /// fingerprints are stored with the other metadata in plan files. Implies `--probe`.
+    #[arg(long)]
+    dedupe_by_fingerprint: bool,
+
+    /// Minimum similarity of the fingerprints of the same track, from 0 to 1.
+    #[arg(
+        long,
+        value_name = "SIMILARITY",
+        default_value_t = fingerprint::DEFAULT_THRESHOLD,
+        value_parser = fingerprint::parse_threshold,
+        requires = "dedupe_by_fingerprint"
+    )]
+    fingerprint_threshold: f64,
+
+    /// Write a folder.jpg with the album art into every output directory.
+    #[arg(long)]
+    folder_art: bool,
+
+    /// Copy the cover image next to the sources (see `--cover-names`) into every output folder.
+    #[arg(long)]
+    copy_art: bool,
+
+    /// Scale copied cover images down to at most this many pixels, writing them as JPEGs.
+    #[arg(long, requires = "copy_art", value_name = "PIXELS")]
+    copy_art_size: Option<u32>,
+
+    /// Copy files with these extensions next to the sources, e.g. `lrc` lyrics, next to the
+    /// output files under their names.
+    #[arg(long, value_delimiter = ',', value_name = "EXTENSIONS")]
+    copy_sidecars: Vec<String>,
+
+    /// Write a file that lists every output file with its source, action, a hash of the settings
+    /// and the time it was written. Written as CSV if the file name ends with `.csv`, and as JSON
+    /// otherwise.
+    #[arg(long, value_name = "PATH")]
+    mapping_file: Option<PathBuf>,
+
+    /// Copy the input playlists unchanged into `_original/` next to the output playlists.
+    #[arg(long)]
+    keep_original_playlist: bool,
+
+    /// Line endings of the output playlists.
+    #[arg(long, value_enum, default_value_t = LineEnding::Lf)]
+    playlist_line_endings: LineEnding,
+
+    /// Text encoding of the output playlists.
+    #[arg(long, value_enum, default_value_t = PlaylistEncoding::Utf8)]
+    playlist_encoding: PlaylistEncoding,
+
+    /// Add new entries to the end of existing output playlists instead of replacing them.
+    ///
+    /// Existing entries keep their order and are not listed twice. Existing playlists that are
+    /// corrupt or in another encoding than `--playlist-encoding` are left unchanged.
+    #[arg(long)]
+    append: bool,
+
+    /// Maximum width and height of album art in pixels.
+    #[arg(long, default_value_t = 500)]
+    art_size: u32,
+
+    /// Do not copy the modification times of the sources onto the output files.
+    #[arg(long)]
+    no_preserve_times: bool,
+
+    /// Truncate the title, artist and album of output files to this many characters.
+    ///
+    /// Applies to copied files only with `--fix-tags`.
+    #[arg(long)]
+    max_tag_len: Option<usize>,
+
+    /// Romanize non-Latin text like Cyrillic or Japanese in the title, artist and album of output
+    /// files, which Sync 2 renders as boxes.
+    ///
+    /// Independent of `--transliterate`, which only applies to output paths.
+    #[arg(long)]
+    transliterate_tags: bool,
+
+    /// Whether `--transliterate-tags` replaces the text (keeping the original in a `TXXX` frame)
+    /// or appends the original in brackets.
+    #[arg(long, value_enum, default_value_t, requires = "transliterate_tags")]
+    transliterate_tags_style: TagTransliteration,
+
+    /// Text encoding of the tags that are written, i.e. of converted files and of copies whose
+    /// tags are fixed or overridden.
+    ///
+    /// With `latin1`, frames that Latin-1 cannot represent are written as UTF-16.
+    #[arg(long, value_enum)]
+    id3_encoding: Option<TextEncoding>,
+
+    /// Attach a cover image next to the source to converted files without embedded art.
+    #[arg(long)]
+    embed_art: bool,
+
+    /// Also attach cover images to copied MP3s without embedded art.
+    #[arg(long, requires = "embed_art")]
+    embed_art_copies: bool,
+
+    /// Names of cover images next to the sources (comma-separated, in order of preference).
+    #[arg(long, value_delimiter = ',', default_values_t = art::SIDECAR_NAMES.map(String::from))]
+    cover_names: Vec<String>,
+
+    /// Replace existing folder.jpg files, cover images and sidecars in the output.
+    #[arg(long)]
+    force: bool,
+
+    /// Stop writing after this many consecutive failures to write to the output directory, e.g.
+    /// because it became read-only (0 to never stop).
+    #[arg(long, default_value_t = 3)]
+    max_write_errors: usize,
+
+    /// Number of times a transient error writing an output file, like an I/O error or timeout of
+    /// a USB stick, is retried with exponential backoff (0 to never retry).
+    ///
+    /// Errors that do not go away by waiting, like missing permissions, are not retried. When
+    /// several files fail at the same time, all writes are paused briefly.
+    #[arg(long, default_value_t = 3)]
+    io_retries: usize,
+
+    /// Only print what would be done, without writing anything.
+    #[arg(short = 'n', long)]
+    dry_run: bool,
+
+    /// Run this shell command after each output file was converted or copied successfully.
+    ///
+    /// The output and source paths are passed as `$1` and `$2` and in the `FORD_SYNC_OUTPUT` and
+    /// `FORD_SYNC_SOURCE` environment variables. If the command fails, the file counts as failed.
+    #[arg(long, value_name = "CMD")]
+    post_hook: Option<String>,
+
+    /// Kill hook commands that take longer than this many seconds.
+    #[arg(long, value_name = "SECONDS", default_value_t = 300)]
+    hook_timeout: u64,
+
+    /// Run this shell command before planning, e.g. to mount the output medium.
+    ///
+    /// The run is aborted if the command fails. Not run with `--dry-run`.
+    #[arg(long, value_name = "CMD")]
+    pre_run: Option<String>,
+
+    /// Run this shell command at the very end, even if the run failed, e.g. to unmount the output
+    /// medium.
+    ///
+    /// The exit status of the run is passed in the `FORD_SYNC_EXIT_STATUS` environment variable.
+    /// Not run with `--dry-run`.
+    #[arg(long, value_name = "CMD")]
+    post_run: Option<String>,
+
+    /// Remove the files that earlier syncs created in the output directory but that the
+    /// playlists no longer need, e.g. after renaming a playlist or removing an album from it.
+    ///
+    /// The files are taken from the manifest of the output directory, so other files are left
+    /// alone. Album art and sidecars next to the synced files are kept. Directories that become
+    /// empty are removed as well, along with junk like `Thumbs.db` and `.DS_Store` in them. The
+    /// re-syncs of `--watch` prune against all playlists, not only the changed ones.
+    #[arg(long, conflicts_with = "emit_script")]
+    prune: bool,
+
+    /// Go ahead with destructive steps, like overwriting many existing files and `--prune`,
+    /// without asking.
+    ///
+    /// Without this flag, such steps need to be confirmed on the terminal and are skipped when
+    /// not running interactively.
+    #[arg(short, long)]
+    yes: bool,
+
+    /// Write a script with the commands to create the output instead of executing them.
+    ///
+    /// Planning happens as usual and the playlists are written, but no audio files. Tag fixes,
+    /// art for copied files and output verification are not part of the script.
+    #[arg(long, value_name = "PATH", conflicts_with_all = ["dry_run", "watch"])]
+    emit_script: Option<PathBuf>,
+
+    /// Kind of script to write with `--emit-script`.
+    #[arg(long, value_enum, value_name = "FORMAT", default_value_t = ScriptFormat::Bash)]
+    script_format: ScriptFormat,
+
+    /// Write a JSON report of the run to this file (`-` for stdout).
+    #[arg(long, value_name = "PATH")]
+    report_json: Option<PathBuf>,
+
+    /// Write a self-contained HTML report of the run to this file (`-` for stdout).
+    #[arg(long, value_name = "PATH")]
+    report_html: Option<PathBuf>,
+
+    /// Treat warnings as failures, either aborting at the first one or collecting all of them.
+    #[arg(long, value_enum, num_args = 0..=1, require_equals = true, default_missing_value = "collect")]
+    strict: Option<StrictMode>,
+
+    /// Only show warnings and errors (and the final summary).
+    #[arg(short, long, conflicts_with = "verbose")]
+    quiet: bool,
+
+    /// Show debug messages, or trace messages including FFmpeg command lines if given twice.
+    ///
+    /// `RUST_LOG` takes precedence if it is set.
+    #[arg(short, long, action = ArgAction::Count)]
+    verbose: u8,
+
+    /// Also write all log messages up to debug level to this file.
+    #[arg(long, value_name = "PATH")]
+    log_file: Option<PathBuf>,
+
+    /// Whether to append to the log file, overwrite it, or write a new one with a timestamped
+    /// name per run.
+    #[arg(long, value_enum, default_value_t)]
+    log_file_mode: LogFileMode,
+
+    /// Write log messages as human-readable text or as one JSON object per line, which includes
+    /// the playlist and the source and destination of the task a message belongs to.
+    #[arg(long, value_enum, default_value_t)]
+    log_format: LogFormat,
+
+    /// Read options from this TOML file instead of `~/.config/ford-sync-convert/config.toml`.
+    ///
+    /// Keys are the names of the options, e.g. `output_dir = "/media/usb"`. Options given on the
+    /// command line take precedence. A `[rules]` section sets the encoder settings per source
+    /// extension or codec, e.g. `flac = { quality = 0 }` or `opus = { bitrate = 64, channels = 1 }`.
+    #[arg(long, value_name = "PATH")]
+    config: Option<PathBuf>,
+
+    /// Set the defaults for a head unit, e.g. its indexing limits, tag version, bitrate and
+    /// playlist format.
+    ///
+    /// Options given on the command line, in the environment or in the configuration file take
+    /// precedence. `--print-config` shows which values come from the preset.
+    #[arg(long, value_enum)]
+    preset: Option<Preset>,
+
+    /// Encoder settings from the `[rules]` section of the configuration file.
+    #[arg(skip)]
+    rules: Rules,
+
+    /// Print the effective configuration as TOML and exit.
+    #[arg(long)]
+    print_config: bool,
+
+    /// Playlist files, or directories to sync all audio files of
     #[arg(num_args=1..)]
     playlists: Vec<PathBuf>,
+
+    /// For directories given instead of playlists, also create a playlist per folder, named from
+    /// its path like `Artist - Album`.
+    ///
+    /// `leaf` creates playlists for folders without subfolders with audio files, `all` for every
+    /// folder with everything below it.
+    #[arg(
+        long,
+        value_enum,
+        value_name = "FOLDERS",
+        num_args = 0..=1,
+        require_equals = true,
+        default_missing_value = "leaf"
+    )]
+    per_folder_playlists: Option<FolderPlaylists>,
+
+    /// Music library to fill the playlists of `--smart-playlists` from.
+    #[arg(long, value_name = "DIR", requires = "smart_playlists")]
+    library: Option<PathBuf>,
+
+    /// Generate playlists from the tracks of `--library` that match the rules in this file.
+    ///
+    /// The file is TOML (or JSON with the extension `.json`) with a `[[playlist]]` table per
+    /// playlist, e.g. `name = "90s Rock"`, `all = ["genre contains rock", "year >= 1990"]`,
+    /// `limit = 200` and `order = "random"`. The generated playlists are synced like the others.
+    #[arg(long, value_name = "FILE", requires = "library")]
+    smart_playlists: Option<PathBuf>,
+
+    /// Also sync the stored playlists of the MPD server at `HOST[:PORT]`, given with
+    /// `--mpd-playlist` or `--mpd-all`.
+    ///
+    /// The songs are read from `--mpd-music-dir`, and the output playlists are named after the
+    /// MPD playlists. Songs that do not exist there, e.g. streams, are skipped.
+    #[arg(long, value_name = "HOST:PORT", requires = "mpd_music_dir")]
+    mpd: Option<MpdServer>,
+
+    /// Name of a stored MPD playlist to sync. Can be given several times.
+    #[arg(
+        long,
+        value_name = "NAME",
+        requires = "mpd",
+        conflicts_with = "mpd_all"
+    )]
+    mpd_playlist: Vec<String>,
+
+    /// Sync all stored playlists of the MPD server.
+    #[arg(long, requires = "mpd")]
+    mpd_all: bool,
+
+    /// Password to authenticate with at the MPD server.
+    #[arg(long, value_name = "PASSWORD", requires = "mpd")]
+    mpd_password: Option<String>,
+
+    /// Local path of the music directory of the MPD server, which song URIs are relative to.
+    #[arg(long, value_name = "DIR", requires = "mpd")]
+    mpd_music_dir: Option<PathBuf>,
+
+    /// Also sync playlists from the Jellyfin server at this URL, e.g. `http://nas:8096`, given
+    /// with `--jellyfin-playlist`.
+    ///
+    /// The server is queried with `curl`. The file paths of the playlist items are mapped to
+    /// local paths with `--map`, and the output playlists are named after the server playlists.
+    #[arg(long, value_name = "URL", requires_all = ["jellyfin_token", "jellyfin_playlist"])]
+    jellyfin: Option<String>,
+
+    /// API key to access the Jellyfin server with.
+    #[arg(long, value_name = "TOKEN", requires = "jellyfin")]
+    jellyfin_token: Option<String>,
+
+    /// Name of a Jellyfin playlist to sync. Can be given several times.
+    #[arg(long, value_name = "NAME", requires = "jellyfin")]
+    jellyfin_playlist: Vec<String>,
+
+    /// Also sync playlists from the Plex server at this URL, e.g. `http://nas:32400`, given with
+    /// `--plex-playlist`. Works like `--jellyfin`.
+    #[arg(long, value_name = "URL", requires_all = ["plex_token", "plex_playlist"])]
+    plex: Option<String>,
+
+    /// Token to access the Plex server with.
+    #[arg(long, value_name = "TOKEN", requires = "plex")]
+    plex_token: Option<String>,
+
+    /// Name of a Plex playlist to sync. Can be given several times.
+    #[arg(long, value_name = "NAME", requires = "plex")]
+    plex_playlist: Vec<String>,
+
+    /// Map file paths of Jellyfin and Plex servers that start with `FROM` to the local directory
+    /// `TO`, given as `FROM=TO`, e.g. `/srv/media=/mnt/nas/media`. Can be given several times;
+    /// the longest matching prefix is used.
+    #[arg(long = "map", value_name = "FROM=TO")]
+    path_map: Vec<PathMapping>,
+
+    /// Read additional playlist paths from a file, one per line (`#` starts a comment).
+    ///
+    /// Relative paths are resolved against the directory of the file. The playlists follow those
+    /// given on the command line, in the order they are listed.
+    #[arg(long, value_name = "FILE")]
+    playlists_from: Vec<PathBuf>,
+
+    /// After the initial sync, keep running and re-sync playlists whenever they change.
+    ///
+    /// Every re-sync plans all playlists, so that aggregate outputs like genre playlists, the
+    /// mapping file and the size budgets stay complete, but only converts and copies the sources
+    /// that are new or changed since the previous sync (like `--since`). Press Ctrl-C to stop.
+    #[arg(long)]
+    watch: bool,
+
+    /// Show the file each worker is on, the overall progress and the warnings and failures on a
+    /// full-screen interface.
+    ///
+    /// While running, `p` pauses starting new files, `s` skips the selected conversion and `q`
+    /// stops after cleaning up. The summary is printed when the screen closes. Terminals that
+    /// cannot show the screen get the normal log instead.
+    #[cfg(feature = "tui")]
+    #[arg(long, conflicts_with_all = ["watch", "emit_script"])]
+    tui: bool,
+
+    /// With `--watch`, also treat changes to the directories of the playlists as changes.
+    #[arg(long, requires = "watch")]
+    watch_dirs: bool,
 }
 
-fn main() {
-    if std::env::var_os("RUST_LOG").is_none() {
-        // Set `RUST_LOG=myapp=debug` to see debug logs, this only shows info logs.
-        std::env::set_var("RUST_LOG", "info");
-    }
-    pretty_env_logger::init();
-
-    let args = Cli::parse();
-
-    std::fs::create_dir_all(&args.output_dir).unwrap();
-
-    let mut files_to_copy = vec![];
-    let mut files_to_convert = vec![];
-    for input_playlist_path in args.playlists.iter() {
-        info!("Parsing Playlist: {}", input_playlist_path.display());
-        let mut reader = m3u::Reader::open(input_playlist_path).unwrap();
-
-        let output_playlist_filename = input_playlist_path.file_name().unwrap();
-        let output_playlist_path = args.output_dir.as_path().join(output_playlist_filename);
-        let mut output_playlist_file = std::fs::File::create(&output_playlist_path).unwrap();
-        let mut writer = m3u::Writer::new(&mut output_playlist_file);
-
-        let input_playlist_dir = input_playlist_path
-            .parent()
-            .map(|parent| {
-                if parent == Path::new("") {
-                    PathBuf::from(".")
-                } else {
-                    parent.to_path_buf()
-                }
-            })
-            .unwrap();
-        for input_audio_path in reader
-            .entries()
-            .filter_map(|res| match res {
-                Ok(entry) => Some(entry),
-                Err(e) => {
-                    warn!("Failed to read playlist entry: {}", e);
-                    None
-                }
-            })
-            .filter_map(|entry| match entry {
-                m3u::Entry::Path(path) => Some(path),
-                m3u::Entry::Url(url) => {
-                    warn!("Ignoring URL: {}", url);
-                    None
-                }
-            })
-        {
-            let extension = match input_audio_path.extension() {
-                Some(ext) => ext,
-                None => {
-                    warn!("{}: Failed to determine file extension", input_audio_path.display());
-                    continue
-                },
-            };
-            let output_audio_path = if extension == "mp3" {
-                files_to_copy.push((
-                    input_playlist_dir.join(&input_audio_path),
-                    args.output_dir.join(&input_audio_path),
-                ));
-                input_audio_path
+/// Arguments that are not stored in plan files.
+const UNPLANNED_ARGS: [&str; 7] = [
+    "emit_script",
+    "plan_file",
+    "playlists",
+    "playlists_from",
+    "watch",
+    "watch_dirs",
+    "yes",
+];
+
+/// Number of existing output files from which overwriting them needs to be confirmed.
+const CONFIRM_OVERWRITE_MIN: usize = 100;
+
+/// Ask before overwriting many existing output files.
+///
+/// Returns whether existing outputs should be kept, or the exit status if the user declined.
+fn confirm_overwrite(
+    plan: &Plan,
+    assume_yes: bool,
+    since: Option<SystemTime>,
+) -> Result<bool, i32> {
+    // Outputs that `--since` leaves alone are not overwritten.
+    let existing: Vec<u64> = plan
+        .tasks
+        .iter()
+        .filter(|task| !since.is_some_and(|cutoff| since::is_unchanged(task, cutoff)))
+        .filter_map(|task| std::fs::metadata(longpath::extended(&task.destination)).ok())
+        .map(|metadata| metadata.len())
+        .collect();
+    if existing.len() < CONFIRM_OVERWRITE_MIN {
+        return Ok(false);
+    }
+    let question = format!(
+        "About to overwrite {} existing files totaling {}, continue?",
+        existing.len(),
+        ByteSize(existing.iter().sum())
+    );
+    let skipped = format!("Not overwriting {} existing files", existing.len());
+    confirm_step(assume_yes, &question, &skipped).map(|overwrite| !overwrite)
+}
+
+/// Ask before a destructive step of a sync, unless `--yes` was given.
+///
+/// Returns whether to go ahead, or the exit status if the user declined. When not running
+/// interactively, the step is skipped and `skipped` says what was left undone.
+fn confirm_step(assume_yes: bool, question: &str, skipped: &str) -> Result<bool, i32> {
+    if assume_yes {
+        return Ok(true);
+    }
+    match confirm::ask(question) {
+        confirm::Answer::Yes => Ok(true),
+        confirm::Answer::No => {
+            error!("Aborted, nothing was written");
+            Err(exit::INTERRUPTED)
+        }
+        confirm::Answer::NotInteractive => {
+            warn!("{} without `--yes` (not running interactively)", skipped);
+            Ok(false)
+        }
+    }
+}
+
+/// Compare the size estimate against the free space of the output filesystem.
+///
+/// Returns `false` if the run should be aborted.
+fn check_space(output_dir: &Path, estimate: &SizeEstimate, ignore_space: bool) -> bool {
+    match estimate::available_space(output_dir) {
+        Ok(available) if available < estimate.total() => {
+            let message = format!(
+                "Estimated output size {} exceeds free space {} on {}",
+                ByteSize(estimate.total()),
+                ByteSize(available),
+                output_dir.display()
+            );
+            if ignore_space {
+                report_warning!(WarningCategory::Space, "{}", message);
+                true
             } else {
-                let new_audio_path = input_audio_path.with_extension("mp3");
-                files_to_convert.push((
-                    input_playlist_dir.join(&input_audio_path),
-                    args.output_dir.join(&new_audio_path),
-                ));
-                new_audio_path
+                error!("{} (use `--ignore-space` to continue anyway)", message);
+                false
+            }
+        }
+        Ok(_) => true,
+        Err(e) => {
+            report_warning!(
+                WarningCategory::Space,
+                "{}: Failed to determine free space ({})",
+                output_dir.display(),
+                e
+            );
+            true
+        }
+    }
+}
+
+/// Log the empty or corrupt source files that were skipped and return their number.
+fn report_corrupt_sources(plan: &Plan) -> usize {
+    let corrupt: BTreeSet<_> = plan
+        .playlists
+        .iter()
+        .flat_map(|playlist| playlist.skipped.iter())
+        .filter(|skipped| skipped.reason.is_corrupt_source())
+        .map(|skipped| (skipped.entry.as_str(), skipped.reason.to_string()))
+        .collect();
+    if !corrupt.is_empty() {
+        warn!("Skipped {} corrupt or empty sources:", corrupt.len());
+        for (entry, reason) in corrupt.iter() {
+            warn!("  {}: {}", entry, reason);
+        }
+    }
+    corrupt.len()
+}
+
+/// Print how long each phase of the run took.
+fn print_timings(timings: &[Timing], sync_writes: bool) {
+    info!("Timings:");
+    for timing in timings {
+        info!(
+            "  {}: {:.2?}",
+            timing.phase,
+            Duration::from_secs_f64(timing.seconds)
+        );
+    }
+    if sync_writes {
+        info!(
+            "  Time spent flushing to disk (`--sync-writes`): {:.2?}",
+            atomic::sync_time()
+        );
+    }
+}
+
+/// Compare the output against the indexing limits of the head unit.
+///
+/// Returns `false` if the run should be aborted.
+fn check_limits(counts: &OutputCounts, args: &SyncArgs) -> bool {
+    let limits = IndexLimits {
+        max_files: args.max_files,
+        max_folders: args.max_folders,
+        max_playlists: args.max_playlists,
+    };
+    let violations = counts.violations(&limits);
+    for violation in violations.iter() {
+        if args.enforce_limits {
+            error!("{}", violation);
+        } else {
+            report_warning!(
+                WarningCategory::IndexLimits,
+                "{} (Sync 2 may not index all of it)",
+                violation
+            );
+        }
+    }
+    !args.enforce_limits || violations.is_empty()
+}
+
+/// Print what a run would do.
+fn print_dry_run(plan: &Plan, counts: &OutputCounts, estimate: &SizeEstimate) {
+    println!(
+        "Would copy {} files and convert {} files.",
+        plan.tasks(Action::Copy).count(),
+        plan.tasks(Action::Convert).count()
+    );
+    let num_remux = plan.tasks(Action::Remux).count();
+    if num_remux > 0 {
+        println!("Would remux {} files.", num_remux);
+    }
+    for task in plan.tasks.iter() {
+        let verb = match task.action {
+            Action::Copy => "copy",
+            Action::Convert => "convert",
+            Action::Remux => "remux",
+        };
+        println!(
+            "  {} {} -> {}",
+            verb,
+            task.source.display(),
+            task.destination.display()
+        );
+    }
+    println!("Output: {}.", counts);
+    for duplicate in plan.duplicates.iter() {
+        println!(
+            "  duplicate {} -> {} ({})",
+            duplicate.dropped.display(),
+            duplicate.kept.display(),
+            duplicate.key
+        );
+    }
+    for playlist in plan.playlists.iter() {
+        println!(
+            "Would write playlist {} ({} entries) from {}.",
+            playlist.path.display(),
+            playlist.entries.len(),
+            playlist.source.display()
+        );
+        for skipped in playlist.skipped.iter() {
+            println!("  skip {}: {}", skipped.entry, skipped.reason);
+        }
+    }
+    println!(
+        "Estimated output size: {} ({} copied, {} converted)",
+        ByteSize(estimate.total()),
+        ByteSize(estimate.copy_bytes),
+        ByteSize(estimate.convert_bytes)
+    );
+}
+
+/// Print the genres of the sources and what they are mapped to.
+fn print_genre_report(plan: &Plan) {
+    println!("Genres:");
+    for usage in genre::usage(plan) {
+        match &usage.mapped {
+            Some(mapped) => println!("  {} -> {} ({} files)", usage.genre, mapped, usage.files),
+            None => println!("  {} (unchanged, {} files)", usage.genre, usage.files),
+        }
+    }
+}
+
+fn main() {
+    let parsed = parse_args();
+    let log_file = match &parsed {
+        Ok(parsed) => {
+            let args = parsed.command.sync_args();
+            logging::init(
+                args.quiet,
+                args.verbose,
+                args.log_file
+                    .as_deref()
+                    .map(|path| (path, args.log_file_mode)),
+                args.log_format,
+            )
+        }
+        Err(_) => logging::init(false, 0, None, LogFormat::Text),
+    };
+    let ParsedArgs {
+        command,
+        definition,
+        matches,
+        sources,
+        config_path,
+        config_error,
+        plan_file,
+    } = match parsed {
+        Ok(parsed) => parsed,
+        Err(e) => {
+            error!("{}", e);
+            std::process::exit(exit::USAGE);
+        }
+    };
+    let args = command.sync_args();
+    let log_file = match log_file {
+        Ok(log_file) => log_file,
+        Err(e) => {
+            error!(
+                "{}: Failed to open log file ({})",
+                args.log_file.as_deref().unwrap_or(Path::new("")).display(),
+                e
+            );
+            std::process::exit(exit::ENVIRONMENT);
+        }
+    };
+    if let Some(path) = &log_file {
+        info!(target: logging::SUMMARY_TARGET, "Logging to {}", path.display());
+    }
+    if let Some(path) = config_path.as_ref().filter(|_| config_error.is_none()) {
+        info!("Read configuration from {}", path.display());
+    }
+    let settings = config::effective(&definition, &matches, &sources);
+    if args.print_config {
+        print!("{}", config::render(&settings));
+        return;
+    }
+    interrupt::install();
+    if let Some(mode) = args.strict {
+        warnings::set_strict(mode);
+    }
+    let run_hooks = !args.dry_run && matches!(command, Commands::Convert(_) | Commands::Apply(_));
+    if let Some(pre_run) = args.pre_run.as_ref().filter(|_| run_hooks) {
+        let hook = Hook {
+            command: pre_run.clone(),
+            timeout: Duration::from_secs(args.hook_timeout),
+        };
+        info!("Running pre-run hook: {}", pre_run);
+        if let Err(e) = hook.run::<&str, &str>("pre-run", [], &[]) {
+            error!("Pre-run hook failed, aborting ({})", e);
+            std::process::exit(exit::ENVIRONMENT);
+        }
+    }
+    let status = match (&command, plan_file) {
+        (Commands::Doctor(_), _) => {
+            let config = match config_error {
+                Some(e) => Err(e),
+                None => Ok(config_path),
             };
+            let checks = doctor::run(&args.output_dir, args.target_fs, config);
+            for check in &checks {
+                println!("{}", check);
+            }
+            doctor::exit_status(&checks)
+        }
+        (Commands::Plan(plan_args), _) => write_plan(plan_args, &settings),
+        (Commands::Verify(verify_args), _) => verify_output(verify_args),
+        (Commands::Diff(diff_args), _) => diff_output(diff_args),
+        (Commands::Estimate(estimate_args), _) => print_estimate(estimate_args),
+        (Commands::Clean(clean_args), _) => clean_output(clean_args),
+        (Commands::Apply(apply_args), Some(plan_file)) => {
+            apply(apply_args, plan_file, report::settings(&settings))
+        }
+        _ if args.watch => watch(args, report::settings(&settings)),
+        _ => run(args, report::settings(&settings)),
+    };
+    let mut status = warnings::finish(status);
+    if let Some(post_run) = args.post_run.as_ref().filter(|_| run_hooks) {
+        let hook = Hook {
+            command: post_run.clone(),
+            timeout: Duration::from_secs(args.hook_timeout),
+        };
+        info!("Running post-run hook: {}", post_run);
+        if let Err(e) = hook.run(
+            "post-run",
+            [("FORD_SYNC_EXIT_STATUS", status.to_string())],
+            &[],
+        ) {
+            report_warning!(WarningCategory::Hook, "Post-run hook failed ({})", e);
+            if status == exit::SUCCESS {
+                status = exit::ENVIRONMENT;
+            }
+        }
+    }
+    if let Some(path) = log_file.filter(|_| logging::problems_logged()) {
+        info!(
+            target: logging::SUMMARY_TARGET,
+            "Warnings or errors occurred, see {} for details.",
+            path.display()
+        );
+    }
+    std::process::exit(status);
+}
+
+/// Command line arguments merged with the environment and the configuration file.
+struct ParsedArgs {
+    command: Commands,
+    /// Definition of the (sub)command's arguments.
+    definition: Command,
+    /// Matches of the (sub)command's arguments.
+    matches: ArgMatches,
+    /// Sources of the options that were not set on the command line.
+    sources: HashMap<String, Source>,
+    /// Path of the configuration file that was read, if any.
+    config_path: Option<PathBuf>,
+    /// Why the configuration file is invalid, for `doctor`.
+    config_error: Option<String>,
+    /// The plan file given to `apply`.
+    plan_file: Option<PlanFile>,
+}
+
+/// Parse the command line arguments, merged with the environment, the plan file of `apply` and
+/// the configuration file.
+fn parse_args() -> Result<ParsedArgs, Box<dyn std::error::Error>> {
+    let argv: Vec<OsString> = std::env::args_os().collect();
+    let (program, cli_args) = argv.split_at(1);
+    let command = Cli::command();
+    let parsed = command.clone().get_matches_from(&argv);
+    // Options of a subcommand have to follow its name, so that they are not taken as options of
+    // the implicit `convert`.
+    let (subcommand, cli_args) = match parsed.subcommand_name() {
+        Some(_) => cli_args.split_at(1),
+        None => (&[][..], cli_args),
+    };
+    let definition = match parsed.subcommand_name() {
+        Some(name) => command.find_subcommand(name).unwrap().clone(),
+        None => command.clone(),
+    };
+    let merge = |before: &[OsString], after: &[OsString]| {
+        let merged: Vec<OsString> = [program, subcommand, before, cli_args, after].concat();
+        let matches = command.clone().get_matches_from(merged);
+        let sub_matches = match matches.subcommand() {
+            Some((_, sub_matches)) => sub_matches.clone(),
+            None => matches.clone(),
+        };
+        (matches, sub_matches)
+    };
+    let (_, matches) = merge(&[], &[]);
+    let env = config::env_args(&definition, &matches, |var| std::env::var_os(var))?;
+    let (_, matches) = merge(&env.options, &env.positionals);
+
+    let mut sources: HashMap<String, Source> = env
+        .ids
+        .iter()
+        .map(|id| (id.clone(), Source::Environment(config::env_var(id))))
+        .collect();
+    let plan_file = match matches.try_get_one::<PathBuf>("plan").ok().flatten() {
+        Some(path) => Some((PlanFile::read(path)?, path.clone())),
+        None => None,
+    };
+    let planned = match &plan_file {
+        Some((plan_file, path)) => {
+            let planned = config::to_args(&definition, &matches, path, &plan_file.settings)?;
+            sources.extend(
+                planned
+                    .ids
+                    .iter()
+                    .map(|id| (id.clone(), Source::PlanFile(path.clone()))),
+            );
+            planned
+        }
+        None => config::Args::default(),
+    };
+    let (_, matches) = merge(
+        &[&planned.options[..], &env.options].concat(),
+        &[&env.positionals[..], &planned.positionals].concat(),
+    );
+
+    let config_path = matches
+        .get_one::<PathBuf>("config")
+        .cloned()
+        .or_else(|| config::default_path().filter(|path| path.exists()));
+    let file = match &config_path {
+        Some(path) => config::load(path).and_then(|config| {
+            Ok((
+                config::to_args(&definition, &matches, path, &config)?,
+                config::rules(path, &config)?,
+            ))
+        }),
+        None => Ok(Default::default()),
+    };
+    let ((file, rules), config_error) = match file {
+        Ok(file) => (file, None),
+        // `doctor` reports an invalid configuration file as one of its checks.
+        Err(e) if parsed.subcommand_name() == Some("doctor") => {
+            (Default::default(), Some(e.to_string()))
+        }
+        Err(e) => return Err(e.into()),
+    };
+    if let Some(path) = &config_path {
+        sources.extend(
+            file.ids
+                .iter()
+                .map(|id| (id.clone(), Source::ConfigFile(path.clone()))),
+        );
+    }
+    let mut options = [file.options, planned.options, env.options].concat();
+    let mut positionals = [env.positionals, planned.positionals, file.positionals].concat();
+    let (mut all_matches, mut matches) = merge(&options, &positionals);
+    // The preset may be set in the configuration file, so its defaults are merged last.
+    if let Some(preset) = matches
+        .try_get_one::<Preset>("preset")
+        .ok()
+        .flatten()
+        .copied()
+    {
+        let preset_args = config::preset_args(&definition, &matches, &sources, preset);
+        sources.extend(
+            preset_args
+                .ids
+                .iter()
+                .map(|id| (id.clone(), Source::Preset(preset))),
+        );
+        options.splice(0..0, preset_args.options);
+        positionals.extend(preset_args.positionals);
+        (all_matches, matches) = merge(&options, &positionals);
+    }
+    let cli = Cli::from_arg_matches(&all_matches).unwrap_or_else(|e| e.exit());
+    let mut command = cli.command.unwrap_or(Commands::Convert(cli.sync));
+    command.sync_args_mut().rules = rules;
+    Ok(ParsedArgs {
+        command,
+        definition,
+        matches,
+        sources,
+        config_path,
+        config_error,
+        plan_file: plan_file.map(|(plan_file, _)| plan_file),
+    })
+}
+
+/// Write the `--report-json` and `--report-html` reports, if requested.
+fn write_report(args: &SyncArgs, report: &Report) {
+    if let Some(path) = &args.report_json {
+        log_report_written(path, report.write_json(path));
+    }
+    if let Some(path) = &args.report_html {
+        log_report_written(path, report.write_html(path));
+    }
+    if args.dry_run {
+        return;
+    }
+    // Reports and logs written into the output directory are removed by `clean` as well.
+    let paths = [&args.report_json, &args.report_html, &args.log_file]
+        .into_iter()
+        .flatten()
+        .map(PathBuf::as_path);
+    if let Err(e) = manifest::record(&args.output_dir, paths, args.sync_writes) {
+        report_warning!(
+            WarningCategory::Io,
+            "{}: Failed to update manifest ({})",
+            args.output_dir.display(),
+            e
+        );
+    }
+}
+
+/// Log the closing summary, which is shown even with `--quiet`.
+fn log_summary(args: &SyncArgs, report: &Report, elapsed: Duration) {
+    let details = [&args.report_html, &args.report_json, &args.log_file]
+        .into_iter()
+        .flatten()
+        .find(|path| path.as_path() != Path::new("-"))
+        .map(|path| path.display().to_string())
+        .unwrap_or_else(|| "the log above".to_string());
+    for line in report
+        .statistics()
+        .into_iter()
+        .chain(report.summary(elapsed, &details))
+    {
+        info!(target: logging::SUMMARY_TARGET, "{}", line);
+    }
+}
 
-            // Write windows path to file
-            let mut output_audio_path_windows =
-                output_audio_path.iter().fold(String::new(), |mut a, b| {
-                    let b_str = b.to_str().unwrap();
-                    a.reserve(b_str.len() + 1);
-                    a.push_str(b_str);
-                    a.push('\\');
-                    a
-                });
-            output_audio_path_windows.truncate(output_audio_path_windows.len() - 1);
-            writer
-                .write_entry(&m3u::path_entry(&output_audio_path_windows))
-                .unwrap();
-        }
-        writer.flush().unwrap();
-        info!("Wrote Playlist: {}", output_playlist_path.display());
-    }
-
-    info!("Files to copy: {}", files_to_copy.len());
-    info!("Files to convert: {}", files_to_convert.len());
-
-    let num_copy_tasks = files_to_copy.len();
-    let num_convert_tasks = files_to_convert.len();
-    let num_tasks_total = num_copy_tasks + num_convert_tasks;
-
-    info!("Starting convert files...");
-    use std::sync::mpsc::channel;
-    use threadpool::ThreadPool;
-
-    let n_workers = 4;
-    let pool = ThreadPool::new(n_workers);
-
-    let (tx, rx) = channel();
-    for (input_path, output_path) in files_to_convert.into_iter() {
-        let input_path_str = input_path.into_os_string().into_string().unwrap();
-        let output_path_str = output_path.clone().into_os_string().into_string().unwrap();
-        let output_dir = output_path.parent().unwrap();
-        std::fs::create_dir_all(output_dir).unwrap();
-
-        let tx = tx.clone();
-        pool.execute(move || {
-            let output = Command::new("ffmpeg")
-                .args([
-                    "-i",
-                    &input_path_str,
-                    "-y",
-                    "-vn",
-                    "-aq",
-                    "2",
-                    &output_path_str,
-                ])
-                .output();
-            tx.send((output_path, output))
-                .expect("channel will be there waiting for the pool");
+fn log_report_written(path: &Path, result: std::io::Result<()>) {
+    match result {
+        Ok(()) if path != Path::new("-") => info!("Wrote report: {}", path.display()),
+        Ok(()) => (),
+        Err(e) => report_warning!(
+            WarningCategory::Io,
+            "{}: Failed to write report ({})",
+            path.display(),
+            e
+        ),
+    }
+}
+
+/// Perform the sync and return the exit status.
+fn run(args: &SyncArgs, settings: BTreeMap<String, serde_json::Value>) -> i32 {
+    match args.split_targets.as_slice() {
+        [] => (),
+        [target] => {
+            let single = SyncArgs {
+                output_dir: target.dir.clone(),
+                max_total_size: Some(
+                    args.max_total_size
+                        .map_or(target.size, |size| size.min(target.size)),
+                ),
+                split_targets: vec![],
+                ..args.clone()
+            };
+            return run(&single, settings);
+        }
+        targets => return run_split(args, targets, settings),
+    }
+    if !args.variants.is_empty() {
+        return run_variants(args, &args.variants, settings);
+    }
+    if let Some(path) = &args.output_zip {
+        let staging = archive::staging_dir(path);
+        return run_staged(args, staging, false, settings, |staging| {
+            write_zip(staging, path)
+        });
+    }
+    if let (Some(path), Some(size)) = (&args.output_image, args.image_size) {
+        let staging = archive::staging_dir(path);
+        return run_staged(args, staging, false, settings, |staging| {
+            write_image(staging, path, size)
         });
     }
+    if let Some(target) = &args.output_sftp {
+        let options = SftpOptions {
+            jobs: args.sftp_jobs,
+            retries: args.retries,
+        };
+        let staging = upload::staging_dir(&format!("sftp://{}{}", target.host, target.path));
+        return run_staged(args, staging, true, settings, |staging| {
+            upload_to_sftp(staging, target, &options)
+        });
+    }
+    #[cfg(feature = "mtp")]
+    if let Some(target) = &args.output_mtp {
+        let staging = match mtp::staging_key(target) {
+            Ok(key) => upload::staging_dir(&key),
+            Err(e) => {
+                error!("{}", e);
+                return exit::ENVIRONMENT;
+            }
+        };
+        return run_staged(args, staging, true, settings, |staging| {
+            upload_to_mtp(staging, target)
+        });
+    }
+    let started = Instant::now();
+    let mut timings = vec![];
+    match build_plan(args, &mut timings) {
+        Ok(plan) => execute_plan(args, plan, settings, timings, started),
+        Err(status) => status,
+    }
+}
+
+/// Start the screen of `--tui`, if the terminal can show it.
+#[cfg(feature = "tui")]
+fn start_screen(args: &SyncArgs, options: &mut SyncOptions) -> Option<tui::Screen> {
+    if !args.tui {
+        return None;
+    }
+    if !tui::is_supported() {
+        info!("Not showing `--tui`, the terminal cannot show it");
+        return None;
+    }
+    match tui::Screen::start(&args.output_dir) {
+        Ok(screen) => {
+            options.observers.push(screen.observer());
+            Some(screen)
+        }
+        Err(e) => {
+            warn!(
+                "Failed to set up the terminal for `--tui`, logging instead ({})",
+                e
+            );
+            None
+        }
+    }
+}
 
-    for (i, (output_path, result)) in rx.iter().take(num_convert_tasks).enumerate() {
-        let index = i + 1;
-        match result {
-            Ok(output) => {
-                if output.status.success() {
-                    info!(
-                        "({}/{}) {}: Conversion succeeded.",
-                        index,
-                        num_tasks_total,
-                        output_path.display()
-                    );
-                } else {
-                    warn!(
-                        "({}/{}) {}: FFmpeg exited with non-zero status {}",
-                        index,
-                        num_tasks_total,
-                        output_path.display(),
-                        output.status
-                    );
+/// Returns the path of a report for target `number` of `--split-targets`, e.g. `report-2.json`.
+fn split_report_path(path: &Path, number: usize) -> PathBuf {
+    suffixed_report_path(path, &number.to_string())
+}
+
+/// Returns the path of a report with `suffix` appended to its name, e.g. `report-car.json`.
+fn suffixed_report_path(path: &Path, suffix: &str) -> PathBuf {
+    if path == Path::new("-") {
+        return path.to_path_buf();
+    }
+    let mut file_name = path.file_stem().unwrap_or_default().to_os_string();
+    file_name.push(format!("-{}", suffix));
+    if let Some(extension) = path.extension() {
+        file_name.push(".");
+        file_name.push(extension);
+    }
+    path.with_file_name(file_name)
+}
+
+/// Partition the plan across the targets, sync each of them and return the worst exit status.
+fn run_split(
+    args: &SyncArgs,
+    targets: &[SplitTarget],
+    settings: BTreeMap<String, serde_json::Value>,
+) -> i32 {
+    let started = Instant::now();
+    let mut timings = vec![];
+    // The plan is made for the first target and then moved to the others.
+    let planned = SyncArgs {
+        output_dir: targets[0].dir.clone(),
+        split_targets: vec![],
+        ..args.clone()
+    };
+    let plan = match build_plan(&planned, &mut timings) {
+        Ok(plan) => plan,
+        Err(status) => return status,
+    };
+    let estimate_options = sync_options(&planned).estimate;
+    let parts = split::split(&plan, &planned.output_dir, targets, |plan, task| {
+        estimate::estimate_task(plan, task, &estimate_options)
+    });
+    for part in parts.iter() {
+        let names: Vec<String> = part
+            .plan
+            .playlists
+            .iter()
+            .map(|playlist| {
+                playlist
+                    .path
+                    .file_stem()
+                    .unwrap_or_default()
+                    .to_string_lossy()
+                    .into_owned()
+            })
+            .collect();
+        info!(
+            target: logging::SUMMARY_TARGET,
+            "{}: {} playlists, {} files, {} of {} ({})",
+            part.target.dir.display(),
+            names.len(),
+            part.num_files(),
+            ByteSize(part.bytes),
+            ByteSize(part.target.size),
+            if names.is_empty() {
+                "nothing".to_string()
+            } else {
+                names.join(", ")
+            }
+        );
+    }
+
+    let mut status = exit::SUCCESS;
+    for (number, part) in parts.into_iter().enumerate() {
+        let target_args = SyncArgs {
+            output_dir: part.target.dir.clone(),
+            report_json: args
+                .report_json
+                .as_deref()
+                .map(|path| split_report_path(path, number + 1)),
+            report_html: args
+                .report_html
+                .as_deref()
+                .map(|path| split_report_path(path, number + 1)),
+            ..planned.clone()
+        };
+        info!(
+            "Syncing target {}: {}",
+            number + 1,
+            part.target.dir.display()
+        );
+        let target_status = execute_plan(
+            &target_args,
+            part.plan,
+            settings.clone(),
+            timings.clone(),
+            started,
+        );
+        status = status.max(target_status);
+        if target_status == exit::INTERRUPTED {
+            break;
+        }
+    }
+    status
+}
+
+/// Sync each of the variants from one plan and return the worst exit status.
+fn run_variants(
+    args: &SyncArgs,
+    variants: &[Variant],
+    settings: BTreeMap<String, serde_json::Value>,
+) -> i32 {
+    let started = Instant::now();
+    let mut timings = vec![];
+    let planned = SyncArgs {
+        output_dir: variants[0].dir.clone(),
+        variants: vec![],
+        ..args.clone()
+    };
+    let plan = match build_plan(&planned, &mut timings) {
+        Ok(plan) => plan,
+        Err(status) => return status,
+    };
+
+    let mut status = exit::SUCCESS;
+    let mut summaries = vec![];
+    for variant in variants {
+        let mut variant_plan = plan.clone();
+        variant.apply(&mut variant_plan, &planned.output_dir);
+        let suffixed = |path: &Option<PathBuf>| {
+            path.as_deref()
+                .map(|path| suffixed_report_path(path, &variant.name))
+        };
+        let variant_args = SyncArgs {
+            output_dir: variant.dir.clone(),
+            report_json: suffixed(&args.report_json),
+            report_html: suffixed(&args.report_html),
+            mapping_file: suffixed(&args.mapping_file),
+            ..planned.clone()
+        };
+        // The settings of a variant differ from those of the others, and so do its hashes.
+        let mut variant_settings = settings.clone();
+        variant_settings.insert("variant".to_string(), variant.name.clone().into());
+        info!(
+            "Syncing variant {}: {}",
+            variant.name,
+            variant.dir.display()
+        );
+        let (variant_status, counts) = execute_plan_counted(
+            &variant_args,
+            variant_plan,
+            variant_settings,
+            timings.clone(),
+            started,
+        );
+        summaries.push((variant, counts));
+        status = status.max(variant_status);
+        if variant_status == exit::INTERRUPTED {
+            break;
+        }
+    }
+
+    info!(target: logging::SUMMARY_TARGET, "Variants:");
+    for (variant, counts) in summaries {
+        info!(
+            target: logging::SUMMARY_TARGET,
+            "  {} ({}): {}",
+            variant.name,
+            variant.dir.display(),
+            counts.map_or_else(|| "not synced".to_string(), |counts| counts.to_string())
+        );
+    }
+    status
+}
+
+/// Perform the sync into the staging directory, pack it with `pack` and return the exit status.
+///
+/// `pack` logs why it failed and returns the exit status for that, which replaces that of the
+/// sync. The staging directory is removed afterwards unless `keep` is set, which is used for the
+/// staging directories of remote targets, see [`upload::staging_dir`].
+fn run_staged(
+    args: &SyncArgs,
+    staging: PathBuf,
+    keep: bool,
+    settings: BTreeMap<String, serde_json::Value>,
+    pack: impl FnOnce(&Path) -> i32,
+) -> i32 {
+    let mut staged = SyncArgs {
+        output_dir: staging.clone(),
+        output_zip: None,
+        output_image: None,
+        output_sftp: None,
+        #[cfg(feature = "mtp")]
+        output_mtp: None,
+        // The staging directory is not where the files end up.
+        target_fs: args
+            .target_fs
+            .or(args.output_image.is_some().then_some(TargetFs::Fat32)),
+        ..args.clone()
+    };
+    let synced = SystemTime::now();
+    let mut status = if keep {
+        // Sources that did not change since the last sync keep their staged output, like with
+        // `--since`, and the staged files are overwritten without asking.
+        staged.since = args.since.or_else(|| upload::last_staged(&staging));
+        staged.yes = true;
+        let started = Instant::now();
+        let mut timings = vec![];
+        match build_plan(&staged, &mut timings) {
+            Ok(plan) => {
+                if !args.dry_run {
+                    remove_stale_staged(&staging, &plan);
                 }
+                execute_plan(&staged, plan, settings, timings, started)
             }
-            Err(e) => {
-                warn!(
-                    "({}/{}) {}: Failed to execute FFmpeg ({})",
-                    index,
-                    num_tasks_total,
-                    output_path.display(),
+            Err(status) => status,
+        }
+    } else {
+        run(&staged, settings)
+    };
+    if !args.dry_run && matches!(status, exit::SUCCESS | exit::FAILURES) {
+        if keep {
+            if let Err(e) = upload::record_staged(&staging, synced) {
+                report_warning!(
+                    WarningCategory::Io,
+                    "{}: Failed to record sync into staging directory ({})",
+                    staging.display(),
                     e
                 );
             }
         }
+        match pack(&staging) {
+            exit::SUCCESS => (),
+            pack_status => status = pack_status,
+        }
+    }
+    if keep {
+        return status;
+    }
+    match std::fs::remove_dir_all(&staging) {
+        Ok(()) => (),
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => (),
+        Err(e) => report_warning!(
+            WarningCategory::Io,
+            "{}: Failed to remove staging directory ({})",
+            staging.display(),
+            e
+        ),
+    }
+    status
+}
+
+/// Remove the staged files of earlier runs that the plan does not produce any more.
+fn remove_stale_staged(staging: &Path, plan: &Plan) {
+    let keep: HashSet<PathBuf> = plan
+        .tasks
+        .iter()
+        .map(|task| task.destination.clone())
+        .collect();
+    match upload::remove_stale(staging, &keep) {
+        Ok(0) => (),
+        Ok(removed) => debug!(
+            "{}: Removed {} stale files from staging directory",
+            staging.display(),
+            removed
+        ),
+        Err(e) => report_warning!(
+            WarningCategory::Io,
+            "{}: Failed to remove stale files from staging directory ({})",
+            staging.display(),
+            e
+        ),
+    }
+}
+
+/// Pack the staged output into a ZIP archive and return the exit status.
+fn write_zip(staging: &Path, path: &Path) -> i32 {
+    info!("Writing archive: {}", path.display());
+    match archive::write_dir(staging, path) {
+        Ok(size) => {
+            info!(
+                target: logging::SUMMARY_TARGET,
+                "Wrote archive: {} ({})",
+                path.display(),
+                ByteSize(size)
+            );
+            exit::SUCCESS
+        }
+        Err(e) => {
+            error!("{}: Failed to write archive ({})", path.display(), e);
+            exit::ENVIRONMENT
+        }
+    }
+}
+
+/// Copy the staged output into a FAT32 image and return the exit status.
+fn write_image(staging: &Path, path: &Path, size: u64) -> i32 {
+    info!("Writing image: {}", path.display());
+    let summary = match image::write_image(staging, path, size) {
+        Ok(summary) => summary,
+        Err(e) => {
+            if let image::ImageError::NoSpace { missing, .. } = &e {
+                for file in missing {
+                    error!("{}: Does not fit into the image", file.display());
+                }
+            }
+            error!("{}: Failed to write image ({})", path.display(), e);
+            return exit::ENVIRONMENT;
+        }
+    };
+    match image::list_root(path) {
+        Ok(names) => {
+            info!(target: logging::SUMMARY_TARGET, "Root directory of the image:");
+            for name in names {
+                info!(target: logging::SUMMARY_TARGET, "  {}", name);
+            }
+        }
+        Err(e) => {
+            error!("{}: Failed to read back image ({})", path.display(), e);
+            return exit::ENVIRONMENT;
+        }
     }
+    info!(
+        target: logging::SUMMARY_TARGET,
+        "Wrote image: {} ({}, {} free)",
+        path.display(),
+        ByteSize(summary.size),
+        ByteSize(summary.free)
+    );
+    exit::SUCCESS
+}
 
-    info!("Starting to copy files...");
-    for (i, (input_path, output_path)) in files_to_copy.into_iter().enumerate() {
-        let index = i + num_convert_tasks + 1;
-        let output_dir = output_path.parent().unwrap();
-        std::fs::create_dir_all(output_dir).unwrap();
-        let result = std::fs::copy(input_path, &output_path);
-        match result {
-            Ok(_) => {
+/// Upload the staged output over SFTP and return the exit status.
+fn upload_to_sftp(staging: &Path, target: &SftpTarget, options: &SftpOptions) -> i32 {
+    match sftp::upload(staging, target, options) {
+        Ok(summary) => {
+            info!(target: logging::SUMMARY_TARGET, "SFTP upload: {}", summary);
+            if summary.failed > 0 {
+                exit::FAILURES
+            } else {
+                exit::SUCCESS
+            }
+        }
+        Err(e) => {
+            error!("{}:{}: {}", target.host, target.path, e);
+            exit::ENVIRONMENT
+        }
+    }
+}
+
+/// Upload the staged output to an MTP device and return the exit status.
+#[cfg(feature = "mtp")]
+fn upload_to_mtp(staging: &Path, target: &mtp::MtpTarget) -> i32 {
+    match mtp::upload(staging, target) {
+        Ok(summary) => {
+            info!(
+                target: logging::SUMMARY_TARGET,
+                "MTP upload: {}",
+                summary
+            );
+            if summary.failed > 0 {
+                exit::FAILURES
+            } else {
+                exit::SUCCESS
+            }
+        }
+        Err(e) => {
+            error!("{}", e);
+            exit::ENVIRONMENT
+        }
+    }
+}
+
+/// Sync once, then re-sync the playlists that change until interrupted, and return the exit
+/// status of the last run.
+fn watch(args: &SyncArgs, settings: BTreeMap<String, serde_json::Value>) -> i32 {
+    let playlists = match playlist_paths(args) {
+        Ok(playlists) => playlists,
+        Err(status) => return status,
+    };
+    let mut watcher = Watcher::new(&playlists, args.watch_dirs);
+    let mut started = SystemTime::now();
+    let mut status = run(args, settings.clone());
+    while !interrupt::is_interrupted() {
+        if status != exit::SUCCESS {
+            warn!("Sync finished with exit status {}, watching anyway", status);
+        }
+        info!(
+            target: logging::SUMMARY_TARGET,
+            "Watching {} playlists for changes (press Ctrl-C to stop)...",
+            watcher.len()
+        );
+        let Some(changed) = watcher.wait() else {
+            break;
+        };
+        for path in &changed {
+            info!(target: logging::SUMMARY_TARGET, "{}: Playlist changed", path.display());
+        }
+        // All playlists are planned again, but outputs of sources that did not change since the
+        // previous sync are kept. With `--since-mode playlists-too`, a later cutoff would also
+        // leave entries out of the playlists.
+        let since = match args.since_mode {
+            SinceMode::PlanOnly => Some(args.since.map_or(started, |since| since.max(started))),
+            SinceMode::PlaylistsToo => args.since,
+        };
+        let cycle_args = SyncArgs {
+            since,
+            ..args.clone()
+        };
+        started = SystemTime::now();
+        status = run(&cycle_args, settings.clone());
+    }
+    status
+}
+
+/// Compute the plan and write it to the plan file, and return the exit status.
+fn write_plan(args: &PlanArgs, settings: &[(String, toml::Value, Source)]) -> i32 {
+    let plan = match build_plan(&args.sync, &mut vec![]) {
+        Ok(plan) => plan,
+        Err(status) => return status,
+    };
+    let settings = settings
+        .iter()
+        .filter(|(key, _, source)| {
+            *source != Source::Default && !UNPLANNED_ARGS.contains(&key.as_str())
+        })
+        .map(|(key, value, _)| (key.clone(), value.clone()))
+        .collect();
+    let plan_file = PlanFile::new(plan, &args.sync.output_dir, settings);
+    match plan_file.write(&args.plan_file) {
+        Ok(()) => {
+            if args.plan_file != Path::new("-") {
                 info!(
-                    "({}/{}) {}: Copying succeeded.",
-                    index,
-                    num_tasks_total,
-                    output_path.display()
+                    target: logging::SUMMARY_TARGET,
+                    "Wrote plan: {}",
+                    args.plan_file.display()
                 );
             }
+            exit::SUCCESS
+        }
+        Err(e) => {
+            error!("{}: Failed to write plan ({})", args.plan_file.display(), e);
+            exit::ENVIRONMENT
+        }
+    }
+}
+
+/// Verify an already synced output directory and return the exit status.
+fn verify_output(args: &VerifyArgs) -> i32 {
+    let plan = match build_plan(&args.sync, &mut vec![]) {
+        Ok(plan) => plan,
+        Err(status) => return status,
+    };
+    let options = verify::VerifyOptions {
+        deep: args.deep,
+        tolerance: args.sync.verify_tolerance,
+        compare_copy_sizes: !args.sync.fix_tags
+            && args.sync.strip_frames.is_none()
+            && !args.sync.embed_art_copies,
+    };
+    let mut status = exit::SUCCESS;
+    for result in verify::verify(&plan, &options) {
+        if result.problems.is_empty() {
+            println!(
+                "[pass] {}: {} entries",
+                result.path.display(),
+                result.entries
+            );
+            continue;
+        }
+        status = exit::FAILURES;
+        println!(
+            "[fail] {}: {} entries, {} problems",
+            result.path.display(),
+            result.entries,
+            result.problems.len()
+        );
+        for problem in result.problems {
+            println!("       {}", problem);
+        }
+    }
+    status
+}
+
+/// Compare the playlists against an already synced output directory and return the exit status.
+fn diff_output(args: &SyncArgs) -> i32 {
+    let plan = match build_plan(args, &mut vec![]) {
+        Ok(plan) => plan,
+        Err(status) => return status,
+    };
+    let mut status = exit::SUCCESS;
+    for result in diff::diff(&plan) {
+        if result.differences.is_empty() {
+            println!(
+                "[same] {}: {} entries",
+                result.source.display(),
+                result.entries
+            );
+            continue;
+        }
+        status = exit::FAILURES;
+        let count = |kind: fn(&diff::Difference) -> bool| {
+            result.differences.iter().filter(|d| kind(d)).count()
+        };
+        println!(
+            "[diff] {}: {} missing, {} extra, {} outdated",
+            result.source.display(),
+            count(|d| matches!(d, diff::Difference::Missing(_))),
+            count(|d| matches!(d, diff::Difference::Extra(_))),
+            count(|d| matches!(d, diff::Difference::Outdated(_))),
+        );
+        for difference in &result.differences {
+            println!("       {}", difference);
+        }
+    }
+    status
+}
+
+/// Print the predicted size and duration of a run and return the exit status.
+fn print_estimate(args: &EstimateArgs) -> i32 {
+    let mut speeds = estimate::Speeds::new(args.encode_speed);
+    if let Some(path) = &args.timings_from {
+        match Report::read_json(path) {
+            Ok(report) => speeds = speeds.measured(&report),
+            Err(e) => {
+                error!("{}: Failed to read report ({})", path.display(), e);
+                return exit::USAGE;
+            }
+        }
+    }
+    // The durations of all sources are needed for the prediction.
+    let sync_args = SyncArgs {
+        probe: true,
+        ..args.sync.clone()
+    };
+    let plan = match build_plan(&sync_args, &mut vec![]) {
+        Ok(plan) => plan,
+        Err(status) => return status,
+    };
+    let options = EstimateOptions {
+        minutes_per_track: args.sync.minutes_per_track,
+    };
+    let prediction = estimate::predict(&plan, &options, &speeds);
+    if args.json {
+        match serde_json::to_string_pretty(&prediction) {
+            Ok(json) => println!("{}", json),
+            Err(e) => {
+                error!("Failed to serialize estimate ({})", e);
+                return exit::ENVIRONMENT;
+            }
+        }
+        return exit::SUCCESS;
+    }
+    let line = |name: &str, prediction: &estimate::Prediction| {
+        println!(
+            "{}: {} tracks, {} source, {} output, about {} to convert and copy",
+            name,
+            prediction.tracks,
+            ByteSize(prediction.source_size),
+            ByteSize(prediction.output_size),
+            humantime::format_duration(Duration::from_secs(prediction.seconds.ceil() as u64))
+        )
+    };
+    for playlist in &prediction.playlists {
+        line(&playlist.source.display().to_string(), &playlist.prediction);
+    }
+    line("Total", &prediction.total);
+    exit::SUCCESS
+}
+
+/// Execute a plan file and return the exit status.
+fn apply(
+    args: &ApplyArgs,
+    plan_file: PlanFile,
+    settings: BTreeMap<String, serde_json::Value>,
+) -> i32 {
+    let started = Instant::now();
+    if !args.sync.playlists.is_empty() || !args.sync.playlists_from.is_empty() {
+        warn!("Ignoring playlists, the plan file already lists them");
+    }
+    let relocated = plan_file.output_dir != args.sync.output_dir;
+    let changes = plan_file.changes(!relocated);
+    for change in &changes {
+        if change.is_fatal() {
+            error!("{}", change);
+        } else {
+            warn!("{}", change);
+        }
+    }
+    if changes.iter().any(Change::is_fatal) {
+        error!("The files changed since the plan was computed, run `plan` again");
+        return exit::USAGE;
+    }
+    let mut plan = plan_file.plan;
+    if relocated {
+        info!(
+            "Moving planned output from {} to {}",
+            plan_file.output_dir.display(),
+            args.sync.output_dir.display()
+        );
+        plan.relocate(&plan_file.output_dir, &args.sync.output_dir);
+    }
+    execute_plan(&args.sync, plan, settings, vec![], started)
+}
+
+/// Returns the playlists from the command line followed by those from `--playlists-from`, without
+/// duplicates.
+fn playlist_paths(args: &SyncArgs) -> Result<Vec<PathBuf>, i32> {
+    let mut paths = args.playlists.clone();
+    for list in &args.playlists_from {
+        match plan::read_playlist_list(list) {
+            Ok(list_paths) => paths.extend(list_paths),
             Err(e) => {
-                warn!(
-                    "({}/{}) {}: Failed to copy file ({})",
-                    index,
-                    num_tasks_total,
-                    output_path.display(),
+                error!("{}: Failed to read playlist list ({})", list.display(), e);
+                return Err(exit::USAGE);
+            }
+        }
+    }
+    let mut seen = HashSet::new();
+    paths.retain(|path| {
+        let key = std::fs::canonicalize(path).unwrap_or_else(|_| path.clone());
+        let is_new = seen.insert(key);
+        if !is_new {
+            warn!("{}: Ignoring duplicate playlist", path.display());
+        }
+        is_new
+    });
+    Ok(paths)
+}
+
+/// Convert the command line arguments into the library's options.
+fn sync_options(args: &SyncArgs) -> SyncOptions {
+    let mut observers = Observers::default();
+    observers.push(Arc::new(LogObserver {
+        retries: args.retries,
+    }));
+    let mut rules = args.rules.clone();
+    if let Some(bitrate) = args.bitrate {
+        rules.set_fallback(EncoderSettings {
+            bitrate: Some(bitrate),
+            ..Default::default()
+        });
+    }
+    SyncOptions {
+        output_dir: args.output_dir.clone(),
+        target_fs: args.target_fs,
+        on_conflict: args.on_conflict,
+        probe: args.probe,
+        estimate: EstimateOptions {
+            minutes_per_track: args.minutes_per_track,
+        },
+        max_total_size: args.max_total_size,
+        max_total_files: args.max_total_files,
+        max_playlist_entries: args.max_playlist_entries,
+        max_files_per_dir: args.max_files_per_dir,
+        priority: args.priority.clone(),
+        exclude: args.exclude.clone(),
+        include_ext: args.include_ext.clone(),
+        skip_genre: args.skip_genre.clone(),
+        skip_artist: args.skip_artist.clone(),
+        min_rating: args.min_rating,
+        min_duration: args.min_duration,
+        split_long_files: args.split_long_files,
+        since: args.since,
+        since_mode: args.since_mode,
+        on_missing_tag: args.on_missing_tag,
+        fuzzy_match: (!args.no_fuzzy_match).then_some(args.fuzzy_max_distance),
+        resolve_missing: args.resolve_missing.clone(),
+        allow_overlap: args.allow_overlap,
+        structure: args.structure,
+        layout: args.layout,
+        music_root: args.music_root.clone(),
+        outside_music_root: args.outside_music_root,
+        group_by_playlist: args.group_by_playlist.then_some(args.shared_tracks),
+        prefix_position: args.prefix_position,
+        filename_template: args.filename_template.clone(),
+        album_from_playlist: args.album_from_playlist,
+        keep_original_album: args.keep_original_album,
+        tracknum_from_position: args.tracknum_from_position,
+        tags_from_filename: args.tags_from_filename.clone(),
+        tags_from_filename_force: args.tags_from_filename_force,
+        compilation_artist: args
+            .fix_compilations
+            .then(|| args.compilation_artist.clone()),
+        genre_map: None,
+        rules,
+        dedupe_by_tag: args.dedupe_by_tag,
+        per_folder_playlists: args.per_folder_playlists,
+        library: args.library.clone(),
+        smart_playlists: vec![],
+        mpd: None,
+        media_servers: vec![],
+        path_map: args.path_map.clone(),
+        genre_playlists: args
+            .genre_playlists
+            .then_some(args.genre_playlist_min_tracks),
+        dedupe_by_fingerprint: args
+            .dedupe_by_fingerprint
+            .then_some(args.fingerprint_threshold),
+        flatten: args.flatten,
+        transliterate: args.transliterate,
+        short_names: args.short_names,
+        sanitize: args.sanitize,
+        sanitize_replacement: args.sanitize_replacement,
+        length_limits: LengthLimits {
+            max_name: args.max_name_len,
+            max_path: args.max_path_len,
+        },
+        folder_art: args.folder_art,
+        copy_art: args.copy_art,
+        copy_art_size: args.copy_art_size,
+        copy_sidecars: args.copy_sidecars.clone(),
+        mapping_file: args.mapping_file.clone(),
+        keep_original_playlists: args.keep_original_playlist,
+        append: args.append,
+        playlist_style: PlaylistStyle {
+            line_ending: args.playlist_line_endings,
+            encoding: args.playlist_encoding,
+        },
+        force: args.force,
+        force_unlock: args.force_unlock,
+        execute: ExecuteOptions {
+            output_dir: args.output_dir.clone(),
+            keep_logs: args.keep_logs,
+            retries: args.retries,
+            verify_output: args.verify_output || args.move_converted_sources,
+            verify_tolerance: args.verify_tolerance,
+            verify_copies: args.verify_copies,
+            sync_writes: args.sync_writes,
+            fix_tags: args.fix_tags,
+            strip_frames: args.strip_frames.clone(),
+            preserve_times: !args.no_preserve_times,
+            max_tag_len: args.max_tag_len,
+            transliterate_tags: args
+                .transliterate_tags
+                .then_some(args.transliterate_tags_style),
+            id3_encoding: args.id3_encoding,
+            embed_art: args.embed_art,
+            embed_art_copies: args.embed_art_copies,
+            art_size: args.art_size,
+            cover_names: args.cover_names.clone(),
+            max_write_errors: args.max_write_errors,
+            io_retries: args.io_retries,
+            keep_existing: false,
+            since: args
+                .since
+                .filter(|_| args.since_mode == SinceMode::PlanOnly),
+            move_sources: args.move_sources,
+            move_converted_sources: args.move_converted_sources,
+            post_hook: args.post_hook.clone().map(|command| Hook {
+                command,
+                timeout: Duration::from_secs(args.hook_timeout),
+            }),
+            ..Default::default()
+        },
+        observers,
+    }
+}
+
+/// Compute the plan from the input playlists, or return the exit status on failure.
+fn build_plan(args: &SyncArgs, timings: &mut Vec<(&'static str, Duration)>) -> Result<Plan, i32> {
+    let phase_started = Instant::now();
+    let playlists = playlist_paths(args)?;
+    let mut options = sync_options(args);
+    for path in &args.exclude_from {
+        match Excludes::read_file(path) {
+            Ok(file_patterns) => options.exclude.extend(file_patterns),
+            Err(e) => {
+                error!(
+                    "{}: Failed to read exclude patterns ({})",
+                    path.display(),
                     e
                 );
+                return Err(exit::USAGE);
+            }
+        }
+    }
+    if let Some(path) = &args.genre_map {
+        match GenreMap::read_file(path) {
+            Ok(map) => options.genre_map = Some(map),
+            Err(e) => {
+                error!("{}: Failed to read genre map ({})", path.display(), e);
+                return Err(exit::USAGE);
+            }
+        }
+    }
+    if let Some(path) = &args.smart_playlists {
+        match smart::read_file(path) {
+            Ok(playlists) => options.smart_playlists = playlists,
+            Err(e) => {
+                error!("{}: Failed to read smart playlists ({})", path.display(), e);
+                return Err(exit::USAGE);
             }
         }
     }
+    if let (Some(server), Some(music_dir)) = (&args.mpd, &args.mpd_music_dir) {
+        if !args.mpd_all && args.mpd_playlist.is_empty() {
+            error!("`--mpd` needs `--mpd-playlist NAME` or `--mpd-all`");
+            return Err(exit::USAGE);
+        }
+        options.mpd = Some(MpdSource {
+            server: server.clone(),
+            password: args.mpd_password.clone(),
+            playlists: if args.mpd_all {
+                MpdPlaylists::All
+            } else {
+                MpdPlaylists::Named(args.mpd_playlist.clone())
+            },
+            music_dir: music_dir.clone(),
+        });
+    }
+    let media_servers = [
+        (
+            ServerKind::Jellyfin,
+            &args.jellyfin,
+            &args.jellyfin_token,
+            &args.jellyfin_playlist,
+        ),
+        (
+            ServerKind::Plex,
+            &args.plex,
+            &args.plex_token,
+            &args.plex_playlist,
+        ),
+    ];
+    for (kind, url, token, playlists) in media_servers {
+        if let (Some(url), Some(token)) = (url, token) {
+            options.media_servers.push(MediaServer {
+                kind,
+                url: url.clone(),
+                token: token.clone(),
+                playlists: playlists.clone(),
+            });
+        }
+    }
+    match sync::plan(&options, &playlists) {
+        Ok(plan) => {
+            timings.push(("Planning", phase_started.elapsed()));
+            if args.genre_map_report {
+                print_genre_report(&plan);
+            }
+            Ok(plan)
+        }
+        Err(e) => {
+            error!("{}", e);
+            // Servers that cannot be reached are a problem of the environment, while unreadable
+            // playlists and colliding outputs need other arguments.
+            match e.kind() {
+                SyncErrorKind::Server => Err(exit::ENVIRONMENT),
+                _ => Err(exit::USAGE),
+            }
+        }
+    }
+}
+
+/// Remove the files listed in the manifest of the output directory and return the exit status.
+fn clean_output(args: &SyncArgs) -> i32 {
+    let output_dir = &args.output_dir;
+    let manifest = match Manifest::read(output_dir) {
+        Ok(Some(manifest)) => manifest,
+        Ok(None) => {
+            error!(
+                "{}: No manifest of created files, refusing to guess what to remove",
+                output_dir.display()
+            );
+            return exit::ENVIRONMENT;
+        }
+        Err(e) => {
+            error!("{}: Failed to read manifest ({})", output_dir.display(), e);
+            return exit::ENVIRONMENT;
+        }
+    };
+    let files = manifest.existing_files(output_dir);
+    let bytes: u64 = files.iter().map(|(_, size)| size).sum();
+    if args.dry_run {
+        print_removals("remove", output_dir, &files);
+        return exit::SUCCESS;
+    }
+    if !args.yes && !files.is_empty() {
+        let question = format!(
+            "About to remove {} files totaling {} from {}, continue?",
+            files.len(),
+            ByteSize(bytes),
+            output_dir.display()
+        );
+        match confirm::ask(&question) {
+            confirm::Answer::Yes => (),
+            confirm::Answer::No => {
+                error!("Aborted, nothing was removed");
+                return exit::INTERRUPTED;
+            }
+            confirm::Answer::NotInteractive => {
+                error!(
+                    "Not removing {} files without `--yes` (not running interactively)",
+                    files.len()
+                );
+                return exit::INTERRUPTED;
+            }
+        }
+    }
+    let _lock = match OutputLock::acquire(output_dir, args.force_unlock) {
+        Ok(lock) => lock,
+        Err(e) => {
+            error!("{}", e);
+            return exit::ENVIRONMENT;
+        }
+    };
+    match manifest::clean(output_dir, &manifest) {
+        Ok(summary) => {
+            info!(
+                target: logging::SUMMARY_TARGET,
+                "Removed {} files and {} empty directories, freed {}",
+                summary.removed_files,
+                summary.removed_dirs,
+                ByteSize(summary.bytes)
+            );
+            if summary.failed > 0 {
+                exit::FAILURES
+            } else {
+                exit::SUCCESS
+            }
+        }
+        Err(e) => {
+            error!(
+                "{}: Failed to update manifest ({})",
+                output_dir.display(),
+                e
+            );
+            exit::ENVIRONMENT
+        }
+    }
+}
+
+/// Returns the manifest of the output directory and the files in it that `--prune` removes, or
+/// `None` without `--prune` or a manifest.
+fn stale_files(
+    args: &SyncArgs,
+    plan: &Plan,
+    options: &SyncOptions,
+) -> Option<(Manifest, Vec<(PathBuf, u64)>)> {
+    if !args.prune {
+        return None;
+    }
+    let manifest = match Manifest::read(&args.output_dir) {
+        Ok(Some(manifest)) => manifest,
+        Ok(None) => {
+            info!(
+                "{}: No manifest of created files, nothing to prune",
+                args.output_dir.display()
+            );
+            return None;
+        }
+        Err(e) => {
+            report_warning!(
+                WarningCategory::Io,
+                "{}: Failed to read manifest, not pruning ({})",
+                args.output_dir.display(),
+                e
+            );
+            return None;
+        }
+    };
+    let keep: Vec<&Path> = [&args.report_json, &args.report_html, &args.log_file]
+        .into_iter()
+        .flatten()
+        .map(PathBuf::as_path)
+        .collect();
+    let files = sync::stale_files(plan, options, &manifest, &keep);
+    Some((manifest, files))
+}
+
+/// Print the files that would be removed, and the directories that would become empty.
+fn print_removals(verb: &str, output_dir: &Path, files: &[(PathBuf, u64)]) {
+    let paths: Vec<PathBuf> = files.iter().map(|(path, _)| path.clone()).collect();
+    let dirs = manifest::emptied_dirs(output_dir, &paths);
+    for path in paths.iter() {
+        println!("  {} {}", verb, path.display());
+    }
+    for dir in dirs.iter() {
+        println!("  remove directory {}", dir.display());
+    }
+    println!(
+        "Would {} {} files ({}) and {} empty directories.",
+        verb,
+        files.len(),
+        ByteSize(files.iter().map(|(_, size)| size).sum()),
+        dirs.len()
+    );
+}
+
+/// Remove the files that `--prune` found after asking, and return the exit status if the user
+/// declined or that failed.
+fn prune_output(args: &SyncArgs, manifest: &Manifest, files: &[(PathBuf, u64)]) -> Result<(), i32> {
+    if files.is_empty() {
+        return Ok(());
+    }
+    let question = format!(
+        "About to prune {} files totaling {} from {}, continue?",
+        files.len(),
+        ByteSize(files.iter().map(|(_, size)| size).sum()),
+        args.output_dir.display()
+    );
+    let skipped = format!("Not pruning {} files", files.len());
+    if !confirm_step(args.yes, &question, &skipped)? {
+        return Ok(());
+    }
+    let _lock = OutputLock::acquire(&args.output_dir, args.force_unlock).map_err(|e| {
+        error!("{}", e);
+        exit::ENVIRONMENT
+    })?;
+    match manifest::prune(&args.output_dir, manifest, files, args.sync_writes) {
+        Ok(summary) => info!(
+            target: logging::SUMMARY_TARGET,
+            "Pruned {} files and {} empty directories, freed {}",
+            summary.removed_files,
+            summary.removed_dirs,
+            ByteSize(summary.bytes)
+        ),
+        Err(e) => report_warning!(
+            WarningCategory::Io,
+            "{}: Failed to update manifest ({})",
+            args.output_dir.display(),
+            e
+        ),
+    }
+    Ok(())
+}
+
+/// Check and execute the plan, and return the exit status.
+fn execute_plan(
+    args: &SyncArgs,
+    plan: Plan,
+    settings: BTreeMap<String, serde_json::Value>,
+    timings: Vec<(&'static str, Duration)>,
+    started: Instant,
+) -> i32 {
+    execute_plan_counted(args, plan, settings, timings, started).0
+}
+
+/// Like [`execute_plan`], but also return the counts of the run if the plan was executed.
+fn execute_plan_counted(
+    args: &SyncArgs,
+    plan: Plan,
+    settings: BTreeMap<String, serde_json::Value>,
+    timings: Vec<(&'static str, Duration)>,
+    started: Instant,
+) -> (i32, Option<Counts>) {
+    let mut options = sync_options(args);
+
+    let estimate = estimate::estimate(&plan, &options.estimate);
+    info!(
+        "Estimated output size: {} ({} copied, {} converted)",
+        ByteSize(estimate.total()),
+        ByteSize(estimate.copy_bytes),
+        ByteSize(estimate.convert_bytes)
+    );
+    let space_ok = check_space(&args.output_dir, &estimate, args.ignore_space);
+    let counts = OutputCounts::of(&plan, &args.output_dir);
+    info!("Output: {}", counts);
+    let limits_ok = check_limits(&counts, args);
+
+    let stale = stale_files(args, &plan, &options);
+    if args.dry_run {
+        print_dry_run(&plan, &counts, &estimate);
+        if let Some((_, files)) = &stale {
+            print_removals("prune", &args.output_dir, files);
+        }
+        write_report(
+            args,
+            &Report::new(&plan, &[], &options.estimate, settings, &timings, None),
+        );
+        let num_corrupt = report_corrupt_sources(&plan);
+        let status = if !space_ok || !limits_ok {
+            exit::ENVIRONMENT
+        } else if args.fail_on_corrupt && num_corrupt > 0 {
+            exit::FAILURES
+        } else {
+            exit::SUCCESS
+        };
+        return (status, None);
+    }
+    if !space_ok || !limits_ok {
+        return (exit::ENVIRONMENT, None);
+    }
+    if args.emit_script.is_none() {
+        if let Err(e) = sync::check_ffmpeg(&plan, &options) {
+            error!("{}", e);
+            return (exit::ENVIRONMENT, None);
+        }
+    }
+
+    if interrupt::is_interrupted() {
+        return (exit::INTERRUPTED, None);
+    }
+    if args.emit_script.is_none() {
+        match confirm_overwrite(&plan, args.yes, options.execute.since) {
+            Ok(keep_existing) => options.execute.keep_existing = keep_existing,
+            Err(status) => return (status, None),
+        }
+        if let Some((manifest, files)) = &stale {
+            if let Err(status) = prune_output(args, manifest, files) {
+                return (status, None);
+            }
+        }
+    }
+
+    #[cfg(feature = "tui")]
+    let screen = start_screen(args, &mut options);
+    let mut executor = Executor::new(options);
+    executor.set_settings(settings.clone());
+    executor.set_timings(timings);
+    if let Some(path) = &args.emit_script {
+        if let Err(e) = executor.prepare(&plan) {
+            error!("{}", e);
+            return (exit::ENVIRONMENT, None);
+        }
+        let script = script::render(
+            &plan,
+            args.script_format,
+            &settings,
+            executor.execute_options(),
+        );
+        if let Err(e) = script::write(path, &script) {
+            error!("{}: Failed to write script ({})", path.display(), e);
+            return (exit::ENVIRONMENT, None);
+        }
+        info!(
+            target: logging::SUMMARY_TARGET,
+            "Wrote script for {} files: {}",
+            plan.tasks.len(),
+            path.display()
+        );
+        return (exit::SUCCESS, None);
+    }
+    let report = executor.run(&plan);
+    #[cfg(feature = "tui")]
+    drop(screen);
+    let report = match report {
+        Ok(report) => report,
+        Err(e) => {
+            error!("{}", e);
+            return (exit::ENVIRONMENT, None);
+        }
+    };
+    if let Some(e) = &report.error {
+        error!("{}", e);
+        write_report(args, &report);
+        return (exit::ENVIRONMENT, Some(report.counts));
+    }
+
+    if args.timings {
+        print_timings(&report.timings, args.sync_writes);
+    }
+    write_report(args, &report);
+    log_summary(args, &report, started.elapsed());
+
+    if interrupt::is_interrupted() {
+        return (exit::INTERRUPTED, Some(report.counts));
+    }
+    let num_corrupt = report_corrupt_sources(&plan);
+    info!(target: logging::SUMMARY_TARGET, "Done.");
+
+    if args.eject {
+        if let Err(e) = atomic::sync_filesystem(&args.output_dir) {
+            error!(
+                "{}: Failed to flush filesystem ({})",
+                args.output_dir.display(),
+                e
+            );
+            return (exit::ENVIRONMENT, Some(report.counts));
+        }
+        if let Err(e) = mount::eject(&args.output_dir) {
+            error!("{}", e);
+            return (exit::ENVIRONMENT, Some(report.counts));
+        }
+    }
+    let status = if report.counts.failed > 0 || args.fail_on_corrupt && num_corrupt > 0 {
+        exit::FAILURES
+    } else {
+        exit::SUCCESS
+    };
+    (status, Some(report.counts))
 }
@@ -0,0 +1,386 @@
+// Copyright (c) 2024 Jan Holthuis <jan.holthuis@rub.de>
+//
+// This Source Code Form is subject to the terms of the Mozilla Public License, v. 2.0. If a copy
+// of the MPL was not distributed with this file, You can obtain one at
+// http://mozilla.org/MPL/2.0/.
+//
+// SPDX-License-Identifier: MPL-2.0
+//! The manifest of the files that were created in an output directory, for `clean` and
+//! `--prune`.
+//!
+//! Every sync adds the files it wrote to [`MANIFEST_FILE_NAME`] in the output directory: audio
+//! files, playlists, album art, sidecars, and reports and logs written there. Files of earlier
+//! runs stay listed, so that `clean` can remove all of them while leaving other files alone.
+use crate::atomic;
+use crate::longpath;
+use crate::overlap;
+use crate::warnings::{report_warning, WarningCategory};
+use log::{debug, info};
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeSet, HashSet};
+use std::ffi::OsStr;
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+
+/// Name of the manifest in the output directory.
+pub const MANIFEST_FILE_NAME: &str = ".ford-sync-convert.manifest.json";
+
+/// Files created in an output directory, relative to it.
+#[derive(Clone, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Manifest {
+    pub files: BTreeSet<PathBuf>,
+}
+
+/// Returns `path` relative to `output_dir`, if it is inside it.
+fn relative(output_dir: &Path, path: &Path) -> Option<PathBuf> {
+    if let Ok(relative) = path.strip_prefix(output_dir) {
+        return Some(relative.to_path_buf());
+    }
+    overlap::canonicalize_lenient(path)
+        .strip_prefix(overlap::canonicalize_lenient(output_dir))
+        .ok()
+        .map(Path::to_path_buf)
+}
+
+impl Manifest {
+    /// Read the manifest of the output directory, or `None` if it has none.
+    pub fn read(output_dir: &Path) -> io::Result<Option<Self>> {
+        let path = longpath::extended(&output_dir.join(MANIFEST_FILE_NAME));
+        let contents = match std::fs::read(path) {
+            Ok(contents) => contents,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(None),
+            Err(e) => return Err(e),
+        };
+        serde_json::from_slice(&contents)
+            .map(Some)
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))
+    }
+
+    /// Write the manifest into the output directory.
+    pub fn write(&self, output_dir: &Path, sync_writes: bool) -> io::Result<()> {
+        let path = longpath::extended(&output_dir.join(MANIFEST_FILE_NAME));
+        let temp_path = atomic::temp_path(&path);
+        let result = std::fs::File::create(&temp_path)
+            .and_then(|mut file| file.write_all(&serde_json::to_vec_pretty(self)?))
+            .and_then(|()| atomic::commit(&temp_path, &path, sync_writes));
+        if result.is_err() {
+            atomic::discard(&temp_path);
+        }
+        result
+    }
+
+    /// Returns the listed files that exist, with their sizes.
+    pub fn existing_files(&self, output_dir: &Path) -> Vec<(PathBuf, u64)> {
+        self.files
+            .iter()
+            .map(|file| output_dir.join(file))
+            .filter_map(|path| {
+                let metadata = std::fs::symlink_metadata(longpath::extended(&path)).ok()?;
+                metadata.is_file().then_some((path, metadata.len()))
+            })
+            .collect()
+    }
+}
+
+/// Add the existing files among `paths` that are inside the output directory to its manifest.
+///
+/// A manifest that cannot be read is left alone and its error returned, since replacing it would
+/// forget the files of earlier runs.
+pub fn record<'a>(
+    output_dir: &Path,
+    paths: impl IntoIterator<Item = &'a Path>,
+    sync_writes: bool,
+) -> io::Result<()> {
+    let files: Vec<PathBuf> = paths
+        .into_iter()
+        .filter(|path| longpath::extended(path).is_file())
+        .filter_map(|path| relative(output_dir, path))
+        .filter(|file| file != Path::new(MANIFEST_FILE_NAME))
+        .collect();
+    if files.is_empty() {
+        return Ok(());
+    }
+    let mut manifest = Manifest::read(output_dir)?.unwrap_or_default();
+    let num_files = manifest.files.len();
+    manifest.files.extend(files);
+    if manifest.files.len() == num_files {
+        return Ok(());
+    }
+    manifest.write(output_dir, sync_writes)?;
+    debug!(
+        "{}: Recorded {} created files in manifest",
+        output_dir.display(),
+        manifest.files.len()
+    );
+    Ok(())
+}
+
+/// What [`clean`] removed.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct CleanSummary {
+    pub removed_files: usize,
+    pub removed_dirs: usize,
+    pub bytes: u64,
+    /// Number of files that could not be removed.
+    pub failed: usize,
+}
+
+/// Returns `true` for files that the operating system leaves behind in folders, like
+/// `Thumbs.db`, `.DS_Store` and `._` files, which do not keep a directory from being empty.
+fn is_junk(name: &OsStr) -> bool {
+    let name = name.to_string_lossy();
+    name.eq_ignore_ascii_case("Thumbs.db") || name == ".DS_Store" || name.starts_with("._")
+}
+
+/// Returns the directories below `output_dir` that contain nothing but junk once `files` are
+/// removed, deepest first. The output directory itself is never included.
+pub fn emptied_dirs(output_dir: &Path, files: &[PathBuf]) -> Vec<PathBuf> {
+    let candidates: BTreeSet<&Path> = files
+        .iter()
+        .flat_map(|path| {
+            path.ancestors()
+                .skip(1)
+                .take_while(|dir| *dir != output_dir && dir.starts_with(output_dir))
+        })
+        .collect();
+    let mut removed: HashSet<&Path> = files.iter().map(PathBuf::as_path).collect();
+    let mut emptied = vec![];
+    // Deeper directories come after their parents, so they are checked first.
+    for dir in candidates.into_iter().rev() {
+        let Ok(entries) = std::fs::read_dir(longpath::extended(dir)) else {
+            continue;
+        };
+        let is_empty = entries.flatten().all(|entry| {
+            let path = dir.join(entry.file_name());
+            let is_file = entry.file_type().is_ok_and(|file_type| !file_type.is_dir());
+            removed.contains(path.as_path()) || is_file && is_junk(&entry.file_name())
+        });
+        if is_empty {
+            removed.insert(dir);
+            emptied.push(dir.to_path_buf());
+        }
+    }
+    emptied
+}
+
+/// Remove the junk in the directories and then the directories, which have to be ordered deepest
+/// first as returned by [`emptied_dirs`]. Returns the number of removed directories.
+fn remove_dirs(dirs: &[PathBuf]) -> usize {
+    let mut removed = 0;
+    for dir in dirs {
+        for entry in std::fs::read_dir(longpath::extended(dir))
+            .into_iter()
+            .flatten()
+            .flatten()
+        {
+            if entry.file_type().is_ok_and(|file_type| !file_type.is_dir())
+                && is_junk(&entry.file_name())
+            {
+                match std::fs::remove_file(entry.path()) {
+                    Ok(()) => debug!("{}: Removed junk", dir.join(entry.file_name()).display()),
+                    Err(e) => debug!(
+                        "{}: Failed to remove junk ({})",
+                        dir.join(entry.file_name()).display(),
+                        e
+                    ),
+                }
+            }
+        }
+        if std::fs::remove_dir(longpath::extended(dir)).is_ok() {
+            debug!("{}: Removed empty directory", dir.display());
+            removed += 1;
+        }
+    }
+    removed
+}
+
+/// Remove `files`, then the directories that became empty, and drop the removed files from the
+/// manifest.
+///
+/// Files that could not be removed are reported and counted in the summary.
+fn remove_files(
+    output_dir: &Path,
+    manifest: &mut Manifest,
+    files: &[(PathBuf, u64)],
+) -> CleanSummary {
+    let mut summary = CleanSummary::default();
+    let paths: Vec<PathBuf> = files.iter().map(|(path, _)| path.clone()).collect();
+    let dirs = emptied_dirs(output_dir, &paths);
+    for (path, size) in files {
+        match std::fs::remove_file(longpath::extended(path)) {
+            Ok(()) => {
+                debug!("{}: Removed", path.display());
+                summary.removed_files += 1;
+                summary.bytes += size;
+                if let Some(file) = relative(output_dir, path) {
+                    manifest.files.remove(&file);
+                }
+            }
+            Err(e) => {
+                report_warning!(
+                    WarningCategory::Io,
+                    "{}: Failed to remove ({})",
+                    path.display(),
+                    e
+                );
+                summary.failed += 1;
+            }
+        }
+    }
+    summary.removed_dirs = remove_dirs(&dirs);
+    summary
+}
+
+/// Remove the files of the manifest, then the directories that became empty, and finally the
+/// manifest itself.
+///
+/// Files that could not be removed are reported and stay in the manifest.
+pub fn clean(output_dir: &Path, manifest: &Manifest) -> io::Result<CleanSummary> {
+    let files = manifest.existing_files(output_dir);
+    let mut remaining = Manifest::default();
+    remaining.files.extend(
+        files
+            .iter()
+            .filter_map(|(path, _)| relative(output_dir, path)),
+    );
+    let summary = remove_files(output_dir, &mut remaining, &files);
+    if remaining.files.is_empty() {
+        std::fs::remove_file(longpath::extended(&output_dir.join(MANIFEST_FILE_NAME)))?;
+        info!("{}: Removed manifest", output_dir.display());
+    } else {
+        remaining.write(output_dir, false)?;
+    }
+    Ok(summary)
+}
+
+/// Remove `files` of the manifest, which are no longer needed, and the directories that became
+/// empty, and update the manifest.
+pub fn prune(
+    output_dir: &Path,
+    manifest: &Manifest,
+    files: &[(PathBuf, u64)],
+    sync_writes: bool,
+) -> io::Result<CleanSummary> {
+    let mut remaining = manifest.clone();
+    let summary = remove_files(output_dir, &mut remaining, files);
+    if remaining != *manifest {
+        remaining.write(output_dir, sync_writes)?;
+    }
+    Ok(summary)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn removes_only_created_files() {
+        let dir = std::env::temp_dir().join(format!("manifest-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(dir.join("Album/CD1")).unwrap();
+        std::fs::create_dir_all(dir.join("Other")).unwrap();
+        for file in [
+            "Album/CD1/a.mp3",
+            "Album/folder.jpg",
+            "Other/b.mp3",
+            "mine.txt",
+        ] {
+            std::fs::write(dir.join(file), b"DATA").unwrap();
+        }
+        assert_eq!(Manifest::read(&dir).unwrap(), None);
+
+        let created = [dir.join("Album/CD1/a.mp3"), dir.join("Other/b.mp3")];
+        record(&dir, created.iter().map(PathBuf::as_path), false).unwrap();
+        let outside = std::env::temp_dir().join("outside.json");
+        let later = [
+            dir.join("Album/folder.jpg"),
+            dir.join("missing.mp3"),
+            outside,
+        ];
+        record(&dir, later.iter().map(PathBuf::as_path), false).unwrap();
+        let manifest = Manifest::read(&dir).unwrap().unwrap();
+        assert_eq!(manifest.files.len(), 3);
+        assert!(manifest.files.contains(Path::new("Album/folder.jpg")));
+
+        std::fs::write(dir.join("Other/c.txt"), b"").unwrap();
+        let summary = clean(&dir, &manifest).unwrap();
+        assert_eq!(
+            (summary.removed_files, summary.removed_dirs, summary.bytes),
+            (3, 2, 12)
+        );
+        assert_eq!(summary.failed, 0);
+        assert!(!dir.join("Album").exists());
+        assert!(dir.join("Other/c.txt").exists());
+        assert!(dir.join("mine.txt").exists());
+        assert_eq!(Manifest::read(&dir).unwrap(), None);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn keeps_an_unreadable_manifest() {
+        let dir = std::env::temp_dir().join(format!("manifest-unreadable-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        std::fs::write(dir.join("a.mp3"), b"DATA").unwrap();
+        std::fs::write(dir.join(MANIFEST_FILE_NAME), b"{\"files\": [").unwrap();
+
+        let created = [dir.join("a.mp3")];
+        let error = record(&dir, created.iter().map(PathBuf::as_path), false).unwrap_err();
+        assert_eq!(error.kind(), io::ErrorKind::InvalidData);
+        assert_eq!(
+            std::fs::read(dir.join(MANIFEST_FILE_NAME)).unwrap(),
+            b"{\"files\": ["
+        );
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn prune_removes_junk_and_emptied_directories_but_not_the_root() {
+        let dir = std::env::temp_dir().join(format!("manifest-prune-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(dir.join("Artist/Album")).unwrap();
+        std::fs::create_dir_all(dir.join("Kept")).unwrap();
+        for file in [
+            "Artist/Album/a.mp3",
+            "Artist/Album/Thumbs.db",
+            "Artist/.DS_Store",
+            "Artist/._a.mp3",
+            "Kept/b.mp3",
+            "root.mp3",
+        ] {
+            std::fs::write(dir.join(file), b"DATA").unwrap();
+        }
+        let created = [
+            dir.join("Artist/Album/a.mp3"),
+            dir.join("Kept/b.mp3"),
+            dir.join("root.mp3"),
+        ];
+        record(&dir, created.iter().map(PathBuf::as_path), false).unwrap();
+        let manifest = Manifest::read(&dir).unwrap().unwrap();
+        let stale = [
+            (dir.join("Artist/Album/a.mp3"), 4),
+            (dir.join("root.mp3"), 4),
+        ];
+
+        // Listing the directories does not remove anything.
+        let paths: Vec<PathBuf> = stale.iter().map(|(path, _)| path.clone()).collect();
+        assert_eq!(
+            emptied_dirs(&dir, &paths),
+            [dir.join("Artist/Album"), dir.join("Artist")]
+        );
+        assert!(dir.join("Artist/Album/a.mp3").exists());
+
+        let summary = prune(&dir, &manifest, &stale, false).unwrap();
+        assert_eq!(
+            (summary.removed_files, summary.removed_dirs, summary.bytes),
+            (2, 2, 8)
+        );
+        assert!(!dir.join("Artist").exists());
+        assert!(dir.join("Kept/b.mp3").exists());
+        assert_eq!(
+            Manifest::read(&dir).unwrap().unwrap().files,
+            BTreeSet::from([PathBuf::from("Kept/b.mp3")])
+        );
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
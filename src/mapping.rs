@@ -0,0 +1,262 @@
+// Copyright (c) 2024 Jan Holthuis <jan.holthuis@rub.de>
+//
+// This Source Code Form is subject to the terms of the Mozilla Public License, v. 2.0. If a copy
+// of the MPL was not distributed with this file, You can obtain one at
+// http://mozilla.org/MPL/2.0/.
+//
+// SPDX-License-Identifier: MPL-2.0
+//! Records that trace output files back to their sources.
+//!
+//! The mapping file given with `--mapping-file` lists every output file with its source, as CSV if
+//! it has the extension `.csv` and as JSON otherwise. With `--keep-original-playlist`, the input
+//! playlists are copied unchanged into [`ORIGINALS_DIR_NAME`] next to the output playlists.
+use crate::atomic;
+use crate::longpath;
+use crate::plan::{Action, Plan};
+use crate::rules::EncoderSettings;
+use log::{debug, info};
+use serde::Serialize;
+use std::collections::{BTreeMap, HashSet};
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::time::SystemTime;
+use xxhash_rust::xxh3::xxh3_64;
+
+/// Name of the directory next to the output playlists that holds the original playlists.
+pub const ORIGINALS_DIR_NAME: &str = "_original";
+
+/// An output file and where it came from.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize)]
+pub struct MappingEntry {
+    /// Path of the output file, relative to the output directory.
+    pub output: PathBuf,
+    pub source: PathBuf,
+    pub action: Action,
+    /// Hash of the settings of the run and the rule for the source, see [`settings_hash`].
+    pub settings_hash: String,
+    /// When the output was written, in RFC 3339 format.
+    pub timestamp: String,
+    /// Path that the output would have had without `--short-names`, relative to the output
+    /// directory.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub original: Option<PathBuf>,
+}
+
+/// Returns a short hash of the settings and the encoder settings of the rule that applies to an
+/// output, so that outputs written with the same settings can be recognized.
+pub fn settings_hash(
+    settings: &BTreeMap<String, serde_json::Value>,
+    encoder: Option<&EncoderSettings>,
+) -> String {
+    let mut json = serde_json::to_vec(settings).unwrap_or_default();
+    if let Some(encoder) = encoder {
+        json.extend(serde_json::to_vec(encoder).unwrap_or_default());
+    }
+    format!("{:016x}", xxh3_64(&json))
+}
+
+/// Returns the entries for the output files of the plan that exist, once per output file.
+pub fn entries(
+    plan: &Plan,
+    output_dir: &Path,
+    settings: &BTreeMap<String, serde_json::Value>,
+    timestamp: SystemTime,
+) -> Vec<MappingEntry> {
+    let timestamp = humantime::format_rfc3339_seconds(timestamp).to_string();
+    let mut seen = HashSet::new();
+    plan.tasks
+        .iter()
+        .filter(|task| seen.insert(&task.destination))
+        .filter(|task| longpath::extended(&task.destination).exists())
+        .map(|task| {
+            let output = task
+                .destination
+                .strip_prefix(output_dir)
+                .unwrap_or(&task.destination)
+                .to_path_buf();
+            MappingEntry {
+                original: plan.short_names.get(&output).cloned(),
+                output,
+                source: task.source.clone(),
+                action: task.action,
+                settings_hash: settings_hash(settings, task.encoder.as_ref()),
+                timestamp: timestamp.clone(),
+            }
+        })
+        .collect()
+}
+
+/// Quote a CSV field if necessary.
+fn csv_field(value: &str) -> String {
+    if value.contains([',', '"', '\n', '\r']) {
+        format!("\"{}\"", value.replace('"', "\"\""))
+    } else {
+        value.to_string()
+    }
+}
+
+/// Render the entries as CSV with a header line, with a column for the original paths if any
+/// output was renamed by `--short-names`.
+pub fn render_csv(entries: &[MappingEntry]) -> String {
+    let has_originals = entries.iter().any(|entry| entry.original.is_some());
+    let mut csv = String::from("output,source,action,settings_hash,timestamp");
+    csv.push_str(if has_originals { ",original\n" } else { "\n" });
+    for entry in entries {
+        let action = match entry.action {
+            Action::Copy => "copy",
+            Action::Convert => "convert",
+            Action::Remux => "remux",
+        };
+        let mut fields = vec![
+            csv_field(&entry.output.to_string_lossy()),
+            csv_field(&entry.source.to_string_lossy()),
+            action.to_string(),
+            entry.settings_hash.clone(),
+            entry.timestamp.clone(),
+        ];
+        if has_originals {
+            let original = entry.original.as_deref().unwrap_or(&entry.output);
+            fields.push(csv_field(&original.to_string_lossy()));
+        }
+        csv.push_str(&fields.join(","));
+        csv.push('\n');
+    }
+    csv
+}
+
+/// Write the mapping file for the plan to `path`.
+pub fn write(
+    path: &Path,
+    plan: &Plan,
+    output_dir: &Path,
+    settings: &BTreeMap<String, serde_json::Value>,
+) -> std::io::Result<()> {
+    let entries = entries(plan, output_dir, settings, SystemTime::now());
+    let is_csv = path
+        .extension()
+        .is_some_and(|extension| extension.eq_ignore_ascii_case("csv"));
+    let contents = if is_csv {
+        render_csv(&entries)
+    } else {
+        serde_json::to_string_pretty(&entries)? + "\n"
+    };
+    if let Some(parent) = path.parent().filter(|parent| *parent != Path::new("")) {
+        std::fs::create_dir_all(parent)?;
+    }
+    let temp_path = atomic::temp_path(path);
+    std::fs::File::create(&temp_path)?.write_all(contents.as_bytes())?;
+    atomic::commit(&temp_path, path, false)?;
+    info!(
+        "Wrote mapping file: {} ({} files)",
+        path.display(),
+        entries.len()
+    );
+    Ok(())
+}
+
+/// Returns the path that the original of the output playlist at `playlist` is copied to, which
+/// keeps the extension of the `source` playlist.
+pub fn original_playlist_path(playlist: &Path, source: &Path) -> PathBuf {
+    let dir = playlist.parent().unwrap_or(Path::new(""));
+    let mut file_name = playlist.file_stem().unwrap_or_default().to_os_string();
+    if let Some(extension) = source.extension() {
+        file_name.push(".");
+        file_name.push(extension);
+    }
+    dir.join(ORIGINALS_DIR_NAME).join(file_name)
+}
+
+/// Copy the input playlists of the plan unchanged into [`ORIGINALS_DIR_NAME`].
+///
+/// Playlists that do not exist as files, e.g. those generated for genres, are left out. The paths
+/// of the copies are added to `kept`, also for the copies made before an error.
+pub fn keep_original_playlists(
+    plan: &Plan,
+    sync_writes: bool,
+    kept: &mut Vec<PathBuf>,
+) -> std::io::Result<()> {
+    for playlist in plan.playlists.iter() {
+        if !playlist.source.is_file() {
+            debug!(
+                "{}: Not keeping original of generated playlist",
+                playlist.path.display()
+            );
+            continue;
+        }
+        let path = longpath::extended(&original_playlist_path(&playlist.path, &playlist.source));
+        if let Some(parent) = path.parent() {
+            std::fs::create_dir_all(parent)?;
+        }
+        let temp_path = atomic::temp_path(&path);
+        std::fs::copy(&playlist.source, &temp_path)?;
+        atomic::commit(&temp_path, &path, sync_writes)?;
+        debug!("{}: Kept original playlist", path.display());
+        kept.push(original_playlist_path(&playlist.path, &playlist.source));
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::plan::Task;
+    use crate::tags::TagOverrides;
+
+    #[test]
+    fn lists_existing_outputs_once() {
+        let dir = std::env::temp_dir().join(format!("mapping-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(dir.join("Album")).unwrap();
+        std::fs::write(dir.join("Album/a, b.mp3"), b"").unwrap();
+        let task = |source: &str, destination: &str| Task {
+            action: Action::Convert,
+            source: PathBuf::from(source),
+            destination: dir.join(destination),
+            tags: TagOverrides::default(),
+            encoder: None,
+            segment: None,
+        };
+        let plan = Plan {
+            tasks: vec![
+                task("music/a, b.flac", "Album/a, b.mp3"),
+                task("music/a, b.flac", "Album/a, b.mp3"),
+                task("music/failed.flac", "Album/failed.mp3"),
+            ],
+            ..Default::default()
+        };
+        let settings = BTreeMap::from([("retries".to_string(), serde_json::json!(2))]);
+        let entries = entries(&plan, &dir, &settings, SystemTime::UNIX_EPOCH);
+        assert_eq!(entries.len(), 1);
+        assert_eq!(entries[0].output, Path::new("Album/a, b.mp3"));
+        assert_eq!(entries[0].settings_hash, settings_hash(&settings, None));
+        assert_ne!(
+            entries[0].settings_hash,
+            settings_hash(&BTreeMap::new(), None)
+        );
+        let encoder = EncoderSettings {
+            quality: Some(0),
+            ..Default::default()
+        };
+        assert_ne!(
+            entries[0].settings_hash,
+            settings_hash(&settings, Some(&encoder))
+        );
+        let csv = render_csv(&entries);
+        assert_eq!(
+            csv.lines().nth(1).unwrap(),
+            format!(
+                "\"Album/a, b.mp3\",\"music/a, b.flac\",convert,{},1970-01-01T00:00:00Z",
+                entries[0].settings_hash
+            )
+        );
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn original_playlists_keep_their_extension() {
+        assert_eq!(
+            original_playlist_path(Path::new("out/Road (2).m3u"), Path::new("lists/Road.pls")),
+            Path::new("out/_original/Road (2).pls")
+        );
+    }
+}
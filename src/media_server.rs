@@ -0,0 +1,551 @@
+// Copyright (c) 2024 Jan Holthuis <jan.holthuis@rub.de>
+//
+// This Source Code Form is subject to the terms of the Mozilla Public License, v. 2.0. If a copy
+// of the MPL was not distributed with this file, You can obtain one at
+// http://mozilla.org/MPL/2.0/.
+//
+// SPDX-License-Identifier: MPL-2.0
+//! Reading playlists from Jellyfin and Plex servers, for `--jellyfin` and `--plex`.
+//!
+//! The HTTP APIs are queried with `curl`, which reads the access token from its standard input so
+//! that it does not show up in the process list. The server-side file path of each playlist item
+//! is mapped to a local path with the `--map` prefix rules, e.g. for media mounted over NFS.
+use crate::layout;
+use crate::longpath;
+use crate::plan::{InputPlaylist, SkipReason, SkippedEntry};
+use crate::warnings::{report_warning, WarningCategory};
+use log::{debug, info};
+use serde_json::Value;
+use std::collections::HashMap;
+use std::fmt;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::process::{Command, Stdio};
+
+/// Number of playlist items requested at a time.
+const PAGE_SIZE: usize = 200;
+
+/// Maximum time in seconds that a single request may take.
+const MAX_TIME: u32 = 60;
+
+/// Kind of media server.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ServerKind {
+    Jellyfin,
+    Plex,
+}
+
+impl fmt::Display for ServerKind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ServerKind::Jellyfin => f.write_str("Jellyfin"),
+            ServerKind::Plex => f.write_str("Plex"),
+        }
+    }
+}
+
+/// Replaces the server-side prefix `from` of file paths with the local directory `to`, given as
+/// `FROM=TO`, e.g. `/srv/media=/mnt/nas/media`.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct PathMapping {
+    pub from: String,
+    pub to: PathBuf,
+}
+
+impl std::str::FromStr for PathMapping {
+    type Err = String;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        value
+            .split_once('=')
+            .filter(|(from, to)| !from.is_empty() && !to.is_empty())
+            .map(|(from, to)| Self {
+                from: from.trim_end_matches(['/', '\\']).to_string(),
+                to: PathBuf::from(to),
+            })
+            .ok_or_else(|| {
+                format!(
+                    "invalid path mapping `{}`, expected `FROM=TO`, e.g. `/srv/media=/mnt/media`",
+                    value
+                )
+            })
+    }
+}
+
+/// Returns the local path of a server-side file path, using the mapping with the longest
+/// matching prefix. Paths that no mapping matches are used as they are.
+pub fn map_path(mappings: &[PathMapping], path: &str) -> PathBuf {
+    let rest = |mapping: &PathMapping| {
+        let rest = path.strip_prefix(mapping.from.as_str())?;
+        (rest.is_empty() || rest.starts_with(['/', '\\'])).then_some(rest)
+    };
+    let mapped = mappings
+        .iter()
+        .filter_map(|mapping| Some((mapping, rest(mapping)?)))
+        .max_by_key(|(mapping, _)| mapping.from.len());
+    match mapped {
+        // Paths from servers on Windows use backslashes.
+        Some((mapping, rest)) => rest
+            .split(['/', '\\'])
+            .filter(|component| !component.is_empty())
+            .fold(mapping.to.clone(), |path, component| path.join(component)),
+        None => PathBuf::from(path),
+    }
+}
+
+/// A Jellyfin or Plex server and the playlists to sync from it.
+#[derive(Clone, Debug)]
+pub struct MediaServer {
+    pub kind: ServerKind,
+    /// Base URL of the server, e.g. `http://nas:8096`.
+    pub url: String,
+    pub token: String,
+    /// Names of the playlists to sync.
+    pub playlists: Vec<String>,
+}
+
+/// Error querying a media server.
+#[derive(Debug)]
+pub enum MediaServerError {
+    /// `curl` could not be run.
+    Curl(std::io::Error),
+    /// The request failed, e.g. because the server cannot be reached or rejected the token.
+    Request { url: String, message: String },
+    /// The response is not what the API returns.
+    Response { url: String, message: String },
+    /// The server has no playlist with the name.
+    NoSuchPlaylist { name: String },
+}
+
+impl fmt::Display for MediaServerError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            MediaServerError::Curl(e) => write!(f, "Failed to run curl ({})", e),
+            MediaServerError::Request { url, message } => {
+                write!(f, "{}: Request failed ({})", url, message)
+            }
+            MediaServerError::Response { url, message } => {
+                write!(f, "{}: Unexpected response ({})", url, message)
+            }
+            MediaServerError::NoSuchPlaylist { name } => write!(f, "No playlist named `{}`", name),
+        }
+    }
+}
+
+impl std::error::Error for MediaServerError {}
+
+/// An item of a playlist.
+#[derive(Clone, Debug, PartialEq)]
+struct Item {
+    title: String,
+    /// Path of the file on the server, if the item has one.
+    path: Option<String>,
+    duration: Option<f64>,
+}
+
+/// Returns the JSON response to a GET request for `url` with the `headers`.
+fn curl(url: &str, headers: &[String]) -> Result<Value, MediaServerError> {
+    debug!("GET {}", url);
+    let mut child = Command::new("curl")
+        .args(["--silent", "--show-error", "--fail", "--location"])
+        .args(["--max-time", &MAX_TIME.to_string(), "--header", "@-", url])
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+        .map_err(MediaServerError::Curl)?;
+    if let Some(mut stdin) = child.stdin.take() {
+        stdin
+            .write_all((headers.join("\n") + "\n").as_bytes())
+            .map_err(MediaServerError::Curl)?;
+    }
+    let output = child.wait_with_output().map_err(MediaServerError::Curl)?;
+    if !output.status.success() {
+        let stderr = String::from_utf8_lossy(&output.stderr);
+        return Err(MediaServerError::Request {
+            url: url.to_string(),
+            message: stderr.trim().trim_start_matches("curl: ").to_string(),
+        });
+    }
+    serde_json::from_slice(&output.stdout).map_err(|e| MediaServerError::Response {
+        url: url.to_string(),
+        message: e.to_string(),
+    })
+}
+
+impl MediaServer {
+    fn headers(&self) -> Vec<String> {
+        let mut headers = vec!["Accept: application/json".to_string()];
+        headers.push(match self.kind {
+            ServerKind::Jellyfin => format!("X-Emby-Token: {}", self.token),
+            ServerKind::Plex => format!("X-Plex-Token: {}", self.token),
+        });
+        headers
+    }
+
+    fn url(&self, path: &str) -> String {
+        format!("{}{}", self.url.trim_end_matches('/'), path)
+    }
+
+    /// Returns the IDs of the playlists on the server by name.
+    fn list_playlists(
+        &self,
+        get: &mut impl FnMut(&str) -> Result<Value, MediaServerError>,
+    ) -> Result<HashMap<String, String>, MediaServerError> {
+        let (path, list, name_key, id_key) = match self.kind {
+            ServerKind::Jellyfin => (
+                "/Items?IncludeItemTypes=Playlist&Recursive=true",
+                "/Items",
+                "Name",
+                "Id",
+            ),
+            ServerKind::Plex => (
+                "/playlists?playlistType=audio",
+                "/MediaContainer/Metadata",
+                "title",
+                "ratingKey",
+            ),
+        };
+        let url = self.url(path);
+        let response = get(&url)?;
+        let playlists = match response.pointer(list) {
+            Some(Value::Array(playlists)) => playlists.as_slice(),
+            // Plex leaves out the list if there are no playlists.
+            None if self.kind == ServerKind::Plex => &[],
+            _ => {
+                return Err(MediaServerError::Response {
+                    url,
+                    message: format!("missing `{}`", list),
+                })
+            }
+        };
+        Ok(playlists
+            .iter()
+            .filter_map(|playlist| {
+                let id = match &playlist[id_key] {
+                    Value::String(id) => id.clone(),
+                    Value::Number(id) => id.to_string(),
+                    _ => return None,
+                };
+                Some((playlist[name_key].as_str()?.to_string(), id))
+            })
+            .collect())
+    }
+
+    /// Returns the items of the playlist with `id`, requesting them a page at a time.
+    fn items(
+        &self,
+        id: &str,
+        get: &mut impl FnMut(&str) -> Result<Value, MediaServerError>,
+    ) -> Result<Vec<Item>, MediaServerError> {
+        let mut items = vec![];
+        loop {
+            let url = self.url(&match self.kind {
+                ServerKind::Jellyfin => format!(
+                    "/Playlists/{}/Items?Fields=Path&StartIndex={}&Limit={}",
+                    id,
+                    items.len(),
+                    PAGE_SIZE
+                ),
+                ServerKind::Plex => format!(
+                    "/playlists/{}/items?X-Plex-Container-Start={}&X-Plex-Container-Size={}",
+                    id,
+                    items.len(),
+                    PAGE_SIZE
+                ),
+            });
+            let response = get(&url)?;
+            let (list, total) = match self.kind {
+                ServerKind::Jellyfin => ("/Items", "/TotalRecordCount"),
+                ServerKind::Plex => ("/MediaContainer/Metadata", "/MediaContainer/totalSize"),
+            };
+            let page = match response.pointer(list) {
+                Some(Value::Array(page)) => page.as_slice(),
+                None if self.kind == ServerKind::Plex => &[],
+                _ => {
+                    return Err(MediaServerError::Response {
+                        url,
+                        message: format!("missing `{}`", list),
+                    })
+                }
+            };
+            items.extend(page.iter().map(|item| self.item(item)));
+            let total = response
+                .pointer(total)
+                .and_then(Value::as_u64)
+                .map_or(items.len(), |total| total as usize);
+            if page.is_empty() || items.len() >= total {
+                return Ok(items);
+            }
+        }
+    }
+
+    fn item(&self, item: &Value) -> Item {
+        match self.kind {
+            ServerKind::Jellyfin => Item {
+                title: item["Name"].as_str().unwrap_or_default().to_string(),
+                path: item["Path"].as_str().map(str::to_string),
+                // Run times are given in ticks of 100 ns.
+                duration: item["RunTimeTicks"]
+                    .as_f64()
+                    .map(|ticks| ticks / 10_000_000.0),
+            },
+            ServerKind::Plex => Item {
+                title: item["title"].as_str().unwrap_or_default().to_string(),
+                path: item
+                    .pointer("/Media/0/Part/0/file")
+                    .and_then(Value::as_str)
+                    .map(str::to_string),
+                // Durations are given in milliseconds.
+                duration: item["duration"].as_f64().map(|ms| ms / 1000.0),
+            },
+        }
+    }
+
+    /// Read the playlists of the server, mapping the file paths of their items with `mappings`.
+    ///
+    /// Fails if the playlists cannot be listed. Playlists that do not exist or whose items
+    /// cannot be read are reported and left out.
+    pub fn read_playlists(
+        &self,
+        mappings: &[PathMapping],
+    ) -> Result<Vec<InputPlaylist>, MediaServerError> {
+        let headers = self.headers();
+        self.read_playlists_with(mappings, &mut |url| curl(url, &headers))
+    }
+
+    fn read_playlists_with(
+        &self,
+        mappings: &[PathMapping],
+        get: &mut impl FnMut(&str) -> Result<Value, MediaServerError>,
+    ) -> Result<Vec<InputPlaylist>, MediaServerError> {
+        let ids = self.list_playlists(get)?;
+        let mut playlists = vec![];
+        for name in self.playlists.iter() {
+            let items = ids
+                .get(name)
+                .ok_or_else(|| MediaServerError::NoSuchPlaylist { name: name.clone() })
+                .and_then(|id| self.items(id, get));
+            match items {
+                Ok(items) => {
+                    info!(
+                        "Read {} playlist: {} ({} items)",
+                        self.kind,
+                        name,
+                        items.len()
+                    );
+                    playlists.push(input_playlist(name, items, mappings));
+                }
+                Err(e) => report_warning!(
+                    WarningCategory::ServerPlaylist,
+                    "{}: Skipping {} playlist ({})",
+                    name,
+                    self.kind,
+                    e
+                ),
+            }
+        }
+        Ok(playlists)
+    }
+}
+
+/// Returns the playlist with the local files of the items.
+///
+/// Items without a file on the server or whose file does not exist locally are skipped. The
+/// playlist is placed in the deepest directory that contains all of its files.
+fn input_playlist(name: &str, items: Vec<Item>, mappings: &[PathMapping]) -> InputPlaylist {
+    let mut sources = vec![];
+    let mut skipped = vec![];
+    for item in items {
+        let Some(path) = item.path else {
+            report_warning!(
+                WarningCategory::UnreadableEntry,
+                "{}: Item has no file on the server: {}",
+                name,
+                item.title
+            );
+            skipped.push(SkippedEntry {
+                entry: item.title,
+                reason: SkipReason::NotAccessible { path: None },
+            });
+            continue;
+        };
+        let local_path = map_path(mappings, &path);
+        if !longpath::extended(&local_path).exists() {
+            report_warning!(
+                WarningCategory::UnreadableEntry,
+                "{}: Item is not accessible locally: {}",
+                name,
+                local_path.display()
+            );
+            skipped.push(SkippedEntry {
+                entry: path,
+                reason: SkipReason::NotAccessible {
+                    path: Some(local_path.display().to_string()),
+                },
+            });
+            continue;
+        }
+        sources.push((local_path, item.duration));
+    }
+    let dir = common_dir(sources.iter().map(|(source, _)| source.as_path()))
+        .or_else(|| mappings.first().map(|mapping| mapping.to.clone()))
+        .unwrap_or_default();
+    let mut entries = vec![];
+    let mut durations = HashMap::new();
+    for (source, duration) in sources {
+        let entry = source.strip_prefix(&dir).unwrap_or(&source).to_path_buf();
+        if let Some(duration) = duration {
+            durations.insert(entry.clone(), duration);
+        }
+        entries.push(entry);
+    }
+    InputPlaylist {
+        path: dir.join(format!("{}.m3u", layout::component(name))),
+        entries,
+        skipped,
+        title: Some(name.to_string()),
+        durations,
+    }
+}
+
+/// Returns the deepest directory that contains all of the files.
+fn common_dir<'a>(mut files: impl Iterator<Item = &'a Path>) -> Option<PathBuf> {
+    let mut dir = files.next()?.parent()?.to_path_buf();
+    for file in files {
+        while !file.starts_with(&dir) {
+            dir = dir.parent()?.to_path_buf();
+        }
+    }
+    Some(dir)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn mapping(value: &str) -> PathMapping {
+        value.parse().unwrap()
+    }
+
+    #[test]
+    fn maps_the_longest_prefix() {
+        let mappings = [
+            mapping("/srv/media=/mnt/media"),
+            mapping("/srv/media/music/=/mnt/music"),
+        ];
+        assert_eq!(
+            map_path(&mappings, "/srv/media/music/a/b.flac"),
+            Path::new("/mnt/music/a/b.flac")
+        );
+        assert_eq!(
+            map_path(&mappings, "/srv/media/podcasts/c.mp3"),
+            Path::new("/mnt/media/podcasts/c.mp3")
+        );
+        assert_eq!(
+            map_path(&mappings, "/srv/mediatheque/d.mp3"),
+            Path::new("/srv/mediatheque/d.mp3")
+        );
+        assert_eq!(
+            map_path(&[mapping("D:\\Music=/mnt/music")], "D:\\Music\\a\\b.flac"),
+            Path::new("/mnt/music/a/b.flac")
+        );
+        assert!("/srv/media".parse::<PathMapping>().is_err());
+    }
+
+    #[test]
+    fn reads_paginated_playlists() {
+        let dir = std::env::temp_dir().join(format!("media-server-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(dir.join("music/Artist")).unwrap();
+        let track = |number: usize| format!("/srv/music/Artist/{}.flac", number);
+        for number in 0..250 {
+            std::fs::write(dir.join(format!("music/Artist/{}.flac", number)), b"").unwrap();
+        }
+        let server = MediaServer {
+            kind: ServerKind::Jellyfin,
+            url: "http://nas:8096/".to_string(),
+            token: "token".to_string(),
+            playlists: vec!["Road/Trip".to_string(), "Gone".to_string()],
+        };
+        let mut requests = vec![];
+        let mut get = |url: &str| {
+            requests.push(url.to_string());
+            if url.ends_with("Recursive=true") {
+                return Ok(json!({"Items": [{"Name": "Road/Trip", "Id": "abc"}]}));
+            }
+            let start: usize = url
+                .split("StartIndex=")
+                .nth(1)
+                .unwrap()
+                .split('&')
+                .next()
+                .unwrap()
+                .parse()
+                .unwrap();
+            let mut items: Vec<Value> = (start..252.min(start + PAGE_SIZE))
+                .map(|number| json!({"Name": number.to_string(), "Path": track(number), "RunTimeTicks": 1_800_000_000u64}))
+                .collect();
+            if start == 0 {
+                items[1] = json!({"Name": "Stream"});
+            }
+            Ok(json!({"Items": items, "TotalRecordCount": 252}))
+        };
+        let mappings = [mapping(&format!(
+            "/srv/music={}",
+            dir.join("music").display()
+        ))];
+        let playlists = server.read_playlists_with(&mappings, &mut get).unwrap();
+        assert_eq!(
+            requests[1],
+            "http://nas:8096/Playlists/abc/Items?Fields=Path&StartIndex=0&Limit=200"
+        );
+        assert_eq!(requests.len(), 3);
+        assert_eq!(playlists.len(), 1);
+        let playlist = &playlists[0];
+        assert_eq!(playlist.name(), "Road/Trip");
+        assert_eq!(playlist.path, dir.join("music/Artist/Road_Trip.m3u"));
+        assert_eq!(playlist.entries.len(), 249);
+        assert_eq!(playlist.entries[0], Path::new("0.flac"));
+        assert_eq!(playlist.durations[Path::new("0.flac")], 180.0);
+        // The item without a path, and the two files that do not exist locally.
+        assert_eq!(playlist.skipped.len(), 3);
+        assert_eq!(playlist.skipped[2].entry, track(251));
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn reads_plex_items() {
+        let server = MediaServer {
+            kind: ServerKind::Plex,
+            url: "http://nas:32400".to_string(),
+            token: "token".to_string(),
+            playlists: vec!["Car".to_string()],
+        };
+        let item = server.item(&json!({
+            "title": "Song",
+            "duration": 215000,
+            "Media": [{"Part": [{"file": "/data/Song.mp3"}]}]
+        }));
+        assert_eq!(
+            item,
+            Item {
+                title: "Song".to_string(),
+                path: Some("/data/Song.mp3".to_string()),
+                duration: Some(215.0),
+            }
+        );
+        let mut get = |url: &str| {
+            Ok(if url.contains("playlistType") {
+                json!({"MediaContainer": {"Metadata": [{"title": "Car", "ratingKey": 12}]}})
+            } else {
+                assert!(url.starts_with("http://nas:32400/playlists/12/items?"));
+                json!({"MediaContainer": {"totalSize": 0}})
+            })
+        };
+        let playlists = server.read_playlists_with(&[], &mut get).unwrap();
+        assert!(playlists[0].entries.is_empty());
+        assert_eq!(server.headers()[1], "X-Plex-Token: token");
+    }
+}
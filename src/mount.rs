@@ -0,0 +1,270 @@
+// Copyright (c) 2024 Jan Holthuis <jan.holthuis@rub.de>
+//
+// This Source Code Form is subject to the terms of the Mozilla Public License, v. 2.0. If a copy
+// of the MPL was not distributed with this file, You can obtain one at
+// http://mozilla.org/MPL/2.0/.
+//
+// SPDX-License-Identifier: MPL-2.0
+//! Information about the filesystem mounts that outputs are written to.
+use clap::ValueEnum;
+use log::info;
+use std::fmt;
+use std::path::{Path, PathBuf};
+use std::process::{Command, Stdio};
+
+/// Filesystem type of the output directory, as far as it matters for the head unit.
+#[derive(ValueEnum, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum TargetFs {
+    Fat32,
+    Exfat,
+    Ntfs,
+    /// Any other filesystem, e.g. a staging directory on ext4.
+    Other,
+}
+
+impl TargetFs {
+    /// Map a filesystem type name as reported by the OS.
+    pub fn from_fs_type(fs_type: &str) -> Self {
+        match fs_type.to_ascii_lowercase().as_str() {
+            "vfat" | "fat" | "fat32" | "msdos" | "msdosfs" => TargetFs::Fat32,
+            "exfat" => TargetFs::Exfat,
+            "ntfs" | "ntfs3" => TargetFs::Ntfs,
+            _ => TargetFs::Other,
+        }
+    }
+
+    /// Detect the filesystem of `path` (which does not need to exist yet).
+    pub fn detect(path: &Path) -> Option<(Self, Mount)> {
+        let mount = find_mount(path)?;
+        Some((Self::from_fs_type(&mount.fs_type), mount))
+    }
+
+    /// Returns `true` for the FAT family of filesystems.
+    pub fn is_fat(self) -> bool {
+        matches!(self, TargetFs::Fat32 | TargetFs::Exfat)
+    }
+
+    /// Returns `true` for the filesystems that Sync 2 can read from USB media.
+    pub fn is_supported_by_sync(self) -> bool {
+        matches!(self, TargetFs::Fat32 | TargetFs::Exfat)
+    }
+
+    /// Maximum size of a single file in bytes.
+    pub fn max_file_size(self) -> Option<u64> {
+        match self {
+            TargetFs::Fat32 => Some(u64::from(u32::MAX)),
+            _ => None,
+        }
+    }
+}
+
+impl fmt::Display for TargetFs {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let name = match self {
+            TargetFs::Fat32 => "FAT32",
+            TargetFs::Exfat => "exFAT",
+            TargetFs::Ntfs => "NTFS",
+            TargetFs::Other => "other",
+        };
+        f.write_str(name)
+    }
+}
+
+/// A mounted filesystem.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Mount {
+    pub mount_point: PathBuf,
+    pub fs_type: String,
+    pub device: String,
+}
+
+#[derive(Debug)]
+pub enum EjectError {
+    /// The mount of the output directory could not be determined.
+    UnknownMount(PathBuf),
+    /// The filesystem is not on removable media (or contains the home directory).
+    NotRemovable(Mount),
+    /// All unmount commands failed.
+    UnmountFailed { mount: Mount, reason: String },
+}
+
+impl fmt::Display for EjectError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            EjectError::UnknownMount(path) => write!(
+                f,
+                "{}: Failed to determine the filesystem to eject",
+                path.display()
+            ),
+            EjectError::NotRemovable(mount) => write!(
+                f,
+                "{}: Refusing to eject {}, it is not a removable medium",
+                mount.mount_point.display(),
+                mount.device
+            ),
+            EjectError::UnmountFailed { mount, reason } => write!(
+                f,
+                "{}: Failed to unmount {} ({})",
+                mount.mount_point.display(),
+                mount.device,
+                reason
+            ),
+        }
+    }
+}
+
+impl std::error::Error for EjectError {}
+
+/// Decode the octal escapes (e.g. `\040` for a space) used in `/proc/self/mountinfo`.
+#[cfg(target_os = "linux")]
+fn unescape_mountinfo(field: &str) -> String {
+    let bytes = field.as_bytes();
+    let mut result = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        if bytes[i] == b'\\' && i + 4 <= bytes.len() {
+            let value = std::str::from_utf8(&bytes[i + 1..i + 4])
+                .ok()
+                .and_then(|digits| u8::from_str_radix(digits, 8).ok());
+            if let Some(value) = value {
+                result.push(value);
+                i += 4;
+                continue;
+            }
+        }
+        result.push(bytes[i]);
+        i += 1;
+    }
+    String::from_utf8_lossy(&result).into_owned()
+}
+
+/// Returns all mounted filesystems.
+#[cfg(target_os = "linux")]
+pub fn mounts() -> Vec<Mount> {
+    let Ok(contents) = std::fs::read_to_string("/proc/self/mountinfo") else {
+        return vec![];
+    };
+    contents
+        .lines()
+        .filter_map(|line| {
+            // Format: ID PARENT MAJ:MIN ROOT MOUNT_POINT OPTIONS [OPTIONAL...] - FSTYPE SOURCE ...
+            let (before, after) = line.split_once(" - ")?;
+            let mount_point = before.split(' ').nth(4)?;
+            let mut after = after.split(' ');
+            let fs_type = after.next()?;
+            let device = after.next()?;
+            Some(Mount {
+                mount_point: PathBuf::from(unescape_mountinfo(mount_point)),
+                fs_type: fs_type.to_string(),
+                device: unescape_mountinfo(device),
+            })
+        })
+        .collect()
+}
+
+#[cfg(not(target_os = "linux"))]
+pub fn mounts() -> Vec<Mount> {
+    vec![]
+}
+
+/// Returns the mount that contains `path` (which does not need to exist yet).
+pub fn find_mount(path: &Path) -> Option<Mount> {
+    let absolute = std::path::absolute(path).ok()?;
+    let canonical = absolute
+        .ancestors()
+        .find_map(|ancestor| {
+            let canonical = ancestor.canonicalize().ok()?;
+            Some(canonical.join(absolute.strip_prefix(ancestor).ok()?))
+        })
+        .unwrap_or(absolute);
+    mounts()
+        .into_iter()
+        .filter(|mount| canonical.starts_with(&mount.mount_point))
+        .max_by_key(|mount| mount.mount_point.as_os_str().len())
+}
+
+/// Returns `true` if the block device backing the mount is marked as removable.
+#[cfg(target_os = "linux")]
+pub fn is_removable(mount: &Mount) -> bool {
+    let Some(name) = mount.device.strip_prefix("/dev/") else {
+        return false;
+    };
+    let Ok(sys_path) = std::fs::canonicalize(Path::new("/sys/class/block").join(name)) else {
+        return false;
+    };
+    // For partitions, the `removable` attribute lives on the parent disk.
+    [sys_path.as_path(), sys_path.parent().unwrap_or(&sys_path)]
+        .iter()
+        .any(|dir| {
+            std::fs::read_to_string(dir.join("removable")).is_ok_and(|value| value.trim() == "1")
+        })
+}
+
+#[cfg(not(target_os = "linux"))]
+pub fn is_removable(_mount: &Mount) -> bool {
+    false
+}
+
+/// Returns `true` if it is safe to unmount the mount, i.e. it is removable and neither the root
+/// filesystem nor contains the home directory.
+fn is_ejectable(mount: &Mount) -> bool {
+    if mount.mount_point == Path::new("/") {
+        return false;
+    }
+    let contains_home = std::env::var_os("HOME")
+        .map(PathBuf::from)
+        .and_then(|home| home.canonicalize().ok())
+        .is_some_and(|home| home.starts_with(&mount.mount_point));
+    !contains_home && is_removable(mount)
+}
+
+fn run_command(command: &mut Command) -> Result<(), String> {
+    let output = command
+        .stdin(Stdio::null())
+        .output()
+        .map_err(|e| format!("{:?}: {}", command.get_program(), e))?;
+    if output.status.success() {
+        Ok(())
+    } else {
+        Err(format!(
+            "{:?}: {}",
+            command.get_program(),
+            String::from_utf8_lossy(&output.stderr).trim()
+        ))
+    }
+}
+
+/// Unmount the removable medium that contains `output_dir`.
+pub fn eject(output_dir: &Path) -> Result<(), EjectError> {
+    let mount =
+        find_mount(output_dir).ok_or_else(|| EjectError::UnknownMount(output_dir.to_path_buf()))?;
+    if !is_ejectable(&mount) {
+        return Err(EjectError::NotRemovable(mount));
+    }
+
+    info!(
+        "Ejecting {} ({})...",
+        mount.mount_point.display(),
+        mount.device
+    );
+    let mut errors = vec![];
+    let mut udisksctl = Command::new("udisksctl");
+    udisksctl
+        .args(["unmount", "--no-user-interaction", "-b"])
+        .arg(&mount.device);
+    let mut umount = Command::new("umount");
+    umount.arg(&mount.mount_point);
+    for command in [&mut udisksctl, &mut umount] {
+        match run_command(command) {
+            Ok(()) => {
+                info!("{}: Safe to remove.", mount.mount_point.display());
+                return Ok(());
+            }
+            Err(e) => errors.push(e),
+        }
+    }
+    Err(EjectError::UnmountFailed {
+        mount,
+        reason: errors.join("; "),
+    })
+}
@@ -0,0 +1,473 @@
+// Copyright (c) 2024 Jan Holthuis <jan.holthuis@rub.de>
+//
+// This Source Code Form is subject to the terms of the Mozilla Public License, v. 2.0. If a copy
+// of the MPL was not distributed with this file, You can obtain one at
+// http://mozilla.org/MPL/2.0/.
+//
+// SPDX-License-Identifier: MPL-2.0
+//! Reading playlists from a Music Player Daemon server, for `--mpd`.
+//!
+//! The stored playlists are listed with `listplaylistinfo`, and the URIs of their songs are
+//! resolved against the local copy of the MPD music directory given with `--mpd-music-dir`. Each
+//! playlist is synced like a playlist file of the same name in that directory.
+use crate::longpath;
+use crate::plan::{InputPlaylist, SkipReason, SkippedEntry};
+use crate::warnings::{report_warning, WarningCategory};
+use log::{debug, info};
+use std::collections::HashMap;
+use std::fmt;
+use std::io::{BufRead, BufReader, Write};
+use std::net::{TcpStream, ToSocketAddrs};
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+/// Port that MPD listens on by default.
+pub const DEFAULT_PORT: u16 = 6600;
+
+/// How long to wait for the server to accept the connection or answer a command.
+const TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Address of an MPD server, given as `HOST[:PORT]`.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct MpdServer {
+    pub host: String,
+    pub port: u16,
+}
+
+impl std::str::FromStr for MpdServer {
+    type Err = String;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        // IPv6 addresses are given in brackets, e.g. `[::1]:6600`.
+        let (host, port) = match value.rsplit_once(':') {
+            Some((host, port))
+                if !host.ends_with(':') && (!host.starts_with('[') || host.ends_with(']')) =>
+            {
+                let port = port
+                    .parse()
+                    .map_err(|_| format!("invalid port `{}`, expected a number", port))?;
+                (host, port)
+            }
+            _ => (value, DEFAULT_PORT),
+        };
+        let host = host.trim_start_matches('[').trim_end_matches(']');
+        if host.is_empty() {
+            return Err(format!(
+                "invalid MPD server `{}`, expected `HOST:PORT`",
+                value
+            ));
+        }
+        Ok(Self {
+            host: host.to_string(),
+            port,
+        })
+    }
+}
+
+impl fmt::Display for MpdServer {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if self.host.contains(':') {
+            write!(f, "[{}]:{}", self.host, self.port)
+        } else {
+            write!(f, "{}:{}", self.host, self.port)
+        }
+    }
+}
+
+/// Which of the stored playlists of the server to sync.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum MpdPlaylists {
+    All,
+    Named(Vec<String>),
+}
+
+/// Where to read playlists from with `--mpd`.
+#[derive(Clone, Debug)]
+pub struct MpdSource {
+    pub server: MpdServer,
+    pub password: Option<String>,
+    pub playlists: MpdPlaylists,
+    /// Local copy of the music directory of the server, which the song URIs are relative to.
+    pub music_dir: PathBuf,
+}
+
+/// Error reading playlists from an MPD server.
+#[derive(Debug)]
+pub enum MpdError {
+    /// The music directory does not exist locally.
+    MusicDir {
+        path: PathBuf,
+        error: std::io::Error,
+    },
+    /// The server cannot be reached.
+    Connect {
+        server: MpdServer,
+        error: std::io::Error,
+    },
+    /// The connection failed after it was established.
+    Io {
+        server: MpdServer,
+        error: std::io::Error,
+    },
+    /// The server sent something that is not an MPD response.
+    Protocol { server: MpdServer, line: String },
+    /// The server rejected a command, e.g. because of a wrong password or unknown playlist.
+    Ack {
+        server: MpdServer,
+        command: String,
+        message: String,
+    },
+}
+
+impl fmt::Display for MpdError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            MpdError::MusicDir { path, error } => write!(
+                f,
+                "{}: Failed to access MPD music directory ({})",
+                path.display(),
+                error
+            ),
+            MpdError::Connect { server, error } => {
+                write!(f, "{}: Failed to connect to MPD ({})", server, error)
+            }
+            MpdError::Io { server, error } => {
+                write!(f, "{}: Lost connection to MPD ({})", server, error)
+            }
+            MpdError::Protocol { server, line } => {
+                write!(f, "{}: Unexpected response from MPD: {}", server, line)
+            }
+            MpdError::Ack {
+                server,
+                command,
+                message,
+            } => write!(f, "{}: MPD rejected `{}` ({})", server, command, message),
+        }
+    }
+}
+
+impl std::error::Error for MpdError {}
+
+/// Quote an argument of an MPD command.
+fn quote(argument: &str) -> String {
+    format!(
+        "\"{}\"",
+        argument.replace('\\', "\\\\").replace('"', "\\\"")
+    )
+}
+
+/// A connection to an MPD server.
+struct Connection<'a> {
+    server: &'a MpdServer,
+    reader: BufReader<TcpStream>,
+    writer: TcpStream,
+}
+
+impl<'a> Connection<'a> {
+    /// Connect to the server and check its greeting.
+    fn open(server: &'a MpdServer) -> Result<Self, MpdError> {
+        let connect_error = |error| MpdError::Connect {
+            server: server.clone(),
+            error,
+        };
+        let addresses = (server.host.as_str(), server.port)
+            .to_socket_addrs()
+            .map_err(connect_error)?;
+        let mut last_error =
+            std::io::Error::new(std::io::ErrorKind::NotFound, "host has no addresses");
+        let mut stream = None;
+        for address in addresses {
+            match TcpStream::connect_timeout(&address, TIMEOUT) {
+                Ok(connected) => {
+                    stream = Some(connected);
+                    break;
+                }
+                Err(error) => last_error = error,
+            }
+        }
+        let stream = stream.ok_or_else(|| connect_error(last_error))?;
+        let setup = |stream: &TcpStream| {
+            stream.set_read_timeout(Some(TIMEOUT))?;
+            stream.set_write_timeout(Some(TIMEOUT))?;
+            stream.try_clone()
+        };
+        let writer = setup(&stream).map_err(connect_error)?;
+        let mut connection = Self {
+            server,
+            reader: BufReader::new(stream),
+            writer,
+        };
+        let greeting = connection.read_line()?;
+        match greeting.strip_prefix("OK MPD ") {
+            Some(version) => debug!("{}: Connected to MPD {}", server, version),
+            None => {
+                return Err(MpdError::Protocol {
+                    server: server.clone(),
+                    line: greeting,
+                })
+            }
+        }
+        Ok(connection)
+    }
+
+    fn io_error(&self, error: std::io::Error) -> MpdError {
+        MpdError::Io {
+            server: self.server.clone(),
+            error,
+        }
+    }
+
+    fn read_line(&mut self) -> Result<String, MpdError> {
+        let mut line = String::new();
+        match self.reader.read_line(&mut line) {
+            Ok(0) => Err(self.io_error(std::io::ErrorKind::UnexpectedEof.into())),
+            Ok(_) => Ok(line.trim_end_matches(['\r', '\n']).to_string()),
+            Err(error) => Err(self.io_error(error)),
+        }
+    }
+
+    /// Send a command and return the key-value pairs of the response.
+    fn command(
+        &mut self,
+        command: &str,
+        arguments: &[&str],
+    ) -> Result<Vec<(String, String)>, MpdError> {
+        let mut line = command.to_string();
+        for argument in arguments {
+            line.push(' ');
+            line.push_str(&quote(argument));
+        }
+        line.push('\n');
+        self.writer
+            .write_all(line.as_bytes())
+            .map_err(|error| self.io_error(error))?;
+        let mut pairs = vec![];
+        loop {
+            let line = self.read_line()?;
+            if line == "OK" {
+                return Ok(pairs);
+            }
+            if let Some(ack) = line.strip_prefix("ACK ") {
+                // The error looks like `[50@0] {listplaylistinfo} No such playlist`.
+                let message = ack.split_once("} ").map_or(ack, |(_, message)| message);
+                return Err(MpdError::Ack {
+                    server: self.server.clone(),
+                    command: command.to_string(),
+                    message: message.to_string(),
+                });
+            }
+            match line.split_once(": ") {
+                Some((key, value)) => pairs.push((key.to_string(), value.to_string())),
+                None => {
+                    return Err(MpdError::Protocol {
+                        server: self.server.clone(),
+                        line,
+                    })
+                }
+            }
+        }
+    }
+}
+
+/// Returns the playlist with the songs of the stored playlist with their URIs resolved against
+/// `music_dir`.
+///
+/// Songs that are streams or whose files do not exist below `music_dir` are skipped.
+fn input_playlist(name: &str, songs: Vec<(String, String)>, music_dir: &Path) -> InputPlaylist {
+    let mut entries = vec![];
+    let mut skipped = vec![];
+    let mut durations = HashMap::new();
+    for (key, value) in songs {
+        match key.as_str() {
+            "file" => {
+                let path = match value.split_once("://") {
+                    None => PathBuf::from(&value),
+                    Some(("file", path)) => PathBuf::from(path),
+                    Some(_) => {
+                        report_warning!(WarningCategory::Url, "Ignoring URL: {}", value);
+                        skipped.push(SkippedEntry {
+                            entry: value,
+                            reason: SkipReason::Url,
+                        });
+                        continue;
+                    }
+                };
+                let local_path = music_dir.join(&path);
+                if !longpath::extended(&local_path).exists() {
+                    report_warning!(
+                        WarningCategory::UnreadableEntry,
+                        "{}: Song is not accessible locally: {}",
+                        name,
+                        local_path.display()
+                    );
+                    skipped.push(SkippedEntry {
+                        entry: value,
+                        reason: SkipReason::NotAccessible {
+                            path: Some(local_path.display().to_string()),
+                        },
+                    });
+                    continue;
+                }
+                entries.push(path);
+            }
+            // Newer servers send `duration` with fractions in addition to `Time`.
+            "duration" | "Time" => {
+                if let (Some(path), Ok(duration)) = (entries.last(), value.parse::<f64>()) {
+                    if key == "duration" || !durations.contains_key(path) {
+                        durations.insert(path.clone(), duration);
+                    }
+                }
+            }
+            _ => (),
+        }
+    }
+    InputPlaylist {
+        path: music_dir.join(format!("{}.m3u", name)),
+        entries,
+        skipped,
+        title: None,
+        durations,
+    }
+}
+
+/// Read the playlists of the source from the server.
+pub fn read_playlists(source: &MpdSource) -> Result<Vec<InputPlaylist>, MpdError> {
+    // Without the music directory, no song could be synced, so fail before asking the server.
+    std::fs::read_dir(longpath::extended(&source.music_dir)).map_err(|error| {
+        MpdError::MusicDir {
+            path: source.music_dir.clone(),
+            error,
+        }
+    })?;
+    let mut connection = Connection::open(&source.server)?;
+    if let Some(password) = &source.password {
+        connection.command("password", &[password])?;
+    }
+    let names = match &source.playlists {
+        MpdPlaylists::Named(names) => names.clone(),
+        MpdPlaylists::All => connection
+            .command("listplaylists", &[])?
+            .into_iter()
+            .filter(|(key, _)| key == "playlist")
+            .map(|(_, name)| name)
+            .collect(),
+    };
+    let mut playlists = vec![];
+    for name in names {
+        info!("Reading MPD playlist: {}", name);
+        let songs = connection.command("listplaylistinfo", &[&name])?;
+        playlists.push(input_playlist(&name, songs, &source.music_dir));
+    }
+    let _ = connection.command("close", &[]);
+    Ok(playlists)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::TcpListener;
+
+    /// Serve one connection, answering each command with the response for it.
+    fn serve(responses: &'static [(&'static str, &'static str)]) -> MpdServer {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+        std::thread::spawn(move || {
+            let (stream, _) = listener.accept().unwrap();
+            let mut writer = stream.try_clone().unwrap();
+            writer.write_all(b"OK MPD 0.23.5\n").unwrap();
+            for line in BufReader::new(stream).lines() {
+                let line = line.unwrap();
+                let response = responses
+                    .iter()
+                    .find(|(command, _)| *command == line)
+                    .map_or("ACK [5@0] {} unknown command\n", |(_, response)| response);
+                if writer.write_all(response.as_bytes()).is_err() {
+                    break;
+                }
+            }
+        });
+        MpdServer {
+            host: "127.0.0.1".to_string(),
+            port,
+        }
+    }
+
+    fn source(server: MpdServer, password: Option<&str>, music_dir: &Path) -> MpdSource {
+        MpdSource {
+            server,
+            password: password.map(str::to_string),
+            playlists: MpdPlaylists::All,
+            music_dir: music_dir.to_path_buf(),
+        }
+    }
+
+    #[test]
+    fn parses_servers() {
+        let server = |value: &str| value.parse::<MpdServer>();
+        assert_eq!(
+            server("localhost:6601").unwrap().to_string(),
+            "localhost:6601"
+        );
+        assert_eq!(server("music.local").unwrap().port, DEFAULT_PORT);
+        assert_eq!(server("[::1]:6601").unwrap().host, "::1");
+        assert_eq!(server("::1").unwrap().to_string(), "[::1]:6600");
+        assert!(server("localhost:mpd")
+            .unwrap_err()
+            .contains("invalid port"));
+        assert!(server(":6600").is_err());
+    }
+
+    #[test]
+    fn reads_stored_playlists() {
+        let dir = std::env::temp_dir().join(format!("mpd-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(dir.join("Artist")).unwrap();
+        std::fs::write(dir.join("Artist/a.flac"), b"").unwrap();
+        let server = serve(&[
+            ("password \"secret\"", "OK\n"),
+            (
+                "listplaylists",
+                "playlist: Road \"Trip\"\nLast-Modified: 2024-06-01T00:00:00Z\nOK\n",
+            ),
+            (
+                "listplaylistinfo \"Road \\\"Trip\\\"\"",
+                "file: Artist/a.flac\nTime: 215\nduration: 214.6\nTitle: A\n\
+                 file: http://radio.example/stream\nfile: Artist/moved.flac\nOK\n",
+            ),
+        ]);
+        let playlists = read_playlists(&source(server, Some("secret"), &dir)).unwrap();
+        assert_eq!(playlists.len(), 1);
+        let playlist = &playlists[0];
+        assert_eq!(playlist.path, dir.join("Road \"Trip\".m3u"));
+        assert_eq!(playlist.entries, [PathBuf::from("Artist/a.flac")]);
+        assert_eq!(playlist.durations[Path::new("Artist/a.flac")], 214.6);
+        assert!(matches!(playlist.skipped[0].reason, SkipReason::Url));
+        assert!(matches!(
+            playlist.skipped[1].reason,
+            SkipReason::NotAccessible { .. }
+        ));
+
+        let server = serve(&[(
+            "password \"wrong\"",
+            "ACK [3@0] {password} incorrect password\n",
+        )]);
+        let error = read_playlists(&source(server, Some("wrong"), &dir)).unwrap_err();
+        assert!(error.to_string().ends_with("(incorrect password)"));
+
+        let error = read_playlists(&source(serve(&[]), None, &dir.join("missing"))).unwrap_err();
+        assert!(matches!(error, MpdError::MusicDir { .. }));
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn unreachable_servers_are_reported() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let server = MpdServer {
+            host: "127.0.0.1".to_string(),
+            port: listener.local_addr().unwrap().port(),
+        };
+        drop(listener);
+        let error = read_playlists(&source(server, None, &std::env::temp_dir())).unwrap_err();
+        assert!(matches!(error, MpdError::Connect { .. }));
+    }
+}
@@ -0,0 +1,148 @@
+// Copyright (c) 2024 Jan Holthuis <jan.holthuis@rub.de>
+//
+// This Source Code Form is subject to the terms of the Mozilla Public License, v. 2.0. If a copy
+// of the MPL was not distributed with this file, You can obtain one at
+// http://mozilla.org/MPL/2.0/.
+//
+// SPDX-License-Identifier: MPL-2.0
+//! Preservation of source modification times on output files.
+use crate::warnings::{report_warning, WarningCategory};
+use std::path::Path;
+use std::time::{Duration, SystemTime};
+
+/// Earliest timestamp that FAT can store (1980-01-01 00:00:00), in seconds since the Unix epoch.
+const FAT_MIN_SECS: u64 = 315_532_800;
+
+/// Latest timestamp that FAT can store (2107-12-31 23:59:58), in seconds since the Unix epoch.
+const FAT_MAX_SECS: u64 = 4_354_819_198;
+
+/// Clamp `time` to the range and the 2-second resolution of FAT modification times.
+pub fn fat_time(time: SystemTime) -> SystemTime {
+    let secs = time
+        .duration_since(SystemTime::UNIX_EPOCH)
+        .map(|duration| duration.as_secs())
+        .unwrap_or(0)
+        .clamp(FAT_MIN_SECS, FAT_MAX_SECS);
+    SystemTime::UNIX_EPOCH + Duration::from_secs(secs & !1)
+}
+
+/// Returns the MS-DOS time and date of `time` in UTC.
+pub fn dos_time(time: SystemTime) -> (u16, u16) {
+    let secs = fat_time(time)
+        .duration_since(SystemTime::UNIX_EPOCH)
+        .map(|duration| duration.as_secs())
+        .unwrap_or(0);
+    let (days, secs) = (secs / 86_400, secs % 86_400);
+    // Civil date from days since the epoch, see http://howardhinnant.github.io/date_algorithms.html
+    let z = days + 719_468;
+    let era = z / 146_097;
+    let doe = z - era * 146_097;
+    let yoe = (doe - doe / 1460 + doe / 36_524 - doe / 146_096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + u64::from(month <= 2);
+    let time = ((secs / 3600) << 11) | ((secs % 3600 / 60) << 5) | (secs % 60 / 2);
+    let date = ((year - 1980) << 9) | (month << 5) | day;
+    (time as u16, date as u16)
+}
+
+/// Set the modification time of `destination` to that of `source`, clamped to FAT limits.
+fn copy_mtime(source: &Path, destination: &Path) -> std::io::Result<()> {
+    let modified = std::fs::metadata(source)?.modified()?;
+    set_mtime(destination, modified)
+}
+
+/// Set the modification time of `destination` to `modified`, clamped to FAT limits.
+fn set_mtime(destination: &Path, modified: SystemTime) -> std::io::Result<()> {
+    std::fs::File::options()
+        .write(true)
+        .open(destination)?
+        .set_modified(fat_time(modified))
+}
+
+/// Set the modification time of `destination` to that of `source`, reporting failures as
+/// warnings.
+pub fn preserve(source: &Path, destination: &Path) {
+    if let Err(e) = copy_mtime(source, destination) {
+        report_warning!(
+            WarningCategory::Io,
+            "{}: Failed to set modification time ({})",
+            destination.display(),
+            e
+        );
+    }
+}
+
+/// Set the modification time of `destination` to `modified`, e.g. one taken from a source that
+/// was moved, reporting failures as warnings.
+pub fn restore(destination: &Path, modified: SystemTime) {
+    if let Err(e) = set_mtime(destination, modified) {
+        report_warning!(
+            WarningCategory::Io,
+            "{}: Failed to set modification time ({})",
+            destination.display(),
+            e
+        );
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn secs(time: SystemTime) -> u64 {
+        time.duration_since(SystemTime::UNIX_EPOCH)
+            .unwrap()
+            .as_secs()
+    }
+
+    #[test]
+    fn rounds_down_to_even_seconds() {
+        let time = SystemTime::UNIX_EPOCH + Duration::new(1_700_000_001, 500_000_000);
+        assert_eq!(secs(fat_time(time)), 1_700_000_000);
+    }
+
+    #[test]
+    fn clamps_to_fat_range() {
+        assert_eq!(secs(fat_time(SystemTime::UNIX_EPOCH)), FAT_MIN_SECS);
+        let far_future = SystemTime::UNIX_EPOCH + Duration::from_secs(5_000_000_000);
+        assert_eq!(secs(fat_time(far_future)), FAT_MAX_SECS);
+    }
+
+    #[test]
+    fn preserves_source_time() {
+        let dir = std::env::temp_dir().join(format!("ford-sync-mtime-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let source = dir.join("source.mp3");
+        let destination = dir.join("destination.mp3");
+        std::fs::write(&source, b"source").unwrap();
+        std::fs::write(&destination, b"destination").unwrap();
+        let time = SystemTime::UNIX_EPOCH + Duration::from_secs(1_500_000_003);
+        std::fs::File::options()
+            .write(true)
+            .open(&source)
+            .unwrap()
+            .set_modified(time)
+            .unwrap();
+
+        preserve(&source, &destination);
+        let modified = std::fs::metadata(&destination).unwrap().modified().unwrap();
+        assert_eq!(secs(modified), 1_500_000_002);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn converts_times_to_dos_format() {
+        // 2024-02-29 13:45:30 UTC
+        let time = SystemTime::UNIX_EPOCH + Duration::from_secs(1_709_214_330);
+        assert_eq!(
+            dos_time(time),
+            (
+                (13 << 11) | (45 << 5) | 15,
+                ((2024 - 1980) << 9) | (2 << 5) | 29
+            )
+        );
+    }
+}
@@ -0,0 +1,556 @@
+// Copyright (c) 2024 Jan Holthuis <jan.holthuis@rub.de>
+//
+// This Source Code Form is subject to the terms of the Mozilla Public License, v. 2.0. If a copy
+// of the MPL was not distributed with this file, You can obtain one at
+// http://mozilla.org/MPL/2.0/.
+//
+// SPDX-License-Identifier: MPL-2.0
+//! Uploading the output to an MTP device like a phone for `--output-mtp`, through libmtp.
+//!
+//! The sync writes into a staging directory first, so that conversions are not held up by the
+//! slow transfers, and the staged files are uploaded one after the other afterwards. Files that
+//! are already on the device with the same size are skipped.
+use crate::archive;
+use crate::estimate::ByteSize;
+use crate::interrupt;
+use crate::upload::UploadSummary;
+use crate::warnings::{report_warning, WarningCategory};
+use log::{debug, info};
+use std::collections::HashMap;
+use std::ffi::{CStr, CString};
+use std::fmt;
+use std::os::raw::c_char;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant};
+
+/// The parts of the libmtp API that are used here, see `libmtp.h`.
+#[allow(non_snake_case)]
+mod ffi {
+    use std::os::raw::{c_char, c_int, c_void};
+
+    #[repr(C)]
+    pub struct DeviceEntry {
+        pub vendor: *mut c_char,
+        pub vendor_id: u16,
+        pub product: *mut c_char,
+        pub product_id: u16,
+        pub device_flags: u32,
+    }
+
+    #[repr(C)]
+    pub struct RawDevice {
+        pub device_entry: DeviceEntry,
+        pub bus_location: u32,
+        pub devnum: u8,
+    }
+
+    #[repr(C)]
+    pub struct File {
+        pub item_id: u32,
+        pub parent_id: u32,
+        pub storage_id: u32,
+        pub filename: *mut c_char,
+        pub filesize: u64,
+        pub modificationdate: libc::time_t,
+        pub filetype: c_int,
+        pub next: *mut File,
+    }
+
+    #[repr(C)]
+    pub struct Error {
+        pub errornumber: c_int,
+        pub error_text: *mut c_char,
+        pub next: *mut Error,
+    }
+
+    /// `LIBMTP_mtpdevice_t`, which is only used through pointers.
+    pub enum Device {}
+
+    pub type ProgressFunc =
+        Option<unsafe extern "C" fn(sent: u64, total: u64, data: *const c_void) -> c_int>;
+
+    /// Parent ID of the objects in the root folder.
+    pub const FILES_AND_FOLDERS_ROOT: u32 = 0xFFFF_FFFF;
+    pub const FILETYPE_FOLDER: c_int = 0;
+    pub const FILETYPE_MP3: c_int = 2;
+    pub const FILETYPE_JPEG: c_int = 14;
+    pub const FILETYPE_TEXT: c_int = 27;
+    /// `LIBMTP_ERROR_NO_DEVICE_ATTACHED`.
+    pub const ERROR_NO_DEVICE_ATTACHED: c_int = 5;
+
+    #[link(name = "mtp")]
+    extern "C" {
+        pub fn LIBMTP_Init();
+        pub fn LIBMTP_Detect_Raw_Devices(devices: *mut *mut RawDevice, count: *mut c_int) -> c_int;
+        pub fn LIBMTP_Open_Raw_Device_Uncached(device: *mut RawDevice) -> *mut Device;
+        pub fn LIBMTP_Release_Device(device: *mut Device);
+        pub fn LIBMTP_Get_Friendlyname(device: *mut Device) -> *mut c_char;
+        pub fn LIBMTP_Get_Modelname(device: *mut Device) -> *mut c_char;
+        pub fn LIBMTP_Get_Serialnumber(device: *mut Device) -> *mut c_char;
+        pub fn LIBMTP_Get_Files_And_Folders(
+            device: *mut Device,
+            storage: u32,
+            parent: u32,
+        ) -> *mut File;
+        pub fn LIBMTP_Create_Folder(
+            device: *mut Device,
+            name: *mut c_char,
+            parent: u32,
+            storage: u32,
+        ) -> u32;
+        pub fn LIBMTP_new_file_t() -> *mut File;
+        pub fn LIBMTP_destroy_file_t(file: *mut File);
+        pub fn LIBMTP_Send_File_From_File(
+            device: *mut Device,
+            path: *const c_char,
+            file: *mut File,
+            callback: ProgressFunc,
+            data: *const c_void,
+        ) -> c_int;
+        pub fn LIBMTP_Delete_Object(device: *mut Device, id: u32) -> c_int;
+        pub fn LIBMTP_Get_Errorstack(device: *mut Device) -> *mut Error;
+        pub fn LIBMTP_Clear_Errorstack(device: *mut Device);
+    }
+}
+
+/// Error talking to an MTP device.
+#[derive(Debug)]
+pub enum MtpError {
+    Io(std::io::Error),
+    /// No device is attached, or none with the given name.
+    NoDevice(String),
+    /// The device reported an error.
+    Device(String),
+}
+
+impl fmt::Display for MtpError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            MtpError::Io(e) => e.fmt(f),
+            MtpError::NoDevice(name) if name.is_empty() => write!(f, "No MTP device found"),
+            MtpError::NoDevice(name) => write!(f, "No MTP device named `{}` found", name),
+            MtpError::Device(message) => write!(f, "MTP device error ({})", message),
+        }
+    }
+}
+
+impl std::error::Error for MtpError {}
+
+/// Device and folder to upload to, given as `device:/folder`.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct MtpTarget {
+    /// Friendly or model name of the device. If empty, the only attached device is used.
+    pub device: String,
+    /// Names of the folders from the root of the device's storage.
+    pub folder: Vec<String>,
+}
+
+impl std::str::FromStr for MtpTarget {
+    type Err = String;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        let (device, folder) = value
+            .split_once(":/")
+            .ok_or_else(|| format!("invalid MTP target `{}`, expected `device:/folder`", value))?;
+        Ok(Self {
+            device: device.trim().to_string(),
+            folder: folder
+                .split('/')
+                .filter(|name| !name.is_empty())
+                .map(String::from)
+                .collect(),
+        })
+    }
+}
+
+/// A file or folder on the device.
+#[derive(Clone, Debug)]
+struct Object {
+    id: u32,
+    name: String,
+    size: u64,
+    is_folder: bool,
+}
+
+/// Returns a string allocated by libmtp and frees it.
+///
+/// # Safety
+///
+/// `ptr` must be null or a string allocated with `malloc`.
+unsafe fn take_string(ptr: *mut c_char) -> Option<String> {
+    if ptr.is_null() {
+        return None;
+    }
+    let string = CStr::from_ptr(ptr).to_string_lossy().into_owned();
+    libc::free(ptr.cast());
+    Some(string)
+}
+
+fn c_string(value: &str) -> Result<CString, MtpError> {
+    CString::new(value).map_err(|e| MtpError::Device(e.to_string()))
+}
+
+/// An opened MTP device.
+struct Device {
+    raw: *mut ffi::Device,
+    name: String,
+    /// Serial number of the device, or its name if it has none.
+    serial: String,
+}
+
+impl Device {
+    /// Open the attached device whose friendly or model name is `name` (case-insensitively), or
+    /// the only attached device if `name` is empty.
+    fn open(name: &str) -> Result<Self, MtpError> {
+        let mut raw_devices: *mut ffi::RawDevice = std::ptr::null_mut();
+        let mut count = 0;
+        // SAFETY: libmtp allocates the array of raw devices, which is freed below.
+        let result = unsafe {
+            ffi::LIBMTP_Init();
+            ffi::LIBMTP_Detect_Raw_Devices(&mut raw_devices, &mut count)
+        };
+        if result == ffi::ERROR_NO_DEVICE_ATTACHED || count <= 0 {
+            return Err(MtpError::NoDevice(name.to_string()));
+        }
+        if result != 0 {
+            return Err(MtpError::Device(format!(
+                "failed to detect devices (error {})",
+                result
+            )));
+        }
+        let mut found = None;
+        for index in 0..count as usize {
+            // SAFETY: `raw_devices` holds `count` devices.
+            let raw = unsafe { ffi::LIBMTP_Open_Raw_Device_Uncached(raw_devices.add(index)) };
+            if raw.is_null() {
+                continue;
+            }
+            // SAFETY: `raw` is an opened device, and the names are allocated by libmtp.
+            let names = unsafe {
+                [
+                    take_string(ffi::LIBMTP_Get_Friendlyname(raw)),
+                    take_string(ffi::LIBMTP_Get_Modelname(raw)),
+                ]
+            };
+            let names: Vec<String> = names.into_iter().flatten().collect();
+            let name_or_model = names.first().cloned().unwrap_or_default();
+            // SAFETY: As above.
+            let serial = unsafe { take_string(ffi::LIBMTP_Get_Serialnumber(raw)) }
+                .filter(|serial| !serial.trim().is_empty())
+                .unwrap_or_else(|| name_or_model.clone());
+            let device = Device {
+                raw,
+                name: name_or_model,
+                serial,
+            };
+            if name.is_empty() && count == 1
+                || names.iter().any(|other| other.eq_ignore_ascii_case(name))
+            {
+                found = Some(device);
+                break;
+            }
+            debug!("Skipping MTP device {}", device.name);
+        }
+        // SAFETY: The array was allocated with `malloc` by libmtp.
+        unsafe { libc::free(raw_devices.cast()) };
+        found.ok_or_else(|| MtpError::NoDevice(name.to_string()))
+    }
+
+    /// Returns the errors that the device reported since the last call.
+    fn error(&self) -> MtpError {
+        let mut messages = vec![];
+        // SAFETY: The error stack belongs to the device and is cleared after reading it.
+        unsafe {
+            let mut error = ffi::LIBMTP_Get_Errorstack(self.raw);
+            while !error.is_null() {
+                if !(*error).error_text.is_null() {
+                    messages.push(
+                        CStr::from_ptr((*error).error_text)
+                            .to_string_lossy()
+                            .into_owned(),
+                    );
+                }
+                error = (*error).next;
+            }
+            ffi::LIBMTP_Clear_Errorstack(self.raw);
+        }
+        if messages.is_empty() {
+            messages.push("unknown error".to_string());
+        }
+        MtpError::Device(messages.join(", "))
+    }
+
+    /// Returns the files and folders in the folder with the given ID.
+    fn children(&self, parent: u32) -> Vec<Object> {
+        let mut objects = vec![];
+        // SAFETY: libmtp returns a linked list of files, which is destroyed while walking it.
+        unsafe {
+            let mut file = ffi::LIBMTP_Get_Files_And_Folders(self.raw, 0, parent);
+            while !file.is_null() {
+                let next = (*file).next;
+                if !(*file).filename.is_null() {
+                    objects.push(Object {
+                        id: (*file).item_id,
+                        name: CStr::from_ptr((*file).filename)
+                            .to_string_lossy()
+                            .into_owned(),
+                        size: (*file).filesize,
+                        is_folder: (*file).filetype == ffi::FILETYPE_FOLDER,
+                    });
+                }
+                ffi::LIBMTP_destroy_file_t(file);
+                file = next;
+            }
+            ffi::LIBMTP_Clear_Errorstack(self.raw);
+        }
+        objects
+    }
+
+    fn create_folder(&self, parent: u32, name: &str) -> Result<u32, MtpError> {
+        let name = c_string(name)?;
+        // SAFETY: libmtp copies the name.
+        let id =
+            unsafe { ffi::LIBMTP_Create_Folder(self.raw, name.as_ptr().cast_mut(), parent, 0) };
+        if id == 0 {
+            return Err(self.error());
+        }
+        Ok(id)
+    }
+
+    fn delete(&self, id: u32) -> Result<(), MtpError> {
+        // SAFETY: Deleting takes only the ID.
+        if unsafe { ffi::LIBMTP_Delete_Object(self.raw, id) } != 0 {
+            return Err(self.error());
+        }
+        Ok(())
+    }
+
+    /// Upload the file at `path` as `name` into the folder with the given ID.
+    fn send(&self, path: &Path, name: &str, parent: u32, size: u64) -> Result<(), MtpError> {
+        let c_path = c_string(&path.to_string_lossy())?;
+        let c_name = c_string(name)?;
+        let filetype = match path
+            .extension()
+            .map(|extension| extension.to_string_lossy().to_lowercase())
+            .as_deref()
+        {
+            Some("mp3") => ffi::FILETYPE_MP3,
+            Some("jpg" | "jpeg") => ffi::FILETYPE_JPEG,
+            _ => ffi::FILETYPE_TEXT,
+        };
+        // SAFETY: The file metadata is allocated by libmtp, which also frees the duplicated name
+        // when it is destroyed.
+        unsafe {
+            let file = ffi::LIBMTP_new_file_t();
+            if file.is_null() {
+                return Err(MtpError::Device("out of memory".to_string()));
+            }
+            (*file).filename = libc::strdup(c_name.as_ptr());
+            (*file).filesize = size;
+            (*file).filetype = filetype;
+            (*file).parent_id = parent;
+            (*file).storage_id = 0;
+            let result = ffi::LIBMTP_Send_File_From_File(
+                self.raw,
+                c_path.as_ptr(),
+                file,
+                None,
+                std::ptr::null(),
+            );
+            ffi::LIBMTP_destroy_file_t(file);
+            if result != 0 {
+                return Err(self.error());
+            }
+        }
+        Ok(())
+    }
+}
+
+impl Drop for Device {
+    fn drop(&mut self) {
+        // SAFETY: The device was opened by `open` and is not used afterwards.
+        unsafe { ffi::LIBMTP_Release_Device(self.raw) };
+    }
+}
+
+/// Folders on the device, with their known contents.
+struct Folders<'a> {
+    device: &'a Device,
+    ids: HashMap<PathBuf, u32>,
+    children: HashMap<u32, Vec<Object>>,
+}
+
+impl Folders<'_> {
+    /// Returns the contents of the folder, listing it on first use.
+    fn children(&mut self, id: u32) -> &mut Vec<Object> {
+        let device = self.device;
+        self.children
+            .entry(id)
+            .or_insert_with(|| device.children(id))
+    }
+
+    /// Returns the ID of the folder at `path` below the root, creating missing folders.
+    fn ensure(&mut self, path: &Path) -> Result<u32, MtpError> {
+        if let Some(&id) = self.ids.get(path) {
+            return Ok(id);
+        }
+        let (parent, name) = match (path.parent(), path.file_name()) {
+            (Some(parent), Some(name)) => (self.ensure(parent)?, name.to_string_lossy()),
+            _ => return Ok(ffi::FILES_AND_FOLDERS_ROOT),
+        };
+        let existing = self
+            .children(parent)
+            .iter()
+            .find(|object| object.is_folder && object.name.eq_ignore_ascii_case(&name))
+            .map(|object| object.id);
+        let id = match existing {
+            Some(id) => id,
+            None => {
+                debug!("{}: Creating folder on MTP device", path.display());
+                let id = self.device.create_folder(parent, &name)?;
+                self.children(parent).push(Object {
+                    id,
+                    name: name.to_string(),
+                    size: 0,
+                    is_folder: true,
+                });
+                self.children.insert(id, vec![]);
+                id
+            }
+        };
+        self.ids.insert(path.to_path_buf(), id);
+        Ok(id)
+    }
+}
+
+/// Upload a single file to `remote` below the root of the device, replacing a file of another
+/// size. Returns its size and how long the upload took, or `None` if it was already there.
+fn upload_file(
+    folders: &mut Folders,
+    path: &Path,
+    remote: &Path,
+) -> Result<Option<(u64, Duration)>, MtpError> {
+    let parent = folders.ensure(remote.parent().unwrap_or(Path::new("")))?;
+    let name = remote
+        .file_name()
+        .map(|name| name.to_string_lossy().into_owned())
+        .unwrap_or_default();
+    let size = std::fs::metadata(path).map_err(MtpError::Io)?.len();
+    let existing = folders
+        .children(parent)
+        .iter()
+        .position(|object| !object.is_folder && object.name.eq_ignore_ascii_case(&name));
+    if let Some(position) = existing {
+        let object = folders.children(parent)[position].clone();
+        if object.size == size {
+            return Ok(None);
+        }
+        folders.device.delete(object.id)?;
+        folders.children(parent).remove(position);
+    }
+    let started = Instant::now();
+    folders.device.send(path, &name, parent, size)?;
+    Ok(Some((size, started.elapsed())))
+}
+
+/// Returns the key of the staging directory for the target, from the serial number of its device
+/// and the folder, e.g. `mtp://R58M123456/Music`.
+///
+/// The serial number tells apart devices with the same name, and stays the same when the device
+/// is renamed.
+pub fn staging_key(target: &MtpTarget) -> Result<String, MtpError> {
+    let device = Device::open(&target.device)?;
+    Ok(format!(
+        "mtp://{}/{}",
+        device.serial,
+        target.folder.join("/")
+    ))
+}
+
+/// Upload all files below `dir` into the target folder of the device.
+///
+/// Files that fail to upload are reported as warnings and counted in the summary. An error is
+/// only returned if the device cannot be used at all.
+pub fn upload(dir: &Path, target: &MtpTarget) -> Result<UploadSummary, MtpError> {
+    let files = archive::files(dir).map_err(MtpError::Io)?;
+    let device = Device::open(&target.device)?;
+    info!(
+        "Uploading {} files to MTP device {}...",
+        files.len(),
+        device.name
+    );
+    let mut folders = Folders {
+        device: &device,
+        ids: HashMap::new(),
+        children: HashMap::new(),
+    };
+    let root: PathBuf = target.folder.iter().collect();
+    let mut summary = UploadSummary::default();
+    for (index, relative) in files.iter().enumerate() {
+        if interrupt::is_interrupted() {
+            break;
+        }
+        let remote = root.join(relative);
+        let result = upload_file(&mut folders, &dir.join(relative), &remote);
+        match result {
+            Ok(Some((size, elapsed))) => {
+                summary.uploaded += 1;
+                summary.bytes += size;
+                let rate = (size as f64 / elapsed.as_secs_f64().max(0.001)) as u64;
+                info!(
+                    "({}/{}) {}: Uploaded {} ({}/s).",
+                    index + 1,
+                    files.len(),
+                    remote.display(),
+                    ByteSize(size),
+                    ByteSize(rate)
+                );
+            }
+            Ok(None) => {
+                summary.skipped += 1;
+                debug!(
+                    "({}/{}) {}: Already on the device, skipping",
+                    index + 1,
+                    files.len(),
+                    remote.display()
+                );
+            }
+            Err(e) => {
+                summary.failed += 1;
+                report_warning!(
+                    WarningCategory::Copy,
+                    "({}/{}) {}: Failed to upload ({})",
+                    index + 1,
+                    files.len(),
+                    remote.display(),
+                    e
+                );
+            }
+        }
+    }
+    Ok(summary)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_targets() {
+        assert_eq!(
+            "Pixel 7:/Music/Car".parse(),
+            Ok(MtpTarget {
+                device: "Pixel 7".to_string(),
+                folder: vec!["Music".to_string(), "Car".to_string()],
+            })
+        );
+        assert_eq!(
+            ":/".parse(),
+            Ok(MtpTarget {
+                device: String::new(),
+                folder: vec![],
+            })
+        );
+        assert!("Pixel 7".parse::<MtpTarget>().is_err());
+    }
+}
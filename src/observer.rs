@@ -0,0 +1,197 @@
+// Copyright (c) 2024 Jan Holthuis <jan.holthuis@rub.de>
+//
+// This Source Code Form is subject to the terms of the Mozilla Public License, v. 2.0. If a copy
+// of the MPL was not distributed with this file, You can obtain one at
+// http://mozilla.org/MPL/2.0/.
+//
+// SPDX-License-Identifier: MPL-2.0
+//! Callbacks for following the progress of a sync, e.g. in a user interface.
+//!
+//! # Threading
+//!
+//! Observers are shared between threads, hence the `Send + Sync` bound:
+//!
+//! - [`SyncObserver::task_started`] is called on the worker thread that runs the task, so several
+//!   calls may happen at the same time.
+//! - [`SyncObserver::warning`] is called on the thread that reported the warning, which may also
+//!   be a worker thread.
+//! - All other callbacks are called on the thread that planned or executed the sync, one at a
+//!   time and in order: every `task_finished` comes after the `task_started` of the same task.
+//!
+//! Callbacks should return quickly, since they hold up the run.
+use crate::execute::TaskResult;
+use crate::plan::{Plan, Task};
+use crate::report::Report;
+use crate::warnings::WarningCategory;
+use std::fmt;
+use std::sync::Arc;
+
+/// How far the execution of the plan progressed.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Progress {
+    /// Number of finished tasks, including the current one.
+    pub done: usize,
+    /// Number of tasks that are executed.
+    pub total: usize,
+}
+
+/// Receives events of a sync. All methods do nothing by default.
+pub trait SyncObserver: Send + Sync {
+    /// The plan was computed.
+    fn plan_finished(&self, _plan: &Plan) {}
+
+    /// The task with the given index in [`Plan::tasks`] was started.
+    fn task_started(&self, _index: usize, _task: &Task) {}
+
+    /// A task was finished, successfully or not.
+    fn task_finished(&self, _progress: Progress, _task: &Task, _result: &TaskResult) {}
+
+    /// A warning was reported (and logged) while planning or executing.
+    fn warning(&self, _category: WarningCategory, _message: &str) {}
+
+    /// The run is over, and this is its report.
+    fn run_finished(&self, _report: &Report) {}
+}
+
+/// A list of observers that is notified as a whole.
+#[derive(Clone, Default)]
+pub struct Observers(Vec<Arc<dyn SyncObserver>>);
+
+impl Observers {
+    pub const fn new() -> Self {
+        Self(Vec::new())
+    }
+
+    pub fn push(&mut self, observer: Arc<dyn SyncObserver>) {
+        self.0.push(observer);
+    }
+
+    pub(crate) fn iter(&self) -> impl Iterator<Item = &Arc<dyn SyncObserver>> {
+        self.0.iter()
+    }
+
+    /// Remove the observers that are also in `other`.
+    pub(crate) fn remove(&mut self, other: &Observers) {
+        self.0
+            .retain(|observer| !other.iter().any(|o| Arc::ptr_eq(o, observer)));
+    }
+
+    pub(crate) fn extend(&mut self, other: &Observers) {
+        self.0.extend(other.iter().cloned());
+    }
+}
+
+impl fmt::Debug for Observers {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Observers({})", self.0.len())
+    }
+}
+
+impl SyncObserver for Observers {
+    fn plan_finished(&self, plan: &Plan) {
+        self.0.iter().for_each(|o| o.plan_finished(plan));
+    }
+
+    fn task_started(&self, index: usize, task: &Task) {
+        self.0.iter().for_each(|o| o.task_started(index, task));
+    }
+
+    fn task_finished(&self, progress: Progress, task: &Task, result: &TaskResult) {
+        self.0
+            .iter()
+            .for_each(|o| o.task_finished(progress, task, result));
+    }
+
+    fn warning(&self, category: WarningCategory, message: &str) {
+        self.0.iter().for_each(|o| o.warning(category, message));
+    }
+
+    fn run_finished(&self, report: &Report) {
+        self.0.iter().for_each(|o| o.run_finished(report));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::execute::{self, ExecuteOptions, TaskStatus};
+    use crate::plan::Action;
+    use crate::tags::TagOverrides;
+    use std::sync::Mutex;
+    use std::thread::ThreadId;
+
+    #[derive(Default)]
+    struct Recorder {
+        started: Mutex<Vec<(usize, ThreadId)>>,
+        finished: Mutex<Vec<(Progress, TaskStatus, ThreadId)>>,
+    }
+
+    impl SyncObserver for Recorder {
+        fn task_started(&self, index: usize, _task: &Task) {
+            let thread = std::thread::current().id();
+            self.started.lock().unwrap().push((index, thread));
+        }
+
+        fn task_finished(&self, progress: Progress, _task: &Task, result: &TaskResult) {
+            let thread = std::thread::current().id();
+            self.finished
+                .lock()
+                .unwrap()
+                .push((progress, result.status, thread));
+        }
+    }
+
+    #[test]
+    fn tasks_start_on_workers_and_finish_on_the_calling_thread() {
+        let dir = std::env::temp_dir().join(format!("ford-sync-observer-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let tasks: Vec<_> = (0..3)
+            .map(|i| {
+                let source = dir.join(format!("{}.mp3", i));
+                std::fs::write(&source, "DATA").unwrap();
+                Task {
+                    action: Action::Copy,
+                    source,
+                    destination: dir.join(format!("out/{}.mp3", i)),
+                    tags: TagOverrides::default(),
+                    encoder: None,
+                    segment: None,
+                }
+            })
+            .collect();
+        let plan = Plan {
+            tasks,
+            ..Default::default()
+        };
+        let recorder = Arc::new(Recorder::default());
+        let mut observers = Observers::new();
+        observers.push(recorder.clone());
+        let options = ExecuteOptions {
+            output_dir: dir.join("out"),
+            ..Default::default()
+        };
+        execute::execute(&plan, &options, &observers);
+        std::fs::remove_dir_all(&dir).unwrap();
+
+        let current = std::thread::current().id();
+        let mut started = recorder.started.lock().unwrap().clone();
+        started.sort_by_key(|(index, _)| *index);
+        assert_eq!(
+            started.iter().map(|(index, _)| *index).collect::<Vec<_>>(),
+            [0, 1, 2]
+        );
+        assert!(started.iter().all(|(_, thread)| *thread != current));
+
+        let finished = recorder.finished.lock().unwrap();
+        assert_eq!(
+            finished
+                .iter()
+                .map(|(progress, _, _)| progress.done)
+                .collect::<Vec<_>>(),
+            [1, 2, 3]
+        );
+        assert!(finished.iter().all(|(progress, status, thread)| {
+            progress.total == 3 && *status == TaskStatus::Succeeded && *thread == current
+        }));
+    }
+}
@@ -0,0 +1,87 @@
+// Copyright (c) 2024 Jan Holthuis <jan.holthuis@rub.de>
+//
+// This Source Code Form is subject to the terms of the Mozilla Public License, v. 2.0. If a copy
+// of the MPL was not distributed with this file, You can obtain one at
+// http://mozilla.org/MPL/2.0/.
+//
+// SPDX-License-Identifier: MPL-2.0
+//! Detection of output directories that overlap with the source tree.
+use crate::plan::InputPlaylist;
+use std::fmt;
+use std::path::{Path, PathBuf};
+
+#[derive(Debug)]
+pub enum OverlapError {
+    /// The output directory is located inside the directory of a playlist.
+    InsidePlaylistDir {
+        output_dir: PathBuf,
+        playlist: PathBuf,
+    },
+    /// A source file is located inside the output directory.
+    ContainsSource {
+        output_dir: PathBuf,
+        source: PathBuf,
+    },
+}
+
+impl fmt::Display for OverlapError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            OverlapError::InsidePlaylistDir {
+                output_dir,
+                playlist,
+            } => write!(
+                f,
+                "Output directory {} is inside the directory of playlist {}, so earlier outputs \
+                 may be picked up as sources (use `--allow-overlap` to continue anyway)",
+                output_dir.display(),
+                playlist.display()
+            ),
+            OverlapError::ContainsSource { output_dir, source } => write!(
+                f,
+                "Output directory {} contains source file {}, which may be overwritten (use \
+                 `--allow-overlap` to continue anyway)",
+                output_dir.display(),
+                source.display()
+            ),
+        }
+    }
+}
+
+impl std::error::Error for OverlapError {}
+
+/// Canonicalize a path that may not exist yet by canonicalizing its nearest existing ancestor.
+pub fn canonicalize_lenient(path: &Path) -> PathBuf {
+    let absolute = std::path::absolute(path).unwrap_or_else(|_| path.to_path_buf());
+    for ancestor in absolute.ancestors() {
+        if let Ok(canonical) = ancestor.canonicalize() {
+            let rest = absolute.strip_prefix(ancestor).unwrap_or(Path::new(""));
+            return canonical.join(rest);
+        }
+    }
+    absolute
+}
+
+/// Check that the output directory neither lies inside a playlist's directory nor contains any of
+/// the sources.
+pub fn check(output_dir: &Path, input_playlists: &[InputPlaylist]) -> Result<(), OverlapError> {
+    let canonical_output_dir = canonicalize_lenient(output_dir);
+    for input_playlist in input_playlists {
+        let playlist_dir = canonicalize_lenient(&input_playlist.dir());
+        if canonical_output_dir.starts_with(&playlist_dir) {
+            return Err(OverlapError::InsidePlaylistDir {
+                output_dir: output_dir.to_path_buf(),
+                playlist: input_playlist.path.clone(),
+            });
+        }
+        for source in input_playlist.sources() {
+            if canonicalize_lenient(&source).starts_with(&canonical_output_dir) {
+                return Err(OverlapError::ContainsSource {
+                    output_dir: output_dir.to_path_buf(),
+                    source,
+                });
+            }
+        }
+    }
+    Ok(())
+}
@@ -0,0 +1,2243 @@
+// Copyright (c) 2024 Jan Holthuis <jan.holthuis@rub.de>
+//
+// This Source Code Form is subject to the terms of the Mozilla Public License, v. 2.0. If a copy
+// of the MPL was not distributed with this file, You can obtain one at
+// http://mozilla.org/MPL/2.0/.
+//
+// SPDX-License-Identifier: MPL-2.0
+use crate::compilation;
+use crate::dedupe::Duplicate;
+use crate::estimate;
+use crate::exclude::{Excludes, Extensions};
+use crate::filter::TagFilter;
+use crate::genre::GenreMap;
+use crate::layout::{self, Layout, SharedTracks, Structure};
+use crate::overlap;
+use crate::playlist::{self, Location};
+use crate::probe::ProbeInfo;
+use crate::rules::{EncoderSettings, Rules};
+use crate::sanitize::{self, LengthLimits};
+use crate::segment::{self, Part, Segment};
+use crate::tags::TagOverrides;
+use crate::target::TargetPath;
+use crate::template::{FilenamePattern, Template};
+use crate::transcode::Encoding;
+use crate::transliterate::{self, TransliterationStyle};
+use crate::warnings::{report_warning, WarningCategory};
+use clap::ValueEnum;
+use log::{debug, info, warn};
+use serde::{Deserialize, Serialize};
+use std::collections::{hash_map, BTreeMap, HashMap, HashSet};
+use std::ffi::OsString;
+use std::fmt;
+use std::path::{Path, PathBuf};
+
+/// What to do when two different sources would end up at the same output path.
+#[derive(ValueEnum, Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum ConflictPolicy {
+    /// Abort planning.
+    #[default]
+    Error,
+    /// Append a number to the file name of the later entry.
+    Rename,
+}
+
+/// What to do with sources outside of the `--music-root`.
+#[derive(ValueEnum, Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum OutsideMusicRoot {
+    /// Abort planning.
+    #[default]
+    Error,
+    /// Derive the output path from the playlist entry, as without a music root.
+    Fallback,
+}
+
+/// How a source file is transferred to the output directory.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Action {
+    Copy,
+    Convert,
+    /// Copy the MP3 stream of a source in another container, like `.m4a`, into an MP3 file
+    /// without encoding it again.
+    Remux,
+}
+
+/// A single file that needs to be copied or converted.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct Task {
+    pub action: Action,
+    pub source: PathBuf,
+    pub destination: PathBuf,
+    /// Tags to set on the output instead of those of the source.
+    #[serde(default, skip_serializing_if = "TagOverrides::is_empty")]
+    pub tags: TagOverrides,
+    /// Encoder settings of the rule that matches the source, for conversions.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub encoder: Option<EncoderSettings>,
+    /// Part of the source to convert, if it is split into several outputs.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub segment: Option<Segment>,
+}
+
+impl Task {
+    /// Returns the duration of the output in seconds, if the source was probed.
+    pub fn duration(&self, metadata: &HashMap<PathBuf, ProbeInfo>) -> Option<f64> {
+        let duration = metadata.get(&self.source).and_then(|info| info.duration);
+        match self.segment {
+            Some(segment) => segment.duration(duration),
+            None => duration,
+        }
+    }
+
+    /// Returns how the source of a conversion or remux is turned into an MP3 stream.
+    pub fn encoding(&self) -> Encoding<'_> {
+        match self.action {
+            Action::Remux => Encoding::Remux,
+            Action::Copy | Action::Convert => Encoding::Encode(self.encoder.as_ref()),
+        }
+    }
+}
+
+/// Why a playlist entry is not part of the output.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub enum SkipReason {
+    /// The entry could not be read from the playlist.
+    Unreadable(String),
+    /// The entry is a URL instead of a local file.
+    Url,
+    /// The file extension could not be determined.
+    NoExtension,
+    /// The output would exceed the maximum file size of the target filesystem.
+    TooLarge { size: u64, limit: u64 },
+    /// The entry was cut to make the output fit into `--max-total-size`.
+    OverSizeBudget,
+    /// The entry was cut to keep the number of output files within `--max-total-files`.
+    OverFileBudget,
+    /// The entry was cut to keep the playlist within `--max-playlist-entries`.
+    OverPlaylistLimit,
+    /// The output path cannot be shortened to the path length limit.
+    PathTooLong { max_path: usize },
+    /// The source file is empty.
+    EmptySource,
+    /// The probe found no audio stream in the source file.
+    NoAudioStream,
+    /// The source path matches an `--exclude` pattern.
+    Excluded { pattern: String },
+    /// The source extension is not in `--include-ext`.
+    ExtensionNotIncluded { extension: String },
+    /// The source was rejected by a tag filter such as `--skip-genre`.
+    Filtered { reason: String },
+    /// The source is shorter than `--min-duration`.
+    TooShort { duration: f64, min_duration: f64 },
+    /// The source was not modified since the cutoff of `--since`, in RFC 3339 format.
+    NotModifiedSince { since: String },
+    /// The song of a server playlist like one from `--mpd` does not exist locally.
+    NotAccessible { path: Option<String> },
+}
+
+impl SkipReason {
+    /// Returns `true` if the source file is empty or corrupt.
+    pub fn is_corrupt_source(&self) -> bool {
+        matches!(self, SkipReason::EmptySource | SkipReason::NoAudioStream)
+    }
+
+    /// Returns `true` if the entry was rejected by a tag, duration or `--since` filter.
+    pub fn is_filtered(&self) -> bool {
+        matches!(
+            self,
+            SkipReason::Filtered { .. }
+                | SkipReason::TooShort { .. }
+                | SkipReason::NotModifiedSince { .. }
+        )
+    }
+
+    /// Returns `true` if the entry was deliberately left out by `--exclude` or `--include-ext`.
+    pub fn is_excluded(&self) -> bool {
+        matches!(
+            self,
+            SkipReason::Excluded { .. } | SkipReason::ExtensionNotIncluded { .. }
+        )
+    }
+}
+
+impl fmt::Display for SkipReason {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            SkipReason::Unreadable(e) => write!(f, "Failed to read playlist entry ({})", e),
+            SkipReason::Url => write!(f, "Ignoring URL"),
+            SkipReason::NoExtension => write!(f, "Failed to determine file extension"),
+            SkipReason::TooLarge { size, limit } => write!(
+                f,
+                "Output of {} bytes exceeds the target filesystem's file size limit of {} bytes",
+                size, limit
+            ),
+            SkipReason::OverSizeBudget => write!(f, "Cut to fit `--max-total-size`"),
+            SkipReason::OverFileBudget => write!(f, "Cut to fit `--max-total-files`"),
+            SkipReason::OverPlaylistLimit => write!(f, "Cut to fit `--max-playlist-entries`"),
+            SkipReason::PathTooLong { max_path } => write!(
+                f,
+                "Output path cannot be shortened to {} characters",
+                max_path
+            ),
+            SkipReason::EmptySource => write!(f, "Source file is empty"),
+            SkipReason::NoAudioStream => write!(f, "Source file has no audio stream"),
+            SkipReason::Excluded { pattern } => write!(f, "Excluded by `{}`", pattern),
+            SkipReason::ExtensionNotIncluded { extension } => {
+                write!(f, "Extension `.{}` is not included", extension)
+            }
+            SkipReason::Filtered { reason } => write!(f, "Filtered ({})", reason),
+            SkipReason::TooShort {
+                duration,
+                min_duration,
+            } => write!(
+                f,
+                "Duration of {:.1}s is shorter than {}s",
+                duration, min_duration
+            ),
+            SkipReason::NotModifiedSince { since } => write!(f, "Not modified since {}", since),
+            SkipReason::NotAccessible { path: None } => write!(f, "Not accessible locally"),
+            SkipReason::NotAccessible { path: Some(path) } => {
+                write!(f, "Not accessible locally at {}", path)
+            }
+        }
+    }
+}
+
+/// A playlist entry that is not part of the output.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct SkippedEntry {
+    pub entry: String,
+    pub reason: SkipReason,
+}
+
+/// An entry of an output playlist.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct OutputEntry {
+    /// The path as written into the playlist.
+    pub target: String,
+    /// Index of the task in [`Plan::tasks`] that produces the file.
+    pub task: usize,
+}
+
+/// A playlist that will be written to the output directory.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct PlaylistOutput {
+    pub source: PathBuf,
+    pub path: PathBuf,
+    pub entries: Vec<OutputEntry>,
+    pub skipped: Vec<SkippedEntry>,
+    /// Name given by the input playlist, see [`InputPlaylist::title`].
+    #[serde(default)]
+    pub title: Option<String>,
+}
+
+/// Everything that needs to happen to sync the given playlists.
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+pub struct Plan {
+    pub playlists: Vec<PlaylistOutput>,
+    pub tasks: Vec<Task>,
+    /// Results of the probe pass, keyed by source path.
+    pub metadata: HashMap<PathBuf, ProbeInfo>,
+    /// Sources that were merged into others by `--dedupe-by-tag` or `--dedupe-by-fingerprint`.
+    #[serde(default)]
+    pub duplicates: Vec<Duplicate>,
+    /// Original paths of the outputs renamed by `--short-names`, keyed by their short path, both
+    /// relative to the output directory.
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub short_names: BTreeMap<PathBuf, PathBuf>,
+}
+
+impl Plan {
+    /// Move all output paths from the output directory `from` to `to`.
+    pub fn relocate(&mut self, from: &Path, to: &Path) {
+        let relocate = |path: &mut PathBuf| {
+            if let Ok(relative) = path.strip_prefix(from) {
+                *path = to.join(relative);
+            }
+        };
+        for playlist in self.playlists.iter_mut() {
+            relocate(&mut playlist.path);
+        }
+        for task in self.tasks.iter_mut() {
+            relocate(&mut task.destination);
+        }
+    }
+
+    pub fn tasks(&self, action: Action) -> impl Iterator<Item = &Task> {
+        self.tasks.iter().filter(move |task| task.action == action)
+    }
+
+    /// Remove tasks that are no longer referenced by any playlist entry.
+    pub fn remove_unreferenced_tasks(&mut self) {
+        let mut referenced = vec![false; self.tasks.len()];
+        for entry in self.playlists.iter().flat_map(|playlist| &playlist.entries) {
+            referenced[entry.task] = true;
+        }
+        let mut new_indices = Vec::with_capacity(self.tasks.len());
+        let mut next_index = 0;
+        for &is_referenced in referenced.iter() {
+            new_indices.push(next_index);
+            if is_referenced {
+                next_index += 1;
+            }
+        }
+        let mut is_referenced = referenced.into_iter();
+        self.tasks.retain(|_| is_referenced.next().unwrap_or(false));
+        for entry in self
+            .playlists
+            .iter_mut()
+            .flat_map(|playlist| &mut playlist.entries)
+        {
+            entry.task = new_indices[entry.task];
+        }
+    }
+}
+
+#[derive(Debug)]
+pub enum PlanError {
+    /// Two different sources map to the same output path (on a case-insensitive filesystem).
+    Collision {
+        path: PathBuf,
+        first: PathBuf,
+        second: PathBuf,
+    },
+    /// An input playlist could not be opened.
+    ReadPlaylist {
+        path: PathBuf,
+        error: std::io::Error,
+    },
+    /// An input playlist is in none of the supported formats.
+    UnsupportedPlaylist { path: PathBuf },
+    /// An input directory could not be read.
+    ScanDirectory {
+        path: PathBuf,
+        error: std::io::Error,
+    },
+    /// A source is not below the music root.
+    OutsideMusicRoot {
+        source: PathBuf,
+        music_root: PathBuf,
+    },
+}
+
+impl fmt::Display for PlanError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            PlanError::Collision {
+                path,
+                first,
+                second,
+            } => write!(
+                f,
+                "{}: Output path collision between {} and {} (use `--on-conflict rename` to \
+                 disambiguate)",
+                path.display(),
+                first.display(),
+                second.display()
+            ),
+            PlanError::ReadPlaylist { path, error } => {
+                write!(f, "{}: Failed to open playlist ({})", path.display(), error)
+            }
+            PlanError::UnsupportedPlaylist { path } => write!(
+                f,
+                "{}: Unsupported playlist format (supported formats: {})",
+                path.display(),
+                playlist::supported_formats()
+            ),
+            PlanError::ScanDirectory { path, error } => {
+                write!(
+                    f,
+                    "{}: Failed to scan directory ({})",
+                    path.display(),
+                    error
+                )
+            }
+            PlanError::OutsideMusicRoot { source, music_root } => write!(
+                f,
+                "{}: Source is outside of the music root {} (use `--outside-music-root \
+                 fallback` to place it by its playlist entry)",
+                source.display(),
+                music_root.display()
+            ),
+        }
+    }
+}
+
+impl std::error::Error for PlanError {}
+
+/// Read a list of playlist paths with one path per line, e.g. for `--playlists-from`.
+///
+/// Empty lines and lines starting with `#` are ignored. Relative paths are resolved against the
+/// directory of the list file.
+pub fn read_playlist_list(path: &Path) -> std::io::Result<Vec<PathBuf>> {
+    let contents = std::fs::read_to_string(path)?;
+    let base_dir = path.parent().unwrap_or(Path::new(""));
+    Ok(contents
+        .lines()
+        .map(str::trim)
+        .filter(|line| !line.is_empty() && !line.starts_with('#'))
+        .map(|line| base_dir.join(line))
+        .collect())
+}
+
+/// A playlist read from disk.
+#[derive(Clone, Debug)]
+pub struct InputPlaylist {
+    pub path: PathBuf,
+    /// Entries as written in the playlist, i.e. relative to the playlist's directory.
+    pub entries: Vec<PathBuf>,
+    /// Entries that could not be used.
+    pub skipped: Vec<SkippedEntry>,
+    /// Name given by the playlist itself, e.g. with an M3U `#PLAYLIST` line.
+    pub title: Option<String>,
+    /// Durations in seconds that the playlist gives for its entries.
+    pub durations: HashMap<PathBuf, f64>,
+}
+
+impl InputPlaylist {
+    /// Read a playlist in any of the supported formats from disk.
+    pub fn read(input_playlist_path: &Path) -> Result<Self, PlanError> {
+        info!("Parsing Playlist: {}", input_playlist_path.display());
+        let contents =
+            std::fs::read(input_playlist_path).map_err(|error| PlanError::ReadPlaylist {
+                path: input_playlist_path.to_path_buf(),
+                error,
+            })?;
+        let reader = playlist::reader_for(input_playlist_path, &contents).ok_or_else(|| {
+            PlanError::UnsupportedPlaylist {
+                path: input_playlist_path.to_path_buf(),
+            }
+        })?;
+        let mut entries = vec![];
+        let mut skipped = vec![];
+        let mut durations = HashMap::new();
+        for result in reader.read(&contents) {
+            let duration = result.as_ref().ok().and_then(|entry| entry.duration);
+            match result.map(|entry| entry.location) {
+                Ok(Location::Path(path)) => {
+                    if let Some(duration) = duration {
+                        durations.insert(path.clone(), duration);
+                    }
+                    entries.push(path);
+                }
+                Ok(Location::Url(url)) => {
+                    report_warning!(WarningCategory::Url, "Ignoring URL: {}", url);
+                    skipped.push(SkippedEntry {
+                        entry: url,
+                        reason: SkipReason::Url,
+                    });
+                }
+                Err(e) => {
+                    report_warning!(
+                        WarningCategory::UnreadableEntry,
+                        "Failed to read playlist entry: {}",
+                        e
+                    );
+                    skipped.push(SkippedEntry {
+                        entry: String::new(),
+                        reason: SkipReason::Unreadable(e),
+                    });
+                }
+            }
+        }
+        let title = reader.title(&contents);
+        if let Some(title) = &title {
+            debug!(
+                "{}: Using playlist name `{}`",
+                input_playlist_path.display(),
+                title
+            );
+        }
+        Ok(Self {
+            path: input_playlist_path.to_path_buf(),
+            entries,
+            skipped,
+            title,
+            durations,
+        })
+    }
+
+    /// The directory that entries are relative to.
+    pub fn dir(&self) -> PathBuf {
+        self.path
+            .parent()
+            .map(|parent| {
+                if parent == Path::new("") {
+                    PathBuf::from(".")
+                } else {
+                    parent.to_path_buf()
+                }
+            })
+            .unwrap()
+    }
+
+    /// Resolve a playlist entry to the path of the source file.
+    pub fn source(&self, entry: &Path) -> PathBuf {
+        self.dir().join(entry)
+    }
+
+    pub fn sources(&self) -> impl Iterator<Item = PathBuf> + '_ {
+        self.entries.iter().map(|entry| self.source(entry))
+    }
+
+    /// The name of the playlist as shown to the user, i.e. its [`title`](Self::title) or else
+    /// its file name without extension.
+    pub fn name(&self) -> String {
+        self.title.clone().unwrap_or_else(|| {
+            self.path
+                .file_stem()
+                .unwrap_or_default()
+                .to_string_lossy()
+                .into_owned()
+        })
+    }
+
+    /// The file name of the output playlist, before sanitization.
+    fn output_file_name(&self) -> PathBuf {
+        let file_name = playlist::output_file_name(Path::new(self.path.file_name().unwrap()));
+        match &self.title {
+            Some(title) => {
+                let extension = file_name.extension().unwrap_or_default();
+                PathBuf::from(format!(
+                    "{}.{}",
+                    layout::component(title),
+                    extension.to_string_lossy()
+                ))
+            }
+            None => file_name,
+        }
+    }
+}
+
+/// Computes the [`Plan`] from a list of playlists.
+#[derive(Debug)]
+pub struct Planner {
+    output_dir: PathBuf,
+    conflict_policy: ConflictPolicy,
+    /// Maximum size of a single output file on the target filesystem.
+    max_file_size: Option<u64>,
+    /// Where audio files and playlists are placed.
+    layout: Layout,
+    /// How output files are arranged.
+    structure: Structure,
+    /// Directory that output paths are relative to instead of the playlist, canonicalized.
+    music_root: Option<PathBuf>,
+    /// What to do with sources outside of the music root.
+    outside_music_root: OutsideMusicRoot,
+    /// Place the audio files of every playlist in a folder of its own, if enabled.
+    group_by_playlist: Option<SharedTracks>,
+    /// Prefix output file names with their position in the playlist.
+    prefix_position: bool,
+    /// Output paths and playlist names of the sources placed so far with `SharedTracks::Single`
+    /// or `prefix_position`, keyed by audio root (empty for `SharedTracks::Single`) and source.
+    placed: HashMap<(PathBuf, PathBuf), (PathBuf, String)>,
+    /// Template for output file names.
+    filename_template: Option<Template>,
+    /// Albums (keyed by album artist and album) with more than one disc.
+    multi_disc_albums: HashSet<(String, String)>,
+    /// Maximum directory depth of output paths.
+    flatten: Option<usize>,
+    /// ASCII transliteration style for output paths, if enabled.
+    transliterate: Option<TransliterationStyle>,
+    /// Replacement character for sanitizing output paths, if enabled.
+    sanitize: Option<char>,
+    /// Maximum lengths of output paths.
+    length_limits: Option<LengthLimits>,
+    /// Patterns of sources to leave out.
+    excludes: Excludes,
+    extensions: Extensions,
+    tag_filter: TagFilter,
+    /// Minimum duration of sources in seconds.
+    min_duration: Option<f64>,
+    /// Longest duration of sources in seconds that are not split into parts.
+    split_long_files: Option<f64>,
+    /// Output paths seen so far, keyed by their FAT-folded form.
+    destinations: HashMap<PathBuf, (PathBuf, PathBuf)>,
+    /// Set the album of every output file to the name of its playlist.
+    album_from_playlist: bool,
+    /// Keep the original album of sources whose album is replaced.
+    keep_original_album: bool,
+    /// Set the track number of every output file to its position in the playlist.
+    tracknum_from_position: bool,
+    /// Pattern to read missing tags from the file names of sources.
+    tags_from_filename: Option<FilenamePattern>,
+    /// Replace existing tags with those from the file name.
+    tags_from_filename_force: bool,
+    /// Album artist to set on compilations, if enabled.
+    compilation_artist: Option<String>,
+    /// Sources that belong to compilations, found on first use.
+    compilations: Option<HashSet<PathBuf>>,
+    /// Mapping of genres, if enabled.
+    genre_map: Option<GenreMap>,
+    /// Also map the genres of copied files.
+    map_copied_genres: bool,
+    /// Encoder settings per source extension or codec.
+    rules: Rules,
+    /// Name of the first playlist and position in it, keyed by output path.
+    first_playlists: HashMap<PathBuf, (String, u32)>,
+    /// Indices of the tasks in the plan, keyed by source and destination, so that every output
+    /// file is only written once.
+    task_indices: HashMap<(PathBuf, PathBuf), usize>,
+    plan: Plan,
+}
+
+impl Planner {
+    pub fn new(output_dir: &Path, conflict_policy: ConflictPolicy) -> Self {
+        Self {
+            output_dir: output_dir.to_path_buf(),
+            conflict_policy,
+            max_file_size: None,
+            layout: Layout::Flat,
+            structure: Structure::Mirror,
+            music_root: None,
+            outside_music_root: OutsideMusicRoot::Error,
+            group_by_playlist: None,
+            prefix_position: false,
+            placed: HashMap::new(),
+            filename_template: None,
+            multi_disc_albums: HashSet::new(),
+            flatten: None,
+            transliterate: None,
+            sanitize: None,
+            length_limits: None,
+            excludes: Excludes::default(),
+            extensions: Extensions::default(),
+            tag_filter: TagFilter::default(),
+            min_duration: None,
+            split_long_files: None,
+            destinations: HashMap::new(),
+            album_from_playlist: false,
+            keep_original_album: false,
+            tracknum_from_position: false,
+            tags_from_filename: None,
+            tags_from_filename_force: false,
+            compilation_artist: None,
+            compilations: None,
+            genre_map: None,
+            map_copied_genres: false,
+            rules: Rules::default(),
+            first_playlists: HashMap::new(),
+            task_indices: HashMap::new(),
+            plan: Plan::default(),
+        }
+    }
+
+    /// Skip entries whose output would be larger than `limit` bytes.
+    pub fn set_max_file_size(&mut self, limit: Option<u64>) {
+        self.max_file_size = limit;
+    }
+
+    /// Place audio files and playlists according to `layout`.
+    pub fn set_layout(&mut self, layout: Layout) {
+        self.layout = layout;
+    }
+
+    /// Arrange output files according to `structure`.
+    pub fn set_structure(&mut self, structure: Structure) {
+        self.structure = structure;
+    }
+
+    /// Derive output paths from the location of the sources below `root` instead of from the
+    /// playlist entries, so that they do not depend on where the playlists are.
+    pub fn set_music_root(&mut self, root: Option<&Path>, outside: OutsideMusicRoot) {
+        self.music_root = root.map(overlap::canonicalize_lenient);
+        self.outside_music_root = outside;
+    }
+
+    /// Returns the path that the output path of `source` is derived from: its location below the
+    /// music root if there is one, and the playlist entry otherwise.
+    fn source_path(&self, source: &Path, entry: &Path) -> Result<PathBuf, PlanError> {
+        let Some(music_root) = &self.music_root else {
+            return Ok(entry.to_path_buf());
+        };
+        if let Ok(relative) = overlap::canonicalize_lenient(source).strip_prefix(music_root) {
+            return Ok(relative.to_path_buf());
+        }
+        match self.outside_music_root {
+            OutsideMusicRoot::Error => Err(PlanError::OutsideMusicRoot {
+                source: source.to_path_buf(),
+                music_root: music_root.clone(),
+            }),
+            OutsideMusicRoot::Fallback => {
+                warn!(
+                    "{}: Source is outside of the music root, placing it by its playlist entry",
+                    source.display()
+                );
+                Ok(entry.to_path_buf())
+            }
+        }
+    }
+
+    /// Place the audio files of every playlist in a folder named after it below the audio root,
+    /// treating tracks in several playlists according to `shared`.
+    pub fn set_group_by_playlist(&mut self, shared: Option<SharedTracks>) {
+        self.group_by_playlist = shared;
+    }
+
+    /// Prefix output file names with their zero-padded position in the playlist, e.g.
+    /// `001 - Track.mp3`. Tracks in several playlists keep the position in the first one, unless
+    /// they are duplicated into per-playlist folders.
+    pub fn set_prefix_position(&mut self, enabled: bool) {
+        self.prefix_position = enabled;
+    }
+
+    /// Returns the folder (relative to the output directory) that holds the audio files of
+    /// `input_playlist`.
+    fn audio_root(&self, input_playlist: &InputPlaylist) -> PathBuf {
+        let root = self.layout.audio_root();
+        if self.group_by_playlist.is_none() {
+            return root.to_path_buf();
+        }
+        let name = PathBuf::from(layout::component(&input_playlist.name()));
+        let folder = self.output_path(Path::new(""), &name).unwrap_or(name);
+        root.join(folder)
+    }
+
+    /// Name output files according to `template`.
+    pub fn set_filename_template(&mut self, template: Option<Template>) {
+        self.filename_template = template;
+    }
+
+    /// Returns the output path (relative to the output directory) of an audio file before
+    /// flattening, sanitization and length limits are applied.
+    fn structured_path(&self, source: &Path, path: &Path) -> PathBuf {
+        let structured = match self.structure {
+            Structure::Mirror => path.to_path_buf(),
+            Structure::ArtistAlbum => {
+                let info = self.plan.metadata.get(source);
+                let multi_disc = info.is_some_and(|info| {
+                    self.multi_disc_albums.contains(&album_key(info))
+                        || info
+                            .tag("disc")
+                            .and_then(|disc| disc.split_once('/'))
+                            .and_then(|(_, total)| total.trim().parse::<u32>().ok())
+                            .is_some_and(|total| total > 1)
+                });
+                layout::artist_album(path, info, multi_disc)
+            }
+        };
+        let Some(template) = &self.filename_template else {
+            return structured;
+        };
+        let stem = path.file_stem().unwrap_or_default().to_string_lossy();
+        let mut file_name = template.render(self.plan.metadata.get(source), &stem);
+        if file_name.trim().is_empty() {
+            file_name = stem.into_owned();
+        }
+        if let Some(extension) = path.extension() {
+            file_name.push('.');
+            file_name.push_str(&extension.to_string_lossy());
+        }
+        structured.with_file_name(file_name)
+    }
+
+    /// Reduce the directory hierarchy of output paths to at most `depth` levels.
+    pub fn set_flatten(&mut self, depth: Option<usize>) {
+        self.flatten = depth;
+    }
+
+    /// Transliterate output paths to ASCII in the given style.
+    pub fn set_transliterate(&mut self, style: Option<TransliterationStyle>) {
+        self.transliterate = style;
+    }
+
+    /// Sanitize output paths for FAT filesystems, replacing invalid characters with
+    /// `replacement`.
+    pub fn set_sanitize(&mut self, replacement: Option<char>) {
+        self.sanitize = replacement;
+    }
+
+    /// Shorten output paths that exceed the given limits.
+    pub fn set_length_limits(&mut self, limits: Option<LengthLimits>) {
+        self.length_limits = limits;
+    }
+
+    /// Skip entries whose source path matches one of the patterns.
+    pub fn set_excludes(&mut self, excludes: Excludes) {
+        self.excludes = excludes;
+    }
+
+    /// Skip entries whose source extension is not included.
+    pub fn set_extensions(&mut self, extensions: Extensions) {
+        self.extensions = extensions;
+    }
+
+    /// Skip entries whose tags are rejected by the filter.
+    pub fn set_tag_filter(&mut self, tag_filter: TagFilter) {
+        self.tag_filter = tag_filter;
+    }
+
+    /// Skip entries that are shorter than `seconds`. Entries of unknown duration are kept.
+    pub fn set_min_duration(&mut self, seconds: Option<f64>) {
+        self.min_duration = seconds;
+    }
+
+    /// Split sources that are longer than `seconds` or have chapter markers into parts, which
+    /// needs metadata from the probe pass.
+    pub fn set_split_long_files(&mut self, seconds: Option<f64>) {
+        self.split_long_files = seconds;
+    }
+
+    /// Set the album of every output file to the name of the (first) playlist it is part of.
+    ///
+    /// With `keep_original`, the album of the source is kept in the original title frame, which
+    /// needs metadata from the probe pass.
+    pub fn set_album_from_playlist(&mut self, enabled: bool, keep_original: bool) {
+        self.album_from_playlist = enabled;
+        self.keep_original_album = keep_original;
+    }
+
+    /// Set the track number of every output file to its position in the (first) playlist it is
+    /// part of, and clear its disc number.
+    pub fn set_tracknum_from_position(&mut self, enabled: bool) {
+        self.tracknum_from_position = enabled;
+    }
+
+    /// Read missing tags from the file names of sources.
+    ///
+    /// With `force`, tags are read from all file names that match and replace existing tags.
+    /// Needs metadata from the probe pass.
+    pub fn set_tags_from_filename(&mut self, pattern: Option<FilenamePattern>, force: bool) {
+        self.tags_from_filename = pattern;
+        self.tags_from_filename_force = force;
+    }
+
+    /// Mark outputs of compilations as such and set their album artist to `artist` if they have
+    /// none. Needs metadata from the probe pass.
+    pub fn set_compilation_artist(&mut self, artist: Option<String>) {
+        self.compilation_artist = artist;
+    }
+
+    /// Map the genres of converted files, and of copied files if `copies` is set. Needs metadata
+    /// from the probe pass.
+    pub fn set_genre_map(&mut self, map: Option<GenreMap>, copies: bool) {
+        self.genre_map = map;
+        self.map_copied_genres = copies;
+    }
+
+    /// Encode the sources that match a rule with its settings. Rules for codecs need metadata
+    /// from the probe pass. Sources with a matching rule are encoded even if their MP3 stream
+    /// could be remuxed.
+    pub fn set_rules(&mut self, rules: Rules) {
+        self.rules = rules;
+    }
+
+    /// Returns the tags to set on the output at `destination` for the entry at the 1-based
+    /// `position` of `input_playlist`.
+    ///
+    /// An output that is part of several playlists gets the tags from the first of them.
+    fn tag_overrides(
+        &mut self,
+        action: Action,
+        source: &Path,
+        destination: &Path,
+        input_playlist: &InputPlaylist,
+        position: u32,
+    ) -> TagOverrides {
+        let mut tags = TagOverrides::default();
+        let info = self.plan.metadata.get(source);
+        if let Some(pattern) = &self.tags_from_filename {
+            let stem = source.file_stem().unwrap_or_default().to_string_lossy();
+            match pattern.extract(&stem, info, self.tags_from_filename_force) {
+                Some(frames) => {
+                    for (id, value) in frames {
+                        tags.set(id, value);
+                    }
+                }
+                None => debug!(
+                    "{}: File name does not match `--tags-from-filename`",
+                    source.display()
+                ),
+            }
+        }
+        if let Some(artist) = &self.compilation_artist {
+            let compilations = self
+                .compilations
+                .get_or_insert_with(|| compilation::find(&self.plan.metadata));
+            if compilations.contains(source) {
+                let has_album_artist = info.is_some_and(|info| {
+                    info.tag("album_artist")
+                        .or_else(|| info.tag("albumartist"))
+                        .is_some()
+                });
+                if !has_album_artist {
+                    tags.set("TPE2", artist.clone());
+                }
+                tags.set("TCMP", "1");
+            }
+        }
+        if let Some(map) = self
+            .genre_map
+            .as_ref()
+            .filter(|_| action != Action::Copy || self.map_copied_genres)
+        {
+            if let Some(genre) = info
+                .and_then(|info| info.tag("genre"))
+                .and_then(|genre| map.map(genre))
+            {
+                tags.set("TCON", genre);
+            }
+        }
+        if !self.album_from_playlist && !self.tracknum_from_position {
+            return tags;
+        }
+        let original_album = info.and_then(|info| info.tag("album")).map(String::from);
+        let name = input_playlist.name();
+        let (first_name, first_position) = self
+            .first_playlists
+            .entry(destination.to_path_buf())
+            .or_insert_with(|| (name.clone(), position))
+            .clone();
+        if first_name != name {
+            warn!(
+                "{}: Keeping tags from first playlist (`{}`, not `{}`)",
+                source.display(),
+                first_name,
+                name
+            );
+        }
+        if self.album_from_playlist {
+            if let Some(original_album) = original_album.filter(|_| self.keep_original_album) {
+                tags.set("TOAL", original_album);
+            }
+            tags.set("TALB", first_name);
+        }
+        if self.tracknum_from_position {
+            tags.set("TRCK", first_position.to_string());
+            tags.set("TPOS", "");
+        }
+        tags
+    }
+
+    /// Returns why `source` is too short, if it is.
+    fn too_short(&self, source: &Path) -> Option<SkipReason> {
+        let min_duration = self.min_duration?;
+        match self
+            .plan
+            .metadata
+            .get(source)
+            .and_then(|info| info.duration)
+        {
+            Some(duration) if duration < min_duration => Some(SkipReason::TooShort {
+                duration,
+                min_duration,
+            }),
+            Some(_) => None,
+            None => {
+                debug!(
+                    "{}: Keeping entry of unknown duration (`--min-duration`)",
+                    source.display()
+                );
+                None
+            }
+        }
+    }
+
+    /// Returns the output path for `path` below `root`, flattened, normalized to NFC, and
+    /// transliterated, sanitized and shortened if enabled.
+    ///
+    /// `root` itself is left untouched, even if `path` already starts with it. Returns `None` if
+    /// the path cannot be shortened to the length limits.
+    fn output_path(&self, root: &Path, path: &Path) -> Option<PathBuf> {
+        let path = path.strip_prefix(root).unwrap_or(path);
+        let path = match self.flatten {
+            Some(depth) => layout::flatten(path, depth),
+            None => path.to_path_buf(),
+        };
+        let path = transliterate::normalize_path(&path, self.transliterate);
+        let path = match self.sanitize {
+            Some(replacement) => sanitize::sanitize_path(&path, replacement),
+            None => path,
+        };
+        let path = match sanitize::avoid_reserved_names(&path) {
+            Some(renamed) => {
+                info!(
+                    "{}: Renamed reserved device name (to {})",
+                    path.display(),
+                    renamed.display()
+                );
+                renamed
+            }
+            None => path,
+        };
+        let path = root.join(path);
+        match self.length_limits {
+            Some(limits) => sanitize::limit_length(&path, limits),
+            None => Some(path),
+        }
+    }
+
+    /// Provide metadata from the probe pass for the sources of the plan.
+    pub fn set_metadata(&mut self, metadata: HashMap<PathBuf, ProbeInfo>) {
+        self.multi_disc_albums = metadata
+            .values()
+            .filter(|info| info.number_tag("disc").is_some_and(|disc| disc > 1))
+            .map(album_key)
+            .collect();
+        self.plan.metadata = metadata;
+    }
+
+    /// Returns why `source` should be skipped as obviously corrupt, if it should be.
+    fn corrupt_source(&self, source: &Path) -> Option<SkipReason> {
+        if std::fs::metadata(source).is_ok_and(|metadata| metadata.len() == 0) {
+            return Some(SkipReason::EmptySource);
+        }
+        self.plan
+            .metadata
+            .get(source)
+            .filter(|info| info.audio_streams == 0)
+            .map(|_| SkipReason::NoAudioStream)
+    }
+
+    /// Add the entries of a playlist to the plan.
+    pub fn add_playlist(&mut self, input_playlist: &InputPlaylist) -> Result<(), PlanError> {
+        let output_playlist_filename = input_playlist.output_file_name();
+        let output_playlist_filename = output_playlist_filename.as_path();
+        let output_playlist_path = TargetPath::new(
+            &self
+                .output_path(Path::new(""), output_playlist_filename)
+                .unwrap_or_else(|| output_playlist_filename.to_path_buf()),
+        )
+        .on_disk(&self.output_dir);
+        let output_playlist_path = self.claim_playlist_path(output_playlist_path);
+
+        let audio_root = self.audio_root(input_playlist);
+        let name = input_playlist.name();
+        let mut output_entries = vec![];
+        let mut skipped = input_playlist.skipped.clone();
+        for input_audio_path in input_playlist.entries.iter() {
+            let source = input_playlist.source(input_audio_path);
+            if let Some(pattern) = self.excludes.matching(&source) {
+                debug!("{}: Excluded by `{}`", source.display(), pattern);
+                skipped.push(SkippedEntry {
+                    entry: input_audio_path.display().to_string(),
+                    reason: SkipReason::Excluded {
+                        pattern: pattern.to_string(),
+                    },
+                });
+                continue;
+            }
+            if !self.extensions.includes(input_audio_path) {
+                let extension = input_audio_path.extension().unwrap_or_default();
+                debug!(
+                    "{}: Extension not included (.{})",
+                    source.display(),
+                    extension.to_string_lossy()
+                );
+                skipped.push(SkippedEntry {
+                    entry: input_audio_path.display().to_string(),
+                    reason: SkipReason::ExtensionNotIncluded {
+                        extension: extension.to_string_lossy().into_owned(),
+                    },
+                });
+                continue;
+            }
+            if let Some(reason) = self.tag_filter.rejects(self.plan.metadata.get(&source)) {
+                debug!("{}: Filtered ({})", source.display(), reason);
+                skipped.push(SkippedEntry {
+                    entry: input_audio_path.display().to_string(),
+                    reason: SkipReason::Filtered { reason },
+                });
+                continue;
+            }
+            if let Some(reason) = self.too_short(&source) {
+                debug!("{}: Skipping source ({})", source.display(), reason);
+                skipped.push(SkippedEntry {
+                    entry: input_audio_path.display().to_string(),
+                    reason,
+                });
+                continue;
+            }
+            let extension = match input_audio_path.extension() {
+                Some(ext) => ext,
+                None => {
+                    report_warning!(
+                        WarningCategory::MissingExtension,
+                        "{}: Failed to determine file extension",
+                        input_audio_path.display()
+                    );
+                    skipped.push(SkippedEntry {
+                        entry: input_audio_path.display().to_string(),
+                        reason: SkipReason::NoExtension,
+                    });
+                    continue;
+                }
+            };
+            let source_path = self.source_path(&source, input_audio_path)?;
+            // Outputs always get a lowercase extension, as some head units only recognize `.mp3`.
+            let codec = self
+                .plan
+                .metadata
+                .get(&source)
+                .and_then(|info| info.audio_codec.as_deref());
+            let rule = self
+                .rules
+                .find(&source, codec)
+                .filter(|_| !extension.eq_ignore_ascii_case("mp3"));
+            if let Some((key, settings)) = rule {
+                debug!("{}: Using rule `{}` ({})", source.display(), key, settings);
+            }
+            let encoder = rule.map(|(_, settings)| *settings);
+            let parts = self.split_long_files.and_then(|max_duration| {
+                segment::parts(self.plan.metadata.get(&source)?, max_duration)
+            });
+            // The parts of MP3 sources are cut out of the source without encoding them.
+            let action = if extension.eq_ignore_ascii_case("mp3") && parts.is_some() {
+                Action::Remux
+            } else if extension.eq_ignore_ascii_case("mp3") {
+                Action::Copy
+            } else if encoder.is_none() && self.has_mp3_stream(&source) {
+                debug!("{}: Remuxing MP3 stream", source.display());
+                Action::Remux
+            } else {
+                Action::Convert
+            };
+            let output_audio_path = source_path.with_extension("mp3");
+            if let Some(reason) = self.corrupt_source(&source) {
+                report_warning!(
+                    WarningCategory::CorruptSource,
+                    "{}: Skipping source ({})",
+                    source.display(),
+                    reason
+                );
+                skipped.push(SkippedEntry {
+                    entry: input_audio_path.display().to_string(),
+                    reason,
+                });
+                continue;
+            }
+            if let Some(limit) = self.max_file_size {
+                let duration = self
+                    .plan
+                    .metadata
+                    .get(&source)
+                    .and_then(|info| info.duration);
+                let longest = parts
+                    .iter()
+                    .flatten()
+                    .map(|part| part.segment)
+                    .max_by(|a, b| {
+                        let duration =
+                            |segment: &Segment| segment.duration(duration).unwrap_or(0.0);
+                        duration(a).total_cmp(&duration(b))
+                    });
+                let size = self.expected_size(action, &source, encoder.as_ref(), longest);
+                if let Some(size) = size.filter(|&s| s > limit) {
+                    report_warning!(
+                        WarningCategory::TooLarge,
+                        "{}: Output would exceed the file size limit of the target filesystem",
+                        source.display()
+                    );
+                    skipped.push(SkippedEntry {
+                        entry: input_audio_path.display().to_string(),
+                        reason: SkipReason::TooLarge { size, limit },
+                    });
+                    continue;
+                }
+            }
+            let output_audio_path = self.structured_path(&source, &output_audio_path);
+            let output_audio_path = if self.prefix_position {
+                let position = output_entries.len() + 1;
+                prefixed_path(&output_audio_path, position, input_playlist.entries.len())
+            } else {
+                output_audio_path
+            };
+            let output_audio_path = audio_root.join(
+                output_audio_path
+                    .strip_prefix(self.layout.audio_root())
+                    .unwrap_or(&output_audio_path),
+            );
+            let Some(output_audio_path) = self.output_path(&audio_root, &output_audio_path) else {
+                let limits = self.length_limits.expect("only length limits can fail");
+                report_warning!(
+                    WarningCategory::PathTooLong,
+                    "{}: Output path cannot be shortened to {} characters",
+                    output_audio_path.display(),
+                    limits.max_path
+                );
+                skipped.push(SkippedEntry {
+                    entry: input_audio_path.display().to_string(),
+                    reason: SkipReason::PathTooLong {
+                        max_path: limits.max_path,
+                    },
+                });
+                continue;
+            };
+            let output_audio_path = self.place(&audio_root, &source, output_audio_path, &name)?;
+            let outputs = match parts {
+                None => vec![(output_audio_path, None)],
+                Some(parts) => {
+                    let count = parts.len();
+                    debug!("{}: Splitting into {} parts", source.display(), count);
+                    let mut outputs = vec![];
+                    for (index, part) in parts.into_iter().enumerate() {
+                        let path = segment::part_path(&output_audio_path, index + 1, count);
+                        let path = self.claim_destination(&source, path)?;
+                        outputs.push((path, Some((index + 1, count, part))));
+                    }
+                    outputs
+                }
+            };
+            for (output_audio_path, part) in outputs {
+                let target = TargetPath::new(&output_audio_path);
+                let destination = target.on_disk(&self.output_dir);
+                let position = output_entries.len() as u32 + 1;
+                let mut tags =
+                    self.tag_overrides(action, &source, &destination, input_playlist, position);
+                if let Some((number, count, part)) = &part {
+                    let title = self.part_title(&source, &tags, *number, part);
+                    tags.set("TIT2", title);
+                    tags.set("TRCK", format!("{}/{}", number, count));
+                }
+                let segment = part.map(|(_, _, part)| part.segment);
+                let task = match self.task_indices.entry((source.clone(), destination)) {
+                    hash_map::Entry::Occupied(entry) => {
+                        debug!(
+                            "{}: Already planned for another entry",
+                            entry.key().0.display()
+                        );
+                        *entry.get()
+                    }
+                    hash_map::Entry::Vacant(entry) => {
+                        let (source, destination) = entry.key().clone();
+                        self.plan.tasks.push(Task {
+                            action,
+                            source,
+                            destination,
+                            tags,
+                            encoder,
+                            segment,
+                        });
+                        *entry.insert(self.plan.tasks.len() - 1)
+                    }
+                };
+                output_entries.push(OutputEntry {
+                    target: target.playlist_entry(),
+                    task,
+                });
+            }
+        }
+
+        self.plan.playlists.push(PlaylistOutput {
+            source: input_playlist.path.clone(),
+            path: output_playlist_path,
+            entries: output_entries,
+            skipped,
+            title: input_playlist.title.clone(),
+        });
+        Ok(())
+    }
+
+    /// Add a playlist named like `Genre - Rock.m3u` for every genre with at least `min_tracks`
+    /// output files in the playlists added so far.
+    ///
+    /// Genres are compared case-insensitively, after the genre map. The entries are taken over
+    /// from the other playlists, so they are written the same way.
+    pub fn add_genre_playlists(&mut self, min_tracks: usize) {
+        let mut genres: BTreeMap<String, (String, Vec<OutputEntry>)> = BTreeMap::new();
+        let mut seen = HashSet::new();
+        for entry in self
+            .plan
+            .playlists
+            .iter()
+            .flat_map(|playlist| &playlist.entries)
+        {
+            let task = &self.plan.tasks[entry.task];
+            if !seen.insert(&task.destination) {
+                continue;
+            }
+            let Some(genre) = task
+                .tags
+                .get("TCON")
+                .or_else(|| {
+                    self.plan
+                        .metadata
+                        .get(&task.source)
+                        .and_then(|info| info.tag("genre"))
+                })
+                .map(str::trim)
+                .filter(|genre| !genre.is_empty())
+            else {
+                continue;
+            };
+            genres
+                .entry(genre.to_lowercase())
+                .or_insert_with(|| (genre.to_string(), vec![]))
+                .1
+                .push(entry.clone());
+        }
+
+        for (genre, entries) in genres.into_values() {
+            if entries.len() < min_tracks {
+                debug!(
+                    "Skipping playlist for genre `{}` ({} of {} tracks)",
+                    genre,
+                    entries.len(),
+                    min_tracks
+                );
+                continue;
+            }
+            let file_name =
+                PathBuf::from(format!("Genre - {}.m3u", genre.replace(['/', '\\'], "-")));
+            let path = TargetPath::new(
+                &self
+                    .output_path(Path::new(""), &file_name)
+                    .unwrap_or_else(|| file_name.clone()),
+            )
+            .on_disk(&self.output_dir);
+            let folded = path.to_string_lossy().to_lowercase();
+            if self
+                .plan
+                .playlists
+                .iter()
+                .any(|playlist| playlist.path.to_string_lossy().to_lowercase() == folded)
+            {
+                warn!(
+                    "{}: Not writing playlist for genre `{}` (path is already taken)",
+                    path.display(),
+                    genre
+                );
+                continue;
+            }
+            info!(
+                "{}: Adding playlist for genre `{}` ({} tracks)",
+                path.display(),
+                genre,
+                entries.len()
+            );
+            self.plan.playlists.push(PlaylistOutput {
+                source: file_name,
+                path,
+                entries,
+                skipped: vec![],
+                title: None,
+            });
+        }
+    }
+
+    /// The plan so far, e.g. to merge duplicates before adding the genre playlists.
+    pub fn plan_mut(&mut self) -> &mut Plan {
+        &mut self.plan
+    }
+
+    /// Returns `path` for an output playlist, or a numbered variant of it if an earlier playlist
+    /// already has that path.
+    fn claim_playlist_path(&self, path: PathBuf) -> PathBuf {
+        let taken: HashSet<PathBuf> = self
+            .plan
+            .playlists
+            .iter()
+            .map(|playlist| fat_key(&playlist.path))
+            .collect();
+        let mut candidate = path.clone();
+        let mut counter = 1;
+        while taken.contains(&fat_key(&candidate)) {
+            counter += 1;
+            candidate = numbered_path(&path, counter);
+        }
+        if candidate != path {
+            warn!(
+                "{}: Playlist path is already taken (writing to {})",
+                path.display(),
+                candidate.display()
+            );
+        }
+        candidate
+    }
+
+    /// Returns `true` if the probe found that the audio stream of `source` is already MP3.
+    fn has_mp3_stream(&self, source: &Path) -> bool {
+        self.plan
+            .metadata
+            .get(source)
+            .is_some_and(|info| info.audio_codec.as_deref() == Some("mp3"))
+    }
+
+    /// Returns the expected output size of a source, or of the `segment` of it, if it can be
+    /// determined.
+    fn expected_size(
+        &self,
+        action: Action,
+        source: &Path,
+        encoder: Option<&EncoderSettings>,
+        segment: Option<Segment>,
+    ) -> Option<u64> {
+        let duration = self
+            .plan
+            .metadata
+            .get(source)
+            .and_then(|info| info.duration);
+        match action {
+            Action::Copy | Action::Remux => {
+                let size = std::fs::metadata(source).ok()?.len();
+                let fraction = segment
+                    .zip(duration.filter(|duration| *duration > 0.0))
+                    .and_then(|(segment, duration)| {
+                        Some(segment.duration(Some(duration))? / duration)
+                    })
+                    .unwrap_or(1.0);
+                Some((size as f64 * fraction) as u64)
+            }
+            Action::Convert => {
+                let duration = match segment {
+                    Some(segment) => segment.duration(duration)?,
+                    None => duration?,
+                };
+                Some((duration * estimate::bitrate_kbps(encoder) * 1000.0 / 8.0) as u64)
+            }
+        }
+    }
+
+    /// Returns the title of part `number` of `source`: the title of its chapter, or else the title
+    /// of the source with the part number.
+    fn part_title(&self, source: &Path, tags: &TagOverrides, number: usize, part: &Part) -> String {
+        if let Some(title) = &part.title {
+            return title.clone();
+        }
+        let title = tags
+            .get("TIT2")
+            .or_else(|| self.plan.metadata.get(source)?.tag("title"))
+            .map(str::to_string)
+            .unwrap_or_else(|| {
+                let stem = source.file_stem().unwrap_or_default();
+                stem.to_string_lossy().into_owned()
+            });
+        format!("{} (Part {})", title, number)
+    }
+
+    /// Register `path` as the destination for `source`, resolving collisions with destinations of
+    /// other sources according to the conflict policy.
+    fn claim_destination(&mut self, source: &Path, path: PathBuf) -> Result<PathBuf, PlanError> {
+        let mut candidate = path.clone();
+        let mut counter = 1;
+        loop {
+            let key = fat_key(&candidate);
+            match self.destinations.get(&key) {
+                None => {
+                    self.destinations
+                        .insert(key, (source.to_path_buf(), candidate.clone()));
+                    return Ok(candidate);
+                }
+                // With a music root, the same file may be spelled differently in different
+                // playlists.
+                Some((existing_source, existing_path))
+                    if existing_source == source
+                        || (self.music_root.is_some()
+                            && overlap::canonicalize_lenient(existing_source)
+                                == overlap::canonicalize_lenient(source)) =>
+                {
+                    return Ok(existing_path.clone());
+                }
+                Some((existing_source, _)) => match self.conflict_policy {
+                    ConflictPolicy::Error => {
+                        return Err(PlanError::Collision {
+                            path: candidate,
+                            first: existing_source.clone(),
+                            second: source.to_path_buf(),
+                        });
+                    }
+                    ConflictPolicy::Rename => {
+                        counter += 1;
+                        candidate = numbered_path(&path, counter);
+                    }
+                },
+            }
+        }
+    }
+
+    /// Returns the output path of `source` in the playlist `name`, which is `path` unless the
+    /// source already has one that is shared between playlists or positions.
+    fn place(
+        &mut self,
+        audio_root: &Path,
+        source: &Path,
+        path: PathBuf,
+        name: &str,
+    ) -> Result<PathBuf, PlanError> {
+        let single = self.group_by_playlist == Some(SharedTracks::Single);
+        if !single && !self.prefix_position {
+            return self.claim_destination(source, path);
+        }
+        let root = if single { Path::new("") } else { audio_root };
+        let key = (root.to_path_buf(), source.to_path_buf());
+        if let Some((placed, first_name)) = self.placed.get(&key) {
+            if self.prefix_position && first_name != name {
+                warn!(
+                    "{}: Keeping position from first playlist (`{}`, not `{}`)",
+                    source.display(),
+                    first_name,
+                    name
+                );
+            }
+            return Ok(placed.clone());
+        }
+        let claimed = self.claim_destination(source, path)?;
+        self.placed.insert(key, (claimed.clone(), name.to_string()));
+        Ok(claimed)
+    }
+
+    pub fn finish(self) -> Plan {
+        self.plan
+    }
+}
+
+/// Returns the key that identifies the album of a file.
+fn album_key(info: &ProbeInfo) -> (String, String) {
+    (
+        layout::album_artist(info)
+            .unwrap_or_default()
+            .to_lowercase(),
+        info.tag("album").unwrap_or_default().to_lowercase(),
+    )
+}
+
+/// Returns the key under which a path is considered equal on FAT filesystems, i.e. compared
+/// case-insensitively and with trailing dots and spaces stripped from every component.
+fn fat_key(path: &Path) -> PathBuf {
+    path.iter()
+        .map(|component| {
+            let component = component.to_string_lossy();
+            component.trim_end_matches(['.', ' ']).to_lowercase()
+        })
+        .collect()
+}
+
+/// Returns `path` with the `position` in a playlist of `len` entries prepended to the file name,
+/// zero-padded to the number of digits of `len` (at least two).
+fn prefixed_path(path: &Path, position: usize, len: usize) -> PathBuf {
+    let width = len.to_string().len().max(2);
+    let mut file_name = OsString::from(format!("{:0width$} - ", position, width = width));
+    file_name.push(path.file_name().unwrap_or_default());
+    path.with_file_name(file_name)
+}
+
+/// Returns `path` with ` (n)` appended to the file stem.
+fn numbered_path(path: &Path, n: usize) -> PathBuf {
+    let mut file_name = OsString::new();
+    if let Some(stem) = path.file_stem() {
+        file_name.push(stem);
+    }
+    file_name.push(format!(" ({n})"));
+    if let Some(extension) = path.extension() {
+        file_name.push(".");
+        file_name.push(extension);
+    }
+    path.with_file_name(file_name)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn plan_entries(policy: ConflictPolicy, entries: &[&str]) -> Result<Plan, PlanError> {
+        let mut planner = Planner::new(Path::new("output"), policy);
+        planner.add_playlist(&InputPlaylist {
+            path: PathBuf::from("music/playlist.m3u"),
+            entries: entries.iter().map(PathBuf::from).collect(),
+            skipped: vec![],
+            title: None,
+            durations: Default::default(),
+        })?;
+        Ok(planner.finish())
+    }
+
+    #[test]
+    fn case_only_difference_is_a_collision() {
+        let result = plan_entries(ConflictPolicy::Error, &["Song.mp3", "song.mp3"]);
+        assert!(matches!(result, Err(PlanError::Collision { .. })));
+    }
+
+    #[test]
+    fn trailing_dots_and_spaces_are_a_collision() {
+        let result = plan_entries(ConflictPolicy::Error, &["Album./a.mp3", "Album /a.mp3"]);
+        assert!(matches!(result, Err(PlanError::Collision { .. })));
+    }
+
+    fn targets(plan: &Plan) -> Vec<&str> {
+        plan.playlists[0]
+            .entries
+            .iter()
+            .map(|entry| entry.target.as_str())
+            .collect()
+    }
+
+    #[test]
+    fn relocate_moves_output_paths() {
+        let mut plan = plan_entries(ConflictPolicy::Error, &["a.mp3"]).unwrap();
+        plan.relocate(Path::new("output"), Path::new("/media/usb"));
+        assert_eq!(
+            plan.playlists[0].path,
+            PathBuf::from("/media/usb/playlist.m3u")
+        );
+        assert_eq!(plan.tasks[0].destination, PathBuf::from("/media/usb/a.mp3"));
+        assert_eq!(plan.tasks[0].source, PathBuf::from("music/a.mp3"));
+    }
+
+    #[test]
+    fn rename_policy_disambiguates() {
+        let plan = plan_entries(ConflictPolicy::Rename, &["Song.mp3", "song.mp3"]).unwrap();
+        assert_eq!(targets(&plan), vec!["Song.mp3", "song (2).mp3"]);
+    }
+
+    #[test]
+    fn sanitization_induced_collision_is_detected() {
+        let mut planner = Planner::new(Path::new("output"), ConflictPolicy::Error);
+        planner.set_sanitize(Some('_'));
+        let result = planner.add_playlist(&InputPlaylist {
+            path: PathBuf::from("music/playlist.m3u"),
+            entries: vec![PathBuf::from("What?.mp3"), PathBuf::from("What*.mp3")],
+            skipped: vec![],
+            title: None,
+            durations: Default::default(),
+        });
+        assert!(matches!(result, Err(PlanError::Collision { .. })));
+    }
+
+    #[test]
+    fn transliteration_induced_collision_is_detected() {
+        let mut planner = Planner::new(Path::new("output"), ConflictPolicy::Rename);
+        planner.set_transliterate(Some(TransliterationStyle::Basic));
+        planner
+            .add_playlist(&InputPlaylist {
+                path: PathBuf::from("music/playlist.m3u"),
+                entries: vec![PathBuf::from("Müller.mp3"), PathBuf::from("Muller.mp3")],
+                skipped: vec![],
+                title: None,
+                durations: Default::default(),
+            })
+            .unwrap();
+        assert_eq!(
+            targets(&planner.finish()),
+            vec!["Muller.mp3", "Muller (2).mp3"]
+        );
+    }
+
+    #[test]
+    fn decomposed_names_are_normalized() {
+        let plan = plan_entries(ConflictPolicy::Error, &["Mu\u{0308}ller.mp3"]).unwrap();
+        assert_eq!(targets(&plan), vec!["Müller.mp3"]);
+    }
+
+    #[test]
+    fn reserved_names_are_renamed() {
+        let plan = plan_entries(ConflictPolicy::Error, &["AUX/con.flac", "AUX/Song.mp3"]).unwrap();
+        assert_eq!(targets(&plan), vec![r"_AUX\_con.mp3", r"_AUX\Song.mp3"]);
+    }
+
+    #[test]
+    fn sync_layout_places_audio_under_music() {
+        let mut planner = Planner::new(Path::new("output"), ConflictPolicy::Error);
+        planner.set_layout(Layout::Sync);
+        planner
+            .add_playlist(&InputPlaylist {
+                path: PathBuf::from("music/playlist.m3u"),
+                entries: vec![PathBuf::from("Artist/Song.mp3")],
+                skipped: vec![],
+                title: None,
+                durations: Default::default(),
+            })
+            .unwrap();
+        let plan = planner.finish();
+        assert_eq!(targets(&plan), vec![r"Music\Artist\Song.mp3"]);
+        assert_eq!(plan.playlists[0].path, Path::new("output/playlist.m3u"));
+        assert_eq!(
+            plan.tasks[0].destination,
+            Path::new("output/Music/Artist/Song.mp3")
+        );
+    }
+
+    #[test]
+    fn group_by_playlist_places_tracks_in_playlist_folders() {
+        let destinations = |shared| {
+            let mut planner = Planner::new(Path::new("output"), ConflictPolicy::Error);
+            planner.set_layout(Layout::Sync);
+            planner.set_group_by_playlist(Some(shared));
+            planner.set_sanitize(Some('_'));
+            for (name, entries) in [
+                ("Road: Trip", ["a.mp3", "b.mp3"]),
+                ("Kids", ["b.mp3", "c.mp3"]),
+            ] {
+                planner
+                    .add_playlist(&InputPlaylist {
+                        path: PathBuf::from(format!("music/{}.m3u", name)),
+                        entries: entries.iter().map(PathBuf::from).collect(),
+                        skipped: vec![],
+                        title: None,
+                        durations: Default::default(),
+                    })
+                    .unwrap();
+            }
+            let plan = planner.finish();
+            let targets: Vec<Vec<String>> = plan
+                .playlists
+                .iter()
+                .map(|playlist| {
+                    playlist
+                        .entries
+                        .iter()
+                        .map(|entry| entry.target.clone())
+                        .collect()
+                })
+                .collect();
+            let destinations: HashSet<_> =
+                plan.tasks.iter().map(|task| &task.destination).collect();
+            (targets, destinations.len())
+        };
+        let (targets, num_files) = destinations(SharedTracks::Duplicate);
+        assert_eq!(
+            targets,
+            [
+                [r"Music\Road_ Trip\a.mp3", r"Music\Road_ Trip\b.mp3"],
+                [r"Music\Kids\b.mp3", r"Music\Kids\c.mp3"],
+            ]
+        );
+        assert_eq!(num_files, 4);
+        let (targets, num_files) = destinations(SharedTracks::Single);
+        assert_eq!(targets[1], [r"Music\Road_ Trip\b.mp3", r"Music\Kids\c.mp3"]);
+        assert_eq!(num_files, 3);
+    }
+
+    #[test]
+    fn prefix_position_keeps_the_first_position() {
+        let mut planner = Planner::new(Path::new("output"), ConflictPolicy::Error);
+        planner.set_prefix_position(true);
+        let entries: Vec<PathBuf> = (1..=10)
+            .map(|i| PathBuf::from(format!("Album/{}.mp3", i)))
+            .collect();
+        for (name, entries) in [("a", &entries[..]), ("b", &entries[8..])] {
+            planner
+                .add_playlist(&InputPlaylist {
+                    path: PathBuf::from(format!("music/{}.m3u", name)),
+                    entries: entries.to_vec(),
+                    skipped: vec![],
+                    title: None,
+                    durations: Default::default(),
+                })
+                .unwrap();
+        }
+        let plan = planner.finish();
+        assert_eq!(plan.playlists[0].entries[0].target, r"Album\01 - 1.mp3");
+        assert_eq!(plan.playlists[0].entries[9].target, r"Album\10 - 10.mp3");
+        let second: Vec<&str> = plan.playlists[1]
+            .entries
+            .iter()
+            .map(|entry| entry.target.as_str())
+            .collect();
+        assert_eq!(second, [r"Album\09 - 9.mp3", r"Album\10 - 10.mp3"]);
+    }
+
+    #[test]
+    fn music_root_places_sources_by_their_location() {
+        let dir = std::env::temp_dir().join(format!("plan-music-root-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(dir.join("lists")).unwrap();
+        for file in [
+            "library/Artist/Album/a.mp3",
+            "library/Artist/b.mp3",
+            "other/c.mp3",
+        ] {
+            let path = dir.join(file);
+            std::fs::create_dir_all(path.parent().unwrap()).unwrap();
+            std::fs::write(path, b"DATA").unwrap();
+        }
+        let plan_with = |outside, playlists: &[(&str, &[PathBuf])]| {
+            let mut planner = Planner::new(Path::new("output"), ConflictPolicy::Error);
+            planner.set_music_root(Some(&dir.join("library")), outside);
+            for (path, entries) in playlists {
+                planner.add_playlist(&InputPlaylist {
+                    path: dir.join(path),
+                    entries: entries.to_vec(),
+                    skipped: vec![],
+                    title: None,
+                    durations: Default::default(),
+                })?;
+            }
+            Ok::<_, PlanError>(planner.finish())
+        };
+
+        let plan = plan_with(
+            OutsideMusicRoot::Error,
+            &[
+                (
+                    "lists/mixed.m3u",
+                    &[
+                        PathBuf::from("../library/Artist/Album/a.mp3"),
+                        dir.join("library/Artist/b.mp3"),
+                    ],
+                ),
+                (
+                    "library/Artist/nested.m3u",
+                    &[PathBuf::from("Album/a.mp3"), PathBuf::from("./b.mp3")],
+                ),
+            ],
+        )
+        .unwrap();
+        for playlist in plan.playlists.iter() {
+            let targets: Vec<&str> = playlist
+                .entries
+                .iter()
+                .map(|entry| entry.target.as_str())
+                .collect();
+            assert_eq!(targets, [r"Artist\Album\a.mp3", r"Artist\b.mp3"]);
+        }
+
+        let outside = [dir.join("other/c.mp3")];
+        let result = plan_with(OutsideMusicRoot::Error, &[("lists/outside.m3u", &outside)]);
+        assert!(matches!(result, Err(PlanError::OutsideMusicRoot { .. })));
+        let outside = [PathBuf::from("../other/c.mp3")];
+        let plan = plan_with(
+            OutsideMusicRoot::Fallback,
+            &[("lists/outside.m3u", &outside)],
+        )
+        .unwrap();
+        assert_eq!(targets(&plan), [r"other\c.mp3"]);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn extensions_are_lowercased() {
+        let plan = plan_entries(ConflictPolicy::Error, &["TRACK.Mp3", "Other.FLAC"]).unwrap();
+        assert_eq!(targets(&plan), ["TRACK.mp3", "Other.mp3"]);
+        assert_eq!(plan.tasks[0].action, Action::Copy);
+        assert_eq!(plan.tasks[0].destination, Path::new("output/TRACK.mp3"));
+        let result = plan_entries(ConflictPolicy::Error, &["A.MP3", "a.mp3"]);
+        assert!(matches!(result, Err(PlanError::Collision { .. })));
+    }
+
+    #[test]
+    fn same_source_is_not_a_collision() {
+        let plan = plan_entries(ConflictPolicy::Error, &["Song.mp3", "Song.mp3"]).unwrap();
+        assert_eq!(targets(&plan), vec!["Song.mp3", "Song.mp3"]);
+    }
+
+    #[test]
+    fn playlist_titles_name_the_output() {
+        let mut planner = Planner::new(Path::new("output"), ConflictPolicy::Error);
+        planner.set_album_from_playlist(true, false);
+        for (uuid, entries) in [("0b5e", ["a.mp3"]), ("9f1c", ["b.mp3"])] {
+            planner
+                .add_playlist(&InputPlaylist {
+                    path: PathBuf::from(format!("music/{}.M3U8", uuid)),
+                    entries: entries.iter().map(PathBuf::from).collect(),
+                    skipped: vec![],
+                    title: Some("Road/Trip Vol. 2".to_string()),
+                    durations: Default::default(),
+                })
+                .unwrap();
+        }
+        let plan = planner.finish();
+        let paths: Vec<_> = plan
+            .playlists
+            .iter()
+            .map(|playlist| &playlist.path)
+            .collect();
+        assert_eq!(
+            paths,
+            [
+                Path::new("output/Road_Trip Vol. 2.m3u8"),
+                Path::new("output/Road_Trip Vol. 2 (2).m3u8"),
+            ]
+        );
+        assert_eq!(plan.tasks[0].tags.get("TALB").unwrap(), "Road/Trip Vol. 2");
+    }
+
+    #[test]
+    fn shared_sources_are_planned_once() {
+        let mut planner = Planner::new(Path::new("output"), ConflictPolicy::Error);
+        for (name, entries) in [
+            ("A", ["a.flac", "b.flac", "c.flac"]),
+            ("B", ["b.flac", "c.flac", "d.flac"]),
+        ] {
+            planner
+                .add_playlist(&InputPlaylist {
+                    path: PathBuf::from(format!("music/{}.m3u", name)),
+                    entries: entries.iter().map(PathBuf::from).collect(),
+                    skipped: vec![],
+                    title: None,
+                    durations: Default::default(),
+                })
+                .unwrap();
+        }
+        let plan = planner.finish();
+        assert_eq!(plan.tasks(Action::Convert).count(), 4);
+        let tasks: Vec<Vec<usize>> = plan
+            .playlists
+            .iter()
+            .map(|playlist| playlist.entries.iter().map(|entry| entry.task).collect())
+            .collect();
+        assert_eq!(tasks, [vec![0, 1, 2], vec![1, 2, 3]]);
+    }
+
+    #[test]
+    fn album_from_playlist_keeps_the_first_playlist() {
+        let mut planner = Planner::new(Path::new("output"), ConflictPolicy::Error);
+        planner.set_album_from_playlist(true, false);
+        for (name, entries) in [
+            ("Road Trip", ["a.mp3", "b.flac"]),
+            ("Chill", ["b.flac", "c.mp3"]),
+        ] {
+            planner
+                .add_playlist(&InputPlaylist {
+                    path: PathBuf::from(format!("music/{}.m3u", name)),
+                    entries: entries.iter().map(PathBuf::from).collect(),
+                    skipped: vec![],
+                    title: None,
+                    durations: Default::default(),
+                })
+                .unwrap();
+        }
+        let plan = planner.finish();
+        let albums: Vec<_> = plan
+            .tasks
+            .iter()
+            .map(|task| task.tags.get("TALB").unwrap())
+            .collect();
+        assert_eq!(albums, ["Road Trip", "Road Trip", "Chill"]);
+        assert!(plan.tasks.iter().all(|task| task.tags.frames.len() == 1));
+    }
+
+    #[test]
+    fn adds_genre_playlists_with_enough_tracks() {
+        let mut planner = Planner::new(Path::new("output"), ConflictPolicy::Error);
+        planner.set_genre_map(Some("Alt Rock=Rock".parse().unwrap()), true);
+        planner.set_metadata(
+            [
+                ("a.mp3", "Rock"),
+                ("b.mp3", "alt rock"),
+                ("c.mp3", "rock"),
+                ("d.mp3", "Jazz"),
+            ]
+            .into_iter()
+            .map(|(name, genre)| {
+                let info = ProbeInfo {
+                    audio_streams: 1,
+                    tags: HashMap::from([("genre".to_string(), genre.to_string())]),
+                    ..Default::default()
+                };
+                (PathBuf::from("music").join(name), info)
+            })
+            .collect(),
+        );
+        for (name, entries) in [("A", ["a.mp3", "b.mp3"]), ("B", ["a.mp3", "c.mp3"])] {
+            planner
+                .add_playlist(&InputPlaylist {
+                    path: PathBuf::from(format!("music/{}.m3u", name)),
+                    entries: entries.iter().map(PathBuf::from).collect(),
+                    skipped: vec![],
+                    title: None,
+                    durations: Default::default(),
+                })
+                .unwrap();
+        }
+        planner
+            .add_playlist(&InputPlaylist {
+                path: PathBuf::from("music/C.m3u"),
+                entries: vec![PathBuf::from("d.mp3")],
+                skipped: vec![],
+                title: None,
+                durations: Default::default(),
+            })
+            .unwrap();
+        planner.add_genre_playlists(2);
+        let plan = planner.finish();
+        assert_eq!(plan.playlists.len(), 4);
+        let genre_playlist = &plan.playlists[3];
+        assert_eq!(genre_playlist.path, Path::new("output/Genre - Rock.m3u"));
+        let targets: Vec<_> = genre_playlist
+            .entries
+            .iter()
+            .map(|entry| entry.target.as_str())
+            .collect();
+        assert_eq!(targets, ["a.mp3", "b.mp3", "c.mp3"]);
+    }
+
+    #[test]
+    fn tracknum_from_position_counts_planned_entries() {
+        let mut planner = Planner::new(Path::new("output"), ConflictPolicy::Error);
+        planner.set_tracknum_from_position(true);
+        for (name, entries) in [
+            ("A", ["a.mp3", "x", "b.flac"]),
+            ("B", ["c.mp3", "b.flac", "d.mp3"]),
+        ] {
+            planner
+                .add_playlist(&InputPlaylist {
+                    path: PathBuf::from(format!("music/{}.m3u", name)),
+                    entries: entries.iter().map(PathBuf::from).collect(),
+                    skipped: vec![],
+                    title: None,
+                    durations: Default::default(),
+                })
+                .unwrap();
+        }
+        let plan = planner.finish();
+        let tracks: Vec<_> = plan
+            .tasks
+            .iter()
+            .map(|task| task.tags.get("TRCK").unwrap())
+            .collect();
+        // `x` has no extension and is skipped, `b.flac` keeps its position in `A`.
+        assert_eq!(tracks, ["1", "2", "1", "3"]);
+        assert!(plan
+            .tasks
+            .iter()
+            .all(|task| task.tags.get("TPOS") == Some("") && task.tags.get("TALB").is_none()));
+    }
+
+    #[test]
+    fn playlist_lists_are_relative_to_their_directory() {
+        let dir = std::env::temp_dir().join(format!("ford-sync-lists-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let list = dir.join("playlists.txt");
+        std::fs::write(&list, "# Car\nRock.m3u\n\n  sub/Pop.m3u  \n/abs/Jazz.m3u\n").unwrap();
+        assert_eq!(
+            read_playlist_list(&list).unwrap(),
+            vec![
+                dir.join("Rock.m3u"),
+                dir.join("sub/Pop.m3u"),
+                PathBuf::from("/abs/Jazz.m3u")
+            ]
+        );
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn excluded_entries_are_skipped_in_every_playlist() {
+        let mut planner = Planner::new(Path::new("output"), ConflictPolicy::Error);
+        planner.set_excludes(Excludes::new(["**/Christmas/**".to_string()]));
+        for name in ["a.m3u", "b.m3u"] {
+            planner
+                .add_playlist(&InputPlaylist {
+                    path: PathBuf::from("music").join(name),
+                    entries: vec![
+                        PathBuf::from("Christmas/Jingle.flac"),
+                        PathBuf::from("Song.mp3"),
+                    ],
+                    skipped: vec![],
+                    title: None,
+                    durations: Default::default(),
+                })
+                .unwrap();
+        }
+        let plan = planner.finish();
+        assert!(plan
+            .tasks
+            .iter()
+            .all(|task| task.source == Path::new("music/Song.mp3")));
+        for playlist in &plan.playlists {
+            assert_eq!(playlist.entries.len(), 1);
+            assert!(matches!(
+                playlist.skipped[0].reason,
+                SkipReason::Excluded { .. }
+            ));
+        }
+    }
+
+    fn probed(audio_streams: usize) -> ProbeInfo {
+        ProbeInfo {
+            duration: Some(60.0),
+            audio_streams,
+            pictures: 1,
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn video_files_need_include_ext_all_and_an_audio_stream() {
+        let playlist = InputPlaylist {
+            path: PathBuf::from("music/playlist.m3u"),
+            entries: vec![
+                PathBuf::from("clip.mp4"),
+                PathBuf::from("silent.mkv"),
+                PathBuf::from("cover.jpg"),
+            ],
+            skipped: vec![],
+            title: None,
+            durations: Default::default(),
+        };
+        let metadata = HashMap::from([
+            (PathBuf::from("music/clip.mp4"), probed(1)),
+            (PathBuf::from("music/silent.mkv"), probed(0)),
+            (PathBuf::from("music/cover.jpg"), probed(0)),
+        ]);
+
+        let mut planner = Planner::new(Path::new("output"), ConflictPolicy::Error);
+        planner.set_extensions(Extensions::new(
+            crate::exclude::DEFAULT_EXTENSIONS.split(','),
+        ));
+        planner.set_metadata(metadata.clone());
+        planner.add_playlist(&playlist).unwrap();
+        let plan = planner.finish();
+        assert!(plan.tasks.is_empty());
+        assert!(plan.playlists[0]
+            .skipped
+            .iter()
+            .all(|skipped| skipped.reason.is_excluded()));
+
+        let mut planner = Planner::new(Path::new("output"), ConflictPolicy::Error);
+        planner.set_extensions(Extensions::new(["all"]));
+        planner.set_metadata(metadata);
+        planner.add_playlist(&playlist).unwrap();
+        let plan = planner.finish();
+        assert_eq!(targets(&plan), vec!["clip.mp3"]);
+        assert!(plan.playlists[0]
+            .skipped
+            .iter()
+            .all(|skipped| matches!(skipped.reason, SkipReason::NoAudioStream)));
+    }
+
+    #[test]
+    fn short_entries_are_dropped_unless_duration_is_unknown() {
+        let mut planner = Planner::new(Path::new("output"), ConflictPolicy::Error);
+        planner.set_min_duration(Some(10.0));
+        planner.set_metadata(HashMap::from([
+            (
+                PathBuf::from("music/intro.mp3"),
+                ProbeInfo {
+                    duration: Some(4.2),
+                    ..probed(1)
+                },
+            ),
+            (PathBuf::from("music/song.mp3"), probed(1)),
+            (
+                PathBuf::from("music/unknown.mp3"),
+                ProbeInfo {
+                    duration: None,
+                    ..probed(1)
+                },
+            ),
+        ]));
+        planner
+            .add_playlist(&InputPlaylist {
+                path: PathBuf::from("music/playlist.m3u"),
+                entries: ["intro.mp3", "song.mp3", "unknown.mp3"]
+                    .iter()
+                    .map(PathBuf::from)
+                    .collect(),
+                skipped: vec![],
+                title: None,
+                durations: Default::default(),
+            })
+            .unwrap();
+        let plan = planner.finish();
+        assert_eq!(targets(&plan), vec!["song.mp3", "unknown.mp3"]);
+        assert!(matches!(
+            plan.playlists[0].skipped[0].reason,
+            SkipReason::TooShort { .. }
+        ));
+    }
+
+    #[test]
+    fn rules_set_the_encoder_of_matching_sources() {
+        let mut planner = Planner::new(Path::new("output"), ConflictPolicy::Error);
+        let codec = |codec: &str| ProbeInfo {
+            audio_codec: Some(codec.to_string()),
+            ..probed(1)
+        };
+        planner.set_metadata(HashMap::from([
+            (PathBuf::from("music/a.ogg"), codec("opus")),
+            (PathBuf::from("music/b.m4a"), codec("mp3")),
+            (PathBuf::from("music/c.mka"), codec("mp3")),
+        ]));
+        let rules = "flac = { quality = 0 }\nopus = { bitrate = 64 }\nmka = { quality = 4 }";
+        planner.set_rules(Rules::from_table(&rules.parse().unwrap()).unwrap());
+        planner
+            .add_playlist(&InputPlaylist {
+                path: PathBuf::from("music/playlist.m3u"),
+                entries: ["a.ogg", "b.m4a", "c.mka", "d.flac", "e.mp3"]
+                    .iter()
+                    .map(PathBuf::from)
+                    .collect(),
+                skipped: vec![],
+                title: None,
+                durations: Default::default(),
+            })
+            .unwrap();
+        let plan = planner.finish();
+        let actions: Vec<_> = plan
+            .tasks
+            .iter()
+            .map(|task| (task.action, task.encoder.map(|encoder| encoder.to_string())))
+            .collect();
+        assert_eq!(
+            actions,
+            [
+                (Action::Convert, Some("64 kbit/s".to_string())),
+                (Action::Remux, None),
+                (Action::Convert, Some("quality 4".to_string())),
+                (Action::Convert, Some("quality 0".to_string())),
+                (Action::Copy, None),
+            ]
+        );
+    }
+
+    #[test]
+    fn long_sources_are_split_into_parts() {
+        let mut planner = Planner::new(Path::new("output"), ConflictPolicy::Error);
+        planner.set_split_long_files(Some(900.0));
+        let mut book = ProbeInfo {
+            duration: Some(2000.0),
+            ..probed(1)
+        };
+        book.tags.insert("title".to_string(), "Book".to_string());
+        let mut chapters = ProbeInfo {
+            duration: Some(100.0),
+            ..probed(1)
+        };
+        chapters.chapters = ["One", "Two"]
+            .iter()
+            .enumerate()
+            .map(|(index, title)| crate::probe::Chapter {
+                start: index as f64 * 50.0,
+                end: (index + 1) as f64 * 50.0,
+                title: Some(title.to_string()),
+            })
+            .collect();
+        planner.set_metadata(HashMap::from([
+            (PathBuf::from("music/Book.mp3"), book),
+            (PathBuf::from("music/Chapters.m4b"), chapters),
+            (PathBuf::from("music/song.mp3"), probed(1)),
+        ]));
+        planner
+            .add_playlist(&InputPlaylist {
+                path: PathBuf::from("music/playlist.m3u"),
+                entries: ["Book.mp3", "song.mp3", "Chapters.m4b"]
+                    .iter()
+                    .map(PathBuf::from)
+                    .collect(),
+                skipped: vec![],
+                title: None,
+                durations: Default::default(),
+            })
+            .unwrap();
+        let plan = planner.finish();
+        assert_eq!(
+            targets(&plan),
+            vec![
+                "Book - 01.mp3",
+                "Book - 02.mp3",
+                "Book - 03.mp3",
+                "song.mp3",
+                "Chapters - 01.mp3",
+                "Chapters - 02.mp3",
+            ]
+        );
+        let book = &plan.tasks[2];
+        assert_eq!(book.action, Action::Remux);
+        assert_eq!(book.tags.get("TIT2"), Some("Book (Part 3)"));
+        assert_eq!(book.tags.get("TRCK"), Some("3/3"));
+        assert_eq!(book.duration(&plan.metadata), Some(200.0));
+        assert_eq!(plan.tasks[3].segment, None);
+        assert_eq!(plan.tasks[5].tags.get("TIT2"), Some("Two"));
+        assert_eq!(plan.tasks[5].action, Action::Convert);
+    }
+
+    #[cfg(windows)]
+    #[test]
+    fn long_paths_do_not_leak_into_entries() {
+        let album = "a".repeat(200);
+        let entry = format!(r"{}\{}\Song.mp3", album, album);
+        let plan = plan_entries(ConflictPolicy::Error, &[&entry]).unwrap();
+        assert_eq!(targets(&plan), vec![entry.as_str()]);
+    }
+}
@@ -0,0 +1,299 @@
+// Copyright (c) 2024 Jan Holthuis <jan.holthuis@rub.de>
+//
+// This Source Code Form is subject to the terms of the Mozilla Public License, v. 2.0. If a copy
+// of the MPL was not distributed with this file, You can obtain one at
+// http://mozilla.org/MPL/2.0/.
+//
+// SPDX-License-Identifier: MPL-2.0
+//! Plan files, which store a computed plan so that it can be reviewed and executed later.
+use crate::longpath;
+use crate::plan::Plan;
+use crate::report;
+use serde::{Deserialize, Serialize};
+use std::fmt;
+use std::path::{Path, PathBuf};
+use std::time::SystemTime;
+use toml::Table;
+
+/// Version of the plan file format. Plan files of other versions are rejected.
+pub const PLAN_VERSION: u32 = 2;
+
+/// Size and modification time of a file.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct FileStamp {
+    pub size: u64,
+    pub modified: Option<SystemTime>,
+}
+
+impl FileStamp {
+    /// Returns the stamp of `path`, or `None` if it does not exist.
+    pub fn of(path: &Path) -> Option<Self> {
+        let metadata = std::fs::metadata(longpath::extended(path)).ok()?;
+        Some(Self {
+            size: metadata.len(),
+            modified: metadata.modified().ok(),
+        })
+    }
+}
+
+/// State of a task's files when the plan was computed.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct TaskSnapshot {
+    pub source: Option<FileStamp>,
+    pub destination: Option<FileStamp>,
+}
+
+/// A difference between the filesystem and the state recorded in a plan file.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Change {
+    /// A source file was removed.
+    SourceMissing(PathBuf),
+    /// A source file was modified.
+    SourceModified(PathBuf),
+    /// An output file was created, modified or removed by someone else.
+    DestinationModified(PathBuf),
+}
+
+impl Change {
+    /// Returns `true` if the plan should not be executed anymore.
+    pub fn is_fatal(&self) -> bool {
+        !matches!(self, Change::SourceModified(_))
+    }
+}
+
+impl fmt::Display for Change {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Change::SourceMissing(path) => {
+                write!(f, "{}: Source no longer exists", path.display())
+            }
+            Change::SourceModified(path) => {
+                write!(f, "{}: Source was modified since planning", path.display())
+            }
+            Change::DestinationModified(path) => write!(
+                f,
+                "{}: Output file was changed since planning",
+                path.display()
+            ),
+        }
+    }
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct PlanFile {
+    pub plan_version: u32,
+    pub tool_version: String,
+    /// The output directory that the plan was computed for.
+    pub output_dir: PathBuf,
+    /// Options that were set when planning, as in a configuration file.
+    pub settings: Table,
+    pub plan: Plan,
+    /// State of the files of each task in [`Plan::tasks`], in the same order.
+    pub snapshot: Vec<TaskSnapshot>,
+}
+
+#[derive(Debug)]
+pub enum PlanFileError {
+    /// The plan file could not be read.
+    Io {
+        path: PathBuf,
+        source: std::io::Error,
+    },
+    /// The plan file is not valid.
+    Parse { path: PathBuf, message: String },
+    /// The plan file was written by an incompatible version.
+    Version { path: PathBuf, version: u32 },
+}
+
+impl fmt::Display for PlanFileError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            PlanFileError::Io { path, source } => {
+                write!(
+                    f,
+                    "{}: Failed to read plan file ({})",
+                    path.display(),
+                    source
+                )
+            }
+            PlanFileError::Parse { path, message } => {
+                write!(
+                    f,
+                    "{}: Failed to parse plan file ({})",
+                    path.display(),
+                    message
+                )
+            }
+            PlanFileError::Version { path, version } => write!(
+                f,
+                "{}: Unsupported plan file version {} (expected {}), run `plan` again",
+                path.display(),
+                version,
+                PLAN_VERSION
+            ),
+        }
+    }
+}
+
+impl std::error::Error for PlanFileError {}
+
+/// Only the version, to check it before parsing the rest.
+#[derive(Deserialize)]
+struct Version {
+    plan_version: u32,
+}
+
+impl PlanFile {
+    pub fn new(plan: Plan, output_dir: &Path, settings: Table) -> Self {
+        let snapshot = plan
+            .tasks
+            .iter()
+            .map(|task| TaskSnapshot {
+                source: FileStamp::of(&task.source),
+                destination: FileStamp::of(&task.destination),
+            })
+            .collect();
+        Self {
+            plan_version: PLAN_VERSION,
+            tool_version: env!("CARGO_PKG_VERSION").to_string(),
+            output_dir: output_dir.to_path_buf(),
+            settings,
+            plan,
+            snapshot,
+        }
+    }
+
+    /// Compare the files of the plan against the snapshot taken when planning. Changes to the
+    /// output files are only reported with `destinations`, e.g. not if the plan is moved to
+    /// another output directory.
+    pub fn changes(&self, destinations: bool) -> Vec<Change> {
+        let mut changes = vec![];
+        for (task, snapshot) in self.plan.tasks.iter().zip(&self.snapshot) {
+            match (snapshot.source, FileStamp::of(&task.source)) {
+                (Some(_), None) => changes.push(Change::SourceMissing(task.source.clone())),
+                (Some(planned), Some(current)) if planned != current => {
+                    changes.push(Change::SourceModified(task.source.clone()))
+                }
+                _ => (),
+            }
+            if destinations && snapshot.destination != FileStamp::of(&task.destination) {
+                changes.push(Change::DestinationModified(task.destination.clone()));
+            }
+        }
+        changes
+    }
+
+    /// Read a plan file.
+    pub fn read(path: &Path) -> Result<Self, PlanFileError> {
+        let contents = std::fs::read_to_string(path).map_err(|source| PlanFileError::Io {
+            path: path.to_path_buf(),
+            source,
+        })?;
+        let parse_error = |e: serde_json::Error| PlanFileError::Parse {
+            path: path.to_path_buf(),
+            message: e.to_string(),
+        };
+        let Version { plan_version } = serde_json::from_str(&contents).map_err(parse_error)?;
+        if plan_version != PLAN_VERSION {
+            return Err(PlanFileError::Version {
+                path: path.to_path_buf(),
+                version: plan_version,
+            });
+        }
+        serde_json::from_str(&contents).map_err(parse_error)
+    }
+
+    /// Write the plan file to `path`, or to stdout if `path` is `-`.
+    pub fn write(&self, path: &Path) -> std::io::Result<()> {
+        report::write_to(path, &serde_json::to_string_pretty(self)?)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::plan::{Action, OutputEntry, PlaylistOutput, Task};
+    use crate::tags::TagOverrides;
+
+    fn plan_file() -> PlanFile {
+        let plan = Plan {
+            playlists: vec![PlaylistOutput {
+                source: PathBuf::from("music/playlist.m3u"),
+                path: PathBuf::from("output/playlist.m3u"),
+                entries: vec![OutputEntry {
+                    target: "a.mp3".to_string(),
+                    task: 0,
+                }],
+                skipped: vec![],
+                title: None,
+            }],
+            tasks: vec![Task {
+                action: Action::Convert,
+                source: PathBuf::from("music/a.flac"),
+                destination: PathBuf::from("output/a.mp3"),
+                tags: TagOverrides::default(),
+                encoder: None,
+                segment: None,
+            }],
+            ..Default::default()
+        };
+        let settings = Table::from_iter([("retries".to_string(), toml::Value::Integer(2))]);
+        PlanFile::new(plan, Path::new("output"), settings)
+    }
+
+    #[test]
+    fn round_trips() {
+        let path = std::env::temp_dir().join(format!("plan-{}.json", std::process::id()));
+        plan_file().write(&path).unwrap();
+        let read = PlanFile::read(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(read.output_dir, PathBuf::from("output"));
+        assert_eq!(read.settings["retries"], toml::Value::Integer(2));
+        assert_eq!(
+            read.plan.tasks[0].destination,
+            PathBuf::from("output/a.mp3")
+        );
+        assert_eq!(
+            read.plan.playlists[0].entries,
+            plan_file().plan.playlists[0].entries
+        );
+    }
+
+    #[test]
+    fn detects_changed_files() {
+        let dir = std::env::temp_dir().join(format!("plan-changes-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let mut plan_file = plan_file();
+        plan_file
+            .plan
+            .relocate(Path::new("output"), &dir.join("output"));
+        plan_file.plan.tasks[0].source = dir.join("a.flac");
+        std::fs::write(dir.join("a.flac"), "DATA").unwrap();
+        let plan_file = PlanFile::new(plan_file.plan, &dir.join("output"), Table::new());
+        assert_eq!(plan_file.changes(true), vec![]);
+
+        std::fs::create_dir_all(dir.join("output")).unwrap();
+        std::fs::write(dir.join("output/a.mp3"), "DATA").unwrap();
+        std::fs::remove_file(dir.join("a.flac")).unwrap();
+        assert_eq!(
+            plan_file.changes(true),
+            vec![
+                Change::SourceMissing(dir.join("a.flac")),
+                Change::DestinationModified(dir.join("output/a.mp3")),
+            ]
+        );
+        assert_eq!(plan_file.changes(false).len(), 1);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn rejects_other_versions() {
+        let path = std::env::temp_dir().join(format!("plan-version-{}.json", std::process::id()));
+        let mut plan_file = plan_file();
+        plan_file.plan_version = PLAN_VERSION + 1;
+        plan_file.write(&path).unwrap();
+        let result = PlanFile::read(&path);
+        std::fs::remove_file(&path).unwrap();
+        assert!(matches!(result, Err(PlanFileError::Version { .. })));
+    }
+}
@@ -0,0 +1,300 @@
+// Copyright (c) 2024 Jan Holthuis <jan.holthuis@rub.de>
+//
+// This Source Code Form is subject to the terms of the Mozilla Public License, v. 2.0. If a copy
+// of the MPL was not distributed with this file, You can obtain one at
+// http://mozilla.org/MPL/2.0/.
+//
+// SPDX-License-Identifier: MPL-2.0
+//! Readers for the supported input playlist formats.
+//!
+//! Each format implements [`PlaylistReader`] in its own module and is registered in [`READERS`].
+//! Output playlists are always written as M3U, with the line endings and encoding of a
+//! [`PlaylistStyle`].
+use clap::ValueEnum;
+use std::path::{Path, PathBuf};
+
+mod m3u;
+mod pls;
+
+pub use self::m3u::M3u;
+pub use self::pls::Pls;
+
+/// Where an entry of a playlist points to.
+#[derive(Clone, Debug, PartialEq)]
+pub enum Location {
+    /// A local file, relative to the playlist's directory unless absolute.
+    Path(PathBuf),
+    Url(String),
+}
+
+/// An entry of an input playlist.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Entry {
+    pub location: Location,
+    /// Title of the track as given in the playlist.
+    pub title: Option<String>,
+    /// Duration of the track in seconds as given in the playlist.
+    pub duration: Option<f64>,
+}
+
+impl Entry {
+    pub fn new(location: Location) -> Self {
+        Self {
+            location,
+            title: None,
+            duration: None,
+        }
+    }
+}
+
+/// Parser for a playlist format.
+pub trait PlaylistReader: Sync {
+    /// Name of the format, e.g. `M3U`.
+    fn name(&self) -> &'static str;
+
+    /// File extensions of the format, in lowercase and without the leading dot.
+    fn extensions(&self) -> &'static [&'static str];
+
+    /// Returns `true` if `contents` look like this format, for files with an unknown extension.
+    fn detect(&self, contents: &[u8]) -> bool;
+
+    /// Parse the entries of a playlist. Entries that cannot be read are returned as errors, so that
+    /// they can be reported and skipped.
+    fn read(&self, contents: &[u8]) -> Vec<Result<Entry, String>>;
+
+    /// Returns the name that the playlist gives itself, if the format supports one.
+    fn title(&self, _contents: &[u8]) -> Option<String> {
+        None
+    }
+}
+
+/// All supported formats.
+pub static READERS: &[&dyn PlaylistReader] = &[&M3u, &Pls];
+
+fn extension(path: &Path) -> Option<String> {
+    path.extension()
+        .map(|extension| extension.to_string_lossy().to_lowercase())
+}
+
+/// Returns the reader for the playlist at `path`, chosen by its extension or else its contents.
+pub fn reader_for(path: &Path, contents: &[u8]) -> Option<&'static dyn PlaylistReader> {
+    let extension = extension(path);
+    READERS
+        .iter()
+        .find(|reader| {
+            extension
+                .as_deref()
+                .is_some_and(|extension| reader.extensions().contains(&extension))
+        })
+        .or_else(|| READERS.iter().find(|reader| reader.detect(contents)))
+        .copied()
+}
+
+/// Returns the supported formats and their extensions for error messages, e.g.
+/// `M3U (.m3u, .m3u8), PLS (.pls)`.
+pub fn supported_formats() -> String {
+    READERS
+        .iter()
+        .map(|reader| {
+            let extensions: Vec<_> = reader
+                .extensions()
+                .iter()
+                .map(|extension| format!(".{}", extension))
+                .collect();
+            format!("{} ({})", reader.name(), extensions.join(", "))
+        })
+        .collect::<Vec<_>>()
+        .join(", ")
+}
+
+/// Returns the file name of the output playlist for an input playlist file name, which gets the
+/// `.m3u` extension unless it already is an M3U playlist, and a lowercase extension in any case.
+pub fn output_file_name(file_name: &Path) -> PathBuf {
+    match extension(file_name) {
+        Some(extension) if M3u.extensions().contains(&extension.as_str()) => {
+            file_name.with_extension(extension)
+        }
+        _ => file_name.with_extension("m3u"),
+    }
+}
+
+/// Line endings of the output playlists.
+#[derive(ValueEnum, Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum LineEnding {
+    #[default]
+    Lf,
+    Crlf,
+}
+
+/// Text encoding of the output playlists.
+#[derive(ValueEnum, Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum PlaylistEncoding {
+    #[default]
+    #[value(name = "utf-8")]
+    Utf8,
+    /// ISO-8859-1, for head units that do not read UTF-8 playlists.
+    Latin1,
+}
+
+/// How the output playlists are written.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct PlaylistStyle {
+    pub line_ending: LineEnding,
+    pub encoding: PlaylistEncoding,
+}
+
+impl PlaylistStyle {
+    /// Render the entries of an output playlist.
+    ///
+    /// Returns the contents and the entries that cannot be represented in the encoding, whose
+    /// other characters are written as `?`.
+    pub fn render<'a>(
+        &self,
+        entries: impl IntoIterator<Item = &'a str>,
+    ) -> (Vec<u8>, Vec<&'a str>) {
+        let line_ending: &[u8] = match self.line_ending {
+            LineEnding::Lf => b"\n",
+            LineEnding::Crlf => b"\r\n",
+        };
+        let mut contents = vec![];
+        let mut unrepresentable = vec![];
+        for entry in entries {
+            match self.encoding {
+                PlaylistEncoding::Utf8 => contents.extend(entry.as_bytes()),
+                PlaylistEncoding::Latin1 => {
+                    if entry.chars().any(|c| c > '\u{ff}') {
+                        unrepresentable.push(entry);
+                    }
+                    contents.extend(entry.chars().map(|c| u8::try_from(c).unwrap_or(b'?')));
+                }
+            }
+            contents.extend(line_ending);
+        }
+        (contents, unrepresentable)
+    }
+}
+
+/// Decode the contents of an output playlist, which are UTF-8 unless they were written as
+/// Latin-1.
+pub fn decode(contents: Vec<u8>) -> String {
+    String::from_utf8(contents)
+        .unwrap_or_else(|e| e.into_bytes().into_iter().map(char::from).collect())
+}
+
+/// Parse the entries of an existing output playlist that was written in `encoding`, e.g. for
+/// `--append`.
+///
+/// Returns why the playlist cannot be read without mangling its entries if it is corrupt or was
+/// written in another encoding.
+pub fn read_output(contents: &[u8], encoding: PlaylistEncoding) -> Result<Vec<String>, String> {
+    if contents.starts_with(b"\xff\xfe") || contents.starts_with(b"\xfe\xff") {
+        return Err("playlist is encoded as UTF-16".to_string());
+    }
+    if contents.contains(&0) {
+        return Err("playlist contains NUL bytes".to_string());
+    }
+    let contents = contents.strip_prefix(b"\xef\xbb\xbf").unwrap_or(contents);
+    let utf8 = std::str::from_utf8(contents);
+    let contents = match (encoding, utf8) {
+        (PlaylistEncoding::Utf8, Ok(contents)) => contents.to_string(),
+        (PlaylistEncoding::Utf8, Err(e)) => {
+            return Err(format!(
+                "playlist is not valid UTF-8 at byte {}, maybe it is Latin-1",
+                e.valid_up_to()
+            ))
+        }
+        (PlaylistEncoding::Latin1, Ok(contents)) if !contents.is_ascii() => {
+            return Err("playlist is encoded as UTF-8 instead of Latin-1".to_string())
+        }
+        (PlaylistEncoding::Latin1, _) => contents.iter().copied().map(char::from).collect(),
+    };
+    Ok(contents
+        .lines()
+        .map(str::trim)
+        .filter(|line| !line.is_empty() && !line.starts_with('#'))
+        .map(String::from)
+        .collect())
+}
+
+/// Returns the contents without leading whitespace and byte order mark.
+fn trim_start(contents: &[u8]) -> &[u8] {
+    let contents = contents.strip_prefix(b"\xef\xbb\xbf").unwrap_or(contents);
+    let start = contents
+        .iter()
+        .position(|byte| !byte.is_ascii_whitespace())
+        .unwrap_or(contents.len());
+    &contents[start..]
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn picks_readers_by_extension_then_content() {
+        let name = |path: &str, contents: &str| {
+            reader_for(Path::new(path), contents.as_bytes()).map(|reader| reader.name())
+        };
+        assert_eq!(name("a.M3U8", "[playlist]"), Some("M3U"));
+        assert_eq!(name("a.pls", ""), Some("PLS"));
+        assert_eq!(
+            name("a.txt", "\u{feff}\n[Playlist]\nFile1=a.mp3"),
+            Some("PLS")
+        );
+        assert_eq!(name("a", "#EXTM3U\na.mp3"), Some("M3U"));
+        assert_eq!(name("a.txt", "a.mp3"), None);
+    }
+
+    #[test]
+    fn renders_line_endings_and_encodings() {
+        let entries = ["Caf\u{e9}/a.mp3", "\u{6771}\u{4eac}.mp3"];
+        let (contents, unrepresentable) = PlaylistStyle::default().render(entries);
+        assert_eq!(
+            contents,
+            "Caf\u{e9}/a.mp3\n\u{6771}\u{4eac}.mp3\n".as_bytes()
+        );
+        assert!(unrepresentable.is_empty());
+
+        let style = PlaylistStyle {
+            line_ending: LineEnding::Crlf,
+            encoding: PlaylistEncoding::Latin1,
+        };
+        let (contents, unrepresentable) = style.render(entries);
+        assert_eq!(contents, b"Caf\xe9/a.mp3\r\n??.mp3\r\n");
+        assert_eq!(unrepresentable, ["\u{6771}\u{4eac}.mp3"]);
+        assert_eq!(decode(contents), "Caf\u{e9}/a.mp3\r\n??.mp3\r\n");
+    }
+
+    #[test]
+    fn reads_output_playlists_in_their_encoding() {
+        let utf8 = "\u{feff}#EXTM3U\r\nCaf\u{e9}/a.mp3\r\n\r\nb.mp3\n";
+        assert_eq!(
+            read_output(utf8.as_bytes(), PlaylistEncoding::Utf8),
+            Ok(vec!["Caf\u{e9}/a.mp3".to_string(), "b.mp3".to_string()])
+        );
+        assert!(read_output(utf8.as_bytes(), PlaylistEncoding::Latin1)
+            .unwrap_err()
+            .contains("UTF-8"));
+        assert_eq!(
+            read_output(b"Caf\xe9.mp3\n", PlaylistEncoding::Latin1),
+            Ok(vec!["Caf\u{e9}.mp3".to_string()])
+        );
+        assert!(read_output(b"Caf\xe9.mp3\n", PlaylistEncoding::Utf8)
+            .unwrap_err()
+            .contains("Latin-1"));
+        assert!(read_output(b"\xff\xfea\0", PlaylistEncoding::Utf8).is_err());
+    }
+
+    #[test]
+    fn lists_supported_formats() {
+        assert_eq!(supported_formats(), "M3U (.m3u, .m3u8), PLS (.pls)");
+    }
+
+    #[test]
+    fn writes_output_playlists_as_m3u() {
+        assert_eq!(output_file_name(Path::new("a.m3u8")), Path::new("a.m3u8"));
+        assert_eq!(output_file_name(Path::new("a.pls")), Path::new("a.m3u"));
+        assert_eq!(output_file_name(Path::new("A.M3U")), Path::new("A.m3u"));
+        assert_eq!(output_file_name(Path::new("a")), Path::new("a.m3u"));
+    }
+}
@@ -0,0 +1,112 @@
+// Copyright (c) 2024 Jan Holthuis <jan.holthuis@rub.de>
+//
+// This Source Code Form is subject to the terms of the Mozilla Public License, v. 2.0. If a copy
+// of the MPL was not distributed with this file, You can obtain one at
+// http://mozilla.org/MPL/2.0/.
+//
+// SPDX-License-Identifier: MPL-2.0
+//! M3U playlists, one path or URL per line.
+use super::{trim_start, Entry, Location, PlaylistReader};
+
+/// Reads M3U and M3U8 playlists. Comments are skipped, except for the durations given by
+/// `#EXTINF` lines and the name given by a `#PLAYLIST` line.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct M3u;
+
+impl PlaylistReader for M3u {
+    fn name(&self) -> &'static str {
+        "M3U"
+    }
+
+    fn extensions(&self) -> &'static [&'static str] {
+        &["m3u", "m3u8"]
+    }
+
+    fn detect(&self, contents: &[u8]) -> bool {
+        trim_start(contents).starts_with(b"#EXTM3U")
+    }
+
+    fn read(&self, contents: &[u8]) -> Vec<Result<Entry, String>> {
+        let mut entries: Vec<_> = ::m3u::Reader::new(contents)
+            .entries()
+            .map(|result| match result {
+                Ok(::m3u::Entry::Path(path)) => Ok(Entry::new(Location::Path(path))),
+                Ok(::m3u::Entry::Url(url)) => Ok(Entry::new(Location::Url(url.to_string()))),
+                Err(e) => Err(e.to_string()),
+            })
+            .collect();
+        let durations = durations(contents);
+        // The durations are only used if they can be matched to the entries unambiguously.
+        if durations.len() == entries.len() {
+            for (entry, duration) in entries.iter_mut().zip(durations) {
+                if let Ok(entry) = entry {
+                    entry.duration = duration;
+                }
+            }
+        }
+        entries
+    }
+
+    fn title(&self, contents: &[u8]) -> Option<String> {
+        String::from_utf8_lossy(contents)
+            .lines()
+            .filter_map(|line| line.trim().strip_prefix("#PLAYLIST:"))
+            .map(|title| title.trim().to_string())
+            .find(|title| !title.is_empty())
+    }
+}
+
+/// Returns the duration given by the `#EXTINF` line before every entry line, in order.
+fn durations(contents: &[u8]) -> Vec<Option<f64>> {
+    let mut durations = vec![];
+    let mut duration = None;
+    for line in String::from_utf8_lossy(contents).lines().map(str::trim) {
+        if let Some(info) = line.strip_prefix("#EXTINF:") {
+            duration = info
+                .split(',')
+                .next()
+                .and_then(|seconds| seconds.trim().parse::<f64>().ok())
+                .filter(|seconds| *seconds >= 0.0);
+        } else if !line.is_empty() && !line.starts_with('#') {
+            durations.push(duration.take());
+        }
+    }
+    durations
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::path::PathBuf;
+
+    #[test]
+    fn reads_paths_and_urls() {
+        let entries = M3u.read(b"#EXTM3U\n#EXTINF:10,Song\na.mp3\n\nhttp://example.com/b.mp3\n");
+        assert_eq!(
+            entries,
+            [
+                Ok(Entry {
+                    duration: Some(10.0),
+                    ..Entry::new(Location::Path(PathBuf::from("a.mp3")))
+                }),
+                Ok(Entry::new(Location::Url(
+                    "http://example.com/b.mp3".to_string()
+                ))),
+            ]
+        );
+    }
+
+    #[test]
+    fn reads_the_playlist_directive() {
+        let contents = b"#EXTM3U\r\n#PLAYLIST: Road Trip \r\na.mp3\r\n";
+        assert_eq!(M3u.title(contents), Some("Road Trip".to_string()));
+        assert_eq!(M3u.read(contents).len(), 1);
+        assert_eq!(M3u.title(b"#PLAYLIST:\na.mp3\n"), None);
+    }
+
+    #[test]
+    fn reports_unreadable_lines() {
+        let entries = M3u.read(b"caf\xe9.mp3\n");
+        assert!(entries[0].is_err());
+    }
+}
@@ -0,0 +1,147 @@
+// Copyright (c) 2024 Jan Holthuis <jan.holthuis@rub.de>
+//
+// This Source Code Form is subject to the terms of the Mozilla Public License, v. 2.0. If a copy
+// of the MPL was not distributed with this file, You can obtain one at
+// http://mozilla.org/MPL/2.0/.
+//
+// SPDX-License-Identifier: MPL-2.0
+//! PLS playlists, an INI-style format with numbered `File`, `Title` and `Length` keys.
+use super::{trim_start, Entry, Location, PlaylistReader};
+use std::collections::BTreeMap;
+use std::path::PathBuf;
+
+/// Reads PLS playlists.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct Pls;
+
+/// Values of a numbered entry.
+#[derive(Default)]
+struct Fields {
+    file: Option<String>,
+    title: Option<String>,
+    length: Option<f64>,
+}
+
+fn location(file: String) -> Location {
+    if file.contains("://") {
+        Location::Url(file)
+    } else {
+        Location::Path(PathBuf::from(file))
+    }
+}
+
+impl PlaylistReader for Pls {
+    fn name(&self) -> &'static str {
+        "PLS"
+    }
+
+    fn extensions(&self) -> &'static [&'static str] {
+        &["pls"]
+    }
+
+    fn detect(&self, contents: &[u8]) -> bool {
+        trim_start(contents)
+            .get(..10)
+            .is_some_and(|header| header.eq_ignore_ascii_case(b"[playlist]"))
+    }
+
+    fn read(&self, contents: &[u8]) -> Vec<Result<Entry, String>> {
+        let contents = String::from_utf8_lossy(trim_start(contents));
+        let mut errors = vec![];
+        let mut fields: BTreeMap<usize, Fields> = BTreeMap::new();
+        for (number, line) in contents.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with([';', '#', '[']) {
+                continue;
+            }
+            let Some((key, value)) = line.split_once('=') else {
+                errors.push(Err(format!("Invalid line {} ({})", number + 1, line)));
+                continue;
+            };
+            let key = key.trim().to_lowercase();
+            let value = value.trim().to_string();
+            let Some((name, index)) = ["file", "title", "length"]
+                .into_iter()
+                .find_map(|name| Some((name, key.strip_prefix(name)?.parse::<usize>().ok()?)))
+            else {
+                // E.g. `NumberOfEntries` and `Version`.
+                continue;
+            };
+            let entry = fields.entry(index).or_default();
+            match name {
+                "file" => entry.file = Some(value),
+                "title" => entry.title = Some(value).filter(|title| !title.is_empty()),
+                _ => entry.length = value.parse().ok().filter(|length: &f64| *length >= 0.0),
+            }
+        }
+        fields
+            .into_iter()
+            .filter_map(|(index, fields)| match fields.file {
+                Some(file) => Some(Ok(Entry {
+                    location: location(file),
+                    title: fields.title,
+                    duration: fields.length,
+                })),
+                None if fields.title.is_some() || fields.length.is_some() => {
+                    Some(Err(format!("Entry {} has no `File{}`", index, index)))
+                }
+                None => None,
+            })
+            .chain(errors)
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn reads_numbered_entries_in_order() {
+        let entries = Pls.read(
+            b"[playlist]\n\
+              File2=http://example.com/stream\n\
+              Title2=Radio\n\
+              Length2=-1\n\
+              file1 = Artist\\song.flac\n\
+              Title1=Song\n\
+              Length1=215\n\
+              NumberOfEntries=2\n\
+              Version=2\n",
+        );
+        assert_eq!(
+            entries,
+            [
+                Ok(Entry {
+                    location: Location::Path(PathBuf::from("Artist\\song.flac")),
+                    title: Some("Song".to_string()),
+                    duration: Some(215.0),
+                }),
+                Ok(Entry {
+                    location: Location::Url("http://example.com/stream".to_string()),
+                    title: Some("Radio".to_string()),
+                    duration: None,
+                }),
+            ]
+        );
+    }
+
+    #[test]
+    fn reports_invalid_lines_and_entries_without_file() {
+        let entries = Pls.read(b"[playlist]\nFile1=a.mp3\nTitle3=Lost\ngarbage\n");
+        assert_eq!(
+            entries,
+            [
+                Ok(Entry::new(Location::Path(PathBuf::from("a.mp3")))),
+                Err("Entry 3 has no `File3`".to_string()),
+                Err("Invalid line 4 (garbage)".to_string()),
+            ]
+        );
+    }
+
+    #[test]
+    fn detects_the_header() {
+        assert!(Pls.detect(b"\n[PlayList]\nFile1=a.mp3"));
+        assert!(!Pls.detect(b"a.mp3"));
+    }
+}
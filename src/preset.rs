@@ -0,0 +1,112 @@
+// Copyright (c) 2024 Jan Holthuis <jan.holthuis@rub.de>
+//
+// This Source Code Form is subject to the terms of the Mozilla Public License, v. 2.0. If a copy
+// of the MPL was not distributed with this file, You can obtain one at
+// http://mozilla.org/MPL/2.0/.
+//
+// SPDX-License-Identifier: MPL-2.0
+//! Built-in presets for head units, for `--preset`.
+//!
+//! A preset sets the defaults of a bundle of options, written like the keys of a configuration
+//! file. Options given on the command line, in the environment or in the configuration file take
+//! precedence. Further devices are added as a variant of [`Preset`] and an entry of [`PRESETS`].
+use clap::ValueEnum;
+use std::fmt;
+use toml::Table;
+
+/// A head unit or kind of device to write the output for.
+#[derive(ValueEnum, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Preset {
+    /// Ford Sync 2, which reads ID3v2.3 tags and Latin-1 playlists with CRLF line endings.
+    Sync2,
+    /// Ford Sync 3, which indexes more files and reads UTF-8 playlists.
+    Sync3,
+    /// Other car stereos and players that read FAT32 USB sticks.
+    GenericUsb,
+}
+
+/// The options that a preset sets.
+pub struct PresetDefinition {
+    pub preset: Preset,
+    /// Options in the format of a configuration file.
+    pub settings: &'static str,
+}
+
+/// The settings of every preset.
+pub const PRESETS: &[PresetDefinition] = &[
+    PresetDefinition {
+        preset: Preset::Sync2,
+        settings: r#"
+            max_files = 15000
+            max_folders = 3000
+            max_playlists = 500
+            max_playlist_entries = 3000
+            max_path_len = 255
+            fix_tags = true
+            id3_encoding = "utf16"
+            bitrate = 192
+            sanitize = "fat32"
+            playlist_line_endings = "crlf"
+            playlist_encoding = "latin1"
+        "#,
+    },
+    PresetDefinition {
+        preset: Preset::Sync3,
+        settings: r#"
+            max_files = 30000
+            max_folders = 5000
+            max_playlists = 1000
+            max_playlist_entries = 10000
+            max_path_len = 255
+            fix_tags = true
+            sanitize = "fat32"
+            playlist_line_endings = "lf"
+            playlist_encoding = "utf-8"
+        "#,
+    },
+    PresetDefinition {
+        preset: Preset::GenericUsb,
+        settings: r#"
+            max_files = 65535
+            max_folders = 65535
+            max_playlists = 65535
+            max_path_len = 255
+            sanitize = "fat32"
+            playlist_line_endings = "crlf"
+            playlist_encoding = "utf-8"
+        "#,
+    },
+];
+
+impl Preset {
+    /// Returns the options that the preset sets.
+    pub fn settings(self) -> Table {
+        PRESETS
+            .iter()
+            .find(|definition| definition.preset == self)
+            .expect("every preset is defined")
+            .settings
+            .parse()
+            .expect("presets are valid TOML")
+    }
+}
+
+impl fmt::Display for Preset {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let value = self.to_possible_value().expect("no preset is skipped");
+        f.write_str(value.get_name())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn every_preset_is_defined() {
+        for preset in Preset::value_variants() {
+            assert!(!preset.settings().is_empty());
+        }
+        assert_eq!(Preset::GenericUsb.to_string(), "generic-usb");
+    }
+}
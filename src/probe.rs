@@ -0,0 +1,241 @@
+// Copyright (c) 2024 Jan Holthuis <jan.holthuis@rub.de>
+//
+// This Source Code Form is subject to the terms of the Mozilla Public License, v. 2.0. If a copy
+// of the MPL was not distributed with this file, You can obtain one at
+// http://mozilla.org/MPL/2.0/.
+//
+// SPDX-License-Identifier: MPL-2.0
+//! Metadata pass using `ffprobe`.
+use crate::tags;
+use crate::warnings::{report_warning, WarningCategory};
+use log::{debug, info};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::process::{Command, Stdio};
+use std::sync::mpsc::channel;
+use threadpool::ThreadPool;
+
+/// Information about a source file as reported by `ffprobe`.
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+pub struct ProbeInfo {
+    /// Duration in seconds.
+    pub duration: Option<f64>,
+    /// Number of audio streams.
+    pub audio_streams: usize,
+    /// Number of attached pictures (e.g. album art).
+    pub pictures: usize,
+    /// Metadata tags, keyed by their lowercase name.
+    pub tags: HashMap<String, String>,
+    /// Rating in stars from 1 to 5, from an ID3 `POPM` frame or a `rating` tag.
+    #[serde(default)]
+    pub rating: Option<u8>,
+    /// Overall bit rate in bits per second.
+    #[serde(default)]
+    pub bit_rate: Option<u64>,
+    /// Codec of the first audio stream, e.g. `mp3` or `flac`.
+    #[serde(default)]
+    pub audio_codec: Option<String>,
+    /// Raw acoustic fingerprint, only computed for `--dedupe-by-fingerprint`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub fingerprint: Option<Vec<u32>>,
+    /// Chapter markers, e.g. of an audiobook.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub chapters: Vec<Chapter>,
+}
+
+/// A chapter marker of a source.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct Chapter {
+    /// Start in seconds.
+    pub start: f64,
+    /// End in seconds.
+    pub end: f64,
+    pub title: Option<String>,
+}
+
+impl ProbeInfo {
+    /// Returns the value of a tag, if it is set and not empty.
+    pub fn tag(&self, name: &str) -> Option<&str> {
+        self.tags
+            .get(name)
+            .map(|value| value.trim())
+            .filter(|value| !value.is_empty())
+    }
+
+    /// Returns the leading number of a tag like `track` (`3` for `3/12`).
+    pub fn number_tag(&self, name: &str) -> Option<u32> {
+        self.tag(name)?.split('/').next()?.trim().parse().ok()
+    }
+}
+
+#[derive(Deserialize)]
+struct FfprobeOutput {
+    #[serde(default)]
+    format: FfprobeFormat,
+    #[serde(default)]
+    streams: Vec<FfprobeStream>,
+    #[serde(default)]
+    chapters: Vec<FfprobeChapter>,
+}
+
+#[derive(Deserialize)]
+struct FfprobeChapter {
+    start_time: Option<String>,
+    end_time: Option<String>,
+    #[serde(default)]
+    tags: HashMap<String, String>,
+}
+
+impl FfprobeChapter {
+    /// Returns the chapter, or `None` if its times are missing or invalid.
+    fn parse(&self) -> Option<Chapter> {
+        let start: f64 = self.start_time.as_deref()?.parse().ok()?;
+        let end: f64 = self.end_time.as_deref()?.parse().ok()?;
+        let title = self
+            .tags
+            .iter()
+            .find(|(name, _)| name.eq_ignore_ascii_case("title"))
+            .map(|(_, title)| title.trim().to_string())
+            .filter(|title| !title.is_empty());
+        (end > start).then_some(Chapter { start, end, title })
+    }
+}
+
+#[derive(Deserialize)]
+struct FfprobeStream {
+    codec_type: Option<String>,
+    codec_name: Option<String>,
+    #[serde(default)]
+    tags: HashMap<String, String>,
+}
+
+#[derive(Deserialize, Default)]
+struct FfprobeFormat {
+    duration: Option<String>,
+    bit_rate: Option<String>,
+    #[serde(default)]
+    tags: HashMap<String, String>,
+}
+
+/// Run `ffprobe` on a single file.
+pub fn probe(path: &Path) -> std::io::Result<ProbeInfo> {
+    let output = Command::new("ffprobe")
+        .args([
+            "-v",
+            "error",
+            "-print_format",
+            "json",
+            "-show_format",
+            "-show_streams",
+            "-show_chapters",
+        ])
+        .arg(path)
+        .stdin(Stdio::null())
+        .output()?;
+    if !output.status.success() {
+        return Err(std::io::Error::other(format!(
+            "ffprobe exited with non-zero status {}",
+            output.status
+        )));
+    }
+    let parsed: FfprobeOutput = serde_json::from_slice(&output.stdout)?;
+    // Some containers (e.g. Ogg) store the tags on the stream instead of the format.
+    let mut tags = HashMap::new();
+    let audio_stream_tags = parsed
+        .streams
+        .iter()
+        .filter(|stream| stream.codec_type.as_deref() == Some("audio"))
+        .flat_map(|stream| stream.tags.iter());
+    for (name, value) in parsed.format.tags.iter().chain(audio_stream_tags) {
+        tags.entry(name.to_lowercase())
+            .or_insert_with(|| value.clone());
+    }
+    let rating = match tags::popm_rating(path) {
+        Ok(Some(rating)) => Some(rating),
+        Ok(None) => tags.get("rating").and_then(|rating| text_rating(rating)),
+        Err(e) => {
+            debug!("{}: Failed to read rating ({})", path.display(), e);
+            None
+        }
+    };
+    Ok(ProbeInfo {
+        rating,
+        bit_rate: parsed
+            .format
+            .bit_rate
+            .as_deref()
+            .and_then(|bit_rate| bit_rate.parse().ok()),
+        duration: parsed
+            .format
+            .duration
+            .and_then(|duration| duration.parse().ok()),
+        audio_streams: parsed
+            .streams
+            .iter()
+            .filter(|stream| stream.codec_type.as_deref() == Some("audio"))
+            .count(),
+        pictures: parsed
+            .streams
+            .iter()
+            .filter(|stream| stream.codec_type.as_deref() == Some("video"))
+            .count(),
+        audio_codec: parsed
+            .streams
+            .iter()
+            .find(|stream| stream.codec_type.as_deref() == Some("audio"))
+            .and_then(|stream| stream.codec_name.clone()),
+        tags,
+        fingerprint: None,
+        chapters: parsed
+            .chapters
+            .iter()
+            .filter_map(FfprobeChapter::parse)
+            .collect(),
+    })
+}
+
+/// Convert a textual `rating` tag to stars. Values up to 5 are taken as stars, larger ones as a
+/// percentage.
+fn text_rating(value: &str) -> Option<u8> {
+    let value: f64 = value.trim().parse().ok()?;
+    let stars = if value <= 5.0 { value } else { value / 20.0 };
+    Some(stars.round().clamp(0.0, 5.0) as u8).filter(|&stars| stars > 0)
+}
+
+/// Probe all given files in parallel.
+///
+/// Files that cannot be probed are logged and left out of the result.
+pub fn probe_all(paths: Vec<PathBuf>, n_workers: usize) -> HashMap<PathBuf, ProbeInfo> {
+    info!("Probing {} files...", paths.len());
+    let num_paths = paths.len();
+    let pool = ThreadPool::new(n_workers);
+    let (tx, rx) = channel();
+    for path in paths {
+        let tx = tx.clone();
+        pool.execute(move || {
+            let result = probe(&path);
+            tx.send((path, result))
+                .expect("channel will be there waiting for the pool");
+        });
+    }
+
+    rx.iter()
+        .take(num_paths)
+        .filter_map(|(path, result)| match result {
+            Ok(info) => {
+                debug!("{}: Probed {:?}", path.display(), info);
+                Some((path, info))
+            }
+            Err(e) => {
+                report_warning!(
+                    WarningCategory::Probe,
+                    "{}: Failed to probe file ({})",
+                    path.display(),
+                    e
+                );
+                None
+            }
+        })
+        .collect()
+}
@@ -0,0 +1,54 @@
+// Copyright (c) 2024 Jan Holthuis <jan.holthuis@rub.de>
+//
+// This Source Code Form is subject to the terms of the Mozilla Public License, v. 2.0. If a copy
+// of the MPL was not distributed with this file, You can obtain one at
+// http://mozilla.org/MPL/2.0/.
+//
+// SPDX-License-Identifier: MPL-2.0
+//! Progress messages on the console.
+use ford_sync_convert::execute::{TaskResult, TaskStatus};
+use ford_sync_convert::observer::{Progress, SyncObserver};
+use ford_sync_convert::plan::{Action, Task};
+use log::info;
+
+/// Logs every finished task. Failures are already logged as warnings by the executor.
+pub struct LogObserver {
+    /// Number of times a failed task is retried.
+    pub retries: usize,
+}
+
+impl SyncObserver for LogObserver {
+    fn task_finished(&self, progress: Progress, task: &Task, result: &TaskResult) {
+        if result.status != TaskStatus::Succeeded {
+            return;
+        }
+        let verb = match result.action(task.action) {
+            Action::Convert => "Conversion",
+            Action::Remux => "Remux",
+            Action::Copy => "Copying",
+        };
+        let elapsed_ms = result.elapsed.as_millis() as u64;
+        let attempts = result.attempts;
+        if result.attempts > 1 {
+            info!(
+                elapsed_ms, attempts;
+                "({}/{}) {}: {} succeeded (attempt {}/{}).",
+                progress.done,
+                progress.total,
+                task.destination.display(),
+                verb,
+                result.attempts,
+                self.retries + 1
+            );
+        } else {
+            info!(
+                elapsed_ms, attempts;
+                "({}/{}) {}: {} succeeded.",
+                progress.done,
+                progress.total,
+                task.destination.display(),
+                verb
+            );
+        }
+    }
+}
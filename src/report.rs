@@ -0,0 +1,1065 @@
+// Copyright (c) 2024 Jan Holthuis <jan.holthuis@rub.de>
+//
+// This Source Code Form is subject to the terms of the Mozilla Public License, v. 2.0. If a copy
+// of the MPL was not distributed with this file, You can obtain one at
+// http://mozilla.org/MPL/2.0/.
+//
+// SPDX-License-Identifier: MPL-2.0
+//! Machine-readable report of a run.
+use crate::config::Source;
+use crate::dedupe::Duplicate;
+use crate::estimate::{self, ByteSize, EstimateOptions};
+use crate::execute::{TaskError, TaskErrorKind, TaskResult, TaskStatus, TranscodeFailureKind};
+use crate::longpath;
+use crate::plan::{Action, Plan};
+use crate::sync::{SyncError, SyncErrorKind};
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, HashSet};
+use std::fmt;
+use std::fmt::Write as _;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+/// Version of the report format. It is increased whenever fields are removed or change their
+/// meaning.
+pub const REPORT_VERSION: u32 = 1;
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct Report {
+    pub report_version: u32,
+    pub tool_version: String,
+    /// Effective values of all options.
+    pub settings: BTreeMap<String, serde_json::Value>,
+    pub counts: Counts,
+    pub timings: Vec<Timing>,
+    pub playlists: Vec<PlaylistReport>,
+    /// Sources that were merged into others by `--dedupe-by-tag`.
+    #[serde(default)]
+    pub duplicates: Vec<Duplicate>,
+    /// Why the run was stopped early, if it was.
+    pub error: Option<String>,
+    /// What kind of error stopped the run.
+    #[serde(default)]
+    pub error_kind: Option<SyncErrorKind>,
+}
+
+/// Number of tasks by outcome, and of skipped playlist entries.
+#[derive(Clone, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Counts {
+    pub converted: usize,
+    pub copied: usize,
+    /// Sources whose MP3 stream was copied into an MP3 file without encoding it.
+    #[serde(default)]
+    pub remuxed: usize,
+    pub failed: usize,
+    pub interrupted: usize,
+    pub not_attempted: usize,
+    pub skipped: usize,
+    /// Failed tasks whose source file does not exist.
+    #[serde(default)]
+    pub missing: usize,
+    /// Entries left out by `--exclude` or `--include-ext`, which are not counted as skipped.
+    #[serde(default)]
+    pub excluded: usize,
+    /// Entries rejected by a tag or duration filter, which are not counted as skipped.
+    #[serde(default)]
+    pub filtered: usize,
+    /// Failed tasks whose `--post-hook` failed.
+    #[serde(default)]
+    pub hook_failed: usize,
+    /// Succeeded tasks that recovered from transient write errors.
+    #[serde(default)]
+    pub recovered: usize,
+    /// Sources that were split into several outputs by `--split-long-files`.
+    #[serde(default)]
+    pub split: usize,
+    /// Outputs of the split sources.
+    #[serde(default)]
+    pub parts: usize,
+}
+
+impl Counts {
+    fn add(&mut self, action: Action, status: TaskStatus, missing: bool) {
+        match (status, action) {
+            (TaskStatus::Succeeded, Action::Convert) => self.converted += 1,
+            (TaskStatus::Succeeded, Action::Copy) => self.copied += 1,
+            (TaskStatus::Succeeded, Action::Remux) => self.remuxed += 1,
+            (TaskStatus::Failed, _) => self.failed += 1,
+            (TaskStatus::Interrupted, _) => self.interrupted += 1,
+            (TaskStatus::NotAttempted, _) => self.not_attempted += 1,
+        }
+        if missing && status == TaskStatus::Failed {
+            self.missing += 1;
+        }
+    }
+}
+
+impl fmt::Display for Counts {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{} converted, {} copied, {} skipped, {} failed, {} missing",
+            self.converted, self.copied, self.skipped, self.failed, self.missing
+        )?;
+        if self.remuxed > 0 {
+            write!(f, ", {} remuxed", self.remuxed)?;
+        }
+        if self.interrupted > 0 {
+            write!(f, ", {} interrupted", self.interrupted)?;
+        }
+        if self.excluded > 0 {
+            write!(f, ", {} excluded", self.excluded)?;
+        }
+        if self.filtered > 0 {
+            write!(f, ", {} filtered", self.filtered)?;
+        }
+        if self.hook_failed > 0 {
+            write!(f, ", {} failed in post-hook", self.hook_failed)?;
+        }
+        if self.recovered > 0 {
+            write!(f, ", {} recovered after retrying", self.recovered)?;
+        }
+        if self.split > 0 {
+            write!(
+                f,
+                ", {} {} split into {} parts",
+                self.split,
+                if self.split == 1 { "source" } else { "sources" },
+                self.parts
+            )?;
+        }
+        Ok(())
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct Timing {
+    pub phase: String,
+    pub seconds: f64,
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct PlaylistReport {
+    pub source: PathBuf,
+    pub output: PathBuf,
+    pub entries: Vec<EntryReport>,
+    /// Skipped entries, including excluded and filtered ones.
+    pub skipped: Vec<SkippedReport>,
+    /// Number of skipped entries that were excluded.
+    #[serde(default)]
+    pub excluded: usize,
+    /// Number of skipped entries that were rejected by a tag or duration filter.
+    #[serde(default)]
+    pub filtered: usize,
+    #[serde(default)]
+    pub stats: PlaylistStats,
+    /// Name given by the input playlist, see [`PlaylistOutput::title`](crate::plan::PlaylistOutput::title).
+    #[serde(default)]
+    pub title: Option<String>,
+}
+
+/// Totals of a playlist, for the statistics table.
+#[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+pub struct PlaylistStats {
+    /// Number of entries, not counting excluded and filtered ones.
+    pub entries: usize,
+    /// Number of distinct files among the entries.
+    pub unique_tracks: usize,
+    /// Total size of the distinct source files in bytes.
+    pub source_size: u64,
+    /// Estimated total size of the distinct output files in bytes.
+    pub estimated_size: u64,
+    /// Total size of the distinct written output files in bytes.
+    pub output_size: u64,
+    /// Total duration of the entries in seconds, if any were probed.
+    pub duration: Option<f64>,
+    pub counts: Counts,
+}
+
+impl PlaylistStats {
+    fn of(playlist: &PlaylistReport) -> Self {
+        let mut seen = HashSet::new();
+        let unique: Vec<_> = playlist
+            .entries
+            .iter()
+            .filter(|entry| seen.insert(&entry.output))
+            .collect();
+        let durations: Vec<_> = playlist
+            .entries
+            .iter()
+            .filter_map(|entry| entry.duration)
+            .collect();
+        Self {
+            entries: playlist.entries.len() + playlist.skipped.len()
+                - playlist.excluded
+                - playlist.filtered,
+            unique_tracks: unique.len(),
+            source_size: unique.iter().filter_map(|entry| entry.source_size).sum(),
+            estimated_size: unique.iter().map(|entry| entry.estimated_size).sum(),
+            output_size: unique.iter().filter_map(|entry| entry.output_size).sum(),
+            duration: (!durations.is_empty()).then(|| durations.iter().sum()),
+            counts: playlist.counts(),
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct EntryReport {
+    /// The path as written into the output playlist.
+    pub target: String,
+    pub source: PathBuf,
+    pub action: Action,
+    pub status: TaskStatus,
+    pub output: PathBuf,
+    pub source_size: Option<u64>,
+    pub output_size: Option<u64>,
+    /// Estimated size of the output file in bytes.
+    #[serde(default)]
+    pub estimated_size: u64,
+    /// Duration of the track in seconds, if it was probed.
+    pub duration: Option<f64>,
+    /// Time spent converting or copying the file in seconds.
+    pub elapsed: f64,
+    pub attempts: usize,
+    pub error: Option<String>,
+    /// What kind of error made the task fail.
+    #[serde(default)]
+    pub error_kind: Option<TaskErrorKind>,
+    /// Why FFmpeg failed, if it did.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub transcode_failure: Option<TranscodeFailureKind>,
+    /// The `--post-hook` failed for this file.
+    #[serde(default)]
+    pub hook_failed: bool,
+    /// Number of transient write errors that were retried.
+    #[serde(default)]
+    pub io_retries: usize,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct SkippedReport {
+    pub entry: String,
+    pub reason: String,
+}
+
+/// Convert the effective settings into the report's settings map.
+pub fn settings(
+    effective: &[(String, toml::Value, Source)],
+) -> BTreeMap<String, serde_json::Value> {
+    effective
+        .iter()
+        .filter_map(|(key, value, _)| Some((key.clone(), serde_json::to_value(value).ok()?)))
+        .collect()
+}
+
+fn file_size(path: &Path) -> Option<u64> {
+    std::fs::metadata(path).ok().map(|metadata| metadata.len())
+}
+
+impl Report {
+    /// Build the report of a run. `results` are the task results in the order of the plan's
+    /// tasks, or empty if no task was executed.
+    pub fn new(
+        plan: &Plan,
+        results: &[TaskResult],
+        estimate_options: &EstimateOptions,
+        settings: BTreeMap<String, serde_json::Value>,
+        timings: &[(&str, Duration)],
+        error: Option<&SyncError>,
+    ) -> Self {
+        let not_attempted = TaskResult::default();
+        let result = |task: usize| results.get(task).unwrap_or(&not_attempted);
+
+        let mut counts = Counts::default();
+        for (index, task) in plan.tasks.iter().enumerate() {
+            let missing = !longpath::extended(&task.source).exists();
+            counts.add(
+                result(index).action(task.action),
+                result(index).status,
+                missing,
+            );
+            if result(index).hook_failed() {
+                counts.hook_failed += 1;
+            }
+            if result(index).recovered() {
+                counts.recovered += 1;
+            }
+        }
+        let parts: Vec<_> = plan
+            .tasks
+            .iter()
+            .filter(|task| task.segment.is_some())
+            .collect();
+        counts.split = parts
+            .iter()
+            .map(|task| &task.source)
+            .collect::<HashSet<_>>()
+            .len();
+        counts.parts = parts.len();
+        for skipped in plan.playlists.iter().flat_map(|playlist| &playlist.skipped) {
+            if skipped.reason.is_excluded() {
+                counts.excluded += 1;
+            } else if skipped.reason.is_filtered() {
+                counts.filtered += 1;
+            } else {
+                counts.skipped += 1;
+            }
+        }
+
+        let playlists = plan
+            .playlists
+            .iter()
+            .map(|playlist| {
+                let mut report = PlaylistReport {
+                    source: playlist.source.clone(),
+                    output: playlist.path.clone(),
+                    entries: playlist
+                        .entries
+                        .iter()
+                        .map(|entry| {
+                            let task = &plan.tasks[entry.task];
+                            let result = result(entry.task);
+                            EntryReport {
+                                target: entry.target.clone(),
+                                source: task.source.clone(),
+                                action: result.action(task.action),
+                                status: result.status,
+                                output: task.destination.clone(),
+                                source_size: file_size(&longpath::extended(&task.source)),
+                                output_size: if result.status == TaskStatus::Succeeded {
+                                    file_size(&longpath::extended(&task.destination))
+                                } else {
+                                    None
+                                },
+                                estimated_size: estimate::estimate_task(
+                                    plan,
+                                    task,
+                                    estimate_options,
+                                ),
+                                duration: task.duration(&plan.metadata),
+                                elapsed: result.elapsed.as_secs_f64(),
+                                attempts: result.attempts,
+                                error: result.error.as_ref().map(ToString::to_string),
+                                error_kind: result.error.as_ref().map(TaskError::kind),
+                                transcode_failure: result
+                                    .error
+                                    .as_ref()
+                                    .and_then(TaskError::transcode_failure),
+                                hook_failed: result.hook_failed(),
+                                io_retries: result.io_retries,
+                            }
+                        })
+                        .collect(),
+                    skipped: playlist
+                        .skipped
+                        .iter()
+                        .map(|skipped| SkippedReport {
+                            entry: skipped.entry.clone(),
+                            reason: skipped.reason.to_string(),
+                        })
+                        .collect(),
+                    excluded: playlist
+                        .skipped
+                        .iter()
+                        .filter(|skipped| skipped.reason.is_excluded())
+                        .count(),
+                    filtered: playlist
+                        .skipped
+                        .iter()
+                        .filter(|skipped| skipped.reason.is_filtered())
+                        .count(),
+                    stats: PlaylistStats::default(),
+                    title: playlist.title.clone(),
+                };
+                report.stats = PlaylistStats::of(&report);
+                report
+            })
+            .collect();
+
+        Self {
+            report_version: REPORT_VERSION,
+            tool_version: env!("CARGO_PKG_VERSION").to_string(),
+            settings,
+            counts,
+            timings: timings
+                .iter()
+                .map(|(phase, duration)| Timing {
+                    phase: phase.to_string(),
+                    seconds: duration.as_secs_f64(),
+                })
+                .collect(),
+            playlists,
+            duplicates: plan.duplicates.clone(),
+            error: error.map(ToString::to_string),
+            error_kind: error.map(SyncError::kind),
+        }
+    }
+
+    /// Total size of the written output files in bytes.
+    pub fn output_size(&self) -> u64 {
+        self.entries().filter_map(|entry| entry.output_size).sum()
+    }
+
+    /// Total duration of the probed tracks.
+    pub fn duration(&self) -> Duration {
+        Duration::from_secs_f64(self.entries().filter_map(|entry| entry.duration).sum())
+    }
+
+    fn entries(&self) -> impl Iterator<Item = &EntryReport> {
+        self.playlists
+            .iter()
+            .flat_map(|playlist| playlist.entries.iter())
+    }
+
+    /// Read a report that was written with `--report-json`.
+    pub fn read_json(path: &Path) -> std::io::Result<Self> {
+        let contents = std::fs::read_to_string(path)?;
+        Ok(serde_json::from_str(&contents)?)
+    }
+
+    /// Write the report as JSON to `path`, or to stdout if `path` is `-`.
+    pub fn write_json(&self, path: &Path) -> std::io::Result<()> {
+        let json = serde_json::to_string_pretty(self)?;
+        write_to(path, &json)
+    }
+
+    /// Write the report as a self-contained HTML page to `path`, or to stdout if `path` is `-`.
+    pub fn write_html(&self, path: &Path) -> std::io::Result<()> {
+        write_to(path, &self.to_html())
+    }
+
+    /// Render the report as a self-contained HTML page.
+    pub fn to_html(&self) -> String {
+        let mut html = String::new();
+        html.push_str(concat!(
+            "<!DOCTYPE html>\n<html lang=\"en\">\n<head>\n<meta charset=\"utf-8\">\n",
+            "<title>ford-sync-convert report</title>\n<style>\n",
+            include_str!("report.css"),
+            "</style>\n</head>\n<body>\n<h1>ford-sync-convert report</h1>\n",
+        ));
+        if let Some(error) = &self.error {
+            let _ = writeln!(
+                html,
+                "<p class=\"failed\">The run was stopped early: {}</p>",
+                escape(error)
+            );
+        }
+
+        let cards = [
+            ("Converted", self.counts.converted.to_string()),
+            ("Copied", self.counts.copied.to_string()),
+            ("Skipped", self.counts.skipped.to_string()),
+            ("Failed", self.counts.failed.to_string()),
+            ("Total size", ByteSize(self.output_size()).to_string()),
+            ("Duration", format_duration(self.duration()).to_string()),
+        ];
+        html.push_str("<div class=\"cards\">\n");
+        for (label, value) in cards {
+            let _ = writeln!(
+                html,
+                "<div class=\"card\"><div class=\"value\">{}</div>{}</div>",
+                escape(&value),
+                label
+            );
+        }
+        html.push_str("</div>\n");
+
+        html.push_str("<h2>Playlists</h2>\n<table>\n<tr>");
+        for column in STATISTICS_COLUMNS {
+            let _ = write!(html, "<th>{}</th>", column);
+        }
+        html.push_str("</tr>\n");
+        for row in self.statistics_rows() {
+            html.push_str("<tr>");
+            for cell in row {
+                let _ = write!(html, "<td>{}</td>", escape(&cell));
+            }
+            html.push_str("</tr>\n");
+        }
+        html.push_str("</table>\n");
+
+        let missing: Vec<_> = self
+            .entries()
+            .filter(|entry| entry.source_size.is_none())
+            .collect();
+        let failed: Vec<_> = self
+            .entries()
+            .filter(|entry| entry.status == TaskStatus::Failed && entry.source_size.is_some())
+            .collect();
+        if !missing.is_empty() || !failed.is_empty() {
+            html.push_str("<section class=\"problems\">\n<h2>Problems</h2>\n");
+            if !missing.is_empty() {
+                html.push_str("<h3>Missing sources</h3>\n<ul>\n");
+                for entry in missing {
+                    let _ = writeln!(html, "<li>{}</li>", escape_path(&entry.source));
+                }
+                html.push_str("</ul>\n");
+            }
+            if !failed.is_empty() {
+                html.push_str("<h3>Failures</h3>\n<ul>\n");
+                for entry in failed {
+                    let _ = writeln!(
+                        html,
+                        "<li>{}: {}</li>",
+                        escape_path(&entry.source),
+                        escape(entry.error.as_deref().unwrap_or("Unknown error"))
+                    );
+                }
+                html.push_str("</ul>\n");
+            }
+            html.push_str("</section>\n");
+        }
+
+        for playlist in &self.playlists {
+            let _ = writeln!(
+                html,
+                "<h2>{}</h2>\n<p>Written to {}</p>",
+                escape(&playlist.name()),
+                escape_path(&playlist.output)
+            );
+            html.push_str(concat!(
+                "<table>\n<tr><th>Entry</th><th>Source</th><th>Action</th><th>Status</th>",
+                "<th>Size</th><th>Details</th></tr>\n",
+            ));
+            for entry in &playlist.entries {
+                let (class, status) = match entry.status {
+                    TaskStatus::NotAttempted => ("", "Not attempted"),
+                    TaskStatus::Succeeded => ("succeeded", "Succeeded"),
+                    TaskStatus::Failed => ("failed", "Failed"),
+                    TaskStatus::Interrupted => ("failed", "Interrupted"),
+                };
+                let action = match entry.action {
+                    Action::Convert => "Convert",
+                    Action::Remux => "Remux",
+                    Action::Copy => "Copy",
+                };
+                let _ = writeln!(
+                    html,
+                    "<tr class=\"{}\"><td>{}</td><td>{}</td><td>{}</td><td>{}</td><td>{}</td><td>{}</td></tr>",
+                    class,
+                    escape(&entry.target),
+                    escape_path(&entry.source),
+                    action,
+                    status,
+                    entry
+                        .output_size
+                        .map(|size| ByteSize(size).to_string())
+                        .unwrap_or_default(),
+                    escape(entry.error.as_deref().unwrap_or_default())
+                );
+            }
+            for skipped in &playlist.skipped {
+                let _ = writeln!(
+                    html,
+                    "<tr class=\"skipped\"><td>{}</td><td></td><td></td><td>Skipped</td><td></td><td>{}</td></tr>",
+                    escape(&skipped.entry),
+                    escape(&skipped.reason)
+                );
+            }
+            html.push_str("</table>\n");
+        }
+
+        let _ = writeln!(
+            html,
+            "<footer>ford-sync-convert {}</footer>\n</body>\n</html>",
+            escape(&self.tool_version)
+        );
+        html
+    }
+}
+
+impl PlaylistReport {
+    /// The name of the playlist as shown in the report, i.e. its title or else its source path.
+    pub fn name(&self) -> String {
+        self.title
+            .clone()
+            .unwrap_or_else(|| self.source.display().to_string())
+    }
+
+    /// Number of entries by outcome. Unlike [`Report::counts`], entries that refer to the same
+    /// file are counted separately.
+    pub fn counts(&self) -> Counts {
+        let mut counts = Counts {
+            skipped: self.skipped.len() - self.excluded - self.filtered,
+            excluded: self.excluded,
+            filtered: self.filtered,
+            ..Default::default()
+        };
+        for entry in &self.entries {
+            counts.add(entry.action, entry.status, entry.source_size.is_none());
+            if entry.hook_failed {
+                counts.hook_failed += 1;
+            }
+            if entry.status == TaskStatus::Succeeded && entry.io_retries > 0 {
+                counts.recovered += 1;
+            }
+        }
+        counts
+    }
+
+    pub fn output_size(&self) -> u64 {
+        self.entries
+            .iter()
+            .filter_map(|entry| entry.output_size)
+            .sum()
+    }
+
+    pub fn duration(&self) -> Duration {
+        Duration::from_secs_f64(self.entries.iter().filter_map(|entry| entry.duration).sum())
+    }
+}
+
+/// Column headers of the per-playlist statistics table.
+const STATISTICS_COLUMNS: [&str; 11] = [
+    "Playlist",
+    "Entries",
+    "Unique",
+    "Source size",
+    "Estimated",
+    "Output size",
+    "Duration",
+    "Converted",
+    "Copied",
+    "Skipped",
+    "Missing",
+];
+
+impl Report {
+    fn statistics_rows(&self) -> Vec<[String; 11]> {
+        self.playlists
+            .iter()
+            .map(|playlist| {
+                let stats = &playlist.stats;
+                [
+                    playlist.name(),
+                    stats.entries.to_string(),
+                    stats.unique_tracks.to_string(),
+                    ByteSize(stats.source_size).to_string(),
+                    ByteSize(stats.estimated_size).to_string(),
+                    ByteSize(stats.output_size).to_string(),
+                    stats
+                        .duration
+                        .map(|seconds| {
+                            format_duration(Duration::from_secs_f64(seconds)).to_string()
+                        })
+                        .unwrap_or_else(|| "-".to_string()),
+                    stats.counts.converted.to_string(),
+                    stats.counts.copied.to_string(),
+                    stats.counts.skipped.to_string(),
+                    stats.counts.missing.to_string(),
+                ]
+            })
+            .collect()
+    }
+
+    /// Lines of the per-playlist statistics table, with aligned columns.
+    pub fn statistics(&self) -> Vec<String> {
+        let rows = self.statistics_rows();
+        let mut widths = STATISTICS_COLUMNS.map(|column| column.chars().count());
+        for row in &rows {
+            for (width, cell) in widths.iter_mut().zip(row) {
+                *width = (*width).max(cell.chars().count());
+            }
+        }
+        let header = STATISTICS_COLUMNS.map(str::to_string);
+        let mut lines = vec!["Playlist statistics:".to_string()];
+        for row in std::iter::once(&header).chain(&rows) {
+            let cells: Vec<_> = row
+                .iter()
+                .zip(widths)
+                .enumerate()
+                .map(|(column, (cell, width))| {
+                    let padding = " ".repeat(width - cell.chars().count());
+                    if column == 0 {
+                        format!("{}{}", cell, padding)
+                    } else {
+                        format!("{}{}", padding, cell)
+                    }
+                })
+                .collect();
+            lines.push(format!("  {}", cells.join("  ").trim_end()));
+        }
+        lines
+    }
+}
+
+/// Maximum number of failures that are listed in the summary.
+const MAX_LISTED_FAILURES: usize = 20;
+
+impl Report {
+    /// Lines of the closing summary. `details` names where to find the full list of failures if
+    /// there are too many to list.
+    pub fn summary(&self, elapsed: Duration, details: &str) -> Vec<String> {
+        let mut lines = vec!["Summary:".to_string()];
+        for playlist in &self.playlists {
+            lines.push(format!(
+                "  {}: {} entries, {}, {} ({})",
+                playlist.name(),
+                playlist.entries.len() + playlist.skipped.len()
+                    - playlist.excluded
+                    - playlist.filtered,
+                playlist.counts(),
+                ByteSize(playlist.output_size()),
+                format_duration(playlist.duration())
+            ));
+        }
+        lines.push(format!(
+            "  Total: {} files, {}, {} ({}) in {}",
+            self.counts.converted
+                + self.counts.copied
+                + self.counts.failed
+                + self.counts.interrupted
+                + self.counts.not_attempted,
+            self.counts,
+            ByteSize(self.output_size()),
+            format_duration(self.duration()),
+            format_duration(elapsed)
+        ));
+        if !self.duplicates.is_empty() {
+            lines.push(format!(
+                "  Merged {} duplicate sources, see {} for details.",
+                self.duplicates.len(),
+                details
+            ));
+        }
+
+        let failures: Vec<_> = self
+            .entries()
+            .filter(|entry| entry.status == TaskStatus::Failed)
+            .collect();
+        if failures.len() > MAX_LISTED_FAILURES {
+            lines.push(format!(
+                "{} files failed, see {} for details.",
+                failures.len(),
+                details
+            ));
+        } else if !failures.is_empty() {
+            lines.push("Failed:".to_string());
+            for entry in failures {
+                lines.push(format!(
+                    "  {}: {}",
+                    entry.source.display(),
+                    entry.error.as_deref().unwrap_or("Unknown error")
+                ));
+            }
+        }
+        lines
+    }
+}
+
+/// Format a duration with second resolution.
+fn format_duration(duration: Duration) -> humantime::FormattedDuration {
+    humantime::format_duration(Duration::from_secs(duration.as_secs()))
+}
+
+/// Write `contents` to `path`, or to stdout if `path` is `-`.
+pub fn write_to(path: &Path, contents: &str) -> std::io::Result<()> {
+    if path == Path::new("-") {
+        let mut stdout = std::io::stdout().lock();
+        writeln!(stdout, "{}", contents)?;
+        return stdout.flush();
+    }
+    if let Some(parent) = path
+        .parent()
+        .filter(|parent| !parent.as_os_str().is_empty())
+    {
+        std::fs::create_dir_all(parent)?;
+    }
+    std::fs::write(path, format!("{}\n", contents))
+}
+
+/// Escape text for use in HTML element content and attribute values.
+fn escape(text: &str) -> String {
+    let mut escaped = String::with_capacity(text.len());
+    for c in text.chars() {
+        match c {
+            '&' => escaped.push_str("&amp;"),
+            '<' => escaped.push_str("&lt;"),
+            '>' => escaped.push_str("&gt;"),
+            '"' => escaped.push_str("&quot;"),
+            '\'' => escaped.push_str("&#39;"),
+            c => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+fn escape_path(path: &Path) -> String {
+    escape(&path.to_string_lossy())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::execute::ExecuteError;
+    use crate::plan::{OutputEntry, PlaylistOutput, SkipReason, SkippedEntry, Task};
+    use crate::tags::TagOverrides;
+
+    fn plan() -> Plan {
+        Plan {
+            playlists: vec![PlaylistOutput {
+                source: PathBuf::from("music/playlist.m3u"),
+                path: PathBuf::from("output/playlist.m3u"),
+                entries: vec![
+                    OutputEntry {
+                        target: "a.mp3".to_string(),
+                        task: 0,
+                    },
+                    OutputEntry {
+                        target: "b.mp3".to_string(),
+                        task: 1,
+                    },
+                ],
+                skipped: vec![SkippedEntry {
+                    entry: "c".to_string(),
+                    reason: SkipReason::NoExtension,
+                }],
+                title: None,
+            }],
+            tasks: vec![
+                Task {
+                    action: Action::Copy,
+                    source: PathBuf::from("music/a.mp3"),
+                    destination: PathBuf::from("output/a.mp3"),
+                    tags: TagOverrides::default(),
+                    encoder: None,
+                    segment: None,
+                },
+                Task {
+                    action: Action::Convert,
+                    source: PathBuf::from("music/b.flac"),
+                    destination: PathBuf::from("output/b.mp3"),
+                    tags: TagOverrides::default(),
+                    encoder: None,
+                    segment: None,
+                },
+            ],
+            ..Default::default()
+        }
+    }
+
+    fn estimate_options() -> EstimateOptions {
+        EstimateOptions {
+            minutes_per_track: 4.0,
+        }
+    }
+
+    #[test]
+    fn counts_task_outcomes() {
+        let results = vec![
+            TaskResult {
+                status: TaskStatus::Succeeded,
+                attempts: 1,
+                io_retries: 2,
+                ..Default::default()
+            },
+            TaskResult {
+                status: TaskStatus::Failed,
+                io_retries: 1,
+                error: Some(TaskError::TranscodeFailed {
+                    status: "exit status: 1".to_string(),
+                    kind: TranscodeFailureKind::Other,
+                    stderr: String::new(),
+                    log_path: None,
+                }),
+                attempts: 2,
+                ..Default::default()
+            },
+        ];
+        let report = Report::new(
+            &plan(),
+            &results,
+            &estimate_options(),
+            BTreeMap::new(),
+            &[],
+            None,
+        );
+        assert_eq!(
+            report.counts,
+            Counts {
+                copied: 1,
+                failed: 1,
+                skipped: 1,
+                missing: 1,
+                recovered: 1,
+                ..Default::default()
+            }
+        );
+        assert!(report
+            .counts
+            .to_string()
+            .ends_with(", 1 recovered after retrying"));
+        let entries = &report.playlists[0].entries;
+        assert_eq!(entries[1].status, TaskStatus::Failed);
+        assert_eq!(entries[1].attempts, 2);
+        assert_eq!(
+            entries[1].error.as_deref(),
+            Some("FFmpeg exited with non-zero status exit status: 1")
+        );
+        assert_eq!(entries[1].error_kind, Some(TaskErrorKind::TranscodeFailed));
+        assert_eq!(
+            entries[1].transcode_failure,
+            Some(TranscodeFailureKind::Other)
+        );
+    }
+
+    #[test]
+    fn round_trips_through_json() {
+        let settings = BTreeMap::from([("retries".to_string(), serde_json::Value::from(2))]);
+        let report = Report::new(
+            &plan(),
+            &[],
+            &estimate_options(),
+            settings,
+            &[("Planning", Duration::from_millis(1500))],
+            Some(&SyncError::Execute(ExecuteError::OutputNotWritable {
+                output_dir: PathBuf::from("/media/usb"),
+                failed: 2,
+                not_attempted: 0,
+                reason: "Read-only file system".to_string(),
+            })),
+        );
+        assert_eq!(report.counts.not_attempted, 2);
+        assert_eq!(report.error_kind, Some(SyncErrorKind::OutputNotWritable));
+
+        let json = serde_json::to_string(&report).unwrap();
+        let value: serde_json::Value = serde_json::from_str(&json).unwrap();
+        assert_eq!(value["report_version"], REPORT_VERSION);
+        assert_eq!(value["error_kind"], "output_not_writable");
+        assert_eq!(value["playlists"][0]["entries"][1]["action"], "convert");
+        assert_eq!(
+            value["playlists"][0]["entries"][0]["status"],
+            "not_attempted"
+        );
+        assert_eq!(serde_json::from_str::<Report>(&json).unwrap(), report);
+    }
+
+    #[test]
+    fn html_lists_failures() {
+        let results = vec![
+            TaskResult::default(),
+            TaskResult {
+                status: TaskStatus::Failed,
+                error: Some(TaskError::OutputValidation(
+                    "<duration> differs".to_string(),
+                )),
+                attempts: 1,
+                ..Default::default()
+            },
+        ];
+        let html = Report::new(
+            &plan(),
+            &results,
+            &estimate_options(),
+            BTreeMap::new(),
+            &[],
+            None,
+        )
+        .to_html();
+        assert!(html.starts_with("<!DOCTYPE html>"));
+        assert!(html.contains("<h3>Missing sources</h3>"));
+        assert!(html.contains("Output verification failed (&lt;duration&gt; differs)"));
+        assert!(html.contains("Failed to determine file extension"));
+        assert!(!html.contains("<duration>"));
+    }
+
+    #[test]
+    fn summary_lists_failures() {
+        let results = vec![
+            TaskResult {
+                status: TaskStatus::Succeeded,
+                ..Default::default()
+            },
+            TaskResult {
+                status: TaskStatus::Failed,
+                error: Some(TaskError::TranscodeFailed {
+                    status: "exit status: 1".to_string(),
+                    kind: TranscodeFailureKind::Other,
+                    stderr: String::new(),
+                    log_path: None,
+                }),
+                ..Default::default()
+            },
+        ];
+        let report = Report::new(
+            &plan(),
+            &results,
+            &estimate_options(),
+            BTreeMap::new(),
+            &[],
+            None,
+        );
+        let lines = report.summary(Duration::from_secs(90), "the log");
+        assert_eq!(
+            lines[1],
+            "  music/playlist.m3u: 3 entries, 0 converted, 1 copied, 1 skipped, 1 failed, 1 missing, 0 B (0s)"
+        );
+        assert_eq!(
+            lines[2],
+            "  Total: 2 files, 0 converted, 1 copied, 1 skipped, 1 failed, 1 missing, 0 B (0s) in 1m 30s"
+        );
+        assert_eq!(
+            lines[4],
+            "  music/b.flac: FFmpeg exited with non-zero status exit status: 1"
+        );
+    }
+
+    #[test]
+    fn summary_points_to_details_for_many_failures() {
+        let mut plan = plan();
+        let failed = TaskResult {
+            status: TaskStatus::Failed,
+            ..Default::default()
+        };
+        let entry = plan.playlists[0].entries[1].clone();
+        plan.playlists[0]
+            .entries
+            .extend(std::iter::repeat_n(entry, MAX_LISTED_FAILURES));
+        let report = Report::new(
+            &plan,
+            &[failed.clone(), failed],
+            &estimate_options(),
+            BTreeMap::new(),
+            &[],
+            None,
+        );
+        let lines = report.summary(Duration::ZERO, "report.json");
+        assert_eq!(
+            lines.last().unwrap(),
+            "22 files failed, see report.json for details."
+        );
+    }
+
+    #[test]
+    fn statistics_align_columns() {
+        let mut plan = plan();
+        let entry = plan.playlists[0].entries[0].clone();
+        plan.playlists[0].entries.push(entry);
+        plan.metadata.insert(
+            PathBuf::from("music/b.flac"),
+            crate::probe::ProbeInfo {
+                duration: Some(90.0),
+                ..Default::default()
+            },
+        );
+        let report = Report::new(&plan, &[], &estimate_options(), BTreeMap::new(), &[], None);
+        let stats = &report.playlists[0].stats;
+        assert_eq!(stats.entries, 4);
+        assert_eq!(stats.unique_tracks, 2);
+        assert_eq!(stats.estimated_size, 90 * 190_000 / 8);
+        assert_eq!(stats.duration, Some(90.0));
+
+        let lines = report.statistics();
+        assert_eq!(lines.len(), 3);
+        assert!(lines[1].starts_with("  Playlist            Entries  Unique"));
+        assert!(lines[2].starts_with("  music/playlist.m3u        4       2"));
+        assert!(lines[2].contains("  1m 30s  "));
+        assert_eq!(lines[1].len(), lines[2].len());
+    }
+
+    #[test]
+    fn escapes_html() {
+        assert_eq!(escape("a & <b> \"c\""), "a &amp; &lt;b&gt; &quot;c&quot;");
+    }
+}
@@ -0,0 +1,464 @@
+// Copyright (c) 2024 Jan Holthuis <jan.holthuis@rub.de>
+//
+// This Source Code Form is subject to the terms of the Mozilla Public License, v. 2.0. If a copy
+// of the MPL was not distributed with this file, You can obtain one at
+// http://mozilla.org/MPL/2.0/.
+//
+// SPDX-License-Identifier: MPL-2.0
+//! Resolution of playlist entries that do not exist as written.
+//!
+//! Entries often differ from the actual files only by case (`the beatles` for `The Beatles`) or
+//! by Unicode normalization (NFD names written on macOS for NFC names). Such entries are resolved
+//! component by component against the names in the same directory, compared case-insensitively
+//! and after normalizing them to NFC.
+//!
+//! With `--resolve-missing`, entries that still do not exist are looked up by their file name in
+//! a [`LibraryIndex`] of a library directory, e.g. after albums were moved to other folders.
+use crate::exclude::Extensions;
+use crate::longpath;
+use crate::plan::InputPlaylist;
+use crate::probe::{self, ProbeInfo};
+use crate::scan;
+use crate::transliterate;
+use crate::warnings::{report_warning, WarningCategory};
+use log::{debug, info, warn};
+use std::collections::HashMap;
+use std::ffi::OsString;
+use std::path::{Component, Path, PathBuf};
+use std::sync::mpsc::channel;
+use threadpool::ThreadPool;
+
+/// Maximum difference in seconds between the duration that a playlist gives for an entry and the
+/// duration of a file in the library for the file to be taken as the entry.
+const DURATION_TOLERANCE: f64 = 2.0;
+
+/// Returns the name that names are compared by, i.e. lowercase and in NFC.
+fn key(name: &str) -> String {
+    transliterate::normalize_name(name).to_lowercase()
+}
+
+/// Returns the number of characters that need to be inserted, removed or replaced to turn `a`
+/// into `b`.
+fn distance(a: &str, b: &str) -> usize {
+    let b: Vec<char> = b.chars().collect();
+    let mut previous: Vec<usize> = (0..=b.len()).collect();
+    for (i, a) in a.chars().enumerate() {
+        let mut current = vec![i + 1];
+        for (j, b) in b.iter().enumerate() {
+            let substitution = previous[j] + usize::from(a != *b);
+            current.push(substitution.min(previous[j + 1] + 1).min(current[j] + 1));
+        }
+        previous = current;
+    }
+    previous[b.len()]
+}
+
+/// Resolves entries against the files that exist, reading every directory at most once.
+#[derive(Debug, Default)]
+pub struct Resolver {
+    /// Maximum number of characters that a name may differ by after ignoring case and Unicode
+    /// normalization.
+    max_distance: usize,
+    /// Names in the directories that were read so far.
+    dirs: HashMap<PathBuf, Vec<OsString>>,
+}
+
+impl Resolver {
+    pub fn new(max_distance: usize) -> Self {
+        Self {
+            max_distance,
+            dirs: HashMap::new(),
+        }
+    }
+
+    /// Returns the names in `dir`, or none if it cannot be read.
+    fn names(&mut self, dir: &Path) -> &[OsString] {
+        self.dirs.entry(dir.to_path_buf()).or_insert_with(|| {
+            std::fs::read_dir(longpath::extended(dir))
+                .map(|entries| {
+                    entries
+                        .filter_map(|entry| entry.ok())
+                        .map(|entry| entry.file_name())
+                        .collect()
+                })
+                .unwrap_or_default()
+        })
+    }
+
+    /// Returns the name in `dir` that is closest to `name`, if it is close enough and no other
+    /// name is as close.
+    fn closest(&mut self, dir: &Path, name: &str) -> Option<OsString> {
+        let wanted = key(name);
+        let max_distance = self.max_distance;
+        let mut candidates: Vec<(usize, &OsString)> = self
+            .names(dir)
+            .iter()
+            .filter_map(|candidate| {
+                let distance = distance(&wanted, &key(&candidate.to_string_lossy()));
+                (distance <= max_distance).then_some((distance, candidate))
+            })
+            .collect();
+        candidates.sort();
+        match candidates[..] {
+            [] => None,
+            [(first, _), (second, _), ..] if first == second => {
+                debug!(
+                    "{}: Several names in {} match, not resolving",
+                    name,
+                    dir.display()
+                );
+                None
+            }
+            [(_, candidate), ..] => Some(candidate.clone()),
+        }
+    }
+
+    /// Returns `entry`, relative to `base` unless absolute, with every component that does not
+    /// exist replaced by a matching name, or `None` if a component has no match.
+    pub fn resolve(&mut self, base: &Path, entry: &Path) -> Option<PathBuf> {
+        let mut resolved = PathBuf::new();
+        for component in entry.components() {
+            let Component::Normal(name) = component else {
+                resolved.push(component);
+                continue;
+            };
+            let dir = base.join(&resolved);
+            if longpath::extended(&dir.join(name))
+                .symlink_metadata()
+                .is_ok()
+            {
+                resolved.push(name);
+                continue;
+            }
+            resolved.push(self.closest(&dir, &name.to_string_lossy())?);
+        }
+        Some(resolved)
+    }
+
+    /// Replace the entries of the playlist whose sources do not exist by the files they
+    /// resolve to. Returns the number of replaced entries.
+    pub fn fix_entries(&mut self, playlist: &mut InputPlaylist) -> usize {
+        let dir = playlist.dir();
+        let mut num_fixed = 0;
+        for index in 0..playlist.entries.len() {
+            let source = playlist.source(&playlist.entries[index]);
+            if longpath::extended(&source).exists() {
+                continue;
+            }
+            let Some(entry) = self
+                .resolve(&dir, &playlist.entries[index])
+                .filter(|entry| *entry != playlist.entries[index])
+            else {
+                continue;
+            };
+            info!(
+                "{}: Using {} instead",
+                source.display(),
+                playlist.source(&entry).display()
+            );
+            playlist.entries[index] = entry;
+            num_fixed += 1;
+        }
+        num_fixed
+    }
+}
+
+/// Returns `path` relative to `base`, going up with `..` where necessary.
+fn relative_to(path: &Path, base: &Path) -> PathBuf {
+    let path = std::path::absolute(path).unwrap_or_else(|_| path.to_path_buf());
+    let base = std::path::absolute(base).unwrap_or_else(|_| base.to_path_buf());
+    let common = path
+        .components()
+        .zip(base.components())
+        .take_while(|(a, b)| a == b)
+        .count();
+    if common == 0 {
+        return path;
+    }
+    let mut relative: PathBuf = base
+        .components()
+        .skip(common)
+        .filter(|component| *component != Component::CurDir)
+        .map(|_| Component::ParentDir)
+        .collect();
+    relative.extend(path.components().skip(common));
+    relative
+}
+
+/// The audio files below a library directory by file name, for `--resolve-missing`.
+#[derive(Debug, Default)]
+pub struct LibraryIndex {
+    /// Paths of the files, keyed by their file name as compared by [`key`].
+    files: HashMap<String, Vec<PathBuf>>,
+}
+
+impl LibraryIndex {
+    /// Index the audio files below `root`, scanning its folders in parallel.
+    pub fn build(root: &Path, extensions: &Extensions, n_workers: usize) -> std::io::Result<Self> {
+        info!("Indexing Library: {}", root.display());
+        let mut files = vec![];
+        let pool = ThreadPool::new(n_workers);
+        let (tx, rx) = channel();
+        for entry in std::fs::read_dir(longpath::extended(root))? {
+            let entry = entry?;
+            if entry.file_name().to_string_lossy().starts_with('.') {
+                continue;
+            }
+            let path = root.join(entry.file_name());
+            if !entry.file_type()?.is_dir() {
+                if path.extension().is_some() && extensions.includes(&path) {
+                    files.push(path);
+                }
+                continue;
+            }
+            let tx = tx.clone();
+            let extensions = extensions.clone();
+            pool.execute(move || {
+                let result = scan::audio_files(&path, &extensions);
+                tx.send((path, result)).expect("channel is open");
+            });
+        }
+        drop(tx);
+        for (dir, result) in rx.iter() {
+            match result {
+                Ok(found) => files.extend(found.into_iter().map(|file| dir.join(file))),
+                Err(e) => warn!("{}: Failed to index folder ({})", dir.display(), e),
+            }
+        }
+        let mut index = Self::default();
+        for file in files {
+            let name = key(&file.file_name().unwrap_or_default().to_string_lossy());
+            index.files.entry(name).or_default().push(file);
+        }
+        for paths in index.files.values_mut() {
+            paths.sort();
+        }
+        info!("Indexed {} files", index.len());
+        Ok(index)
+    }
+
+    /// Returns the number of indexed files.
+    pub fn len(&self) -> usize {
+        self.files.values().map(Vec::len).sum()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.files.is_empty()
+    }
+
+    /// Returns the file in the library for an entry with the file name of `source`.
+    ///
+    /// If several files have that name, the one whose duration matches `duration` is returned,
+    /// probing them into `metadata` as needed. Entries without a duration or with several
+    /// matching files are not resolved.
+    fn find(
+        &self,
+        source: &Path,
+        duration: Option<f64>,
+        metadata: &mut HashMap<PathBuf, ProbeInfo>,
+        n_workers: usize,
+    ) -> Option<&PathBuf> {
+        let name = key(&source.file_name()?.to_string_lossy());
+        let candidates = self.files.get(&name)?;
+        if let [candidate] = &candidates[..] {
+            return Some(candidate);
+        }
+        let matching: Vec<&PathBuf> = match duration {
+            Some(duration) => {
+                let unprobed: Vec<PathBuf> = candidates
+                    .iter()
+                    .filter(|candidate| !metadata.contains_key(*candidate))
+                    .cloned()
+                    .collect();
+                if !unprobed.is_empty() {
+                    metadata.extend(probe::probe_all(unprobed, n_workers));
+                }
+                candidates
+                    .iter()
+                    .filter(|candidate| {
+                        metadata
+                            .get(*candidate)
+                            .and_then(|info| info.duration)
+                            .is_some_and(|found| (found - duration).abs() <= DURATION_TOLERANCE)
+                    })
+                    .collect()
+            }
+            None => vec![],
+        };
+        if let [candidate] = &matching[..] {
+            return Some(candidate);
+        }
+        report_warning!(
+            WarningCategory::AmbiguousEntry,
+            "{}: Found {} files of the same name in the library, leaving the entry missing ({})",
+            source.display(),
+            candidates.len(),
+            candidates
+                .iter()
+                .map(|candidate| candidate.display().to_string())
+                .collect::<Vec<_>>()
+                .join(", ")
+        );
+        None
+    }
+
+    /// Replace the entries of the playlist whose sources do not exist by the files of the same
+    /// name in the library. Returns the number of replaced entries.
+    pub fn fix_entries(
+        &self,
+        playlist: &mut InputPlaylist,
+        metadata: &mut HashMap<PathBuf, ProbeInfo>,
+        n_workers: usize,
+    ) -> usize {
+        let dir = playlist.dir();
+        let mut num_fixed = 0;
+        for index in 0..playlist.entries.len() {
+            let source = playlist.source(&playlist.entries[index]);
+            if longpath::extended(&source).exists() {
+                continue;
+            }
+            let duration = playlist.durations.get(&playlist.entries[index]).copied();
+            let Some(found) = self.find(&source, duration, metadata, n_workers) else {
+                continue;
+            };
+            info!(
+                "{}: Using {} from the library instead",
+                source.display(),
+                found.display()
+            );
+            let entry = relative_to(found, &dir);
+            if let Some(duration) = playlist.durations.remove(&playlist.entries[index]) {
+                playlist.durations.insert(entry.clone(), duration);
+            }
+            playlist.entries[index] = entry;
+            num_fixed += 1;
+        }
+        num_fixed
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn counts_differing_characters() {
+        assert_eq!(distance("abbey road", "abbey road"), 0);
+        assert_eq!(distance("abbey road", "abby road"), 1);
+        assert_eq!(distance("abbey road", "abbey roads"), 1);
+        assert_eq!(distance("", "abc"), 3);
+    }
+
+    #[test]
+    fn resolves_case_and_normalization() {
+        let dir = std::env::temp_dir().join(format!("resolve-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        let album = dir.join("The Beatles/Abbey Road");
+        std::fs::create_dir_all(&album).unwrap();
+        for file in [
+            "01 Come Together.flac",
+            "Caf\u{e9}.flac",
+            "Song.flac",
+            "song.FLAC",
+        ] {
+            std::fs::write(album.join(file), b"").unwrap();
+        }
+
+        let mut resolver = Resolver::new(0);
+        let mut resolve = |entry: &str| resolver.resolve(&dir, Path::new(entry));
+        assert_eq!(
+            resolve("the beatles/abbey road/01 come together.flac"),
+            Some(PathBuf::from(
+                "The Beatles/Abbey Road/01 Come Together.flac"
+            ))
+        );
+        assert_eq!(
+            resolve("The Beatles/Abbey Road/Cafe\u{301}.flac"),
+            Some(PathBuf::from("The Beatles/Abbey Road/Caf\u{e9}.flac"))
+        );
+        assert_eq!(resolve("The Beatles/Abbey Road/Come Together.flac"), None);
+        // Both `Song.flac` and `song.FLAC` match, so neither is guessed.
+        assert_eq!(resolve("The Beatles/Abbey Road/SONG.flac"), None);
+
+        let mut resolver = Resolver::new(3);
+        assert_eq!(
+            resolver.resolve(
+                &dir,
+                Path::new("the beatles/abbey road/1 Come Together.flac")
+            ),
+            Some(PathBuf::from(
+                "The Beatles/Abbey Road/01 Come Together.flac"
+            ))
+        );
+
+        let mut playlist = InputPlaylist {
+            path: dir.join("playlist.m3u"),
+            entries: vec![
+                PathBuf::from("The Beatles/Abbey Road/Song.flac"),
+                PathBuf::from("the beatles/abbey road/cafe\u{301}.flac"),
+                PathBuf::from("missing.flac"),
+            ],
+            skipped: vec![],
+            title: None,
+            durations: Default::default(),
+        };
+        assert_eq!(Resolver::new(0).fix_entries(&mut playlist), 1);
+        assert_eq!(
+            playlist.entries[1],
+            Path::new("The Beatles/Abbey Road/Caf\u{e9}.flac")
+        );
+        assert_eq!(playlist.entries[2], Path::new("missing.flac"));
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn finds_moved_files_in_the_library() {
+        let dir = std::env::temp_dir().join(format!("resolve-library-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        for folder in [
+            "lists",
+            "library/Moved",
+            "library/A",
+            "library/B",
+            "library/.hidden",
+        ] {
+            std::fs::create_dir_all(dir.join(folder)).unwrap();
+        }
+        for file in [
+            "library/Moved/Song.flac",
+            "library/A/Intro.flac",
+            "library/B/Intro.flac",
+            "library/.hidden/Hidden.flac",
+            "library/cover.jpg",
+        ] {
+            std::fs::write(dir.join(file), b"").unwrap();
+        }
+
+        let extensions = Extensions::default();
+        let index = LibraryIndex::build(&dir.join("library"), &extensions, 2).unwrap();
+        assert_eq!(index.len(), 4);
+        let mut playlist = InputPlaylist {
+            path: dir.join("lists/playlist.m3u"),
+            entries: vec![
+                PathBuf::from("Old/song.flac"),
+                PathBuf::from("Old/Intro.flac"),
+                PathBuf::from("Old/Hidden.flac"),
+            ],
+            skipped: vec![],
+            title: None,
+            durations: HashMap::from([(PathBuf::from("Old/song.flac"), 10.0)]),
+        };
+        assert_eq!(index.fix_entries(&mut playlist, &mut HashMap::new(), 2), 1);
+        assert_eq!(playlist.entries[0], Path::new("../library/Moved/Song.flac"));
+        assert_eq!(
+            playlist
+                .durations
+                .get(Path::new("../library/Moved/Song.flac")),
+            Some(&10.0)
+        );
+        // Without a duration, the two files named `Intro.flac` cannot be told apart.
+        assert_eq!(playlist.entries[1], Path::new("Old/Intro.flac"));
+        assert_eq!(playlist.entries[2], Path::new("Old/Hidden.flac"));
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
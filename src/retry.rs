@@ -0,0 +1,253 @@
+// Copyright (c) 2024 Jan Holthuis <jan.holthuis@rub.de>
+//
+// This Source Code Form is subject to the terms of the Mozilla Public License, v. 2.0. If a copy
+// of the MPL was not distributed with this file, You can obtain one at
+// http://mozilla.org/MPL/2.0/.
+//
+// SPDX-License-Identifier: MPL-2.0
+//! Retries of transient errors writing to the output, for `--io-retries`.
+//!
+//! USB sticks and SD cards occasionally fail single writes with I/O errors or timeouts, and
+//! usually recover after a short moment. Such errors are retried with exponential backoff, while
+//! errors that will not go away by waiting, like missing permissions, fail right away. When
+//! several workers hit errors at the same time, all writes are paused to let the device recover.
+use crate::estimate;
+use crate::interrupt;
+use humantime::format_duration;
+use log::warn;
+use std::cell::Cell;
+use std::io::{self, ErrorKind};
+use std::path::Path;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+/// Delay before the first retry, doubled for every further one.
+const BASE_DELAY: Duration = Duration::from_secs(1);
+
+/// Longest delay between two retries.
+const MAX_DELAY: Duration = Duration::from_secs(30);
+
+/// Number of failed writes within [`STALL_WINDOW`] after which all writes are paused.
+const STALL_FAILURES: usize = 2;
+
+const STALL_WINDOW: Duration = Duration::from_secs(5);
+
+/// How long all writes are paused when the output device stalls.
+const STALL_PAUSE: Duration = Duration::from_secs(10);
+
+/// Returns `true` if the error may go away by retrying, e.g. `EIO` or a timeout.
+///
+/// Running out of space is not transient by itself, see [`Retrier::run`].
+pub fn is_transient(error: &io::Error) -> bool {
+    #[cfg(unix)]
+    if error.raw_os_error() == Some(libc::EIO) {
+        return true;
+    }
+    matches!(
+        error.kind(),
+        ErrorKind::TimedOut
+            | ErrorKind::Interrupted
+            | ErrorKind::WouldBlock
+            | ErrorKind::ResourceBusy
+    )
+}
+
+/// Returns the delay before retry number `attempt` (starting at 1).
+fn backoff(base: Duration, attempt: usize) -> Duration {
+    let exponent = u32::try_from(attempt.saturating_sub(1)).unwrap_or(u32::MAX);
+    base.saturating_mul(2u32.saturating_pow(exponent))
+        .min(MAX_DELAY)
+}
+
+/// Failed writes of all workers, to pause all of them when the output device stalls.
+#[derive(Debug)]
+pub struct Stall {
+    failures: Mutex<Vec<Instant>>,
+    paused_until: Mutex<Option<Instant>>,
+    window: Duration,
+    pause: Duration,
+}
+
+impl Default for Stall {
+    fn default() -> Self {
+        Self {
+            failures: Mutex::new(vec![]),
+            paused_until: Mutex::new(None),
+            window: STALL_WINDOW,
+            pause: STALL_PAUSE,
+        }
+    }
+}
+
+impl Stall {
+    /// Record a failed write, pausing all writes if others failed shortly before.
+    fn failed(&self) {
+        let now = Instant::now();
+        let mut failures = self.failures.lock().unwrap();
+        failures.retain(|failure| now.duration_since(*failure) < self.window);
+        failures.push(now);
+        if failures.len() < STALL_FAILURES {
+            return;
+        }
+        let mut paused_until = self.paused_until.lock().unwrap();
+        if paused_until.is_some_and(|until| until > now) {
+            return;
+        }
+        warn!(
+            "{} writes failed within {}, pausing all writes for {}...",
+            failures.len(),
+            format_duration(self.window),
+            format_duration(self.pause)
+        );
+        failures.clear();
+        *paused_until = Some(now + self.pause);
+    }
+
+    /// Wait until writes are no longer paused or the run is interrupted.
+    pub fn wait(&self) {
+        let Some(until) = *self.paused_until.lock().unwrap() else {
+            return;
+        };
+        while !interrupt::is_interrupted() {
+            let Some(remaining) = until.checked_duration_since(Instant::now()) else {
+                break;
+            };
+            std::thread::sleep(remaining.min(Duration::from_millis(100)));
+        }
+    }
+}
+
+/// Retries transient errors writing one output file.
+pub struct Retrier<'a> {
+    retries: usize,
+    base_delay: Duration,
+    stall: &'a Stall,
+    retried: Cell<usize>,
+}
+
+impl<'a> Retrier<'a> {
+    /// Retry transient errors up to `retries` times.
+    pub fn new(retries: usize, stall: &'a Stall) -> Self {
+        Self {
+            retries,
+            base_delay: BASE_DELAY,
+            stall,
+            retried: Cell::new(0),
+        }
+    }
+
+    /// Returns the number of errors that were retried so far.
+    pub fn retried(&self) -> usize {
+        self.retried.get()
+    }
+
+    /// Run `op`, which writes `path`, and retry it with exponential backoff if it fails with a
+    /// transient error.
+    ///
+    /// `cleanup` runs after every failure, e.g. to remove a partial temporary file. Running out of
+    /// space is retried only if the cleanup freed some.
+    pub fn run<T>(
+        &self,
+        path: &Path,
+        mut op: impl FnMut() -> io::Result<T>,
+        mut cleanup: impl FnMut(),
+    ) -> io::Result<T> {
+        let mut attempt = 1;
+        loop {
+            let error = match op() {
+                Ok(value) => return Ok(value),
+                Err(error) => error,
+            };
+            let is_full = error.kind() == ErrorKind::StorageFull;
+            let available = || estimate::available_space(path).unwrap_or(0);
+            let before = if is_full { available() } else { 0 };
+            cleanup();
+            let retryable = if is_full {
+                available() > before
+            } else {
+                is_transient(&error)
+            };
+            if !retryable || attempt > self.retries || interrupt::is_interrupted() {
+                return Err(error);
+            }
+            let delay = backoff(self.base_delay, attempt);
+            warn!(
+                "{}: Write failed ({}), retrying in {} (retry {}/{})...",
+                path.display(),
+                error,
+                format_duration(delay),
+                attempt,
+                self.retries
+            );
+            self.stall.failed();
+            std::thread::sleep(delay);
+            self.stall.wait();
+            self.retried.set(self.retried.get() + 1);
+            attempt += 1;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn classifies_errors() {
+        assert!(is_transient(&io::Error::from_raw_os_error(libc::EIO)));
+        assert!(is_transient(&io::Error::from(ErrorKind::TimedOut)));
+        assert!(!is_transient(&io::Error::from_raw_os_error(libc::EACCES)));
+        assert!(!is_transient(&io::Error::from_raw_os_error(libc::ENOENT)));
+        assert!(!is_transient(&io::Error::from_raw_os_error(libc::ENOSPC)));
+    }
+
+    #[test]
+    fn backs_off_exponentially() {
+        let base = Duration::from_secs(1);
+        assert_eq!(backoff(base, 1), Duration::from_secs(1));
+        assert_eq!(backoff(base, 3), Duration::from_secs(4));
+        assert_eq!(backoff(base, 100), MAX_DELAY);
+    }
+
+    #[test]
+    fn retries_transient_errors_only() {
+        let stall = Stall {
+            pause: Duration::from_millis(10),
+            ..Default::default()
+        };
+        let retrier = Retrier {
+            base_delay: Duration::ZERO,
+            ..Retrier::new(3, &stall)
+        };
+        let path = Path::new("out.mp3");
+        let mut failures = 2;
+        let mut cleanups = 0;
+        let result = retrier.run(
+            path,
+            || {
+                if failures == 0 {
+                    return Ok(());
+                }
+                failures -= 1;
+                Err(io::Error::from_raw_os_error(libc::EIO))
+            },
+            || cleanups += 1,
+        );
+        assert!(result.is_ok());
+        assert_eq!((retrier.retried(), cleanups), (2, 2));
+        assert!(stall.paused_until.lock().unwrap().is_some());
+
+        let result: io::Result<()> = retrier.run(
+            path,
+            || Err(io::Error::from(ErrorKind::PermissionDenied)),
+            || (),
+        );
+        assert!(result.is_err());
+        assert_eq!(retrier.retried(), 2);
+
+        let result: io::Result<()> =
+            retrier.run(path, || Err(io::Error::from(ErrorKind::TimedOut)), || ());
+        assert_eq!(result.unwrap_err().kind(), ErrorKind::TimedOut);
+        assert_eq!(retrier.retried(), 5);
+    }
+}
@@ -0,0 +1,224 @@
+// Copyright (c) 2024 Jan Holthuis <jan.holthuis@rub.de>
+//
+// This Source Code Form is subject to the terms of the Mozilla Public License, v. 2.0. If a copy
+// of the MPL was not distributed with this file, You can obtain one at
+// http://mozilla.org/MPL/2.0/.
+//
+// SPDX-License-Identifier: MPL-2.0
+//! Encoder settings per source extension or codec, from the `[rules]` section of the
+//! configuration file.
+//!
+//! Every key of the section is a source extension like `flac` or a codec found by the probe like
+//! `opus`, and its value sets the encoder settings of the sources it matches, e.g.
+//! `flac = { quality = 0 }` or `wav = { bitrate = 192 }`. Extensions are matched before codecs,
+//! and the key `*` matches all other sources. Sources without a matching rule are encoded with
+//! LAME VBR quality 2.
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::ffi::OsString;
+use std::fmt;
+use std::path::Path;
+use toml::{Table, Value};
+
+/// VBR quality that sources are encoded with unless a rule says otherwise.
+pub const DEFAULT_QUALITY: u8 = 2;
+
+/// Constant bitrates (in kbit/s) that MP3 supports at 44.1 and 48 kHz.
+const BITRATES: [u32; 14] = [
+    32, 40, 48, 56, 64, 80, 96, 112, 128, 160, 192, 224, 256, 320,
+];
+
+/// Key of the rule for sources that match no other rule.
+pub const FALLBACK_KEY: &str = "*";
+
+/// Parse a constant bitrate in kbit/s that MP3 supports, e.g. for `--bitrate`.
+pub fn parse_bitrate(value: &str) -> Result<u32, String> {
+    let bitrate = value
+        .parse()
+        .map_err(|_| format!("invalid bitrate `{}`, expected a number", value))?;
+    EncoderSettings {
+        bitrate: Some(bitrate),
+        ..Default::default()
+    }
+    .validate()?;
+    Ok(bitrate)
+}
+
+/// Settings that override how the sources matching a rule are encoded.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct EncoderSettings {
+    /// LAME VBR quality from 0 (best) to 9.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub quality: Option<u8>,
+    /// Constant bitrate in kbit/s, instead of VBR.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub bitrate: Option<u32>,
+    /// Number of channels, 1 to downmix to mono.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub channels: Option<u8>,
+}
+
+impl EncoderSettings {
+    /// Returns why the settings are invalid, if they are.
+    pub(crate) fn validate(&self) -> Result<(), String> {
+        if self.quality.is_some() && self.bitrate.is_some() {
+            return Err("`quality` and `bitrate` cannot be combined".to_string());
+        }
+        if let Some(quality) = self.quality.filter(|quality| *quality > 9) {
+            return Err(format!(
+                "invalid quality {}, expected 0 (best) to 9",
+                quality
+            ));
+        }
+        if let Some(bitrate) = self.bitrate.filter(|bitrate| !BITRATES.contains(bitrate)) {
+            return Err(format!(
+                "invalid bitrate {}, expected one of {}",
+                bitrate,
+                BITRATES.map(|bitrate| bitrate.to_string()).join(", ")
+            ));
+        }
+        if let Some(channels) = self.channels.filter(|channels| !(1..=2).contains(channels)) {
+            return Err(format!("invalid channels {}, expected 1 or 2", channels));
+        }
+        Ok(())
+    }
+
+    /// Returns the FFmpeg arguments for the encoder settings of an output.
+    pub fn ffmpeg_args(settings: Option<&Self>) -> Vec<OsString> {
+        let settings = settings.copied().unwrap_or_default();
+        let mut args: Vec<OsString> = match (settings.bitrate, settings.quality) {
+            (Some(bitrate), _) => vec!["-b:a".into(), format!("{}k", bitrate).into()],
+            (None, quality) => vec![
+                "-aq".into(),
+                quality.unwrap_or(DEFAULT_QUALITY).to_string().into(),
+            ],
+        };
+        if let Some(channels) = settings.channels {
+            args.extend(["-ac".into(), channels.to_string().into()]);
+        }
+        args
+    }
+}
+
+impl fmt::Display for EncoderSettings {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.bitrate {
+            Some(bitrate) => write!(f, "{} kbit/s", bitrate)?,
+            None => write!(f, "quality {}", self.quality.unwrap_or(DEFAULT_QUALITY))?,
+        }
+        match self.channels {
+            Some(1) => f.write_str(", mono"),
+            Some(channels) => write!(f, ", {} channels", channels),
+            None => Ok(()),
+        }
+    }
+}
+
+/// Encoder settings keyed by lowercase source extension or codec.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct Rules(BTreeMap<String, EncoderSettings>);
+
+impl Rules {
+    /// Read and validate the rules from the `[rules]` section of a configuration file.
+    ///
+    /// Returns the key of the first invalid rule and why it is invalid.
+    pub fn from_table(table: &Table) -> Result<Self, (String, String)> {
+        let mut rules = BTreeMap::new();
+        for (key, value) in table.iter() {
+            let invalid = |message: String| (key.clone(), message);
+            let Value::Table(value) = value else {
+                return Err(invalid(
+                    "expected a table like `{ quality = 0 }`".to_string(),
+                ));
+            };
+            let settings: EncoderSettings = Value::Table(value.clone())
+                .try_into()
+                .map_err(|e: toml::de::Error| invalid(e.message().to_string()))?;
+            settings.validate().map_err(invalid)?;
+            rules.insert(key.to_lowercase(), settings);
+        }
+        Ok(Self(rules))
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+
+    /// Encode sources that match no rule with `settings`, unless the rules have a fallback
+    /// already.
+    pub fn set_fallback(&mut self, settings: EncoderSettings) {
+        self.0.entry(FALLBACK_KEY.to_string()).or_insert(settings);
+    }
+
+    /// Returns the key and settings of the rule for `source`, matching its extension before the
+    /// `codec` of its audio stream, and then the fallback.
+    pub fn find(&self, source: &Path, codec: Option<&str>) -> Option<(&str, &EncoderSettings)> {
+        let extension = source
+            .extension()
+            .map(|extension| extension.to_string_lossy().to_lowercase());
+        let rule = [extension.as_deref(), codec, Some(FALLBACK_KEY)]
+            .into_iter()
+            .flatten()
+            .find_map(|key| self.0.get_key_value(&key.to_lowercase()));
+        rule.map(|(key, settings)| (key.as_str(), settings))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn rules(contents: &str) -> Result<Rules, (String, String)> {
+        Rules::from_table(&contents.parse().unwrap())
+    }
+
+    #[test]
+    fn matches_extensions_before_codecs() {
+        let rules = rules(
+            r#"
+                FLAC = { quality = 0 }
+                opus = { bitrate = 64, channels = 1 }
+                wav = { bitrate = 192 }
+            "#,
+        )
+        .unwrap();
+        let find = |source: &str, codec| rules.find(Path::new(source), codec).map(|r| r.0);
+        assert_eq!(find("a.flac", None), Some("flac"));
+        assert_eq!(find("a.ogg", Some("opus")), Some("opus"));
+        assert_eq!(find("a.wav", Some("opus")), Some("wav"));
+        assert_eq!(find("a.m4a", Some("aac")), None);
+
+        let (_, opus) = rules.find(Path::new("a.opus"), None).unwrap();
+        assert_eq!(opus.to_string(), "64 kbit/s, mono");
+        assert_eq!(
+            EncoderSettings::ffmpeg_args(Some(opus)),
+            ["-b:a", "64k", "-ac", "1"]
+        );
+        assert_eq!(EncoderSettings::ffmpeg_args(None), ["-aq", "2"]);
+
+        let mut rules = rules;
+        rules.set_fallback(EncoderSettings {
+            bitrate: Some(parse_bitrate("192").unwrap()),
+            ..Default::default()
+        });
+        assert_eq!(
+            rules.find(Path::new("a.m4a"), Some("aac")).map(|r| r.0),
+            Some(FALLBACK_KEY)
+        );
+        assert!(parse_bitrate("100").unwrap_err().contains("192"));
+    }
+
+    #[test]
+    fn rejects_invalid_rules() {
+        let error = |contents| rules(contents).unwrap_err();
+        assert_eq!(error("flac = 0").0, "flac");
+        assert!(error("flac = { quality = 10 }").1.contains("0 (best) to 9"));
+        assert!(error("wav = { bitrate = 100 }").1.contains("192"));
+        assert!(error("wav = { channels = 6 }").1.contains("1 or 2"));
+        assert!(error("wav = { quality = 0, bitrate = 320 }")
+            .1
+            .contains("cannot be combined"));
+        assert!(error("wav = { qualty = 0 }").1.contains("qualty"));
+    }
+}
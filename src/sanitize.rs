@@ -0,0 +1,290 @@
+// Copyright (c) 2024 Jan Holthuis <jan.holthuis@rub.de>
+//
+// This Source Code Form is subject to the terms of the Mozilla Public License, v. 2.0. If a copy
+// of the MPL was not distributed with this file, You can obtain one at
+// http://mozilla.org/MPL/2.0/.
+//
+// SPDX-License-Identifier: MPL-2.0
+//! Sanitization of output path components for FAT filesystems.
+use clap::ValueEnum;
+use std::path::{Component, Path, PathBuf};
+use xxhash_rust::xxh3::xxh3_64;
+
+/// When to sanitize output paths.
+#[derive(ValueEnum, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum SanitizeMode {
+    /// Always sanitize output paths.
+    On,
+    /// Never sanitize output paths.
+    Off,
+    /// Sanitize output paths if the target filesystem is FAT32 or exFAT.
+    Fat32,
+}
+
+/// Characters that must not appear in file names on FAT filesystems.
+const INVALID_CHARS: [char; 9] = ['<', '>', ':', '"', '/', '\\', '|', '?', '*'];
+
+/// Returns `true` if `c` is not allowed in file names on FAT filesystems.
+fn is_invalid(c: char) -> bool {
+    c.is_control() || INVALID_CHARS.contains(&c)
+}
+
+/// Parse the replacement character for invalid characters.
+pub fn parse_replacement(value: &str) -> Result<char, String> {
+    let mut chars = value.chars();
+    match (chars.next(), chars.next()) {
+        (Some(c), None) if !is_invalid(c) && c != '.' && c != ' ' => Ok(c),
+        _ => Err(format!(
+            "`{}` is not a single character that is valid in FAT file names",
+            value
+        )),
+    }
+}
+
+/// Sanitize a single file or directory name.
+///
+/// Invalid characters are replaced with `replacement` and trailing dots and spaces are trimmed.
+pub fn sanitize_name(name: &str, replacement: char) -> String {
+    let sanitized: String = name
+        .chars()
+        .map(|c| if is_invalid(c) { replacement } else { c })
+        .collect();
+    let trimmed = sanitized.trim_end_matches(['.', ' ']);
+    if trimmed.is_empty() {
+        replacement.to_string()
+    } else {
+        trimmed.to_string()
+    }
+}
+
+/// Sanitize every component of a relative output path.
+pub fn sanitize_path(path: &Path, replacement: char) -> PathBuf {
+    path.components()
+        .map(|component| match component {
+            Component::Normal(name) => {
+                PathBuf::from(sanitize_name(&name.to_string_lossy(), replacement))
+            }
+            component => PathBuf::from(component.as_os_str()),
+        })
+        .collect()
+}
+
+/// Device names that Windows reserves in every directory, regardless of the extension.
+const RESERVED_NAMES: [&str; 4] = ["CON", "PRN", "AUX", "NUL"];
+
+/// Returns `true` if `name` is a reserved Windows device name like `CON` or `lpt1.mp3`.
+pub(crate) fn is_reserved(name: &str) -> bool {
+    let base = name
+        .split('.')
+        .next()
+        .unwrap_or_default()
+        .trim_end_matches(' ');
+    let upper = base.to_ascii_uppercase();
+    if RESERVED_NAMES.contains(&upper.as_str()) {
+        return true;
+    }
+    match upper
+        .strip_prefix("COM")
+        .or_else(|| upper.strip_prefix("LPT"))
+    {
+        Some(digit) => matches!(digit.as_bytes(), [b'1'..=b'9']),
+        None => false,
+    }
+}
+
+/// Prefix every component of a relative output path that is a reserved Windows device name with
+/// an underscore.
+///
+/// Returns `None` if no component is reserved.
+pub fn avoid_reserved_names(path: &Path) -> Option<PathBuf> {
+    if !path.iter().any(|name| is_reserved(&name.to_string_lossy())) {
+        return None;
+    }
+    Some(
+        path.components()
+            .map(|component| match component {
+                Component::Normal(name) if is_reserved(&name.to_string_lossy()) => {
+                    PathBuf::from(format!("_{}", name.to_string_lossy()))
+                }
+                component => PathBuf::from(component.as_os_str()),
+            })
+            .collect(),
+    )
+}
+
+/// Maximum lengths of output paths, in characters.
+#[derive(Clone, Copy, Debug)]
+pub struct LengthLimits {
+    /// Maximum length of a single file or directory name.
+    pub max_name: usize,
+    /// Maximum length of the path relative to the output directory.
+    pub max_path: usize,
+}
+
+/// Length of the `~xxxxxx` hash suffix that keeps truncated names unique.
+const HASH_SUFFIX_LEN: usize = 7;
+
+/// Truncate `name` to `max_len` characters, keeping the extension if `keep_extension` is set.
+///
+/// Truncated names end with a short hash of the original name, so that names which only differ
+/// after the cut stay unique. Returns `None` if the name cannot be made short enough.
+fn truncate_name(name: &str, keep_extension: bool, max_len: usize) -> Option<String> {
+    if name.chars().count() <= max_len {
+        return Some(name.to_string());
+    }
+    let (stem, extension) = match name.rfind('.') {
+        Some(index) if keep_extension && index > 0 => name.split_at(index),
+        _ => (name, ""),
+    };
+    let stem_len = max_len
+        .checked_sub(extension.chars().count() + HASH_SUFFIX_LEN)
+        .filter(|&len| len > 0)?;
+    let stem: String = stem.chars().take(stem_len).collect();
+    Some(format!(
+        "{}~{:06x}{}",
+        stem.trim_end_matches(['.', ' ']),
+        xxh3_64(name.as_bytes()) & 0xff_ffff,
+        extension
+    ))
+}
+
+/// Shorten a relative output path to fit `limits`.
+///
+/// Directory names are only truncated to the name limit, so that all files of a directory stay
+/// together. The path limit is met by truncating the file name. Returns `None` if that is not
+/// possible.
+pub fn limit_length(path: &Path, limits: LengthLimits) -> Option<PathBuf> {
+    let components: Vec<String> = path
+        .iter()
+        .map(|component| component.to_string_lossy().into_owned())
+        .collect();
+    let path_len = components.iter().map(|c| c.chars().count()).sum::<usize>()
+        + components.len().saturating_sub(1);
+    let fits = path_len <= limits.max_path
+        && components
+            .iter()
+            .all(|c| c.chars().count() <= limits.max_name);
+    if fits {
+        return Some(path.to_path_buf());
+    }
+
+    let (file_name, dirs) = components.split_last()?;
+    let dirs = dirs
+        .iter()
+        .map(|dir| truncate_name(dir, false, limits.max_name))
+        .collect::<Option<Vec<_>>>()?;
+    let dirs_len: usize = dirs.iter().map(|dir| dir.chars().count() + 1).sum();
+    let max_file_len = limits.max_name.min(limits.max_path.checked_sub(dirs_len)?);
+    let file_name = truncate_name(file_name, true, max_file_len)?;
+    Some(dirs.iter().chain(std::iter::once(&file_name)).collect())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn replaces_invalid_characters() {
+        assert_eq!(
+            sanitize_name(r#"Symphony No. 5: "Fate"?.mp3"#, '_'),
+            "Symphony No. 5_ _Fate__.mp3"
+        );
+    }
+
+    #[test]
+    fn trims_trailing_dots_and_spaces() {
+        assert_eq!(sanitize_name("Op. 27 No. 2 ...", '_'), "Op. 27 No. 2");
+        assert_eq!(sanitize_name("...", '_'), "_");
+    }
+
+    #[test]
+    fn sanitizes_every_component() {
+        assert_eq!(
+            sanitize_path(Path::new("Vol. 1./Act I*/Aria?.mp3"), '-'),
+            PathBuf::from("Vol. 1/Act I-/Aria-.mp3")
+        );
+    }
+
+    const LIMITS: LengthLimits = LengthLimits {
+        max_name: 20,
+        max_path: 40,
+    };
+
+    #[test]
+    fn short_paths_are_unchanged() {
+        let path = Path::new("Artist/Album/Song.mp3");
+        assert_eq!(limit_length(path, LIMITS).as_deref(), Some(path));
+    }
+
+    #[test]
+    fn long_names_keep_extension_and_stay_unique() {
+        let first = limit_length(Path::new("A very long song title, part 1.mp3"), LIMITS).unwrap();
+        let second = limit_length(Path::new("A very long song title, part 2.mp3"), LIMITS).unwrap();
+        let name = first.to_str().unwrap();
+        assert_eq!(name.chars().count(), 20);
+        assert!(name.starts_with("A very lo~"));
+        assert!(name.ends_with(".mp3"));
+        assert_ne!(first, second);
+    }
+
+    #[test]
+    fn long_paths_truncate_the_file_name() {
+        let path = limit_length(
+            Path::new("Some Artist/Some Album/Thirteen chars.mp3"),
+            LIMITS,
+        )
+        .unwrap();
+        assert!(path.starts_with("Some Artist/Some Album"));
+        assert_eq!(path.to_str().unwrap().chars().count(), 40);
+    }
+
+    #[test]
+    fn unfixable_paths_are_rejected() {
+        let path = Path::new("Twenty characters!!/Twenty characters!!/Song.mp3");
+        assert_eq!(limit_length(path, LIMITS), None);
+    }
+
+    #[test]
+    fn detects_reserved_names() {
+        for name in [
+            "CON",
+            "con.flac",
+            "Aux",
+            "NUL.tar.gz",
+            "COM1.mp3",
+            "lpt9",
+            "PRN .mp3",
+        ] {
+            assert!(is_reserved(name), "{}", name);
+        }
+        for name in [
+            "CONSOLE.mp3",
+            "COM0",
+            "LPT10",
+            "Icon.mp3",
+            "_CON.mp3",
+            "xAUX",
+        ] {
+            assert!(!is_reserved(name), "{}", name);
+        }
+    }
+
+    #[test]
+    fn renames_reserved_components() {
+        assert_eq!(
+            avoid_reserved_names(Path::new("AUX/Live/CON.mp3")),
+            Some(PathBuf::from("_AUX/Live/_CON.mp3"))
+        );
+        assert_eq!(
+            avoid_reserved_names(Path::new("Auxiliary/Console.mp3")),
+            None
+        );
+    }
+
+    #[test]
+    fn rejects_invalid_replacement() {
+        assert!(parse_replacement("_").is_ok());
+        assert!(parse_replacement("?").is_err());
+        assert!(parse_replacement("ab").is_err());
+    }
+}
@@ -0,0 +1,199 @@
+// Copyright (c) 2024 Jan Holthuis <jan.holthuis@rub.de>
+//
+// This Source Code Form is subject to the terms of the Mozilla Public License, v. 2.0. If a copy
+// of the MPL was not distributed with this file, You can obtain one at
+// http://mozilla.org/MPL/2.0/.
+//
+// SPDX-License-Identifier: MPL-2.0
+//! Directories given as input instead of playlists.
+//!
+//! A directory becomes a playlist of all audio files below it, named after the directory. With
+//! `--per-folder-playlists`, its folders additionally become playlists of their own, named from
+//! their path like `Artist - Album`.
+use crate::exclude::Extensions;
+use crate::plan::{InputPlaylist, PlanError};
+use clap::ValueEnum;
+use log::{debug, info};
+use std::collections::BTreeMap;
+use std::path::{Path, PathBuf};
+
+/// Which folders of an input directory get a playlist of their own.
+#[derive(ValueEnum, Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum FolderPlaylists {
+    /// Folders that contain audio files, but no subfolders with audio files.
+    #[default]
+    Leaf,
+    /// Every folder with audio files in it or below it, with all of them.
+    All,
+}
+
+/// Returns the audio files below `root`, relative to it and sorted by path.
+///
+/// Hidden files and folders are left out.
+pub fn audio_files(root: &Path, extensions: &Extensions) -> std::io::Result<Vec<PathBuf>> {
+    let mut files = vec![];
+    let mut dirs = vec![PathBuf::new()];
+    while let Some(dir) = dirs.pop() {
+        for entry in std::fs::read_dir(root.join(&dir))? {
+            let entry = entry?;
+            if entry.file_name().to_string_lossy().starts_with('.') {
+                continue;
+            }
+            let path = dir.join(entry.file_name());
+            if entry.file_type()?.is_dir() {
+                dirs.push(path);
+            } else if path.extension().is_some() && extensions.includes(&path) {
+                files.push(path);
+            }
+        }
+    }
+    files.sort();
+    Ok(files)
+}
+
+/// Returns the name of a playlist for the folder at `relative` below the input directory.
+fn folder_name(relative: &Path) -> String {
+    relative
+        .iter()
+        .map(|component| component.to_string_lossy())
+        .collect::<Vec<_>>()
+        .join(" - ")
+}
+
+/// Returns a playlist of the given files, named `name`, whose entries are relative to `root`.
+fn playlist(root: &Path, name: &str, entries: Vec<PathBuf>) -> InputPlaylist {
+    InputPlaylist {
+        path: root.join(format!("{}.m3u", name)),
+        entries,
+        skipped: vec![],
+        title: None,
+        durations: Default::default(),
+    }
+}
+
+/// Read a directory as input, i.e. a playlist of all audio files below it, followed by one for
+/// each folder if `folders` is set.
+pub fn scan(
+    root: &Path,
+    extensions: &Extensions,
+    folders: Option<FolderPlaylists>,
+) -> Result<Vec<InputPlaylist>, PlanError> {
+    info!("Scanning Directory: {}", root.display());
+    let files = audio_files(root, extensions).map_err(|error| PlanError::ScanDirectory {
+        path: root.to_path_buf(),
+        error,
+    })?;
+    let name = std::fs::canonicalize(root)
+        .ok()
+        .and_then(|root| {
+            root.file_name()
+                .map(|name| name.to_string_lossy().into_owned())
+        })
+        .unwrap_or_else(|| "Music".to_string());
+    let mut playlists = vec![playlist(root, &name, files.clone())];
+    let Some(folders) = folders else {
+        return Ok(playlists);
+    };
+
+    // The files of every folder, including its subfolders for `All`.
+    let mut by_folder: BTreeMap<&Path, Vec<PathBuf>> = BTreeMap::new();
+    for file in files.iter() {
+        let Some(parent) = file.parent().filter(|parent| *parent != Path::new("")) else {
+            continue;
+        };
+        match folders {
+            FolderPlaylists::Leaf => by_folder.entry(parent).or_default().push(file.clone()),
+            FolderPlaylists::All => {
+                for folder in parent.ancestors().filter(|folder| *folder != Path::new("")) {
+                    by_folder.entry(folder).or_default().push(file.clone());
+                }
+            }
+        }
+    }
+    if folders == FolderPlaylists::Leaf {
+        let parents: Vec<&Path> = by_folder.keys().copied().collect();
+        by_folder.retain(|folder, _| {
+            let is_leaf = !parents
+                .iter()
+                .any(|other| other != folder && other.starts_with(folder));
+            if !is_leaf {
+                debug!(
+                    "{}: Not creating a playlist for folder with subfolders",
+                    root.join(folder).display()
+                );
+            }
+            is_leaf
+        });
+    }
+    playlists.extend(by_folder.into_iter().map(|(folder, mut entries)| {
+        entries.sort();
+        playlist(root, &folder_name(folder), entries)
+    }));
+    Ok(playlists)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::exclude::DEFAULT_EXTENSIONS;
+
+    fn scratch_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("scan-{}-{}", name, std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    fn names_and_entries(playlists: &[InputPlaylist]) -> Vec<(String, usize)> {
+        playlists
+            .iter()
+            .map(|playlist| (playlist.name(), playlist.entries.len()))
+            .collect()
+    }
+
+    #[test]
+    fn creates_playlists_for_folders() {
+        let dir = scratch_dir("folders").join("Library");
+        for file in [
+            "Loose.mp3",
+            "Artist/Single.mp3",
+            "Artist/Album/01.flac",
+            "Artist/Album/02.flac",
+            "Artist/Album/cover.jpg",
+            "Other/Album/01.mp3",
+            "Empty/.hidden.mp3",
+        ] {
+            let path = dir.join(file);
+            std::fs::create_dir_all(path.parent().unwrap()).unwrap();
+            std::fs::write(path, b"").unwrap();
+        }
+        let extensions = Extensions::new(DEFAULT_EXTENSIONS.split(','));
+
+        let playlists = scan(&dir, &extensions, None).unwrap();
+        assert_eq!(names_and_entries(&playlists), [("Library".to_string(), 5)]);
+        assert_eq!(playlists[0].entries[0], Path::new("Artist/Album/01.flac"));
+        assert_eq!(playlists[0].dir(), dir);
+
+        let playlists = scan(&dir, &extensions, Some(FolderPlaylists::Leaf)).unwrap();
+        assert_eq!(
+            names_and_entries(&playlists),
+            [
+                ("Library".to_string(), 5),
+                ("Artist - Album".to_string(), 2),
+                ("Other - Album".to_string(), 1),
+            ]
+        );
+
+        let playlists = scan(&dir, &extensions, Some(FolderPlaylists::All)).unwrap();
+        assert_eq!(
+            names_and_entries(&playlists),
+            [
+                ("Library".to_string(), 5),
+                ("Artist".to_string(), 3),
+                ("Artist - Album".to_string(), 2),
+                ("Other".to_string(), 1),
+                ("Other - Album".to_string(), 1),
+            ]
+        );
+    }
+}
@@ -0,0 +1,240 @@
+// Copyright (c) 2024 Jan Holthuis <jan.holthuis@rub.de>
+//
+// This Source Code Form is subject to the terms of the Mozilla Public License, v. 2.0. If a copy
+// of the MPL was not distributed with this file, You can obtain one at
+// http://mozilla.org/MPL/2.0/.
+//
+// SPDX-License-Identifier: MPL-2.0
+//! Shell scripts that execute a plan elsewhere, for `--emit-script`.
+use crate::execute::{self, ExecuteOptions};
+use crate::plan::{Action, Plan};
+use crate::transcode;
+use clap::ValueEnum;
+use std::collections::{BTreeMap, HashSet};
+use std::ffi::OsStr;
+use std::path::{Path, PathBuf};
+
+/// Kind of script to write.
+#[derive(ValueEnum, Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum ScriptFormat {
+    /// A Bash script for Linux and macOS.
+    #[default]
+    Bash,
+    /// A batch file for the Windows command prompt.
+    Batch,
+}
+
+impl ScriptFormat {
+    fn comment(self) -> &'static str {
+        match self {
+            ScriptFormat::Bash => "#",
+            ScriptFormat::Batch => "REM",
+        }
+    }
+
+    /// Quote a single argument.
+    fn quote(self, arg: &OsStr) -> String {
+        let arg = arg.to_string_lossy();
+        match self {
+            ScriptFormat::Bash => format!("'{}'", arg.replace('\'', r"'\''")),
+            // Quotes cannot appear in Windows paths, but `%` would be expanded.
+            ScriptFormat::Batch => format!("\"{}\"", arg.replace('%', "%%")),
+        }
+    }
+
+    /// Use the path separators of the platform that runs the script.
+    fn native(self, path: &Path) -> PathBuf {
+        match self {
+            ScriptFormat::Bash => path.to_path_buf(),
+            ScriptFormat::Batch => PathBuf::from(path.to_string_lossy().replace('/', "\\")),
+        }
+    }
+
+    fn path(self, path: &Path) -> String {
+        self.quote(self.native(path).as_os_str())
+    }
+
+    fn command<S: AsRef<OsStr>>(self, program: &str, args: impl IntoIterator<Item = S>) -> String {
+        let mut line = program.to_string();
+        for arg in args {
+            line.push(' ');
+            line.push_str(&self.quote(arg.as_ref()));
+        }
+        if self == ScriptFormat::Batch {
+            line.push_str(" || exit /b 1");
+        }
+        line
+    }
+}
+
+/// Render a script that creates the output directories and converts and copies all files of
+/// the plan, in order.
+///
+/// The header lists the given settings. Nothing that varies between runs (like the current time)
+/// is included, so the same plan always yields the same script.
+pub fn render(
+    plan: &Plan,
+    format: ScriptFormat,
+    settings: &BTreeMap<String, serde_json::Value>,
+    options: &ExecuteOptions,
+) -> String {
+    let comment = format.comment();
+    let mut lines = vec![];
+    match format {
+        ScriptFormat::Bash => {
+            lines.push("#!/usr/bin/env bash".to_string());
+        }
+        ScriptFormat::Batch => {
+            lines.push("@echo off".to_string());
+            lines.push("setlocal".to_string());
+            lines.push("chcp 65001 >NUL".to_string());
+        }
+    }
+    lines.push(format!(
+        "{} Generated by ford-sync-convert {}",
+        comment,
+        env!("CARGO_PKG_VERSION")
+    ));
+    lines.push(format!("{} Settings:", comment));
+    for (key, value) in settings {
+        lines.push(format!("{}   {} = {}", comment, key, value));
+    }
+    if format == ScriptFormat::Bash {
+        lines.push("set -euo pipefail".to_string());
+    }
+    lines.push(String::new());
+
+    let mut created = HashSet::new();
+    for task in &plan.tasks {
+        if let Some(parent) = task.destination.parent() {
+            if !parent.as_os_str().is_empty() && created.insert(parent) {
+                lines.push(match format {
+                    ScriptFormat::Bash => format!("mkdir -p -- {}", format.path(parent)),
+                    ScriptFormat::Batch => format!(
+                        "if not exist {0} mkdir {0} || exit /b 1",
+                        format.path(parent)
+                    ),
+                });
+            }
+        }
+        lines.push(match task.action {
+            Action::Copy => match format {
+                ScriptFormat::Bash => format!(
+                    "cp -- {} {}",
+                    format.path(&task.source),
+                    format.path(&task.destination)
+                ),
+                ScriptFormat::Batch => format!(
+                    "copy /Y {} {} >NUL || exit /b 1",
+                    format.path(&task.source),
+                    format.path(&task.destination)
+                ),
+            },
+            Action::Convert | Action::Remux => {
+                let cover = options
+                    .embed_art
+                    .then(|| execute::sidecar_cover(plan, &task.source, options))
+                    .flatten();
+                let args = transcode::ffmpeg_args(
+                    &format.native(&task.source),
+                    task.segment.as_ref(),
+                    cover.map(|cover| format.native(&cover)).as_deref(),
+                    &format.native(&task.destination),
+                    &task.tags,
+                    task.encoding(),
+                    options,
+                );
+                format.command("ffmpeg", args)
+            }
+        });
+    }
+    let newline = match format {
+        ScriptFormat::Bash => "\n",
+        ScriptFormat::Batch => "\r\n",
+    };
+    let mut script = lines.join(newline);
+    script.push_str(newline);
+    script
+}
+
+/// Write the script to `path` and make it executable.
+pub fn write(path: &Path, script: &str) -> std::io::Result<()> {
+    std::fs::write(path, script)?;
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::PermissionsExt;
+        std::fs::set_permissions(path, std::fs::Permissions::from_mode(0o755))?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::plan::Task;
+    use crate::tags::TagOverrides;
+    use std::path::PathBuf;
+
+    fn plan() -> Plan {
+        Plan {
+            tasks: vec![
+                Task {
+                    action: Action::Copy,
+                    source: PathBuf::from("music/It's.mp3"),
+                    destination: PathBuf::from("out/A/It's.mp3"),
+                    tags: TagOverrides::default(),
+                    encoder: None,
+                    segment: None,
+                },
+                Task {
+                    action: Action::Convert,
+                    source: PathBuf::from("music/100% Pure.flac"),
+                    destination: PathBuf::from("out/A/100% Pure.mp3"),
+                    tags: TagOverrides::default(),
+                    encoder: None,
+                    segment: None,
+                },
+            ],
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn bash_quotes_paths() {
+        let settings = BTreeMap::from([("output_dir".to_string(), "out".into())]);
+        let script = render(
+            &plan(),
+            ScriptFormat::Bash,
+            &settings,
+            &ExecuteOptions::default(),
+        );
+        let lines: Vec<_> = script.lines().collect();
+        assert_eq!(lines[0], "#!/usr/bin/env bash");
+        assert!(lines.contains(&"#   output_dir = \"out\""));
+        assert!(lines.contains(&"mkdir -p -- 'out/A'"));
+        assert!(lines.contains(&r"cp -- 'music/It'\''s.mp3' 'out/A/It'\''s.mp3'"));
+        assert!(lines.contains(
+            &"ffmpeg '-i' 'music/100% Pure.flac' '-vn' '-y' '-aq' '2' '-f' 'mp3' 'out/A/100% Pure.mp3'"
+        ));
+        assert_eq!(
+            lines
+                .iter()
+                .filter(|line| line.starts_with("mkdir"))
+                .count(),
+            1
+        );
+    }
+
+    #[test]
+    fn batch_escapes_percent_signs() {
+        let script = render(
+            &plan(),
+            ScriptFormat::Batch,
+            &BTreeMap::new(),
+            &ExecuteOptions::default(),
+        );
+        assert!(script.contains("\r\n"));
+        assert!(script.contains(r#"if not exist "out\A" mkdir "out\A" || exit /b 1"#));
+        assert!(script.contains(r#""music\100%% Pure.flac""#));
+    }
+}
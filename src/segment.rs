@@ -0,0 +1,187 @@
+// Copyright (c) 2024 Jan Holthuis <jan.holthuis@rub.de>
+//
+// This Source Code Form is subject to the terms of the Mozilla Public License, v. 2.0. If a copy
+// of the MPL was not distributed with this file, You can obtain one at
+// http://mozilla.org/MPL/2.0/.
+//
+// SPDX-License-Identifier: MPL-2.0
+//! Splitting of long sources like audiobooks into parts, for `--split-long-files`.
+//!
+//! Sources with chapter markers are split at the chapters, other sources that are longer than the
+//! threshold into parts of that length. Each part is converted on its own into a file named like
+//! `Book - 01.mp3`, and takes the place of the source in the playlists.
+use crate::probe::ProbeInfo;
+use serde::{Deserialize, Serialize};
+use std::ffi::OsString;
+use std::path::{Path, PathBuf};
+
+/// Parse the longest duration of a source that is not split, like `15m` or `1h 30m`.
+pub fn parse_max_duration(value: &str) -> Result<f64, String> {
+    let duration = humantime::parse_duration(value)
+        .map_err(|_| format!("invalid duration `{}`, expected e.g. `15m`", value))?;
+    if duration.is_zero() {
+        return Err("duration must be longer than zero".to_string());
+    }
+    Ok(duration.as_secs_f64())
+}
+
+/// The part of a source that a task converts.
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+pub struct Segment {
+    /// Start in the source in seconds.
+    pub start: f64,
+    /// End in the source in seconds, or `None` if the part lasts until the end of the source.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub end: Option<f64>,
+}
+
+impl Segment {
+    /// Returns the duration of the part of a source that lasts `source_duration` seconds.
+    pub fn duration(&self, source_duration: Option<f64>) -> Option<f64> {
+        let end = self.end.or(source_duration)?;
+        Some((end - self.start).max(0.0))
+    }
+
+    /// Returns the FFmpeg input options that select the part of the following input.
+    pub fn ffmpeg_args(&self) -> Vec<OsString> {
+        let mut args: Vec<OsString> = vec!["-ss".into(), format!("{:.3}", self.start).into()];
+        if let Some(end) = self.end {
+            args.extend(["-t".into(), format!("{:.3}", end - self.start).into()]);
+        }
+        args
+    }
+}
+
+/// A part of a split source.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Part {
+    pub segment: Segment,
+    /// Title of the chapter, if the source was split at its chapters.
+    pub title: Option<String>,
+}
+
+/// Returns the parts that a source should be split into if it has at least two chapters or is
+/// longer than `max_duration` seconds.
+pub fn parts(info: &ProbeInfo, max_duration: f64) -> Option<Vec<Part>> {
+    if info.chapters.len() > 1 {
+        // Parts start where their chapter starts and end where the next one starts, so that gaps
+        // between chapters are not lost.
+        let starts: Vec<f64> = info.chapters.iter().map(|chapter| chapter.start).collect();
+        return Some(
+            info.chapters
+                .iter()
+                .enumerate()
+                .map(|(index, chapter)| Part {
+                    segment: Segment {
+                        start: if index == 0 { 0.0 } else { chapter.start },
+                        end: starts.get(index + 1).copied(),
+                    },
+                    title: chapter.title.clone(),
+                })
+                .collect(),
+        );
+    }
+    let duration = info.duration.filter(|duration| *duration > max_duration)?;
+    let count = (duration / max_duration).ceil() as usize;
+    Some(
+        (0..count)
+            .map(|index| Part {
+                segment: Segment {
+                    start: index as f64 * max_duration,
+                    end: (index + 1 < count).then(|| (index + 1) as f64 * max_duration),
+                },
+                title: None,
+            })
+            .collect(),
+    )
+}
+
+/// Returns `path` with the number of part `part` of `count` appended to the file stem, zero-padded
+/// to the number of digits of `count` (at least two), like `Book - 01.mp3`.
+pub fn part_path(path: &Path, part: usize, count: usize) -> PathBuf {
+    let width = count.to_string().len().max(2);
+    let mut file_name = OsString::new();
+    if let Some(stem) = path.file_stem() {
+        file_name.push(stem);
+    }
+    file_name.push(format!(" - {:0width$}", part, width = width));
+    if let Some(extension) = path.extension() {
+        file_name.push(".");
+        file_name.push(extension);
+    }
+    path.with_file_name(file_name)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::probe::Chapter;
+
+    #[test]
+    fn splits_at_chapters_or_into_fixed_lengths() {
+        let mut info = ProbeInfo {
+            duration: Some(2000.0),
+            ..Default::default()
+        };
+        assert_eq!(parts(&info, 3600.0), None);
+
+        let segments: Vec<_> = parts(&info, 900.0)
+            .unwrap()
+            .into_iter()
+            .map(|part| part.segment.duration(info.duration).unwrap())
+            .collect();
+        assert_eq!(segments, [900.0, 900.0, 200.0]);
+
+        info.chapters = vec![
+            Chapter {
+                start: 1.5,
+                end: 1000.0,
+                title: Some("Prologue".to_string()),
+            },
+            Chapter {
+                start: 1000.5,
+                end: 2000.0,
+                title: None,
+            },
+        ];
+        let parts = parts(&info, 3600.0).unwrap();
+        assert_eq!(
+            parts,
+            [
+                Part {
+                    segment: Segment {
+                        start: 0.0,
+                        end: Some(1000.5)
+                    },
+                    title: Some("Prologue".to_string()),
+                },
+                Part {
+                    segment: Segment {
+                        start: 1000.5,
+                        end: None
+                    },
+                    title: None,
+                },
+            ]
+        );
+        assert_eq!(
+            parts[0].segment.ffmpeg_args(),
+            ["-ss", "0.000", "-t", "1000.500"]
+        );
+    }
+
+    #[test]
+    fn numbers_part_paths() {
+        assert_eq!(
+            part_path(Path::new("Books/Book.mp3"), 1, 12),
+            Path::new("Books/Book - 01.mp3")
+        );
+        assert_eq!(
+            part_path(Path::new("Book.mp3"), 7, 120),
+            Path::new("Book - 007.mp3")
+        );
+        assert_eq!(parse_max_duration("15m"), Ok(900.0));
+        assert!(parse_max_duration("0s").is_err());
+        assert!(parse_max_duration("long").is_err());
+    }
+}
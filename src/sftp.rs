@@ -0,0 +1,399 @@
+// Copyright (c) 2024 Jan Holthuis <jan.holthuis@rub.de>
+//
+// This Source Code Form is subject to the terms of the Mozilla Public License, v. 2.0. If a copy
+// of the MPL was not distributed with this file, You can obtain one at
+// http://mozilla.org/MPL/2.0/.
+//
+// SPDX-License-Identifier: MPL-2.0
+//! Uploading the output to a remote directory over SFTP for `--output-sftp`.
+//!
+//! This runs the OpenSSH `ssh` and `sftp` clients, so hosts, users and keys are configured as
+//! usual in `~/.ssh/config`. All connections share one master connection. The remote host needs
+//! GNU `find` to list the files that are already up to date.
+use crate::archive;
+use crate::estimate::ByteSize;
+use crate::interrupt;
+use crate::upload::UploadSummary;
+use crate::warnings::{report_warning, WarningCategory};
+use log::{debug, info};
+use std::collections::{BTreeSet, HashMap};
+use std::fmt;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::process::{Command, Output, Stdio};
+use std::sync::mpsc::channel;
+use std::time::{Instant, SystemTime};
+use threadpool::ThreadPool;
+
+/// Number of directories created by a single `mkdir` command.
+const MKDIR_BATCH: usize = 100;
+
+/// Error uploading to a remote host.
+#[derive(Debug)]
+pub enum SftpError {
+    Io(std::io::Error),
+    /// A remote command failed, e.g. because the host cannot be reached.
+    Command {
+        command: String,
+        message: String,
+    },
+}
+
+impl fmt::Display for SftpError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            SftpError::Io(e) => e.fmt(f),
+            SftpError::Command { command, message } => {
+                write!(f, "Remote command `{}` failed ({})", command, message)
+            }
+        }
+    }
+}
+
+impl std::error::Error for SftpError {}
+
+/// Remote directory to upload to, given as `[user@]host:/path`.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct SftpTarget {
+    pub host: String,
+    pub path: String,
+}
+
+impl std::str::FromStr for SftpTarget {
+    type Err = String;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        value
+            .split_once(':')
+            .filter(|(host, path)| !host.is_empty() && !path.is_empty())
+            .map(|(host, path)| Self {
+                host: host.to_string(),
+                path: path.trim_end_matches('/').to_string(),
+            })
+            .map(|target| Self {
+                path: if target.path.is_empty() {
+                    "/".to_string()
+                } else {
+                    target.path
+                },
+                ..target
+            })
+            .ok_or_else(|| {
+                format!(
+                    "invalid SFTP target `{}`, expected `[user@]host:/path`",
+                    value
+                )
+            })
+    }
+}
+
+/// Settings for [`upload`].
+#[derive(Clone, Debug)]
+pub struct SftpOptions {
+    /// Number of files uploaded at the same time.
+    pub jobs: usize,
+    /// Number of times a failed upload is retried.
+    pub retries: usize,
+}
+
+/// Quote `value` for a POSIX shell.
+fn shell_quote(value: &str) -> String {
+    format!("'{}'", value.replace('\'', r"'\''"))
+}
+
+/// Quote `value` for an `sftp` batch file.
+fn sftp_quote(value: &str) -> String {
+    format!("\"{}\"", value.replace('\\', r"\\").replace('"', "\\\""))
+}
+
+/// Returns the size and modification time in seconds of the files below the remote directory,
+/// by path relative to it, from the output of `find -printf '%s %T@ %P\n'`.
+fn parse_listing(listing: &str) -> HashMap<String, (u64, u64)> {
+    listing
+        .lines()
+        .filter_map(|line| {
+            let (size, rest) = line.split_once(' ')?;
+            let (modified, path) = rest.split_once(' ')?;
+            let modified: f64 = modified.parse().ok()?;
+            Some((path.to_string(), (size.parse().ok()?, modified as u64)))
+        })
+        .collect()
+}
+
+/// Returns the output of a command, or the last line of its error output if it failed.
+fn run(mut command: Command, stdin: Option<String>, name: &str) -> Result<Output, SftpError> {
+    let mut child = command
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+        .map_err(SftpError::Io)?;
+    if let Some(mut pipe) = child.stdin.take() {
+        pipe.write_all(stdin.unwrap_or_default().as_bytes())
+            .map_err(SftpError::Io)?;
+    }
+    let output = child.wait_with_output().map_err(SftpError::Io)?;
+    if output.status.success() {
+        return Ok(output);
+    }
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    Err(SftpError::Command {
+        command: name.to_string(),
+        message: stderr
+            .lines()
+            .last()
+            .map(String::from)
+            .unwrap_or_else(|| format!("exited with {}", output.status)),
+    })
+}
+
+/// Connection settings shared by all commands.
+#[derive(Clone, Debug)]
+struct Connection {
+    host: String,
+    control_path: PathBuf,
+}
+
+impl Connection {
+    fn options(&self) -> Vec<String> {
+        vec![
+            "-o".to_string(),
+            "BatchMode=yes".to_string(),
+            "-o".to_string(),
+            "ControlMaster=auto".to_string(),
+            "-o".to_string(),
+            format!("ControlPath={}", self.control_path.display()),
+            "-o".to_string(),
+            "ControlPersist=60".to_string(),
+        ]
+    }
+
+    /// Run a shell command on the remote host.
+    fn ssh(&self, command: &str, name: &str) -> Result<Output, SftpError> {
+        let mut ssh = Command::new("ssh");
+        ssh.args(self.options()).arg(&self.host).arg(command);
+        run(ssh, None, name)
+    }
+
+    /// Run an `sftp` batch on the remote host.
+    fn sftp(&self, batch: String, name: &str) -> Result<Output, SftpError> {
+        let mut sftp = Command::new("sftp");
+        sftp.arg("-q")
+            .args(["-b", "-"])
+            .args(self.options())
+            .arg(&self.host);
+        run(sftp, Some(batch), name)
+    }
+
+    /// Close the master connection.
+    fn close(&self) {
+        let _ = Command::new("ssh")
+            .args(self.options())
+            .args(["-O", "exit"])
+            .arg(&self.host)
+            .stdin(Stdio::null())
+            .stdout(Stdio::null())
+            .stderr(Stdio::null())
+            .status();
+    }
+}
+
+/// Returns the modification time of a file in seconds since the Unix epoch.
+fn modified_secs(path: &Path) -> std::io::Result<u64> {
+    Ok(std::fs::metadata(path)?
+        .modified()?
+        .duration_since(SystemTime::UNIX_EPOCH)
+        .map(|duration| duration.as_secs())
+        .unwrap_or(0))
+}
+
+/// Upload all files below `dir` into the remote directory, skipping those with the same size and
+/// modification time there.
+///
+/// Files that fail to upload after all retries are reported as warnings and counted in the
+/// summary. An error is only returned if the remote directory cannot be used at all.
+pub fn upload(
+    dir: &Path,
+    target: &SftpTarget,
+    options: &SftpOptions,
+) -> Result<UploadSummary, SftpError> {
+    let connection = Connection {
+        host: target.host.clone(),
+        control_path: std::env::temp_dir()
+            .join(format!(".ford-sync-convert-{}.ssh", std::process::id())),
+    };
+    let result = upload_files(dir, target, options, &connection);
+    connection.close();
+    result
+}
+
+fn upload_files(
+    dir: &Path,
+    target: &SftpTarget,
+    options: &SftpOptions,
+    connection: &Connection,
+) -> Result<UploadSummary, SftpError> {
+    let files = archive::files(dir).map_err(SftpError::Io)?;
+    // Paths relative to the remote directory, with `/` as separator.
+    let name = |relative: &Path| {
+        relative
+            .iter()
+            .map(|component| component.to_string_lossy())
+            .collect::<Vec<_>>()
+            .join("/")
+    };
+    let remote_path = |relative: &Path| match name(relative) {
+        name if name.is_empty() => target.path.clone(),
+        name => format!("{}/{}", target.path.trim_end_matches('/'), name),
+    };
+
+    let dirs: BTreeSet<String> = files
+        .iter()
+        .map(|file| remote_path(file.parent().unwrap_or(Path::new(""))))
+        .chain([target.path.clone()])
+        .collect();
+    let dirs: Vec<String> = dirs.into_iter().collect();
+    for batch in dirs.chunks(MKDIR_BATCH) {
+        let quoted: Vec<String> = batch.iter().map(|dir| shell_quote(dir)).collect();
+        connection.ssh(&format!("mkdir -p -- {}", quoted.join(" ")), "mkdir")?;
+    }
+    let listing = connection.ssh(
+        &format!(
+            "find {} -type f -printf '%s %T@ %P\\n'",
+            shell_quote(&target.path)
+        ),
+        "find",
+    )?;
+    let remote = parse_listing(&String::from_utf8_lossy(&listing.stdout));
+
+    let mut summary = UploadSummary::default();
+    let mut pending = vec![];
+    for relative in files {
+        let path = dir.join(&relative);
+        let size = std::fs::metadata(&path).map_err(SftpError::Io)?.len();
+        let modified = modified_secs(&path).map_err(SftpError::Io)?;
+        if remote.get(&name(&relative)) == Some(&(size, modified)) {
+            debug!(
+                "{}: Up to date on {}, skipping",
+                relative.display(),
+                target.host
+            );
+            summary.skipped += 1;
+            continue;
+        }
+        pending.push((path, remote_path(&relative), size));
+    }
+    info!(
+        "Uploading {} files to {}:{}...",
+        pending.len(),
+        target.host,
+        target.path
+    );
+
+    let num_pending = pending.len();
+    let pool = ThreadPool::new(options.jobs.max(1));
+    let (tx, rx) = channel();
+    for (path, remote, size) in pending {
+        let tx = tx.clone();
+        let connection = connection.clone();
+        let retries = options.retries;
+        pool.execute(move || {
+            let started = Instant::now();
+            let batch = format!(
+                "put -p {} {}\n",
+                sftp_quote(&path.to_string_lossy()),
+                sftp_quote(&remote)
+            );
+            let mut attempts = 0;
+            let result = loop {
+                attempts += 1;
+                if interrupt::is_interrupted() {
+                    break Err(None);
+                }
+                match connection.sftp(batch.clone(), "put") {
+                    Ok(_) => break Ok(()),
+                    Err(e) if attempts > retries => break Err(Some(e)),
+                    Err(e) => debug!("{}: Upload failed, retrying ({})", remote, e),
+                }
+            };
+            tx.send((remote, size, result, attempts, started.elapsed()))
+                .expect("channel will be there waiting for the pool");
+        });
+    }
+
+    for (index, (remote, size, result, attempts, elapsed)) in
+        rx.iter().take(num_pending).enumerate()
+    {
+        match result {
+            Ok(()) => {
+                summary.uploaded += 1;
+                summary.bytes += size;
+                let rate = (size as f64 / elapsed.as_secs_f64().max(0.001)) as u64;
+                info!(
+                    "({}/{}) {}: Uploaded {} ({}/s).",
+                    index + 1,
+                    num_pending,
+                    remote,
+                    ByteSize(size),
+                    ByteSize(rate)
+                );
+            }
+            Err(None) => (),
+            Err(Some(e)) => {
+                summary.failed += 1;
+                report_warning!(
+                    WarningCategory::Copy,
+                    "({}/{}) {}: Failed to upload ({}, attempt {}/{})",
+                    index + 1,
+                    num_pending,
+                    remote,
+                    e,
+                    attempts,
+                    options.retries + 1
+                );
+            }
+        }
+    }
+    Ok(summary)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_targets() {
+        assert_eq!(
+            "me@server:/srv/car-usb/".parse(),
+            Ok(SftpTarget {
+                host: "me@server".to_string(),
+                path: "/srv/car-usb".to_string(),
+            })
+        );
+        assert_eq!(
+            "server:/".parse::<SftpTarget>().unwrap().path,
+            "/".to_string()
+        );
+        assert!("server".parse::<SftpTarget>().is_err());
+        assert!(":/srv".parse::<SftpTarget>().is_err());
+    }
+
+    #[test]
+    fn quotes_paths() {
+        assert_eq!(shell_quote("it's"), r"'it'\''s'");
+        assert_eq!(sftp_quote(r#"a "b" \c"#), r#""a \"b\" \\c""#);
+    }
+
+    #[test]
+    fn parses_remote_listings() {
+        let listing =
+            parse_listing("4096 1700000000.1234567890 Album/01 Song.mp3\nbroken\n12 1.5 a.m3u\n");
+        assert_eq!(
+            listing,
+            HashMap::from([
+                ("Album/01 Song.mp3".to_string(), (4096, 1_700_000_000)),
+                ("a.m3u".to_string(), (12, 1)),
+            ])
+        );
+    }
+}
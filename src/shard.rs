@@ -0,0 +1,269 @@
+// Copyright (c) 2024 Jan Holthuis <jan.holthuis@rub.de>
+//
+// This Source Code Form is subject to the terms of the Mozilla Public License, v. 2.0. If a copy
+// of the MPL was not distributed with this file, You can obtain one at
+// http://mozilla.org/MPL/2.0/.
+//
+// SPDX-License-Identifier: MPL-2.0
+//! Numbered subfolders for output directories with too many files, for `--max-files-per-dir`.
+//!
+//! FAT directories get slow with many entries, and some head units fail to index them. The files
+//! of an output directory that would hold more than the limit are distributed over subfolders
+//! `001`, `002`, … in the order of their names. Files that an earlier run put into a subfolder stay
+//! there, so that incremental runs do not move them.
+use crate::longpath;
+use crate::plan::Plan;
+use crate::target::TargetPath;
+use log::info;
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::path::{Path, PathBuf};
+
+/// Returns the name of the subfolder with the number `n`.
+fn shard_name(n: usize) -> String {
+    format!("{:03}", n)
+}
+
+/// Returns the numbers of the subfolders that an earlier run put files of `dir` into, keyed by
+/// the lowercase file name.
+fn existing_shards(dir: &Path) -> HashMap<String, usize> {
+    let mut shards = HashMap::new();
+    let Ok(entries) = std::fs::read_dir(longpath::extended(dir)) else {
+        return shards;
+    };
+    for entry in entries.flatten() {
+        let name = entry.file_name().to_string_lossy().into_owned();
+        let Some(n) = Some(&name)
+            .filter(|name| name.chars().all(|c| c.is_ascii_digit()))
+            .and_then(|name| name.parse::<usize>().ok())
+            .filter(|n| *n > 0)
+        else {
+            continue;
+        };
+        if !entry.file_type().is_ok_and(|file_type| file_type.is_dir()) {
+            continue;
+        }
+        let Ok(files) = std::fs::read_dir(entry.path()) else {
+            continue;
+        };
+        for file in files.flatten() {
+            shards.insert(file.file_name().to_string_lossy().to_lowercase(), n);
+        }
+    }
+    shards
+}
+
+/// Move the output files of every directory below `output_dir` that would hold more than
+/// `max_files` of them into numbered subfolders, and update the playlist entries and the
+/// [`Plan::short_names`].
+///
+/// Directories that an earlier run split are split again, even if they no longer hold more than
+/// `max_files`. Subfolder numbers that are taken by other output directories are skipped. Returns
+/// the number of directories that were split.
+pub fn shard(plan: &mut Plan, output_dir: &Path, max_files: usize) -> usize {
+    // Destinations of every directory, keyed by lowercase file name so that they are ordered
+    // deterministically and compared like on FAT.
+    let mut dirs: BTreeMap<PathBuf, BTreeMap<String, PathBuf>> = BTreeMap::new();
+    // Lowercase names of the subdirectories of every directory.
+    let mut subdirs: HashMap<PathBuf, HashSet<String>> = HashMap::new();
+    for task in plan.tasks.iter() {
+        let destination = &task.destination;
+        let (Some(dir), Some(name)) = (destination.parent(), destination.file_name()) else {
+            continue;
+        };
+        if !dir.starts_with(output_dir) {
+            continue;
+        }
+        dirs.entry(dir.to_path_buf())
+            .or_default()
+            .insert(name.to_string_lossy().to_lowercase(), destination.clone());
+        for ancestor in dir
+            .ancestors()
+            .take_while(|ancestor| *ancestor != output_dir)
+        {
+            if let (Some(parent), Some(name)) = (ancestor.parent(), ancestor.file_name()) {
+                subdirs
+                    .entry(parent.to_path_buf())
+                    .or_default()
+                    .insert(name.to_string_lossy().to_lowercase());
+            }
+        }
+    }
+
+    let mut moved: HashMap<PathBuf, PathBuf> = HashMap::new();
+    let mut sharded = 0;
+    for (dir, files) in dirs {
+        let existing = existing_shards(&dir);
+        let taken = |n: usize| {
+            subdirs
+                .get(&dir)
+                .is_some_and(|names| names.contains(&shard_name(n)))
+        };
+        let kept: Vec<_> = files
+            .iter()
+            .filter_map(|(name, destination)| {
+                let n = existing.get(name).copied().filter(|n| !taken(*n))?;
+                Some((destination, n))
+            })
+            .collect();
+        if files.len() <= max_files && kept.is_empty() {
+            continue;
+        }
+        let mut counts: BTreeMap<usize, usize> = BTreeMap::new();
+        for (destination, n) in kept {
+            *counts.entry(n).or_default() += 1;
+            moved.insert(destination.clone(), dir.join(shard_name(n)));
+        }
+        for destination in files.values() {
+            if moved.contains_key(destination) {
+                continue;
+            }
+            let n = (1..)
+                .find(|n| !taken(*n) && counts.get(n).copied().unwrap_or(0) < max_files)
+                .expect("some subfolder has room");
+            *counts.entry(n).or_default() += 1;
+            moved.insert(destination.clone(), dir.join(shard_name(n)));
+        }
+        info!(
+            "{}: Splitting {} files into {} folders",
+            dir.display(),
+            files.len(),
+            counts.len()
+        );
+        sharded += 1;
+    }
+    if moved.is_empty() {
+        return 0;
+    }
+
+    let mut moved_tasks = HashSet::new();
+    for (index, task) in plan.tasks.iter_mut().enumerate() {
+        if let Some(shard) = moved.get(&task.destination) {
+            let destination = shard.join(task.destination.file_name().unwrap_or_default());
+            let relative = |path: &Path| path.strip_prefix(output_dir).ok().map(Path::to_path_buf);
+            if let (Some(short), Some(sharded)) =
+                (relative(&task.destination), relative(&destination))
+            {
+                if let Some(original) = plan.short_names.remove(&short) {
+                    plan.short_names.insert(sharded, original);
+                }
+            }
+            task.destination = destination;
+            moved_tasks.insert(index);
+        }
+    }
+    for entry in plan
+        .playlists
+        .iter_mut()
+        .flat_map(|playlist| playlist.entries.iter_mut())
+        .filter(|entry| moved_tasks.contains(&entry.task))
+    {
+        let destination = &plan.tasks[entry.task].destination;
+        if let Ok(relative) = destination.strip_prefix(output_dir) {
+            entry.target = TargetPath::new(relative).playlist_entry();
+        }
+    }
+    sharded
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::plan::{Action, OutputEntry, PlaylistOutput, Task};
+
+    fn planned(output_dir: &Path, files: &[&str]) -> Plan {
+        Plan {
+            tasks: files
+                .iter()
+                .map(|file| Task {
+                    action: Action::Copy,
+                    source: PathBuf::from(file),
+                    destination: output_dir.join(file),
+                    tags: Default::default(),
+                    encoder: None,
+                    segment: None,
+                })
+                .collect(),
+            playlists: vec![PlaylistOutput {
+                source: PathBuf::from("playlist.m3u"),
+                path: output_dir.join("playlist.m3u"),
+                entries: (0..files.len())
+                    .map(|task| OutputEntry {
+                        target: files[task].replace('/', "\\"),
+                        task,
+                    })
+                    .collect(),
+                skipped: vec![],
+                title: None,
+            }],
+            ..Default::default()
+        }
+    }
+
+    fn targets(plan: &Plan) -> Vec<&str> {
+        plan.playlists[0]
+            .entries
+            .iter()
+            .map(|entry| entry.target.as_str())
+            .collect()
+    }
+
+    #[test]
+    fn splits_full_directories_in_name_order() {
+        let output_dir = Path::new("out");
+        let mut plan = planned(
+            output_dir,
+            &[
+                "Singles/c.mp3",
+                "Singles/a.mp3",
+                "Singles/B.mp3",
+                "Singles/001/x.mp3",
+                "Album/a.mp3",
+            ],
+        );
+        assert_eq!(shard(&mut plan, output_dir, 2), 1);
+        assert_eq!(
+            targets(&plan),
+            [
+                "Singles\\003\\c.mp3",
+                "Singles\\002\\a.mp3",
+                "Singles\\002\\B.mp3",
+                "Singles\\001\\x.mp3",
+                "Album\\a.mp3",
+            ]
+        );
+        assert_eq!(
+            plan.tasks[0].destination,
+            Path::new("out/Singles/003/c.mp3")
+        );
+    }
+
+    #[test]
+    fn keeps_files_in_the_subfolders_of_earlier_runs() {
+        let output_dir = std::env::temp_dir().join(format!("shard-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&output_dir);
+        for file in ["Singles/001/c.mp3", "Singles/002/a.mp3"] {
+            let path = output_dir.join(file);
+            std::fs::create_dir_all(path.parent().unwrap()).unwrap();
+            std::fs::write(path, b"").unwrap();
+        }
+        let mut plan = planned(
+            &output_dir,
+            &["Singles/a.mp3", "Singles/b.mp3", "Singles/c.mp3"],
+        );
+        assert_eq!(shard(&mut plan, &output_dir, 2), 1);
+        assert_eq!(
+            targets(&plan),
+            [
+                "Singles\\002\\a.mp3",
+                "Singles\\001\\b.mp3",
+                "Singles\\001\\c.mp3",
+            ]
+        );
+
+        // The directory stays split when it no longer needs to be.
+        let mut plan = planned(&output_dir, &["Singles/c.mp3"]);
+        assert_eq!(shard(&mut plan, &output_dir, 2), 1);
+        assert_eq!(targets(&plan), ["Singles\\001\\c.mp3"]);
+        std::fs::remove_dir_all(&output_dir).unwrap();
+    }
+}
@@ -0,0 +1,258 @@
+// Copyright (c) 2024 Jan Holthuis <jan.holthuis@rub.de>
+//
+// This Source Code Form is subject to the terms of the Mozilla Public License, v. 2.0. If a copy
+// of the MPL was not distributed with this file, You can obtain one at
+// http://mozilla.org/MPL/2.0/.
+//
+// SPDX-License-Identifier: MPL-2.0
+//! DOS-compatible 8.3 output names, for `--short-names`.
+//!
+//! Every file and directory below the output directory gets a name with an uppercase stem of at
+//! most eight characters and an extension of at most three, which old head units index reliably.
+//! File names start with the track number and go on with an abbreviation of the title, directory
+//! names abbreviate the original name. Names that are taken in a directory get a numeric tail like
+//! `~2`. The original paths are kept in the plan for the mapping file.
+use crate::plan::{Plan, Task};
+use crate::sanitize;
+use crate::target::TargetPath;
+use crate::transliterate::{self, TransliterationStyle};
+use log::info;
+use std::collections::{HashMap, HashSet};
+use std::path::{Path, PathBuf};
+
+/// Maximum length of the stem of a short name.
+const MAX_STEM: usize = 8;
+
+/// Maximum length of the extension of a short name.
+const MAX_EXTENSION: usize = 3;
+
+/// Returns the uppercase ASCII letters and digits of `name`, dropping vowels from the end (but
+/// not the first character) while it is longer than `max_len`, and then truncating it to
+/// `max_len`.
+fn abbreviate(name: &str, max_len: usize) -> String {
+    let mut letters: Vec<char> =
+        transliterate::transliterate_name(name, TransliterationStyle::Basic)
+            .chars()
+            .filter(char::is_ascii_alphanumeric)
+            .map(|c| c.to_ascii_uppercase())
+            .collect();
+    while letters.len() > max_len {
+        let Some(index) = letters.iter().rposition(|c| "AEIOU".contains(*c)) else {
+            break;
+        };
+        if index == 0 {
+            break;
+        }
+        letters.remove(index);
+    }
+    letters.into_iter().take(max_len).collect()
+}
+
+/// Returns the stem of the short name of a task's output: the track number followed by an
+/// abbreviation of the title, or of the original file name if the title is unknown.
+fn file_stem(plan: &Plan, task: &Task) -> String {
+    let info = plan.metadata.get(&task.source);
+    let track = task
+        .tags
+        .get("TRCK")
+        .and_then(|track| track.split('/').next()?.trim().parse::<u32>().ok())
+        .or_else(|| info.and_then(|info| info.number_tag("track")));
+    let title = task
+        .tags
+        .get("TIT2")
+        .filter(|title| !title.is_empty())
+        .or_else(|| info.and_then(|info| info.tag("title")))
+        .map(str::to_string)
+        .unwrap_or_else(|| {
+            let stem = task.destination.file_stem().unwrap_or_default();
+            stem.to_string_lossy().into_owned()
+        });
+    let track = track
+        .map(|track| format!("{:02}", track))
+        .unwrap_or_default();
+    let title = abbreviate(&title, MAX_STEM.saturating_sub(track.len()));
+    track + &title
+}
+
+/// The short names taken so far.
+#[derive(Default)]
+struct Names {
+    /// Uppercase names taken in each directory, keyed by its short path.
+    taken: HashMap<PathBuf, HashSet<String>>,
+    /// Short paths of the directories, keyed by their original path.
+    dirs: HashMap<PathBuf, PathBuf>,
+    /// Short paths of the files, keyed by their original path.
+    files: HashMap<PathBuf, PathBuf>,
+}
+
+impl Names {
+    /// Take a name in the directory `dir` with the stem `stem` (shortened if necessary) and the
+    /// file extension `extension`, adding a numeric tail to the stem if the name is taken.
+    fn claim(&mut self, dir: &Path, stem: &str, extension: &str) -> String {
+        let stem = if stem.is_empty() { "_" } else { stem };
+        let extension: String = extension
+            .chars()
+            .filter(char::is_ascii_alphanumeric)
+            .take(MAX_EXTENSION)
+            .collect::<String>()
+            .to_ascii_lowercase();
+        let taken = self.taken.entry(dir.to_path_buf()).or_default();
+        (1..)
+            .map(|n| {
+                let stem = if n == 1 {
+                    stem.chars().take(MAX_STEM).collect()
+                } else {
+                    let tail = format!("~{}", n);
+                    let kept: String = stem.chars().take(MAX_STEM - tail.len()).collect();
+                    kept + &tail
+                };
+                if extension.is_empty() {
+                    stem
+                } else {
+                    format!("{}.{}", stem, extension)
+                }
+            })
+            .find(|name| !sanitize::is_reserved(name) && taken.insert(name.to_ascii_uppercase()))
+            .expect("some numeric tail is free")
+    }
+
+    /// Returns the short path of the directory at the relative path `original`.
+    fn dir(&mut self, original: &Path) -> PathBuf {
+        if let Some(short) = self.dirs.get(original) {
+            return short.clone();
+        }
+        let Some(name) = original.file_name() else {
+            return PathBuf::new();
+        };
+        let parent = self.dir(original.parent().unwrap_or(Path::new("")));
+        let name = self.claim(&parent, &abbreviate(&name.to_string_lossy(), MAX_STEM), "");
+        let short = parent.join(name);
+        self.dirs.insert(original.to_path_buf(), short.clone());
+        short
+    }
+
+    /// Returns the short path of the file at the relative path `original`, whose short stem is
+    /// `stem`.
+    fn file(&mut self, original: &Path, stem: impl FnOnce() -> String) -> PathBuf {
+        if let Some(short) = self.files.get(original) {
+            return short.clone();
+        }
+        let dir = self.dir(original.parent().unwrap_or(Path::new("")));
+        let extension = original.extension().unwrap_or_default().to_string_lossy();
+        let name = self.claim(&dir, &stem(), &extension);
+        let short = dir.join(name);
+        self.files.insert(original.to_path_buf(), short.clone());
+        short
+    }
+}
+
+/// Rename all output files, directories and playlists of the plan below `output_dir` to 8.3
+/// names, and record the original paths in [`Plan::short_names`].
+pub fn shorten(plan: &mut Plan, output_dir: &Path) {
+    let mut names = Names::default();
+    for index in 0..plan.tasks.len() {
+        let task = &plan.tasks[index];
+        let Ok(original) = task.destination.strip_prefix(output_dir) else {
+            continue;
+        };
+        let original = original.to_path_buf();
+        let short = names.file(&original, || file_stem(plan, task));
+        plan.tasks[index].destination = output_dir.join(&short);
+        plan.short_names.insert(short, original);
+    }
+    for playlist in plan.playlists.iter_mut() {
+        if let Ok(original) = playlist.path.strip_prefix(output_dir) {
+            let short = names.file(original, || {
+                let stem = original.file_stem().unwrap_or_default();
+                abbreviate(&stem.to_string_lossy(), MAX_STEM)
+            });
+            playlist.path = output_dir.join(short);
+        }
+        for entry in playlist.entries.iter_mut() {
+            let destination = &plan.tasks[entry.task].destination;
+            if let Ok(short) = destination.strip_prefix(output_dir) {
+                entry.target = TargetPath::new(short).playlist_entry();
+            }
+        }
+    }
+    info!(
+        "Shortened the names of {} output files and {} directories",
+        names.files.len(),
+        names.dirs.len()
+    );
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::plan::{Action, OutputEntry, PlaylistOutput};
+    use crate::probe::ProbeInfo;
+
+    fn task(source: &str, destination: &str) -> Task {
+        Task {
+            action: Action::Copy,
+            source: PathBuf::from(source),
+            destination: Path::new("out").join(destination),
+            tags: Default::default(),
+            encoder: None,
+            segment: None,
+        }
+    }
+
+    #[test]
+    fn abbreviates_names() {
+        assert_eq!(abbreviate("Road Trip", 8), "ROADTRIP");
+        assert_eq!(abbreviate("Some Album", 8), "SOMEALBM");
+        assert_eq!(abbreviate("Love Will Tear Us Apart", 8), "LVWLLTRS");
+        assert_eq!(abbreviate("Über", 8), "UBER");
+    }
+
+    #[test]
+    fn shortens_files_directories_and_playlists() {
+        let mut info = ProbeInfo::default();
+        info.tags.insert("track".to_string(), "3/12".to_string());
+        info.tags
+            .insert("title".to_string(), "Atmosphere".to_string());
+        let mut plan = Plan {
+            tasks: vec![
+                task("a.flac", "Joy Division/Closer/03 Atmosphere.mp3"),
+                task("b.mp3", "Joy Division/Closer/Isolation.mp3"),
+                task("c.mp3", "Joy Division/Closer/Isolation!.mp3"),
+                task("d.mp3", "Joy Division Live/Con.mp3"),
+            ],
+            playlists: vec![PlaylistOutput {
+                source: PathBuf::from("Joy Division.m3u"),
+                path: PathBuf::from("out/Joy Division.m3u"),
+                entries: (0..4)
+                    .map(|task| OutputEntry {
+                        target: String::new(),
+                        task,
+                    })
+                    .collect(),
+                skipped: vec![],
+                title: None,
+            }],
+            metadata: HashMap::from([(PathBuf::from("a.flac"), info)]),
+            ..Default::default()
+        };
+        shorten(&mut plan, Path::new("out"));
+
+        let destinations: Vec<_> = plan.tasks.iter().map(|task| &task.destination).collect();
+        assert_eq!(
+            destinations,
+            [
+                Path::new("out/JOYDIVSN/CLOSER/03ATMSPH.mp3"),
+                Path::new("out/JOYDIVSN/CLOSER/ISOLATIN.mp3"),
+                Path::new("out/JOYDIVSN/CLOSER/ISOLAT~2.mp3"),
+                Path::new("out/JYDVSNLV/CON~2.mp3"),
+            ]
+        );
+        let playlist = &plan.playlists[0];
+        assert_eq!(playlist.path, Path::new("out/JOYDIVSN.m3u"));
+        assert_eq!(playlist.entries[0].target, "JOYDIVSN\\CLOSER\\03ATMSPH.mp3");
+        assert_eq!(
+            plan.short_names[Path::new("JOYDIVSN/CLOSER/ISOLAT~2.mp3")],
+            Path::new("Joy Division/Closer/Isolation!.mp3")
+        );
+    }
+}
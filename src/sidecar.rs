@@ -0,0 +1,180 @@
+// Copyright (c) 2024 Jan Holthuis <jan.holthuis@rub.de>
+//
+// This Source Code Form is subject to the terms of the Mozilla Public License, v. 2.0. If a copy
+// of the MPL was not distributed with this file, You can obtain one at
+// http://mozilla.org/MPL/2.0/.
+//
+// SPDX-License-Identifier: MPL-2.0
+//! Files next to the sources that belong to a track, like `.lrc` lyrics.
+//!
+//! A sidecar has the same stem as its source and is copied next to the output under the output's
+//! stem, e.g. `Album/01 Song.lrc` becomes `ALBUM/01_Song.lrc` for `ALBUM/01_Song.mp3`.
+use crate::atomic;
+use crate::interrupt;
+use crate::longpath;
+use crate::mtime;
+use crate::plan::Plan;
+use crate::warnings::{report_warning, WarningCategory};
+use log::{debug, info};
+use std::collections::BTreeMap;
+use std::path::{Path, PathBuf};
+
+/// Settings for [`copy_sidecars`].
+#[derive(Clone, Debug, Default)]
+pub struct SidecarOptions {
+    /// Extensions of the sidecars, without the dot.
+    pub extensions: Vec<String>,
+    /// Replace existing sidecars even if they are not older than the source.
+    pub force: bool,
+    /// Copy the modification times of the sidecars.
+    pub preserve_times: bool,
+    /// Flush every sidecar to disk.
+    pub sync_writes: bool,
+}
+
+/// Returns the sidecar of `source` with the given extension, in its own or in upper case.
+fn find(source: &Path, extension: &str) -> Option<PathBuf> {
+    [extension.to_string(), extension.to_uppercase()]
+        .into_iter()
+        .map(|extension| source.with_extension(extension))
+        .find(|path| longpath::extended(path).is_file())
+}
+
+/// Returns the sidecars of the plan's sources as pairs of source and output paths.
+///
+/// Only sidecars of outputs that exist are included, so failed tasks do not leave lyrics without
+/// a track behind. Parts of split sources get none, as the lyrics of the source would not match
+/// them.
+pub fn sidecars(plan: &Plan, extensions: &[String]) -> BTreeMap<PathBuf, PathBuf> {
+    let mut sidecars = BTreeMap::new();
+    for task in plan.tasks.iter() {
+        if task.segment.is_some() || !longpath::extended(&task.destination).exists() {
+            continue;
+        }
+        for extension in extensions {
+            let extension = extension.trim_start_matches('.').to_lowercase();
+            if let Some(source) = find(&task.source, &extension) {
+                sidecars.insert(task.destination.with_extension(&extension), source);
+            }
+        }
+    }
+    sidecars
+}
+
+/// Returns whether the sidecar at `output` is at least as new as its `source`.
+fn is_up_to_date(source: &Path, output: &Path) -> bool {
+    let modified = |path: &Path| std::fs::metadata(path).and_then(|metadata| metadata.modified());
+    match (modified(source), modified(output)) {
+        (Ok(source), Ok(output)) => output >= mtime::fat_time(source),
+        _ => false,
+    }
+}
+
+/// Copy a single sidecar atomically.
+fn copy(source: &Path, output: &Path, options: &SidecarOptions) -> std::io::Result<()> {
+    let temp_path = atomic::temp_path(output);
+    let result = std::fs::copy(source, &temp_path)
+        .and_then(|_| atomic::commit(&temp_path, output, options.sync_writes));
+    if result.is_err() {
+        atomic::discard(&temp_path);
+    }
+    result
+}
+
+/// Copy the sidecars of all sources of the plan next to their outputs.
+///
+/// Sources without sidecars are skipped silently, and sidecars that cannot be copied are
+/// reported as warnings. Returns the paths of the copied sidecars.
+pub fn copy_sidecars(plan: &Plan, options: &SidecarOptions) -> Vec<PathBuf> {
+    let sidecars = sidecars(plan, &options.extensions);
+    info!("Copying {} sidecar files...", sidecars.len());
+    let mut copied = vec![];
+    for (plain_output, source) in sidecars {
+        if interrupt::is_interrupted() {
+            break;
+        }
+        let source = longpath::extended(&source);
+        let output = longpath::extended(&plain_output);
+        if !options.force && is_up_to_date(&source, &output) {
+            debug!("{}: Sidecar is up to date, skipping", output.display());
+            continue;
+        }
+        match copy(&source, &output, options) {
+            Ok(()) => {
+                debug!("{}: Copied sidecar {}", output.display(), source.display());
+                if options.preserve_times {
+                    mtime::preserve(&source, &output);
+                }
+                copied.push(plain_output);
+            }
+            Err(e) => report_warning!(
+                WarningCategory::Copy,
+                "{}: Failed to copy sidecar {} ({})",
+                output.display(),
+                source.display(),
+                e
+            ),
+        }
+    }
+    info!("Copied {} sidecar files.", copied.len());
+    copied
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::plan::{Action, Task};
+    use crate::tags::TagOverrides;
+
+    #[test]
+    fn finds_sidecars_of_existing_outputs() {
+        let dir = std::env::temp_dir().join(format!("sidecar-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(dir.join("out")).unwrap();
+        for file in [
+            "01 Song.flac",
+            "01 Song.lrc",
+            "02 Song.mp3",
+            "03 Song.mp3",
+            "03 Song.LRC",
+        ] {
+            std::fs::write(dir.join(file), b"").unwrap();
+        }
+        for file in ["01_Song.mp3", "02_Song.mp3", "03_Song.mp3"] {
+            std::fs::write(dir.join("out").join(file), b"").unwrap();
+        }
+        let task = |source: &str, destination: &str| Task {
+            action: Action::Copy,
+            source: dir.join(source),
+            destination: dir.join("out").join(destination),
+            tags: TagOverrides::default(),
+            encoder: None,
+            segment: None,
+        };
+        let plan = Plan {
+            tasks: vec![
+                task("01 Song.flac", "01_Song.mp3"),
+                task("02 Song.mp3", "02_Song.mp3"),
+                task("03 Song.mp3", "03_Song.mp3"),
+                task("01 Song.flac", "missing.mp3"),
+            ],
+            ..Default::default()
+        };
+        let sidecars = sidecars(&plan, &[".lrc".to_string()]);
+        assert_eq!(
+            sidecars.into_iter().collect::<Vec<_>>(),
+            [
+                (dir.join("out/01_Song.lrc"), dir.join("01 Song.lrc")),
+                (dir.join("out/03_Song.lrc"), dir.join("03 Song.LRC")),
+            ]
+        );
+
+        let options = SidecarOptions {
+            extensions: vec!["lrc".to_string()],
+            ..Default::default()
+        };
+        copy_sidecars(&plan, &options);
+        assert!(dir.join("out/01_Song.lrc").is_file());
+        assert!(!dir.join("out/02_Song.lrc").exists());
+    }
+}
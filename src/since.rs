@@ -0,0 +1,196 @@
+// Copyright (c) 2024 Jan Holthuis <jan.holthuis@rub.de>
+//
+// This Source Code Form is subject to the terms of the Mozilla Public License, v. 2.0. If a copy
+// of the MPL was not distributed with this file, You can obtain one at
+// http://mozilla.org/MPL/2.0/.
+//
+// SPDX-License-Identifier: MPL-2.0
+//! Processing only sources that were added or modified recently, for `--since`.
+use crate::longpath;
+use crate::plan::{Plan, SkipReason, SkippedEntry, Task};
+use clap::ValueEnum;
+use log::{debug, info};
+use std::path::Path;
+use std::time::SystemTime;
+
+/// What `--since` leaves out.
+#[derive(ValueEnum, Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum SinceMode {
+    /// Only leave older sources out of the conversion. Playlists still list them, assuming that
+    /// their output is on the target from a previous run. Older sources without an output are
+    /// processed anyway.
+    #[default]
+    PlanOnly,
+    /// Also leave older sources out of the playlists.
+    PlaylistsToo,
+}
+
+/// Parse a cutoff for `--since`, either a date like `2024-06-01` (in UTC), a date and time like
+/// `2024-06-01 18:30:00`, or a duration before now like `7d` or `12h`.
+pub fn parse_cutoff(value: &str) -> Result<SystemTime, String> {
+    let value = value.trim();
+    let is_date = value.len() == 10 && value.as_bytes()[4] == b'-' && value.as_bytes()[7] == b'-';
+    let timestamp = if is_date {
+        humantime::parse_rfc3339_weak(&format!("{} 00:00:00", value))
+    } else {
+        humantime::parse_rfc3339_weak(value)
+    };
+    if let Ok(timestamp) = timestamp {
+        return Ok(timestamp);
+    }
+    if let Ok(duration) = humantime::parse_duration(value) {
+        return SystemTime::now()
+            .checked_sub(duration)
+            .ok_or_else(|| format!("duration `{}` reaches too far into the past", value));
+    }
+    Err(
+        if is_date || value.starts_with(|c: char| c.is_ascii_digit()) && value.contains('-') {
+            format!("invalid date `{}`, expected e.g. `2024-06-01`", value)
+        } else {
+            format!(
+                "invalid cutoff `{}`, expected a date like `2024-06-01` or a duration like `7d`",
+                value
+            )
+        },
+    )
+}
+
+/// Returns `true` if the source was last modified before `cutoff`.
+///
+/// Sources whose modification time cannot be read are not considered old, so that they fail
+/// like any other unreadable source.
+pub fn is_older(source: &Path, cutoff: SystemTime) -> bool {
+    std::fs::metadata(longpath::extended(source))
+        .and_then(|metadata| metadata.modified())
+        .is_ok_and(|modified| modified < cutoff)
+}
+
+/// Returns `true` if the task can be left alone with [`SinceMode::PlanOnly`], i.e. its source is
+/// older than `cutoff` and its output exists.
+pub fn is_unchanged(task: &Task, cutoff: SystemTime) -> bool {
+    is_older(&task.source, cutoff) && longpath::extended(&task.destination).exists()
+}
+
+/// Move the entries whose sources are older than `cutoff` from the playlists of the plan to their
+/// skipped entries, for [`SinceMode::PlaylistsToo`]. Returns the number of removed entries.
+pub fn remove_older(plan: &mut Plan, cutoff: SystemTime) -> usize {
+    let since = humantime::format_rfc3339_seconds(cutoff).to_string();
+    let older: Vec<bool> = plan
+        .tasks
+        .iter()
+        .map(|task| is_older(&task.source, cutoff))
+        .collect();
+    let mut num_removed = 0;
+    for playlist in plan.playlists.iter_mut() {
+        let (kept, removed): (Vec<_>, Vec<_>) = playlist
+            .entries
+            .drain(..)
+            .partition(|entry| !older[entry.task]);
+        for entry in removed.iter() {
+            debug!(
+                "{}: Not modified since {}",
+                plan.tasks[entry.task].source.display(),
+                since
+            );
+        }
+        num_removed += removed.len();
+        playlist
+            .skipped
+            .extend(removed.into_iter().map(|entry| SkippedEntry {
+                entry: entry.target,
+                reason: SkipReason::NotModifiedSince {
+                    since: since.clone(),
+                },
+            }));
+        playlist.entries = kept;
+    }
+    plan.remove_unreferenced_tasks();
+    info!(
+        "Left out {} entries not modified since {}",
+        num_removed, since
+    );
+    num_removed
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::plan::{Action, OutputEntry, PlaylistOutput};
+    use std::time::Duration;
+
+    #[test]
+    fn parses_dates_and_durations() {
+        assert_eq!(
+            parse_cutoff("2024-06-01"),
+            Ok(SystemTime::UNIX_EPOCH + Duration::from_secs(1_717_200_000))
+        );
+        assert_eq!(
+            parse_cutoff("2024-06-01 00:00:10"),
+            Ok(SystemTime::UNIX_EPOCH + Duration::from_secs(1_717_200_010))
+        );
+        let week = Duration::from_secs(7 * 24 * 60 * 60);
+        let cutoff = parse_cutoff("7d").unwrap();
+        let age = SystemTime::now().duration_since(cutoff).unwrap();
+        assert!(age >= week && age < week + Duration::from_secs(60));
+        assert_eq!(
+            parse_cutoff("2024-13-01"),
+            Err("invalid date `2024-13-01`, expected e.g. `2024-06-01`".to_string())
+        );
+        assert!(parse_cutoff("last week").unwrap_err().contains("like `7d`"));
+    }
+
+    #[test]
+    fn only_recent_sources_are_kept() {
+        let dir = std::env::temp_dir().join(format!("since-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(dir.join("out")).unwrap();
+        for file in ["old.mp3", "new.mp3", "out/old.mp3"] {
+            std::fs::write(dir.join(file), b"").unwrap();
+        }
+        let old = std::fs::File::options()
+            .write(true)
+            .open(dir.join("old.mp3"))
+            .unwrap();
+        old.set_modified(SystemTime::UNIX_EPOCH + Duration::from_secs(1_000_000))
+            .unwrap();
+        let cutoff = SystemTime::UNIX_EPOCH + Duration::from_secs(2_000_000);
+        let task = |name: &str| Task {
+            action: Action::Copy,
+            source: dir.join(name),
+            destination: dir.join("out").join(name),
+            tags: Default::default(),
+            encoder: None,
+            segment: None,
+        };
+        assert!(is_unchanged(&task("old.mp3"), cutoff));
+        assert!(!is_unchanged(&task("new.mp3"), cutoff));
+        assert!(!is_older(&dir.join("missing.mp3"), cutoff));
+
+        let mut plan = Plan {
+            tasks: vec![task("old.mp3"), task("new.mp3")],
+            playlists: vec![PlaylistOutput {
+                source: dir.join("playlist.m3u"),
+                path: dir.join("out/playlist.m3u"),
+                entries: vec![
+                    OutputEntry {
+                        target: "old.mp3".to_string(),
+                        task: 0,
+                    },
+                    OutputEntry {
+                        target: "new.mp3".to_string(),
+                        task: 1,
+                    },
+                ],
+                skipped: vec![],
+                title: None,
+            }],
+            ..Default::default()
+        };
+        assert_eq!(remove_older(&mut plan, cutoff), 1);
+        assert_eq!(plan.tasks.len(), 1);
+        assert_eq!(plan.playlists[0].entries[0].target, "new.mp3");
+        assert_eq!(plan.playlists[0].entries[0].task, 0);
+        assert_eq!(plan.playlists[0].skipped[0].entry, "old.mp3");
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,561 @@
+// Copyright (c) 2024 Jan Holthuis <jan.holthuis@rub.de>
+//
+// This Source Code Form is subject to the terms of the Mozilla Public License, v. 2.0. If a copy
+// of the MPL was not distributed with this file, You can obtain one at
+// http://mozilla.org/MPL/2.0/.
+//
+// SPDX-License-Identifier: MPL-2.0
+//! Smart playlists defined by rules (`--smart-playlists`) and filled from the metadata of the
+//! files in a library (`--library`).
+//!
+//! The rules file is TOML (or JSON if its extension is `.json`) with one table per playlist:
+//!
+//! ```toml
+//! [[playlist]]
+//! name = "90s Rock"
+//! all = ["genre contains rock", "year >= 1990", "year < 2000"]
+//! limit = 200
+//! order = "random"
+//! ```
+//!
+//! A track is part of the playlist if it matches all rules of `all` and, if given, at least one of
+//! `any`. Every rule compares a field (`genre`, `artist`, `album`, `path`, `year`, `rating` or
+//! `duration` in seconds) with a value using `=`, `!=`, `contains` (text fields only) or `<`,
+//! `<=`, `>`, `>=` (number fields only). Text is compared case-insensitively. Tracks that lack the
+//! field never match.
+use crate::plan::InputPlaylist;
+use crate::probe::ProbeInfo;
+use log::info;
+use serde::Deserialize;
+use std::collections::HashMap;
+use std::fmt;
+use std::path::{Path, PathBuf};
+use std::time::SystemTime;
+use toml::Spanned;
+use xxhash_rust::xxh3::xxh3_64_with_seed;
+
+/// Error reading a rules file.
+#[derive(Debug)]
+pub enum SmartPlaylistError {
+    Io(std::io::Error),
+    /// The file is not valid TOML or JSON, or has unknown keys.
+    Parse(String),
+    /// A rule cannot be parsed.
+    InvalidRule {
+        playlist: String,
+        rule: String,
+        /// Line of the rule in the file, if known.
+        line: Option<usize>,
+        reason: String,
+    },
+}
+
+impl fmt::Display for SmartPlaylistError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            SmartPlaylistError::Io(e) => e.fmt(f),
+            SmartPlaylistError::Parse(e) => write!(f, "{}", e.trim_end()),
+            SmartPlaylistError::InvalidRule {
+                playlist,
+                rule,
+                line,
+                reason,
+            } => {
+                if let Some(line) = line {
+                    write!(f, "line {}: ", line)?;
+                }
+                write!(
+                    f,
+                    "invalid rule `{}` of playlist `{}` ({})",
+                    rule, playlist, reason
+                )
+            }
+        }
+    }
+}
+
+impl std::error::Error for SmartPlaylistError {}
+
+/// A field of a track that rules can compare.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum Field {
+    Genre,
+    Artist,
+    Album,
+    Path,
+    Year,
+    Rating,
+    Duration,
+}
+
+impl Field {
+    fn parse(name: &str) -> Option<Self> {
+        Some(match name {
+            "genre" => Field::Genre,
+            "artist" => Field::Artist,
+            "album" => Field::Album,
+            "path" => Field::Path,
+            "year" => Field::Year,
+            "rating" => Field::Rating,
+            "duration" => Field::Duration,
+            _ => return None,
+        })
+    }
+
+    fn is_number(self) -> bool {
+        matches!(self, Field::Year | Field::Rating | Field::Duration)
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum Operator {
+    Equal,
+    NotEqual,
+    Contains,
+    Less,
+    LessOrEqual,
+    Greater,
+    GreaterOrEqual,
+}
+
+/// Operators by their spelling, longer ones first so that `<=` is not taken for `<`.
+const OPERATORS: [(&str, Operator); 8] = [
+    ("contains", Operator::Contains),
+    ("!=", Operator::NotEqual),
+    ("<=", Operator::LessOrEqual),
+    (">=", Operator::GreaterOrEqual),
+    ("==", Operator::Equal),
+    ("=", Operator::Equal),
+    ("<", Operator::Less),
+    (">", Operator::Greater),
+];
+
+#[derive(Clone, Debug, PartialEq)]
+enum Value {
+    Text(String),
+    Number(f64),
+}
+
+/// A single comparison like `year >= 1990`.
+#[derive(Clone, Debug, PartialEq)]
+struct Rule {
+    field: Field,
+    operator: Operator,
+    value: Value,
+}
+
+impl std::str::FromStr for Rule {
+    type Err = String;
+
+    fn from_str(rule: &str) -> Result<Self, Self::Err> {
+        let rule = rule.trim();
+        let name_end = rule
+            .find(|c: char| !c.is_ascii_alphabetic())
+            .unwrap_or(rule.len());
+        let (name, rest) = rule.split_at(name_end);
+        let field = Field::parse(&name.to_lowercase()).ok_or_else(|| {
+            format!(
+                "unknown field `{}`, expected one of genre, artist, album, path, year, rating \
+                 and duration",
+                name
+            )
+        })?;
+        let rest = rest.trim_start();
+        let (spelling, operator) = OPERATORS
+            .iter()
+            .find(|(spelling, _)| rest.starts_with(spelling))
+            .ok_or("expected one of =, !=, contains, <, <=, >, >= after the field")?;
+        let value = rest[spelling.len()..].trim();
+        let value = value
+            .strip_prefix('"')
+            .and_then(|value| value.strip_suffix('"'))
+            .unwrap_or(value);
+        if value.is_empty() {
+            return Err("missing value".to_string());
+        }
+        let value = if field.is_number() {
+            if *operator == Operator::Contains {
+                return Err(format!(
+                    "`contains` only works with text fields, not {}",
+                    name
+                ));
+            }
+            Value::Number(parse_number(field, value)?)
+        } else {
+            if !matches!(
+                operator,
+                Operator::Equal | Operator::NotEqual | Operator::Contains
+            ) {
+                return Err(format!(
+                    "`{}` only works with year, rating and duration, not {}",
+                    spelling, name
+                ));
+            }
+            Value::Text(value.to_lowercase())
+        };
+        Ok(Rule {
+            field,
+            operator: *operator,
+            value,
+        })
+    }
+}
+
+/// Parse the value of a number field. Durations may also be given as `m:ss`.
+fn parse_number(field: Field, value: &str) -> Result<f64, String> {
+    let parsed = match (field, value.split_once(':')) {
+        (Field::Duration, Some((minutes, seconds))) => minutes
+            .parse::<f64>()
+            .ok()
+            .zip(seconds.parse::<f64>().ok())
+            .map(|(minutes, seconds)| minutes * 60.0 + seconds),
+        _ => value.parse().ok(),
+    };
+    parsed.ok_or_else(|| format!("`{}` is not a number", value))
+}
+
+impl Rule {
+    /// Returns whether the track at `path` (relative to the library) matches the rule.
+    fn matches(&self, path: &Path, info: Option<&ProbeInfo>) -> bool {
+        match &self.value {
+            Value::Number(expected) => {
+                let Some(actual) = info.and_then(|info| number(self.field, info)) else {
+                    return false;
+                };
+                match self.operator {
+                    Operator::Equal => actual == *expected,
+                    Operator::NotEqual => actual != *expected,
+                    Operator::Less => actual < *expected,
+                    Operator::LessOrEqual => actual <= *expected,
+                    Operator::Greater => actual > *expected,
+                    Operator::GreaterOrEqual => actual >= *expected,
+                    Operator::Contains => false,
+                }
+            }
+            Value::Text(expected) => {
+                let actual = match self.field {
+                    Field::Path => Some(path.to_string_lossy().replace('\\', "/")),
+                    Field::Genre => info.and_then(|info| info.tag("genre")).map(str::to_string),
+                    Field::Artist => info.and_then(|info| info.tag("artist")).map(str::to_string),
+                    Field::Album => info.and_then(|info| info.tag("album")).map(str::to_string),
+                    _ => None,
+                };
+                let Some(actual) = actual.map(|actual| actual.to_lowercase()) else {
+                    return false;
+                };
+                match self.operator {
+                    Operator::Equal => actual == *expected,
+                    Operator::NotEqual => actual != *expected,
+                    Operator::Contains => actual.contains(expected.as_str()),
+                    _ => false,
+                }
+            }
+        }
+    }
+}
+
+/// Returns the value of a number field of a track.
+fn number(field: Field, info: &ProbeInfo) -> Option<f64> {
+    match field {
+        Field::Year => info
+            .tag("date")
+            .or_else(|| info.tag("year"))
+            .and_then(|date| date.get(..4))
+            .and_then(|year| year.parse().ok()),
+        Field::Rating => info.rating.map(f64::from),
+        Field::Duration => info.duration,
+        _ => None,
+    }
+}
+
+/// Order of the tracks of a smart playlist.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Order {
+    /// By path in the library.
+    #[default]
+    Path,
+    /// Shuffled anew on every run.
+    Random,
+    /// Oldest first.
+    Year,
+    /// Best rated first.
+    Rating,
+}
+
+/// A playlist that is generated from the tracks of the library that match its rules.
+#[derive(Clone, Debug, PartialEq)]
+pub struct SmartPlaylist {
+    pub name: String,
+    all: Vec<Rule>,
+    any: Vec<Rule>,
+    /// Maximum number of tracks.
+    pub limit: Option<usize>,
+    pub order: Order,
+}
+
+/// The text of a rule in the rules file, with its position if the format provides it.
+trait RuleText {
+    fn text(&self) -> &str;
+    fn line(&self, contents: &str) -> Option<usize>;
+}
+
+impl RuleText for String {
+    fn text(&self) -> &str {
+        self
+    }
+
+    fn line(&self, _: &str) -> Option<usize> {
+        None
+    }
+}
+
+impl RuleText for Spanned<String> {
+    fn text(&self) -> &str {
+        self.get_ref()
+    }
+
+    fn line(&self, contents: &str) -> Option<usize> {
+        Some(contents[..self.span().start].matches('\n').count() + 1)
+    }
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct RawPlaylist<R> {
+    name: String,
+    #[serde(default = "Vec::new")]
+    all: Vec<R>,
+    #[serde(default = "Vec::new")]
+    any: Vec<R>,
+    limit: Option<usize>,
+    #[serde(default)]
+    order: Order,
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct RawFile<R> {
+    #[serde(default = "Vec::new")]
+    playlist: Vec<RawPlaylist<R>>,
+}
+
+impl<R: RuleText> RawFile<R> {
+    fn parse_rules(self, contents: &str) -> Result<Vec<SmartPlaylist>, SmartPlaylistError> {
+        self.playlist
+            .into_iter()
+            .map(|raw| {
+                let parse = |rules: Vec<R>| {
+                    rules
+                        .iter()
+                        .map(|rule| {
+                            rule.text()
+                                .parse()
+                                .map_err(|reason| SmartPlaylistError::InvalidRule {
+                                    playlist: raw.name.clone(),
+                                    rule: rule.text().to_string(),
+                                    line: rule.line(contents),
+                                    reason,
+                                })
+                        })
+                        .collect::<Result<Vec<_>, _>>()
+                };
+                Ok(SmartPlaylist {
+                    name: raw.name.clone(),
+                    all: parse(raw.all)?,
+                    any: parse(raw.any)?,
+                    limit: raw.limit,
+                    order: raw.order,
+                })
+            })
+            .collect()
+    }
+}
+
+/// Parse rules from TOML.
+pub fn parse(contents: &str) -> Result<Vec<SmartPlaylist>, SmartPlaylistError> {
+    toml::from_str::<RawFile<Spanned<String>>>(contents)
+        .map_err(|e| SmartPlaylistError::Parse(e.to_string()))?
+        .parse_rules(contents)
+}
+
+/// Read a rules file, which is JSON if it has the extension `.json` and TOML otherwise.
+pub fn read_file(path: &Path) -> Result<Vec<SmartPlaylist>, SmartPlaylistError> {
+    let contents = std::fs::read_to_string(path).map_err(SmartPlaylistError::Io)?;
+    let is_json = path
+        .extension()
+        .is_some_and(|extension| extension.eq_ignore_ascii_case("json"));
+    if is_json {
+        serde_json::from_str::<RawFile<String>>(&contents)
+            .map_err(|e| SmartPlaylistError::Parse(e.to_string()))?
+            .parse_rules(&contents)
+    } else {
+        parse(&contents)
+    }
+}
+
+impl SmartPlaylist {
+    /// Returns whether the track at `path` (relative to the library) belongs to the playlist.
+    fn matches(&self, path: &Path, info: Option<&ProbeInfo>) -> bool {
+        self.all.iter().all(|rule| rule.matches(path, info))
+            && (self.any.is_empty() || self.any.iter().any(|rule| rule.matches(path, info)))
+    }
+
+    /// Returns the playlist of the matching `files` of the library at `root`, whose metadata is
+    /// keyed by their full path.
+    pub fn generate(
+        &self,
+        root: &Path,
+        files: &[PathBuf],
+        metadata: &HashMap<PathBuf, ProbeInfo>,
+    ) -> InputPlaylist {
+        let info = |file: &PathBuf| metadata.get(&root.join(file));
+        let mut entries: Vec<PathBuf> = files
+            .iter()
+            .filter(|file| self.matches(file, info(file)))
+            .cloned()
+            .collect();
+        entries.sort();
+        match self.order {
+            Order::Path => (),
+            Order::Random => {
+                let seed = SystemTime::now()
+                    .duration_since(SystemTime::UNIX_EPOCH)
+                    .map(|elapsed| elapsed.as_nanos() as u64)
+                    .unwrap_or_default();
+                entries.sort_by_cached_key(|file| {
+                    xxh3_64_with_seed(file.to_string_lossy().as_bytes(), seed)
+                });
+            }
+            Order::Year => entries.sort_by(|a, b| {
+                let year = |file| info(file).and_then(|info| number(Field::Year, info));
+                year(a)
+                    .unwrap_or(f64::MAX)
+                    .total_cmp(&year(b).unwrap_or(f64::MAX))
+            }),
+            Order::Rating => entries
+                .sort_by_key(|file| std::cmp::Reverse(info(file).and_then(|info| info.rating))),
+        }
+        let matched = entries.len();
+        if let Some(limit) = self.limit {
+            entries.truncate(limit);
+        }
+        info!(
+            "{}: Smart playlist matches {} tracks (using {})",
+            self.name,
+            matched,
+            entries.len()
+        );
+        InputPlaylist {
+            path: root.join(format!("{}.m3u", self.name)),
+            entries,
+            skipped: vec![],
+            title: None,
+            durations: Default::default(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn info(tags: &[(&str, &str)], rating: Option<u8>, duration: f64) -> ProbeInfo {
+        ProbeInfo {
+            tags: tags
+                .iter()
+                .map(|(name, value)| (name.to_string(), value.to_string()))
+                .collect(),
+            rating,
+            duration: Some(duration),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn parses_rules() {
+        let rule: Rule = "year>=1990".parse().unwrap();
+        assert_eq!(rule.operator, Operator::GreaterOrEqual);
+        assert_eq!(rule.value, Value::Number(1990.0));
+        let rule: Rule = "Genre contains \"Hard Rock\"".parse().unwrap();
+        assert_eq!(rule.field, Field::Genre);
+        assert_eq!(rule.value, Value::Text("hard rock".to_string()));
+        let rule: Rule = "duration < 4:30".parse().unwrap();
+        assert_eq!(rule.value, Value::Number(270.0));
+    }
+
+    #[test]
+    fn reports_invalid_rules_with_their_line() {
+        let error = parse(
+            "[[playlist]]\nname = \"Short\"\nall = [\n  \"duration < 300\",\n  \"artist < b\",\n]\n",
+        )
+        .unwrap_err();
+        assert_eq!(
+            error.to_string(),
+            "line 5: invalid rule `artist < b` of playlist `Short` (`<` only works with year, \
+             rating and duration, not artist)"
+        );
+        let error = parse("[[playlist]]\nname = \"A\"\nall = [\"bpm > 120\"]").unwrap_err();
+        assert!(
+            error.to_string().contains("unknown field `bpm`"),
+            "{}",
+            error
+        );
+        assert!(parse("[[playlist]]\nname = \"A\"\nsort = \"path\"").is_err());
+    }
+
+    #[test]
+    fn generates_playlists_from_matching_tracks() {
+        let root = Path::new("library");
+        let tracks = [
+            (
+                "rock/a.mp3",
+                info(&[("genre", "Rock"), ("date", "1994-05-01")], Some(3), 200.0),
+            ),
+            (
+                "rock/b.mp3",
+                info(&[("genre", "Hard Rock"), ("date", "1999")], Some(5), 250.0),
+            ),
+            (
+                "rock/c.mp3",
+                info(&[("genre", "Rock"), ("date", "2003")], None, 180.0),
+            ),
+            (
+                "jazz/d.mp3",
+                info(&[("genre", "Jazz"), ("date", "1995")], Some(4), 400.0),
+            ),
+            ("misc/e.mp3", info(&[], None, 100.0)),
+        ];
+        let files: Vec<PathBuf> = tracks.iter().map(|(file, _)| PathBuf::from(file)).collect();
+        let metadata: HashMap<PathBuf, ProbeInfo> = tracks
+            .iter()
+            .map(|(file, info)| (root.join(file), info.clone()))
+            .collect();
+        let playlists = parse(
+            r#"
+            [[playlist]]
+            name = "90s Rock"
+            all = ["genre contains rock", "year >= 1990", "year < 2000"]
+            order = "rating"
+
+            [[playlist]]
+            name = "Short or Jazz"
+            any = ["duration <= 3:00", "path contains jazz/"]
+            limit = 2
+            "#,
+        )
+        .unwrap();
+
+        let generated = playlists[0].generate(root, &files, &metadata);
+        assert_eq!(generated.path, Path::new("library/90s Rock.m3u"));
+        assert_eq!(
+            generated.entries,
+            [PathBuf::from("rock/b.mp3"), PathBuf::from("rock/a.mp3")]
+        );
+        let generated = playlists[1].generate(root, &files, &metadata);
+        assert_eq!(
+            generated.entries,
+            [PathBuf::from("jazz/d.mp3"), PathBuf::from("misc/e.mp3")]
+        );
+    }
+}
@@ -0,0 +1,125 @@
+// Copyright (c) 2024 Jan Holthuis <jan.holthuis@rub.de>
+//
+// This Source Code Form is subject to the terms of the Mozilla Public License, v. 2.0. If a copy
+// of the MPL was not distributed with this file, You can obtain one at
+// http://mozilla.org/MPL/2.0/.
+//
+// SPDX-License-Identifier: MPL-2.0
+//! Context for the log records of a playlist or a task.
+//!
+//! A span is entered on the current thread until its guard is dropped. The structured log format
+//! attaches the fields of all entered spans to every record, while the text format keeps putting
+//! the context into the messages.
+use crate::plan::{Action, Task};
+use std::cell::RefCell;
+use std::fmt;
+
+thread_local! {
+    static ENTERED: RefCell<Vec<Span>> = const { RefCell::new(Vec::new()) };
+}
+
+/// A named set of fields.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Span {
+    pub name: &'static str,
+    pub fields: Vec<(&'static str, String)>,
+}
+
+impl Span {
+    pub fn new(name: &'static str) -> Self {
+        Self {
+            name,
+            fields: vec![],
+        }
+    }
+
+    pub fn field(mut self, key: &'static str, value: impl fmt::Display) -> Self {
+        self.fields.push((key, value.to_string()));
+        self
+    }
+
+    /// The span for reading and planning a playlist.
+    pub fn playlist(path: &std::path::Path) -> Self {
+        Self::new("playlist").field("playlist", path.display())
+    }
+
+    /// The span for converting or copying a file.
+    pub fn task(task: &Task) -> Self {
+        let action = match task.action {
+            Action::Convert => "convert",
+            Action::Remux => "remux",
+            Action::Copy => "copy",
+        };
+        Self::new("task")
+            .field("source", task.source.display())
+            .field("dest", task.destination.display())
+            .field("action", action)
+    }
+
+    /// Enter the span on the current thread.
+    pub fn enter(self) -> Entered {
+        enter_all(vec![self])
+    }
+}
+
+/// Leaves the spans when dropped.
+#[must_use = "the spans are left when the guard is dropped"]
+pub struct Entered(usize);
+
+impl Drop for Entered {
+    fn drop(&mut self) {
+        ENTERED.with(|entered| {
+            let mut entered = entered.borrow_mut();
+            let len = entered.len().saturating_sub(self.0);
+            entered.truncate(len);
+        });
+    }
+}
+
+/// Returns the spans entered on the current thread, outermost first, e.g. to enter them on
+/// another thread with [`enter_all`].
+pub fn current() -> Vec<Span> {
+    ENTERED.with(|entered| entered.borrow().clone())
+}
+
+/// Enter several spans on the current thread.
+pub fn enter_all(spans: Vec<Span>) -> Entered {
+    let count = spans.len();
+    ENTERED.with(|entered| entered.borrow_mut().extend(spans));
+    Entered(count)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn nests_spans_until_they_are_left() {
+        let outer = Span::new("playlist").field("playlist", "a.m3u").enter();
+        {
+            let _inner = Span::new("task").field("source", "a.flac").enter();
+            let names: Vec<_> = current().iter().map(|span| span.name).collect();
+            assert_eq!(names, ["playlist", "task"]);
+        }
+        assert_eq!(
+            current(),
+            [Span::new("playlist").field("playlist", "a.m3u")]
+        );
+        drop(outer);
+        assert!(current().is_empty());
+    }
+
+    #[test]
+    fn propagates_spans_to_other_threads() {
+        let _span = Span::new("task").field("source", "a.flac").enter();
+        let spans = current();
+        let names = std::thread::spawn(move || {
+            assert!(current().is_empty());
+            let _spans = enter_all(spans);
+            current().iter().map(|span| span.name).collect::<Vec<_>>()
+        })
+        .join()
+        .unwrap();
+        assert_eq!(names, ["task"]);
+    }
+}
@@ -0,0 +1,340 @@
+// Copyright (c) 2024 Jan Holthuis <jan.holthuis@rub.de>
+//
+// This Source Code Form is subject to the terms of the Mozilla Public License, v. 2.0. If a copy
+// of the MPL was not distributed with this file, You can obtain one at
+// http://mozilla.org/MPL/2.0/.
+//
+// SPDX-License-Identifier: MPL-2.0
+//! Splitting of the output across several targets like USB sticks, for `--split-targets`.
+//!
+//! Each playlist is kept on a single target where possible, and is only written to the target
+//! that holds its files. Playlists that fit on no target are split into parts like
+//! `Name (disc 2)`, one per target.
+use crate::estimate::parse_size;
+use crate::plan::{OutputEntry, Plan, PlaylistOutput, SkipReason, SkippedEntry, Task};
+use log::warn;
+use std::collections::{HashMap, HashSet};
+use std::path::{Path, PathBuf};
+
+/// An output directory with a size budget, given as `DIR:SIZE`, e.g. `/media/usb1:32G`.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct SplitTarget {
+    pub dir: PathBuf,
+    pub size: u64,
+}
+
+impl std::str::FromStr for SplitTarget {
+    type Err = String;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        // The size comes last, so that directories may contain colons, e.g. on Windows.
+        let (dir, size) = value
+            .rsplit_once(':')
+            .filter(|(dir, size)| !dir.is_empty() && !size.is_empty())
+            .ok_or_else(|| format!("invalid split target `{}`, expected `DIR:SIZE`", value))?;
+        Ok(Self {
+            dir: PathBuf::from(dir),
+            size: parse_size(size)?,
+        })
+    }
+}
+
+/// The part of the plan that is written to one target.
+#[derive(Clone, Debug)]
+pub struct TargetPlan {
+    pub target: SplitTarget,
+    /// The plan with all output paths in the target's directory.
+    pub plan: Plan,
+    /// Estimated size of the distinct output files.
+    pub bytes: u64,
+}
+
+impl TargetPlan {
+    /// Returns the number of distinct output files.
+    pub fn num_files(&self) -> usize {
+        self.plan
+            .tasks
+            .iter()
+            .map(|task| &task.destination)
+            .collect::<HashSet<_>>()
+            .len()
+    }
+}
+
+/// Returns the path of part `number` of the playlist at `path`, e.g. `Road (disc 2).m3u`.
+fn part_path(path: &Path, number: usize) -> PathBuf {
+    let mut file_name = path.file_stem().unwrap_or_default().to_os_string();
+    file_name.push(format!(" (disc {})", number));
+    if let Some(extension) = path.extension() {
+        file_name.push(".");
+        file_name.push(extension);
+    }
+    path.with_file_name(file_name)
+}
+
+/// The files on one target so far.
+#[derive(Default)]
+struct Fill {
+    destinations: HashSet<PathBuf>,
+    bytes: u64,
+}
+
+impl Fill {
+    /// Returns the bytes that the destination adds to the target.
+    fn cost(&self, destination: &Path, costs: &HashMap<PathBuf, u64>) -> u64 {
+        if self.destinations.contains(destination) {
+            0
+        } else {
+            costs[destination]
+        }
+    }
+}
+
+/// Partition the plan, whose output paths are in `output_dir`, across `targets`.
+///
+/// Playlists are placed in their order on the first target that has space left for all of their
+/// files, where files that are already on a target for another playlist cost nothing. A playlist
+/// that fits on no target is split into parts that fill the targets in order. Entries that fit
+/// on no target at all are cut.
+///
+/// Returns one plan for each target, in the order of `targets`.
+pub fn split(
+    plan: &Plan,
+    output_dir: &Path,
+    targets: &[SplitTarget],
+    cost: impl Fn(&Plan, &Task) -> u64,
+) -> Vec<TargetPlan> {
+    let mut costs: HashMap<PathBuf, u64> = HashMap::new();
+    for task in plan.tasks.iter() {
+        costs
+            .entry(task.destination.clone())
+            .or_insert_with(|| cost(plan, task));
+    }
+    let destination = |entry: &OutputEntry| plan.tasks[entry.task].destination.as_path();
+
+    let mut fills: Vec<Fill> = targets.iter().map(|_| Fill::default()).collect();
+    let mut playlists: Vec<Vec<PlaylistOutput>> = targets.iter().map(|_| vec![]).collect();
+    for playlist in plan.playlists.iter() {
+        let whole = (0..targets.len()).find(|&index| {
+            let fill = &fills[index];
+            let added: u64 = playlist
+                .entries
+                .iter()
+                .map(destination)
+                .collect::<HashSet<_>>()
+                .into_iter()
+                .map(|destination| fill.cost(destination, &costs))
+                .sum();
+            fill.bytes + added <= targets[index].size
+        });
+        // Pairs of target index and entries of the parts of the playlist.
+        let mut parts: Vec<(usize, Vec<OutputEntry>)> = vec![];
+        let mut cut = vec![];
+        match whole {
+            Some(index) => parts.push((index, playlist.entries.clone())),
+            None => {
+                let mut current = 0;
+                for entry in playlist.entries.iter() {
+                    let destination = destination(entry);
+                    let Some(index) = (current..targets.len()).find(|&index| {
+                        fills[index].bytes + fills[index].cost(destination, &costs)
+                            <= targets[index].size
+                    }) else {
+                        cut.push(SkippedEntry {
+                            entry: entry.target.clone(),
+                            reason: SkipReason::OverSizeBudget,
+                        });
+                        continue;
+                    };
+                    current = index;
+                    let added = fills[index].cost(destination, &costs);
+                    fills[index].bytes += added;
+                    fills[index].destinations.insert(destination.to_path_buf());
+                    match parts.last_mut() {
+                        Some((last, entries)) if *last == index => entries.push(entry.clone()),
+                        _ => parts.push((index, vec![entry.clone()])),
+                    }
+                }
+            }
+        }
+        if let Some((index, entries)) = parts.first().filter(|_| whole.is_some()) {
+            for entry in entries {
+                let destination = destination(entry);
+                let added = fills[*index].cost(destination, &costs);
+                fills[*index].bytes += added;
+                fills[*index].destinations.insert(destination.to_path_buf());
+            }
+        }
+        if !cut.is_empty() {
+            warn!(
+                "{}: Cut {} entries that fit on no target",
+                playlist.source.display(),
+                cut.len()
+            );
+        }
+        if parts.is_empty() {
+            // Nothing of the playlist fits anywhere, so it only lists the cut entries.
+            parts.push((0, vec![]));
+        }
+        let num_parts = parts.len();
+        for (number, (index, entries)) in parts.into_iter().enumerate() {
+            let path = if num_parts > 1 {
+                part_path(&playlist.path, number + 1)
+            } else {
+                playlist.path.clone()
+            };
+            // The entries that were skipped before are only listed once, in the first part.
+            let mut skipped = if number == 0 {
+                playlist.skipped.clone()
+            } else {
+                vec![]
+            };
+            if number + 1 == num_parts {
+                skipped.append(&mut cut);
+            }
+            playlists[index].push(PlaylistOutput {
+                source: playlist.source.clone(),
+                path,
+                entries,
+                skipped,
+                title: playlist.title.as_ref().map(|title| {
+                    if num_parts > 1 {
+                        format!("{} (disc {})", title, number + 1)
+                    } else {
+                        title.clone()
+                    }
+                }),
+            });
+        }
+    }
+
+    targets
+        .iter()
+        .zip(playlists)
+        .zip(fills)
+        .map(|((target, playlists), fill)| {
+            let mut part = Plan {
+                playlists,
+                tasks: vec![],
+                metadata: plan.metadata.clone(),
+                duplicates: plan.duplicates.clone(),
+                short_names: plan.short_names.clone(),
+            };
+            let mut new_indices: HashMap<usize, usize> = HashMap::new();
+            for entry in part
+                .playlists
+                .iter_mut()
+                .flat_map(|playlist| &mut playlist.entries)
+            {
+                entry.task = *new_indices.entry(entry.task).or_insert_with(|| {
+                    part.tasks.push(plan.tasks[entry.task].clone());
+                    part.tasks.len() - 1
+                });
+            }
+            part.relocate(output_dir, &target.dir);
+            TargetPlan {
+                target: target.clone(),
+                plan: part,
+                bytes: fill.bytes,
+            }
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::plan::{ConflictPolicy, InputPlaylist, Planner};
+
+    fn plan(playlists: &[(&str, &[&str])]) -> Plan {
+        let mut planner = Planner::new(Path::new("output"), ConflictPolicy::Error);
+        for (path, entries) in playlists {
+            planner
+                .add_playlist(&InputPlaylist {
+                    path: PathBuf::from(path),
+                    entries: entries.iter().map(PathBuf::from).collect(),
+                    skipped: vec![],
+                    title: None,
+                    durations: Default::default(),
+                })
+                .unwrap();
+        }
+        planner.finish()
+    }
+
+    fn target(dir: &str, size: u64) -> SplitTarget {
+        SplitTarget {
+            dir: PathBuf::from(dir),
+            size,
+        }
+    }
+
+    fn playlists(part: &TargetPlan) -> Vec<(PathBuf, usize)> {
+        part.plan
+            .playlists
+            .iter()
+            .map(|playlist| (playlist.path.clone(), playlist.entries.len()))
+            .collect()
+    }
+
+    #[test]
+    fn parses_targets() {
+        assert_eq!(
+            "/media/usb1:32G".parse::<SplitTarget>(),
+            Ok(target("/media/usb1", 32 << 30))
+        );
+        assert_eq!(
+            "E:\\Music:1K".parse::<SplitTarget>(),
+            Ok(target("E:\\Music", 1024))
+        );
+        assert!("/media/usb1".parse::<SplitTarget>().is_err());
+        assert!(":32G".parse::<SplitTarget>().is_err());
+    }
+
+    #[test]
+    fn keeps_playlists_together_and_splits_the_rest() {
+        let plan = plan(&[
+            ("a.m3u", &["1.mp3", "2.mp3"]),
+            ("b.m3u", &["3.mp3", "4.mp3", "5.mp3"]),
+            ("c.m3u", &["1.mp3", "6.mp3"]),
+            ("d.m3u", &["7.mp3", "8.mp3", "9.mp3", "10.mp3", "11.mp3"]),
+        ]);
+        let targets = [target("usb1", 4), target("usb2", 4)];
+        let parts = split(&plan, Path::new("output"), &targets, |_, _| 1);
+
+        assert_eq!(
+            playlists(&parts[0]),
+            [
+                (PathBuf::from("usb1/a.m3u"), 2),
+                (PathBuf::from("usb1/c.m3u"), 2),
+                (PathBuf::from("usb1/d (disc 1).m3u"), 1),
+            ]
+        );
+        assert_eq!(
+            playlists(&parts[1]),
+            [
+                (PathBuf::from("usb2/b.m3u"), 3),
+                (PathBuf::from("usb2/d (disc 2).m3u"), 1),
+            ]
+        );
+        assert_eq!((parts[0].num_files(), parts[0].bytes), (4, 4));
+        assert_eq!((parts[1].num_files(), parts[1].bytes), (4, 4));
+        assert!(parts[1].plan.tasks[0].destination.starts_with("usb2"));
+        let last = &parts[1].plan.playlists[1];
+        assert_eq!(last.skipped.len(), 3);
+        assert!(matches!(last.skipped[0].reason, SkipReason::OverSizeBudget));
+    }
+
+    #[test]
+    fn shared_files_are_written_to_every_target_that_needs_them() {
+        let plan = plan(&[
+            ("a.m3u", &["1.mp3", "2.mp3"]),
+            ("b.m3u", &["2.mp3", "3.mp3"]),
+        ]);
+        let targets = [target("usb1", 2), target("usb2", 2)];
+        let parts = split(&plan, Path::new("output"), &targets, |_, _| 1);
+        assert_eq!(parts[0].num_files(), 2);
+        assert_eq!(parts[1].num_files(), 2);
+        assert_eq!(parts[1].plan.tasks[0].destination, Path::new("usb2/2.mp3"));
+    }
+}
@@ -0,0 +1,1063 @@
+// Copyright (c) 2024 Jan Holthuis <jan.holthuis@rub.de>
+//
+// This Source Code Form is subject to the terms of the Mozilla Public License, v. 2.0. If a copy
+// of the MPL was not distributed with this file, You can obtain one at
+// http://mozilla.org/MPL/2.0/.
+//
+// SPDX-License-Identifier: MPL-2.0
+//! Planning and executing a whole sync, independent of the command line.
+use crate::art::{self, CopyArtOptions, FolderArtOptions};
+use crate::atomic;
+use crate::budget;
+use crate::dedupe::{self, DedupeKey};
+use crate::estimate::{self, ByteSize, EstimateOptions};
+use crate::exclude::{self, Excludes, Extensions};
+use crate::execute::{self, ExecuteError, ExecuteOptions, TaskResult, TaskStatus};
+use crate::filter::{MissingTag, TagFilter};
+use crate::fingerprint;
+use crate::genre::GenreMap;
+use crate::interrupt;
+use crate::layout::{Layout, SharedTracks, Structure};
+use crate::limits;
+use crate::lock::{LockError, OutputLock};
+use crate::longpath;
+use crate::manifest::{self, Manifest};
+use crate::mapping;
+use crate::media_server::{MediaServer, MediaServerError, PathMapping};
+use crate::mount::TargetFs;
+use crate::mpd::{self, MpdError, MpdSource};
+use crate::observer::{Observers, SyncObserver};
+use crate::overlap::{self, OverlapError};
+use crate::plan::{
+    Action, ConflictPolicy, InputPlaylist, OutsideMusicRoot, Plan, PlanError, Planner,
+    PlaylistOutput, SkipReason,
+};
+use crate::playlist::{self, PlaylistStyle};
+use crate::probe;
+use crate::report::Report;
+use crate::resolve::{LibraryIndex, Resolver};
+use crate::rules::Rules;
+use crate::sanitize::{LengthLimits, SanitizeMode};
+use crate::scan::{self, FolderPlaylists};
+use crate::shard;
+use crate::short_names;
+use crate::sidecar::{self, SidecarOptions};
+use crate::since::{self, SinceMode};
+use crate::smart::SmartPlaylist;
+use crate::span::Span;
+use crate::template::{FilenamePattern, Template};
+use crate::transliterate::TransliterationStyle;
+use crate::warnings::{self, report_warning, WarningCategory};
+use log::{info, warn};
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, BTreeSet, HashMap, HashSet};
+use std::fmt;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant, SystemTime};
+
+/// Number of parallel `ffprobe` processes.
+const PROBE_WORKERS: usize = 4;
+
+/// Settings of a sync. The defaults match those of the command line.
+#[derive(Clone, Debug)]
+pub struct SyncOptions {
+    /// Root of the output directory.
+    pub output_dir: PathBuf,
+    /// Filesystem that the output will end up on, detected from the output directory if `None`.
+    pub target_fs: Option<TargetFs>,
+    pub on_conflict: ConflictPolicy,
+    /// Probe all sources, not only when another option needs their metadata.
+    pub probe: bool,
+    pub estimate: EstimateOptions,
+    /// Trim the plan so that the output fits into this many bytes.
+    pub max_total_size: Option<u64>,
+    /// Trim the plan so that at most this many audio files are written.
+    pub max_total_files: Option<u64>,
+    /// Cut every playlist after this many entries.
+    pub max_playlist_entries: Option<usize>,
+    /// Move the files of output directories with more than this many into numbered subfolders.
+    pub max_files_per_dir: Option<usize>,
+    /// Playlists to keep when trimming the plan, in order of preference.
+    pub priority: Vec<PathBuf>,
+    /// Glob patterns of sources to leave out.
+    pub exclude: Vec<String>,
+    /// Source extensions to consider, or `all`.
+    pub include_ext: Vec<String>,
+    pub skip_genre: Vec<String>,
+    pub skip_artist: Vec<String>,
+    pub min_rating: Option<u8>,
+    /// Leave out tracks shorter than this many seconds.
+    pub min_duration: Option<f64>,
+    /// Split sources that are longer than this many seconds or have chapters into parts.
+    pub split_long_files: Option<f64>,
+    /// Only process sources modified after this time.
+    pub since: Option<SystemTime>,
+    pub since_mode: SinceMode,
+    pub on_missing_tag: MissingTag,
+    /// Resolve entries that do not exist to files whose names differ by at most this many
+    /// characters after ignoring case and Unicode normalization, if set.
+    pub fuzzy_match: Option<usize>,
+    /// Look up entries that still do not exist by their file name in this library directory.
+    pub resolve_missing: Option<PathBuf>,
+    /// Allow the output directory to overlap with the playlist directories or sources.
+    pub allow_overlap: bool,
+    pub structure: Structure,
+    pub layout: Layout,
+    /// Derive output paths from the location of the sources below this directory.
+    pub music_root: Option<PathBuf>,
+    pub outside_music_root: OutsideMusicRoot,
+    /// Place the audio files of every playlist in a folder of its own, with this policy for
+    /// tracks in several playlists.
+    pub group_by_playlist: Option<SharedTracks>,
+    /// Prefix output file names with their position in the playlist.
+    pub prefix_position: bool,
+    pub filename_template: Option<Template>,
+    /// Set the album of every output file to the name of its playlist.
+    pub album_from_playlist: bool,
+    /// Keep the original album in the original title frame with `album_from_playlist`.
+    pub keep_original_album: bool,
+    /// Set the track number of every output file to its position in its playlist.
+    pub tracknum_from_position: bool,
+    /// Read missing tags from the file names of sources.
+    pub tags_from_filename: Option<FilenamePattern>,
+    /// Replace existing tags with those read from the file names.
+    pub tags_from_filename_force: bool,
+    /// Album artist to set on compilations, if they should be fixed.
+    pub compilation_artist: Option<String>,
+    /// Mapping of genres, applied to copied files only with `execute.fix_tags`.
+    pub genre_map: Option<GenreMap>,
+    /// Encoder settings per source extension or codec.
+    pub rules: Rules,
+    /// Keep only one source per track with these tags.
+    pub dedupe_by_tag: Option<DedupeKey>,
+    /// Keep only one source per track with fingerprints at least this similar (from 0 to 1).
+    pub dedupe_by_fingerprint: Option<f64>,
+    /// Also write a playlist per genre with at least this many tracks.
+    pub genre_playlists: Option<usize>,
+    /// Also create playlists for the folders of input directories.
+    pub per_folder_playlists: Option<FolderPlaylists>,
+    /// Root of the music library that smart playlists are filled from.
+    pub library: Option<PathBuf>,
+    pub smart_playlists: Vec<SmartPlaylist>,
+    /// Also sync playlists stored on an MPD server.
+    pub mpd: Option<MpdSource>,
+    /// Also sync playlists from Jellyfin and Plex servers.
+    pub media_servers: Vec<MediaServer>,
+    /// Prefix rules that map file paths on the media servers to local paths.
+    pub path_map: Vec<PathMapping>,
+    pub flatten: Option<usize>,
+    pub transliterate: Option<TransliterationStyle>,
+    /// Rename all outputs to DOS-compatible 8.3 names.
+    pub short_names: bool,
+    pub sanitize: SanitizeMode,
+    pub sanitize_replacement: char,
+    pub length_limits: LengthLimits,
+    /// Write a `folder.jpg` into every output directory.
+    pub folder_art: bool,
+    /// Copy the cover image next to the sources into every output directory.
+    pub copy_art: bool,
+    /// Scale copied cover images to at most this many pixels instead of copying them unchanged.
+    pub copy_art_size: Option<u32>,
+    /// Extensions of files next to the sources that are copied next to the outputs, like `lrc`.
+    pub copy_sidecars: Vec<String>,
+    /// Write a file that maps every output file to its source.
+    pub mapping_file: Option<PathBuf>,
+    /// Copy the input playlists unchanged next to the output playlists.
+    pub keep_original_playlists: bool,
+    /// Add the entries to the existing output playlists instead of replacing them.
+    pub append: bool,
+    /// Line endings and encoding of the output playlists.
+    pub playlist_style: PlaylistStyle,
+    /// Replace existing `folder.jpg` files, cover images and sidecars.
+    pub force: bool,
+    /// Remove the lock file of another run on the output directory.
+    pub force_unlock: bool,
+    /// How files are converted and copied. Its `output_dir` is replaced by the one above.
+    pub execute: ExecuteOptions,
+    /// Observers that are notified while planning and executing.
+    pub observers: Observers,
+}
+
+impl Default for SyncOptions {
+    fn default() -> Self {
+        Self {
+            output_dir: PathBuf::from("output"),
+            target_fs: None,
+            on_conflict: ConflictPolicy::default(),
+            probe: false,
+            estimate: EstimateOptions {
+                minutes_per_track: 4.0,
+            },
+            max_total_size: None,
+            max_total_files: None,
+            max_playlist_entries: None,
+            max_files_per_dir: None,
+            priority: vec![],
+            exclude: vec![],
+            include_ext: exclude::DEFAULT_EXTENSIONS
+                .split(',')
+                .map(String::from)
+                .collect(),
+            skip_genre: vec![],
+            skip_artist: vec![],
+            min_rating: None,
+            min_duration: None,
+            split_long_files: None,
+            since: None,
+            since_mode: SinceMode::PlanOnly,
+            on_missing_tag: MissingTag::Keep,
+            fuzzy_match: Some(0),
+            resolve_missing: None,
+            allow_overlap: false,
+            structure: Structure::Mirror,
+            layout: Layout::Flat,
+            music_root: None,
+            outside_music_root: OutsideMusicRoot::Error,
+            group_by_playlist: None,
+            prefix_position: false,
+            filename_template: None,
+            album_from_playlist: false,
+            keep_original_album: false,
+            tracknum_from_position: false,
+            tags_from_filename: None,
+            tags_from_filename_force: false,
+            compilation_artist: None,
+            genre_map: None,
+            rules: Rules::default(),
+            dedupe_by_tag: None,
+            dedupe_by_fingerprint: None,
+            genre_playlists: None,
+            per_folder_playlists: None,
+            library: None,
+            smart_playlists: vec![],
+            mpd: None,
+            media_servers: vec![],
+            path_map: vec![],
+            flatten: None,
+            transliterate: None,
+            short_names: false,
+            sanitize: SanitizeMode::Fat32,
+            sanitize_replacement: '_',
+            length_limits: LengthLimits {
+                max_name: 255,
+                max_path: 255,
+            },
+            folder_art: false,
+            copy_art: false,
+            copy_art_size: None,
+            copy_sidecars: vec![],
+            mapping_file: None,
+            keep_original_playlists: false,
+            append: false,
+            playlist_style: PlaylistStyle::default(),
+            force: false,
+            force_unlock: false,
+            execute: ExecuteOptions {
+                verify_tolerance: 2.0,
+                preserve_times: true,
+                art_size: 500,
+                cover_names: art::SIDECAR_NAMES.map(String::from).to_vec(),
+                max_write_errors: 3,
+                ..Default::default()
+            },
+            observers: Observers::default(),
+        }
+    }
+}
+
+/// Returns the paths of the outputs and playlists of the plan, and of the copies of the original
+/// playlists with `keep_original_playlists`, whether they were written or not.
+fn created_files(plan: &Plan, keep_original_playlists: bool) -> Vec<PathBuf> {
+    let mut created: Vec<PathBuf> = plan
+        .tasks
+        .iter()
+        .map(|task| task.destination.clone())
+        .collect();
+    for playlist in plan.playlists.iter() {
+        created.push(playlist.path.clone());
+        if keep_original_playlists {
+            created.push(mapping::original_playlist_path(
+                &playlist.path,
+                &playlist.source,
+            ));
+        }
+    }
+    created
+}
+
+/// Returns the paths of the outputs of the plan that were written according to `results`.
+///
+/// Outputs that were skipped, e.g. because they already existed, are left out, so that files
+/// the sync did not write never end up in the manifest.
+fn written_outputs(plan: &Plan, results: &[TaskResult]) -> Vec<PathBuf> {
+    plan.tasks
+        .iter()
+        .zip(results)
+        .filter(|(_, result)| result.wrote_output())
+        .map(|(task, _)| task.destination.clone())
+        .collect()
+}
+
+/// Returns the files that earlier syncs created in the output directory and that the plan no
+/// longer produces, with their sizes, for `--prune`. `keep` are other files to leave alone, like
+/// reports and logs.
+///
+/// Files next to the planned outputs that are neither audio files nor playlists, like album art
+/// and sidecars, are kept as well, since the sync writes them again.
+pub fn stale_files(
+    plan: &Plan,
+    options: &SyncOptions,
+    manifest: &Manifest,
+    keep: &[&Path],
+) -> Vec<(PathBuf, u64)> {
+    let planned: HashSet<PathBuf> = created_files(plan, options.keep_original_playlists)
+        .into_iter()
+        .collect();
+    // The other files may be given relative to another directory.
+    let kept: HashSet<PathBuf> = keep
+        .iter()
+        .copied()
+        .chain(options.mapping_file.as_deref())
+        .map(overlap::canonicalize_lenient)
+        .collect();
+    let planned_dirs: HashSet<&Path> = plan
+        .tasks
+        .iter()
+        .filter_map(|task| task.destination.parent())
+        .collect();
+    let planned_extensions: HashSet<String> = plan
+        .tasks
+        .iter()
+        .map(|task| &task.destination)
+        .chain(plan.playlists.iter().map(|playlist| &playlist.path))
+        .filter_map(|path| path.extension())
+        .map(|extension| extension.to_string_lossy().to_lowercase())
+        .collect();
+    manifest
+        .existing_files(&options.output_dir)
+        .into_iter()
+        .filter(|(path, _)| {
+            !planned.contains(path) && !kept.contains(&overlap::canonicalize_lenient(path))
+        })
+        .filter(|(path, _)| {
+            let is_companion = path.parent().is_some_and(|dir| planned_dirs.contains(dir))
+                && !path.extension().is_some_and(|extension| {
+                    planned_extensions.contains(&extension.to_string_lossy().to_lowercase())
+                });
+            !is_companion
+        })
+        .collect()
+}
+
+/// Error that prevents a sync from being planned or started.
+#[derive(Debug)]
+pub enum SyncError {
+    /// A playlist could not be read or planned.
+    Playlist(PlanError),
+    /// The playlists could not be read from the MPD server.
+    Mpd(MpdError),
+    /// The playlists could not be read from a Jellyfin or Plex server.
+    MediaServer(MediaServerError),
+    /// The output directory overlaps with the inputs.
+    Overlap(OverlapError),
+    /// FFmpeg is missing.
+    Execute(ExecuteError),
+    /// The output directory could not be created.
+    OutputDir {
+        path: PathBuf,
+        error: std::io::Error,
+    },
+    /// Another run holds the lock on the output directory.
+    Lock(LockError),
+    /// An output playlist could not be written.
+    WritePlaylist {
+        path: PathBuf,
+        error: std::io::Error,
+    },
+}
+
+impl fmt::Display for SyncError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            SyncError::Playlist(e) => write!(f, "{}", e),
+            SyncError::Mpd(e) => write!(f, "{}", e),
+            SyncError::MediaServer(e) => write!(f, "{}", e),
+            SyncError::Overlap(e) => write!(f, "{}", e),
+            SyncError::Execute(e) => write!(f, "{}", e),
+            SyncError::OutputDir { path, error } => write!(
+                f,
+                "{}: Failed to create output directory ({})",
+                path.display(),
+                error
+            ),
+            SyncError::Lock(e) => write!(f, "{}", e),
+            SyncError::WritePlaylist { path, error } => {
+                write!(
+                    f,
+                    "{}: Failed to write playlist ({})",
+                    path.display(),
+                    error
+                )
+            }
+        }
+    }
+}
+
+impl std::error::Error for SyncError {}
+
+/// The variant of a [`SyncError`], without details.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum SyncErrorKind {
+    /// An input playlist could not be read, or is in none of the supported formats.
+    PlaylistParse,
+    /// Two sources map to the same output path.
+    CollisionDetected,
+    ScanDirectory,
+    OutsideMusicRoot,
+    /// The playlists could not be read from an MPD, Jellyfin or Plex server.
+    Server,
+    Overlap,
+    TranscoderUnavailable,
+    OutputNotWritable,
+    OutputDirectory,
+    Lock,
+    WritePlaylist,
+}
+
+impl SyncError {
+    pub fn kind(&self) -> SyncErrorKind {
+        match self {
+            SyncError::Playlist(
+                PlanError::ReadPlaylist { .. } | PlanError::UnsupportedPlaylist { .. },
+            ) => SyncErrorKind::PlaylistParse,
+            SyncError::Playlist(PlanError::Collision { .. }) => SyncErrorKind::CollisionDetected,
+            SyncError::Playlist(PlanError::ScanDirectory { .. }) => SyncErrorKind::ScanDirectory,
+            SyncError::Playlist(PlanError::OutsideMusicRoot { .. }) => {
+                SyncErrorKind::OutsideMusicRoot
+            }
+            SyncError::Mpd(_) | SyncError::MediaServer(_) => SyncErrorKind::Server,
+            SyncError::Overlap(_) => SyncErrorKind::Overlap,
+            SyncError::Execute(ExecuteError::FfmpegMissing(_)) => {
+                SyncErrorKind::TranscoderUnavailable
+            }
+            SyncError::Execute(ExecuteError::OutputNotWritable { .. }) => {
+                SyncErrorKind::OutputNotWritable
+            }
+            SyncError::OutputDir { .. } => SyncErrorKind::OutputDirectory,
+            SyncError::Lock(_) => SyncErrorKind::Lock,
+            SyncError::WritePlaylist { .. } => SyncErrorKind::WritePlaylist,
+        }
+    }
+}
+
+/// Determine the filesystem of the output directory.
+pub fn target_fs(options: &SyncOptions) -> TargetFs {
+    if let Some(target_fs) = options.target_fs {
+        info!("Target filesystem: {} (from `--target-fs`)", target_fs);
+        return target_fs;
+    }
+    let Some((target_fs, mount)) = TargetFs::detect(&options.output_dir) else {
+        warn!(
+            "{}: Failed to detect the filesystem, assuming FAT32 (use `--target-fs` to override)",
+            options.output_dir.display()
+        );
+        return TargetFs::Fat32;
+    };
+    info!(
+        "Target filesystem: {} ({} on {}, mounted at {})",
+        target_fs,
+        mount.fs_type,
+        mount.device,
+        mount.mount_point.display()
+    );
+    if !target_fs.is_supported_by_sync() {
+        report_warning!(
+            WarningCategory::Filesystem,
+            "{}: Sync 2 only reads FAT32 and exFAT media, but the output is on {} (use \
+             `--target-fs` if this is a staging directory)",
+            options.output_dir.display(),
+            mount.fs_type
+        );
+    }
+    target_fs
+}
+
+/// Resolve the playlists into a plan.
+pub fn plan(options: &SyncOptions, playlists: &[PathBuf]) -> Result<Plan, SyncError> {
+    let _warnings = warnings::forward_to(&options.observers);
+    let extensions = Extensions::new(&options.include_ext);
+    // The servers are asked first, so that one being unreachable stops the run before any scans.
+    let mut server_playlists = match &options.mpd {
+        Some(source) => mpd::read_playlists(source).map_err(SyncError::Mpd)?,
+        None => vec![],
+    };
+    for server in options.media_servers.iter() {
+        server_playlists.extend(
+            server
+                .read_playlists(&options.path_map)
+                .map_err(SyncError::MediaServer)?,
+        );
+    }
+    let mut input_playlists = playlists
+        .iter()
+        .map(|path| {
+            let _span = Span::playlist(path).enter();
+            if path.is_dir() {
+                scan::scan(path, &extensions, options.per_folder_playlists)
+            } else {
+                InputPlaylist::read(path).map(|playlist| vec![playlist])
+            }
+        })
+        .collect::<Result<Vec<_>, _>>()
+        .map_err(SyncError::Playlist)?
+        .into_iter()
+        .flatten()
+        .chain(server_playlists)
+        .collect::<Vec<_>>();
+    if let Some(max_distance) = options.fuzzy_match {
+        let mut resolver = Resolver::new(max_distance);
+        for playlist in input_playlists.iter_mut() {
+            let _span = Span::playlist(&playlist.path).enter();
+            resolver.fix_entries(playlist);
+        }
+    }
+
+    // Metadata of the library, which is probed anyway to fill the smart playlists.
+    let mut metadata = HashMap::new();
+    if let Some(library) = options.resolve_missing.as_ref() {
+        let _span = Span::playlist(library).enter();
+        let index = LibraryIndex::build(library, &extensions, PROBE_WORKERS).map_err(|error| {
+            SyncError::Playlist(PlanError::ScanDirectory {
+                path: library.clone(),
+                error,
+            })
+        })?;
+        for playlist in input_playlists.iter_mut() {
+            let _span = Span::playlist(&playlist.path).enter();
+            index.fix_entries(playlist, &mut metadata, PROBE_WORKERS);
+        }
+    }
+    if let Some(library) = options
+        .library
+        .as_ref()
+        .filter(|_| !options.smart_playlists.is_empty())
+    {
+        let _span = Span::playlist(library).enter();
+        let files = scan::audio_files(library, &extensions).map_err(|error| {
+            SyncError::Playlist(PlanError::ScanDirectory {
+                path: library.clone(),
+                error,
+            })
+        })?;
+        metadata.extend(probe::probe_all(
+            files
+                .iter()
+                .map(|file| library.join(file))
+                .filter(|file| !metadata.contains_key(file))
+                .collect(),
+            PROBE_WORKERS,
+        ));
+        input_playlists.extend(
+            options
+                .smart_playlists
+                .iter()
+                .map(|playlist| playlist.generate(library, &files, &metadata)),
+        );
+    }
+
+    if !options.allow_overlap {
+        overlap::check(&options.output_dir, &input_playlists).map_err(SyncError::Overlap)?;
+    }
+
+    let excludes = Excludes::new(options.exclude.clone());
+    let tag_filter = TagFilter::new(
+        &options.skip_genre,
+        &options.skip_artist,
+        options.min_rating,
+        options.on_missing_tag,
+    );
+
+    let target_fs = target_fs(options);
+
+    let mut planner = Planner::new(&options.output_dir, options.on_conflict);
+    planner.set_max_file_size(target_fs.max_file_size());
+    let sanitize = match options.sanitize {
+        SanitizeMode::On => true,
+        SanitizeMode::Off => false,
+        SanitizeMode::Fat32 => target_fs.is_fat(),
+    };
+    planner.set_layout(options.layout);
+    planner.set_structure(options.structure);
+    planner.set_music_root(options.music_root.as_deref(), options.outside_music_root);
+    planner.set_group_by_playlist(options.group_by_playlist);
+    planner.set_prefix_position(options.prefix_position);
+    planner.set_filename_template(options.filename_template.clone());
+    planner.set_flatten(options.flatten);
+    planner.set_album_from_playlist(options.album_from_playlist, options.keep_original_album);
+    planner.set_tracknum_from_position(options.tracknum_from_position);
+    planner.set_tags_from_filename(
+        options.tags_from_filename.clone(),
+        options.tags_from_filename_force,
+    );
+    planner.set_compilation_artist(options.compilation_artist.clone());
+    planner.set_genre_map(options.genre_map.clone(), options.execute.fix_tags);
+    planner.set_rules(options.rules.clone());
+    planner.set_transliterate(options.transliterate);
+    planner.set_sanitize(sanitize.then_some(options.sanitize_replacement));
+    planner.set_length_limits(Some(options.length_limits));
+    if options.probe
+        || options.structure != Structure::Mirror
+        || options.filename_template.is_some()
+        || (options.album_from_playlist && options.keep_original_album)
+        || options.tags_from_filename.is_some()
+        || options.compilation_artist.is_some()
+        || options.genre_map.is_some()
+        || !options.rules.is_empty()
+        || options.dedupe_by_tag.is_some()
+        || options.dedupe_by_fingerprint.is_some()
+        || options.genre_playlists.is_some()
+        || options.execute.embed_art
+        || tag_filter.is_active()
+        || options.min_duration.is_some()
+        || options.split_long_files.is_some()
+        || options.short_names
+    {
+        let sources: BTreeSet<_> = input_playlists
+            .iter()
+            .flat_map(InputPlaylist::sources)
+            .filter(|source| excludes.matching(source).is_none() && extensions.includes(source))
+            .filter(|source| !metadata.contains_key(source))
+            .collect();
+        metadata.extend(probe::probe_all(
+            sources.into_iter().collect(),
+            PROBE_WORKERS,
+        ));
+    }
+    if !metadata.is_empty() {
+        planner.set_metadata(metadata);
+    }
+    planner.set_excludes(excludes);
+    planner.set_extensions(extensions);
+    planner.set_tag_filter(tag_filter);
+    planner.set_min_duration(options.min_duration);
+    planner.set_split_long_files(options.split_long_files);
+    for input_playlist in input_playlists.iter() {
+        let _span = Span::playlist(&input_playlist.path).enter();
+        planner
+            .add_playlist(input_playlist)
+            .map_err(SyncError::Playlist)?;
+    }
+    if let Some(key) = options.dedupe_by_tag {
+        dedupe::dedupe(planner.plan_mut(), key);
+    }
+    if let Some(threshold) = options.dedupe_by_fingerprint {
+        let plan = planner.plan_mut();
+        let sources = plan.tasks.iter().map(|task| task.source.clone()).collect();
+        fingerprint::fingerprint_all(&mut plan.metadata, sources, PROBE_WORKERS);
+        dedupe::dedupe_by_fingerprint(plan, threshold);
+    }
+    if let Some(min_tracks) = options.genre_playlists {
+        planner.add_genre_playlists(min_tracks);
+    }
+    let mut plan = planner.finish();
+
+    if let (Some(cutoff), SinceMode::PlaylistsToo) = (options.since, options.since_mode) {
+        since::remove_older(&mut plan, cutoff);
+    }
+    if let Some(max_entries) = options.max_playlist_entries {
+        limits::truncate_playlists(&mut plan, max_entries);
+    }
+    let priority = budget::priority_order(&plan, &options.priority);
+    if let Some(max_total_files) = options.max_total_files {
+        budget::trim(
+            &mut plan,
+            &priority,
+            max_total_files,
+            |_, _| 1,
+            SkipReason::OverFileBudget,
+        );
+    }
+    if let Some(max_total_size) = options.max_total_size {
+        budget::trim(
+            &mut plan,
+            &priority,
+            max_total_size,
+            |plan, task| estimate::estimate_task(plan, task, &options.estimate),
+            SkipReason::OverSizeBudget,
+        );
+    }
+    if options.short_names {
+        short_names::shorten(&mut plan, &options.output_dir);
+    }
+    // Shortened names first, so that the subfolders of earlier runs are found by the names on
+    // disk.
+    if let Some(max_files) = options.max_files_per_dir {
+        shard::shard(&mut plan, &options.output_dir, max_files);
+    }
+    options.observers.plan_finished(&plan);
+    Ok(plan)
+}
+
+/// Check that FFmpeg can be run if the plan needs it.
+pub fn check_ffmpeg(plan: &Plan, options: &SyncOptions) -> Result<(), SyncError> {
+    let uses_ffmpeg = plan.tasks.iter().any(|task| task.action != Action::Copy);
+    if uses_ffmpeg || options.execute.embed_art {
+        execute::check_ffmpeg(&options.execute).map_err(SyncError::Execute)?;
+    }
+    Ok(())
+}
+
+/// Returns the entries of the existing output playlist for `--append`, or why it cannot be read.
+fn existing_entries(
+    playlist: &PlaylistOutput,
+    style: PlaylistStyle,
+) -> Result<Vec<String>, String> {
+    match std::fs::read(longpath::extended(&playlist.path)) {
+        Ok(contents) => playlist::read_output(&contents, style.encoding),
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(vec![]),
+        Err(e) => Err(e.to_string()),
+    }
+}
+
+/// Write an output playlist atomically, after the `existing` entries that are kept.
+fn write_playlist(
+    playlist: &PlaylistOutput,
+    existing: &[String],
+    style: PlaylistStyle,
+    sync_writes: bool,
+) -> std::io::Result<()> {
+    let path = longpath::extended(&playlist.path);
+    let temp_path = atomic::temp_path(&path);
+    let mut seen: HashSet<&str> = existing.iter().map(String::as_str).collect();
+    let added: Vec<&str> = playlist
+        .entries
+        .iter()
+        .map(|entry| entry.target.as_str())
+        .filter(|target| seen.insert(target))
+        .collect();
+    if !existing.is_empty() {
+        info!(
+            "{}: Appending {} entries to {} existing ones",
+            playlist.path.display(),
+            added.len(),
+            existing.len()
+        );
+    }
+    let (contents, unrepresentable) =
+        style.render(existing.iter().map(String::as_str).chain(added));
+    for entry in unrepresentable {
+        report_warning!(
+            WarningCategory::PlaylistEncoding,
+            "{}: Entry {} cannot be written in the playlist encoding (use `--transliterate`)",
+            playlist.path.display(),
+            entry
+        );
+    }
+    std::fs::write(&temp_path, contents)?;
+    atomic::commit(&temp_path, &path, sync_writes)
+}
+
+/// Returns the total size of all output files of the plan that exist on disk.
+fn written_size(plan: &Plan) -> u64 {
+    let destinations: BTreeSet<_> = plan.tasks.iter().map(|task| &task.destination).collect();
+    destinations
+        .into_iter()
+        .filter_map(|destination| std::fs::metadata(destination).ok())
+        .map(|metadata| metadata.len())
+        .sum()
+}
+
+/// Executes plans.
+pub struct Executor {
+    options: SyncOptions,
+    execute_options: ExecuteOptions,
+    settings: BTreeMap<String, serde_json::Value>,
+    timings: Vec<(&'static str, Duration)>,
+}
+
+impl Executor {
+    pub fn new(options: SyncOptions) -> Self {
+        let execute_options = ExecuteOptions {
+            output_dir: options.output_dir.clone(),
+            ..options.execute.clone()
+        };
+        Self {
+            options,
+            execute_options,
+            settings: BTreeMap::new(),
+            timings: vec![],
+        }
+    }
+
+    /// Settings to list in the report.
+    pub fn set_settings(&mut self, settings: BTreeMap<String, serde_json::Value>) {
+        self.settings = settings;
+    }
+
+    /// Timings of earlier phases (e.g. planning) to list in the report.
+    pub fn set_timings(&mut self, timings: Vec<(&'static str, Duration)>) {
+        self.timings = timings;
+    }
+
+    /// The options used for converting and copying files.
+    pub fn execute_options(&self) -> &ExecuteOptions {
+        &self.execute_options
+    }
+
+    /// Create and lock the output directory and write the output playlists with all entries,
+    /// for scripts that run the tasks later.
+    ///
+    /// The lock is held until the returned guard is dropped.
+    pub fn prepare(&mut self, plan: &Plan) -> Result<OutputLock, SyncError> {
+        let lock = self.lock_output()?;
+        self.write_playlists(plan, None, &mut Vec::new())?;
+        Ok(lock)
+    }
+
+    /// Create and lock the output directory.
+    fn lock_output(&self) -> Result<OutputLock, SyncError> {
+        let output_dir = &self.options.output_dir;
+        std::fs::create_dir_all(longpath::extended(output_dir)).map_err(|error| {
+            SyncError::OutputDir {
+                path: output_dir.clone(),
+                error,
+            }
+        })?;
+        let lock =
+            OutputLock::acquire(output_dir, self.options.force_unlock).map_err(SyncError::Lock)?;
+        atomic::remove_stale(&longpath::extended(output_dir));
+        Ok(lock)
+    }
+
+    /// Write the output playlists.
+    ///
+    /// Given the task results of a run, the playlists only list the entries whose output was
+    /// written or already existed, so that an interrupted or failed run leaves no entries behind
+    /// that the head unit cannot play. The paths of the written playlists, and of the kept
+    /// originals, are added to `written`.
+    fn write_playlists(
+        &mut self,
+        plan: &Plan,
+        results: Option<&[TaskResult]>,
+        written: &mut Vec<PathBuf>,
+    ) -> Result<(), SyncError> {
+        let output_dir = &self.options.output_dir;
+        let phase_started = Instant::now();
+        let is_written = |task: usize| {
+            results.is_none_or(|results| {
+                results
+                    .get(task)
+                    .is_some_and(|result| result.status == TaskStatus::Succeeded)
+                    || longpath::extended(&plan.tasks[task].destination).exists()
+            })
+        };
+
+        for playlist in plan.playlists.iter() {
+            let existing = if self.options.append {
+                match existing_entries(playlist, self.options.playlist_style) {
+                    Ok(existing) => existing,
+                    Err(reason) => {
+                        report_warning!(
+                            WarningCategory::ExistingPlaylist,
+                            "{}: Leaving existing playlist unchanged instead of appending ({})",
+                            playlist.path.display(),
+                            reason
+                        );
+                        continue;
+                    }
+                }
+            } else {
+                vec![]
+            };
+            let (entries, unwritten): (Vec<_>, Vec<_>) = playlist
+                .entries
+                .iter()
+                .cloned()
+                .partition(|entry| is_written(entry.task));
+            if !unwritten.is_empty() {
+                info!(
+                    "{}: Leaving out {} entries whose output was not written",
+                    playlist.path.display(),
+                    unwritten.len()
+                );
+            }
+            let playlist = PlaylistOutput {
+                entries,
+                ..playlist.clone()
+            };
+            write_playlist(
+                &playlist,
+                &existing,
+                self.options.playlist_style,
+                self.execute_options.sync_writes,
+            )
+            .map_err(|error| SyncError::WritePlaylist {
+                path: playlist.path.clone(),
+                error,
+            })?;
+            info!("Wrote Playlist: {}", playlist.path.display());
+            written.push(playlist.path.clone());
+        }
+        if self.options.keep_original_playlists {
+            if let Err(e) =
+                mapping::keep_original_playlists(plan, self.execute_options.sync_writes, written)
+            {
+                report_warning!(
+                    WarningCategory::Io,
+                    "{}: Failed to keep original playlists ({})",
+                    output_dir.display(),
+                    e
+                );
+            }
+        }
+        self.timings
+            .push(("Writing playlists", phase_started.elapsed()));
+        Ok(())
+    }
+
+    /// Add the files written by this run to the manifest of the output directory.
+    fn record_created(&self, created: &[PathBuf]) {
+        let output_dir = &self.options.output_dir;
+        let paths = created.iter().map(PathBuf::as_path);
+        if let Err(e) = manifest::record(output_dir, paths, self.execute_options.sync_writes) {
+            report_warning!(
+                WarningCategory::Io,
+                "{}: Failed to update manifest ({})",
+                output_dir.display(),
+                e
+            );
+        }
+    }
+
+    /// Execute the plan and return the report of the run.
+    ///
+    /// If the run had to be stopped early, e.g. because the output directory became read-only,
+    /// the report's `error` says why.
+    pub fn run(mut self, plan: &Plan) -> Result<Report, SyncError> {
+        let _warnings = warnings::forward_to(&self.options.observers);
+        check_ffmpeg(plan, &self.options)?;
+        let lock = self.lock_output()?;
+        let phase_started = Instant::now();
+        let mut execution = execute::execute(plan, &self.execute_options, &self.options.observers);
+        self.timings
+            .push(("Converting and copying", phase_started.elapsed()));
+        let mut created = written_outputs(plan, &execution.results);
+        if let Some(e) = execution.error.take() {
+            // The playlists still list what was written before the run was stopped, if the
+            // output directory can be written to at all.
+            if let Err(e) = self.write_playlists(plan, Some(&execution.results), &mut created) {
+                report_warning!(WarningCategory::Io, "{}", e);
+            }
+            self.record_created(&created);
+            let report = Report::new(
+                plan,
+                &execution.results,
+                &self.options.estimate,
+                self.settings,
+                &self.timings,
+                Some(&SyncError::Execute(e)),
+            );
+            self.options.observers.run_finished(&report);
+            return Ok(report);
+        }
+
+        let playlists = self.write_playlists(plan, Some(&execution.results), &mut created);
+        if let Err(e) = playlists {
+            self.record_created(&created);
+            return Err(e);
+        }
+
+        if self.options.folder_art && !interrupt::is_interrupted() {
+            let phase_started = Instant::now();
+            created.extend(art::write_folder_art(
+                plan,
+                &FolderArtOptions {
+                    max_size: self.execute_options.art_size,
+                    force: self.options.force,
+                    sidecar_names: self.execute_options.cover_names.clone(),
+                },
+            ));
+            self.timings.push(("Album art", phase_started.elapsed()));
+        }
+
+        if self.options.copy_art && !interrupt::is_interrupted() {
+            let phase_started = Instant::now();
+            created.extend(art::copy_art(
+                plan,
+                &CopyArtOptions {
+                    max_size: self.options.copy_art_size,
+                    force: self.options.force,
+                    sidecar_names: self.execute_options.cover_names.clone(),
+                },
+            ));
+            self.timings
+                .push(("Copying album art", phase_started.elapsed()));
+        }
+
+        if !self.options.copy_sidecars.is_empty() && !interrupt::is_interrupted() {
+            let phase_started = Instant::now();
+            created.extend(sidecar::copy_sidecars(
+                plan,
+                &SidecarOptions {
+                    extensions: self.options.copy_sidecars.clone(),
+                    force: self.options.force,
+                    preserve_times: self.execute_options.preserve_times,
+                    sync_writes: self.execute_options.sync_writes,
+                },
+            ));
+            self.timings.push(("Sidecars", phase_started.elapsed()));
+        }
+
+        if let Some(path) = &self.options.mapping_file {
+            match mapping::write(path, plan, &self.options.output_dir, &self.settings) {
+                Ok(()) => created.push(path.clone()),
+                Err(e) => report_warning!(
+                    WarningCategory::Io,
+                    "{}: Failed to write mapping file ({})",
+                    path.display(),
+                    e
+                ),
+            }
+        }
+        self.record_created(&created);
+
+        if let Some(max_total_size) = self.options.max_total_size {
+            let written = written_size(plan);
+            if written > max_total_size {
+                report_warning!(
+                    WarningCategory::Space,
+                    "Wrote {} which exceeds `--max-total-size` of {}",
+                    ByteSize(written),
+                    ByteSize(max_total_size)
+                );
+            }
+        }
+
+        if self.execute_options.sync_writes {
+            let phase_started = Instant::now();
+            info!("Flushing writes to disk...");
+            if let Err(e) = atomic::sync_filesystem(&self.options.output_dir) {
+                report_warning!(
+                    WarningCategory::Io,
+                    "{}: Failed to flush filesystem ({})",
+                    self.options.output_dir.display(),
+                    e
+                );
+            }
+            self.timings.push(("Final flush", phase_started.elapsed()));
+        }
+        drop(lock);
+
+        let report = Report::new(
+            plan,
+            &execution.results,
+            &self.options.estimate,
+            self.settings,
+            &self.timings,
+            None,
+        );
+        self.options.observers.run_finished(&report);
+        Ok(report)
+    }
+}
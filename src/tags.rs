@@ -0,0 +1,733 @@
+// Copyright (c) 2024 Jan Holthuis <jan.holthuis@rub.de>
+//
+// This Source Code Form is subject to the terms of the Mozilla Public License, v. 2.0. If a copy
+// of the MPL was not distributed with this file, You can obtain one at
+// http://mozilla.org/MPL/2.0/.
+//
+// SPDX-License-Identifier: MPL-2.0
+//! Rewriting of ID3 tags in output MP3s.
+use crate::transliterate;
+use clap::ValueEnum;
+use id3::frame::{Content, ExtendedText, Picture, PictureType};
+use id3::v1v2::{self, FormatVersion};
+use id3::{Encoding, ErrorKind, Frame, TagLike, Version};
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::path::Path;
+
+/// Frames to remove from output MP3s.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct StripFrames {
+    /// IDs of frames to remove entirely.
+    pub ids: Vec<String>,
+    /// Remove all attached pictures but the first.
+    pub extra_pictures: bool,
+}
+
+impl StripFrames {
+    /// Returns the FFmpeg metadata keys that are written as one of the stripped frames.
+    pub fn ffmpeg_metadata_keys(&self) -> impl Iterator<Item = &'static str> + '_ {
+        self.ids.iter().filter_map(|id| match id.as_str() {
+            "USLT" => Some("lyrics"),
+            "COMM" => Some("comment"),
+            _ => None,
+        })
+    }
+}
+
+/// Parse a comma-separated list of frame IDs like `PRIV,GEOB,APIC+`, where `APIC+` stands for
+/// all attached pictures but the first.
+pub fn parse_strip_frames(value: &str) -> Result<StripFrames, String> {
+    let mut strip = StripFrames::default();
+    for id in value.split(',').map(str::trim).filter(|id| !id.is_empty()) {
+        if id == "APIC+" {
+            strip.extra_pictures = true;
+        } else if id.len() == 4
+            && id
+                .chars()
+                .all(|c| c.is_ascii_uppercase() || c.is_ascii_digit())
+        {
+            strip.ids.push(id.to_string());
+        } else {
+            return Err(format!("`{}` is not an ID3v2 frame ID", id));
+        }
+    }
+    Ok(strip)
+}
+
+/// Text frames that are shown in the browse menus, which are truncated to the maximum tag length
+/// and transliterated.
+const DISPLAYED_FRAMES: [&str; 3] = ["TIT2", "TPE1", "TALB"];
+
+/// Shorten `text` to at most `max_len` characters at a word boundary, ending it with an ellipsis.
+///
+/// Returns `None` if the text is short enough.
+pub fn truncate_text(text: &str, max_len: usize) -> Option<String> {
+    if text.chars().count() <= max_len {
+        return None;
+    }
+    let cut: String = text.chars().take(max_len.saturating_sub(1)).collect();
+    let cut = match cut.rfind(char::is_whitespace) {
+        Some(index) if index > 0 => &cut[..index],
+        _ => &cut,
+    };
+    Some(format!("{}…", cut.trim_end()))
+}
+
+/// A text frame that was shortened by [`rewrite`].
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Truncation {
+    pub frame: &'static str,
+    pub before: String,
+    pub after: String,
+}
+
+/// Changes made by [`rewrite`].
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct Rewrite {
+    /// The tags were rewritten as ID3v2.3.
+    pub version_fixed: bool,
+    /// At least one frame was removed.
+    pub frames_stripped: bool,
+    /// Number of bytes by which the file shrunk.
+    pub bytes_saved: u64,
+    /// Text frames that were shortened.
+    pub truncations: Vec<Truncation>,
+}
+
+/// Rewrite the tags of an MP3 file in place. The audio data is left untouched.
+///
+/// If `fix_version` is set, ID3v2.4 (or ID3v2.2) and ID3v1-only tags, which Sync 2 cannot read,
+/// are rewritten as ID3v2.3. If `strip` is given, the listed frames are removed. If
+/// `max_text_len` is given, the title, artist and album are truncated to that many characters.
+pub fn rewrite(
+    path: &Path,
+    fix_version: bool,
+    strip: Option<&StripFrames>,
+    max_text_len: Option<usize>,
+) -> id3::Result<Rewrite> {
+    let format = v1v2::is_candidate_path(path)?;
+    let mut tag = match v1v2::read_from_path(path) {
+        Ok(tag) => tag,
+        Err(id3::Error {
+            kind: ErrorKind::NoTag,
+            ..
+        }) => return Ok(Rewrite::default()),
+        Err(e) => return Err(e),
+    };
+    if tag.frames().next().is_none() {
+        return Ok(Rewrite::default());
+    }
+
+    let version_fixed = fix_version
+        && match format {
+            FormatVersion::None => false,
+            FormatVersion::Id3v1 => true,
+            FormatVersion::Id3v2 | FormatVersion::Both => tag.version() != Version::Id3v23,
+        };
+    let mut frames_stripped = false;
+    if let Some(strip) = strip.filter(|_| format != FormatVersion::Id3v1) {
+        for id in strip.ids.iter() {
+            frames_stripped |= !tag.remove(id).is_empty();
+        }
+        if strip.extra_pictures {
+            let pictures: Vec<Frame> = tag.remove("APIC");
+            frames_stripped |= pictures.len() > 1;
+            if let Some(first) = pictures.into_iter().next() {
+                tag.add_frame(first);
+            }
+        }
+    }
+    let mut truncations = vec![];
+    if let Some(max_len) = max_text_len.filter(|_| format != FormatVersion::Id3v1 || version_fixed)
+    {
+        for frame in DISPLAYED_FRAMES {
+            let Some(before) = tag.get(frame).and_then(|f| f.content().text()) else {
+                continue;
+            };
+            if let Some(after) = truncate_text(before, max_len) {
+                truncations.push(Truncation {
+                    frame,
+                    before: before.to_string(),
+                    after,
+                });
+            }
+        }
+        for truncation in truncations.iter() {
+            tag.set_text(truncation.frame, truncation.after.clone());
+        }
+    }
+    if !version_fixed && !frames_stripped && truncations.is_empty() {
+        return Ok(Rewrite::default());
+    }
+
+    let size_before = std::fs::metadata(path)?.len();
+    if version_fixed {
+        v1v2::write_to_path(path, &tag, Version::Id3v23)?;
+    } else {
+        tag.write_to_path(path, tag.version())?;
+    }
+    let size_after = std::fs::metadata(path)?.len();
+    Ok(Rewrite {
+        version_fixed,
+        frames_stripped,
+        bytes_saved: size_before.saturating_sub(size_after),
+        truncations,
+    })
+}
+
+/// How `--transliterate-tags` romanizes the title, artist and album.
+#[derive(ValueEnum, Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum TagTransliteration {
+    /// Replace the text, keeping the original in a user-defined text frame (`TXXX`) like
+    /// `ORIGINAL TITLE`.
+    #[default]
+    Replace,
+    /// Append the original text in brackets, e.g. `Kino [Кино]`.
+    Append,
+}
+
+/// Returns the description of the `TXXX` frame that keeps the original text of a transliterated
+/// frame.
+fn original_description(frame: &str) -> &'static str {
+    match frame {
+        "TIT2" => "ORIGINAL TITLE",
+        "TPE1" => "ORIGINAL ARTIST",
+        _ => "ORIGINAL ALBUM",
+    }
+}
+
+/// Romanize non-Latin text in the title, artist and album of an MP3 file, see
+/// [`transliterate::romanize_text`].
+///
+/// Returns the IDs of the frames that were changed.
+pub fn transliterate(path: &Path, style: TagTransliteration) -> id3::Result<Vec<&'static str>> {
+    let mut tag = match id3::Tag::read_from_path(path) {
+        Ok(tag) => tag,
+        Err(id3::Error {
+            kind: ErrorKind::NoTag,
+            ..
+        }) => return Ok(vec![]),
+        Err(e) => return Err(e),
+    };
+    let mut changed = vec![];
+    for frame in DISPLAYED_FRAMES {
+        let Some(original) = tag.get(frame).and_then(|f| f.content().text()) else {
+            continue;
+        };
+        let Some(romanized) = transliterate::romanize_text(original) else {
+            continue;
+        };
+        let original = original.to_string();
+        match style {
+            TagTransliteration::Replace => {
+                tag.set_text(frame, romanized);
+                tag.add_frame(ExtendedText {
+                    description: original_description(frame).to_string(),
+                    value: original,
+                });
+            }
+            TagTransliteration::Append => {
+                tag.set_text(frame, format!("{} [{}]", romanized, original));
+            }
+        }
+        changed.push(frame);
+    }
+    if !changed.is_empty() {
+        tag.write_to_path(path, tag.version())?;
+    }
+    Ok(changed)
+}
+
+/// Tags that an output file gets instead of those of its source.
+#[derive(Clone, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct TagOverrides {
+    /// Text frames to set, keyed by their ID, like `TALB` for the album. An empty value removes
+    /// the frame.
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub frames: BTreeMap<String, String>,
+}
+
+impl TagOverrides {
+    pub fn is_empty(&self) -> bool {
+        self.frames.is_empty()
+    }
+
+    /// Returns the value of an overridden frame.
+    pub fn get(&self, id: &str) -> Option<&str> {
+        self.frames.get(id).map(String::as_str)
+    }
+
+    pub fn set(&mut self, id: &str, value: impl Into<String>) {
+        self.frames.insert(id.to_string(), value.into());
+    }
+
+    /// Returns the FFmpeg metadata that sets the overridden frames that FFmpeg can write.
+    pub fn ffmpeg_metadata(&self) -> impl Iterator<Item = (&str, &str)> {
+        self.frames
+            .iter()
+            .filter_map(|(id, value)| Some((ffmpeg_key(id)?, value.as_str())))
+    }
+
+    /// Returns the overridden frames that FFmpeg cannot write, which have to be written into
+    /// converted files afterwards.
+    pub fn without_ffmpeg_metadata(&self) -> TagOverrides {
+        TagOverrides {
+            frames: self
+                .frames
+                .iter()
+                .filter(|(id, _)| ffmpeg_key(id).is_none())
+                .map(|(id, value)| (id.clone(), value.clone()))
+                .collect(),
+        }
+    }
+}
+
+/// Returns the FFmpeg metadata key that FFmpeg writes as the frame, which is the ID itself for
+/// standard frames that FFmpeg has no name for.
+///
+/// FFmpeg writes other keys as user-defined text frames (`TXXX`), so frames like the iTunes
+/// compilation flag (`TCMP`) have no key.
+fn ffmpeg_key(id: &str) -> Option<&str> {
+    match id {
+        "TIT2" => Some("title"),
+        "TPE1" => Some("artist"),
+        "TPE2" => Some("album_artist"),
+        "TALB" => Some("album"),
+        "TRCK" => Some("track"),
+        "TPOS" => Some("disc"),
+        "TYER" => Some("date"),
+        "TCON" => Some("genre"),
+        "TOAL" => Some(id),
+        _ => None,
+    }
+}
+
+/// Write the overridden frames into an MP3 file.
+///
+/// Files without an ID3v2 tag get a new ID3v2.3 tag. Returns `false` if there was nothing to
+/// override.
+pub fn apply_overrides(path: &Path, overrides: &TagOverrides) -> id3::Result<bool> {
+    if overrides.is_empty() {
+        return Ok(false);
+    }
+    let mut tag = match v1v2::read_from_path(path) {
+        Ok(tag) => tag,
+        Err(id3::Error {
+            kind: ErrorKind::NoTag,
+            ..
+        }) => id3::Tag::with_version(Version::Id3v23),
+        Err(e) => return Err(e),
+    };
+    for (id, value) in overrides.frames.iter() {
+        // ID3v2.4 replaced the year with the recording time.
+        let id = match id.as_str() {
+            "TYER" if tag.version() == Version::Id3v24 => "TDRC",
+            id => id,
+        };
+        if value.is_empty() {
+            tag.remove(id);
+        } else {
+            tag.set_text(id, value.clone());
+        }
+    }
+    tag.write_to_path(path, tag.version())?;
+    Ok(true)
+}
+
+/// Returns `true` if the file has an ID3v2 tag with an attached picture.
+pub fn has_picture(path: &Path) -> id3::Result<bool> {
+    match id3::Tag::read_from_path(path) {
+        Ok(tag) => Ok(tag.pictures().next().is_some()),
+        Err(id3::Error {
+            kind: ErrorKind::NoTag,
+            ..
+        }) => Ok(false),
+        Err(e) => Err(e),
+    }
+}
+
+/// Returns the rating in stars (1 to 5) from the first `POPM` frame of the file's ID3v2 tag.
+///
+/// Files without an ID3v2 tag or rating yield `None`.
+pub fn popm_rating(path: &Path) -> id3::Result<Option<u8>> {
+    let tag = match id3::Tag::read_from_path(path) {
+        Ok(tag) => tag,
+        Err(id3::Error {
+            kind: ErrorKind::NoTag,
+            ..
+        }) => return Ok(None),
+        Err(e) => return Err(e),
+    };
+    let rating = tag
+        .frames()
+        .filter_map(|frame| frame.content().popularimeter())
+        .map(|popm| popm.rating)
+        .find(|&rating| rating > 0);
+    Ok(rating.map(popm_stars))
+}
+
+/// Map a `POPM` rating (1 to 255) to stars the way Windows Media Player does.
+fn popm_stars(rating: u8) -> u8 {
+    match rating {
+        0..=31 => 1,
+        32..=95 => 2,
+        96..=159 => 3,
+        160..=223 => 4,
+        224..=255 => 5,
+    }
+}
+
+/// Attach a JPEG image as the front cover of an MP3 file.
+///
+/// Files without an ID3v2 tag get a new ID3v2.3 tag.
+pub fn add_picture(path: &Path, jpeg: Vec<u8>) -> id3::Result<()> {
+    let mut tag = match v1v2::read_from_path(path) {
+        Ok(tag) => tag,
+        Err(id3::Error {
+            kind: ErrorKind::NoTag,
+            ..
+        }) => id3::Tag::with_version(Version::Id3v23),
+        Err(e) => return Err(e),
+    };
+    tag.add_frame(Picture {
+        mime_type: "image/jpeg".to_string(),
+        picture_type: PictureType::CoverFront,
+        description: String::new(),
+        data: jpeg,
+    });
+    tag.write_to_path(path, tag.version())
+}
+
+/// Text encoding of the frames written to output MP3s.
+#[derive(ValueEnum, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum TextEncoding {
+    /// ISO-8859-1, falling back to UTF-16 for frames with other characters.
+    Latin1,
+    Utf16,
+}
+
+/// Returns the text of the frame's content in its written order, if it has any.
+fn frame_texts(content: &Content) -> Option<Vec<&str>> {
+    Some(match content {
+        Content::Text(text) => vec![text],
+        Content::ExtendedText(text) => vec![&text.description, &text.value],
+        Content::Comment(comment) => vec![&comment.description, &comment.text],
+        Content::Lyrics(lyrics) => vec![&lyrics.description, &lyrics.text],
+        _ => return None,
+    })
+}
+
+/// Write all text frames of an MP3 file in `encoding`.
+///
+/// Returns the IDs of the frames that were written as UTF-16 instead of Latin-1 because they
+/// contain other characters.
+pub fn set_encoding(path: &Path, encoding: TextEncoding) -> id3::Result<Vec<String>> {
+    let tag = match id3::Tag::read_from_path(path) {
+        Ok(tag) => tag,
+        Err(id3::Error {
+            kind: ErrorKind::NoTag,
+            ..
+        }) => return Ok(vec![]),
+        Err(e) => return Err(e),
+    };
+    // Frames are added to a new tag, since frames that only differ in their encoding would
+    // otherwise be kept side by side.
+    let mut encoded = id3::Tag::with_version(tag.version());
+    let mut fallbacks = vec![];
+    for frame in tag.frames().cloned() {
+        let Some(texts) = frame_texts(frame.content()) else {
+            encoded.add_frame(frame);
+            continue;
+        };
+        let is_latin1 = texts.iter().all(|text| text.chars().all(|c| c <= '\u{ff}'));
+        let frame_encoding = match encoding {
+            TextEncoding::Latin1 if is_latin1 => Encoding::Latin1,
+            TextEncoding::Latin1 => {
+                fallbacks.push(frame.id().to_string());
+                Encoding::UTF16
+            }
+            TextEncoding::Utf16 => Encoding::UTF16,
+        };
+        encoded.add_frame(frame.set_encoding(Some(frame_encoding)));
+    }
+    fallbacks.dedup();
+    encoded.write_to_path(path, tag.version())?;
+    Ok(fallbacks)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use id3::frame::{Content, Popularimeter, Private};
+    use id3::Tag;
+
+    fn temp_file(name: &str) -> std::path::PathBuf {
+        let path =
+            std::env::temp_dir().join(format!("ford-sync-{}-{}.mp3", name, std::process::id()));
+        // Some bytes standing in for the audio data.
+        std::fs::write(&path, [0xFF, 0xFB, 0x90, 0x00].repeat(64)).unwrap();
+        path
+    }
+
+    #[test]
+    fn rewrites_v24_as_v23() {
+        let path = temp_file("v24");
+        let mut tag = Tag::new();
+        tag.set_title("Title");
+        tag.set_artist("Artist");
+        tag.set_album("Album");
+        tag.set_track(3);
+        tag.add_frame(Picture {
+            mime_type: "image/jpeg".to_string(),
+            picture_type: PictureType::CoverFront,
+            description: String::new(),
+            data: vec![1, 2, 3],
+        });
+        tag.write_to_path(&path, Version::Id3v24).unwrap();
+
+        assert!(rewrite(&path, true, None, None).unwrap().version_fixed);
+        let tag = Tag::read_from_path(&path).unwrap();
+        assert_eq!(tag.version(), Version::Id3v23);
+        assert_eq!(tag.title(), Some("Title"));
+        assert_eq!(tag.artist(), Some("Artist"));
+        assert_eq!(tag.album(), Some("Album"));
+        assert_eq!(tag.track(), Some(3));
+        assert_eq!(tag.pictures().count(), 1);
+        assert_eq!(
+            rewrite(&path, true, None, None).unwrap(),
+            Rewrite::default()
+        );
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn strips_frames_and_extra_pictures() {
+        let path = temp_file("strip");
+        let mut tag = Tag::new();
+        tag.set_title("Title");
+        tag.add_frame(Frame::with_content(
+            "PRIV",
+            Content::Private(Private {
+                owner_identifier: "junk".to_string(),
+                private_data: vec![0; 4096],
+            }),
+        ));
+        for picture_type in [PictureType::CoverFront, PictureType::CoverBack] {
+            tag.add_frame(Picture {
+                mime_type: "image/jpeg".to_string(),
+                picture_type,
+                description: String::new(),
+                data: vec![1; 1024],
+            });
+        }
+        tag.write_to_path(&path, Version::Id3v23).unwrap();
+
+        let strip = parse_strip_frames("PRIV,GEOB,APIC+").unwrap();
+        let result = rewrite(&path, false, Some(&strip), None).unwrap();
+        assert!(result.frames_stripped);
+        assert!(result.bytes_saved > 4096 + 1024);
+        let tag = Tag::read_from_path(&path).unwrap();
+        assert_eq!(tag.title(), Some("Title"));
+        assert!(tag.get("PRIV").is_none());
+        let pictures: Vec<_> = tag.pictures().collect();
+        assert_eq!(pictures.len(), 1);
+        assert_eq!(pictures[0].picture_type, PictureType::CoverFront);
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn rejects_invalid_frame_ids() {
+        assert!(parse_strip_frames("PRIV,usLT").is_err());
+    }
+
+    #[test]
+    fn leaves_untagged_files_alone() {
+        let path = temp_file("untagged");
+        assert_eq!(
+            rewrite(&path, true, None, None).unwrap(),
+            Rewrite::default()
+        );
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn reads_popm_rating_as_stars() {
+        let path = temp_file("popm");
+        assert_eq!(popm_rating(&path).unwrap(), None);
+        let mut tag = Tag::new();
+        tag.add_frame(Popularimeter {
+            user: "user@example.com".to_string(),
+            rating: 196,
+            counter: 0,
+        });
+        tag.write_to_path(&path, Version::Id3v23).unwrap();
+        assert_eq!(popm_rating(&path).unwrap(), Some(4));
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn adds_cover_to_untagged_files() {
+        let path = temp_file("cover");
+        assert!(!has_picture(&path).unwrap());
+        add_picture(&path, vec![0xFF, 0xD8, 0xFF]).unwrap();
+        assert!(has_picture(&path).unwrap());
+        let tag = Tag::read_from_path(&path).unwrap();
+        assert_eq!(tag.version(), Version::Id3v23);
+        let pictures: Vec<_> = tag.pictures().collect();
+        assert_eq!(pictures.len(), 1);
+        assert_eq!(pictures[0].picture_type, PictureType::CoverFront);
+        assert_eq!(pictures[0].data, [0xFF, 0xD8, 0xFF]);
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn truncates_at_word_boundaries() {
+        assert_eq!(truncate_text("Short", 10), None);
+        assert_eq!(
+            truncate_text("Allegro ma non troppo, un poco maestoso", 20).as_deref(),
+            Some("Allegro ma non…")
+        );
+        assert_eq!(
+            truncate_text("Supercalifragilistic", 10).as_deref(),
+            Some("Supercali…")
+        );
+    }
+
+    #[test]
+    fn truncates_long_text_frames() {
+        let path = temp_file("truncate");
+        let mut tag = Tag::new();
+        tag.set_title("Symphony No. 9 in D minor, Op. 125: IV. Presto – Allegro assai");
+        tag.set_artist("Artist");
+        tag.write_to_path(&path, Version::Id3v23).unwrap();
+
+        let result = rewrite(&path, false, None, Some(30)).unwrap();
+        assert_eq!(result.truncations.len(), 1);
+        assert_eq!(result.truncations[0].frame, "TIT2");
+        let tag = Tag::read_from_path(&path).unwrap();
+        assert_eq!(tag.title(), Some("Symphony No. 9 in D minor,…"));
+        assert_eq!(tag.artist(), Some("Artist"));
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn overrides_album_and_track() {
+        let path = temp_file("overrides");
+        let mut tag = Tag::new();
+        tag.set_title("Title");
+        tag.set_album("Album");
+        tag.set_track(3);
+        tag.set_total_tracks(12);
+        tag.set_disc(2);
+        tag.write_to_path(&path, Version::Id3v24).unwrap();
+
+        let mut overrides = TagOverrides::default();
+        overrides.set("TALB", "Road Trip");
+        overrides.set("TOAL", "Album");
+        overrides.set("TRCK", "7");
+        overrides.set("TPOS", "");
+        assert!(apply_overrides(&path, &overrides).unwrap());
+        let tag = Tag::read_from_path(&path).unwrap();
+        assert_eq!(tag.album(), Some("Road Trip"));
+        assert_eq!(
+            tag.get("TOAL").and_then(|f| f.content().text()),
+            Some("Album")
+        );
+        assert_eq!(tag.get("TRCK").and_then(|f| f.content().text()), Some("7"));
+        assert_eq!(tag.disc(), None);
+        assert_eq!(tag.title(), Some("Title"));
+        assert!(!apply_overrides(&path, &TagOverrides::default()).unwrap());
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn leaves_frames_without_ffmpeg_key_for_later() {
+        let mut overrides = TagOverrides::default();
+        overrides.set("TPE2", "Various Artists");
+        overrides.set("TCMP", "1");
+        let metadata: Vec<_> = overrides.ffmpeg_metadata().collect();
+        assert_eq!(metadata, [("album_artist", "Various Artists")]);
+        let rest = overrides.without_ffmpeg_metadata();
+        assert_eq!(rest.frames.keys().collect::<Vec<_>>(), ["TCMP"]);
+    }
+
+    /// Returns the encoding byte of the first frame with the given ID in the file.
+    fn encoding_byte(path: &Path, id: &str) -> u8 {
+        let bytes = std::fs::read(path).unwrap();
+        let start = bytes
+            .windows(4)
+            .position(|window| window == id.as_bytes())
+            .unwrap();
+        // The encoding follows the ID, size and flags of the frame header.
+        bytes[start + 10]
+    }
+
+    fn write_tag(name: &str, title: &str, artist: &str) -> std::path::PathBuf {
+        let path = temp_file(name);
+        let mut tag = Tag::new();
+        tag.set_title(title);
+        tag.set_artist(artist);
+        tag.write_to_path(&path, Version::Id3v23).unwrap();
+        path
+    }
+
+    #[test]
+    fn transliterates_displayed_frames() {
+        let path = write_tag("transliterate", "Звезда по имени Солнце", "Кино");
+        let mut tag = Tag::read_from_path(&path).unwrap();
+        tag.set_album("Μίκης");
+        tag.write_to_path(&path, Version::Id3v23).unwrap();
+        assert_eq!(
+            transliterate(&path, TagTransliteration::Replace).unwrap(),
+            ["TIT2", "TPE1", "TALB"]
+        );
+        let tag = Tag::read_from_path(&path).unwrap();
+        assert_eq!(tag.title(), Some("Zvezda po imeni Solntse"));
+        assert_eq!(tag.artist(), Some("Kino"));
+        assert_eq!(tag.album(), Some("Mikes"));
+        let originals: Vec<_> = tag
+            .extended_texts()
+            .map(|text| (text.description.as_str(), text.value.as_str()))
+            .collect();
+        assert!(originals.contains(&("ORIGINAL ARTIST", "Кино")));
+        assert_eq!(originals.len(), 3);
+        assert!(transliterate(&path, TagTransliteration::Replace)
+            .unwrap()
+            .is_empty());
+
+        let path = write_tag("transliterate-append", "Müller", "東京事変");
+        assert_eq!(
+            transliterate(&path, TagTransliteration::Append).unwrap(),
+            ["TPE1"]
+        );
+        let tag = Tag::read_from_path(&path).unwrap();
+        assert_eq!(tag.title(), Some("Müller"));
+        assert_eq!(tag.artist(), Some("Dong Jing Shi Bian [東京事変]"));
+        assert_eq!(tag.extended_texts().count(), 0);
+    }
+
+    #[test]
+    fn writes_latin1_with_utf16_fallback() {
+        let path = write_tag("latin1", "Müller Straße", "東京事変");
+        assert_eq!(set_encoding(&path, TextEncoding::Latin1).unwrap(), ["TPE1"]);
+        assert_eq!(encoding_byte(&path, "TIT2"), 0);
+        assert_eq!(encoding_byte(&path, "TPE1"), 1);
+        let tag = Tag::read_from_path(&path).unwrap();
+        assert_eq!(tag.title(), Some("Müller Straße"));
+        assert_eq!(tag.artist(), Some("東京事変"));
+        assert_eq!(tag.frames().count(), 2);
+    }
+
+    #[test]
+    fn writes_utf16() {
+        let path = write_tag("utf16", "Müller Straße", "東京事変");
+        assert_eq!(set_encoding(&path, TextEncoding::Latin1).unwrap(), ["TPE1"]);
+        assert!(set_encoding(&path, TextEncoding::Utf16).unwrap().is_empty());
+        assert_eq!(encoding_byte(&path, "TIT2"), 1);
+        assert_eq!(encoding_byte(&path, "TPE1"), 1);
+        let tag = Tag::read_from_path(&path).unwrap();
+        assert_eq!(tag.title(), Some("Müller Straße"));
+        assert_eq!(tag.artist(), Some("東京事変"));
+    }
+}
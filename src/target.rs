@@ -0,0 +1,147 @@
+// Copyright (c) 2024 Jan Holthuis <jan.holthuis@rub.de>
+//
+// This Source Code Form is subject to the terms of the Mozilla Public License, v. 2.0. If a copy
+// of the MPL was not distributed with this file, You can obtain one at
+// http://mozilla.org/MPL/2.0/.
+//
+// SPDX-License-Identifier: MPL-2.0
+//! Locations of output files relative to the output directory.
+use std::ffi::{OsStr, OsString};
+use std::path::{Component, Path, PathBuf};
+
+/// Separator of the paths in the output playlists, which the head unit expects Windows-style.
+pub const PLAYLIST_SEPARATOR: char = '\\';
+
+/// Path of an output file relative to the output directory, as a list of components.
+#[derive(Clone, Debug, Default, PartialEq, Eq, Hash)]
+pub struct TargetPath(Vec<OsString>);
+
+impl TargetPath {
+    /// Returns the target for a path relative to the output directory.
+    ///
+    /// Root, prefix, `.` and `..` components are dropped, so that the target can never point
+    /// outside of the output directory.
+    pub fn new(path: &Path) -> Self {
+        Self(
+            path.components()
+                .filter_map(|component| match component {
+                    Component::Normal(name) => Some(name.to_os_string()),
+                    _ => None,
+                })
+                .collect(),
+        )
+    }
+
+    /// Parse an entry of an output playlist, which may use `\` or `/` as separator.
+    ///
+    /// Like in [`TargetPath::new`], `.` and `..` components are dropped.
+    pub fn parse(entry: &str) -> Self {
+        Self(
+            entry
+                .split(['\\', '/'])
+                .filter(|component| !["", ".", ".."].contains(component))
+                .map(OsString::from)
+                .collect(),
+        )
+    }
+
+    pub fn components(&self) -> impl Iterator<Item = &OsStr> {
+        self.0.iter().map(OsString::as_os_str)
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+
+    /// Render the target for a playlist, joining the components with `separator` after `prefix`.
+    ///
+    /// Playlists are written as UTF-8, so components that are not valid UTF-8 are converted
+    /// lossily.
+    pub fn render(&self, separator: char, prefix: &str) -> String {
+        let mut rendered = prefix.to_string();
+        for (i, component) in self.components().enumerate() {
+            if i > 0 {
+                rendered.push(separator);
+            }
+            rendered.push_str(&component.to_string_lossy());
+        }
+        rendered
+    }
+
+    /// Render the target the way it is written into the output playlists.
+    pub fn playlist_entry(&self) -> String {
+        self.render(PLAYLIST_SEPARATOR, "")
+    }
+
+    /// Returns the location of the target below `output_dir`.
+    pub fn on_disk(&self, output_dir: &Path) -> PathBuf {
+        let mut path = output_dir.to_path_buf();
+        path.extend(&self.0);
+        path
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn renders_empty_paths_as_prefix() {
+        let target = TargetPath::new(Path::new(""));
+        assert!(target.is_empty());
+        assert_eq!(target.playlist_entry(), "");
+        assert_eq!(target.render('/', "USB/"), "USB/");
+        assert_eq!(target.on_disk(Path::new("out")), PathBuf::from("out"));
+    }
+
+    #[test]
+    fn renders_single_components_without_separator() {
+        let target = TargetPath::new(Path::new("song.mp3"));
+        assert_eq!(target.playlist_entry(), "song.mp3");
+        assert_eq!(
+            target.on_disk(Path::new("out")),
+            Path::new("out").join("song.mp3")
+        );
+    }
+
+    #[test]
+    fn combines_prefixes_and_separators() {
+        let target = TargetPath::new(Path::new("Artist/Album/song.mp3"));
+        assert_eq!(target.playlist_entry(), r"Artist\Album\song.mp3");
+        assert_eq!(target.render('/', ""), "Artist/Album/song.mp3");
+        assert_eq!(
+            target.render('\\', r"\Music\"),
+            r"\Music\Artist\Album\song.mp3"
+        );
+        assert_eq!(target.render('/', "../"), "../Artist/Album/song.mp3");
+    }
+
+    #[test]
+    fn never_leaves_the_output_directory() {
+        let target = TargetPath::new(Path::new("/a/./b/../c.mp3"));
+        assert_eq!(target.playlist_entry(), r"a\b\c.mp3");
+        assert_eq!(TargetPath::parse(r"a\.\b/../c.mp3"), target);
+    }
+
+    #[test]
+    fn parses_playlist_entries() {
+        let target = TargetPath::parse(r"Artist\song.mp3");
+        assert_eq!(target, TargetPath::new(Path::new("Artist/song.mp3")));
+        assert_eq!(TargetPath::parse(r"\\a//b\"), TargetPath::parse("a/b"));
+        assert!(TargetPath::parse("").is_empty());
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn renders_non_utf8_components_lossily() {
+        use std::os::unix::ffi::OsStrExt;
+
+        let path = Path::new("Artist").join(OsStr::from_bytes(b"caf\xe9.mp3"));
+        let target = TargetPath::new(&path);
+        assert_eq!(target.playlist_entry(), "Artist\\caf\u{FFFD}.mp3");
+        assert_eq!(
+            target.on_disk(Path::new("out")),
+            Path::new("out").join(&path)
+        );
+    }
+}
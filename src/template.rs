@@ -0,0 +1,471 @@
+// Copyright (c) 2024 Jan Holthuis <jan.holthuis@rub.de>
+//
+// This Source Code Form is subject to the terms of the Mozilla Public License, v. 2.0. If a copy
+// of the MPL was not distributed with this file, You can obtain one at
+// http://mozilla.org/MPL/2.0/.
+//
+// SPDX-License-Identifier: MPL-2.0
+//! Tag-based templates for output file names.
+//!
+//! A template like `{track:02} - {artist} - {title}` consists of literal text and placeholders.
+//! A placeholder names a tag, optionally followed by `:WIDTH` to zero-pad numbers and `|FALLBACK`
+//! for the value to use when the tag is missing (e.g. `{album|Singles}`). Literal braces are
+//! written as `{{` and `}}`. The file extension is appended automatically.
+//!
+//! Templates also work the other way round: [`FilenamePattern`] reads tags from file names.
+use crate::layout;
+use crate::probe::ProbeInfo;
+use std::fmt;
+use std::str::FromStr;
+
+/// A tag that can be used in a template.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum Field {
+    Artist,
+    AlbumArtist,
+    Album,
+    Title,
+    Track,
+    Disc,
+    Year,
+}
+
+impl Field {
+    fn is_number(self) -> bool {
+        matches!(self, Field::Track | Field::Disc | Field::Year)
+    }
+
+    /// Returns the ID of the ID3v2.3 frame that holds the field.
+    fn frame_id(self) -> &'static str {
+        match self {
+            Field::Artist => "TPE1",
+            Field::AlbumArtist => "TPE2",
+            Field::Album => "TALB",
+            Field::Title => "TIT2",
+            Field::Track => "TRCK",
+            Field::Disc => "TPOS",
+            Field::Year => "TYER",
+        }
+    }
+
+    /// Returns `true` if the tags have no value for the field. Unlike in templates, a missing
+    /// album artist does not fall back to the artist.
+    fn is_missing(self, info: Option<&ProbeInfo>) -> bool {
+        let Some(info) = info else {
+            return true;
+        };
+        match self {
+            Field::AlbumArtist => info
+                .tag("album_artist")
+                .or_else(|| info.tag("albumartist"))
+                .is_none(),
+            field => value(info, field).is_none(),
+        }
+    }
+}
+
+impl FromStr for Field {
+    type Err = TemplateError;
+
+    fn from_str(name: &str) -> Result<Self, Self::Err> {
+        match name {
+            "artist" => Ok(Field::Artist),
+            "albumartist" => Ok(Field::AlbumArtist),
+            "album" => Ok(Field::Album),
+            "title" => Ok(Field::Title),
+            "track" => Ok(Field::Track),
+            "disc" => Ok(Field::Disc),
+            "year" => Ok(Field::Year),
+            _ => Err(TemplateError::UnknownPlaceholder(name.to_string())),
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+enum Segment {
+    Literal(String),
+    Placeholder {
+        field: Field,
+        width: Option<usize>,
+        fallback: Option<String>,
+    },
+}
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum TemplateError {
+    UnknownPlaceholder(String),
+    InvalidWidth(String),
+    /// A `{` without matching `}` or a `}` without matching `{`.
+    UnbalancedBrace,
+    Empty,
+}
+
+impl fmt::Display for TemplateError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            TemplateError::UnknownPlaceholder(name) => write!(
+                f,
+                "unknown placeholder `{{{}}}` (expected one of artist, albumartist, album, \
+                 title, track, disc or year)",
+                name
+            ),
+            TemplateError::InvalidWidth(width) => write!(f, "invalid width `{}`", width),
+            TemplateError::UnbalancedBrace => {
+                write!(
+                    f,
+                    "unbalanced brace (use `{{{{` and `}}}}` for literal braces)"
+                )
+            }
+            TemplateError::Empty => write!(f, "template is empty"),
+        }
+    }
+}
+
+impl std::error::Error for TemplateError {}
+
+/// A parsed file name template.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Template {
+    segments: Vec<Segment>,
+}
+
+impl FromStr for Template {
+    type Err = TemplateError;
+
+    fn from_str(template: &str) -> Result<Self, Self::Err> {
+        let mut segments = vec![];
+        let mut literal = String::new();
+        let mut chars = template.chars().peekable();
+        while let Some(c) = chars.next() {
+            match c {
+                '{' if chars.peek() == Some(&'{') => {
+                    chars.next();
+                    literal.push('{');
+                }
+                '}' if chars.peek() == Some(&'}') => {
+                    chars.next();
+                    literal.push('}');
+                }
+                '}' => return Err(TemplateError::UnbalancedBrace),
+                '{' => {
+                    let mut placeholder = String::new();
+                    loop {
+                        match chars.next() {
+                            Some('}') => break,
+                            Some('{') | None => return Err(TemplateError::UnbalancedBrace),
+                            Some(c) => placeholder.push(c),
+                        }
+                    }
+                    if !literal.is_empty() {
+                        segments.push(Segment::Literal(std::mem::take(&mut literal)));
+                    }
+                    segments.push(parse_placeholder(&placeholder)?);
+                }
+                c => literal.push(c),
+            }
+        }
+        if !literal.is_empty() {
+            segments.push(Segment::Literal(literal));
+        }
+        if segments.is_empty() {
+            return Err(TemplateError::Empty);
+        }
+        Ok(Template { segments })
+    }
+}
+
+/// Parse the contents of a placeholder, i.e. `NAME[:WIDTH][|FALLBACK]`.
+fn parse_placeholder(placeholder: &str) -> Result<Segment, TemplateError> {
+    let (spec, fallback) = match placeholder.split_once('|') {
+        Some((spec, fallback)) => (spec, Some(fallback.to_string())),
+        None => (placeholder, None),
+    };
+    let (name, width) = match spec.split_once(':') {
+        Some((name, width)) => (
+            name,
+            Some(
+                width
+                    .parse()
+                    .map_err(|_| TemplateError::InvalidWidth(width.to_string()))?,
+            ),
+        ),
+        None => (spec, None),
+    };
+    Ok(Segment::Placeholder {
+        field: name.trim().parse()?,
+        width,
+        fallback,
+    })
+}
+
+impl Template {
+    /// Render the template for a file with the given tags.
+    ///
+    /// `stem` is the source's file name without extension, used for a missing title.
+    pub fn render(&self, info: Option<&ProbeInfo>, stem: &str) -> String {
+        let mut result = String::new();
+        for segment in self.segments.iter() {
+            match segment {
+                Segment::Literal(text) => result.push_str(text),
+                Segment::Placeholder {
+                    field,
+                    width,
+                    fallback,
+                } => {
+                    let value = info.and_then(|info| value(info, *field));
+                    let value = match (value, fallback) {
+                        (Some(value), _) => value,
+                        (None, Some(fallback)) => fallback.clone(),
+                        (None, None) => default_value(*field, stem),
+                    };
+                    match width {
+                        Some(width) if field.is_number() => {
+                            result.push_str(&format!("{:0>width$}", value, width = width))
+                        }
+                        Some(width) => result.push_str(&format!("{:width$}", value, width = width)),
+                        None => result.push_str(&value),
+                    }
+                }
+            }
+        }
+        result.replace(['/', '\\'], "_")
+    }
+}
+
+impl Template {
+    /// Match a file name against the template and return the values of its placeholders.
+    ///
+    /// Placeholders match as little text as possible, and number placeholders only digits.
+    /// Returns `None` if the file name does not match.
+    fn extract(&self, name: &str) -> Option<Vec<(Field, String)>> {
+        let mut values = vec![];
+        extract_segments(&self.segments, name, &mut values).then_some(values)
+    }
+}
+
+fn extract_segments(segments: &[Segment], text: &str, values: &mut Vec<(Field, String)>) -> bool {
+    let Some((segment, rest)) = segments.split_first() else {
+        return text.is_empty();
+    };
+    let field = match segment {
+        Segment::Literal(literal) => {
+            return text
+                .strip_prefix(literal.as_str())
+                .is_some_and(|text| extract_segments(rest, text, values));
+        }
+        Segment::Placeholder { field, .. } => *field,
+    };
+    let ends = text.char_indices().skip(1).map(|(end, _)| end);
+    for end in ends.chain([text.len()]) {
+        let value = text[..end].trim();
+        if field.is_number() && !value.chars().all(|c| c.is_ascii_digit()) {
+            return false;
+        }
+        if !value.is_empty() && extract_segments(rest, &text[end..], values) {
+            values.insert(0, (field, value.to_string()));
+            return true;
+        }
+    }
+    false
+}
+
+/// Patterns tried by `--tags-from-filename auto`, most specific first.
+const AUTO_PATTERNS: [&str; 6] = [
+    "{track} - {artist} - {title}",
+    "{track}. {artist} - {title}",
+    "{track} - {title}",
+    "{track}. {title}",
+    "{artist} - {title}",
+    "{track} {title}",
+];
+
+/// Where to read missing tags from in the file names of sources.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum FilenamePattern {
+    /// Try common patterns like `{track} - {artist} - {title}`.
+    Auto,
+    Template(Template),
+}
+
+impl FromStr for FilenamePattern {
+    type Err = TemplateError;
+
+    fn from_str(pattern: &str) -> Result<Self, Self::Err> {
+        match pattern {
+            "auto" => Ok(FilenamePattern::Auto),
+            pattern => pattern.parse().map(FilenamePattern::Template),
+        }
+    }
+}
+
+impl FilenamePattern {
+    /// Returns the tags to write for a source with the file name `stem` and the tags `info`, as
+    /// frame IDs and values.
+    ///
+    /// Sources with an artist and a title keep their tags, and existing tags are never replaced,
+    /// unless `force` is set. Returns `None` if the file name matches none of the patterns.
+    pub fn extract(
+        &self,
+        stem: &str,
+        info: Option<&ProbeInfo>,
+        force: bool,
+    ) -> Option<Vec<(&'static str, String)>> {
+        if !force && !Field::Artist.is_missing(info) && !Field::Title.is_missing(info) {
+            return Some(vec![]);
+        }
+        let values = match self {
+            FilenamePattern::Template(template) => template.extract(stem),
+            FilenamePattern::Auto => AUTO_PATTERNS.iter().find_map(|pattern| {
+                let template: Template = pattern.parse().expect("built-in patterns are valid");
+                template.extract(stem)
+            }),
+        }?;
+        Some(
+            values
+                .into_iter()
+                .filter(|(field, _)| force || field.is_missing(info))
+                .map(|(field, value)| {
+                    let value = match field.is_number() {
+                        // Strip leading zeros, e.g. of `03`.
+                        true => value.parse::<u32>().map_or(value, |n| n.to_string()),
+                        false => value,
+                    };
+                    (field.frame_id(), value)
+                })
+                .collect(),
+        )
+    }
+}
+
+/// Returns the value of a field from the tags.
+fn value(info: &ProbeInfo, field: Field) -> Option<String> {
+    match field {
+        Field::Artist => info.tag("artist").map(str::to_string),
+        Field::AlbumArtist => layout::album_artist(info).map(str::to_string),
+        Field::Album => info.tag("album").map(str::to_string),
+        Field::Title => info.tag("title").map(str::to_string),
+        Field::Track => info.number_tag("track").map(|n| n.to_string()),
+        Field::Disc => info.number_tag("disc").map(|n| n.to_string()),
+        Field::Year => info
+            .tag("date")
+            .or_else(|| info.tag("year"))
+            .and_then(|date| date.get(..4))
+            .map(str::to_string),
+    }
+}
+
+/// Returns the value of a missing field without an explicit fallback.
+fn default_value(field: Field, stem: &str) -> String {
+    match field {
+        Field::Artist | Field::AlbumArtist => "Unknown Artist".to_string(),
+        Field::Album => "Unknown Album".to_string(),
+        Field::Title => stem.to_string(),
+        Field::Track | Field::Disc => "0".to_string(),
+        Field::Year => "0000".to_string(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn info(tags: &[(&str, &str)]) -> ProbeInfo {
+        ProbeInfo {
+            tags: tags
+                .iter()
+                .map(|(name, value)| (name.to_string(), value.to_string()))
+                .collect(),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn renders_tags() {
+        let template: Template = "{track:02} - {artist} - {title}".parse().unwrap();
+        let info = info(&[("artist", "Björk"), ("title", "Jóga"), ("track", "3/10")]);
+        assert_eq!(template.render(Some(&info), "x"), "03 - Björk - Jóga");
+    }
+
+    #[test]
+    fn uses_fallbacks() {
+        let template: Template = "{album|Singles} - {title}".parse().unwrap();
+        assert_eq!(template.render(None, "Song"), "Singles - Song");
+    }
+
+    #[test]
+    fn escapes_braces() {
+        let template: Template = "{{{title}}}".parse().unwrap();
+        assert_eq!(template.render(None, "Song"), "{Song}");
+    }
+
+    #[test]
+    fn reports_parse_errors() {
+        assert_eq!(
+            "{titel}".parse::<Template>(),
+            Err(TemplateError::UnknownPlaceholder("titel".to_string()))
+        );
+        assert_eq!(
+            "{title".parse::<Template>(),
+            Err(TemplateError::UnbalancedBrace)
+        );
+        assert_eq!(
+            "{track:x}".parse::<Template>(),
+            Err(TemplateError::InvalidWidth("x".to_string()))
+        );
+    }
+
+    #[test]
+    fn extracts_tags_from_file_names() {
+        let template: FilenamePattern = "{track} - {artist} - {title}".parse().unwrap();
+        assert_eq!(
+            template.extract("03 - Artist - Title - Live", None, false),
+            Some(vec![
+                ("TRCK", "3".to_string()),
+                ("TPE1", "Artist".to_string()),
+                ("TIT2", "Title - Live".to_string()),
+            ])
+        );
+        assert_eq!(template.extract("Artist - Title", None, false), None);
+    }
+
+    #[test]
+    fn finds_a_matching_built_in_pattern() {
+        let extract = |stem| FilenamePattern::Auto.extract(stem, None, false);
+        assert_eq!(
+            extract("07. Title"),
+            Some(vec![
+                ("TRCK", "7".to_string()),
+                ("TIT2", "Title".to_string())
+            ])
+        );
+        assert_eq!(
+            extract("Artist - Title"),
+            Some(vec![
+                ("TPE1", "Artist".to_string()),
+                ("TIT2", "Title".to_string())
+            ])
+        );
+        assert_eq!(extract("Title"), None);
+    }
+
+    #[test]
+    fn keeps_existing_tags_unless_forced() {
+        let pattern = FilenamePattern::Auto;
+        let tagged = info(&[("artist", "Tagged"), ("title", "Song")]);
+        assert_eq!(
+            pattern.extract("01 - A - B", Some(&tagged), false),
+            Some(vec![])
+        );
+        let untitled = info(&[("artist", "Tagged"), ("track", "5")]);
+        assert_eq!(
+            pattern.extract("01 - A - B", Some(&untitled), false),
+            Some(vec![("TIT2", "B".to_string())])
+        );
+        assert_eq!(
+            pattern
+                .extract("01 - A - B", Some(&tagged), true)
+                .map(|tags| tags.len()),
+            Some(3)
+        );
+    }
+}
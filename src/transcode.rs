@@ -0,0 +1,301 @@
+// Copyright (c) 2024 Jan Holthuis <jan.holthuis@rub.de>
+//
+// This Source Code Form is subject to the terms of the Mozilla Public License, v. 2.0. If a copy
+// of the MPL was not distributed with this file, You can obtain one at
+// http://mozilla.org/MPL/2.0/.
+//
+// SPDX-License-Identifier: MPL-2.0
+//! The program that converts files to MP3, which is FFmpeg unless replaced (e.g. in tests).
+use crate::art;
+use crate::atomic;
+use crate::execute::ExecuteOptions;
+use crate::interrupt;
+use crate::rules::EncoderSettings;
+use crate::segment::Segment;
+use crate::span;
+use crate::tags::TagOverrides;
+use log::{log_enabled, trace, warn, Level};
+use std::ffi::OsString;
+use std::fmt;
+use std::io::{BufRead, BufReader, Write};
+use std::path::{Path, PathBuf};
+use std::process::{Command, Stdio};
+use std::sync::Arc;
+use std::time::Duration;
+
+/// How often a running FFmpeg is checked for completion or interruption.
+const POLL_INTERVAL: Duration = Duration::from_millis(50);
+
+/// A single conversion to MP3.
+#[derive(Clone, Copy, Debug)]
+pub struct Job<'a> {
+    pub input_path: &'a Path,
+    /// Part of the input to convert instead of all of it.
+    pub segment: Option<&'a Segment>,
+    /// Image to attach to the output as its front cover.
+    pub cover: Option<&'a Path>,
+    /// Final location of the output, used in log messages.
+    pub output_path: &'a Path,
+    /// Temporary file that the output is written to.
+    pub temp_path: &'a Path,
+    /// Log file for the command line and output of the conversion.
+    pub log_path: &'a Path,
+    /// Tags to set on the output instead of those of the input.
+    pub tags: &'a TagOverrides,
+    pub encoding: Encoding<'a>,
+    pub options: &'a ExecuteOptions,
+}
+
+/// How the audio stream of the input becomes the MP3 stream of the output.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Encoding<'a> {
+    /// Copy the MP3 stream of the input instead of encoding it.
+    Remux,
+    /// Encode the input, with the settings of a rule instead of the defaults if given.
+    Encode(Option<&'a EncoderSettings>),
+}
+
+/// Reason why a conversion did not succeed.
+#[derive(Debug)]
+pub enum TranscodeError {
+    /// The transcoder could not be started.
+    Spawn(std::io::Error),
+    /// The transcoder exited with a non-zero status.
+    Status {
+        /// Description of the exit status.
+        status: String,
+        stderr: String,
+        /// Log file containing the command line and the transcoder's output.
+        log_path: Option<PathBuf>,
+    },
+    /// Waiting for the transcoder failed.
+    Io(std::io::Error),
+    /// The transcoder was killed (or never started) because the run was interrupted.
+    Interrupted,
+    /// The transcoder was killed because the conversion was skipped (see [`interrupt::skip`]).
+    Skipped,
+}
+
+/// Converts files to MP3. Shared between the worker threads of a run.
+pub trait Transcoder: Send + Sync {
+    /// Check that the transcoder can be run at all.
+    fn check(&self) -> std::io::Result<()> {
+        Ok(())
+    }
+
+    /// Write the converted `job.input_path` to `job.temp_path`.
+    fn transcode(&self, job: &Job) -> Result<(), TranscodeError>;
+}
+
+/// The transcoder used by a run, [`Ffmpeg`] by default.
+#[derive(Clone)]
+pub struct SharedTranscoder(Arc<dyn Transcoder>);
+
+impl SharedTranscoder {
+    pub fn new(transcoder: Arc<dyn Transcoder>) -> Self {
+        Self(transcoder)
+    }
+}
+
+impl Default for SharedTranscoder {
+    fn default() -> Self {
+        Self(Arc::new(Ffmpeg))
+    }
+}
+
+impl fmt::Debug for SharedTranscoder {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "SharedTranscoder")
+    }
+}
+
+impl Transcoder for SharedTranscoder {
+    fn check(&self) -> std::io::Result<()> {
+        self.0.check()
+    }
+
+    fn transcode(&self, job: &Job) -> Result<(), TranscodeError> {
+        self.0.transcode(job)
+    }
+}
+
+/// Converts files by running `ffmpeg` from the `PATH`.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct Ffmpeg;
+
+impl Transcoder for Ffmpeg {
+    fn check(&self) -> std::io::Result<()> {
+        Command::new("ffmpeg")
+            .arg("-version")
+            .stdin(Stdio::null())
+            .stdout(Stdio::null())
+            .stderr(Stdio::null())
+            .status()
+            .map(|_| ())
+    }
+
+    /// Run FFmpeg, killing it if the run gets interrupted, and log its output to `job.log_path`
+    /// if it fails (or always if `keep_logs` is set).
+    fn transcode(&self, job: &Job) -> Result<(), TranscodeError> {
+        let mut command = Command::new("ffmpeg");
+        command
+            .args(ffmpeg_args(
+                job.input_path,
+                job.segment,
+                job.cover,
+                job.temp_path,
+                job.tags,
+                job.encoding,
+                job.options,
+            ))
+            .stdin(Stdio::null())
+            .stdout(Stdio::null())
+            .stderr(Stdio::piped());
+        trace!("{}: Running {:?}", job.output_path.display(), command);
+        let mut child = command.spawn().map_err(TranscodeError::Spawn)?;
+
+        // Drain stderr on a separate thread so that FFmpeg never blocks on a full pipe. At trace
+        // verbosity, each line is also forwarded to the log.
+        let stderr_pipe = child.stderr.take().expect("stderr is piped");
+        let log_prefix = job
+            .output_path
+            .file_name()
+            .unwrap_or_default()
+            .to_string_lossy()
+            .into_owned();
+        let spans = span::current();
+        let stderr_reader = std::thread::spawn(move || {
+            let _spans = span::enter_all(spans);
+            let mut stderr = String::new();
+            let mut reader = BufReader::new(stderr_pipe);
+            let mut line = Vec::new();
+            while matches!(reader.read_until(b'\n', &mut line), Ok(n) if n > 0) {
+                let text = String::from_utf8_lossy(&line);
+                if log_enabled!(Level::Trace) {
+                    trace!("{}: {}", log_prefix, text.trim_end());
+                }
+                stderr.push_str(&text);
+                line.clear();
+            }
+            stderr
+        });
+
+        let status = loop {
+            match child.try_wait() {
+                Ok(Some(status)) => break Ok(status),
+                Ok(None) => (),
+                Err(e) => break Err(e),
+            }
+            if interrupt::is_interrupted() {
+                let _ = child.kill();
+                let _ = child.wait();
+                let _ = stderr_reader.join();
+                return Err(TranscodeError::Interrupted);
+            }
+            if interrupt::take_skip(job.output_path) {
+                let _ = child.kill();
+                let _ = child.wait();
+                let _ = stderr_reader.join();
+                return Err(TranscodeError::Skipped);
+            }
+            std::thread::sleep(POLL_INTERVAL);
+        };
+        let stderr = stderr_reader.join().unwrap_or_default();
+
+        let succeeded = matches!(status, Ok(status) if status.success());
+        let logged = if job.options.keep_logs || !succeeded {
+            match write_log(job.log_path, &command, &stderr) {
+                Ok(()) => true,
+                Err(e) => {
+                    warn!("{}: Failed to write log ({})", job.log_path.display(), e);
+                    false
+                }
+            }
+        } else {
+            // Remove the log of a previously failed attempt.
+            atomic::discard(job.log_path);
+            false
+        };
+
+        match status {
+            Ok(status) if status.success() => Ok(()),
+            Ok(status) => Err(TranscodeError::Status {
+                status: status.to_string(),
+                stderr,
+                log_path: logged.then(|| job.log_path.to_path_buf()),
+            }),
+            Err(e) => Err(TranscodeError::Io(e)),
+        }
+    }
+}
+
+/// Write the command line and the captured output of an FFmpeg run to a log file.
+fn write_log(log_path: &Path, command: &Command, stderr: &str) -> std::io::Result<()> {
+    if let Some(parent) = log_path.parent() {
+        std::fs::create_dir_all(parent)?;
+    }
+    let mut file = std::fs::File::create(log_path)?;
+    writeln!(file, "Command: {:?}", command)?;
+    writeln!(file)?;
+    file.write_all(stderr.as_bytes())
+}
+
+/// Returns the FFmpeg arguments for converting `input_path` to an MP3 at `output_path`.
+///
+/// If `cover` is given, the image is attached to the output as its front cover. If `segment` is
+/// given, only that part of the input is converted.
+pub fn ffmpeg_args(
+    input_path: &Path,
+    segment: Option<&Segment>,
+    cover: Option<&Path>,
+    output_path: &Path,
+    tags: &TagOverrides,
+    encoding: Encoding,
+    options: &ExecuteOptions,
+) -> Vec<OsString> {
+    let mut args: Vec<OsString> = segment.map(Segment::ffmpeg_args).unwrap_or_default();
+    args.extend(["-i".into(), input_path.into()]);
+    match cover {
+        Some(cover) => {
+            args.extend(["-i".into(), cover.into()]);
+            args.extend(
+                ["-map", "0:a", "-map", "1:v", "-c:v", "mjpeg", "-vf"]
+                    .into_iter()
+                    .map(OsString::from),
+            );
+            args.push(art::scale_filter(options.art_size).into());
+            args.extend(
+                ["-disposition:v", "attached_pic", "-id3v2_version", "3"]
+                    .into_iter()
+                    .map(OsString::from),
+            );
+        }
+        None => args.push("-vn".into()),
+    }
+    if segment.is_some() {
+        // The chapters of the input refer to the whole source, not to the part.
+        args.extend(["-map_chapters", "-1"].into_iter().map(OsString::from));
+    }
+    args.push("-y".into());
+    match encoding {
+        Encoding::Remux => {
+            args.extend(["-c:a", "copy"].into_iter().map(OsString::from));
+            if cover.is_none() {
+                args.extend(["-id3v2_version", "3"].into_iter().map(OsString::from));
+            }
+        }
+        Encoding::Encode(encoder) => args.extend(EncoderSettings::ffmpeg_args(encoder)),
+    }
+    if let Some(strip) = &options.strip_frames {
+        for key in strip.ffmpeg_metadata_keys() {
+            args.push("-metadata".into());
+            args.push(format!("{}=", key).into());
+        }
+    }
+    for (key, value) in tags.ffmpeg_metadata() {
+        args.push("-metadata".into());
+        args.push(format!("{}={}", key, value).into());
+    }
+    args.extend(["-f".into(), "mp3".into(), output_path.into()]);
+    args
+}
@@ -0,0 +1,179 @@
+// Copyright (c) 2024 Jan Holthuis <jan.holthuis@rub.de>
+//
+// This Source Code Form is subject to the terms of the Mozilla Public License, v. 2.0. If a copy
+// of the MPL was not distributed with this file, You can obtain one at
+// http://mozilla.org/MPL/2.0/.
+//
+// SPDX-License-Identifier: MPL-2.0
+//! Unicode normalization and ASCII transliteration of output path components, and romanization
+//! of tag text.
+use clap::ValueEnum;
+use std::path::{Component, Path, PathBuf};
+use unicode_normalization::UnicodeNormalization;
+
+/// How non-ASCII characters are mapped to ASCII.
+#[derive(ValueEnum, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum TransliterationStyle {
+    /// Drop diacritics and approximate other scripts (ü → u).
+    Basic,
+    /// Like `basic`, but spell out German umlauts and ß (ü → ue, ß → ss).
+    German,
+}
+
+/// Map a German umlaut or ß to its conventional ASCII spelling.
+fn german(c: char) -> Option<&'static str> {
+    Some(match c {
+        'ä' => "ae",
+        'ö' => "oe",
+        'ü' => "ue",
+        'Ä' => "Ae",
+        'Ö' => "Oe",
+        'Ü' => "Ue",
+        'ß' => "ss",
+        'ẞ' => "SS",
+        _ => return None,
+    })
+}
+
+/// Normalize a name to Unicode Normalization Form C.
+pub fn normalize_name(name: &str) -> String {
+    name.nfc().collect()
+}
+
+/// Transliterate a (normalized) name to ASCII.
+///
+/// Characters without an ASCII approximation are replaced with `_`.
+pub fn transliterate_name(name: &str, style: TransliterationStyle) -> String {
+    let mut result = String::with_capacity(name.len());
+    for c in name.chars() {
+        match german(c).filter(|_| style == TransliterationStyle::German) {
+            Some(replacement) => result.push_str(replacement),
+            None if c.is_ascii() => result.push(c),
+            None => result.push_str(deunicode::deunicode_char(c).unwrap_or("_")),
+        }
+    }
+    let trimmed = result.trim();
+    if trimmed.is_empty() {
+        "_".to_string()
+    } else {
+        trimmed.to_string()
+    }
+}
+
+/// Returns `true` if the character is in Latin script, or punctuation and symbols that go with it.
+fn is_latin(c: char) -> bool {
+    matches!(c, '\0'..='\u{24f}' | '\u{1e00}'..='\u{1eff}' | '\u{2000}'..='\u{20cf}')
+}
+
+/// Romanize the characters of `text` that are not in Latin script, e.g. Cyrillic or Japanese,
+/// leaving Latin characters with diacritics alone.
+///
+/// Returns `None` if the text is in Latin script already.
+pub fn romanize_text(text: &str) -> Option<String> {
+    if text.chars().all(is_latin) {
+        return None;
+    }
+    let mut result = String::with_capacity(text.len());
+    let mut rest = text;
+    while let Some(c) = rest.chars().next() {
+        let latin = is_latin(c);
+        let end = rest
+            .find(|c: char| is_latin(c) != latin)
+            .unwrap_or(rest.len());
+        let (run, tail) = rest.split_at(end);
+        if latin {
+            result.push_str(run);
+        } else {
+            // Characters without a romanization are kept, which is no worse than dropping them.
+            let romanized: String = run
+                .chars()
+                .map(|c| deunicode::deunicode_char(c).map_or(c.to_string(), str::to_string))
+                .collect();
+            let romanized = romanized.split_whitespace().collect::<Vec<_>>().join(" ");
+            let needs_space = |c: Option<char>| c.is_some_and(char::is_alphanumeric);
+            if needs_space(result.chars().next_back()) {
+                result.push(' ');
+            }
+            result.push_str(&romanized);
+            if needs_space(tail.chars().next()) {
+                result.push(' ');
+            }
+        }
+        rest = tail;
+    }
+    Some(result)
+}
+
+/// Normalize every component of a relative output path to NFC and transliterate it to ASCII if
+/// `style` is given.
+pub fn normalize_path(path: &Path, style: Option<TransliterationStyle>) -> PathBuf {
+    path.components()
+        .map(|component| match component {
+            Component::Normal(name) => {
+                let name = normalize_name(&name.to_string_lossy());
+                match style {
+                    Some(style) => PathBuf::from(transliterate_name(&name, style)),
+                    None => PathBuf::from(name),
+                }
+            }
+            component => PathBuf::from(component.as_os_str()),
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn normalizes_decomposed_names() {
+        assert_eq!(normalize_name("Mo\u{0308}tley Cru\u{0308}e"), "Mötley Crüe");
+    }
+
+    #[test]
+    fn transliterates_names() {
+        let name = normalize_name("Mo\u{0308}tley Cru\u{0308}e – “Größe”.mp3");
+        assert_eq!(
+            transliterate_name(&name, TransliterationStyle::Basic),
+            "Motley Crue - \"Grosse\".mp3"
+        );
+        assert_eq!(
+            transliterate_name(&name, TransliterationStyle::German),
+            "Moetley Cruee - \"Groesse\".mp3"
+        );
+        assert!(transliterate_name("東京", TransliterationStyle::Basic).is_ascii());
+    }
+
+    #[test]
+    fn romanizes_non_latin_text() {
+        assert_eq!(romanize_text("Mötley Crüe"), None);
+        assert_eq!(romanize_text("Кино").as_deref(), Some("Kino"));
+        assert_eq!(
+            romanize_text("Ария - Штиль (Live)").as_deref(),
+            Some("Ariia - Shtil' (Live)")
+        );
+        assert_eq!(
+            romanize_text("Μίκης Θεοδωράκης").as_deref(),
+            Some("Mikes Theodorakes")
+        );
+        assert_eq!(
+            romanize_text("東京事変").as_deref(),
+            Some("Dong Jing Shi Bian")
+        );
+        assert_eq!(
+            romanize_text("Live at 武道館").as_deref(),
+            Some("Live at Wu Dao Guan")
+        );
+    }
+
+    #[test]
+    fn normalizes_every_component() {
+        assert_eq!(
+            normalize_path(
+                Path::new("Bjo\u{0308}rk/Homogenic/Jo\u{0301}ga.mp3"),
+                Some(TransliterationStyle::Basic)
+            ),
+            Path::new("Bjork/Homogenic/Joga.mp3")
+        );
+    }
+}
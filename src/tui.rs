@@ -0,0 +1,439 @@
+// Copyright (c) 2024 Jan Holthuis <jan.holthuis@rub.de>
+//
+// This Source Code Form is subject to the terms of the Mozilla Public License, v. 2.0. If a copy
+// of the MPL was not distributed with this file, You can obtain one at
+// http://mozilla.org/MPL/2.0/.
+//
+// SPDX-License-Identifier: MPL-2.0
+//! Full-screen interface for `--tui`.
+//!
+//! The screen shows the file each worker is on, the overall progress with an estimate of the time
+//! left, and the warnings and failures of the run. Keys are read with the terminal in raw mode,
+//! which needs a Unix terminal. Elsewhere, and on terminals that cannot move the cursor, the
+//! normal log is shown instead (see [`is_supported`]).
+use ford_sync_convert::execute::{TaskResult, TaskStatus};
+use ford_sync_convert::interrupt;
+use ford_sync_convert::logging;
+use ford_sync_convert::observer::{Progress, SyncObserver};
+use ford_sync_convert::plan::Task;
+use ford_sync_convert::warnings::WarningCategory;
+use std::collections::VecDeque;
+use std::io::{self, IsTerminal, Read, Write};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread::JoinHandle;
+use std::time::{Duration, Instant};
+
+/// How often the screen is redrawn.
+const REFRESH_INTERVAL: Duration = Duration::from_millis(200);
+
+/// Number of warnings and failures that are kept for the screen.
+const MAX_MESSAGES: usize = 200;
+
+/// Returns `true` if the screen can be shown, i.e. stdin and stderr are a terminal that can move
+/// the cursor.
+pub fn is_supported() -> bool {
+    let term = std::env::var("TERM").unwrap_or_default();
+    cfg!(unix)
+        && io::stdin().is_terminal()
+        && io::stderr().is_terminal()
+        && !term.is_empty()
+        && term != "dumb"
+}
+
+/// Formats `duration` like a clock, e.g. `1:02:03` or `2:03`.
+fn clock(duration: Duration) -> String {
+    let seconds = duration.as_secs();
+    let (hours, minutes, seconds) = (seconds / 3600, seconds / 60 % 60, seconds % 60);
+    if hours > 0 {
+        format!("{}:{:02}:{:02}", hours, minutes, seconds)
+    } else {
+        format!("{}:{:02}", minutes, seconds)
+    }
+}
+
+/// A key pressed on the terminal.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum Key {
+    Up,
+    Down,
+    /// Ctrl-C, which is read as a key in raw mode.
+    Interrupt,
+    Char(char),
+}
+
+/// The terminal in raw mode on the alternate screen, restored when dropped.
+struct Terminal {
+    #[cfg(unix)]
+    original: libc::termios,
+    active: AtomicBool,
+}
+
+impl Terminal {
+    #[cfg(unix)]
+    fn enter() -> io::Result<Self> {
+        // SAFETY: `termios` is plain data that `tcgetattr` fills in.
+        let mut original: libc::termios = unsafe { std::mem::zeroed() };
+        // SAFETY: The pointer is valid for the duration of the call.
+        if unsafe { libc::tcgetattr(libc::STDIN_FILENO, &mut original) } != 0 {
+            return Err(io::Error::last_os_error());
+        }
+        // Keys arrive one by one without echo, and Ctrl-C arrives as a key so that the terminal
+        // is restored before exiting. Reads time out after 0.1s, so that waiting for keys can
+        // stop at the end of the run.
+        let mut raw = original;
+        raw.c_lflag &= !(libc::ICANON | libc::ECHO | libc::ISIG);
+        raw.c_cc[libc::VMIN] = 0;
+        raw.c_cc[libc::VTIME] = 1;
+        // SAFETY: The pointer is valid for the duration of the call.
+        if unsafe { libc::tcsetattr(libc::STDIN_FILENO, libc::TCSANOW, &raw) } != 0 {
+            return Err(io::Error::last_os_error());
+        }
+        let mut stderr = io::stderr();
+        let _ = write!(stderr, "\x1b[?1049h\x1b[?25l");
+        let _ = stderr.flush();
+        Ok(Self {
+            original,
+            active: AtomicBool::new(true),
+        })
+    }
+
+    #[cfg(not(unix))]
+    fn enter() -> io::Result<Self> {
+        Err(io::Error::new(
+            io::ErrorKind::Unsupported,
+            "raw mode needs a Unix terminal",
+        ))
+    }
+
+    /// Restore the terminal. Does nothing if it was already restored.
+    fn leave(&self) {
+        if !self.active.swap(false, Ordering::SeqCst) {
+            return;
+        }
+        let mut stderr = io::stderr();
+        let _ = write!(stderr, "\x1b[?25h\x1b[?1049l");
+        let _ = stderr.flush();
+        // SAFETY: The pointer is valid for the duration of the call.
+        #[cfg(unix)]
+        unsafe {
+            libc::tcsetattr(libc::STDIN_FILENO, libc::TCSANOW, &self.original);
+        }
+    }
+
+    /// Returns the width and height of the terminal.
+    fn size(&self) -> (usize, usize) {
+        #[cfg(unix)]
+        {
+            // SAFETY: `winsize` is plain data that `ioctl` fills in.
+            let mut size: libc::winsize = unsafe { std::mem::zeroed() };
+            // SAFETY: The pointer is valid for the duration of the call.
+            let result = unsafe { libc::ioctl(libc::STDERR_FILENO, libc::TIOCGWINSZ, &mut size) };
+            if result == 0 && size.ws_col > 0 && size.ws_row > 0 {
+                return (size.ws_col.into(), size.ws_row.into());
+            }
+        }
+        (80, 24)
+    }
+
+    /// Replace the screen with `lines`, cut to its size.
+    fn draw(&self, lines: &[String]) {
+        let (width, height) = self.size();
+        let mut frame = String::from("\x1b[H");
+        for (i, line) in lines.iter().take(height).enumerate() {
+            if i > 0 {
+                frame.push_str("\r\n");
+            }
+            frame.push_str("\x1b[2K");
+            frame.extend(line.chars().take(width));
+        }
+        frame.push_str("\x1b[J");
+        let mut stderr = io::stderr().lock();
+        let _ = stderr.write_all(frame.as_bytes());
+        let _ = stderr.flush();
+    }
+
+    /// Wait up to 0.1s for keys.
+    fn keys(&self) -> Vec<Key> {
+        let mut buffer = [0; 64];
+        match io::stdin().lock().read(&mut buffer) {
+            Ok(n) => parse_keys(&buffer[..n]),
+            Err(_) => vec![],
+        }
+    }
+}
+
+/// Parses the keys in the bytes read from a terminal in raw mode. Unknown escape sequences are
+/// left out.
+fn parse_keys(mut bytes: &[u8]) -> Vec<Key> {
+    let mut keys = vec![];
+    loop {
+        let (key, len) = match bytes {
+            [] => return keys,
+            [0x1b, b'[', b'A', ..] => (Some(Key::Up), 3),
+            [0x1b, b'[', b'B', ..] => (Some(Key::Down), 3),
+            [0x1b, b'[' | b'O', _, ..] => (None, 3),
+            [0x1b, ..] => (None, 1),
+            [0x03, ..] => (Some(Key::Interrupt), 1),
+            [b'k', ..] => (Some(Key::Up), 1),
+            [b'j', ..] => (Some(Key::Down), 1),
+            [byte, ..] => (Some(Key::Char(char::from(*byte))), 1),
+        };
+        keys.extend(key);
+        bytes = &bytes[len..];
+    }
+}
+
+impl Drop for Terminal {
+    fn drop(&mut self) {
+        self.leave();
+    }
+}
+
+/// What the screen shows, updated by the callbacks of the run.
+#[derive(Default)]
+struct State {
+    /// Destinations of the running tasks with their start times, in the order they started.
+    running: Vec<(PathBuf, Instant)>,
+    /// Index of the running task that `s` skips.
+    selected: usize,
+    progress: Option<Progress>,
+    failed: usize,
+    /// The latest warnings and failures.
+    messages: VecDeque<String>,
+}
+
+impl State {
+    fn push(&mut self, message: String) {
+        if self.messages.len() == MAX_MESSAGES {
+            self.messages.pop_front();
+        }
+        self.messages.push_back(message);
+    }
+}
+
+/// Shared between the callbacks of the run, the thread drawing the screen and the thread reading
+/// keys.
+struct Shared {
+    state: Mutex<State>,
+    output_dir: PathBuf,
+    started: Instant,
+    finished: AtomicBool,
+}
+
+impl Shared {
+    fn state(&self) -> std::sync::MutexGuard<'_, State> {
+        self.state.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    fn relative<'a>(&self, path: &'a Path) -> &'a Path {
+        path.strip_prefix(&self.output_dir).unwrap_or(path)
+    }
+
+    /// Returns the lines of the screen for a terminal of the given size.
+    fn render(&self, width: usize, height: usize) -> Vec<String> {
+        let state = self.state();
+        let elapsed = self.started.elapsed();
+        let (done, total) = state
+            .progress
+            .map_or((0, 0), |progress| (progress.done, progress.total));
+        let left = match done {
+            0 => "--:--".to_string(),
+            _ => clock(elapsed.mul_f64(total.saturating_sub(done) as f64 / done as f64)),
+        };
+        let status = if interrupt::is_interrupted() {
+            "stopping"
+        } else if interrupt::is_paused() {
+            "paused"
+        } else {
+            "running"
+        };
+        let mut lines = vec![format!(
+            "{}/{} files, {} failed, {} elapsed, {} left ({})",
+            done,
+            total,
+            state.failed,
+            clock(elapsed),
+            left,
+            status
+        )];
+        let bar_width = width.saturating_sub(2);
+        let filled = (bar_width * done).checked_div(total).unwrap_or(0);
+        lines.push(format!(
+            "[{}{}]",
+            "#".repeat(filled),
+            "-".repeat(bar_width - filled)
+        ));
+        lines.push(String::new());
+        lines.push("Running:".to_string());
+        let selected = state.selected.min(state.running.len().saturating_sub(1));
+        lines.extend(
+            state
+                .running
+                .iter()
+                .enumerate()
+                .map(|(i, (path, started))| {
+                    format!(
+                        "{} {} ({})",
+                        if i == selected { '>' } else { ' ' },
+                        self.relative(path).display(),
+                        clock(started.elapsed())
+                    )
+                }),
+        );
+        lines.push(String::new());
+        lines.push("Warnings and failures:".to_string());
+        // Keep the last line for the keys.
+        let room = height.saturating_sub(lines.len() + 1);
+        let skip = state.messages.len().saturating_sub(room);
+        lines.extend(state.messages.iter().skip(skip).cloned());
+        lines.resize(height.saturating_sub(1).max(lines.len()), String::new());
+        lines.push("p pause/resume  up/down select  s skip  q quit".to_string());
+        lines
+    }
+
+    fn handle(&self, key: Key, terminal: &Terminal) {
+        let mut state = self.state();
+        let last = state.running.len().saturating_sub(1);
+        match key {
+            Key::Char('p') => interrupt::set_paused(!interrupt::is_paused()),
+            Key::Up => state.selected = state.selected.min(last).saturating_sub(1),
+            Key::Down => state.selected = (state.selected + 1).min(last),
+            Key::Char('s') => {
+                if let Some((path, _)) = state.running.get(state.selected.min(last)) {
+                    let message = format!("{}: Skipping", self.relative(path).display());
+                    interrupt::skip(path);
+                    state.push(message);
+                }
+            }
+            Key::Char('q') if !interrupt::is_user_interrupt() => interrupt::request(),
+            Key::Interrupt => {
+                // A second Ctrl-C exits right away.
+                if interrupt::is_user_interrupt() {
+                    terminal.leave();
+                    logging::unmute_console();
+                }
+                interrupt::request();
+            }
+            _ => (),
+        }
+    }
+}
+
+impl SyncObserver for Shared {
+    fn task_started(&self, _index: usize, task: &Task) {
+        self.state()
+            .running
+            .push((task.destination.clone(), Instant::now()));
+    }
+
+    fn task_finished(&self, progress: Progress, task: &Task, result: &TaskResult) {
+        let mut state = self.state();
+        state.running.retain(|(path, _)| *path != task.destination);
+        state.progress = Some(progress);
+        if result.status == TaskStatus::Failed {
+            state.failed += 1;
+            if let Some(error) = &result.error {
+                let message = format!("{}: {}", self.relative(&task.destination).display(), error);
+                state.push(message);
+            }
+        }
+    }
+
+    fn warning(&self, _category: WarningCategory, message: &str) {
+        self.state().push(message.to_string());
+    }
+}
+
+/// The screen of a running sync. Dropping it restores the terminal and the console log.
+pub struct Screen {
+    shared: Arc<Shared>,
+    terminal: Arc<Terminal>,
+    threads: Vec<JoinHandle<()>>,
+}
+
+impl Screen {
+    /// Switch the terminal to the screen. The console log is muted until the screen is dropped.
+    pub fn start(output_dir: &Path) -> io::Result<Self> {
+        let terminal = Arc::new(Terminal::enter()?);
+        logging::mute_console();
+        let shared = Arc::new(Shared {
+            state: Mutex::default(),
+            output_dir: output_dir.to_path_buf(),
+            started: Instant::now(),
+            finished: AtomicBool::new(false),
+        });
+        let draw = {
+            let (shared, terminal) = (Arc::clone(&shared), Arc::clone(&terminal));
+            std::thread::spawn(move || {
+                while !shared.finished.load(Ordering::SeqCst) {
+                    let (width, height) = terminal.size();
+                    terminal.draw(&shared.render(width, height));
+                    std::thread::sleep(REFRESH_INTERVAL);
+                }
+            })
+        };
+        let keys = {
+            let (shared, terminal) = (Arc::clone(&shared), Arc::clone(&terminal));
+            std::thread::spawn(move || {
+                while !shared.finished.load(Ordering::SeqCst) {
+                    for key in terminal.keys() {
+                        shared.handle(key, &terminal);
+                    }
+                }
+            })
+        };
+        Ok(Self {
+            shared,
+            terminal,
+            threads: vec![draw, keys],
+        })
+    }
+
+    /// Returns the observer that feeds the screen.
+    pub fn observer(&self) -> Arc<dyn SyncObserver> {
+        self.shared.clone()
+    }
+}
+
+impl Drop for Screen {
+    fn drop(&mut self) {
+        self.shared.finished.store(true, Ordering::SeqCst);
+        for thread in self.threads.drain(..) {
+            let _ = thread.join();
+        }
+        self.terminal.leave();
+        logging::unmute_console();
+        interrupt::set_paused(false);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_keys_and_escape_sequences() {
+        assert_eq!(
+            parse_keys(b"jjs\x1b[Akp"),
+            [
+                Key::Down,
+                Key::Down,
+                Key::Char('s'),
+                Key::Up,
+                Key::Up,
+                Key::Char('p')
+            ]
+        );
+        assert_eq!(parse_keys(b"\x1b[C\x1b"), []);
+        assert_eq!(parse_keys(b"\x03"), [Key::Interrupt]);
+    }
+
+    #[test]
+    fn formats_durations_as_clock() {
+        assert_eq!(clock(Duration::from_secs(5)), "0:05");
+        assert_eq!(clock(Duration::from_secs(125)), "2:05");
+        assert_eq!(clock(Duration::from_secs(3723)), "1:02:03");
+    }
+}
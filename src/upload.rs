@@ -0,0 +1,138 @@
+// Copyright (c) 2024 Jan Holthuis <jan.holthuis@rub.de>
+//
+// This Source Code Form is subject to the terms of the Mozilla Public License, v. 2.0. If a copy
+// of the MPL was not distributed with this file, You can obtain one at
+// http://mozilla.org/MPL/2.0/.
+//
+// SPDX-License-Identifier: MPL-2.0
+//! Shared parts of the remote output targets, i.e. `--output-mtp` and `--output-sftp`.
+//!
+//! Remote targets are synced into a staging directory like archives, whose files are then
+//! uploaded. Files that are already up to date on the target are skipped.
+//!
+//! The staging directory of a target is kept in the user's cache directory between runs, so that
+//! sources that did not change since the last run are not converted again.
+use crate::archive;
+use crate::estimate::ByteSize;
+use crate::longpath;
+use log::debug;
+use std::collections::HashSet;
+use std::fmt;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::time::SystemTime;
+use xxhash_rust::xxh3::xxh3_64;
+
+/// Name of the file in a staging directory with the start time of the last sync into it.
+const STAGED_FILE_NAME: &str = ".ford-sync-convert.staged";
+
+/// Returns the staging directory of uploads to the target with the given key, e.g.
+/// `sftp://host/music`.
+///
+/// It is below `$XDG_CACHE_HOME` or `~/.cache`, and the temporary directory if neither is set.
+pub fn staging_dir(key: &str) -> PathBuf {
+    let cache_dir = std::env::var_os("XDG_CACHE_HOME")
+        .filter(|dir| !dir.is_empty())
+        .map(PathBuf::from)
+        .or_else(|| std::env::var_os("HOME").map(|home| PathBuf::from(home).join(".cache")))
+        .unwrap_or_else(std::env::temp_dir);
+    cache_dir
+        .join("ford-sync-convert")
+        .join("staging")
+        .join(format!("{:016x}", xxh3_64(key.as_bytes())))
+}
+
+/// Returns the start time of the last sync into the staging directory, if there was one.
+pub fn last_staged(staging: &Path) -> Option<SystemTime> {
+    let contents = std::fs::read_to_string(staging.join(STAGED_FILE_NAME)).ok()?;
+    humantime::parse_rfc3339(contents.trim()).ok()
+}
+
+/// Record the start time of a sync into the staging directory, for [`last_staged`].
+pub fn record_staged(staging: &Path, started: SystemTime) -> io::Result<()> {
+    std::fs::write(
+        staging.join(STAGED_FILE_NAME),
+        humantime::format_rfc3339_nanos(started).to_string(),
+    )
+}
+
+/// Remove the files of earlier syncs from the staging directory, except for `keep`, so that
+/// entries that were removed from the playlists are not uploaded again. Returns the number of
+/// removed files.
+pub fn remove_stale(staging: &Path, keep: &HashSet<PathBuf>) -> io::Result<usize> {
+    let files = match archive::files(staging) {
+        Ok(files) => files,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(0),
+        Err(e) => return Err(e),
+    };
+    let mut removed = 0;
+    for path in files.into_iter().map(|file| staging.join(file)) {
+        if !keep.contains(&path) {
+            std::fs::remove_file(longpath::extended(&path))?;
+            debug!("{}: Removed from staging directory", path.display());
+            removed += 1;
+        }
+    }
+    Ok(removed)
+}
+
+/// Counts of an upload.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct UploadSummary {
+    pub uploaded: usize,
+    pub skipped: usize,
+    pub failed: usize,
+    pub bytes: u64,
+}
+
+impl fmt::Display for UploadSummary {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{} uploaded ({}), {} up to date, {} failed",
+            self.uploaded,
+            ByteSize(self.bytes),
+            self.skipped,
+            self.failed
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn staging_dirs_are_per_target() {
+        assert_eq!(
+            staging_dir("sftp://car/music"),
+            staging_dir("sftp://car/music")
+        );
+        assert_ne!(
+            staging_dir("sftp://car/music"),
+            staging_dir("sftp://car/podcasts")
+        );
+    }
+
+    #[test]
+    fn removes_stale_files_and_remembers_the_last_sync() {
+        let dir = std::env::temp_dir().join(format!("upload-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(dir.join("Album")).unwrap();
+        for file in ["Album/a.mp3", "Album/b.mp3", ".ford-sync-convert.lock"] {
+            std::fs::write(dir.join(file), b"DATA").unwrap();
+        }
+        assert_eq!(last_staged(&dir), None);
+        let started = SystemTime::now();
+        record_staged(&dir, started).unwrap();
+        assert_eq!(last_staged(&dir), Some(started));
+
+        let keep = HashSet::from([dir.join("Album/a.mp3")]);
+        assert_eq!(remove_stale(&dir, &keep).unwrap(), 1);
+        assert!(dir.join("Album/a.mp3").exists());
+        assert!(!dir.join("Album/b.mp3").exists());
+        assert!(dir.join(".ford-sync-convert.lock").exists());
+        assert_eq!(last_staged(&dir), Some(started));
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
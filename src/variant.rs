@@ -0,0 +1,208 @@
+// Copyright (c) 2024 Jan Holthuis <jan.holthuis@rub.de>
+//
+// This Source Code Form is subject to the terms of the Mozilla Public License, v. 2.0. If a copy
+// of the MPL was not distributed with this file, You can obtain one at
+// http://mozilla.org/MPL/2.0/.
+//
+// SPDX-License-Identifier: MPL-2.0
+//! Several quality variants of the output in one run, for `--variant`.
+//!
+//! A variant like `car:mp3,q=2,dir=/media/usb` names an output directory and the encoder settings
+//! of the conversions written to it. All variants share one plan, so that each source is read and
+//! probed once, and each of them has its own playlists, report and mapping file.
+use crate::plan::{Action, Plan};
+use crate::rules::{parse_bitrate, EncoderSettings};
+use std::path::{Path, PathBuf};
+
+/// Codecs that variants can be encoded with.
+const CODECS: [&str; 1] = ["mp3"];
+
+/// A named output directory with encoder settings, given as `NAME:CODEC[,OPTION=VALUE]...`.
+///
+/// The options are `q` for the VBR quality, `b` for a constant bitrate like `96k`, `ac` for the
+/// number of channels and `dir` for the output directory.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Variant {
+    pub name: String,
+    pub dir: PathBuf,
+    pub encoder: EncoderSettings,
+}
+
+/// Expand a leading `~` to the home directory, since the shell leaves it alone after `dir=`.
+fn expand_home(path: &str) -> PathBuf {
+    let home = std::env::var_os("HOME");
+    match (path.strip_prefix('~'), home) {
+        (Some(rest), Some(home)) if rest.is_empty() || rest.starts_with('/') => {
+            PathBuf::from(home).join(rest.trim_start_matches('/'))
+        }
+        _ => PathBuf::from(path),
+    }
+}
+
+impl std::str::FromStr for Variant {
+    type Err = String;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        let (name, spec) = value
+            .split_once(':')
+            .filter(|(name, _)| !name.is_empty())
+            .ok_or_else(|| {
+                format!(
+                    "invalid variant `{}`, expected e.g. `car:mp3,q=2,dir=/media/usb`",
+                    value
+                )
+            })?;
+        if !name
+            .chars()
+            .all(|c| c.is_alphanumeric() || c == '-' || c == '_')
+        {
+            return Err(format!(
+                "invalid variant name `{}`, expected letters, digits, `-` and `_`",
+                name
+            ));
+        }
+        let mut options = spec.split(',');
+        let codec = options.next().unwrap_or_default().to_lowercase();
+        if !CODECS.contains(&codec.as_str()) {
+            return Err(format!(
+                "{}: unsupported codec `{}`, expected one of {}",
+                name,
+                codec,
+                CODECS.join(", ")
+            ));
+        }
+        let mut encoder = EncoderSettings::default();
+        let mut dir = None;
+        for option in options {
+            let (key, value) = option.split_once('=').ok_or_else(|| {
+                format!(
+                    "{}: invalid option `{}`, expected `KEY=VALUE`",
+                    name, option
+                )
+            })?;
+            let number = |value: &str| {
+                value.parse().map_err(|_| {
+                    format!("{}: invalid {} `{}`, expected a number", name, key, value)
+                })
+            };
+            match key {
+                "q" => encoder.quality = Some(number(value)?),
+                "b" => {
+                    let kbps = value.strip_suffix(['k', 'K']).unwrap_or(value);
+                    encoder.bitrate =
+                        Some(parse_bitrate(kbps).map_err(|e| format!("{}: {}", name, e))?);
+                }
+                "ac" => encoder.channels = Some(number(value)?),
+                "dir" if !value.is_empty() => dir = Some(expand_home(value)),
+                _ => {
+                    return Err(format!(
+                        "{}: unknown option `{}`, expected `q`, `b`, `ac` or `dir`",
+                        name, key
+                    ))
+                }
+            }
+        }
+        encoder.validate().map_err(|e| format!("{}: {}", name, e))?;
+        Ok(Self {
+            name: name.to_string(),
+            dir: dir.ok_or_else(|| format!("{}: missing output directory `dir=PATH`", name))?,
+            encoder,
+        })
+    }
+}
+
+impl Variant {
+    /// Move the plan, whose output paths are in `output_dir`, to the directory of the variant.
+    ///
+    /// Conversions that no rule applies to are encoded with the settings of the variant, and MP3
+    /// sources that would be remuxed are converted if the variant sets any.
+    pub fn apply(&self, plan: &mut Plan, output_dir: &Path) {
+        plan.relocate(output_dir, &self.dir);
+        if self.encoder == EncoderSettings::default() {
+            return;
+        }
+        for task in plan.tasks.iter_mut() {
+            match task.action {
+                Action::Convert if task.encoder.is_none() => task.encoder = Some(self.encoder),
+                Action::Remux => {
+                    task.action = Action::Convert;
+                    task.encoder.get_or_insert(self.encoder);
+                }
+                _ => (),
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::plan::Task;
+
+    #[test]
+    fn parses_variants() {
+        assert_eq!(
+            "car:mp3,q=2,dir=/media/usb".parse::<Variant>(),
+            Ok(Variant {
+                name: "car".to_string(),
+                dir: PathBuf::from("/media/usb"),
+                encoder: EncoderSettings {
+                    quality: Some(2),
+                    ..Default::default()
+                },
+            })
+        );
+        let phone: Variant = "phone:MP3,b=96k,ac=1,dir=Phone".parse().unwrap();
+        assert_eq!(phone.encoder.to_string(), "96 kbit/s, mono");
+        let error = |value: &str| value.parse::<Variant>().unwrap_err();
+        assert!(error("phone:aac,dir=Phone").contains("unsupported codec `aac`"));
+        assert!(error("phone:mp3,b=100k,dir=Phone").contains("192"));
+        assert!(error("phone:mp3,q=2,b=96k,dir=Phone").contains("cannot be combined"));
+        assert!(error("phone:mp3,q=2").contains("dir=PATH"));
+        assert!(error("phone:mp3,x=1,dir=Phone").contains("unknown option `x`"));
+        assert!(error("a/b:mp3,dir=Phone").contains("name"));
+        assert!(error("mp3").contains("expected e.g."));
+    }
+
+    #[test]
+    fn encodes_conversions_without_a_rule() {
+        let task = |action, encoder| Task {
+            action,
+            source: PathBuf::from("a"),
+            destination: PathBuf::from("output/a.mp3"),
+            tags: Default::default(),
+            encoder,
+            segment: None,
+        };
+        let rule = EncoderSettings {
+            quality: Some(0),
+            ..Default::default()
+        };
+        let mut plan = Plan {
+            tasks: vec![
+                task(Action::Convert, None),
+                task(Action::Convert, Some(rule)),
+                task(Action::Remux, None),
+                task(Action::Copy, None),
+            ],
+            ..Default::default()
+        };
+        let variant: Variant = "phone:mp3,b=96k,dir=phone".parse().unwrap();
+        variant.apply(&mut plan, Path::new("output"));
+        let actions: Vec<_> = plan
+            .tasks
+            .iter()
+            .map(|task| (task.action, task.encoder))
+            .collect();
+        assert_eq!(
+            actions,
+            [
+                (Action::Convert, Some(variant.encoder)),
+                (Action::Convert, Some(rule)),
+                (Action::Convert, Some(variant.encoder)),
+                (Action::Copy, None),
+            ]
+        );
+        assert_eq!(plan.tasks[0].destination, Path::new("phone/a.mp3"));
+    }
+}
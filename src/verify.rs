@@ -0,0 +1,341 @@
+// Copyright (c) 2024 Jan Holthuis <jan.holthuis@rub.de>
+//
+// This Source Code Form is subject to the terms of the Mozilla Public License, v. 2.0. If a copy
+// of the MPL was not distributed with this file, You can obtain one at
+// http://mozilla.org/MPL/2.0/.
+//
+// SPDX-License-Identifier: MPL-2.0
+//! Audit of an already synced output directory against the plan.
+use crate::longpath;
+use crate::plan::{Action, Plan, PlaylistOutput};
+use crate::playlist;
+use crate::probe;
+use crate::target::TargetPath;
+use std::collections::HashSet;
+use std::fmt;
+use std::path::{Path, PathBuf};
+
+#[derive(Clone, Copy, Debug)]
+pub struct VerifyOptions {
+    /// Probe every output file and compare its duration with the source.
+    pub deep: bool,
+    /// Maximum duration difference in seconds.
+    pub tolerance: f64,
+    /// Copies are expected to have the same size as their source, i.e. their tags are not
+    /// rewritten.
+    pub compare_copy_sizes: bool,
+}
+
+/// A problem found on the output medium.
+#[derive(Clone, Debug, PartialEq)]
+pub enum Problem {
+    PlaylistMissing,
+    PlaylistUnreadable(String),
+    /// An entry of the playlist on the medium does not resolve to an existing file.
+    DanglingEntry(String),
+    /// A planned entry is not in the playlist on the medium.
+    NotInPlaylist(String),
+    OutputMissing(PathBuf),
+    OutputEmpty(PathBuf),
+    SizeMismatch {
+        path: PathBuf,
+        expected: u64,
+        actual: u64,
+    },
+    Undecodable {
+        path: PathBuf,
+        reason: String,
+    },
+    DurationMismatch {
+        path: PathBuf,
+        expected: f64,
+        actual: f64,
+    },
+}
+
+impl fmt::Display for Problem {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Problem::PlaylistMissing => write!(f, "Playlist is missing"),
+            Problem::PlaylistUnreadable(e) => write!(f, "Failed to read playlist ({})", e),
+            Problem::DanglingEntry(entry) => write!(f, "{}: Entry does not exist", entry),
+            Problem::NotInPlaylist(entry) => write!(f, "{}: Entry is missing from playlist", entry),
+            Problem::OutputMissing(path) => write!(f, "{}: File is missing", path.display()),
+            Problem::OutputEmpty(path) => write!(f, "{}: File is empty", path.display()),
+            Problem::SizeMismatch {
+                path,
+                expected,
+                actual,
+            } => write!(
+                f,
+                "{}: File has {} bytes instead of {}",
+                path.display(),
+                actual,
+                expected
+            ),
+            Problem::Undecodable { path, reason } => {
+                write!(f, "{}: File is not decodable ({})", path.display(), reason)
+            }
+            Problem::DurationMismatch {
+                path,
+                expected,
+                actual,
+            } => write!(
+                f,
+                "{}: Duration {:.1}s differs from source duration {:.1}s",
+                path.display(),
+                actual,
+                expected
+            ),
+        }
+    }
+}
+
+/// Result of verifying one output playlist.
+#[derive(Clone, Debug)]
+pub struct PlaylistVerification {
+    pub path: PathBuf,
+    pub entries: usize,
+    pub problems: Vec<Problem>,
+}
+
+/// Read the entries of a playlist on the medium.
+pub fn read_entries(path: &Path) -> Result<Vec<String>, Problem> {
+    let path = longpath::extended(path);
+    if !path.exists() {
+        return Err(Problem::PlaylistMissing);
+    }
+    let contents = std::fs::read(&path).map_err(|e| Problem::PlaylistUnreadable(e.to_string()))?;
+    let contents = playlist::decode(contents);
+    let mut reader = m3u::Reader::new(contents.as_bytes());
+    let entries = reader
+        .entries()
+        .filter_map(|entry| match entry {
+            Ok(m3u::Entry::Path(path)) => Some(path.to_string_lossy().into_owned()),
+            _ => None,
+        })
+        .collect();
+    Ok(entries)
+}
+
+/// Check a single planned output file.
+fn verify_file(plan: &Plan, task: usize, options: &VerifyOptions) -> Option<Problem> {
+    let task = &plan.tasks[task];
+    let destination = task.destination.clone();
+    let Ok(metadata) = std::fs::metadata(longpath::extended(&destination)) else {
+        return Some(Problem::OutputMissing(destination));
+    };
+    if metadata.len() == 0 {
+        return Some(Problem::OutputEmpty(destination));
+    }
+    if task.action == Action::Copy && options.compare_copy_sizes && task.tags.is_empty() {
+        if let Ok(source) = std::fs::metadata(longpath::extended(&task.source)) {
+            if source.len() != metadata.len() {
+                return Some(Problem::SizeMismatch {
+                    path: destination,
+                    expected: source.len(),
+                    actual: metadata.len(),
+                });
+            }
+        }
+    }
+    if !options.deep {
+        return None;
+    }
+    let info = match probe::probe(&longpath::extended(&destination)) {
+        Ok(info) if info.audio_streams > 0 => info,
+        Ok(_) => {
+            return Some(Problem::Undecodable {
+                path: destination,
+                reason: "no audio stream".to_string(),
+            })
+        }
+        Err(e) => {
+            return Some(Problem::Undecodable {
+                path: destination,
+                reason: e.to_string(),
+            })
+        }
+    };
+    let source_duration = plan
+        .metadata
+        .get(&task.source)
+        .and_then(|info| info.duration)
+        .or_else(|| probe::probe(&task.source).ok()?.duration);
+    match (source_duration, info.duration) {
+        (Some(expected), Some(actual)) if (expected - actual).abs() > options.tolerance => {
+            Some(Problem::DurationMismatch {
+                path: destination,
+                expected,
+                actual,
+            })
+        }
+        _ => None,
+    }
+}
+
+fn verify_playlist(
+    plan: &Plan,
+    playlist: &PlaylistOutput,
+    options: &VerifyOptions,
+) -> PlaylistVerification {
+    let mut problems = vec![];
+    let dir = playlist.path.parent().unwrap_or(Path::new("."));
+    let planned: HashSet<&str> = playlist
+        .entries
+        .iter()
+        .map(|entry| entry.target.as_str())
+        .collect();
+    match read_entries(&playlist.path) {
+        Ok(entries) => {
+            let present: HashSet<&str> = entries.iter().map(String::as_str).collect();
+            for entry in entries
+                .iter()
+                .filter(|entry| !planned.contains(entry.as_str()))
+            {
+                if !longpath::extended(&TargetPath::parse(entry).on_disk(dir)).exists() {
+                    problems.push(Problem::DanglingEntry(entry.clone()));
+                }
+            }
+            for entry in playlist.entries.iter() {
+                if !present.contains(entry.target.as_str()) {
+                    problems.push(Problem::NotInPlaylist(entry.target.clone()));
+                }
+            }
+        }
+        Err(problem) => problems.push(problem),
+    }
+    let mut checked = HashSet::new();
+    for entry in playlist.entries.iter() {
+        if checked.insert(entry.task) {
+            problems.extend(verify_file(plan, entry.task, options));
+        }
+    }
+    PlaylistVerification {
+        path: playlist.path.clone(),
+        entries: playlist.entries.len(),
+        problems,
+    }
+}
+
+/// Verify all output playlists of the plan and the files they refer to.
+pub fn verify(plan: &Plan, options: &VerifyOptions) -> Vec<PlaylistVerification> {
+    plan.playlists
+        .iter()
+        .map(|playlist| verify_playlist(plan, playlist, options))
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::plan::{OutputEntry, Task};
+    use crate::tags::TagOverrides;
+
+    const OPTIONS: VerifyOptions = VerifyOptions {
+        deep: false,
+        tolerance: 1.0,
+        compare_copy_sizes: true,
+    };
+
+    fn scratch_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("verify-{}-{}", name, std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(dir.join("music")).unwrap();
+        std::fs::create_dir_all(dir.join("output/Album")).unwrap();
+        dir
+    }
+
+    fn plan(dir: &Path) -> Plan {
+        let entries = ["Album\\a.mp3", "Album\\b.mp3"];
+        Plan {
+            playlists: vec![PlaylistOutput {
+                source: dir.join("music/playlist.m3u"),
+                path: dir.join("output/playlist.m3u"),
+                entries: entries
+                    .iter()
+                    .enumerate()
+                    .map(|(task, target)| OutputEntry {
+                        target: target.to_string(),
+                        task,
+                    })
+                    .collect(),
+                skipped: vec![],
+                title: None,
+            }],
+            tasks: ["a", "b"]
+                .iter()
+                .map(|name| Task {
+                    action: Action::Copy,
+                    source: dir.join(format!("music/{}.mp3", name)),
+                    destination: dir.join(format!("output/Album/{}.mp3", name)),
+                    tags: TagOverrides::default(),
+                    encoder: None,
+                    segment: None,
+                })
+                .collect(),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn resolves_backslashes() {
+        assert_eq!(
+            TargetPath::parse("Album\\a.mp3").on_disk(Path::new("/media/usb")),
+            PathBuf::from("/media/usb/Album/a.mp3")
+        );
+    }
+
+    #[test]
+    fn complete_output_passes() {
+        let dir = scratch_dir("complete");
+        for name in ["a", "b"] {
+            std::fs::write(dir.join(format!("music/{}.mp3", name)), "DATA").unwrap();
+            std::fs::write(dir.join(format!("output/Album/{}.mp3", name)), "DATA").unwrap();
+        }
+        std::fs::write(
+            dir.join("output/playlist.m3u"),
+            "Album\\a.mp3\nAlbum\\b.mp3\n",
+        )
+        .unwrap();
+        let results = verify(&plan(&dir), &OPTIONS);
+        std::fs::remove_dir_all(&dir).unwrap();
+        assert_eq!(results[0].entries, 2);
+        assert_eq!(results[0].problems, vec![]);
+    }
+
+    #[test]
+    fn finds_problems() {
+        let dir = scratch_dir("problems");
+        std::fs::write(dir.join("music/a.mp3"), "DATA").unwrap();
+        std::fs::write(dir.join("output/Album/a.mp3"), "DAT").unwrap();
+        std::fs::write(
+            dir.join("output/playlist.m3u"),
+            "Album\\a.mp3\nOld\\c.mp3\n",
+        )
+        .unwrap();
+        let results = verify(&plan(&dir), &OPTIONS);
+        std::fs::remove_dir_all(&dir).unwrap();
+        assert_eq!(
+            results[0].problems,
+            vec![
+                Problem::DanglingEntry("Old\\c.mp3".to_string()),
+                Problem::NotInPlaylist("Album\\b.mp3".to_string()),
+                Problem::SizeMismatch {
+                    path: dir.join("output/Album/a.mp3"),
+                    expected: 4,
+                    actual: 3,
+                },
+                Problem::OutputMissing(dir.join("output/Album/b.mp3")),
+            ]
+        );
+    }
+
+    #[test]
+    fn missing_playlist() {
+        let dir = scratch_dir("missing");
+        let results = verify(&plan(&dir), &OPTIONS);
+        std::fs::remove_dir_all(&dir).unwrap();
+        assert_eq!(results[0].problems[0], Problem::PlaylistMissing);
+    }
+}
@@ -0,0 +1,173 @@
+// Copyright (c) 2024 Jan Holthuis <jan.holthuis@rub.de>
+//
+// This Source Code Form is subject to the terms of the Mozilla Public License, v. 2.0. If a copy
+// of the MPL was not distributed with this file, You can obtain one at
+// http://mozilla.org/MPL/2.0/.
+//
+// SPDX-License-Identifier: MPL-2.0
+//! Collection of warnings for `--strict` mode.
+//!
+//! Conditions that make the output incomplete are reported through [`report_warning!`] instead of
+//! plain `warn!` calls, so that they can be listed at the end of the run and turned into a
+//! failure.
+use crate::exit;
+use crate::interrupt;
+use crate::observer::{Observers, SyncObserver};
+use clap::ValueEnum;
+use log::error;
+use std::fmt;
+use std::sync::{Mutex, OnceLock};
+
+/// What to do when a warning is reported in strict mode.
+#[derive(ValueEnum, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum StrictMode {
+    /// Stop the run at the first warning.
+    Abort,
+    /// Finish the run, then fail with a list of all warnings.
+    Collect,
+}
+
+/// Kind of condition that caused a warning.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub enum WarningCategory {
+    UnreadableEntry,
+    AmbiguousEntry,
+    Url,
+    MissingExtension,
+    Probe,
+    Conversion,
+    Verification,
+    Copy,
+    Space,
+    Filesystem,
+    TooLarge,
+    PathTooLong,
+    PlaylistEncoding,
+    ExistingPlaylist,
+    ServerPlaylist,
+    IndexLimits,
+    CorruptSource,
+    Tags,
+    Art,
+    Hook,
+    Io,
+}
+
+impl fmt::Display for WarningCategory {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let name = match self {
+            WarningCategory::UnreadableEntry => "Unreadable playlist entries",
+            WarningCategory::AmbiguousEntry => "Ambiguous playlist entries",
+            WarningCategory::Url => "Skipped URLs",
+            WarningCategory::MissingExtension => "Missing file extensions",
+            WarningCategory::Probe => "Probe failures",
+            WarningCategory::Conversion => "Failed conversions",
+            WarningCategory::Verification => "Failed verifications",
+            WarningCategory::Copy => "Failed copies",
+            WarningCategory::Space => "Insufficient free space",
+            WarningCategory::Filesystem => "Unsupported target filesystem",
+            WarningCategory::TooLarge => "Files too large for the target filesystem",
+            WarningCategory::PathTooLong => "Paths too long for the head unit",
+            WarningCategory::PlaylistEncoding => {
+                "Entries not representable in the playlist encoding"
+            }
+            WarningCategory::ExistingPlaylist => "Existing playlists left unchanged",
+            WarningCategory::ServerPlaylist => "Skipped server playlists",
+            WarningCategory::IndexLimits => "Exceeded indexing limits",
+            WarningCategory::CorruptSource => "Corrupt or empty sources",
+            WarningCategory::Tags => "Failed tag rewrites",
+            WarningCategory::Art => "Failed album art",
+            WarningCategory::Hook => "Failed hooks",
+            WarningCategory::Io => "I/O errors",
+        };
+        f.write_str(name)
+    }
+}
+
+static STRICT_MODE: OnceLock<StrictMode> = OnceLock::new();
+static WARNINGS: Mutex<Vec<(WarningCategory, String)>> = Mutex::new(Vec::new());
+static OBSERVERS: Mutex<Observers> = Mutex::new(Observers::new());
+
+/// Forwards reported warnings to observers until it is dropped.
+pub(crate) struct ForwardWarnings(Observers);
+
+/// Forward all warnings that are reported from now on to the observers.
+pub(crate) fn forward_to(observers: &Observers) -> ForwardWarnings {
+    OBSERVERS
+        .lock()
+        .unwrap_or_else(|e| e.into_inner())
+        .extend(observers);
+    ForwardWarnings(observers.clone())
+}
+
+impl Drop for ForwardWarnings {
+    fn drop(&mut self) {
+        OBSERVERS
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .remove(&self.0);
+    }
+}
+
+/// Enable strict mode.
+pub fn set_strict(mode: StrictMode) {
+    let _ = STRICT_MODE.set(mode);
+}
+
+/// Record a warning that has already been logged.
+///
+/// Use [`report_warning!`] instead of calling this directly.
+pub fn record(category: WarningCategory, message: String) {
+    WARNINGS
+        .lock()
+        .unwrap_or_else(|e| e.into_inner())
+        .push((category, message.clone()));
+    // Don't hold the lock while notifying, observers may report warnings themselves.
+    let observers = OBSERVERS.lock().unwrap_or_else(|e| e.into_inner()).clone();
+    observers.warning(category, &message);
+    if STRICT_MODE.get() == Some(&StrictMode::Abort) {
+        interrupt::stop();
+    }
+}
+
+/// Log a warning and record it for strict mode.
+#[macro_export]
+macro_rules! report_warning {
+    ($category:expr, $($arg:tt)+) => {{
+        let message = format!($($arg)+);
+        log::warn!("{}", message);
+        $crate::warnings::record($category, message);
+    }};
+}
+pub use report_warning;
+
+/// Apply strict mode to the exit status of a run.
+///
+/// If strict mode is enabled and any warnings were reported, they are listed by category and a
+/// failure status is returned.
+pub fn finish(status: i32) -> i32 {
+    if STRICT_MODE.get().is_none() {
+        return status;
+    }
+    let mut warnings = WARNINGS.lock().unwrap_or_else(|e| e.into_inner()).clone();
+    if warnings.is_empty() {
+        return status;
+    }
+    warnings.sort_by_key(|(category, _)| *category);
+
+    error!("Strict mode: {} warnings were reported", warnings.len());
+    let mut current = None;
+    for (category, message) in warnings.iter() {
+        if current != Some(category) {
+            error!("{}:", category);
+            current = Some(category);
+        }
+        error!("  {}", message);
+    }
+
+    if status == exit::SUCCESS || status == exit::INTERRUPTED && !interrupt::is_user_interrupt() {
+        exit::FAILURES
+    } else {
+        status
+    }
+}
@@ -0,0 +1,136 @@
+// Copyright (c) 2024 Jan Holthuis <jan.holthuis@rub.de>
+//
+// This Source Code Form is subject to the terms of the Mozilla Public License, v. 2.0. If a copy
+// of the MPL was not distributed with this file, You can obtain one at
+// http://mozilla.org/MPL/2.0/.
+//
+// SPDX-License-Identifier: MPL-2.0
+//! Watching the input playlists for changes in `--watch` mode.
+//!
+//! Changes are detected by polling modification times, which works the same on every platform
+//! and filesystem (including network shares).
+use crate::interrupt;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant, SystemTime};
+
+/// How often the playlists are checked for changes.
+const POLL_INTERVAL: Duration = Duration::from_millis(500);
+
+/// How long the playlists have to stay unchanged before a change is reported.
+const DEBOUNCE: Duration = Duration::from_secs(2);
+
+/// Modification times of a playlist and, optionally, its directory.
+type Stamp = (Option<SystemTime>, Option<SystemTime>);
+
+/// Polls a set of playlists for changes.
+pub struct Watcher {
+    playlists: Vec<(PathBuf, Stamp)>,
+    watch_dirs: bool,
+}
+
+impl Watcher {
+    /// Start watching the playlists. With `watch_dirs`, changes to the directory of a playlist
+    /// (e.g. added or renamed files) also count as a change of the playlist.
+    pub fn new(playlists: &[PathBuf], watch_dirs: bool) -> Self {
+        let playlists = playlists
+            .iter()
+            .map(|path| (path.clone(), stamp(path, watch_dirs)))
+            .collect();
+        Self {
+            playlists,
+            watch_dirs,
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.playlists.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.playlists.is_empty()
+    }
+
+    /// Returns the playlists that changed since the last call.
+    fn changed(&mut self) -> Vec<PathBuf> {
+        let mut changed = vec![];
+        for (path, last) in self.playlists.iter_mut() {
+            let current = stamp(path, self.watch_dirs);
+            if current != *last {
+                *last = current;
+                changed.push(path.clone());
+            }
+        }
+        changed
+    }
+
+    /// Block until some playlists changed and then stayed unchanged for a moment, and return
+    /// them in their original order. Returns `None` if the run was interrupted.
+    pub fn wait(&mut self) -> Option<Vec<PathBuf>> {
+        let mut changed = vec![];
+        let mut last_change = Instant::now();
+        loop {
+            std::thread::sleep(POLL_INTERVAL);
+            if interrupt::is_interrupted() {
+                return None;
+            }
+            let new = self.changed();
+            if !new.is_empty() {
+                changed.extend(new);
+                last_change = Instant::now();
+            } else if !changed.is_empty() && last_change.elapsed() >= DEBOUNCE {
+                break;
+            }
+        }
+        Some(
+            self.playlists
+                .iter()
+                .map(|(path, _)| path)
+                .filter(|path| changed.contains(path))
+                .cloned()
+                .collect(),
+        )
+    }
+}
+
+fn modified(path: &Path) -> Option<SystemTime> {
+    std::fs::metadata(path).and_then(|m| m.modified()).ok()
+}
+
+fn stamp(path: &Path, watch_dirs: bool) -> Stamp {
+    let dir = watch_dirs.then(|| path.parent()).flatten().map(|dir| {
+        if dir.as_os_str().is_empty() {
+            Path::new(".")
+        } else {
+            dir
+        }
+    });
+    (modified(path), dir.and_then(modified))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn detects_modified_and_removed_playlists() {
+        let dir = std::env::temp_dir().join(format!("ford-sync-watch-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let a = dir.join("a.m3u");
+        let b = dir.join("b.m3u");
+        std::fs::write(&a, "a.mp3").unwrap();
+        std::fs::write(&b, "b.mp3").unwrap();
+
+        let mut watcher = Watcher::new(&[a.clone(), b.clone()], false);
+        assert!(watcher.changed().is_empty());
+
+        let file = std::fs::File::options().write(true).open(&a).unwrap();
+        file.set_modified(SystemTime::now() + Duration::from_secs(60))
+            .unwrap();
+        assert_eq!(watcher.changed(), vec![a.clone()]);
+        assert!(watcher.changed().is_empty());
+
+        std::fs::remove_file(&b).unwrap();
+        assert_eq!(watcher.changed(), vec![b]);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,1162 @@
+// Copyright (c) 2024 Jan Holthuis <jan.holthuis@rub.de>
+//
+// This Source Code Form is subject to the terms of the Mozilla Public License, v. 2.0. If a copy
+// of the MPL was not distributed with this file, You can obtain one at
+// http://mozilla.org/MPL/2.0/.
+//
+// SPDX-License-Identifier: MPL-2.0
+//! Argument parsing and subcommands.
+mod common;
+
+use common::{run, run_with_path, scratch_dir, write_music};
+use std::path::Path;
+use std::process::{Child, Command, Stdio};
+use std::time::{Duration, Instant};
+
+fn stdout(output: std::process::Output) -> String {
+    String::from_utf8(output.stdout).unwrap()
+}
+
+/// Start the binary in `dir` like [`run`], without waiting for it, e.g. for `--watch`.
+fn spawn(dir: &Path, args: &[&str]) -> Child {
+    let empty_path = dir.join("bin");
+    std::fs::create_dir_all(&empty_path).unwrap();
+    Command::new(env!("CARGO_BIN_EXE_ford-sync-convert"))
+        .args(args)
+        .current_dir(dir)
+        .env("PATH", &empty_path)
+        .env("XDG_CONFIG_HOME", dir)
+        .env("XDG_CACHE_HOME", dir.join("cache"))
+        .env("RUST_LOG", "off")
+        .stdin(Stdio::null())
+        .stdout(Stdio::null())
+        .stderr(Stdio::null())
+        .spawn()
+        .unwrap()
+}
+
+/// Poll `condition` until it holds, panicking with `what` after 20 seconds.
+fn wait_until(what: &str, mut condition: impl FnMut() -> bool) {
+    let started = Instant::now();
+    while !condition() {
+        assert!(
+            started.elapsed() < Duration::from_secs(20),
+            "timed out waiting until {}",
+            what
+        );
+        std::thread::sleep(Duration::from_millis(100));
+    }
+}
+
+#[test]
+fn help_lists_subcommands() {
+    let dir = scratch_dir("help");
+    let help = stdout(run(&dir, &["--help"]));
+    for subcommand in ["convert", "plan", "apply", "doctor"] {
+        assert!(help.contains(&format!("  {} ", subcommand)), "{}", help);
+    }
+    assert!(help.contains("--output-dir"));
+
+    let help = stdout(run(&dir, &["plan", "--help"]));
+    assert!(help.contains("--plan-file <PATH>"), "{}", help);
+    let help = stdout(run(&dir, &["apply", "--help"]));
+    assert!(help.contains("<PLAN>"), "{}", help);
+}
+
+#[test]
+fn convert_is_the_default() {
+    let dir = scratch_dir("default");
+    write_music(&dir, &["a.mp3"], &["a.mp3"]);
+    let implicit = run(&dir, &["-n", "-o", "out", "music/playlist.m3u"]);
+    let explicit = run(&dir, &["convert", "-n", "-o", "out", "music/playlist.m3u"]);
+    assert!(implicit.status.success());
+    assert_eq!(implicit.stdout, explicit.stdout);
+    assert!(stdout(implicit).contains("Would write playlist"));
+}
+
+#[test]
+fn options_of_subcommands_follow_their_name() {
+    let dir = scratch_dir("order");
+    write_music(&dir, &["a.mp3"], &["a.mp3"]);
+    let output = run(&dir, &["-n", "convert", "music/playlist.m3u"]);
+    assert_eq!(output.status.code(), Some(2));
+    let output = run(&dir, &["plan", "music/playlist.m3u"]);
+    assert_eq!(output.status.code(), Some(2));
+}
+
+#[test]
+fn plan_and_apply() {
+    let dir = scratch_dir("apply");
+    write_music(&dir, &["a.mp3"], &["a.mp3"]);
+    let output = run(
+        &dir,
+        &[
+            "plan",
+            "--plan-file",
+            "plan.json",
+            "-o",
+            "out",
+            "--retries",
+            "1",
+            "music/playlist.m3u",
+        ],
+    );
+    assert!(output.status.success());
+    assert!(!dir.join("out").exists());
+
+    let plan: serde_json::Value =
+        serde_json::from_str(&std::fs::read_to_string(dir.join("plan.json")).unwrap()).unwrap();
+    assert_eq!(plan["settings"]["retries"], 1);
+    assert_eq!(plan["plan"]["tasks"][0]["action"], "copy");
+
+    let config = stdout(run(&dir, &["apply", "plan.json", "--print-config"]));
+    assert!(
+        config.contains("retries = 1 # plan file plan.json"),
+        "{}",
+        config
+    );
+
+    let output = run(&dir, &["apply", "plan.json", "-o", "elsewhere"]);
+    assert!(output.status.success());
+    assert!(dir.join("elsewhere/a.mp3").exists());
+    assert!(dir.join("elsewhere/playlist.m3u").exists());
+    assert!(!dir.join("out").exists());
+}
+
+#[test]
+fn apply_rejects_invalid_plan_files() {
+    let dir = scratch_dir("invalid-plan");
+    assert_eq!(run(&dir, &["apply", "missing.json"]).status.code(), Some(2));
+    std::fs::write(dir.join("plan.json"), "{\"plan_version\": 0}").unwrap();
+    assert_eq!(run(&dir, &["apply", "plan.json"]).status.code(), Some(2));
+}
+
+#[test]
+fn apply_rejects_changed_files() {
+    let dir = scratch_dir("stale-plan");
+    write_music(&dir, &["a.mp3", "b.mp3"], &["a.mp3", "b.mp3"]);
+    let plan = [
+        "plan",
+        "--plan-file",
+        "plan.json",
+        "-o",
+        "out",
+        "music/playlist.m3u",
+    ];
+    assert!(run(&dir, &plan).status.success());
+    std::fs::create_dir_all(dir.join("out")).unwrap();
+    std::fs::write(dir.join("out/a.mp3"), "OTHER").unwrap();
+    assert_eq!(run(&dir, &["apply", "plan.json"]).status.code(), Some(2));
+    assert_eq!(
+        std::fs::read_to_string(dir.join("out/a.mp3")).unwrap(),
+        "OTHER"
+    );
+
+    assert!(run(&dir, &plan).status.success());
+    std::fs::remove_file(dir.join("music/b.mp3")).unwrap();
+    assert_eq!(run(&dir, &["apply", "plan.json"]).status.code(), Some(2));
+    assert!(!dir.join("out/playlist.m3u").exists());
+}
+
+#[test]
+fn doctor_reports_invalid_config() {
+    let dir = scratch_dir("doctor");
+    std::fs::write(dir.join("config.toml"), "no_such_option = true\n").unwrap();
+    let output = run(&dir, &["doctor", "--config", "config.toml", "-o", "."]);
+    assert_eq!(output.status.code(), Some(3));
+    let report = stdout(output);
+    assert!(report.contains("[fail] FFmpeg: "), "{}", report);
+    assert!(report.contains("[fail] Configuration: "), "{}", report);
+    assert!(report.contains("[pass] Output directory: "), "{}", report);
+}
+
+#[test]
+fn rejects_invalid_rules_at_startup() {
+    let dir = scratch_dir("rules");
+    write_music(&dir, &["a.mp3"], &["a.mp3"]);
+    let args = ["--config", "config.toml", "-o", "out", "music/playlist.m3u"];
+    std::fs::write(dir.join("config.toml"), "[rules]\nflac = { quality = 0 }\n").unwrap();
+    assert!(run(&dir, &args).status.success());
+
+    std::fs::write(
+        dir.join("config.toml"),
+        "[rules]\nflac = { bitrate = 100 }\n",
+    )
+    .unwrap();
+    std::fs::remove_dir_all(dir.join("out")).unwrap();
+    assert_eq!(run(&dir, &args).status.code(), Some(2));
+    assert!(!dir.join("out").exists());
+}
+
+#[test]
+fn verify_finds_missing_files() {
+    let dir = scratch_dir("verify");
+    write_music(&dir, &["a.mp3", "b.mp3"], &["a.mp3", "b.mp3"]);
+    let args = ["-o", "out", "music/playlist.m3u"];
+    assert!(run(&dir, &args).status.success());
+    let output = run(&dir, &[&["verify"][..], &args].concat());
+    assert_eq!(output.status.code(), Some(0));
+    assert!(stdout(output).starts_with("[pass] out/playlist.m3u: 2 entries"));
+
+    std::fs::remove_file(dir.join("out/b.mp3")).unwrap();
+    let output = run(&dir, &[&["verify"][..], &args].concat());
+    assert_eq!(output.status.code(), Some(1));
+    assert!(stdout(output).contains("out/b.mp3: File is missing"));
+}
+
+#[test]
+fn playlists_from_file_are_merged_without_duplicates() {
+    let dir = scratch_dir("playlists-from");
+    write_music(&dir, &["a.mp3", "b.mp3"], &["a.mp3"]);
+    std::fs::write(dir.join("music/other.m3u"), "b.mp3").unwrap();
+    std::fs::write(
+        dir.join("music/playlists.txt"),
+        "# Car\nplaylist.m3u\nother.m3u\n",
+    )
+    .unwrap();
+    let output = run(
+        &dir,
+        &[
+            "-n",
+            "-o",
+            "out",
+            "--playlists-from",
+            "music/playlists.txt",
+            "music/playlist.m3u",
+        ],
+    );
+    assert!(output.status.success());
+    let stdout = stdout(output);
+    let written: Vec<_> = stdout
+        .lines()
+        .filter(|line| line.starts_with("Would write playlist"))
+        .collect();
+    assert_eq!(written.len(), 2, "{}", stdout);
+    assert!(written[0].contains("from music/playlist.m3u"), "{}", stdout);
+    assert!(written[1].contains("from music/other.m3u"), "{}", stdout);
+
+    let output = run(
+        &dir,
+        &["-n", "-o", "out", "--playlists-from", "missing.txt"],
+    );
+    assert_eq!(output.status.code(), Some(2));
+}
+
+#[test]
+fn music_root_places_sources_independently_of_playlists() {
+    let dir = scratch_dir("music-root");
+    let album = dir.join("library/Artist/Album");
+    std::fs::create_dir_all(&album).unwrap();
+    std::fs::create_dir_all(dir.join("lists")).unwrap();
+    for file in ["a.mp3", "b.mp3"] {
+        std::fs::write(album.join(file), "DATA").unwrap();
+    }
+    std::fs::write(dir.join("outside.mp3"), "DATA").unwrap();
+    std::fs::write(
+        dir.join("lists/mixed.m3u"),
+        format!(
+            "../library/Artist/Album/a.mp3\n{}\n",
+            album.join("b.mp3").display()
+        ),
+    )
+    .unwrap();
+    std::fs::write(dir.join("library/Artist/nested.m3u"), "Album/b.mp3\n").unwrap();
+    let output = run(
+        &dir,
+        &[
+            "-o",
+            "out",
+            "--music-root",
+            "library",
+            "lists/mixed.m3u",
+            "library/Artist/nested.m3u",
+        ],
+    );
+    assert!(output.status.success());
+    assert!(dir.join("out/Artist/Album/a.mp3").exists());
+    assert!(dir.join("out/Artist/Album/b.mp3").exists());
+    assert_eq!(
+        std::fs::read_to_string(dir.join("out/nested.m3u")).unwrap(),
+        "Artist\\Album\\b.mp3\n"
+    );
+
+    std::fs::write(dir.join("lists/outside.m3u"), "../outside.mp3\n").unwrap();
+    let args = [
+        "-n",
+        "-o",
+        "out",
+        "--music-root",
+        "library",
+        "lists/outside.m3u",
+    ];
+    assert_eq!(run(&dir, &args).status.code(), Some(2));
+    let output = run(
+        &dir,
+        &[&args[..], &["--outside-music-root", "fallback"]].concat(),
+    );
+    assert!(output.status.success());
+}
+
+#[test]
+fn writes_mapping_file_and_original_playlists() {
+    let dir = scratch_dir("mapping");
+    write_music(&dir, &["a.mp3"], &["a.mp3"]);
+    let output = run(
+        &dir,
+        &[
+            "-o",
+            "out",
+            "--mapping-file",
+            "mapping.csv",
+            "--keep-original-playlist",
+            "music/playlist.m3u",
+        ],
+    );
+    assert!(output.status.success());
+    let mapping = std::fs::read_to_string(dir.join("mapping.csv")).unwrap();
+    let lines: Vec<&str> = mapping.lines().collect();
+    assert_eq!(lines[0], "output,source,action,settings_hash,timestamp");
+    assert!(
+        lines[1].starts_with("a.mp3,music/a.mp3,copy,"),
+        "{}",
+        mapping
+    );
+    assert_eq!(
+        std::fs::read_to_string(dir.join("out/_original/playlist.m3u")).unwrap(),
+        "a.mp3"
+    );
+}
+
+#[test]
+fn watch_cycles_keep_the_mapping_of_unchanged_playlists() {
+    let dir = scratch_dir("watch-mapping");
+    write_music(&dir, &["a.mp3", "b.mp3", "c.mp3"], &[]);
+    std::fs::write(dir.join("music/one.m3u"), "a.mp3\n").unwrap();
+    std::fs::write(dir.join("music/two.m3u"), "b.mp3\n").unwrap();
+    let mut child = spawn(
+        &dir,
+        &[
+            "-o",
+            "out",
+            "--mapping-file",
+            "mapping.csv",
+            "--watch",
+            "music/one.m3u",
+            "music/two.m3u",
+        ],
+    );
+    let mapping = || std::fs::read_to_string(dir.join("mapping.csv")).unwrap_or_default();
+    wait_until("the first sync is done", || mapping().contains("b.mp3,"));
+    let modified = || {
+        std::fs::metadata(dir.join("out/b.mp3"))
+            .unwrap()
+            .modified()
+            .unwrap()
+    };
+    let first_copy = modified();
+
+    std::fs::write(dir.join("music/one.m3u"), "a.mp3\nc.mp3\n").unwrap();
+    wait_until("the changed playlist is synced", || {
+        mapping().contains("c.mp3,")
+    });
+    child.kill().unwrap();
+    child.wait().unwrap();
+    let mapping = mapping();
+    assert!(mapping.contains("a.mp3,"), "{}", mapping);
+    assert!(mapping.contains("b.mp3,"), "{}", mapping);
+    assert!(dir.join("out/two.m3u").exists());
+    // The unchanged source was not copied again.
+    assert_eq!(modified(), first_copy);
+}
+
+#[test]
+fn short_names_are_listed_in_the_playlist_and_mapping_file() {
+    let dir = scratch_dir("short-names");
+    std::fs::create_dir_all(dir.join("music/Some Album")).unwrap();
+    write_music(
+        &dir,
+        &["Some Album/First Song.mp3", "Some Album/First Song!.mp3"],
+        &["Some Album/First Song.mp3", "Some Album/First Song!.mp3"],
+    );
+    let output = run(
+        &dir,
+        &[
+            "-o",
+            "out",
+            "--short-names",
+            "--mapping-file",
+            "mapping.csv",
+            "music/playlist.m3u",
+        ],
+    );
+    assert!(output.status.success());
+    assert!(dir.join("out/SOMEALBM/FIRSTSNG.mp3").exists());
+    assert!(dir.join("out/SOMEALBM/FIRSTS~2.mp3").exists());
+    assert_eq!(
+        std::fs::read_to_string(dir.join("out/PLAYLIST.m3u")).unwrap(),
+        "SOMEALBM\\FIRSTSNG.mp3\nSOMEALBM\\FIRSTS~2.mp3\n"
+    );
+    let mapping = std::fs::read_to_string(dir.join("mapping.csv")).unwrap();
+    let lines: Vec<&str> = mapping.lines().collect();
+    assert!(lines[0].ends_with(",original"));
+    assert!(
+        lines[2].ends_with(",Some Album/First Song!.mp3"),
+        "{}",
+        mapping
+    );
+}
+
+#[test]
+fn full_folders_are_split_into_numbered_subfolders() {
+    let dir = scratch_dir("max-files-per-dir");
+    write_music(
+        &dir,
+        &["b.mp3", "c.mp3", "d.mp3"],
+        &["b.mp3", "c.mp3", "d.mp3"],
+    );
+    let args = [
+        "-o",
+        "out",
+        "--max-files-per-dir",
+        "2",
+        "music/playlist.m3u",
+    ];
+    assert!(run(&dir, &args).status.success());
+    assert_eq!(
+        std::fs::read_to_string(dir.join("out/playlist.m3u")).unwrap(),
+        "001\\b.mp3\n001\\c.mp3\n002\\d.mp3\n"
+    );
+
+    // A new file that sorts first goes into a subfolder with room, the others stay.
+    write_music(
+        &dir,
+        &["a.mp3", "b.mp3", "c.mp3", "d.mp3"],
+        &["a.mp3", "b.mp3", "c.mp3", "d.mp3"],
+    );
+    assert!(run(&dir, &args).status.success());
+    assert_eq!(
+        std::fs::read_to_string(dir.join("out/playlist.m3u")).unwrap(),
+        "002\\a.mp3\n001\\b.mp3\n001\\c.mp3\n002\\d.mp3\n"
+    );
+    assert!(!dir.join("out/003").exists());
+}
+
+#[test]
+fn short_names_keep_their_numbered_subfolders() {
+    let dir = scratch_dir("max-files-per-dir-short-names");
+    write_music(
+        &dir,
+        &["c song.mp3", "d song.mp3", "e song.mp3"],
+        &["c song.mp3", "d song.mp3", "e song.mp3"],
+    );
+    let args = [
+        "-o",
+        "out",
+        "--short-names",
+        "--max-files-per-dir",
+        "2",
+        "--mapping-file",
+        "mapping.csv",
+        "music/playlist.m3u",
+    ];
+    assert!(run(&dir, &args).status.success());
+    assert_eq!(
+        std::fs::read_to_string(dir.join("out/PLAYLIST.m3u")).unwrap(),
+        "001\\CSONG.mp3\n001\\DSONG.mp3\n002\\ESONG.mp3\n"
+    );
+
+    write_music(
+        &dir,
+        &["a song.mp3", "c song.mp3", "d song.mp3", "e song.mp3"],
+        &["a song.mp3", "c song.mp3", "d song.mp3", "e song.mp3"],
+    );
+    assert!(run(&dir, &args).status.success());
+    assert_eq!(
+        std::fs::read_to_string(dir.join("out/PLAYLIST.m3u")).unwrap(),
+        "002\\ASONG.mp3\n001\\CSONG.mp3\n001\\DSONG.mp3\n002\\ESONG.mp3\n"
+    );
+    let mapping = std::fs::read_to_string(dir.join("mapping.csv")).unwrap();
+    assert!(mapping.contains(",a song.mp3"), "{}", mapping);
+}
+
+#[test]
+fn file_budget_reports_the_cut_entries_per_playlist() {
+    let dir = scratch_dir("max-total-files");
+    std::fs::create_dir_all(dir.join("music")).unwrap();
+    for file in ["1.mp3", "2.mp3", "3.mp3"] {
+        std::fs::write(dir.join("music").join(file), "DATA").unwrap();
+    }
+    std::fs::write(dir.join("music/a.m3u"), "1.mp3\n2.mp3\n3.mp3\n").unwrap();
+    std::fs::write(dir.join("music/b.m3u"), "2.mp3\n").unwrap();
+    let output = run(
+        &dir,
+        &[
+            "-o",
+            "out",
+            "--max-total-files",
+            "1",
+            "--priority",
+            "music/b.m3u",
+            "--report-json",
+            "report.json",
+            "music/a.m3u",
+            "music/b.m3u",
+        ],
+    );
+    assert!(output.status.success());
+    // The shared entry stays in the lower-priority playlist, as cutting it frees nothing.
+    assert_eq!(
+        std::fs::read_to_string(dir.join("out/a.m3u")).unwrap(),
+        "2.mp3\n"
+    );
+    assert_eq!(
+        std::fs::read_to_string(dir.join("out/b.m3u")).unwrap(),
+        "2.mp3\n"
+    );
+    assert!(!dir.join("out/1.mp3").exists());
+    let report: serde_json::Value =
+        serde_json::from_str(&std::fs::read_to_string(dir.join("report.json")).unwrap()).unwrap();
+    let skipped = |index: usize| -> Vec<(String, String)> {
+        report["playlists"][index]["skipped"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|skipped| {
+                (
+                    skipped["entry"].as_str().unwrap().to_string(),
+                    skipped["reason"].as_str().unwrap().to_string(),
+                )
+            })
+            .collect()
+    };
+    let reason = "Cut to fit `--max-total-files`".to_string();
+    assert_eq!(
+        skipped(0),
+        [
+            ("1.mp3".to_string(), reason.clone()),
+            ("3.mp3".to_string(), reason)
+        ]
+    );
+    assert!(skipped(1).is_empty());
+    assert_eq!(report["counts"]["skipped"], 2);
+}
+
+#[test]
+fn writes_output_to_zip() {
+    let dir = scratch_dir("zip");
+    write_music(&dir, &["a.mp3", "b.mp3"], &["a.mp3", "b.mp3"]);
+    let output = run(&dir, &["--output-zip", "out.zip", "music/playlist.m3u"]);
+    assert!(output.status.success());
+    assert!(!dir.join(".out.zip.staging").exists());
+    let archive = std::fs::read(dir.join("out.zip")).unwrap();
+    let contains = |name: &str| archive.windows(name.len()).any(|w| w == name.as_bytes());
+    assert!(contains("a.mp3") && contains("b.mp3") && contains("playlist.m3u"));
+    assert!(!dir.join("output").exists());
+}
+
+#[test]
+fn writes_output_to_image() {
+    let dir = scratch_dir("image");
+    write_music(&dir, &["a.mp3"], &["a.mp3"]);
+    let output = run(
+        &dir,
+        &[
+            "--output-image",
+            "stick.img",
+            "--image-size",
+            "64M",
+            "music/playlist.m3u",
+        ],
+    );
+    assert!(output.status.success());
+    assert_eq!(
+        std::fs::metadata(dir.join("stick.img")).unwrap().len(),
+        64 << 20
+    );
+    assert!(!dir.join(".stick.img.staging").exists());
+
+    let output = run(
+        &dir,
+        &[
+            "--output-image",
+            "small.img",
+            "--image-size",
+            "1M",
+            "music/playlist.m3u",
+        ],
+    );
+    assert_eq!(output.status.code(), Some(3));
+    assert!(!dir.join("small.img").exists());
+}
+
+#[cfg(unix)]
+#[test]
+fn uploads_output_over_sftp() {
+    use std::os::unix::fs::PermissionsExt;
+
+    let dir = scratch_dir("sftp");
+    write_music(&dir, &["a.mp3"], &["a.mp3"]);
+    // Stand-ins for the OpenSSH clients that run everything locally.
+    let bin = dir.join("bin");
+    std::fs::create_dir_all(&bin).unwrap();
+    let scripts = [
+        (
+            "ssh",
+            "#!/bin/sh\nfor last; do :; done\ncase \" $* \" in *\" -O \"*) exit 0;; esac\n\
+             PATH=/usr/bin:/bin exec /bin/sh -c \"$last\"\n",
+        ),
+        (
+            "sftp",
+            "#!/bin/sh\nPATH=/usr/bin:/bin\n\
+             while read -r line; do eval \"set -- $line\"; cp -p \"$3\" \"$4\" || exit 1; done\n",
+        ),
+    ];
+    for (name, script) in scripts {
+        let path = bin.join(name);
+        std::fs::write(&path, script).unwrap();
+        std::fs::set_permissions(&path, std::fs::Permissions::from_mode(0o755)).unwrap();
+    }
+    let remote = dir.join("remote");
+    let target = format!("server:{}", remote.join("car").display());
+    let output = run_with_path(
+        &dir,
+        &["--output-sftp", &target, "music/playlist.m3u"],
+        &bin,
+    );
+    assert!(output.status.success());
+    assert_eq!(
+        std::fs::read_to_string(remote.join("car/a.mp3")).unwrap(),
+        "DATA"
+    );
+    assert!(remote.join("car/playlist.m3u").exists());
+
+    // The staging directory is kept, and unchanged sources are not converted again.
+    let staging_dirs: Vec<_> = std::fs::read_dir(dir.join("cache/ford-sync-convert/staging"))
+        .unwrap()
+        .map(|entry| entry.unwrap().path())
+        .collect();
+    let [staging] = staging_dirs.as_slice() else {
+        panic!("expected one staging directory, found {:?}", staging_dirs);
+    };
+    std::fs::write(staging.join("a.mp3"), "KEPT").unwrap();
+    std::fs::write(dir.join("music/b.mp3"), "DATA").unwrap();
+    let output = run_with_path(
+        &dir,
+        &["--output-sftp", &target, "music/playlist.m3u"],
+        &bin,
+    );
+    assert!(output.status.success());
+    assert_eq!(
+        std::fs::read_to_string(staging.join("a.mp3")).unwrap(),
+        "KEPT"
+    );
+
+    // Staged files that are not in the playlists any more are removed.
+    std::fs::write(dir.join("music/playlist.m3u"), "b.mp3\n").unwrap();
+    let output = run_with_path(
+        &dir,
+        &["--output-sftp", &target, "music/playlist.m3u"],
+        &bin,
+    );
+    assert!(output.status.success());
+    assert!(!staging.join("a.mp3").exists());
+    assert!(staging.join("b.mp3").exists());
+}
+
+#[test]
+fn split_targets_keep_playlists_together() {
+    let dir = scratch_dir("split-targets");
+    write_music(&dir, &["1.mp3", "2.mp3", "3.mp3", "4.mp3"], &["1.mp3"]);
+    std::fs::write(dir.join("music/long.m3u"), "2.mp3\n3.mp3\n4.mp3\n").unwrap();
+    let output = run(
+        &dir,
+        &[
+            "--split-targets",
+            "usb1:8,usb2:8",
+            "music/playlist.m3u",
+            "music/long.m3u",
+        ],
+    );
+    assert!(output.status.success());
+    assert!(dir.join("usb1/playlist.m3u").exists());
+    assert!(dir.join("usb1/1.mp3").exists());
+    assert_eq!(
+        std::fs::read_to_string(dir.join("usb1/long (disc 1).m3u")).unwrap(),
+        "2.mp3\n"
+    );
+    assert_eq!(
+        std::fs::read_to_string(dir.join("usb2/long (disc 2).m3u")).unwrap(),
+        "3.mp3\n4.mp3\n"
+    );
+    assert!(!dir.join("usb2/1.mp3").exists());
+    assert!(!dir.join("usb2/playlist.m3u").exists());
+}
+
+#[test]
+fn variants_are_written_from_one_plan() {
+    let dir = scratch_dir("variants");
+    write_music(&dir, &["1.mp3", "2.mp3"], &["1.mp3", "2.mp3"]);
+    let output = run(
+        &dir,
+        &[
+            "--variant",
+            "car:mp3,q=2,dir=car",
+            "--variant",
+            "phone:mp3,b=96k,dir=phone",
+            "--report-json",
+            "report.json",
+            "music/playlist.m3u",
+        ],
+    );
+    assert!(output.status.success());
+    for variant in ["car", "phone"] {
+        assert_eq!(
+            std::fs::read_to_string(dir.join(variant).join("playlist.m3u")).unwrap(),
+            "1.mp3\n2.mp3\n"
+        );
+        assert!(dir.join(variant).join("2.mp3").exists());
+        assert!(dir.join(format!("report-{}.json", variant)).exists());
+    }
+    let report = std::fs::read_to_string(dir.join("report-phone.json")).unwrap();
+    assert!(report.contains("\"variant\": \"phone\""));
+
+    let output = run(
+        &dir,
+        &["--variant", "phone:aac,dir=phone", "music/playlist.m3u"],
+    );
+    assert_eq!(output.status.code(), Some(2));
+}
+
+#[test]
+fn entries_are_resolved_ignoring_case() {
+    let dir = scratch_dir("fuzzy");
+    write_music(&dir, &["Song.mp3"], &["song.mp3"]);
+    let output = run(&dir, &["-o", "out", "music/playlist.m3u"]);
+    assert!(output.status.success());
+    assert_eq!(
+        std::fs::read_to_string(dir.join("out/playlist.m3u")).unwrap(),
+        "Song.mp3\n"
+    );
+
+    let output = run(
+        &dir,
+        &["-o", "strict", "--no-fuzzy-match", "music/playlist.m3u"],
+    );
+    assert_eq!(output.status.code(), Some(1));
+    assert!(!dir.join("strict/Song.mp3").exists());
+}
+
+#[test]
+fn missing_entries_are_found_in_the_library() {
+    let dir = scratch_dir("resolve-missing");
+    write_music(&dir, &["Song.mp3"], &["Old/Song.mp3"]);
+    std::fs::create_dir_all(dir.join("music/New")).unwrap();
+    std::fs::rename(dir.join("music/Song.mp3"), dir.join("music/New/Song.mp3")).unwrap();
+    let output = run(
+        &dir,
+        &[
+            "-o",
+            "out",
+            "--resolve-missing",
+            "music",
+            "music/playlist.m3u",
+        ],
+    );
+    assert!(output.status.success());
+    assert!(dir.join("out/New/Song.mp3").exists());
+}
+
+#[test]
+fn presets_set_defaults_that_options_override() {
+    let dir = scratch_dir("preset");
+    let config = stdout(run(
+        &dir,
+        &[
+            "--preset",
+            "sync2",
+            "--max-files",
+            "100",
+            "--print-config",
+            "a.m3u",
+        ],
+    ));
+    assert!(
+        config.contains("max_playlist_entries = 3000 # preset sync2\n"),
+        "{}",
+        config
+    );
+    assert!(config.contains("max_files = 100 # command line\n"));
+    for preset in ["sync3", "generic-usb"] {
+        let output = run(&dir, &["--preset", preset, "--print-config", "a.m3u"]);
+        assert!(output.status.success(), "{}", preset);
+    }
+
+    write_music(&dir, &["Caf\u{e9}.mp3"], &["Caf\u{e9}.mp3"]);
+    let output = run(
+        &dir,
+        &["-o", "out", "--preset", "generic-usb", "music/playlist.m3u"],
+    );
+    assert!(output.status.success());
+    assert_eq!(
+        std::fs::read(dir.join("out/playlist.m3u")).unwrap(),
+        "Caf\u{e9}.mp3\r\n".as_bytes()
+    );
+    let output = run(
+        &dir,
+        &[
+            "-o",
+            "latin1",
+            "--preset",
+            "generic-usb",
+            "--playlist-encoding",
+            "latin1",
+            "music/playlist.m3u",
+        ],
+    );
+    assert!(output.status.success());
+    assert_eq!(
+        std::fs::read(dir.join("latin1/playlist.m3u")).unwrap(),
+        b"Caf\xe9.mp3\r\n"
+    );
+}
+
+#[test]
+fn estimate_predicts_without_writing() {
+    let dir = scratch_dir("estimate");
+    write_music(&dir, &["a.mp3", "b.flac"], &["a.mp3", "b.flac", "a.mp3"]);
+    let output = run(
+        &dir,
+        &[
+            "estimate",
+            "-o",
+            "out",
+            "--json",
+            "--encode-speed",
+            "60",
+            "--minutes-per-track",
+            "4",
+            "music/playlist.m3u",
+        ],
+    );
+    assert!(output.status.success());
+    assert!(!dir.join("out").exists());
+    let prediction: serde_json::Value = serde_json::from_slice(&output.stdout).unwrap();
+    assert_eq!(prediction["playlists"][0]["tracks"], 3);
+    assert_eq!(prediction["total"]["tracks"], 2);
+    assert_eq!(prediction["total"]["source_size"], 8);
+    assert_eq!(prediction["speeds"]["encode"], 60.0);
+    // The FLAC file is converted for 4 minutes at 60 times real time, on one of four workers.
+    let seconds = prediction["total"]["seconds"].as_f64().unwrap();
+    assert!((1.0..1.1).contains(&seconds), "{}", seconds);
+
+    std::fs::write(dir.join("music/copies.m3u"), "a.mp3\n").unwrap();
+    let output = run(
+        &dir,
+        &[
+            "-o",
+            "out",
+            "--report-json",
+            "report.json",
+            "music/copies.m3u",
+        ],
+    );
+    assert!(output.status.success());
+    let output = run(
+        &dir,
+        &[
+            "estimate",
+            "--json",
+            "--timings-from",
+            "report.json",
+            "music/playlist.m3u",
+        ],
+    );
+    let prediction: serde_json::Value = serde_json::from_slice(&output.stdout).unwrap();
+    assert_eq!(prediction["speeds"]["encode"], 30.0);
+    assert_ne!(prediction["speeds"]["copy"], 20971520.0);
+}
+
+#[test]
+fn append_keeps_existing_entries() {
+    let dir = scratch_dir("append");
+    write_music(&dir, &["a.mp3", "b.mp3", "c.mp3"], &["b.mp3", "c.mp3"]);
+    std::fs::create_dir_all(dir.join("out")).unwrap();
+    std::fs::write(dir.join("out/playlist.m3u"), "old.mp3\nb.mp3\n").unwrap();
+    let output = run(&dir, &["-o", "out", "--append", "music/playlist.m3u"]);
+    assert!(output.status.success());
+    assert_eq!(
+        std::fs::read_to_string(dir.join("out/playlist.m3u")).unwrap(),
+        "old.mp3\nb.mp3\nc.mp3\n"
+    );
+
+    std::fs::write(dir.join("out/playlist.m3u"), b"caf\xe9.mp3\n").unwrap();
+    let output = run(&dir, &["-o", "out", "--append", "music/playlist.m3u"]);
+    assert!(output.status.success());
+    assert_eq!(
+        std::fs::read(dir.join("out/playlist.m3u")).unwrap(),
+        b"caf\xe9.mp3\n"
+    );
+}
+
+#[test]
+fn clean_removes_only_created_files() {
+    let dir = scratch_dir("clean");
+    std::fs::create_dir_all(dir.join("music/Album")).unwrap();
+    write_music(&dir, &["Album/1.mp3"], &["Album/1.mp3"]);
+    let output = run(&dir, &["clean", "-o", "out", "--yes"]);
+    assert_eq!(output.status.code(), Some(3));
+
+    let args = ["-o", "out", "--report-json", "out/report.json"];
+    let output = run(&dir, &[&args[..], &["music/playlist.m3u"]].concat());
+    assert!(output.status.success());
+    std::fs::write(dir.join("out/Album/notes.txt"), "mine").unwrap();
+    std::fs::create_dir_all(dir.join("out/Other")).unwrap();
+
+    let output = run(&dir, &["clean", "-o", "out", "--dry-run"]);
+    assert!(output.status.success());
+    assert!(stdout(output).contains("Would remove 3 files"));
+    assert!(dir.join("out/Album/1.mp3").exists());
+
+    let output = run(&dir, &["clean", "-o", "out"]);
+    assert_eq!(output.status.code(), Some(4));
+    let output = run(&dir, &["clean", "-o", "out", "--yes"]);
+    assert!(output.status.success());
+    assert!(!dir.join("out/Album/1.mp3").exists());
+    assert!(!dir.join("out/playlist.m3u").exists());
+    assert!(!dir.join("out/report.json").exists());
+    assert!(dir.join("out/Album/notes.txt").exists());
+    assert!(dir.join("out/Other").exists());
+    assert!(!dir.join("out/.ford-sync-convert.manifest.json").exists());
+}
+
+#[test]
+fn clean_keeps_existing_files_the_sync_skipped() {
+    let dir = scratch_dir("clean-skipped");
+    write_music(&dir, &["a.mp3", "b.mp3"], &["a.mp3", "b.mp3"]);
+    std::fs::create_dir_all(dir.join("out")).unwrap();
+    std::fs::write(dir.join("out/a.mp3"), "mine").unwrap();
+    let args = ["-o", "out", "--since", "2999-01-01", "music/playlist.m3u"];
+    let output = run(&dir, &args);
+    assert!(output.status.success());
+    assert!(dir.join("out/b.mp3").exists());
+
+    let output = run(&dir, &["clean", "-o", "out", "--yes"]);
+    assert!(output.status.success());
+    assert_eq!(
+        std::fs::read_to_string(dir.join("out/a.mp3")).unwrap(),
+        "mine"
+    );
+    assert!(!dir.join("out/b.mp3").exists());
+}
+
+#[test]
+fn watch_cycles_do_not_prune_the_outputs_of_unchanged_playlists() {
+    let dir = scratch_dir("watch-prune");
+    write_music(&dir, &["a.mp3", "b.mp3", "c.mp3"], &[]);
+    std::fs::write(dir.join("music/one.m3u"), "a.mp3\n").unwrap();
+    std::fs::write(dir.join("music/two.m3u"), "b.mp3\n").unwrap();
+    let mut child = spawn(
+        &dir,
+        &[
+            "-o",
+            "out",
+            "--prune",
+            "--yes",
+            "--watch",
+            "music/one.m3u",
+            "music/two.m3u",
+        ],
+    );
+    wait_until("the first sync is done", || {
+        dir.join("out/one.m3u").exists() && dir.join("out/two.m3u").exists()
+    });
+
+    std::fs::write(dir.join("music/one.m3u"), "a.mp3\nc.mp3\n").unwrap();
+    wait_until("the changed playlist is synced", || {
+        std::fs::read_to_string(dir.join("out/one.m3u")).is_ok_and(|p| p.contains("c.mp3"))
+    });
+    child.kill().unwrap();
+    child.wait().unwrap();
+    assert!(dir.join("out/a.mp3").exists());
+    assert!(dir.join("out/b.mp3").exists());
+    assert!(dir.join("out/two.m3u").exists());
+}
+
+#[cfg(feature = "tui")]
+#[test]
+fn tui_falls_back_to_the_log_when_not_interactive() {
+    let dir = scratch_dir("tui");
+    write_music(&dir, &["a.mp3"], &["a.mp3"]);
+    std::fs::write(dir.join("music/other.m3u"), "a.mp3\n").unwrap();
+    let output = run(
+        &dir,
+        &[
+            "--tui",
+            "-o",
+            "out",
+            "music/playlist.m3u",
+            "music/other.m3u",
+        ],
+    );
+    assert!(output.status.success());
+    assert!(dir.join("out/a.mp3").exists());
+    assert!(dir.join("out/playlist.m3u").exists());
+    assert!(dir.join("out/other.m3u").exists());
+}
+
+#[test]
+fn prune_removes_stale_files_and_emptied_folders() {
+    let dir = scratch_dir("prune");
+    std::fs::create_dir_all(dir.join("music/Album")).unwrap();
+    std::fs::create_dir_all(dir.join("music/Other")).unwrap();
+    write_music(
+        &dir,
+        &["Album/1.mp3", "Other/2.mp3"],
+        &["Album/1.mp3", "Other/2.mp3"],
+    );
+    let output = run(&dir, &["-o", "out", "music/playlist.m3u"]);
+    assert!(output.status.success());
+    std::fs::write(dir.join("out/Other/Thumbs.db"), "junk").unwrap();
+    std::fs::write(dir.join("out/Other/._2.mp3"), "junk").unwrap();
+    std::fs::write(dir.join("music/playlist.m3u"), "Album/1.mp3\n").unwrap();
+
+    let args = ["-o", "out", "--prune", "music/playlist.m3u"];
+    let output = run(&dir, &[&args[..], &["--dry-run"]].concat());
+    assert!(output.status.success());
+    let stdout = stdout(output);
+    assert!(stdout.contains("  prune out/Other/2.mp3\n"), "{}", stdout);
+    assert!(
+        stdout.contains("  remove directory out/Other\n"),
+        "{}",
+        stdout
+    );
+    assert!(stdout.contains("and 1 empty directories."), "{}", stdout);
+    assert!(dir.join("out/Other/2.mp3").exists());
+
+    // Without `--yes`, nothing is pruned when not running interactively.
+    let output = run(&dir, &args);
+    assert!(output.status.success());
+    assert!(dir.join("out/Other/2.mp3").exists());
+
+    let output = run(&dir, &[&args[..], &["--yes"]].concat());
+    assert!(output.status.success());
+    assert!(!dir.join("out/Other").exists());
+    assert!(dir.join("out/Album/1.mp3").exists());
+    assert_eq!(
+        std::fs::read_to_string(dir.join("out/playlist.m3u")).unwrap(),
+        "Album\\1.mp3\n"
+    );
+
+    // The output directory itself stays even if nothing is left in it.
+    std::fs::write(dir.join("music/playlist.m3u"), "").unwrap();
+    let output = run(&dir, &[&args[..], &["--yes"]].concat());
+    assert!(output.status.success());
+    assert!(!dir.join("out/Album").exists());
+    assert!(dir.join("out").exists());
+}
+
+#[cfg(unix)]
+#[test]
+fn interrupt_leaves_no_partial_outputs() {
+    use std::os::unix::fs::PermissionsExt;
+
+    let dir = scratch_dir("interrupt");
+    let flacs = ["1.flac", "2.flac", "3.flac", "4.flac", "5.flac", "6.flac"];
+    write_music(&dir, &flacs, &["a.mp3"]);
+    std::fs::write(dir.join("music/a.mp3"), "DATA").unwrap();
+    let output = run(&dir, &["-o", "out", "music/playlist.m3u"]);
+    assert!(output.status.success());
+    std::fs::write(
+        dir.join("music/playlist.m3u"),
+        [&["a.mp3"][..], &flacs].concat().join("\n"),
+    )
+    .unwrap();
+    // Stand-in for FFmpeg that writes part of its output and then hangs.
+    let ffmpeg = dir.join("bin/ffmpeg");
+    std::fs::create_dir_all(dir.join("bin")).unwrap();
+    std::fs::write(
+        &ffmpeg,
+        format!(
+            "#!/bin/sh\nPATH=/usr/bin:/bin\n[ \"$1\" = -version ] && exit 0\n\
+             for last; do :; done\necho \"$last\" >> {}\necho PARTIAL > \"$last\"\nexec sleep 60\n",
+            dir.join("calls").display()
+        ),
+    )
+    .unwrap();
+    std::fs::set_permissions(&ffmpeg, std::fs::Permissions::from_mode(0o755)).unwrap();
+
+    let mut child = spawn(&dir, &["-o", "out", "music/playlist.m3u"]);
+    wait_until("a conversion was started", || {
+        std::fs::read_dir(dir.join("out")).is_ok_and(|mut entries| {
+            entries.any(|entry| {
+                entry.is_ok_and(|entry| entry.file_name().to_string_lossy().starts_with(".tmp."))
+            })
+        })
+    });
+    let pid = libc::pid_t::try_from(child.id()).unwrap();
+    // SAFETY: The signal is sent to the child started above, which has not been waited for.
+    assert_eq!(unsafe { libc::kill(pid, libc::SIGINT) }, 0);
+    let status = child.wait().unwrap();
+    assert_eq!(status.code(), Some(4));
+
+    // No more conversions were started than can run at the same time.
+    let calls = std::fs::read_to_string(dir.join("calls")).unwrap();
+    assert!(calls.lines().count() < flacs.len(), "{}", calls);
+    let mut outputs: Vec<_> = std::fs::read_dir(dir.join("out"))
+        .unwrap()
+        .map(|entry| entry.unwrap().file_name().into_string().unwrap())
+        .filter(|name| !name.starts_with(".ford-sync-convert."))
+        .collect();
+    outputs.sort();
+    assert_eq!(outputs, ["a.mp3", "playlist.m3u"]);
+    assert_eq!(
+        std::fs::read_to_string(dir.join("out/playlist.m3u")).unwrap(),
+        "a.mp3\n"
+    );
+}
+
+#[test]
+fn leftover_temporary_files_are_removed_at_startup() {
+    let dir = scratch_dir("leftovers");
+    write_music(&dir, &["a.mp3"], &["a.mp3"]);
+    std::fs::create_dir_all(dir.join("out/Album")).unwrap();
+    std::fs::write(dir.join("out/.tmp.a.mp3.99999"), "PARTIAL").unwrap();
+    std::fs::write(dir.join("out/Album/.tmp.b.mp3.1"), "PARTIAL").unwrap();
+    std::fs::write(dir.join("out/.tmp.notes"), "mine").unwrap();
+
+    let output = run(&dir, &["-o", "out", "music/playlist.m3u"]);
+    assert!(output.status.success());
+    assert!(!dir.join("out/.tmp.a.mp3.99999").exists());
+    assert!(!dir.join("out/Album/.tmp.b.mp3.1").exists());
+    assert!(dir.join("out/.tmp.notes").exists());
+    assert_eq!(
+        std::fs::read_to_string(dir.join("out/a.mp3")).unwrap(),
+        "DATA"
+    );
+}
+
+#[cfg(unix)]
+#[test]
+fn overlap_is_detected_through_symlinks_and_relative_paths() {
+    let dir = scratch_dir("overlap");
+    write_music(&dir, &["a.mp3"], &["a.mp3"]);
+    std::os::unix::fs::symlink(dir.join("music"), dir.join("link")).unwrap();
+    std::fs::create_dir_all(dir.join("lists")).unwrap();
+    std::fs::write(dir.join("lists/other.m3u"), "../music/a.mp3\n").unwrap();
+
+    // The output directory is inside the playlist's directory.
+    let output = run(&dir, &["-o", "link/out", "music/playlist.m3u"]);
+    assert_eq!(output.status.code(), Some(2));
+    // The output directory contains the source.
+    let output = run(&dir, &["-o", "lists/../link", "lists/other.m3u"]);
+    assert_eq!(output.status.code(), Some(2));
+    let mut files: Vec<_> = std::fs::read_dir(dir.join("music"))
+        .unwrap()
+        .map(|entry| entry.unwrap().file_name().into_string().unwrap())
+        .collect();
+    files.sort();
+    assert_eq!(files, ["a.mp3", "playlist.m3u"]);
+
+    let output = run(&dir, &["-o", "out", "lists/other.m3u"]);
+    assert!(output.status.success());
+}
@@ -0,0 +1,52 @@
+// Copyright (c) 2024 Jan Holthuis <jan.holthuis@rub.de>
+//
+// This Source Code Form is subject to the terms of the Mozilla Public License, v. 2.0. If a copy
+// of the MPL was not distributed with this file, You can obtain one at
+// http://mozilla.org/MPL/2.0/.
+//
+// SPDX-License-Identifier: MPL-2.0
+//! Helpers for running the binary.
+use std::path::{Path, PathBuf};
+use std::process::{Command, Output};
+
+/// Create an empty scratch directory for a test.
+pub fn scratch_dir(name: &str) -> PathBuf {
+    let dir = std::env::temp_dir().join(format!(
+        "ford-sync-convert-test-{}-{}",
+        name,
+        std::process::id()
+    ));
+    let _ = std::fs::remove_dir_all(&dir);
+    std::fs::create_dir_all(&dir).unwrap();
+    dir
+}
+
+/// Run the binary in `dir` with an empty `PATH`, so that FFmpeg cannot be found.
+pub fn run(dir: &Path, args: &[&str]) -> Output {
+    let empty_path = dir.join("bin");
+    std::fs::create_dir_all(&empty_path).unwrap();
+    run_with_path(dir, args, &empty_path)
+}
+
+/// Run the binary in `dir` with the given `PATH`.
+pub fn run_with_path(dir: &Path, args: &[&str], path: &Path) -> Output {
+    Command::new(env!("CARGO_BIN_EXE_ford-sync-convert"))
+        .args(args)
+        .current_dir(dir)
+        .env("PATH", path)
+        .env("XDG_CONFIG_HOME", dir)
+        .env("XDG_CACHE_HOME", dir.join("cache"))
+        .env("RUST_LOG", "off")
+        .output()
+        .unwrap()
+}
+
+/// Write a playlist and its existing source files to the `music` subdirectory.
+pub fn write_music(dir: &Path, sources: &[&str], entries: &[&str]) {
+    let music = dir.join("music");
+    std::fs::create_dir_all(&music).unwrap();
+    for source in sources {
+        std::fs::write(music.join(source), "DATA").unwrap();
+    }
+    std::fs::write(music.join("playlist.m3u"), entries.join("\n")).unwrap();
+}
@@ -0,0 +1,166 @@
+// Copyright (c) 2024 Jan Holthuis <jan.holthuis@rub.de>
+//
+// This Source Code Form is subject to the terms of the Mozilla Public License, v. 2.0. If a copy
+// of the MPL was not distributed with this file, You can obtain one at
+// http://mozilla.org/MPL/2.0/.
+//
+// SPDX-License-Identifier: MPL-2.0
+//! Exit statuses of the binary for common failure scenarios.
+mod common;
+
+use common::{scratch_dir, write_music};
+use std::path::Path;
+
+fn run(dir: &Path, args: &[&str]) -> i32 {
+    common::run(dir, args).status.code().unwrap()
+}
+
+#[test]
+fn success() {
+    let dir = scratch_dir("success");
+    write_music(&dir, &["a.mp3"], &["a.mp3"]);
+    assert_eq!(run(&dir, &["-o", "out", "music/playlist.m3u"]), 0);
+    assert!(dir.join("out/a.mp3").exists());
+}
+
+#[test]
+fn task_failures() {
+    let dir = scratch_dir("failures");
+    write_music(&dir, &["a.mp3"], &["a.mp3", "missing.mp3"]);
+    assert_eq!(run(&dir, &["-o", "out", "music/playlist.m3u"]), 1);
+}
+
+#[test]
+fn invalid_arguments() {
+    let dir = scratch_dir("arguments");
+    assert_eq!(run(&dir, &["--no-such-option"]), 2);
+    assert_eq!(run(&dir, &["--retries", "many", "music/playlist.m3u"]), 2);
+}
+
+#[test]
+fn nonexistent_playlist() {
+    let dir = scratch_dir("playlist");
+    assert_eq!(run(&dir, &["-o", "out", "missing.m3u"]), 2);
+}
+
+#[test]
+fn invalid_config() {
+    let dir = scratch_dir("config");
+    std::fs::write(dir.join("config.toml"), "no_such_option = true\n").unwrap();
+    assert_eq!(run(&dir, &["--config", "config.toml"]), 2);
+}
+
+#[test]
+fn missing_ffmpeg() {
+    let dir = scratch_dir("ffmpeg");
+    write_music(&dir, &["a.flac"], &["a.flac"]);
+    assert_eq!(run(&dir, &["-o", "out", "music/playlist.m3u"]), 3);
+    assert!(!dir.join("out/playlist.m3u").exists());
+}
+
+#[test]
+fn many_existing_outputs_are_kept_without_yes() {
+    let dir = scratch_dir("confirm");
+    let names: Vec<_> = (0..100).map(|i| format!("{:03}.mp3", i)).collect();
+    let names: Vec<_> = names.iter().map(String::as_str).collect();
+    write_music(&dir, &names, &names);
+    std::fs::create_dir_all(dir.join("out")).unwrap();
+    for name in &names {
+        std::fs::write(dir.join("out").join(name), "OLD").unwrap();
+    }
+
+    assert_eq!(run(&dir, &["-o", "out", "music/playlist.m3u"]), 0);
+    assert_eq!(std::fs::read(dir.join("out/000.mp3")).unwrap(), b"OLD");
+
+    assert_eq!(run(&dir, &["-o", "out", "--yes", "music/playlist.m3u"]), 0);
+    assert_eq!(std::fs::read(dir.join("out/000.mp3")).unwrap(), b"DATA");
+}
+
+#[cfg(unix)]
+#[test]
+fn run_hooks() {
+    let dir = scratch_dir("hooks");
+    write_music(&dir, &["a.mp3"], &["a.mp3"]);
+    let path = Path::new("/bin:/usr/bin");
+    let run = |args: &[&str]| {
+        common::run_with_path(&dir, args, path)
+            .status
+            .code()
+            .unwrap()
+    };
+
+    assert_eq!(
+        run(&["--pre-run", "exit 1", "-o", "out", "music/playlist.m3u"]),
+        3
+    );
+    assert!(!dir.join("out").exists());
+
+    let post_run = "echo $FORD_SYNC_EXIT_STATUS > status.txt";
+    assert_eq!(
+        run(&[
+            "-n",
+            "--post-run",
+            post_run,
+            "-o",
+            "out",
+            "music/playlist.m3u"
+        ]),
+        0
+    );
+    assert!(!dir.join("status.txt").exists());
+    assert_eq!(
+        run(&[
+            "--post-run",
+            post_run,
+            "-o",
+            "out",
+            "music/playlist.m3u",
+            "music/missing.m3u"
+        ]),
+        2
+    );
+    assert_eq!(
+        std::fs::read_to_string(dir.join("status.txt")).unwrap(),
+        "2\n"
+    );
+}
+
+#[test]
+fn invalid_smart_playlists() {
+    let dir = scratch_dir("smart");
+    std::fs::create_dir_all(dir.join("music")).unwrap();
+    std::fs::write(
+        dir.join("rules.toml"),
+        "[[playlist]]\nname = \"Loud\"\nall = [\"loudness > 3\"]\n",
+    )
+    .unwrap();
+    let args = [
+        "-o",
+        "out",
+        "--library",
+        "music",
+        "--smart-playlists",
+        "rules.toml",
+    ];
+    assert_eq!(run(&dir, &args), 2);
+}
+
+#[test]
+fn unreachable_mpd_server() {
+    let dir = scratch_dir("mpd");
+    write_music(&dir, &["a.mp3"], &["a.mp3"]);
+    let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+    let server = listener.local_addr().unwrap().to_string();
+    drop(listener);
+    let args = [
+        "-o",
+        "out",
+        "--mpd",
+        &server,
+        "--mpd-all",
+        "--mpd-music-dir",
+        "music",
+    ];
+    assert_eq!(run(&dir, &args), 3);
+    assert!(!dir.join("out").exists());
+}
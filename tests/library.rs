@@ -0,0 +1,184 @@
+// Copyright (c) 2024 Jan Holthuis <jan.holthuis@rub.de>
+//
+// This Source Code Form is subject to the terms of the Mozilla Public License, v. 2.0. If a copy
+// of the MPL was not distributed with this file, You can obtain one at
+// http://mozilla.org/MPL/2.0/.
+//
+// SPDX-License-Identifier: MPL-2.0
+//! Using the library without the command line.
+#[allow(dead_code)]
+mod common;
+
+use common::{scratch_dir, write_music};
+use ford_sync_convert::execute::TaskResult;
+use ford_sync_convert::mount::TargetFs;
+use ford_sync_convert::observer::{Progress, SyncObserver};
+use ford_sync_convert::plan::{Action, Plan, PlanError, Task};
+use ford_sync_convert::report::Report;
+use ford_sync_convert::scan::FolderPlaylists;
+use ford_sync_convert::sync::{self, Executor, SyncError, SyncErrorKind, SyncOptions};
+use ford_sync_convert::warnings::WarningCategory;
+use std::sync::{Arc, Mutex};
+
+fn options(dir: &std::path::Path) -> SyncOptions {
+    SyncOptions {
+        output_dir: dir.join("out"),
+        target_fs: Some(TargetFs::Fat32),
+        ..Default::default()
+    }
+}
+
+#[test]
+fn plans_and_executes_copies() {
+    let dir = scratch_dir("library");
+    write_music(
+        &dir,
+        &["a.mp3", "b.mp3"],
+        &["a.mp3", "b.mp3", "missing.txt"],
+    );
+    let options = options(&dir);
+
+    let plan = sync::plan(&options, &[dir.join("music/playlist.m3u")]).unwrap();
+    assert_eq!(plan.tasks(Action::Copy).count(), 2);
+    assert!(!dir.join("out").exists());
+
+    let report = Executor::new(options).run(&plan).unwrap();
+    assert_eq!(report.error, None);
+    assert_eq!(report.counts.copied, 2);
+    assert_eq!(report.counts.excluded, 1);
+    assert!(dir.join("out/a.mp3").exists());
+    let playlist = std::fs::read_to_string(dir.join("out/playlist.m3u")).unwrap();
+    assert_eq!(playlist.lines().collect::<Vec<_>>(), ["a.mp3", "b.mp3"]);
+}
+
+#[test]
+fn reports_errors_instead_of_exiting() {
+    let dir = scratch_dir("library-errors");
+    let result = sync::plan(&options(&dir), &[dir.join("missing.m3u")]);
+    assert!(matches!(result, Err(SyncError::Playlist(_))));
+
+    write_music(&dir, &["a.mp3"], &["a.mp3"]);
+    let options = SyncOptions {
+        output_dir: dir.join("music/out"),
+        ..options(&dir)
+    };
+    let result = sync::plan(&options, &[dir.join("music/playlist.m3u")]);
+    assert!(matches!(result, Err(SyncError::Overlap(_))), "{:?}", result);
+}
+
+#[test]
+fn planning_errors_have_kinds() {
+    let dir = scratch_dir("library-error-kinds");
+    let result = sync::plan(&options(&dir), &[dir.join("missing.m3u")]);
+    assert_eq!(result.unwrap_err().kind(), SyncErrorKind::PlaylistParse);
+
+    // `a.mp3` and `A.mp3` collide on the case-insensitive FAT32 output.
+    write_music(&dir, &["a.mp3", "A.mp3"], &["a.mp3", "A.mp3"]);
+    let result = sync::plan(&options(&dir), &[dir.join("music/playlist.m3u")]);
+    assert_eq!(result.unwrap_err().kind(), SyncErrorKind::CollisionDetected);
+}
+
+#[test]
+fn reads_pls_playlists_and_rejects_unknown_formats() {
+    let dir = scratch_dir("library-formats");
+    write_music(&dir, &["a.mp3", "b.mp3"], &[]);
+    std::fs::write(
+        dir.join("music/radio.pls"),
+        "[playlist]\nFile1=b.mp3\nTitle1=B\nFile2=a.mp3\nNumberOfEntries=2\n",
+    )
+    .unwrap();
+    let options = options(&dir);
+    let plan = sync::plan(&options, &[dir.join("music/radio.pls")]).unwrap();
+    assert_eq!(plan.playlists[0].path, dir.join("out/radio.m3u"));
+    let targets: Vec<_> = plan.playlists[0]
+        .entries
+        .iter()
+        .map(|entry| entry.target.as_str())
+        .collect();
+    assert_eq!(targets, ["b.mp3", "a.mp3"]);
+
+    std::fs::write(dir.join("music/list.txt"), "a.mp3\n").unwrap();
+    let error = sync::plan(&options, &[dir.join("music/list.txt")]).unwrap_err();
+    assert!(
+        matches!(
+            error,
+            SyncError::Playlist(PlanError::UnsupportedPlaylist { .. })
+        ),
+        "{:?}",
+        error
+    );
+    assert!(error.to_string().contains("M3U (.m3u, .m3u8), PLS (.pls)"));
+}
+
+#[derive(Default)]
+struct Events(Mutex<Vec<String>>);
+
+impl SyncObserver for Events {
+    fn plan_finished(&self, plan: &Plan) {
+        let event = format!("planned {}", plan.tasks.len());
+        self.0.lock().unwrap().push(event);
+    }
+
+    fn task_finished(&self, progress: Progress, _task: &Task, _result: &TaskResult) {
+        let event = format!("finished {}/{}", progress.done, progress.total);
+        self.0.lock().unwrap().push(event);
+    }
+
+    fn warning(&self, category: WarningCategory, _message: &str) {
+        let event = format!("warning {:?}", category);
+        self.0.lock().unwrap().push(event);
+    }
+
+    fn run_finished(&self, report: &Report) {
+        let event = format!("done {}", report.counts.copied);
+        self.0.lock().unwrap().push(event);
+    }
+}
+
+#[test]
+fn notifies_observers() {
+    let dir = scratch_dir("library-observers");
+    write_music(&dir, &["a.mp3"], &["a.mp3", "http://example.com/b.mp3"]);
+    let events = Arc::new(Events::default());
+    let mut options = options(&dir);
+    options.observers.push(events.clone());
+
+    let plan = sync::plan(&options, &[dir.join("music/playlist.m3u")]).unwrap();
+    Executor::new(options).run(&plan).unwrap();
+    assert_eq!(
+        *events.0.lock().unwrap(),
+        ["warning Url", "planned 1", "finished 1/1", "done 1"]
+    );
+}
+
+#[test]
+fn syncs_directories_with_folder_playlists() {
+    let dir = scratch_dir("library-folders");
+    for source in ["A/B/1.mp3", "A - B/2.mp3", "A - B/3.mp3"] {
+        let path = dir.join("music").join(source);
+        std::fs::create_dir_all(path.parent().unwrap()).unwrap();
+        std::fs::write(path, "DATA").unwrap();
+    }
+    let options = SyncOptions {
+        per_folder_playlists: Some(FolderPlaylists::Leaf),
+        ..options(&dir)
+    };
+
+    let plan = sync::plan(&options, &[dir.join("music")]).unwrap();
+    let report = Executor::new(options).run(&plan).unwrap();
+    assert_eq!(report.error, None);
+    let read = |name: &str| std::fs::read_to_string(dir.join("out").join(name)).unwrap();
+    assert_eq!(
+        read("music.m3u").lines().collect::<Vec<_>>(),
+        ["A\\B\\1.mp3", "A - B\\2.mp3", "A - B\\3.mp3"]
+    );
+    // `A/B` comes first and gets the name, `A - B` is numbered.
+    assert_eq!(
+        read("A - B.m3u").lines().collect::<Vec<_>>(),
+        ["A\\B\\1.mp3"]
+    );
+    assert_eq!(
+        read("A - B (2).m3u").lines().collect::<Vec<_>>(),
+        ["A - B\\2.mp3", "A - B\\3.mp3"]
+    );
+}
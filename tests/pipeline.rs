@@ -0,0 +1,350 @@
+// Copyright (c) 2024 Jan Holthuis <jan.holthuis@rub.de>
+//
+// This Source Code Form is subject to the terms of the Mozilla Public License, v. 2.0. If a copy
+// of the MPL was not distributed with this file, You can obtain one at
+// http://mozilla.org/MPL/2.0/.
+//
+// SPDX-License-Identifier: MPL-2.0
+//! The whole sync with a fake transcoder, so that FFmpeg is not needed.
+#[allow(dead_code)]
+mod common;
+
+use common::{scratch_dir, write_music};
+use ford_sync_convert::execute::{TaskErrorKind, TaskStatus, TranscodeFailureKind};
+use ford_sync_convert::mount::TargetFs;
+use ford_sync_convert::plan::{Action, OutputEntry, Task};
+use ford_sync_convert::report::Report;
+use ford_sync_convert::segment::Segment;
+use ford_sync_convert::sync::{self, Executor, SyncOptions};
+use ford_sync_convert::tags::TagTransliteration;
+use ford_sync_convert::transcode::{Encoding, Job, SharedTranscoder, TranscodeError, Transcoder};
+use std::collections::HashMap;
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+/// Contents of the files written by [`FakeTranscoder`].
+const FAKE_MP3: &str = "ID3 converted";
+
+/// Records its invocations and writes [`FAKE_MP3`] as the output, unless a failure is injected.
+#[derive(Default)]
+struct FakeTranscoder {
+    /// File names of the inputs, in the order of the invocations.
+    calls: Mutex<Vec<String>>,
+    /// Number of times that converting a file should still fail, and FFmpeg's output for it.
+    failures: Mutex<HashMap<String, (usize, &'static str)>>,
+    /// File names of the inputs whose MP3 stream cannot be remuxed.
+    broken_streams: Vec<String>,
+}
+
+impl FakeTranscoder {
+    fn fail(self, name: &str, times: usize, stderr: &'static str) -> Self {
+        self.failures
+            .lock()
+            .unwrap()
+            .insert(name.to_string(), (times, stderr));
+        self
+    }
+
+    fn break_stream(mut self, name: &str) -> Self {
+        self.broken_streams.push(name.to_string());
+        self
+    }
+
+    fn calls(&self) -> Vec<String> {
+        let mut calls = self.calls.lock().unwrap().clone();
+        calls.sort();
+        calls
+    }
+}
+
+impl Transcoder for FakeTranscoder {
+    fn transcode(&self, job: &Job) -> Result<(), TranscodeError> {
+        let name = job
+            .input_path
+            .file_name()
+            .unwrap()
+            .to_string_lossy()
+            .into_owned();
+        self.calls.lock().unwrap().push(name.clone());
+        if job.encoding == Encoding::Remux && self.broken_streams.contains(&name) {
+            return Err(TranscodeError::Status {
+                status: "exit status: 1".to_string(),
+                stderr: "Invalid audio stream".to_string(),
+                log_path: None,
+            });
+        }
+        if let Some((remaining, stderr)) = self.failures.lock().unwrap().get_mut(&name) {
+            if *remaining > 0 {
+                *remaining -= 1;
+                return Err(TranscodeError::Status {
+                    status: "exit status: 1".to_string(),
+                    stderr: stderr.to_string(),
+                    log_path: None,
+                });
+            }
+        }
+        std::fs::write(job.temp_path, FAKE_MP3).map_err(TranscodeError::Io)
+    }
+}
+
+fn sync(dir: &Path, transcoder: &Arc<FakeTranscoder>, configure: fn(&mut SyncOptions)) -> Report {
+    let mut options = SyncOptions {
+        output_dir: dir.join("out"),
+        target_fs: Some(TargetFs::Fat32),
+        ..Default::default()
+    };
+    options.execute.transcoder = SharedTranscoder::new(transcoder.clone());
+    configure(&mut options);
+    let plan = sync::plan(&options, &[dir.join("music/playlist.m3u")]).unwrap();
+    Executor::new(options).run(&plan).unwrap()
+}
+
+/// Like [`sync`], but with all conversions planned as remuxes, as for MP3 streams.
+fn sync_remuxing(dir: &Path, transcoder: &Arc<FakeTranscoder>) -> Report {
+    let mut options = SyncOptions {
+        output_dir: dir.join("out"),
+        target_fs: Some(TargetFs::Fat32),
+        ..Default::default()
+    };
+    options.execute.transcoder = SharedTranscoder::new(transcoder.clone());
+    let mut plan = sync::plan(&options, &[dir.join("music/playlist.m3u")]).unwrap();
+    for task in plan.tasks.iter_mut() {
+        if task.action == Action::Convert {
+            task.action = Action::Remux;
+        }
+    }
+    Executor::new(options).run(&plan).unwrap()
+}
+
+fn statuses(report: &Report) -> Vec<(String, TaskStatus, usize)> {
+    report.playlists[0]
+        .entries
+        .iter()
+        .map(|entry| (entry.target.clone(), entry.status, entry.attempts))
+        .collect()
+}
+
+#[test]
+fn converts_and_copies_without_ffmpeg() {
+    let dir = scratch_dir("pipeline");
+    write_music(
+        &dir,
+        &["a.flac", "b.mp3", "c.ogg"],
+        &["a.flac", "b.mp3", "c.ogg"],
+    );
+    let transcoder = Arc::new(FakeTranscoder::default());
+
+    let report = sync(&dir, &transcoder, |_| ());
+    assert_eq!(report.error, None);
+    assert_eq!(transcoder.calls(), ["a.flac", "c.ogg"]);
+    assert_eq!(report.counts.converted, 2);
+    assert_eq!(report.counts.copied, 1);
+    assert_eq!(
+        std::fs::read_to_string(dir.join("out/a.mp3")).unwrap(),
+        FAKE_MP3
+    );
+    assert_eq!(
+        std::fs::read_to_string(dir.join("out/b.mp3")).unwrap(),
+        "DATA"
+    );
+    let playlist = std::fs::read_to_string(dir.join("out/playlist.m3u")).unwrap();
+    assert_eq!(
+        playlist.lines().collect::<Vec<_>>(),
+        ["a.mp3", "b.mp3", "c.mp3"]
+    );
+}
+
+#[test]
+fn sets_the_album_of_copies_to_the_playlist_name() {
+    let dir = scratch_dir("pipeline-album");
+    write_music(&dir, &["a.mp3"], &["a.mp3"]);
+    let transcoder = Arc::new(FakeTranscoder::default());
+
+    let report = sync(&dir, &transcoder, |options| {
+        options.album_from_playlist = true
+    });
+    assert_eq!(report.counts.copied, 1);
+    let tag = id3::Tag::read_from_path(dir.join("out/a.mp3")).unwrap();
+    assert_eq!(id3::TagLike::album(&tag), Some("playlist"));
+}
+
+#[test]
+fn transliterates_the_tags_of_copies() {
+    let dir = scratch_dir("pipeline-transliterate");
+    write_music(&dir, &["a.mp3"], &["a.mp3"]);
+    let mut tag = id3::Tag::new();
+    id3::TagLike::set_artist(&mut tag, "Кино");
+    id3::TagLike::set_title(&mut tag, "Группа крови");
+    tag.write_to_path(dir.join("music/a.mp3"), id3::Version::Id3v23)
+        .unwrap();
+    let transcoder = Arc::new(FakeTranscoder::default());
+
+    let report = sync(&dir, &transcoder, |options| {
+        options.execute.transliterate_tags = Some(TagTransliteration::Append)
+    });
+    assert_eq!(report.counts.copied, 1);
+    let tag = id3::Tag::read_from_path(dir.join("out/a.mp3")).unwrap();
+    assert_eq!(id3::TagLike::artist(&tag), Some("Kino [Кино]"));
+    assert_eq!(
+        id3::TagLike::title(&tag),
+        Some("Gruppa krovi [Группа крови]")
+    );
+    // Only the output is changed.
+    let source_tag = id3::Tag::read_from_path(dir.join("music/a.mp3")).unwrap();
+    assert_eq!(id3::TagLike::artist(&source_tag), Some("Кино"));
+}
+
+#[test]
+fn retries_transient_failures() {
+    let dir = scratch_dir("pipeline-retries");
+    write_music(&dir, &["a.flac"], &["a.flac"]);
+    let transcoder = Arc::new(FakeTranscoder::default().fail("a.flac", 1, "Connection reset"));
+
+    let report = sync(&dir, &transcoder, |options| options.execute.retries = 1);
+    assert_eq!(transcoder.calls(), ["a.flac", "a.flac"]);
+    assert_eq!(
+        statuses(&report),
+        [("a.mp3".to_string(), TaskStatus::Succeeded, 2)]
+    );
+    assert!(dir.join("out/a.mp3").exists());
+}
+
+#[test]
+fn reports_permanent_failures_in_the_summary() {
+    let dir = scratch_dir("pipeline-failures");
+    write_music(&dir, &["a.flac", "b.flac"], &["a.flac", "b.flac"]);
+    let transcoder = Arc::new(FakeTranscoder::default().fail(
+        "b.flac",
+        3,
+        "[asf @ 0x1] DRM protected stream detected, decoding will likely fail!\n\
+         b.flac: Invalid data found when processing input",
+    ));
+
+    let report = sync(&dir, &transcoder, |options| options.execute.retries = 2);
+    // DRM protected files are not retried.
+    assert_eq!(transcoder.calls(), ["a.flac", "b.flac"]);
+    assert_eq!(
+        statuses(&report),
+        [
+            ("a.mp3".to_string(), TaskStatus::Succeeded, 1),
+            ("b.mp3".to_string(), TaskStatus::Failed, 1),
+        ]
+    );
+    assert_eq!(report.counts.failed, 1);
+    assert_eq!(
+        report.playlists[0].entries[1].error_kind,
+        Some(TaskErrorKind::TranscodeFailed)
+    );
+    assert_eq!(
+        report.playlists[0].entries[1].transcode_failure,
+        Some(TranscodeFailureKind::Protected)
+    );
+    assert!(!dir.join("out/b.mp3").exists());
+    // The playlist leaves out the entry that could not be converted.
+    assert_eq!(
+        std::fs::read_to_string(dir.join("out/playlist.m3u")).unwrap(),
+        "a.mp3\n"
+    );
+    let summary = report.summary(Duration::ZERO, "report.json");
+    assert!(
+        summary.iter().any(|line| line.contains("music/b.flac")
+            && line.contains("FFmpeg exited with non-zero status exit status: 1")),
+        "{:?}",
+        summary
+    );
+}
+
+#[test]
+fn stops_when_the_output_is_not_writable() {
+    let dir = scratch_dir("pipeline-read-only");
+    let names = ["a.flac", "b.flac", "c.flac", "d.mp3"];
+    write_music(&dir, &names, &names);
+    let transcoder = Arc::new(
+        names[..3]
+            .iter()
+            .fold(FakeTranscoder::default(), |transcoder, name| {
+                transcoder.fail(name, 1, "Read-only file system")
+            }),
+    );
+
+    let report = sync(&dir, &transcoder, |options| {
+        options.execute.max_write_errors = 3
+    });
+    let error = report.error.expect("run is stopped");
+    assert!(
+        error.contains("Output directory is not writable"),
+        "{}",
+        error
+    );
+    assert_eq!(report.counts.failed, 3);
+    assert!(!dir.join("out/d.mp3").exists());
+}
+
+#[test]
+fn converts_when_remuxing_fails() {
+    let dir = scratch_dir("pipeline-remux");
+    write_music(&dir, &["a.m4a", "b.m4a"], &["a.m4a", "b.m4a"]);
+    let transcoder = Arc::new(FakeTranscoder::default().break_stream("b.m4a"));
+
+    let report = sync_remuxing(&dir, &transcoder);
+    assert_eq!(report.error, None);
+    assert_eq!(transcoder.calls(), ["a.m4a", "b.m4a", "b.m4a"]);
+    assert_eq!(report.counts.remuxed, 1);
+    assert_eq!(report.counts.converted, 1);
+    assert_eq!(
+        statuses(&report),
+        [
+            ("a.mp3".to_string(), TaskStatus::Succeeded, 1),
+            ("b.mp3".to_string(), TaskStatus::Succeeded, 2),
+        ]
+    );
+    assert!(report.counts.to_string().contains("1 remuxed"));
+}
+
+#[test]
+fn converts_the_parts_of_split_sources() {
+    let dir = scratch_dir("pipeline-split");
+    write_music(&dir, &["book.flac"], &["book.flac"]);
+    let transcoder = Arc::new(FakeTranscoder::default());
+    let mut options = SyncOptions {
+        output_dir: dir.join("out"),
+        target_fs: Some(TargetFs::Fat32),
+        ..Default::default()
+    };
+    options.execute.transcoder = SharedTranscoder::new(transcoder.clone());
+    let mut plan = sync::plan(&options, &[dir.join("music/playlist.m3u")]).unwrap();
+    // Without FFprobe, the duration of the source is unknown, so the parts are planned here.
+    let task = plan.tasks.remove(0);
+    plan.tasks = [(0.0, Some(900.0)), (900.0, None)]
+        .into_iter()
+        .enumerate()
+        .map(|(index, (start, end))| Task {
+            destination: task
+                .destination
+                .with_file_name(format!("book - {:02}.mp3", index + 1)),
+            segment: Some(Segment { start, end }),
+            ..task.clone()
+        })
+        .collect();
+    plan.playlists[0].entries = (0..2)
+        .map(|task| OutputEntry {
+            target: format!("book - {:02}.mp3", task + 1),
+            task,
+        })
+        .collect();
+
+    let report = Executor::new(options).run(&plan).unwrap();
+    assert_eq!(report.error, None);
+    assert_eq!(transcoder.calls(), ["book.flac", "book.flac"]);
+    assert_eq!(report.counts.converted, 2);
+    assert!(report
+        .counts
+        .to_string()
+        .ends_with(", 1 source split into 2 parts"));
+    let playlist = std::fs::read_to_string(dir.join("out/playlist.m3u")).unwrap();
+    assert_eq!(
+        playlist.lines().collect::<Vec<_>>(),
+        ["book - 01.mp3", "book - 02.mp3"]
+    );
+}